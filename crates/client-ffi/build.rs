@@ -0,0 +1,3 @@
+fn main() {
+    uniffi::generate_scaffolding("../client/src/osp_client.udl").unwrap();
+}
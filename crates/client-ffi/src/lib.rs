@@ -0,0 +1,50 @@
+//! # OSP Client Bindings
+//!
+//! The UniFFI scaffolding for the `ffi` module of `osp_client_sdk`, built
+//! into the library Kotlin and Swift apps load. Generate the bindings for
+//! them from the built library:
+//!
+//! ```text
+//! cargo build -p osp_client_ffi --release
+//! uniffi-bindgen generate --library target/release/libosp_client_ffi.so --language kotlin --out-dir out
+//! ```
+
+// the generated scaffolding leaves blank lines after some doc comments
+#![allow(clippy::empty_line_after_doc_comments)]
+
+pub use osp_client_sdk::ffi::*;
+pub use osp_client_sdk::mobile::ConnectionState;
+
+uniffi::include_scaffolding!("osp_client");
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{ClientCallbacks, ClientConfig, ClientError, ConnectionState, OspClient, SubscriptionStatus};
+
+    struct Ignored;
+
+    impl ClientCallbacks for Ignored {
+        fn on_state(&self, _state: ConnectionState) {}
+        fn on_sent(&self, _object_id: String, _sequence: u64) {}
+        fn on_refused(&self, _object_id: String, _reason: String) {}
+        fn on_subscription(&self, _status: SubscriptionStatus) {}
+        fn on_error(&self, _message: String) {}
+    }
+
+    #[test]
+    fn test_scaffolding() {
+        let config = ClientConfig {
+            node_url: "https://node.test".to_string(),
+            hostname: "app.test".to_string(),
+            private_key_pem: String::new(),
+            invite_token: None,
+            keepalive_interval: None,
+            idle_timeout: None,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        };
+        assert!(matches!(OspClient::new(config, Box::new(Ignored)), Err(ClientError::InvalidInput { .. })));
+    }
+}
@@ -0,0 +1,768 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, warn};
+
+use tokio::io::{self, AsyncRead};
+use tokio::time::Instant;
+
+use uuid::Uuid;
+
+use osp_data::Data;
+use osp_protocol::{Compression, ConnectionType, Envelope, Invite, Keepalive, KeepaliveTimer, OSPUrl, PayloadFormat, Protocol, ProtocolVersion, Scheme, SensitivityFilter, TypeDescriptor, VersionRange, PROTOCOL_VERSION};
+use osp_protocol::packet::{chunked, PacketDecoder, PacketEncoder};
+use osp_protocol::packet::handshake::{HandshakePacketGuestToHost, HandshakePacketHostToGuest, KeyAlgorithm};
+use osp_protocol::packet::transfer::{DeliveryQos, SubscriptionState, TransferPacketGuestToHost, TransferPacketHostToGuest, KEEPALIVE_VERSION, PUSH_ACK_VERSION, STREAM_VERSION};
+
+use crate::key::PrivateKey;
+
+type HandshakeProtocol = Protocol<HandshakePacketHostToGuest, HandshakePacketGuestToHost>; // packet types reversed
+
+/// How long requests wait for their answer unless configured otherwise.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Builds an [OSProtocolClient] and connects it to a node.
+#[derive(Clone, Default)]
+pub struct OSProtocolClientBuilder {
+    hostname: Option<String>,
+    private_key: Option<PrivateKey>,
+    invite: Option<Invite>,
+    last_sequence: u64,
+    request_timeout: Option<Duration>,
+    compression: Option<Vec<Compression>>,
+    formats: Option<Vec<PayloadFormat>>,
+    keepalive: Option<Keepalive>,
+}
+
+impl OSProtocolClientBuilder {
+    /// The hostname the client identifies as. The node challenges it with
+    /// the key published for this hostname, unless an invite is presented.
+    pub fn hostname(mut self, hostname: String) -> Self {
+        self.hostname = Some(hostname);
+        self
+    }
+
+    pub fn private_key(mut self, key: PrivateKey) -> Self {
+        self.private_key = Some(key);
+        self
+    }
+
+    /// Read the client's private key from the PEM file at `path`.
+    pub fn private_key_file(self, path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(self.private_key(PrivateKey::from_file(path)?))
+    }
+
+    /// Present `invite` when identifying, for nodes that only accept invited
+    /// peers or when the client's hostname has no `_osp` DNS record.
+    pub fn invite(mut self, invite: Invite) -> Self {
+        self.invite = Some(invite);
+        self
+    }
+
+    /// Continue from an earlier session, whose last pushed object had
+    /// sequence number `sequence`, see [OSProtocolClient::last_sequence].
+    /// Nodes ignore objects with a sequence number they already processed,
+    /// so a client that pushes to the same node repeatedly has to keep
+    /// track of it.
+    pub fn resume_from(mut self, sequence: u64) -> Self {
+        self.last_sequence = sequence;
+        self
+    }
+
+    /// How long requests such as [OSProtocolClient::describe_type] wait for
+    /// their answer before failing with [TimedOut](io::ErrorKind::TimedOut).
+    /// The node is told, so it stops working on requests the client gave up
+    /// on. Defaults to 30 seconds.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// The algorithms frames to and from the node may be compressed with,
+    /// the preferred first. The node picks the one it prefers. Defaults to
+    /// every algorithm this build supports, empty turns compression off.
+    pub fn compression(mut self, compression: Vec<Compression>) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// The formats the client may encode objects in, the preferred first.
+    /// The node picks the one it prefers, which
+    /// [send_data](OSProtocolClient::send_data) encodes objects in.
+    /// Defaults to every format.
+    pub fn formats(mut self, formats: Vec<PayloadFormat>) -> Self {
+        self.formats = Some(formats);
+        self
+    }
+
+    /// When the node is pinged while the client waits for its packets, and
+    /// when the connection is given up on with
+    /// [IdleTimeout](osp_protocol::ProtocolError::IdleTimeout) as the node
+    /// stopped responding, see [Keepalive]. The node's pings are answered
+    /// as packets are read, so a client holding its connection open
+    /// without reading should [ping](OSProtocolClient::ping) the node to
+    /// keep it from closing the connection. Defaults to
+    /// [Keepalive::default].
+    pub fn keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Whether a [keepalive](Self::keepalive) was set.
+    #[cfg(feature = "mobile")]
+    pub(crate) fn has_keepalive(&self) -> bool {
+        self.keepalive.is_some()
+    }
+
+    /// Resolve the node at `url` and [connect](Self::connect) to it. Fails
+    /// with [Unsupported](io::ErrorKind::Unsupported) for `ws://` and
+    /// `wss://` URLs, as the client only connects over raw TCP.
+    pub async fn connect_url(self, url: OSPUrl) -> io::Result<OSProtocolClient> {
+        if url.scheme != Scheme::Osp {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("Unable to connect to {url}, the client only connects over raw TCP")));
+        }
+        info!("Resolving osp connection to {url}");
+        let addr = tokio::net::lookup_host((url.domain.as_str(), url.port)).await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, format!("Failed to resolve address {}", url.domain)))?;
+        let mut client = self.connect(addr).await?;
+        client.peer = url.domain;
+        Ok(client)
+    }
+
+    /// Connect to the node at `addr` and perform the handshake. Fails with
+    /// [PermissionDenied](io::ErrorKind::PermissionDenied) if the node
+    /// rejects the client.
+    pub async fn connect(self, addr: SocketAddr) -> io::Result<OSProtocolClient> {
+        let missing = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("A client needs a {what} to connect"));
+        let hostname = self.hostname.ok_or_else(|| missing("hostname"))?;
+        let private_key = self.private_key.ok_or_else(|| missing("private key"))?;
+
+        info!("Opening connection to {addr}");
+        let mut protocol = HandshakeProtocol::connect(addr).await?;
+        protocol.send_message(HandshakePacketGuestToHost::Hello {
+            connection_type: ConnectionType::Client,
+            version: PROTOCOL_VERSION,
+            compression: self.compression.unwrap_or_else(Compression::supported),
+            formats: self.formats.unwrap_or_else(|| PayloadFormat::ALL.to_vec()),
+            software: Some(concat!("osp_client_sdk/", env!("CARGO_PKG_VERSION")).to_string()),
+            timestamp: Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64),
+        }).await?;
+        let (versions, subscription_lease, compression, format) = match read_handshake(&mut protocol, addr).await? {
+            HandshakePacketHostToGuest::Acknowledge { ok: true, versions, subscription_lease, compression, format, .. } => {
+                (versions.unwrap_or(VersionRange::INITIAL), subscription_lease, compression, format)
+            }
+            HandshakePacketHostToGuest::Acknowledge { ok: false, err, .. } => return Err(rejected(addr, err)),
+            _ => return Err(unexpected(addr)),
+        };
+        let Some(version) = VersionRange::SUPPORTED.negotiate(&versions) else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{addr} speaks protocol versions {versions}, the client speaks {}", VersionRange::SUPPORTED),
+            ));
+        };
+        debug!("Speaking protocol version {version} with {addr}, compressing with {compression:?}, objects in {format:?}");
+        protocol.set_version(version);
+        protocol.set_compression(compression);
+
+        protocol.send_message(HandshakePacketGuestToHost::Identify {
+            hostname: hostname.clone(),
+            invite: self.invite,
+        }).await?;
+        let HandshakePacketHostToGuest::Challenge { nonce, encrypted_challenge, algorithm } = read_handshake(&mut protocol, addr).await? else {
+            return Err(unexpected(addr));
+        };
+        if algorithm != KeyAlgorithm::Rsa {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{addr} challenged the client with a {algorithm:?} key, only RSA keys are supported")));
+        }
+        debug!("Challenge received, connection nonce: {nonce}");
+        protocol.send_message(HandshakePacketGuestToHost::Verify {
+            nonce,
+            challenge: private_key.decrypt(&encrypted_challenge)?,
+            signature: None,
+            resumption: None,
+        }).await?;
+
+        let mut preferences = SensitivityFilter::default();
+        loop {
+            match read_handshake(&mut protocol, addr).await? {
+                HandshakePacketHostToGuest::Preferences { filter } => preferences = filter,
+                HandshakePacketHostToGuest::Close { can_continue: true, .. } => break,
+                _ => return Err(unexpected(addr)),
+            }
+        }
+        info!("Handshake with {addr} successful");
+
+        Ok(OSProtocolClient {
+            keepalive: KeepaliveTimer::new(self.keepalive.unwrap_or_default(), version),
+            protocol: protocol.map_codecs(
+                |_| PacketDecoder::new(),
+                |_| PacketEncoder::new(),
+            ),
+            hostname,
+            peer: addr.ip().to_string(),
+            preferences,
+            last_sequence: self.last_sequence,
+            request_timeout: self.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+            subscription_lease,
+            renew_at: None,
+            subscription_qos: DeliveryQos::default(),
+            format,
+        })
+    }
+}
+
+/// Read the next handshake packet, failing if the node closed the
+/// connection.
+async fn read_handshake(protocol: &mut HandshakeProtocol, addr: SocketAddr) -> io::Result<HandshakePacketHostToGuest> {
+    match protocol.read_frame().await? {
+        HandshakePacketHostToGuest::Close { can_continue: false, err, .. } => Err(rejected(addr, err)),
+        packet => Ok(packet),
+    }
+}
+
+fn rejected(addr: SocketAddr, err: Option<String>) -> io::Error {
+    let reason = err.unwrap_or_else(|| "no reason given".to_string());
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("Handshake with {addr} was rejected: {reason}"))
+}
+
+fn deadline_exceeded(type_id: Uuid, timeout: Duration) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("The node did not describe type {type_id} within {timeout:?}"))
+}
+
+fn unanswered(timeout: Duration) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("The node did not answer the subscription request within {timeout:?}"))
+}
+
+fn overloaded() -> io::Error {
+    io::Error::new(io::ErrorKind::ResourceBusy, "The node is overloaded, try again later")
+}
+
+fn unexpected(addr: SocketAddr) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected handshake packet from {addr}"))
+}
+
+/// A connection from an application to a node, past the handshake.
+pub struct OSProtocolClient {
+    protocol: Protocol<TransferPacketHostToGuest, TransferPacketGuestToHost>, // packet types reversed
+    hostname: String,
+    /// Hostname of the node, or its address if connected to one directly
+    peer: String,
+    preferences: SensitivityFilter,
+    last_sequence: u64,
+    request_timeout: Duration,
+    subscription_lease: Option<Duration>,
+    /// When to renew the client's subscription, `None` if it has none that
+    /// expires
+    renew_at: Option<Instant>,
+    subscription_qos: DeliveryQos,
+    /// The format the node picked for objects
+    format: PayloadFormat,
+    /// When the node was last heard from
+    keepalive: KeepaliveTimer,
+}
+
+impl OSProtocolClient {
+    pub fn builder() -> OSProtocolClientBuilder {
+        OSProtocolClientBuilder::default()
+    }
+
+    /// The hostname the client identified as, which is the origin of the
+    /// objects it sends.
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// Flagged objects the node doesn't want. [send](Self::send) refuses to
+    /// send objects they exclude.
+    pub fn preferences(&self) -> &SensitivityFilter {
+        &self.preferences
+    }
+
+    /// The protocol version negotiated with the node.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol.version()
+    }
+
+    /// The compression frames to and from the node are framed with.
+    pub fn compression(&self) -> Compression {
+        self.protocol.compression()
+    }
+
+    /// The format the node picked for the objects the client sends.
+    pub fn format(&self) -> PayloadFormat {
+        self.format
+    }
+
+    /// How long subscriptions to the node last unless they are renewed,
+    /// `None` if they don't expire.
+    pub fn subscription_lease(&self) -> Option<Duration> {
+        self.subscription_lease
+    }
+
+    /// How the node delivers objects to the client's subscription, as it
+    /// reported when it last answered a subscription request or renewal.
+    pub fn subscription_qos(&self) -> DeliveryQos {
+        self.subscription_qos
+    }
+
+    /// Sequence number of the last object sent to the node, to
+    /// [resume](OSProtocolClientBuilder::resume_from) from when connecting
+    /// again. An object counts as sent once any part of it was, even if
+    /// sending it failed.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Send an object to the node, returning the sequence number it was sent
+    /// with. Objects the node's [preferences](Self::preferences) exclude are
+    /// refused with [io::ErrorKind::PermissionDenied]. The node answers
+    /// objects it refuses with a [Nack](TransferPacketHostToGuest::Nack).
+    pub async fn send(&mut self, envelope: Envelope) -> io::Result<u64> {
+        self.send_push(envelope, false).await
+    }
+
+    /// [Send](Self::send) an object and wait until the node acknowledges it
+    /// handled the object. Fails with
+    /// [PermissionDenied](io::ErrorKind::PermissionDenied) if the node
+    /// refused it, or [TimedOut](io::ErrorKind::TimedOut) if there is no ack
+    /// within the [request timeout](OSProtocolClientBuilder::request_timeout).
+    /// Nodes that don't speak [PUSH_ACK_VERSION] can't acknowledge objects,
+    /// so objects sent to them are only sent.
+    pub async fn send_acked(&mut self, envelope: Envelope) -> io::Result<u64> {
+        let ack = self.protocol.version() >= PUSH_ACK_VERSION;
+        let object_id = envelope.object_id;
+        let sequence = self.send_push(envelope, ack).await?;
+        if !ack {
+            return Ok(sequence);
+        }
+        let timeout = self.request_timeout;
+        let acked = async {
+            loop {
+                match self.read_packet().await? {
+                    TransferPacketHostToGuest::Ack { sequence: acked, .. } if acked == sequence => return Ok(sequence),
+                    TransferPacketHostToGuest::Nack { object_id: refused, code, reason } if refused == object_id => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            format!("{} refused object {object_id} ({code:?}): {}", self.peer, reason.unwrap_or_default()),
+                        ));
+                    }
+                    packet => self.log_unsolicited(packet),
+                }
+            }
+        };
+        tokio::time::timeout(timeout, acked).await.unwrap_or_else(|_| Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("The node did not acknowledge object {object_id} within {timeout:?}"),
+        )))
+    }
+
+    async fn send_push(&mut self, envelope: Envelope, ack: bool) -> io::Result<u64> {
+        self.renew_if_due().await?;
+        if let Some(reason) = self.preferences.excludes(&envelope.sensitivity) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Not sending object {} to {}: {reason}", envelope.object_id, self.peer)
+            ));
+        }
+        let sequence = self.last_sequence + 1;
+        // objects too large for one packet are pushed in chunks, one
+        // transfer at a time, so the sequence tells them apart
+        let version = self.protocol.version();
+        let packets = chunked::push_packets(sequence, envelope, None, ack, sequence as u32, version)?;
+        // the node may have taken in part of the object from here on
+        self.last_sequence = sequence;
+        for packet in packets {
+            self.send_request(packet).await?;
+        }
+        Ok(sequence)
+    }
+
+    /// Stream the bytes `reader` yields to the node as the attachment with id
+    /// `stream_id`, returning how many were sent once the node's stream
+    /// handler took them all in. Fails with
+    /// [PermissionDenied](io::ErrorKind::PermissionDenied) if the node
+    /// refused the stream, [Unsupported](io::ErrorKind::Unsupported) if it
+    /// doesn't speak [STREAM_VERSION], or [TimedOut](io::ErrorKind::TimedOut)
+    /// if it doesn't answer within the
+    /// [request timeout](OSProtocolClientBuilder::request_timeout) once the
+    /// stream ended. If reading fails, or the node refuses the stream before
+    /// it ended, the stream is aborted.
+    pub async fn send_stream(&mut self, stream_id: Uuid, mut reader: impl AsyncRead + Unpin) -> io::Result<u64> {
+        self.renew_if_due().await?;
+        if self.protocol.version() < STREAM_VERSION {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} does not take streams", self.peer)));
+        }
+        // one stream at a time, so they can all have the same transfer id
+        let transfer_id = 0;
+        self.send_request(TransferPacketGuestToHost::StreamBegin { transfer_id, stream_id }).await?;
+        let mut sent = 0;
+        loop {
+            // no use sending the rest of a stream the node refused, reading
+            // is cancel safe, so this only takes in what already arrived
+            if let Ok(packet) = tokio::time::timeout(Duration::ZERO, self.read_packet()).await {
+                if let Some(Err(e)) = self.stream_answer(stream_id, packet?) {
+                    self.send_request(TransferPacketGuestToHost::StreamEnd { transfer_id, aborted: true }).await?;
+                    return Err(e);
+                }
+            }
+            let data = match chunked::read_chunk(&mut reader).await {
+                Ok(data) if data.is_empty() => break,
+                Ok(data) => data,
+                Err(e) => {
+                    self.send_request(TransferPacketGuestToHost::StreamEnd { transfer_id, aborted: true }).await?;
+                    return Err(e);
+                }
+            };
+            sent += data.len() as u64;
+            self.send_request(TransferPacketGuestToHost::StreamChunk { transfer_id, data }).await?;
+        }
+        self.send_request(TransferPacketGuestToHost::StreamEnd { transfer_id, aborted: false }).await?;
+
+        let timeout = self.request_timeout;
+        let received = async {
+            loop {
+                let packet = self.read_packet().await?;
+                if let Some(answer) = self.stream_answer(stream_id, packet) {
+                    return answer.map(|_| sent);
+                }
+            }
+        };
+        tokio::time::timeout(timeout, received).await.unwrap_or_else(|_| Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("The node did not take in stream {stream_id} within {timeout:?}"),
+        )))
+    }
+
+    /// Wrap `data`, encoded in the [format](Self::format) the node picked,
+    /// in a new envelope originating from the client and [send](Self::send)
+    /// it.
+    pub async fn send_data<T: Data>(&mut self, data: &T) -> io::Result<u64> {
+        let envelope = data.to_envelope_as(self.hostname.clone(), self.format)?;
+        self.send(envelope).await
+    }
+
+    /// Subscribe to objects of `data_types` from the node, returning whether
+    /// it approved the subscription. A pending subscription can be checked on
+    /// by subscribing again later. Fails with
+    /// [ResourceBusy](io::ErrorKind::ResourceBusy) if the node is too busy.
+    ///
+    /// Clients don't listen for pushed objects, so the subscription only
+    /// records the client's interest with the node. Once subscribed, the
+    /// client renews the subscription before its lease runs out whenever it
+    /// sends a request, see [renew_subscription](Self::renew_subscription).
+    pub async fn subscribe(&mut self, data_types: &[Uuid]) -> io::Result<SubscriptionState> {
+        self.subscribe_topics(data_types, &[]).await
+    }
+
+    /// [Subscribe](Self::subscribe) to objects of `data_types` published
+    /// under a topic matching one of `topics`. A `*` level matches any one
+    /// level and a `#` as the last level any number of levels, so
+    /// `blog/rust/*` matches `blog/rust/async` and `blog/#` every topic
+    /// under `blog`. Nodes deny subscriptions with filters they can't parse.
+    pub async fn subscribe_topics(&mut self, data_types: &[Uuid], topics: &[&str]) -> io::Result<SubscriptionState> {
+        self.subscribe_with(data_types, topics, DeliveryQos::default()).await
+    }
+
+    /// [Subscribe](Self::subscribe_topics) to objects of `data_types` under
+    /// `topics`, asking the node to deliver them with `qos`. The node
+    /// reports the level it delivers with, see
+    /// [subscription_qos](Self::subscription_qos).
+    pub async fn subscribe_with(&mut self, data_types: &[Uuid], topics: &[&str], qos: DeliveryQos) -> io::Result<SubscriptionState> {
+        let request = TransferPacketGuestToHost::Subscribe {
+            data_types: data_types.to_vec(),
+            port: None,
+            topics: topics.iter().map(|topic| topic.to_string()).collect(),
+            qos,
+            type_versions: Vec::new(),
+            deadline: Some(self.request_timeout),
+        };
+        let request_id = u8::from(&request);
+        self.send_request(request).await?;
+        self.await_subscription(Some(request_id)).await
+    }
+
+    /// Extend the lease of the client's subscription, returning the state of
+    /// the subscription, which is
+    /// [Unsubscribed](SubscriptionState::Unsubscribed) if there is none to
+    /// renew. The client renews on its own while it sends requests, so this
+    /// is only needed by clients that are idle for longer than the
+    /// [subscription lease](Self::subscription_lease).
+    pub async fn renew_subscription(&mut self) -> io::Result<SubscriptionState> {
+        let deadline = Some(self.request_timeout);
+        self.send_request(TransferPacketGuestToHost::RenewSubscription { deadline }).await?;
+        self.await_subscription(None).await
+    }
+
+    /// Wait for the node to answer a subscription request, which was shed
+    /// if the node is overloaded with `request`. Fails with
+    /// [TimedOut](io::ErrorKind::TimedOut) if there is no answer within the
+    /// [request timeout](OSProtocolClientBuilder::request_timeout).
+    async fn await_subscription(&mut self, request: Option<u8>) -> io::Result<SubscriptionState> {
+        let timeout = self.request_timeout;
+        let answer = async {
+            loop {
+                match self.read_packet().await? {
+                    TransferPacketHostToGuest::Subscription { state, lease, qos } => {
+                        self.leased(state, lease);
+                        self.subscription_qos = qos;
+                        return Ok(state);
+                    }
+                    TransferPacketHostToGuest::DeadlineExceeded { id } if id.is_nil() => return Err(unanswered(timeout)),
+                    TransferPacketHostToGuest::Overloaded { request: shed } if Some(shed) == request => {
+                        return Err(overloaded());
+                    }
+                    packet => self.log_unsolicited(packet),
+                }
+            }
+        };
+        tokio::time::timeout(timeout, answer).await.unwrap_or_else(|_| Err(unanswered(timeout)))
+    }
+
+    /// Renew the client's subscription once half its lease has passed.
+    async fn renew_if_due(&mut self) -> io::Result<()> {
+        if self.renew_at.is_some_and(|renew_at| renew_at <= Instant::now()) {
+            debug!("Renewing subscription to {}", self.peer);
+            self.renew_subscription().await?;
+        }
+        Ok(())
+    }
+
+    /// Schedule the renewal of a subscription in `state`, leased for
+    /// `lease`.
+    fn leased(&mut self, state: SubscriptionState, lease: Option<Duration>) {
+        self.renew_at = match state {
+            SubscriptionState::Pending | SubscriptionState::Approved => lease.map(|lease| Instant::now() + lease / 2),
+            SubscriptionState::Denied | SubscriptionState::Unsubscribed => None,
+        };
+    }
+
+    /// Stop subscribing to objects of `data_types`, or of every type if
+    /// `data_types` is empty.
+    pub async fn unsubscribe(&mut self, data_types: &[Uuid]) -> io::Result<()> {
+        if data_types.is_empty() {
+            self.renew_at = None;
+        }
+        self.send_request(TransferPacketGuestToHost::Unsubscribe {
+            data_types: data_types.to_vec(),
+        }).await
+    }
+
+    /// Ask the node to describe the data type `type_id`, returning `None` if
+    /// it doesn't know the type. Fails with [TimedOut](io::ErrorKind::TimedOut)
+    /// if there is no answer within the
+    /// [request timeout](OSProtocolClientBuilder::request_timeout),
+    /// or with [ResourceBusy](io::ErrorKind::ResourceBusy) if the node is too
+    /// busy.
+    pub async fn describe_type(&mut self, type_id: Uuid) -> io::Result<Option<TypeDescriptor>> {
+        self.renew_if_due().await?;
+        let timeout = self.request_timeout;
+        let request = TransferPacketGuestToHost::DescribeType { type_id, deadline: Some(timeout) };
+        let request_id = u8::from(&request);
+        self.send_request(request).await?;
+        let answer = async {
+            loop {
+                match self.read_packet().await? {
+                    TransferPacketHostToGuest::TypeDescription { type_id: described, descriptor } if described == type_id => {
+                        return Ok(descriptor);
+                    }
+                    TransferPacketHostToGuest::DeadlineExceeded { id } if id == type_id => {
+                        return Err(deadline_exceeded(type_id, timeout));
+                    }
+                    TransferPacketHostToGuest::Overloaded { request } if request == request_id => {
+                        return Err(overloaded());
+                    }
+                    packet => self.log_unsolicited(packet),
+                }
+            }
+        };
+        tokio::time::timeout(timeout, answer).await.unwrap_or_else(|_| Err(deadline_exceeded(type_id, timeout)))
+    }
+
+    /// Check the node is still there, returning how long it took to
+    /// answer. Fails with [Unsupported](io::ErrorKind::Unsupported) if it
+    /// doesn't speak [KEEPALIVE_VERSION], or
+    /// [TimedOut](io::ErrorKind::TimedOut) if there is no answer within the
+    /// [request timeout](OSProtocolClientBuilder::request_timeout).
+    pub async fn ping(&mut self) -> io::Result<Duration> {
+        if self.protocol.version() < KEEPALIVE_VERSION {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} can't be pinged", self.peer)));
+        }
+        let nonce = self.keepalive.nonce();
+        let sent_at = Instant::now();
+        self.protocol.send_message(TransferPacketGuestToHost::Ping { nonce }).await?;
+        let timeout = self.request_timeout;
+        let pong = async {
+            loop {
+                match self.read_packet().await? {
+                    TransferPacketHostToGuest::Pong { nonce: answered } if answered == nonce => return io::Result::Ok(sent_at.elapsed()),
+                    packet => self.log_unsolicited(packet),
+                }
+            }
+        };
+        tokio::time::timeout(timeout, pong).await.unwrap_or_else(|_| Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} did not answer a ping within {timeout:?}", self.peer),
+        )))
+    }
+
+    /// Read the next packet sent by the node, pinging it while it is silent
+    /// and answering its pings. Fails with
+    /// [TimedOut](io::ErrorKind::TimedOut) once it was silent for the
+    /// [idle timeout](OSProtocolClientBuilder::keepalive).
+    pub async fn read_packet(&mut self) -> io::Result<TransferPacketHostToGuest> {
+        loop {
+            match self.keepalive.read_frame(&mut self.protocol, |nonce| TransferPacketGuestToHost::Ping { nonce }).await? {
+                TransferPacketHostToGuest::Ping { nonce } => {
+                    self.protocol.send_message(TransferPacketGuestToHost::Pong { nonce }).await?;
+                }
+                packet => return Ok(packet),
+            }
+        }
+    }
+
+    /// Send the node `packet`, restarting the idle clock: the time the node
+    /// takes to answer isn't it falling silent. Pings mustn't be sent this
+    /// way, or a node that died would never time out.
+    async fn send_request(&mut self, packet: TransferPacketGuestToHost) -> io::Result<()> {
+        self.protocol.send_message(packet).await?;
+        self.keepalive.heard();
+        Ok(())
+    }
+
+    /// What `packet` tells about the stream with id `stream_id`: `Ok` once
+    /// the node took it in, an error if it refused it, `None` if nothing.
+    fn stream_answer(&self, stream_id: Uuid, packet: TransferPacketHostToGuest) -> Option<io::Result<()>> {
+        match packet {
+            TransferPacketHostToGuest::StreamReceived { stream_id: received } if received == stream_id => Some(Ok(())),
+            TransferPacketHostToGuest::Nack { object_id, code, reason } if object_id == stream_id => {
+                Some(Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} refused stream {stream_id} ({code:?}): {}", self.peer, reason.unwrap_or_default()),
+                )))
+            }
+            packet => {
+                self.log_unsolicited(packet);
+                None
+            }
+        }
+    }
+
+    fn log_unsolicited(&self, packet: TransferPacketHostToGuest) {
+        if let TransferPacketHostToGuest::Nack { object_id, code, reason } = packet {
+            warn!("{} refused object {object_id} ({code:?}): {}", self.peer, reason.unwrap_or_default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+
+    use tokio::io::{self, AsyncReadExt};
+
+    use uuid::Uuid;
+
+    use osp_data::{impl_data, Data, HandlerError};
+    use osp_protocol::{Compression, PayloadFormat, PROTOCOL_VERSION};
+    use osp_protocol::packet::transfer::{DeliveryQos, SubscriptionState};
+    use osp_server_sdk::OSProtocolNode;
+    use osp_server_sdk::stream::IncomingStream;
+    use osp_server_sdk::subscription::DEFAULT_SUBSCRIPTION_LEASE;
+
+    use crate::{OSProtocolClient, PrivateKey};
+
+    #[derive(Serialize, Deserialize)]
+    struct Note {
+        text: String,
+    }
+
+    impl_data!(Note, "7a0f3f0e-60c1-4d55-9a3b-0d0c5d2f8e41");
+
+    #[test]
+    fn test_client() -> io::Result<()> {
+        let streamed = Arc::new(Mutex::new(Vec::new()));
+        // the 256 byte challenge only fits keys of more than 2048 bits
+        let server_key = osp_server_sdk::crypto::PrivateKey::generate(1024)?;
+        let client_key = osp_server_sdk::crypto::PrivateKey::generate(4096)?;
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57501".parse().unwrap())
+            .hostname("node.test".to_string())
+            .private_key(server_key)
+            .invite_only(true)
+            .subscription_lease(Some(DEFAULT_SUBSCRIPTION_LEASE))
+            .stream_handler({
+                let streamed = streamed.clone();
+                move |mut stream: IncomingStream| {
+                    let streamed = streamed.clone();
+                    async move {
+                        let mut bytes = Vec::new();
+                        stream.read_to_end(&mut bytes).await?;
+                        if bytes.is_empty() {
+                            return Err(HandlerError::rejected("Empty attachment"));
+                        }
+                        streamed.lock().unwrap().push((stream.stream_id, bytes.len()));
+                        Ok(())
+                    }
+                }
+            })
+            .build()?;
+        let invite = node.admin().issue_invite("app.test", &client_key.public_key()?.to_pem()?, None)?;
+
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let listener = node.clone();
+            tokio::spawn(async move { listener.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let builder = OSProtocolClient::builder()
+                .hostname("app.test".to_string())
+                .private_key(PrivateKey::from_pem(&client_key.to_pem()?)?);
+            assert_eq!(builder.connect("127.0.0.1:57501".parse().unwrap()).await.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+
+            let mut client = OSProtocolClient::builder()
+                .hostname("app.test".to_string())
+                .private_key(PrivateKey::from_pem(&client_key.to_pem()?)?)
+                .invite(invite)
+                .request_timeout(Duration::from_secs(5))
+                .compression(vec![Compression::Lz4])
+                .formats(vec![PayloadFormat::Json, PayloadFormat::Cbor])
+                .connect("127.0.0.1:57501".parse().unwrap())
+                .await?;
+            assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+            assert_eq!(client.compression(), Compression::Lz4);
+            // the node prefers CBOR to JSON
+            assert_eq!(client.format(), PayloadFormat::Cbor);
+            assert_eq!(client.send_data(&Note { text: "hello ".repeat(100) }).await?, 1);
+            // larger than a frame, so it is pushed in chunks
+            assert_eq!(client.send_data(&Note { text: "hello ".repeat(1_500_000) }).await?, 2);
+            assert_eq!(client.send_acked(Note { text: "acked".to_string() }.to_envelope("app.test".to_string())?).await?, 3);
+            assert_eq!(client.subscription_lease(), Some(DEFAULT_SUBSCRIPTION_LEASE));
+            assert_eq!(client.subscribe(&[Note::TYPE_ID]).await?, SubscriptionState::Approved);
+            assert_eq!(client.renew_subscription().await?, SubscriptionState::Approved);
+            assert_eq!(client.subscribe_topics(&[Note::TYPE_ID], &["notes/#/drafts"]).await?, SubscriptionState::Denied);
+            assert_eq!(client.subscribe_topics(&[Note::TYPE_ID], &["notes/*/drafts"]).await?, SubscriptionState::Approved);
+            assert_eq!(client.subscription_qos(), DeliveryQos::AtLeastOnce);
+            assert_eq!(client.subscribe_with(&[Note::TYPE_ID], &[], DeliveryQos::Ordered).await?, SubscriptionState::Approved);
+            assert_eq!(client.renew_subscription().await?, SubscriptionState::Approved);
+            assert_eq!(client.subscription_qos(), DeliveryQos::Ordered);
+            // the node handles packets in order, so the push was handled
+            // before the subscription was answered
+            assert_eq!(node.admin().type_usage(Note::TYPE_ID)?.usage.objects, 3);
+            assert_eq!(client.last_sequence(), 3);
+            assert!(client.describe_type(Uuid::new_v4()).await?.is_none());
+            assert!(client.ping().await? < Duration::from_secs(5));
+
+            // longer than a chunk, so it is streamed in two
+            let stream_id = Uuid::new_v4();
+            assert_eq!(client.send_stream(stream_id, &vec![7; 1_500_000][..]).await?, 1_500_000);
+            assert_eq!(*streamed.lock().unwrap(), vec![(stream_id, 1_500_000)]);
+            let refused = client.send_stream(Uuid::new_v4(), &[][..]).await;
+            assert_eq!(refused.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+            Ok(())
+        })
+    }
+}
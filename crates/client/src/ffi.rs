@@ -0,0 +1,320 @@
+//! # Bindings
+//!
+//! Mobile reader apps are written in Kotlin and Swift. With the `ffi`
+//! feature, the client exposes an [OspClient] wrapping a [MobileClient] in
+//! the types [UniFFI](https://mozilla.github.io/uniffi-rs/) can carry
+//! across the language boundary: strings instead of UUIDs and URLs, PEM
+//! keys and invite tokens instead of parsed ones, and a [ClientError]
+//! instead of [io::Error].
+//!
+//! The interface is described in `src/osp_client.udl`. The
+//! `osp_client_ffi` crate generates the scaffolding from it in its build
+//! script and builds the library the apps load, see its docs for
+//! generating the Kotlin and Swift bindings. The UDL file has to change
+//! along with this module.
+
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+
+use bytes::BytesMut;
+
+use tokio::io;
+
+use url::Url;
+
+use uuid::Uuid;
+
+use osp_protocol::{Envelope, Invite, Keepalive, OSPUrl, Scheme};
+use osp_protocol::packet::DeserializePacket;
+use osp_protocol::packet::transfer::SubscriptionState;
+
+use crate::{OSProtocolClient, PrivateKey};
+use crate::mobile::{ClientListener, ConnectionState, MobileClient, ReconnectPolicy};
+
+/// Why a call to an [OspClient] failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientError {
+    /// A setting or argument was malformed, such as a URL or UUID.
+    InvalidInput { message: String },
+    /// The client was closed.
+    NotConnected { message: String },
+    Io { message: String },
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidInput { message } | ClientError::NotConnected { message } | ClientError::Io { message } => {
+                f.write_str(message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> Self {
+        let message = err.to_string();
+        match err.kind() {
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => ClientError::InvalidInput { message },
+            io::ErrorKind::NotConnected => ClientError::NotConnected { message },
+            _ => ClientError::Io { message },
+        }
+    }
+}
+
+impl From<ClientError> for io::Error {
+    fn from(err: ClientError) -> Self {
+        match err {
+            ClientError::InvalidInput { message } => io::Error::new(io::ErrorKind::InvalidInput, message),
+            ClientError::NotConnected { message } => io::Error::new(io::ErrorKind::NotConnected, message),
+            ClientError::Io { message } => io::Error::other(message),
+        }
+    }
+}
+
+/// The settings of an [OspClient].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientConfig {
+    /// `osp://` URL of the node to connect to.
+    pub node_url: String,
+    /// The hostname the client identifies as.
+    pub hostname: String,
+    /// PEM encoded RSA key, in PKCS#1 or PKCS#8 format.
+    pub private_key_pem: String,
+    /// An invite token issued by the node, for clients whose hostname has
+    /// no `_osp` DNS record.
+    pub invite_token: Option<String>,
+    /// How often a silent node is pinged, `None` for
+    /// [LOW_POWER_KEEPALIVE](crate::mobile::LOW_POWER_KEEPALIVE).
+    pub keepalive_interval: Option<Duration>,
+    /// How long the node may be silent before the connection is given up
+    /// on, if a `keepalive_interval` is set.
+    pub idle_timeout: Option<Duration>,
+    /// How long to wait before reconnecting, see [ReconnectPolicy].
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+/// How an [OspClient] reports back. Unlike a [ClientListener], bindings
+/// implement every callback.
+pub trait ClientCallbacks: Send + Sync {
+    fn on_state(&self, state: ConnectionState);
+    fn on_sent(&self, object_id: String, sequence: u64);
+    fn on_refused(&self, object_id: String, reason: String);
+    fn on_subscription(&self, status: SubscriptionStatus);
+    fn on_error(&self, message: String);
+}
+
+/// See [SubscriptionState].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SubscriptionStatus {
+    Pending,
+    Approved,
+    Denied,
+    Unsubscribed,
+}
+
+impl From<SubscriptionState> for SubscriptionStatus {
+    fn from(state: SubscriptionState) -> Self {
+        match state {
+            SubscriptionState::Pending => SubscriptionStatus::Pending,
+            SubscriptionState::Approved => SubscriptionStatus::Approved,
+            SubscriptionState::Denied => SubscriptionStatus::Denied,
+            SubscriptionState::Unsubscribed => SubscriptionStatus::Unsubscribed,
+        }
+    }
+}
+
+/// Hands the events of a [MobileClient] to the callbacks of a binding.
+struct Callbacks(Box<dyn ClientCallbacks>);
+
+impl ClientListener for Callbacks {
+    fn on_state(&self, state: ConnectionState) {
+        self.0.on_state(state);
+    }
+
+    fn on_sent(&self, object_id: Uuid, sequence: u64) {
+        self.0.on_sent(object_id.to_string(), sequence);
+    }
+
+    fn on_refused(&self, object_id: Uuid, reason: String) {
+        self.0.on_refused(object_id.to_string(), reason);
+    }
+
+    fn on_subscription(&self, state: SubscriptionState) {
+        self.0.on_subscription(state.into());
+    }
+
+    fn on_error(&self, error: String) {
+        self.0.on_error(error);
+    }
+}
+
+/// A [MobileClient] for bindings, see the [module](self) docs.
+pub struct OspClient {
+    client: MobileClient,
+    hostname: String,
+}
+
+impl OspClient {
+    /// Start connecting to the node of `config`, reporting to `callbacks`.
+    pub fn new(config: ClientConfig, callbacks: Box<dyn ClientCallbacks>) -> Result<Self, ClientError> {
+        let mut builder = OSProtocolClient::builder()
+            .hostname(config.hostname.clone())
+            .private_key(PrivateKey::from_pem(&config.private_key_pem)?);
+        if let Some(token) = &config.invite_token {
+            builder = builder.invite(invite_from_token(token)?);
+        }
+        if let Some(interval) = config.keepalive_interval {
+            builder = builder.keepalive(Keepalive { interval: Some(interval), idle_timeout: config.idle_timeout });
+        }
+        let policy = ReconnectPolicy { initial_backoff: config.initial_backoff, max_backoff: config.max_backoff };
+        let client = MobileClient::start(builder, parse_url(&config.node_url)?, policy, Callbacks(callbacks))?;
+        Ok(OspClient { client, hostname: config.hostname })
+    }
+
+    /// Publish `payload`, encoded as the data type `type_id`, under `topic`
+    /// if given, returning the id of the new object.
+    pub fn publish(&self, type_id: String, payload: Vec<u8>, topic: Option<String>) -> Result<String, ClientError> {
+        let mut envelope = Envelope::new(parse_uuid(&type_id)?, self.hostname.clone(), payload);
+        if let Some(topic) = &topic {
+            envelope = envelope.with_topic(topic);
+        }
+        let object_id = envelope.object_id.to_string();
+        self.client.publish(envelope)?;
+        Ok(object_id)
+    }
+
+    /// Subscribe to objects of the data types `type_ids`.
+    pub fn subscribe(&self, type_ids: Vec<String>) -> Result<(), ClientError> {
+        let data_types = type_ids.iter().map(|type_id| parse_uuid(type_id)).collect::<Result<_, _>>()?;
+        self.client.subscribe(data_types).map_err(ClientError::from)
+    }
+
+    /// See [MobileClient::background].
+    pub fn background(&self) -> Result<(), ClientError> {
+        self.client.background().map_err(ClientError::from)
+    }
+
+    /// See [MobileClient::foreground].
+    pub fn foreground(&self) -> Result<(), ClientError> {
+        self.client.foreground().map_err(ClientError::from)
+    }
+
+    /// See [MobileClient::network_changed].
+    pub fn network_changed(&self) -> Result<(), ClientError> {
+        self.client.network_changed().map_err(ClientError::from)
+    }
+
+    pub fn close(&self) -> Result<(), ClientError> {
+        self.client.close().map_err(ClientError::from)
+    }
+}
+
+fn invalid(message: String) -> ClientError {
+    ClientError::InvalidInput { message }
+}
+
+fn parse_uuid(uuid: &str) -> Result<Uuid, ClientError> {
+    Uuid::parse_str(uuid).map_err(|e| invalid(format!("Invalid UUID {uuid:?}: {e}")))
+}
+
+fn parse_url(url: &str) -> Result<OSPUrl, ClientError> {
+    let parsed = Url::parse(url).map_err(|e| invalid(format!("Invalid node URL {url:?}: {e}")))?;
+    if parsed.scheme() != Scheme::Osp.as_str() {
+        return Err(invalid(format!("Invalid node URL {url:?}: not an osp:// URL")));
+    }
+    match (parsed.host_str(), parsed.port()) {
+        (Some(domain), Some(port)) => Ok(OSPUrl { domain: domain.to_string(), port, scheme: Scheme::Osp }),
+        _ => Err(invalid(format!("Invalid node URL {url:?}: needs a host and a port"))),
+    }
+}
+
+/// Decode an invite token, as issued by a node.
+fn invite_from_token(token: &str) -> Result<Invite, ClientError> {
+    let bytes = BASE64.decode(token.trim()).map_err(|e| invalid(format!("Invalid invite token: {e}")))?;
+    Ok(Invite::deserialize(&mut BytesMut::from(bytes.as_slice()))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use tokio::io;
+
+    use osp_server_sdk::OSProtocolNode;
+
+    use crate::ffi::{ClientCallbacks, ClientConfig, ClientError, OspClient, SubscriptionStatus};
+    use crate::mobile::ConnectionState;
+
+    struct Recorded(Mutex<Sender<String>>);
+
+    impl ClientCallbacks for Recorded {
+        fn on_state(&self, state: ConnectionState) {
+            let _ = self.0.lock().unwrap().send(format!("{state:?}"));
+        }
+
+        fn on_sent(&self, object_id: String, sequence: u64) {
+            let _ = self.0.lock().unwrap().send(format!("sent {object_id} {sequence}"));
+        }
+
+        fn on_refused(&self, object_id: String, _reason: String) {
+            let _ = self.0.lock().unwrap().send(format!("refused {object_id}"));
+        }
+
+        fn on_subscription(&self, status: SubscriptionStatus) {
+            let _ = self.0.lock().unwrap().send(format!("{status:?}"));
+        }
+
+        fn on_error(&self, _message: String) {}
+    }
+
+    #[test]
+    fn test_bindings() -> io::Result<()> {
+        // the 256 byte challenge only fits keys of more than 2048 bits
+        let client_key = osp_server_sdk::crypto::PrivateKey::generate(4096)?;
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57503".parse().unwrap())
+            .hostname("node.test".to_string())
+            .private_key(osp_server_sdk::crypto::PrivateKey::generate(1024)?)
+            .invite_only(true)
+            .build()?;
+        let invite = node.admin().issue_invite("app.test", &client_key.public_key()?.to_pem()?, None)?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.spawn(async move { node.listen().await });
+        std::thread::sleep(Duration::from_millis(100));
+
+        let config = ClientConfig {
+            node_url: "osp://127.0.0.1:57503".to_string(),
+            hostname: "app.test".to_string(),
+            private_key_pem: client_key.to_pem()?,
+            invite_token: Some(osp_server_sdk::invite::to_token(&invite)?),
+            keepalive_interval: None,
+            idle_timeout: None,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        };
+        let invalid = ClientConfig { node_url: "https://node.test".to_string(), ..config.clone() };
+        assert!(matches!(OspClient::new(invalid, Box::new(Recorded(Mutex::new(mpsc::channel().0)))), Err(ClientError::InvalidInput { .. })));
+
+        let (sender, events) = mpsc::channel();
+        let client = OspClient::new(config, Box::new(Recorded(Mutex::new(sender))))?;
+        let next = || events.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!((next(), next()), ("Connecting".to_string(), "Connected".to_string()));
+        assert!(matches!(client.publish("not a uuid".to_string(), Vec::new(), None), Err(ClientError::InvalidInput { .. })));
+        client.subscribe(vec!["7a0f3f0e-60c1-4d55-9a3b-0d0c5d2f8e41".to_string()])?;
+        assert_eq!(next(), "Approved");
+        let object_id = client.publish("7a0f3f0e-60c1-4d55-9a3b-0d0c5d2f8e41".to_string(), b"note".to_vec(), Some("notes".to_string()))?;
+        assert_eq!(next(), format!("sent {object_id} 1"));
+        client.close()?;
+        assert_eq!(next(), "Closed");
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+use std::fs;
+use std::path::Path;
+
+use rsa::{Pkcs1v15Encrypt, RsaPrivateKey};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::DecodePrivateKey;
+
+use tokio::io;
+
+/// The private RSA key a client answers handshake challenges with.
+#[derive(Clone)]
+pub struct PrivateKey(RsaPrivateKey);
+
+impl PrivateKey {
+    /// Parse a PEM encoded key, in PKCS#1 or PKCS#8 format.
+    pub fn from_pem(pem: &str) -> io::Result<Self> {
+        // editors on Windows like to save keys with CRLF line endings
+        let pem = pem.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+        RsaPrivateKey::from_pkcs1_pem(&pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs8_pem(&pem))
+            .map(Self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid private key: {e}")))
+    }
+
+    /// Read a PEM encoded key from the file at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_pem(&fs::read_to_string(path)?)
+    }
+
+    /// Decrypt a handshake challenge, encrypted with RSAES-PKCS1-v1_5.
+    pub(crate) fn decrypt(&self, challenge: &[u8]) -> io::Result<Vec<u8>> {
+        self.0.decrypt(Pkcs1v15Encrypt, challenge)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Unable to decrypt challenge: {e}")))
+    }
+}
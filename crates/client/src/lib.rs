@@ -1 +1,37 @@
+//! # OSP Client SDK
+//!
+//! Connect an application to an Open Syndication Protocol node. An
+//! [OSProtocolClient] performs the handshake as the guest, then pushes typed
+//! objects to the node and subscribes to data types from it:
+//!
+//! ```no_run
+//! # use osp_client_sdk::OSProtocolClient;
+//! # use osp_protocol::{OSPUrl, Scheme};
+//! # async fn run() -> std::io::Result<()> {
+//! let mut client = OSProtocolClient::builder()
+//!     .hostname("app.example.com".to_string())
+//!     .private_key_file("key.pem")?
+//!     .connect_url(OSPUrl { domain: "node.example.com".to_string(), port: 42069, scheme: Scheme::Osp })
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Like a node, a client proves its identity by decrypting a challenge with
+//! its private key. The node finds the matching public key in the `_osp`
+//! DNS record of the client's hostname, or in an invite it issued to the
+//! client, see [OSProtocolClientBuilder::invite].
+//!
+//! Apps on phones, which are suspended in the background and switch
+//! networks, can use the callback based client of the
+//! [mobile] module with the `mobile` feature, bound to Kotlin and Swift
+//! through the [ffi] module with the `ffi` feature.
 
+mod client;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod key;
+#[cfg(feature = "mobile")]
+pub mod mobile;
+
+pub use {client::{OSProtocolClient, OSProtocolClientBuilder}, key::PrivateKey};
@@ -0,0 +1,513 @@
+//! # Mobile Clients
+//!
+//! Apps on phones can't hold on to an [OSProtocolClient] the way a service
+//! does: they have no async runtime of their own, are bound from Kotlin or
+//! Swift, lose their network when switching from Wi-Fi to cellular, and are
+//! suspended in the background. With the `mobile` feature, a
+//! [MobileClient] runs a client on a thread of its own and is driven by
+//! plain method calls, reporting back through a [ClientListener]:
+//!
+//! - the connection is kept up, reconnecting with the backoff of a
+//!   [ReconnectPolicy] when it is lost, and resuming the sequence numbers
+//!   and subscriptions of the last connection.
+//! - [background](MobileClient::background) closes the connection until
+//!   [foreground](MobileClient::foreground) is called, and
+//!   [network_changed](MobileClient::network_changed) reconnects right away
+//!   over the new network rather than waiting for the old connection to
+//!   time out. Objects published meanwhile are queued.
+//! - unless the builder sets a [keepalive](OSProtocolClientBuilder::keepalive),
+//!   the node is pinged at [LOW_POWER_KEEPALIVE], so the radio wakes up as
+//!   rarely as NAT mappings allow. The node pings silent clients on its own
+//!   schedule too.
+//!
+//! Every method takes `&self`, is synchronous and only uses owned types, so
+//! the client can be bound to other languages as an object behind an `Arc`.
+//! Like every build of the client, it uses no OpenSSL, keys are handled in
+//! pure Rust.
+
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use tokio::io;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use uuid::Uuid;
+
+use osp_protocol::{Envelope, Keepalive, OSPUrl};
+use osp_protocol::packet::transfer::{SubscriptionState, TransferPacketHostToGuest};
+
+use crate::client::{OSProtocolClient, OSProtocolClientBuilder};
+
+/// Pings a silent node every 4 minutes, below the TCP timeouts of most
+/// carrier NATs, and gives up on it after 10.
+pub const LOW_POWER_KEEPALIVE: Keepalive = Keepalive {
+    interval: Some(Duration::from_secs(240)),
+    idle_timeout: Some(Duration::from_secs(600)),
+};
+
+/// Where the connection of a [MobileClient] stands.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    /// The connection was lost or couldn't be opened, and is tried again
+    /// after `delay`.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// In the [background](MobileClient::background), without a
+    /// connection.
+    Paused,
+    /// For good, as the client was [closed](MobileClient::close) or the
+    /// node rejected it.
+    Closed,
+}
+
+/// How a [MobileClient] reports back to the app. Callbacks run on the
+/// client's thread, and should hand their work off rather than block it.
+pub trait ClientListener: Send + Sync {
+    fn on_state(&self, state: ConnectionState);
+
+    /// An object was sent to the node with `sequence`.
+    fn on_sent(&self, _object_id: Uuid, _sequence: u64) {}
+
+    /// The node refused an object, or a stream.
+    fn on_refused(&self, _object_id: Uuid, _reason: String) {}
+
+    /// The node answered a subscription request.
+    fn on_subscription(&self, _state: SubscriptionState) {}
+
+    /// Connecting failed or the connection was lost, which is retried
+    /// unless the state moves to [Closed](ConnectionState::Closed).
+    fn on_error(&self, _error: String) {}
+}
+
+/// How long a [MobileClient] waits before reconnecting, doubling with every
+/// failed attempt.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Defaults to 1 second.
+    pub initial_backoff: Duration,
+    /// Defaults to 5 minutes.
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// How long to wait after `attempts` failed attempts.
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+enum Command {
+    Publish(Box<Envelope>),
+    Subscribe(Vec<Uuid>),
+    Background,
+    Foreground,
+    NetworkChanged,
+    Close,
+}
+
+/// An [OSProtocolClient] kept connected on a thread of its own, see the
+/// [module](self) docs. Dropping it closes the connection.
+pub struct MobileClient {
+    commands: UnboundedSender<Command>,
+}
+
+impl MobileClient {
+    /// Connect to the node at `url` with `builder` on a new thread, and keep
+    /// the connection up. Fails if the thread can't be started; connecting
+    /// is reported to `listener`.
+    pub fn start(builder: OSProtocolClientBuilder, url: OSPUrl, policy: ReconnectPolicy, listener: impl ClientListener + 'static) -> io::Result<Self> {
+        let builder = match builder.has_keepalive() {
+            true => builder,
+            false => builder.keepalive(LOW_POWER_KEEPALIVE),
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let session = Session {
+            builder,
+            url,
+            policy,
+            listener: Box::new(listener),
+            commands: receiver,
+            queue: VecDeque::new(),
+            subscriptions: Vec::new(),
+            last_sequence: 0,
+        };
+        thread::Builder::new()
+            .name("osp-client".to_string())
+            .spawn(move || runtime.block_on(session.run()))?;
+        Ok(MobileClient { commands })
+    }
+
+    /// Send `envelope` to the node, once connected. Fails with
+    /// [NotConnected](io::ErrorKind::NotConnected) once the client is
+    /// closed.
+    pub fn publish(&self, envelope: Envelope) -> io::Result<()> {
+        self.send(Command::Publish(Box::new(envelope)))
+    }
+
+    /// Subscribe to objects of `data_types`, on this and every later
+    /// connection, see [OSProtocolClient::subscribe].
+    pub fn subscribe(&self, data_types: Vec<Uuid>) -> io::Result<()> {
+        self.send(Command::Subscribe(data_types))
+    }
+
+    /// The app moved to the background: close the connection until
+    /// [foreground](Self::foreground).
+    pub fn background(&self) -> io::Result<()> {
+        self.send(Command::Background)
+    }
+
+    /// The app is back in the foreground: connect again right away.
+    pub fn foreground(&self) -> io::Result<()> {
+        self.send(Command::Foreground)
+    }
+
+    /// The device switched networks: drop the connection, which likely
+    /// went with the old network, and connect again right away.
+    pub fn network_changed(&self) -> io::Result<()> {
+        self.send(Command::NetworkChanged)
+    }
+
+    /// Close the connection for good.
+    pub fn close(&self) -> io::Result<()> {
+        self.send(Command::Close)
+    }
+
+    fn send(&self, command: Command) -> io::Result<()> {
+        self.commands.send(command).map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "The client is closed"))
+    }
+}
+
+impl Drop for MobileClient {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// Why a connection ended.
+enum Ended {
+    Lost(io::Error),
+    Background,
+    NetworkChanged,
+    Closed,
+}
+
+/// The state of a [MobileClient] kept across connections.
+struct Session {
+    builder: OSProtocolClientBuilder,
+    url: OSPUrl,
+    policy: ReconnectPolicy,
+    listener: Box<dyn ClientListener>,
+    commands: UnboundedReceiver<Command>,
+    /// Objects published while not connected
+    queue: VecDeque<Envelope>,
+    subscriptions: Vec<Uuid>,
+    last_sequence: u64,
+}
+
+impl Session {
+    async fn run(mut self) {
+        let mut attempts = 0;
+        loop {
+            self.listener.on_state(ConnectionState::Connecting);
+            // the builder may resume from a sequence of its own
+            let builder = match self.last_sequence {
+                0 => self.builder.clone(),
+                sequence => self.builder.clone().resume_from(sequence),
+            };
+            let connected = builder.connect_url(self.url.clone()).await;
+            let ended = match connected {
+                Ok(client) => {
+                    info!("Connected to {}", self.url);
+                    attempts = 0;
+                    self.listener.on_state(ConnectionState::Connected);
+                    self.serve(client).await
+                }
+                // rejections won't change by trying again
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    self.listener.on_error(e.to_string());
+                    Ended::Closed
+                }
+                Err(e) => Ended::Lost(e),
+            };
+            let ended = match ended {
+                Ended::Lost(e) => {
+                    warn!("Connection to {} lost: {e}", self.url);
+                    self.listener.on_error(e.to_string());
+                    attempts += 1;
+                    let delay = self.policy.backoff(attempts);
+                    self.listener.on_state(ConnectionState::Reconnecting { attempt: attempts, delay });
+                    match self.wait(Some(delay)).await {
+                        Some(ended) => ended,
+                        None => continue,
+                    }
+                }
+                ended => ended,
+            };
+            match ended {
+                Ended::Background if self.paused().await => attempts = 0,
+                Ended::NetworkChanged => attempts = 0,
+                Ended::Lost(_) => {}
+                Ended::Background | Ended::Closed => break,
+            }
+        }
+        self.listener.on_state(ConnectionState::Closed);
+    }
+
+    /// Wait in the background until the app is in the foreground again,
+    /// returning `false` if the client was closed instead.
+    async fn paused(&mut self) -> bool {
+        self.listener.on_state(ConnectionState::Paused);
+        matches!(self.wait(None).await, Some(Ended::NetworkChanged))
+    }
+
+    /// Wait for `delay`, or until a command ends the wait: returns `None`
+    /// once the delay passed, and how the wait ended otherwise, with
+    /// [Ended::NetworkChanged] to connect right away.
+    async fn wait(&mut self, delay: Option<Duration>) -> Option<Ended> {
+        let sleep = async {
+            match delay {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(sleep);
+        loop {
+            let command = tokio::select! {
+                _ = &mut sleep => return None,
+                command = self.commands.recv() => command,
+            };
+            match command {
+                Some(Command::Publish(envelope)) => self.queue.push_back(*envelope),
+                Some(Command::Subscribe(data_types)) => self.remember(&data_types),
+                Some(Command::Background) if delay.is_some() => return Some(Ended::Background),
+                Some(Command::Background) => {}
+                Some(Command::Foreground | Command::NetworkChanged) => return Some(Ended::NetworkChanged),
+                Some(Command::Close) | None => return Some(Ended::Closed),
+            }
+        }
+    }
+
+    /// Serve `client` until the connection ends: renew the subscriptions,
+    /// send what was queued, then send what is published and read what
+    /// the node sends.
+    async fn serve(&mut self, mut client: OSProtocolClient) -> Ended {
+        if !self.subscriptions.is_empty() {
+            let data_types = self.subscriptions.clone();
+            if let Err(e) = self.subscribe(&mut client, &data_types).await {
+                return Ended::Lost(e);
+            }
+        }
+        while let Some(envelope) = self.queue.pop_front() {
+            if let Err(e) = self.publish(&mut client, envelope).await {
+                return Ended::Lost(e);
+            }
+        }
+        loop {
+            let command = tokio::select! {
+                command = self.commands.recv() => command,
+                // reading is cancel safe, a partly read frame stays buffered
+                packet = client.read_packet() => match packet {
+                    Ok(packet) => {
+                        self.handle(packet);
+                        continue;
+                    }
+                    Err(e) => return Ended::Lost(e),
+                },
+            };
+            let sent = match command {
+                Some(Command::Publish(envelope)) => self.publish(&mut client, *envelope).await,
+                Some(Command::Subscribe(data_types)) => {
+                    self.remember(&data_types);
+                    self.subscribe(&mut client, &data_types).await
+                }
+                Some(Command::Foreground) => Ok(()),
+                Some(Command::Background) => return Ended::Background,
+                Some(Command::NetworkChanged) => return Ended::NetworkChanged,
+                Some(Command::Close) | None => return Ended::Closed,
+            };
+            if let Err(e) = sent {
+                return Ended::Lost(e);
+            }
+        }
+    }
+
+    /// Keep subscribing to `data_types` on later connections.
+    fn remember(&mut self, data_types: &[Uuid]) {
+        for data_type in data_types {
+            if !self.subscriptions.contains(data_type) {
+                self.subscriptions.push(*data_type);
+            }
+        }
+    }
+
+    async fn publish(&mut self, client: &mut OSProtocolClient, envelope: Envelope) -> io::Result<()> {
+        let object_id = envelope.object_id;
+        let sent_before = client.last_sequence();
+        match client.send(envelope.clone()).await {
+            Ok(sequence) => {
+                self.last_sequence = sequence;
+                self.listener.on_sent(object_id, sequence);
+                Ok(())
+            }
+            // the node's preferences exclude it, sending again won't help
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                self.listener.on_refused(object_id, e.to_string());
+                Ok(())
+            }
+            // nothing went out, so send it again on the next connection
+            Err(e) if client.last_sequence() == sent_before => {
+                self.queue.push_front(envelope);
+                Err(e)
+            }
+            // the node may have it already, and sending it again could
+            // deliver it twice
+            Err(e) => {
+                self.last_sequence = client.last_sequence();
+                self.listener.on_error(format!("Object {object_id} may not have reached the node: {e}"));
+                Err(e)
+            }
+        }
+    }
+
+    async fn subscribe(&mut self, client: &mut OSProtocolClient, data_types: &[Uuid]) -> io::Result<()> {
+        match client.subscribe(data_types).await {
+            Ok(state) => {
+                self.listener.on_subscription(state);
+                Ok(())
+            }
+            // tried again on the next connection
+            Err(e) if e.kind() == io::ErrorKind::ResourceBusy => {
+                self.listener.on_error(e.to_string());
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn handle(&self, packet: TransferPacketHostToGuest) {
+        match packet {
+            TransferPacketHostToGuest::Nack { object_id, code, reason } => {
+                self.listener.on_refused(object_id, format!("{code:?}: {}", reason.unwrap_or_default()));
+            }
+            TransferPacketHostToGuest::Subscription { state, .. } => self.listener.on_subscription(state),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use tokio::io;
+
+    use uuid::Uuid;
+
+    use osp_protocol::{Envelope, OSPUrl, Scheme};
+    use osp_protocol::packet::transfer::SubscriptionState;
+    use osp_server_sdk::OSProtocolNode;
+
+    use crate::{OSProtocolClient, PrivateKey};
+    use crate::mobile::{ClientListener, ConnectionState, MobileClient, ReconnectPolicy};
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        State(ConnectionState),
+        Sent(u64),
+        Subscription(SubscriptionState),
+    }
+
+    struct Events(Mutex<Sender<Event>>);
+
+    impl ClientListener for Events {
+        fn on_state(&self, state: ConnectionState) {
+            let _ = self.0.lock().unwrap().send(Event::State(state));
+        }
+
+        fn on_sent(&self, _object_id: Uuid, sequence: u64) {
+            let _ = self.0.lock().unwrap().send(Event::Sent(sequence));
+        }
+
+        fn on_subscription(&self, state: SubscriptionState) {
+            let _ = self.0.lock().unwrap().send(Event::Subscription(state));
+        }
+    }
+
+    fn next(events: &Receiver<Event>) -> Event {
+        events.recv_timeout(Duration::from_secs(10)).unwrap()
+    }
+
+    #[test]
+    fn test_mobile_client() -> io::Result<()> {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(40), policy.max_backoff);
+
+        // the 256 byte challenge only fits keys of more than 2048 bits
+        let client_key = osp_server_sdk::crypto::PrivateKey::generate(4096)?;
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57502".parse().unwrap())
+            .hostname("node.test".to_string())
+            .private_key(osp_server_sdk::crypto::PrivateKey::generate(1024)?)
+            .invite_only(true)
+            .build()?;
+        let invite = node.admin().issue_invite("app.test", &client_key.public_key()?.to_pem()?, None)?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.spawn(async move { node.listen().await });
+        std::thread::sleep(Duration::from_millis(100));
+
+        let builder = OSProtocolClient::builder()
+            .hostname("app.test".to_string())
+            .private_key(PrivateKey::from_pem(&client_key.to_pem()?)?)
+            .invite(invite);
+        let url = OSPUrl { domain: "127.0.0.1".to_string(), port: 57502, scheme: Scheme::Osp };
+        let (sender, events) = mpsc::channel();
+        let client = MobileClient::start(builder, url, policy, Events(Mutex::new(sender)))?;
+        let note = || Envelope::new(Uuid::new_v4(), "app.test".to_string(), b"note".to_vec());
+
+        assert_eq!(next(&events), Event::State(ConnectionState::Connecting));
+        assert_eq!(next(&events), Event::State(ConnectionState::Connected));
+        client.subscribe(vec![Uuid::new_v4()])?;
+        assert_eq!(next(&events), Event::Subscription(SubscriptionState::Approved));
+        client.publish(note())?;
+        assert_eq!(next(&events), Event::Sent(1));
+
+        // objects published in the background are sent once connected
+        // again, continuing the sequence, and the subscription is renewed
+        client.background()?;
+        assert_eq!(next(&events), Event::State(ConnectionState::Paused));
+        client.publish(note())?;
+        client.foreground()?;
+        assert_eq!(next(&events), Event::State(ConnectionState::Connecting));
+        assert_eq!(next(&events), Event::State(ConnectionState::Connected));
+        assert_eq!(next(&events), Event::Subscription(SubscriptionState::Approved));
+        assert_eq!(next(&events), Event::Sent(2));
+
+        client.network_changed()?;
+        assert_eq!(next(&events), Event::State(ConnectionState::Connecting));
+        assert_eq!(next(&events), Event::State(ConnectionState::Connected));
+        assert_eq!(next(&events), Event::Subscription(SubscriptionState::Approved));
+
+        drop(client);
+        assert_eq!(next(&events), Event::State(ConnectionState::Closed));
+        Ok(())
+    }
+}
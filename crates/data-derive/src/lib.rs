@@ -0,0 +1,119 @@
+//! # OSP Data Derive
+//!
+//! `#[derive(OspData)]` for the data types of `osp_data`, see
+//! `osp_data::OspData`.
+
+use proc_macro::TokenStream;
+
+use quote::{format_ident, quote};
+
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Fields, LitInt, LitStr};
+
+use uuid::Uuid;
+
+/// Implement `osp_data::Data` and the serde traits for a struct or enum, see
+/// `osp_data::OspData` for the attributes it takes.
+#[proc_macro_derive(OspData, attributes(osp, serde))]
+pub fn derive_osp_data(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let ident = input.ident.clone();
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&input.generics, "OspData can't be derived for generic types"));
+    }
+    let (id, name, version) = osp_attribute(&input.attrs, &ident)?;
+    let id = id.as_u128();
+    let name = name.unwrap_or_else(|| ident.to_string());
+    let version = version.map(|version| quote! { const VERSION: u16 = #version; });
+
+    // serde derives the impls for a copy of the type, through `remote`, as a
+    // derive can't add derives to the type itself
+    let shadow = format_ident!("__OspData{ident}");
+    let remote = ident.to_string();
+    input.ident = shadow.clone();
+    input.attrs.retain(|attr| attr.path().is_ident("serde"));
+    match &mut input.data {
+        Data::Struct(data) => strip_fields(&mut data.fields),
+        Data::Enum(data) => data.variants.iter_mut().for_each(|variant| {
+            variant.attrs.retain(|attr| attr.path().is_ident("serde"));
+            strip_fields(&mut variant.fields);
+        }),
+        Data::Union(_) => return Err(syn::Error::new_spanned(&ident, "OspData can't be derived for unions")),
+    }
+
+    Ok(quote! {
+        const _: () = {
+            use ::osp_data::__private::serde;
+
+            #[derive(serde::Serialize, serde::Deserialize)]
+            #[serde(crate = "::osp_data::__private::serde", remote = #remote)]
+            #[allow(dead_code, non_camel_case_types)]
+            #input
+
+            impl serde::Serialize for #ident {
+                fn serialize<S: serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+                    #shadow::serialize(self, serializer)
+                }
+            }
+
+            impl<'de> serde::Deserialize<'de> for #ident {
+                fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+                    #shadow::deserialize(deserializer)
+                }
+            }
+
+            impl ::osp_data::Data for #ident {
+                const TYPE_ID: ::osp_data::__private::Uuid = ::osp_data::__private::Uuid::from_u128(#id);
+                const NAME: &'static str = #name;
+                #version
+            }
+        };
+    })
+}
+
+fn strip_fields(fields: &mut Fields) {
+    for field in fields.iter_mut() {
+        field.attrs.retain(|attr| attr.path().is_ident("serde"));
+    }
+}
+
+/// The id, checked to be a valid UUID, and the optional name and version of
+/// the type's `#[osp(...)]` attribute.
+fn osp_attribute(attrs: &[Attribute], ident: &syn::Ident) -> syn::Result<(Uuid, Option<String>, Option<u16>)> {
+    let mut id = None;
+    let mut name = None;
+    let mut version = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("osp")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                let lit = meta.value()?.parse::<LitStr>()?;
+                let uuid = Uuid::parse_str(&lit.value())
+                    .map_err(|e| syn::Error::new_spanned(&lit, format!("invalid data type id: {e}")))?;
+                id = Some(uuid);
+                Ok(())
+            } else if meta.path.is_ident("name") {
+                name = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else if meta.path.is_ident("version") {
+                let lit = meta.value()?.parse::<LitInt>()?;
+                match lit.base10_parse::<u16>()? {
+                    0 => Err(syn::Error::new_spanned(&lit, "data type versions start at 1")),
+                    number => {
+                        version = Some(number);
+                        Ok(())
+                    }
+                }
+            } else {
+                Err(meta.error("expected `id`, `name` or `version`"))
+            }
+        })?;
+    }
+    let id = id.ok_or_else(|| syn::Error::new_spanned(ident, "missing #[osp(id = \"...\")] attribute"))?;
+    Ok((id, name, version))
+}
@@ -0,0 +1,272 @@
+//! # Canonical Encoding
+//!
+//! Signatures over an object need its encoding to be byte-stable: the same
+//! value has to encode to the same bytes on every node, whatever version or
+//! transport encoding it runs. The canonical encoding guarantees that, and is
+//! what objects are signed and verified over, see [Data::canonical_bytes].
+//!
+//! The layout is bincode's with its default options, so signatures made over
+//! bincode encoded values without maps stay valid:
+//!
+//! - integers and floats are fixed width and little endian, `bool` is a `u8`
+//! - strings, byte arrays, sequences and maps are prefixed with their length
+//!   as a `u64`
+//! - `Option` is a `u8` tag followed by the value if it is `1`
+//! - enum variants are written as their index as a `u32`, followed by their
+//!   fields
+//! - struct and tuple fields are written in order, without names
+//!
+//! On top of that, map entries are sorted by their encoded key, so maps
+//! without a defined iteration order, such as `HashMap`, encode the same
+//! wherever they are built.
+//!
+//! [Data::canonical_bytes]: crate::Data::canonical_bytes
+
+use serde::ser::{self, Serialize};
+
+use crate::{Error, Result};
+
+/// Encode `value` canonically, see the [module](self) docs.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut encoder = Encoder::default();
+    value.serialize(&mut encoder)?;
+    Ok(encoder.out)
+}
+
+impl ser::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    out: Vec<u8>,
+}
+
+impl Encoder {
+    fn put_len(&mut self, len: usize) {
+        self.out.extend_from_slice(&(len as u64).to_le_bytes());
+    }
+
+    fn put_variant(&mut self, index: u32) {
+        self.out.extend_from_slice(&index.to_le_bytes());
+    }
+}
+
+macro_rules! fixed {
+    ($($method:ident: $ty:ty),*) => {
+        $(fn $method(self, v: $ty) -> Result<()> {
+            self.out.extend_from_slice(&v.to_le_bytes());
+            Ok(())
+        })*
+    };
+}
+
+impl<'a> ser::Serializer for &'a mut Encoder {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = SeqEncoder<'a>;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = MapEncoder<'a>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fixed!(
+        serialize_i8: i8, serialize_i16: i16, serialize_i32: i32, serialize_i64: i64, serialize_i128: i128,
+        serialize_u8: u8, serialize_u16: u16, serialize_u32: u32, serialize_u64: u64, serialize_u128: u128,
+        serialize_f32: f32, serialize_f64: f64
+    );
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.out.push(v as u8);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.out.extend_from_slice(v.encode_utf8(&mut [0; 4]).as_bytes());
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.put_len(v.len());
+        self.out.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.out.push(0);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        self.out.push(1);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, index: u32, _: &'static str) -> Result<()> {
+        self.put_variant(index);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, index: u32, _: &'static str, value: &T) -> Result<()> {
+        self.put_variant(index);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<SeqEncoder<'a>> {
+        // elements are buffered, so sequences of unknown length work too
+        Ok(SeqEncoder { parent: self, len: 0, elements: Encoder::default() })
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(self, _: &'static str, index: u32, _: &'static str, _: usize) -> Result<Self> {
+        self.put_variant(index);
+        Ok(self)
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<MapEncoder<'a>> {
+        Ok(MapEncoder { parent: self, entries: Vec::new(), key: None })
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(self, _: &'static str, index: u32, _: &'static str, _: usize) -> Result<Self> {
+        self.put_variant(index);
+        Ok(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+struct SeqEncoder<'a> {
+    parent: &'a mut Encoder,
+    len: usize,
+    elements: Encoder,
+}
+
+impl ser::SerializeSeq for SeqEncoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.len += 1;
+        value.serialize(&mut self.elements)
+    }
+
+    fn end(self) -> Result<()> {
+        self.parent.put_len(self.len);
+        self.parent.out.extend_from_slice(&self.elements.out);
+        Ok(())
+    }
+}
+
+struct MapEncoder<'a> {
+    parent: &'a mut Encoder,
+    /// Encoded keys and values, sorted by key when the map ends.
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    key: Option<Vec<u8>>,
+}
+
+impl ser::SerializeMap for MapEncoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(to_vec(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self.key.take().ok_or_else(|| Error::Message("map value without a key".to_string()))?;
+        self.entries.push((key, to_vec(value)?));
+        Ok(())
+    }
+
+    fn end(mut self) -> Result<()> {
+        self.entries.sort();
+        self.parent.put_len(self.entries.len());
+        for (key, value) in self.entries {
+            self.parent.out.extend_from_slice(&key);
+            self.parent.out.extend_from_slice(&value);
+        }
+        Ok(())
+    }
+}
+
+macro_rules! fields {
+    ($($trait:ident: $method:ident($($name:ident),*)),*) => {
+        $(impl ser::$trait for &mut Encoder {
+            type Ok = ();
+            type Error = Error;
+
+            fn $method<T: Serialize + ?Sized>(&mut self, $($name: &'static str,)* value: &T) -> Result<()> {
+                $(let _ = $name;)*
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> Result<()> {
+                Ok(())
+            }
+        })*
+    };
+}
+
+fields!(
+    SerializeTuple: serialize_element(),
+    SerializeTupleStruct: serialize_field(),
+    SerializeTupleVariant: serialize_field(),
+    SerializeStruct: serialize_field(name),
+    SerializeStructVariant: serialize_field(name)
+);
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::canonical;
+    use crate::standard::{FederationAction, FederationRule};
+
+    #[test]
+    fn test_canonical_encoding() -> crate::Result<()> {
+        // without maps, the encoding is bincode's
+        let rules = vec![FederationRule { host: "peer.test".to_string(), action: FederationAction::Deny, updated_at: 7 }];
+        let value = ("signer.test", &rules, Some('é'), (-1i16, 0.5f64));
+        assert_eq!(canonical::to_vec(&value)?, bincode::serialize(&value)?);
+
+        // maps encode the same whatever order they were built in
+        let forward: HashMap<String, u32> = (0..64).map(|i| (format!("key{i}"), i)).collect();
+        let backward: HashMap<String, u32> = (0..64).rev().map(|i| (format!("key{i}"), i)).collect();
+        assert_eq!(canonical::to_vec(&forward)?, canonical::to_vec(&backward)?);
+        Ok(())
+    }
+}
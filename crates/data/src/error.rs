@@ -1,50 +1,66 @@
-use serde::{de, ser};
 use std::fmt::{self, Display};
+use std::io;
+
+use uuid::Uuid;
+
+use osp_protocol::PayloadFormat;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-// This is a bare-bones implementation. A real library would provide additional
-// information in its error type, for example the line and column at which the
-// error occurred, the byte offset into the input, or the current key being
-// processed.
 #[derive(Debug)]
 pub enum Error {
-    // One or more variants that can be created by data structures through the
-    // `ser::Error` and `de::Error` traits. For example the Serialize impl for
-    // Mutex<T> might return an error because the mutex is poisoned, or the
-    // Deserialize impl for a struct may return an error because a required
-    // field is missing.
     Message(String),
+    /// The object could not be encoded, or the payload could not be decoded
+    /// as the requested type.
+    Encoding(bincode::Error),
+    /// The object could not be encoded as, or the payload could not be
+    /// decoded from, CBOR or JSON.
+    Format {
+        format: PayloadFormat,
+        reason: String,
+    },
+    /// The envelope holds a different data type than the one requested.
+    WrongType {
+        expected: Uuid,
+        found: Uuid,
+    },
+    /// The envelope holds another version of the data type than the one
+    /// requested.
+    WrongVersion {
+        type_id: Uuid,
+        expected: u16,
+        found: u16,
+    },
+    /// No data type is registered with the id, see
+    /// [DataTypeRegistry](crate::DataTypeRegistry).
+    UnknownType(Uuid),
+    /// Another data type is registered with the same id and version
+    /// already.
+    DuplicateType {
+        id: Uuid,
+        version: u16,
+        registered: &'static str,
+        name: &'static str,
+    },
+    /// Objects of a data type can't be translated between two of its
+    /// versions, as no chain of upgrades or downgrades leads from one to the
+    /// other.
+    NoTranslation {
+        type_id: Uuid,
+        from: u16,
+        to: u16,
+    },
+}
 
-    // Zero or more variants that can be created directly by the Serializer and
-    // Deserializer without going through `ser::Error` and `de::Error`. These
-    // are specific to the format, in this case JSON.
-    Eof,
-    // Syntax,
-    // ExpectedBoolean,
-    // ExpectedInteger,
-    // ExpectedString,
-    // ExpectedNull,
-    // ExpectedArray,
-    // ExpectedArrayComma,
-    // ExpectedArrayEnd,
-    // ExpectedMap,
-    // ExpectedMapColon,
-    // ExpectedMapComma,
-    // ExpectedMapEnd,
-    // ExpectedEnum,
-    // TrailingCharacters,
-}
-
-impl ser::Error for Error {
-    fn custom<T: Display>(msg: T) -> Self {
-        Error::Message(msg.to_string())
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Self {
+        Error::Encoding(err)
     }
 }
 
-impl de::Error for Error {
-    fn custom<T: Display>(msg: T) -> Self {
-        Error::Message(msg.to_string())
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
     }
 }
 
@@ -52,11 +68,58 @@ impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::Message(msg) => write!(f, "{}", msg),
-            Error::Eof => f.write_str("unexpected end of input"),
-            /* and so forth */
-            _ => unimplemented!(),
+            Error::Encoding(err) => write!(f, "invalid object encoding: {}", err),
+            Error::Format { format, reason } => write!(f, "invalid {:?} object encoding: {}", format, reason),
+            Error::WrongType { expected, found } => write!(f, "expected an object of type {}, found {}", expected, found),
+            Error::WrongVersion { type_id, expected, found } => write!(f, "expected version {} of data type {}, found version {}", expected, type_id, found),
+            Error::UnknownType(id) => write!(f, "unknown data type {}", id),
+            Error::DuplicateType { id, version, registered, name } => write!(f, "can't register {} as version {} of data type {}, {} is registered as it", name, version, id, registered),
+            Error::NoTranslation { type_id, from, to } => write!(f, "can't translate data type {} from version {} to {}", type_id, from, to),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Why a [DataHandler](crate::DataHandler) failed on an object. Nodes refuse
+/// objects their handlers fail on, sending the error back to the peer as the
+/// reason.
+#[derive(Debug)]
+pub enum HandlerError {
+    /// The object is unacceptable to the application, e.g. it refers to
+    /// something that doesn't exist.
+    Rejected(String),
+    /// The handler couldn't process the object, e.g. because a database it
+    /// writes to is unavailable.
+    Failed(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl HandlerError {
+    pub fn rejected(reason: impl Into<String>) -> Self {
+        HandlerError::Rejected(reason.into())
+    }
+}
+
+impl From<io::Error> for HandlerError {
+    fn from(err: io::Error) -> Self {
+        HandlerError::Failed(Box::new(err))
+    }
+}
+
+/// Objects that can't be decoded are rejected.
+impl From<Error> for HandlerError {
+    fn from(err: Error) -> Self {
+        HandlerError::Rejected(err.to_string())
+    }
+}
+
+impl Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandlerError::Rejected(reason) => write!(f, "{}", reason),
+            HandlerError::Failed(err) => write!(f, "handler failed: {}", err),
         }
     }
 }
 
-impl std::error::Error for Error {}
\ No newline at end of file
+impl std::error::Error for HandlerError {}
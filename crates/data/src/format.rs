@@ -0,0 +1,164 @@
+//! # Wire Formats
+//!
+//! Payloads are encoded with bincode by default, and can be encoded as CBOR
+//! or JSON for implementations that don't share the Rust types, see
+//! [PayloadFormat]. Each format is a [WireFormat], and [encode] and
+//! [decode] pick one by the [PayloadFormat] an envelope names:
+//!
+//! ```
+//! use osp_data::format::{self, Json, WireFormat};
+//! use osp_protocol::PayloadFormat;
+//!
+//! let payload = Json::encode(&vec!["a", "b"])?;
+//! assert_eq!(payload, br#"["a","b"]"#);
+//! let cbor = format::transcode(&payload, PayloadFormat::Json, PayloadFormat::Cbor)?;
+//! assert_eq!(format::decode::<Vec<String>>(PayloadFormat::Cbor, &cbor)?, ["a", "b"]);
+//! # Ok::<(), osp_data::Error>(())
+//! ```
+//!
+//! Bincode payloads can only be read knowing their type, so only a
+//! [DataTypeRegistry](crate::DataTypeRegistry) can re-encode them, see
+//! [transcode](crate::DataTypeRegistry::transcode).
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub use osp_protocol::PayloadFormat;
+
+use crate::{Error, Result};
+
+/// A format payloads are encoded in.
+pub trait WireFormat {
+    /// The id of the format on the wire.
+    const FORMAT: PayloadFormat;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T>;
+}
+
+/// [bincode](https://github.com/bincode-org/bincode) with its default
+/// options, compact but not self-describing.
+pub struct Bincode;
+
+/// [CBOR](https://cbor.io), as written by ciborium.
+pub struct Cbor;
+
+/// JSON, as written by serde_json.
+pub struct Json;
+
+impl WireFormat for Bincode {
+    const FORMAT: PayloadFormat = PayloadFormat::Bincode;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(payload)?)
+    }
+}
+
+impl WireFormat for Cbor {
+    const FORMAT: PayloadFormat = PayloadFormat::Cbor;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        ciborium::into_writer(value, &mut payload).map_err(|e| invalid(Self::FORMAT, e))?;
+        Ok(payload)
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+        ciborium::from_reader(payload).map_err(|e| invalid(Self::FORMAT, e))
+    }
+}
+
+impl WireFormat for Json {
+    const FORMAT: PayloadFormat = PayloadFormat::Json;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| invalid(Self::FORMAT, e))
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+        serde_json::from_slice(payload).map_err(|e| invalid(Self::FORMAT, e))
+    }
+}
+
+/// Encode `value` in `format`.
+pub fn encode<T: Serialize + ?Sized>(format: PayloadFormat, value: &T) -> Result<Vec<u8>> {
+    match format {
+        PayloadFormat::Bincode => Bincode::encode(value),
+        PayloadFormat::Cbor => Cbor::encode(value),
+        PayloadFormat::Json => Json::encode(value),
+    }
+}
+
+/// Decode a `T` from `payload`, encoded in `format`.
+pub fn decode<T: DeserializeOwned>(format: PayloadFormat, payload: &[u8]) -> Result<T> {
+    match format {
+        PayloadFormat::Bincode => Bincode::decode(payload),
+        PayloadFormat::Cbor => Cbor::decode(payload),
+        PayloadFormat::Json => Json::decode(payload),
+    }
+}
+
+/// Re-encode `payload` from the format `from` in `to`, without knowing its
+/// type. Fails for bincode payloads, which can't be read without it.
+pub fn transcode(payload: &[u8], from: PayloadFormat, to: PayloadFormat) -> Result<Vec<u8>> {
+    if from == to {
+        return Ok(payload.to_vec());
+    }
+    if !from.is_self_describing() {
+        return Err(Error::Message(format!("{from:?} payloads can't be transcoded without their type")));
+    }
+    encode(to, &decode::<ciborium::Value>(from, payload)?)
+}
+
+fn invalid(format: PayloadFormat, err: impl ToString) -> Error {
+    Error::Format {
+        format,
+        reason: err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::Result;
+    use crate::format::{self, PayloadFormat};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Post {
+        title: String,
+        tags: Vec<String>,
+        views: Option<u64>,
+        meta: BTreeMap<String, i32>,
+    }
+
+    #[test]
+    fn test_formats() -> Result<()> {
+        let post = Post {
+            title: "Hello".to_string(),
+            tags: vec!["rust".to_string()],
+            views: None,
+            meta: BTreeMap::from([("likes".to_string(), 3)]),
+        };
+        for format in PayloadFormat::ALL {
+            assert_eq!(format::decode::<Post>(format, &format::encode(format, &post)?)?, post);
+        }
+        let json = format::encode(PayloadFormat::Json, &post)?;
+        assert_eq!(json, br#"{"title":"Hello","tags":["rust"],"views":null,"meta":{"likes":3}}"#);
+
+        let cbor = format::transcode(&json, PayloadFormat::Json, PayloadFormat::Cbor)?;
+        assert_eq!(format::decode::<Post>(PayloadFormat::Cbor, &cbor)?, post);
+        assert_eq!(format::transcode(&cbor, PayloadFormat::Cbor, PayloadFormat::Json)?, json);
+        let bincode = format::encode(PayloadFormat::Bincode, &post)?;
+        assert!(format::transcode(&bincode, PayloadFormat::Bincode, PayloadFormat::Json).is_err());
+        assert!(format::decode::<Post>(PayloadFormat::Json, &cbor).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! # Content Hashing
+//!
+//! The one way objects are hashed, so that every subsystem comparing
+//! objects by content, such as deduplication, sync inventories, blob
+//! storage and delivery tracking, agrees on their identity. Hashes are
+//! BLAKE3.
+//!
+//! [ObjectId::derive] names an object after its data type and canonical
+//! encoding, so the same content always gets the same id on every node. This
+//! is separate from the random `object_id` of an [Envelope]: publishing the
+//! same content twice still makes two objects.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use uuid::{Builder, Uuid};
+
+use osp_protocol::Envelope;
+
+use crate::Error;
+
+/// Context string of the BLAKE3 key derivation object ids are computed with,
+/// so they never collide with hashes made for other purposes.
+const OBJECT_ID_CONTEXT: &str = "osp 2024 object id v1";
+
+/// BLAKE3 hash of some bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ContentHash([u8; 32]);
+
+impl ContentHash {
+    pub fn of(bytes: &[u8]) -> Self {
+        ContentHash(*blake3::hash(bytes).as_bytes())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+/// Lowercase hex.
+impl Display for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl FromStr for ContentHash {
+    type Err = Error;
+
+    fn from_str(hex: &str) -> Result<Self, Error> {
+        let invalid = || Error::Message(format!("invalid content hash {hex}"));
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(ContentHash(bytes))
+    }
+}
+
+/// Id of an object's content, see the [module](self) docs. It is a version
+/// 8 UUID, so it fits wherever object ids are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectId(Uuid);
+
+impl ObjectId {
+    /// The id of an object of the data type `type_id`, whose
+    /// [canonical](crate::canonical) encoding is `canonical_bytes`.
+    pub fn derive(type_id: Uuid, canonical_bytes: &[u8]) -> Self {
+        let mut hasher = blake3::Hasher::new_derive_key(OBJECT_ID_CONTEXT);
+        hasher.update(type_id.as_bytes());
+        hasher.update(canonical_bytes);
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&hasher.finalize().as_bytes()[..16]);
+        ObjectId(Builder::from_custom_bytes(bytes).into_uuid())
+    }
+
+    /// The id of the object in `envelope`, for objects of types this node
+    /// can't decode. The payload is taken as its canonical encoding, which
+    /// holds for objects encoded by [Data::encode](crate::Data::encode) that
+    /// contain no maps.
+    pub fn of_envelope(envelope: &Envelope) -> Self {
+        Self::derive(envelope.type_id, &envelope.payload)
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.0
+    }
+}
+
+impl From<ObjectId> for Uuid {
+    fn from(id: ObjectId) -> Self {
+        id.0
+    }
+}
+
+impl Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use crate::Data;
+    use crate::hash::{ContentHash, ObjectId};
+
+    #[derive(Serialize, Deserialize)]
+    struct Note {
+        text: String,
+    }
+
+    crate::impl_data!(Note, "5e0cbd52-2d38-4a3e-9bb0-7a4d1b1f3c11");
+
+    #[test]
+    fn test_object_id() -> crate::Result<()> {
+        let note = Note { text: "hello".to_string() };
+        let id = note.object_id()?;
+        assert_eq!(id, Note { text: "hello".to_string() }.object_id()?);
+        assert_ne!(id, Note { text: "hello!".to_string() }.object_id()?);
+        assert_eq!(id.uuid().get_version_num(), 8);
+        // the type is part of the identity
+        assert_ne!(id, ObjectId::derive(uuid::Uuid::nil(), &note.canonical_bytes()?));
+        assert_eq!(id, ObjectId::of_envelope(&note.to_envelope("origin.test".to_string())?));
+
+        let hash = ContentHash::of(b"hello");
+        assert_eq!(hash.to_string().parse::<ContentHash>()?, hash);
+        assert!("not a hash".parse::<ContentHash>().is_err());
+        Ok(())
+    }
+}
@@ -1,25 +1,346 @@
+//! # OSP Data
+//!
+//! Typed objects carried in the payload of an [Envelope]. A data type is any
+//! serde type implementing [Data], which ties it to the id nodes use to tell
+//! data types apart on the wire. Derive it with [OspData](derive@OspData), or
+//! use [impl_data] for types that derive the serde traits themselves.
+
+use std::future::Future;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
 use uuid::Uuid;
 
-#[cfg(feature = "serde")]
-pub mod serde;
-mod ser;
+use osp_protocol::{Envelope, PayloadFormat, TypeDescriptor};
+use osp_protocol::packet::transfer::AckStatus;
+
+// lets the derive refer to this crate as ::osp_data
+extern crate self as osp_data;
+
+pub mod canonical;
 mod error;
+pub mod format;
+pub mod hash;
+pub mod registry;
+pub mod signed;
+pub mod standard;
+
+pub use error::{Error, HandlerError, Result};
+pub use hash::{ContentHash, ObjectId};
+pub use registry::{DataTypeRegistry, DecodedObject};
+pub use signed::SignedData;
+
+/// Implement [Data] for a struct or enum, along with the serde traits it
+/// needs, so the type must not derive `Serialize` and `Deserialize` itself.
+/// The data type id is given with `#[osp(id = "...")]` and checked to be a
+/// valid UUID at compile time. The name defaults to the name of the type and
+/// can be set with `#[osp(name = "...")]`, and the [version](Data::VERSION)
+/// with `#[osp(version = 2)]`. `#[serde(...)]` attributes are passed on to
+/// serde.
+///
+/// ```
+/// use osp_data::{Data, OspData};
+///
+/// #[derive(OspData)]
+/// #[osp(id = "5e0cbd52-2d38-4a3e-9bb0-7a4d1b1f3c11", name = "example.note")]
+/// struct Note {
+///     text: String,
+///     #[serde(default)]
+///     tags: Vec<String>,
+/// }
+///
+/// assert_eq!(Note::NAME, "example.note");
+/// ```
+///
+/// An invalid id doesn't compile:
+///
+/// ```compile_fail
+/// #[derive(osp_data::OspData)]
+/// #[osp(id = "5e0cbd52-2d38-4a3e-9bb0")]
+/// struct Broken;
+/// ```
+pub use osp_data_derive::OspData;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use serde;
+    pub use uuid::{uuid, Uuid};
+}
+
+/// A type that can be syndicated as the payload of an [Envelope].
+pub trait Data: Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// Id of the data type, carried in the envelope of every object of the
+    /// type. Must never change once objects of the type have been published.
+    const TYPE_ID: Uuid;
+    /// Human readable name of the data type.
+    const NAME: &'static str;
+    /// Version of the data type's encoding, carried in the envelope next to
+    /// its id. Changing the fields of a type means giving the new layout a
+    /// new version, so peers still on an older one can be told apart, see
+    /// [registry] for translating between versions.
+    const VERSION: u16 = 1;
+
+    /// Machine-readable description of the type, shared with peers that ask
+    /// about it. Types that don't override this describe only their id and
+    /// name.
+    fn descriptor() -> TypeDescriptor {
+        TypeDescriptor {
+            type_id: Self::TYPE_ID,
+            name: Self::NAME.to_string(),
+            description: None,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Encode the object byte-stably, for signing it or verifying its
+    /// signature, see [canonical].
+    fn canonical_bytes(&self) -> Result<Vec<u8>> {
+        canonical::to_vec(self)
+    }
+
+    /// The id of the object's content, the same on every node, see
+    /// [hash](crate::hash).
+    fn object_id(&self) -> Result<ObjectId> {
+        Ok(ObjectId::derive(Self::TYPE_ID, &self.canonical_bytes()?))
+    }
+
+    /// Encode the object as an envelope payload, with bincode.
+    fn encode(&self) -> Result<Vec<u8>> {
+        self.encode_as(PayloadFormat::Bincode)
+    }
+
+    /// Decode an object from an envelope payload encoded with bincode.
+    fn decode(payload: &[u8]) -> Result<Self> {
+        Self::decode_as(PayloadFormat::Bincode, payload)
+    }
+
+    /// Encode the object as an envelope payload in `format`, see
+    /// [format](crate::format).
+    fn encode_as(&self, format: PayloadFormat) -> Result<Vec<u8>> {
+        format::encode(format, self)
+    }
+
+    /// Decode an object from an envelope payload encoded in `format`.
+    fn decode_as(format: PayloadFormat, payload: &[u8]) -> Result<Self> {
+        format::decode(format, payload)
+    }
+
+    /// Wrap the object in a new envelope originating from `origin`.
+    fn to_envelope(&self, origin: String) -> Result<Envelope> {
+        self.to_envelope_as(origin, PayloadFormat::Bincode)
+    }
+
+    /// Wrap the object, encoded in `format`, in a new envelope originating
+    /// from `origin`.
+    fn to_envelope_as(&self, origin: String, format: PayloadFormat) -> Result<Envelope> {
+        Ok(Envelope::new(Self::TYPE_ID, origin, self.encode_as(format)?)
+            .with_type_version(Self::VERSION)
+            .with_format(format))
+    }
+
+    /// Decode the object held by `envelope`.
+    fn from_envelope(envelope: &Envelope) -> Result<Self> {
+        if envelope.type_id != Self::TYPE_ID {
+            return Err(Error::WrongType {
+                expected: Self::TYPE_ID,
+                found: envelope.type_id,
+            });
+        }
+        if envelope.type_version != Self::VERSION {
+            return Err(Error::WrongVersion {
+                type_id: Self::TYPE_ID,
+                expected: Self::VERSION,
+                found: envelope.type_version,
+            });
+        }
+        Self::decode_as(envelope.format, &envelope.payload)
+    }
+}
+
+/// Runtime description of a data type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DataType {
+    pub id: Uuid,
+    pub name: &'static str,
+    pub version: u16,
+}
+
+impl DataType {
+    pub fn of<T: Data>() -> Self {
+        DataType {
+            id: T::TYPE_ID,
+            name: T::NAME,
+            version: T::VERSION,
+        }
+    }
+}
+
+/// How a [DataHandler] took in an object. Nodes send it back to the peer
+/// that pushed the object when acknowledging it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HandlerStatus {
+    /// The object was processed.
+    #[default]
+    Processed,
+    /// The object was taken in to be processed later, e.g. queued.
+    Deferred,
+    /// The application had no use for the object.
+    Ignored,
+}
+
+impl From<HandlerStatus> for AckStatus {
+    fn from(status: HandlerStatus) -> Self {
+        match status {
+            HandlerStatus::Processed => AckStatus::Processed,
+            HandlerStatus::Deferred => AckStatus::Deferred,
+            HandlerStatus::Ignored => AckStatus::Ignored,
+        }
+    }
+}
+
+/// Application code run for received objects of the data type `T`. It is
+/// implemented for closures taking the object and its envelope and returning
+/// a future, so a handler can do I/O of its own. Closures return `Ok(())`
+/// once they processed the object, or, wrapped in [WithStatus], a
+/// [HandlerStatus] telling otherwise:
+///
+/// ```
+/// use osp_data::{Data, DataHandler, HandlerError, HandlerStatus, WithStatus};
+/// use osp_protocol::Envelope;
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize)]
+/// # struct Note {
+/// #     text: String,
+/// # }
+/// # osp_data::impl_data!(Note, "5e0cbd52-2d38-4a3e-9bb0-7a4d1b1f3c11");
+///
+/// fn log_notes() -> impl DataHandler<Note> {
+///     WithStatus(|note: Note, envelope: Envelope| async move {
+///         if note.text.is_empty() {
+///             return Err(HandlerError::rejected("Empty note"));
+///         }
+///         if envelope.origin.ends_with(".invalid") {
+///             return Ok(HandlerStatus::Ignored);
+///         }
+///         println!("Note from {}: {}", envelope.origin, note.text);
+///         Ok(HandlerStatus::Processed)
+///     })
+/// }
+/// ```
+pub trait DataHandler<T: Data>: Send + Sync {
+    /// Handle `data`, received in `envelope`, returning how it was taken
+    /// in. Failing refuses the object, see [HandlerError].
+    fn handle(&self, data: T, envelope: Envelope) -> impl Future<Output = std::result::Result<HandlerStatus, HandlerError>> + Send;
+}
+
+impl<T, F, Fut> DataHandler<T> for F
+where
+    T: Data,
+    F: Fn(T, Envelope) -> Fut + Send + Sync,
+    Fut: Future<Output = std::result::Result<(), HandlerError>> + Send,
+{
+    fn handle(&self, data: T, envelope: Envelope) -> impl Future<Output = std::result::Result<HandlerStatus, HandlerError>> + Send {
+        let handled = self(data, envelope);
+        async move { handled.await.map(|()| HandlerStatus::Processed) }
+    }
+}
 
-pub struct DataType<T> {
-    id: Uuid
+/// A [DataHandler] closure returning how it took in the object, instead of
+/// `Ok(())` once it processed it.
+pub struct WithStatus<F>(pub F);
+
+impl<T, F, Fut> DataHandler<T> for WithStatus<F>
+where
+    T: Data,
+    F: Fn(T, Envelope) -> Fut + Send + Sync,
+    Fut: Future<Output = std::result::Result<HandlerStatus, HandlerError>> + Send,
+{
+    fn handle(&self, data: T, envelope: Envelope) -> impl Future<Output = std::result::Result<HandlerStatus, HandlerError>> + Send {
+        (self.0)(data, envelope)
+    }
 }
 
-pub enum Marker {
-    Unit = 0,
-    SeqBegin = 1,
-    SeqEnd = 2,
+/// Implement [Data] for a serde type. Prefer deriving
+/// [OspData](derive@OspData), which checks the id at compile time.
+///
+/// ```
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Note {
+///     text: String,
+/// }
+///
+/// osp_data::impl_data!(Note, "5e0cbd52-2d38-4a3e-9bb0-7a4d1b1f3c11");
+/// ```
+///
+/// The name and the [version](Data::VERSION) can follow the id.
+#[macro_export]
+macro_rules! impl_data {
+    ($ty:ty, $id:literal) => {
+        $crate::impl_data!($ty, $id, stringify!($ty));
+    };
+    ($ty:ty, $id:literal, $name:expr) => {
+        $crate::impl_data!($ty, $id, $name, 1);
+    };
+    ($ty:ty, $id:literal, $name:expr, $version:literal) => {
+        impl $crate::Data for $ty {
+            const TYPE_ID: $crate::__private::Uuid = $crate::__private::uuid!($id);
+            const NAME: &'static str = $name;
+            const VERSION: u16 = $version;
+        }
+    };
 }
 
-// impl From<Marker> for u8 {
-//     fn from(marker: Marker) -> Self {
-//         match marker {
-//             Marker::OptionBegin => 1,
-//             Marker::OptionEnd => 2,
-//         }
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use osp_protocol::Envelope;
+
+    use crate::{Data, Error, OspData};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Note {
+        text: String,
+        tags: Vec<String>,
+    }
+
+    impl_data!(Note, "5e0cbd52-2d38-4a3e-9bb0-7a4d1b1f3c11");
+
+    #[test]
+    fn test_envelope_roundtrip() -> crate::Result<()> {
+        let note = Note { text: "hello".to_string(), tags: vec!["test".to_string()] };
+        let envelope = note.to_envelope("origin.test".to_string())?;
+        assert_eq!(envelope.type_id, Note::TYPE_ID);
+        assert_eq!(Note::from_envelope(&envelope)?, note);
+
+        let other = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), envelope.payload.clone());
+        assert!(matches!(Note::from_envelope(&other), Err(Error::WrongType { .. })));
+        Ok(())
+    }
+
+    #[derive(Debug, PartialEq, OspData)]
+    #[osp(id = "0f5b6f0e-2a4c-4d1b-9e7a-3c8d1f2b6a90", name = "test.reaction")]
+    enum Reaction {
+        Like,
+        Emoji {
+            #[serde(rename = "e")]
+            emoji: String,
+        },
+    }
+
+    #[test]
+    fn test_derive() -> crate::Result<()> {
+        assert_eq!(Reaction::TYPE_ID, Uuid::parse_str("0f5b6f0e-2a4c-4d1b-9e7a-3c8d1f2b6a90").unwrap());
+        assert_eq!(Reaction::NAME, "test.reaction");
+
+        for reaction in [Reaction::Like, Reaction::Emoji { emoji: "🎉".to_string() }] {
+            let envelope = reaction.to_envelope("origin.test".to_string())?;
+            assert_eq!(Reaction::from_envelope(&envelope)?, reaction);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,535 @@
+//! # Registry
+//!
+//! Objects arrive as envelopes carrying only the id of their data type. A
+//! [DataTypeRegistry] maps those ids to the data types an application knows,
+//! so the receive path can decode an object, or run the handlers of its
+//! type, without naming the concrete type:
+//!
+//! ```
+//! # use serde::{Deserialize, Serialize};
+//! use osp_data::{Data, DataTypeRegistry};
+//! use osp_protocol::Envelope;
+//!
+//! # #[derive(Serialize, Deserialize)]
+//! # struct Note {
+//! #     text: String,
+//! # }
+//! # osp_data::impl_data!(Note, "5e0cbd52-2d38-4a3e-9bb0-7a4d1b1f3c11");
+//! # fn main() -> osp_data::Result<()> {
+//! let mut registry = DataTypeRegistry::new();
+//! registry.handler("print", |note: Note, envelope: Envelope| async move {
+//!     println!("Note from {}: {}", envelope.origin, note.text);
+//!     Ok(())
+//! })?;
+//!
+//! let envelope = Note { text: "hello".to_string() }.to_envelope("origin.test".to_string())?;
+//! let object = registry.decode(&envelope)?;
+//! assert_eq!(object.data_type().name, "Note");
+//! assert_eq!(object.downcast_ref::<Note>().map(|note| note.text.as_str()), Some("hello"));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A type can be registered in several [versions](Data::VERSION), each a
+//! Rust type of its own sharing the type's id, with
+//! [upgrade](DataTypeRegistry::upgrade)s and
+//! [downgrade](DataTypeRegistry::downgrade)s between them given by `From`
+//! implementations. Objects are then decoded as the latest version, and
+//! handed to each handler as the version it takes, whichever version they
+//! were sent as, by translating them along the upgrades and downgrades.
+//! Payloads are decoded and translated in the [format](crate::format) their
+//! envelope names, and can be [transcoded](DataTypeRegistry::transcode) to
+//! another.
+
+use std::any::{Any, TypeId};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+use osp_protocol::{Envelope, PayloadFormat, TypeDescriptor};
+
+use crate::{format, Data, DataHandler, DataType, Error, HandlerError, HandlerStatus, Result};
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = std::result::Result<HandlerStatus, HandlerError>> + Send>>;
+/// A [DataHandler] for objects of a type only known at runtime, decoding
+/// them from their envelopes itself.
+pub type ErasedHandler = dyn Fn(Envelope) -> HandlerFuture + Send + Sync;
+
+type Decoder = fn(PayloadFormat, &[u8]) -> Result<Box<dyn Any + Send + Sync>>;
+/// Re-encodes a payload of one version of a type as another, in the same
+/// format.
+type Translator = fn(PayloadFormat, &[u8]) -> Result<Vec<u8>>;
+/// Re-encodes a payload of a type from one format in another.
+type Transcoder = fn(&[u8], PayloadFormat, PayloadFormat) -> Result<Vec<u8>>;
+
+/// An object decoded by a [DataTypeRegistry].
+pub struct DecodedObject {
+    data_type: DataType,
+    value: Box<dyn Any + Send + Sync>,
+}
+
+impl DecodedObject {
+    pub fn data_type(&self) -> DataType {
+        self.data_type
+    }
+
+    /// Whether the object is of the data type `T`.
+    pub fn is<T: Data>(&self) -> bool {
+        self.value.is::<T>()
+    }
+
+    pub fn downcast_ref<T: Data>(&self) -> Option<&T> {
+        self.value.downcast_ref()
+    }
+
+    /// Take the object as a `T`, or get it back if it is of another type.
+    pub fn downcast<T: Data>(self) -> std::result::Result<T, Self> {
+        match self.value.downcast() {
+            Ok(value) => Ok(*value),
+            Err(value) => Err(DecodedObject { data_type: self.data_type, value }),
+        }
+    }
+}
+
+impl Debug for DecodedObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DecodedObject").field("data_type", &self.data_type).finish_non_exhaustive()
+    }
+}
+
+/// A handler, with the version of the type it takes.
+type RegisteredHandler = (String, u16, Arc<ErasedHandler>);
+
+#[derive(Clone)]
+struct Version {
+    data_type: DataType,
+    /// The Rust type registered for the version, to tell registering the
+    /// same type twice from two types sharing an id and version.
+    rust_type: TypeId,
+    descriptor: TypeDescriptor,
+    decode: Decoder,
+    transcode: Transcoder,
+}
+
+#[derive(Clone, Default)]
+struct Registered {
+    versions: BTreeMap<u16, Version>,
+    /// By the versions they translate from and to
+    translations: HashMap<(u16, u16), Translator>,
+    handlers: Vec<RegisteredHandler>,
+}
+
+impl Registered {
+    fn latest(&self) -> &Version {
+        // types are only added with a version
+        self.versions.values().next_back().unwrap()
+    }
+
+    /// The translations leading from version `from` to `to`, in order,
+    /// taking as few steps as possible.
+    fn path(&self, from: u16, to: u16) -> Option<Vec<Translator>> {
+        let mut previous = HashMap::from([(from, None)]);
+        let mut queue = VecDeque::from([from]);
+        while let Some(version) = queue.pop_front() {
+            if version == to {
+                let mut path = Vec::new();
+                let mut at = to;
+                while let Some(&Some(before)) = previous.get(&at) {
+                    path.push(self.translations[&(before, at)]);
+                    at = before;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for &(_, next) in self.translations.keys().filter(|(start, _)| *start == version) {
+                if let Entry::Vacant(entry) = previous.entry(next) {
+                    entry.insert(Some(version));
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The data types an application knows, in each of their versions, and the
+/// handlers of each, keyed by data type id.
+#[derive(Clone, Default)]
+pub struct DataTypeRegistry {
+    types: HashMap<Uuid, Registered>,
+}
+
+impl DataTypeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the data type `T`, as the version [T::VERSION](Data::VERSION)
+    /// of its type. Registering it again does nothing, but registering
+    /// another Rust type as the same version of the type fails.
+    pub fn register<T: Data>(&mut self) -> Result<&mut Self> {
+        self.entry::<T>()?;
+        Ok(self)
+    }
+
+    /// Register the versions `Old` and `New` of a data type, translating
+    /// objects from `Old` to the later `New` with its `From` implementation.
+    pub fn upgrade<Old: Data, New: Data + From<Old>>(&mut self) -> Result<&mut Self> {
+        check_versions::<Old, New>()?;
+        self.entry::<Old>()?;
+        self.entry::<New>()?.translations.insert((Old::VERSION, New::VERSION), |format, payload| New::from(Old::decode_as(format, payload)?).encode_as(format));
+        Ok(self)
+    }
+
+    /// Register the versions `New` and `Old` of a data type, translating
+    /// objects from `New` back to the earlier `Old` with its `From`
+    /// implementation, for peers that only understand `Old`.
+    pub fn downgrade<New: Data, Old: Data + From<New>>(&mut self) -> Result<&mut Self> {
+        check_versions::<Old, New>()?;
+        self.entry::<Old>()?;
+        self.entry::<New>()?.translations.insert((New::VERSION, Old::VERSION), |format, payload| Old::from(New::decode_as(format, payload)?).encode_as(format));
+        Ok(self)
+    }
+
+    /// Run `handler` for objects of the data type `T` when they are
+    /// [dispatched](Self::dispatch), registering `T` if it isn't yet. `id`
+    /// identifies the handler.
+    pub fn handler<T: Data, H: DataHandler<T> + 'static>(&mut self, id: &str, handler: H) -> Result<&mut Self> {
+        let handler = Arc::new(handler);
+        self.entry::<T>()?.handlers.push((id.to_string(), T::VERSION, Arc::new(move |envelope: Envelope| -> HandlerFuture {
+            let handler = handler.clone();
+            Box::pin(async move { handler.handle(T::from_envelope(&envelope)?, envelope).await })
+        })));
+        Ok(self)
+    }
+
+    fn entry<T: Data>(&mut self) -> Result<&mut Registered> {
+        let registered = self.types.entry(T::TYPE_ID).or_default();
+        let version = registered.versions.entry(T::VERSION).or_insert_with(|| Version {
+            data_type: DataType::of::<T>(),
+            rust_type: TypeId::of::<T>(),
+            descriptor: T::descriptor(),
+            decode: |format, payload| Ok(Box::new(T::decode_as(format, payload)?)),
+            transcode: |payload, from, to| T::decode_as(from, payload)?.encode_as(to),
+        });
+        if version.rust_type != TypeId::of::<T>() {
+            return Err(Error::DuplicateType {
+                id: T::TYPE_ID,
+                version: T::VERSION,
+                registered: version.data_type.name,
+                name: T::NAME,
+            });
+        }
+        Ok(registered)
+    }
+
+    fn registered(&self, type_id: Uuid) -> Result<&Registered> {
+        self.types.get(&type_id).ok_or(Error::UnknownType(type_id))
+    }
+
+    /// The latest registered version of the data type with the id
+    /// `type_id`.
+    pub fn get(&self, type_id: Uuid) -> Option<DataType> {
+        self.types.get(&type_id).map(|registered| registered.latest().data_type)
+    }
+
+    /// The version `version` of the data type with the id `type_id`.
+    pub fn version(&self, type_id: Uuid, version: u16) -> Option<DataType> {
+        self.types.get(&type_id)?.versions.get(&version).map(|version| version.data_type)
+    }
+
+    /// The registered versions of the data type `type_id`, from the
+    /// earliest.
+    pub fn versions(&self, type_id: Uuid) -> impl Iterator<Item = u16> + '_ {
+        self.types.get(&type_id).into_iter().flat_map(|registered| registered.versions.keys().copied())
+    }
+
+    pub fn contains(&self, type_id: Uuid) -> bool {
+        self.types.contains_key(&type_id)
+    }
+
+    /// The latest version of each registered type.
+    pub fn types(&self) -> impl Iterator<Item = DataType> + '_ {
+        self.types.values().map(|registered| registered.latest().data_type)
+    }
+
+    /// The descriptions of the latest version of each registered type, see
+    /// [Data::descriptor].
+    pub fn descriptors(&self) -> impl Iterator<Item = &TypeDescriptor> {
+        self.types.values().map(|registered| &registered.latest().descriptor)
+    }
+
+    /// The handlers of the data type `type_id`, with their ids and the
+    /// version of the type they take, in the order they were registered.
+    pub fn handlers(&self, type_id: Uuid) -> impl Iterator<Item = (&str, u16, &Arc<ErasedHandler>)> {
+        self.types.get(&type_id).into_iter()
+            .flat_map(|registered| registered.handlers.iter().map(|(id, version, handler)| (id.as_str(), *version, handler)))
+    }
+
+    /// Translate the object held by `envelope` to the version `version` of
+    /// its type.
+    pub fn translate(&self, envelope: &Envelope, version: u16) -> Result<Envelope> {
+        if envelope.type_version == version {
+            return Ok(envelope.clone());
+        }
+        let registered = self.registered(envelope.type_id)?;
+        let no_translation = || Error::NoTranslation { type_id: envelope.type_id, from: envelope.type_version, to: version };
+        let mut payload = envelope.payload.clone();
+        for translate in registered.path(envelope.type_version, version).ok_or_else(no_translation)? {
+            payload = translate(envelope.format, &payload)?;
+        }
+        Ok(Envelope {
+            type_version: version,
+            payload,
+            ..envelope.clone()
+        })
+    }
+
+    /// Translate the object held by `envelope` for a peer understanding the
+    /// versions of its type up to `version`: objects of a later version are
+    /// translated to the latest version before it they can be, others are
+    /// left as they are.
+    pub fn translate_for(&self, envelope: &Envelope, version: u16) -> Result<Envelope> {
+        if envelope.type_version <= version {
+            return Ok(envelope.clone());
+        }
+        let registered = self.registered(envelope.type_id)?;
+        registered.versions.range(..=version).rev()
+            .find_map(|(&earlier, _)| self.translate(envelope, earlier).ok())
+            .ok_or(Error::NoTranslation { type_id: envelope.type_id, from: envelope.type_version, to: version })
+    }
+
+    /// Re-encode the object held by `envelope` in `format`. Objects in CBOR
+    /// or JSON are transcoded whether their type is registered or not,
+    /// objects in bincode only if the version they are of is.
+    pub fn transcode(&self, envelope: &Envelope, format: PayloadFormat) -> Result<Envelope> {
+        if envelope.format == format {
+            return Ok(envelope.clone());
+        }
+        let payload = match self.types.get(&envelope.type_id).and_then(|registered| registered.versions.get(&envelope.type_version)) {
+            Some(version) => (version.transcode)(&envelope.payload, envelope.format, format)?,
+            None if envelope.format.is_self_describing() => format::transcode(&envelope.payload, envelope.format, format)?,
+            None => return Err(Error::UnknownType(envelope.type_id)),
+        };
+        Ok(Envelope {
+            format,
+            payload,
+            ..envelope.clone()
+        })
+    }
+
+    /// Decode the object held by `envelope` as the latest registered
+    /// version of its type, translating it if it is of another.
+    pub fn decode(&self, envelope: &Envelope) -> Result<DecodedObject> {
+        let latest = self.registered(envelope.type_id)?.latest();
+        let envelope = self.translate(envelope, latest.data_type.version)?;
+        Ok(DecodedObject {
+            data_type: latest.data_type,
+            value: (latest.decode)(envelope.format, &envelope.payload)?,
+        })
+    }
+
+    /// Run the handlers of the type of `envelope` one after the other, each
+    /// with the object translated to the version it takes, returning the
+    /// errors of those that failed. Objects that can't be decoded or
+    /// translated fail the handlers.
+    pub async fn dispatch(&self, envelope: &Envelope) -> Result<Vec<HandlerError>> {
+        self.registered(envelope.type_id)?;
+        let mut errors = Vec::new();
+        for (_, version, handler) in self.handlers(envelope.type_id) {
+            let result = match self.translate(envelope, version) {
+                Ok(envelope) => handler(envelope).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                errors.push(e);
+            }
+        }
+        Ok(errors)
+    }
+}
+
+/// Check that `Old` and `New` are an earlier and a later version of the same
+/// data type.
+fn check_versions<Old: Data, New: Data>() -> Result<()> {
+    if Old::TYPE_ID != New::TYPE_ID {
+        return Err(Error::WrongType { expected: Old::TYPE_ID, found: New::TYPE_ID });
+    }
+    if Old::VERSION >= New::VERSION {
+        return Err(Error::Message(format!(
+            "version {} of data type {} must come before version {}", Old::VERSION, Old::TYPE_ID, New::VERSION
+        )));
+    }
+    Ok(())
+}
+
+impl Debug for DataTypeRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.types.values().flat_map(|registered| registered.versions.values().map(|version| version.data_type))).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Waker};
+
+    use serde::{Deserialize, Serialize};
+
+    use uuid::Uuid;
+
+    use osp_protocol::{Envelope, PayloadFormat};
+
+    use crate::{impl_data, Data, DataTypeRegistry, Error, HandlerError};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Note {
+        text: String,
+    }
+
+    impl_data!(Note, "5e0cbd52-2d38-4a3e-9bb0-7a4d1b1f3c11");
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Impostor {
+        text: String,
+    }
+
+    impl_data!(Impostor, "5e0cbd52-2d38-4a3e-9bb0-7a4d1b1f3c11");
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Like;
+
+    impl_data!(Like, "0f5b6f0e-2a4c-4d1b-9e7a-3c8d1f2b6a90");
+
+    // the handlers don't await anything
+    fn ready<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future is pending"),
+        }
+    }
+
+    #[test]
+    fn test_registry() -> crate::Result<()> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = DataTypeRegistry::new();
+        registry.register::<Like>()?
+            .handler("record", {
+                let seen = seen.clone();
+                move |note: Note, _: Envelope| {
+                    seen.lock().unwrap().push(note.text);
+                    async { Ok(()) }
+                }
+            })?
+            .handler("picky", |note: Note, _: Envelope| async move {
+                match note.text.is_empty() {
+                    true => Err(HandlerError::rejected("Empty note")),
+                    false => Ok(()),
+                }
+            })?;
+        assert!(registry.register::<Note>().is_ok());
+        assert!(matches!(registry.register::<Impostor>(), Err(Error::DuplicateType { registered: "Note", .. })));
+        assert_eq!(registry.get(Like::TYPE_ID).map(|data_type| data_type.name), Some("Like"));
+        assert_eq!(registry.handlers(Note::TYPE_ID).map(|(id, ..)| id).collect::<Vec<_>>(), ["record", "picky"]);
+
+        let note = Note { text: "a".to_string() }.to_envelope("origin.test".to_string())?;
+        let object = registry.decode(&note)?;
+        assert!(object.is::<Note>() && !object.is::<Like>());
+        let object = object.downcast::<Like>().unwrap_err();
+        assert_eq!(object.downcast::<Note>().unwrap(), Note { text: "a".to_string() });
+
+        assert!(ready(registry.dispatch(&note))?.is_empty());
+        let empty = Note { text: String::new() }.to_envelope("origin.test".to_string())?;
+        assert!(matches!(ready(registry.dispatch(&empty))?[..], [HandlerError::Rejected(_)]));
+        assert_eq!(*seen.lock().unwrap(), ["a", ""]);
+        assert!(ready(registry.dispatch(&Like.to_envelope("origin.test".to_string())?))?.is_empty());
+
+        let unknown = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), note.payload);
+        assert!(matches!(registry.decode(&unknown), Err(Error::UnknownType(_))));
+        assert!(matches!(ready(registry.dispatch(&unknown)), Err(Error::UnknownType(_))));
+        Ok(())
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct NoteV2 {
+        text: String,
+        tags: Vec<String>,
+    }
+
+    impl_data!(NoteV2, "5e0cbd52-2d38-4a3e-9bb0-7a4d1b1f3c11", "Note", 2);
+
+    impl From<Note> for NoteV2 {
+        fn from(note: Note) -> Self {
+            NoteV2 { text: note.text, tags: Vec::new() }
+        }
+    }
+
+    impl From<NoteV2> for Note {
+        fn from(note: NoteV2) -> Self {
+            Note { text: note.text }
+        }
+    }
+
+    #[test]
+    fn test_versions() -> crate::Result<()> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = DataTypeRegistry::new();
+        registry.upgrade::<Note, NoteV2>()?
+            .handler("legacy", {
+                let seen = seen.clone();
+                move |note: Note, _: Envelope| {
+                    seen.lock().unwrap().push(note.text);
+                    async { Ok(()) }
+                }
+            })?;
+        assert!(registry.upgrade::<NoteV2, Note>().is_err());
+        assert_eq!(registry.get(Note::TYPE_ID).map(|data_type| data_type.version), Some(2));
+        assert_eq!(registry.versions(Note::TYPE_ID).collect::<Vec<_>>(), [1, 2]);
+
+        let old = Note { text: "old".to_string() }.to_envelope("origin.test".to_string())?;
+        assert_eq!(registry.decode(&old)?.downcast::<NoteV2>().unwrap(), NoteV2 { text: "old".to_string(), tags: Vec::new() });
+        assert!(ready(registry.dispatch(&old))?.is_empty());
+
+        // without a downgrade, the legacy handler can't take new notes
+        let new = NoteV2 { text: "new".to_string(), tags: vec!["tag".to_string()] }.to_envelope("origin.test".to_string())?;
+        assert!(matches!(registry.translate_for(&new, 1), Err(Error::NoTranslation { from: 2, to: 1, .. })));
+        assert!(matches!(ready(registry.dispatch(&new))?[..], [HandlerError::Rejected(_)]));
+        registry.downgrade::<NoteV2, Note>()?;
+        let translated = registry.translate_for(&new, 1)?;
+        assert_eq!((translated.object_id, translated.type_version), (new.object_id, 1));
+        assert_eq!(Note::from_envelope(&translated)?, Note { text: "new".to_string() });
+        assert_eq!(registry.translate_for(&old, 1)?, old);
+        assert!(ready(registry.dispatch(&new))?.is_empty());
+        assert_eq!(*seen.lock().unwrap(), ["old", "new"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcode() -> crate::Result<()> {
+        let mut registry = DataTypeRegistry::new();
+        registry.upgrade::<Note, NoteV2>()?;
+        let old = Note { text: "old".to_string() }.to_envelope("origin.test".to_string())?;
+        let json = registry.transcode(&old, PayloadFormat::Json)?;
+        assert_eq!((json.format, &json.payload[..]), (PayloadFormat::Json, &br#"{"text":"old"}"#[..]));
+        assert_eq!(Note::from_envelope(&json)?, Note { text: "old".to_string() });
+        assert_eq!(registry.transcode(&json, PayloadFormat::Bincode)?, old);
+
+        // translations keep the format
+        let upgraded = registry.translate(&json, 2)?;
+        assert_eq!(upgraded.format, PayloadFormat::Json);
+        assert_eq!(registry.decode(&upgraded)?.downcast::<NoteV2>().unwrap().text, "old");
+
+        // only self-describing payloads of unknown types can be transcoded
+        let like = Like.to_envelope_as("origin.test".to_string(), PayloadFormat::Cbor)?;
+        assert_eq!(Like::from_envelope(&registry.transcode(&like, PayloadFormat::Json)?)?, Like);
+        assert!(matches!(registry.transcode(&Like.to_envelope("origin.test".to_string())?, PayloadFormat::Json), Err(Error::UnknownType(_))));
+        Ok(())
+    }
+}
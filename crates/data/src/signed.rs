@@ -0,0 +1,162 @@
+//! # Signed Data
+//!
+//! Objects are relayed from node to node, so the origin an envelope names
+//! only says where the object claims to come from. A [SignedData] carries an
+//! object along with the hostname of its origin, when it was signed and the
+//! origin's signature over it, made with the key the origin publishes in its
+//! `_osp` DNS record. Any node can check the object was published by its
+//! origin and not altered since, however many nodes it went through.
+//!
+//! `SignedData<T>` is a data type of its own, with an id derived from the id
+//! of `T` (see [signed_type_id]), so nodes taking signed objects of a type
+//! tell them apart from unsigned ones. The signature is made over the
+//! [signed_bytes](SignedData::signed_bytes), which cover the canonical
+//! encoding of the object, so it holds whatever format the payload is sent
+//! in.
+
+use serde::{Deserialize, Serialize};
+
+use uuid::Uuid;
+
+use osp_protocol::{FieldDescriptor, TypeDescriptor};
+
+use crate::{canonical, Data, Result};
+
+/// Prefix of the signed bytes, so a signature over an object can't be passed
+/// off as a signature over anything else.
+const SIGNATURE_CONTEXT: &[u8] = b"osp-signed-data-v1";
+/// The ASCII of "signed" in the bits of the id that aren't its version or
+/// variant.
+const SIGNED_TYPE_MASK: u128 = 0x7369676e_6564_0000_0000_000000000000;
+
+/// Id of the data type `SignedData<T>` for the type `T` with id `type_id`.
+pub const fn signed_type_id(type_id: Uuid) -> Uuid {
+    Uuid::from_u128(type_id.as_u128() ^ SIGNED_TYPE_MASK)
+}
+
+/// Algorithm of the key an object is signed with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SignatureAlgorithm {
+    /// RSASSA-PKCS1-v1_5 with SHA-256.
+    Rsa,
+    Ed25519,
+}
+
+/// An object signed by its origin, see the [module](self) docs.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignedData<T> {
+    /// Hostname of the node that published and signed the object.
+    pub origin: String,
+    /// Unix timestamp (seconds) the object was signed at.
+    pub signed_at: u64,
+    pub algorithm: SignatureAlgorithm,
+    pub data: T,
+    /// The origin's signature, see [SignedData::signed_bytes].
+    pub signature: Vec<u8>,
+}
+
+/// What a [SignedData] attests, with the bytes its signature is over, so it
+/// can be verified without knowing the type of the object.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attestation {
+    pub origin: String,
+    pub signed_at: u64,
+    pub algorithm: SignatureAlgorithm,
+    pub signed_bytes: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl<T: Data> SignedData<T> {
+    /// `data` from `origin`, not signed yet. The signature is made over its
+    /// [signed_bytes](Self::signed_bytes) with a key of `algorithm`.
+    pub fn unsigned(data: T, origin: String, signed_at: u64, algorithm: SignatureAlgorithm) -> Self {
+        SignedData {
+            origin,
+            signed_at,
+            algorithm,
+            data,
+            signature: Vec::new(),
+        }
+    }
+
+    /// The bytes the signature is computed over: the
+    /// [canonically](crate::canonical) encoded origin, timestamp, algorithm,
+    /// id of the type and [canonical bytes](Data::canonical_bytes) of the
+    /// object.
+    pub fn signed_bytes(&self) -> Result<Vec<u8>> {
+        canonical::to_vec(&(
+            SIGNATURE_CONTEXT,
+            &self.origin,
+            self.signed_at,
+            self.algorithm,
+            T::TYPE_ID.as_bytes(),
+            self.data.canonical_bytes()?,
+        ))
+    }
+
+    pub fn attestation(&self) -> Result<Attestation> {
+        Ok(Attestation {
+            origin: self.origin.clone(),
+            signed_at: self.signed_at,
+            algorithm: self.algorithm,
+            signed_bytes: self.signed_bytes()?,
+            signature: self.signature.clone(),
+        })
+    }
+}
+
+impl<T: Data> Data for SignedData<T> {
+    const TYPE_ID: Uuid = signed_type_id(T::TYPE_ID);
+    const NAME: &'static str = "osp.signed";
+    // the layout changes with the layout of the object
+    const VERSION: u16 = T::VERSION;
+
+    fn descriptor() -> TypeDescriptor {
+        let field = |name: &str, kind: &str| FieldDescriptor {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            optional: false,
+        };
+        TypeDescriptor {
+            type_id: Self::TYPE_ID,
+            name: format!("{}<{}>", Self::NAME, T::NAME),
+            description: Some(format!("A {} signed by its origin", T::NAME)),
+            fields: vec![
+                field("origin", "string"),
+                field("signed_at", "u64"),
+                field("algorithm", "signature_algorithm"),
+                field("data", T::NAME),
+                field("signature", "bytes"),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Data, Result};
+    use crate::format::PayloadFormat;
+    use crate::signed::{signed_type_id, SignatureAlgorithm, SignedData};
+    use crate::standard::Article;
+
+    #[test]
+    fn test_signed_data() -> Result<()> {
+        assert_ne!(SignedData::<Article>::TYPE_ID, Article::TYPE_ID);
+        assert_eq!(signed_type_id(SignedData::<Article>::TYPE_ID), Article::TYPE_ID);
+        assert_eq!(SignedData::<Article>::TYPE_ID.get_version_num(), 4);
+
+        let article = Article { title: "Hello".to_string(), ..Article::default() };
+        let mut signed = SignedData::unsigned(article, "blog.test".to_string(), 1_700_000_000, SignatureAlgorithm::Rsa);
+        let bytes = signed.signed_bytes()?;
+        signed.signature = vec![1, 2, 3];
+        // the signature isn't part of what it signs, the payload format
+        // neither
+        assert_eq!(signed.signed_bytes()?, bytes);
+        let envelope = signed.to_envelope_as("blog.test".to_string(), PayloadFormat::Json)?;
+        assert_eq!(SignedData::<Article>::from_envelope(&envelope)?.attestation()?.signed_bytes, bytes);
+
+        signed.data.title = "Altered".to_string();
+        assert_ne!(signed.signed_bytes()?, bytes);
+        Ok(())
+    }
+}
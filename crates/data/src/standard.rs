@@ -0,0 +1,122 @@
+//! # Standard Data Types
+//!
+//! Data types every node can be expected to understand. Bridges from other
+//! formats should convert into these where possible.
+
+use serde::{Deserialize, Serialize};
+
+use uuid::{uuid, Uuid};
+
+use osp_protocol::{FieldDescriptor, TypeDescriptor};
+
+use crate::{canonical, Data, Result};
+
+/// A piece of published writing, such as a blog post or news story.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Article {
+    pub title: String,
+    /// Short plain text summary.
+    pub summary: Option<String>,
+    /// The body of the article, as HTML.
+    pub content: String,
+    /// Canonical location of the article on the web, if any.
+    pub url: Option<String>,
+    pub authors: Vec<String>,
+    /// Unix timestamp (seconds) the article was published at.
+    pub published_at: u64,
+    pub tags: Vec<String>,
+}
+
+fn field(name: &str, kind: &str, optional: bool) -> FieldDescriptor {
+    FieldDescriptor {
+        name: name.to_string(),
+        kind: kind.to_string(),
+        optional,
+    }
+}
+
+impl Data for Article {
+    const TYPE_ID: Uuid = uuid!("0b6f1a9e-4c8d-4e2a-9f3b-7d5c2e1a8b40");
+    const NAME: &'static str = "osp.article";
+
+    fn descriptor() -> TypeDescriptor {
+        TypeDescriptor {
+            type_id: Self::TYPE_ID,
+            name: Self::NAME.to_string(),
+            description: Some("A piece of published writing, such as a blog post or news story".to_string()),
+            fields: vec![
+                field("title", "string", false),
+                field("summary", "string", true),
+                field("content", "html", false),
+                field("url", "string", true),
+                field("authors", "list<string>", false),
+                field("published_at", "u64", false),
+                field("tags", "list<string>", false),
+            ],
+        }
+    }
+}
+
+/// Whether a [FederationRule] lets its host federate with a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FederationAction {
+    Allow,
+    Deny,
+}
+
+/// An allow or deny list entry for a single host.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FederationRule {
+    /// Hostname the rule applies to, in lowercase.
+    pub host: String,
+    pub action: FederationAction,
+    /// Unix timestamp (seconds) the rule was set at.
+    pub updated_at: u64,
+}
+
+impl FederationRule {
+    /// Whether the rule takes precedence over `other` when merging. The most
+    /// recently set rule wins, and a deny wins over an allow set at the same
+    /// time.
+    pub fn supersedes(&self, other: &FederationRule) -> bool {
+        self.updated_at > other.updated_at
+            || (self.updated_at == other.updated_at && self.action == FederationAction::Deny && other.action == FederationAction::Allow)
+    }
+}
+
+/// A node's federation rules, shared with the other nodes of a trusted
+/// cluster so they merge them into their own.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FederationUpdate {
+    /// Hostname of the node that published and signed the update.
+    pub signer: String,
+    pub rules: Vec<FederationRule>,
+    /// The signer's signature, see [FederationUpdate::signed_bytes].
+    pub signature: Vec<u8>,
+}
+
+impl FederationUpdate {
+    /// The bytes the signature is computed over: the
+    /// [canonically](crate::canonical) encoded signer and rules.
+    pub fn signed_bytes(&self) -> Result<Vec<u8>> {
+        canonical::to_vec(&(&self.signer, &self.rules))
+    }
+}
+
+impl Data for FederationUpdate {
+    const TYPE_ID: Uuid = uuid!("7c2e9d41-0a5b-4f86-b3d7-1e8f6a2c9b53");
+    const NAME: &'static str = "osp.federation_update";
+
+    fn descriptor() -> TypeDescriptor {
+        TypeDescriptor {
+            type_id: Self::TYPE_ID,
+            name: Self::NAME.to_string(),
+            description: Some("Signed allow and deny list entries shared between the nodes of a cluster".to_string()),
+            fields: vec![
+                field("signer", "string", false),
+                field("rules", "list<federation_rule>", false),
+                field("signature", "bytes", false),
+            ],
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! # OSP Protocol Derive
+//!
+//! `#[derive(DescribePackets)]` for the packet enums of `osp_protocol`, see
+//! `osp_protocol::spec`.
+
+use proc_macro::TokenStream;
+
+use quote::quote;
+
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Expr, ExprLit, Fields, Lit, LitInt, LitStr, Meta};
+
+/// Describe a packet enum for the protocol specification and number its
+/// variants. Every variant needs a `#[packet(id = N)]` attribute, which is
+/// also the packet id it is sent with, through the derived
+/// `From<&Self> for u8`. A field can describe its own wire encoding with
+/// `#[packet(wire = "...")]` when it doesn't use the default one for its
+/// type.
+#[proc_macro_derive(DescribePackets, attributes(packet))]
+pub fn derive_describe_packets(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(name, "DescribePackets can only be derived for enums"));
+    };
+
+    let mut ids = Vec::new();
+    let mut packets = Vec::new();
+    let mut arms = Vec::new();
+    for variant in &data.variants {
+        let ident = &variant.ident;
+        let id = variant_id(&variant.attrs)?
+            .ok_or_else(|| syn::Error::new_spanned(ident, "missing #[packet(id = ...)] attribute"))?;
+        if ids.contains(&id) {
+            return Err(syn::Error::new_spanned(ident, format!("packet id {id} is used twice")));
+        }
+        ids.push(id);
+
+        let fields = variant.fields.iter().map(|field| {
+            let field_name = field.ident.as_ref().map(ToString::to_string).unwrap_or_default();
+            let ty = &field.ty;
+            let rust_type = quote!(#ty).to_string().replace(' ', "");
+            let field_doc = doc(&field.attrs);
+            let encoding = match field_wire(&field.attrs)? {
+                Some(wire) => quote!(#wire.to_string()),
+                None => quote!(::osp_protocol::spec::encoding_of(#rust_type)),
+            };
+            Ok(quote! {
+                ::osp_protocol::spec::FieldSpec {
+                    name: #field_name,
+                    rust_type: #rust_type,
+                    encoding: #encoding,
+                    doc: #field_doc,
+                }
+            })
+        }).collect::<syn::Result<Vec<_>>>()?;
+
+        let variant_name = ident.to_string();
+        let variant_doc = doc(&variant.attrs);
+        packets.push(quote! {
+            ::osp_protocol::spec::PacketSpec {
+                id: #id,
+                name: #variant_name,
+                doc: #variant_doc,
+                fields: vec![#(#fields),*],
+            }
+        });
+        arms.push(match variant.fields {
+            Fields::Named(_) => quote!(#name::#ident { .. } => #id),
+            Fields::Unnamed(_) => quote!(#name::#ident(..) => #id),
+            Fields::Unit => quote!(#name::#ident => #id),
+        });
+    }
+
+    let set_name = name.to_string();
+    let set_doc = doc(&input.attrs);
+    Ok(quote! {
+        impl ::osp_protocol::spec::DescribePackets for #name {
+            fn describe() -> ::osp_protocol::spec::PacketSetSpec {
+                ::osp_protocol::spec::PacketSetSpec {
+                    name: #set_name,
+                    doc: #set_doc,
+                    packets: vec![#(#packets),*],
+                }
+            }
+        }
+
+        impl From<&#name> for u8 {
+            fn from(packet: &#name) -> Self {
+                match packet {
+                    #(#arms),*
+                }
+            }
+        }
+    })
+}
+
+/// The doc comment on an item, with its lines joined.
+fn doc(attrs: &[Attribute]) -> String {
+    attrs.iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta {
+            Meta::NameValue(meta) => match &meta.value {
+                Expr::Lit(ExprLit { lit: Lit::Str(line), .. }) => Some(line.value().trim().to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The `id` of a variant's `#[packet(...)]` attribute.
+fn variant_id(attrs: &[Attribute]) -> syn::Result<Option<u8>> {
+    let mut id = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                id = Some(meta.value()?.parse::<LitInt>()?.base10_parse::<u8>()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `id`"))
+            }
+        })?;
+    }
+    Ok(id)
+}
+
+/// The `wire` of a field's `#[packet(...)]` attribute.
+fn field_wire(attrs: &[Attribute]) -> syn::Result<Option<LitStr>> {
+    let mut wire = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("wire") {
+                wire = Some(meta.value()?.parse::<LitStr>()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `wire`"))
+            }
+        })?;
+    }
+    Ok(wire)
+}
@@ -0,0 +1,84 @@
+//! # Audiences
+//!
+//! An object is public by default, and syndicated to every subscriber of its
+//! type. Its [Audience] can restrict it to specific hosts, or to the members
+//! of a community, such as the nodes of a private group. Every node passing
+//! the object on, relays included, only pushes it to peers in its audience.
+//! Communities have no members on the wire: each node knows which of its
+//! peers are members of which communities, and pushes community objects to
+//! no one if it doesn't know the community.
+
+use bytes::{BufMut, BytesMut};
+
+use tokio::io;
+
+use crate::ProtocolError;
+use crate::packet::{DeserializePacket, SerializePacket, TakeField};
+
+const PUBLIC: u8 = 0;
+const HOSTS: u8 = 1;
+const COMMUNITY: u8 = 2;
+
+/// Who an object may be pushed to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Audience {
+    /// Every peer.
+    #[default]
+    Public,
+    /// Only the listed hosts, compared case-insensitively.
+    Hosts(Vec<String>),
+    /// Only the members of the community with this name.
+    Community(String),
+}
+
+impl Audience {
+    pub fn is_public(&self) -> bool {
+        *self == Audience::Public
+    }
+
+    /// Whether `hostname` is in the audience. `is_member` tells whether
+    /// `hostname` is a member of the community it is given the name of.
+    pub fn includes(&self, hostname: &str, is_member: impl FnOnce(&str) -> bool) -> bool {
+        match self {
+            Audience::Public => true,
+            Audience::Hosts(hosts) => hosts.iter().any(|host| host.eq_ignore_ascii_case(hostname)),
+            Audience::Community(community) => is_member(community),
+        }
+    }
+}
+
+impl SerializePacket for Audience {
+    fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
+        match self {
+            Audience::Public => {
+                buf.put_u8(PUBLIC);
+                Ok(1)
+            }
+            Audience::Hosts(hosts) => {
+                buf.put_u8(HOSTS);
+                buf.put_u16(hosts.len() as u16);
+                Ok(3 + hosts.iter().map(|host| self.write_string(buf, host)).sum::<usize>())
+            }
+            Audience::Community(community) => {
+                buf.put_u8(COMMUNITY);
+                Ok(1 + self.write_string(buf, community))
+            }
+        }
+    }
+}
+
+impl DeserializePacket for Audience {
+    type Output = Audience;
+
+    fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
+        match buf.take_u8("Audience kind")? {
+            PUBLIC => Ok(Audience::Public),
+            HOSTS => {
+                let count = buf.take_u16("Audience host count")?;
+                Ok(Audience::Hosts((0..count).map(|_| Self::read_string(buf)).collect::<io::Result<_>>()?))
+            }
+            COMMUNITY => Ok(Audience::Community(Self::read_string(buf)?)),
+            kind => Err(ProtocolError::UnknownAudience { kind }.into()),
+        }
+    }
+}
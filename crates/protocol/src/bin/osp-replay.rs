@@ -0,0 +1,38 @@
+//! Decode every packet of a connection recording and print what it is, see
+//! [osp_protocol::recording].
+
+use std::process::ExitCode;
+
+use osp_protocol::recording::{FrameDirection, Recording};
+
+fn main() -> ExitCode {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("Usage: osp-replay <recording>");
+        return ExitCode::FAILURE;
+    };
+    let recording = match Recording::open(&path) {
+        Ok(recording) => recording,
+        Err(e) => {
+            eprintln!("Failed to read {path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut undecodable = 0;
+    for (i, frame) in recording.frames.iter().enumerate() {
+        let direction = match frame.direction {
+            FrameDirection::Read => "<-",
+            FrameDirection::Written => "->",
+        };
+        let packet = frame.describe().unwrap_or_else(|e| {
+            undecodable += 1;
+            eprintln!("Packet {i} doesn't decode: {e}");
+            "undecodable"
+        });
+        println!("{i:>6} {} {direction} {}::{packet} (v{}, {} bytes)", frame.at, frame.packet, frame.version(), frame.data.len());
+    }
+    match undecodable {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
@@ -0,0 +1,5 @@
+//! Print the protocol specification as JSON, see [osp_protocol::spec].
+
+fn main() {
+    println!("{}", osp_protocol::spec::protocol_spec().to_json());
+}
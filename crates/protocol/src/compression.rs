@@ -0,0 +1,176 @@
+//! # Frame Compression
+//!
+//! Syndicated objects compress well, so peers may compress the frames they
+//! exchange. The guest offers the [Compression] algorithms it supports in
+//! its Hello, and the host picks the one it prefers in its Acknowledge, see
+//! [Compression::negotiate]. From then on, the codecs of a connection's
+//! [Protocol](crate::Protocol) start every frame with the id of the
+//! algorithm its packet is compressed with, or [Compression::None] for
+//! packets too small to be worth compressing. Peers from before compression
+//! was negotiated offer nothing, and frames to and from them are left as
+//! they were.
+//!
+//! Which algorithms are supported depends on the `zstd` and `lz4` features,
+//! which are enabled by default.
+
+use std::io;
+
+use crate::ProtocolError;
+
+/// Packets shorter than this are sent uncompressed.
+const COMPRESSION_THRESHOLD: usize = 256;
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// An algorithm frames are compressed with.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Compression {
+    #[default]
+    None = 0,
+    Zstd = 1,
+    Lz4 = 2,
+}
+
+impl Compression {
+    /// The algorithm with id `id`, or `None` if it is unknown.
+    pub fn from_u8(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    /// The algorithms this crate was built with, the preferred first.
+    pub fn supported() -> Vec<Compression> {
+        let mut supported = Vec::new();
+        if cfg!(feature = "zstd") {
+            supported.push(Compression::Zstd);
+        }
+        if cfg!(feature = "lz4") {
+            supported.push(Compression::Lz4);
+        }
+        supported
+    }
+
+    /// The first algorithm of `ours` that is also in `theirs`, or
+    /// [Compression::None] if they have none in common.
+    pub fn negotiate(ours: &[Compression], theirs: &[Compression]) -> Compression {
+        ours.iter()
+            .find(|algorithm| **algorithm != Compression::None && theirs.contains(algorithm))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Frame `packet`: the id of the algorithm it is compressed with, then
+    /// the packet, compressed if that makes it smaller.
+    pub(crate) fn frame(&self, packet: &[u8]) -> io::Result<Vec<u8>> {
+        if *self != Compression::None && packet.len() >= COMPRESSION_THRESHOLD {
+            let compressed = self.compress(packet)?;
+            if compressed.len() < packet.len() {
+                let mut frame = Vec::with_capacity(1 + compressed.len());
+                frame.push(*self as u8);
+                frame.extend_from_slice(&compressed);
+                return Ok(frame);
+            }
+        }
+        let mut frame = Vec::with_capacity(1 + packet.len());
+        frame.push(Compression::None as u8);
+        frame.extend_from_slice(packet);
+        Ok(frame)
+    }
+
+    /// Read the packet of a frame written by [frame](Self::frame), refusing
+    /// packets that decompress to more than `max_length` bytes.
+    pub(crate) fn unframe(frame: &[u8], max_length: usize) -> io::Result<Vec<u8>> {
+        let Some((&id, data)) = frame.split_first() else {
+            return Err(invalid("empty frame".to_string()));
+        };
+        match Compression::from_u8(id) {
+            Some(Compression::None) => Ok(data.to_vec()),
+            Some(algorithm) => algorithm.decompress(data, max_length),
+            None => Err(invalid(format!("unknown compression {id}"))),
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            #[allow(unreachable_patterns)]
+            algorithm => Err(unsupported(*algorithm)),
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+    fn decompress(&self, data: &[u8], max_length: usize) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::bulk::decompress(data, max_length)
+                .map_err(|e| invalid(format!("zstd: {e}"))),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                // check the size the data claims before allocating for it
+                let length = data.get(..4)
+                    .map(|prefix| u32::from_le_bytes(prefix.try_into().unwrap()) as usize)
+                    .ok_or_else(|| invalid("lz4: missing size".to_string()))?;
+                if length > max_length {
+                    return Err(ProtocolError::FrameTooLarge { length }.into());
+                }
+                lz4_flex::decompress_size_prepended(data).map_err(|e| invalid(format!("lz4: {e}")))
+            }
+            #[allow(unreachable_patterns)]
+            algorithm => Err(unsupported(*algorithm)),
+        }
+    }
+}
+
+fn invalid(reason: String) -> io::Error {
+    ProtocolError::Compression { reason }.into()
+}
+
+#[allow(dead_code)]
+fn unsupported(algorithm: Compression) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("{algorithm:?} compression is not supported by this build"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use crate::compression::Compression;
+
+    #[test]
+    fn test_frame() -> io::Result<()> {
+        let packet = "a syndicated article, ".repeat(100).into_bytes();
+        for algorithm in Compression::supported() {
+            let frame = algorithm.frame(&packet)?;
+            assert_eq!(frame[0], algorithm as u8);
+            assert!(frame.len() < packet.len());
+            assert_eq!(Compression::unframe(&frame, packet.len())?, packet);
+            // too large once decompressed
+            assert!(Compression::unframe(&frame, packet.len() - 1).is_err());
+        }
+
+        // small packets aren't worth it
+        let frame = Compression::Lz4.frame(b"hello")?;
+        assert_eq!(frame, b"\0hello");
+        assert!(Compression::unframe(&[9, 1, 2], 10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let (zstd, lz4) = (Compression::Zstd, Compression::Lz4);
+        assert_eq!(Compression::negotiate(&[zstd, lz4], &[lz4, zstd]), zstd);
+        assert_eq!(Compression::negotiate(&[lz4, zstd], &[zstd, lz4]), lz4);
+        assert_eq!(Compression::negotiate(&[zstd], &[lz4]), Compression::None);
+        assert_eq!(Compression::negotiate(&[zstd, lz4], &[]), Compression::None);
+    }
+}
@@ -0,0 +1,126 @@
+//! # Delegations
+//!
+//! An origin with many subscribers to a type can hand delivering its
+//! objects of that type to designated relays, instead of pushing every
+//! object to every subscriber itself. Each relay gets a [Delegation] signed
+//! by the origin, naming the peer it receives the origin's objects from, its
+//! `parent`, and the peers it delivers them on to, its `children`. Relays
+//! may be children of other relays, so the delegations form a tree rooted at
+//! the origin.
+//!
+//! A relay only forwards objects of the origin that it received from the
+//! parent of its delegation, so an object can't travel around a loop
+//! however the tree was built.
+
+use bytes::{BufMut, BytesMut};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use crate::{OSPUrl, Scheme};
+use crate::packet::{DeserializePacket, SerializePacket, TakeField};
+use crate::packet::transfer::DeliveryQos;
+
+/// A peer a relay delivers the origin's objects to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DelegatedPeer {
+    pub url: OSPUrl,
+    /// How the peer subscribed to have objects delivered.
+    pub qos: DeliveryQos,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delegation {
+    /// Unique id of the delegation, used to revoke it.
+    pub delegation_id: Uuid,
+    /// Hostname of the node whose objects are delegated.
+    pub origin: String,
+    /// Hostname of the relay the delegation is issued to.
+    pub relay: String,
+    /// Hostname of the peer the relay receives the objects from, the origin
+    /// or another relay.
+    pub parent: String,
+    /// The type of the objects delegated.
+    pub type_id: Uuid,
+    pub children: Vec<DelegatedPeer>,
+    /// Unix timestamp (seconds) the delegation expires at, if it does.
+    pub expires_at: Option<u64>,
+    /// The origin's signature over every other field, see
+    /// [Delegation::signed_bytes].
+    pub signature: Vec<u8>,
+}
+
+impl Delegation {
+    /// The bytes the signature is computed over: the encoded delegation
+    /// without its signature.
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        self.write_unsigned(&mut buf);
+        buf.to_vec()
+    }
+
+    /// Whether `hostname` is one of the children, ignoring case.
+    pub fn delegates_to(&self, hostname: &str) -> bool {
+        self.children.iter().any(|child| child.url.domain.eq_ignore_ascii_case(hostname))
+    }
+
+    fn write_unsigned(&self, buf: &mut BytesMut) -> usize {
+        let mut bytes_written = self.write_uuid(buf, &self.delegation_id);
+        bytes_written += self.write_string(buf, &self.origin);
+        bytes_written += self.write_string(buf, &self.relay);
+        bytes_written += self.write_string(buf, &self.parent);
+        bytes_written += self.write_uuid(buf, &self.type_id);
+        buf.put_u16(self.children.len() as u16);
+        bytes_written += 2;
+        for child in &self.children {
+            bytes_written += self.write_string(buf, &child.url.domain);
+            buf.put_u16(child.url.port);
+            buf.put_u8(child.qos as u8);
+            bytes_written += 3;
+        }
+        buf.put_u64(self.expires_at.unwrap_or(0));
+        bytes_written + 8
+    }
+}
+
+impl SerializePacket for Delegation {
+    fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
+        let bytes_written = self.write_unsigned(buf);
+        Ok(bytes_written + self.write_bytes(buf, &self.signature))
+    }
+}
+
+impl DeserializePacket for Delegation {
+    type Output = Delegation;
+
+    fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
+        let delegation_id = Self::read_uuid(buf)?;
+        let origin = Self::read_string(buf)?;
+        let relay = Self::read_string(buf)?;
+        let parent = Self::read_string(buf)?;
+        let type_id = Self::read_uuid(buf)?;
+        let count = buf.take_u16("Delegated peer count")?;
+        let mut children = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let domain = Self::read_string(buf)?;
+            children.push(DelegatedPeer {
+                url: OSPUrl { domain, port: buf.take_u16("Delegated peer port")?, scheme: Scheme::Osp },
+                qos: DeliveryQos::from_u8(buf.take_u8("Delegated peer qos")?).unwrap_or_default(),
+            });
+        }
+        Ok(Delegation {
+            delegation_id,
+            origin,
+            relay,
+            parent,
+            type_id,
+            children,
+            expires_at: match buf.take_u64("Expires at")? {
+                0 => None,
+                expires_at => Some(expires_at),
+            },
+            signature: Self::read_bytes(buf)?,
+        })
+    }
+}
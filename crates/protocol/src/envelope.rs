@@ -0,0 +1,328 @@
+//! # Object Envelope
+//!
+//! Every syndicated object travels inside an [Envelope], which carries the
+//! metadata nodes need to route and store the object without understanding
+//! its payload.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{BufMut, BytesMut};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use crate::{Audience, PayloadFormat, ProtocolError, Sensitivity};
+use crate::packet::{DeserializePacket, SerializePacket, TakeField};
+use crate::packet::transfer::TraceContext;
+
+/// Version of the envelope encoding. Written as the first byte so the layout
+/// can change without breaking stored envelopes. Every earlier version can
+/// still be decoded.
+///
+/// - 1: initial layout
+/// - 2: adds `license` and `attribution`
+/// - 3: adds `sensitivity`
+/// - 4: adds `actor`
+/// - 5: adds `trace_parent`
+/// - 6: adds `topic`
+/// - 7: adds `audience`
+/// - 8: adds `type_version`
+/// - 9: adds `format`
+/// - 10: adds `ttl`
+pub const ENVELOPE_VERSION: u8 = 10;
+
+/// How many times objects may be relayed unless their origin says
+/// otherwise, and how many times objects from before envelopes carried a
+/// `ttl` may be.
+pub const DEFAULT_TTL: u8 = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    /// Unique id of the object.
+    pub object_id: Uuid,
+    /// Id of the data type the payload is encoded as.
+    pub type_id: Uuid,
+    /// Version of the data type the payload is encoded as, 1 for objects
+    /// from before types were versioned.
+    pub type_version: u16,
+    /// Format the payload is encoded in, bincode for objects from before
+    /// formats were negotiated.
+    pub format: PayloadFormat,
+    /// Hostname of the node the object was first published on.
+    pub origin: String,
+    /// Unix timestamp (seconds) the object was created at.
+    pub created_at: u64,
+    /// Id of the account on the origin node that created the object, if it
+    /// was created by one.
+    pub actor: Option<String>,
+    /// License the object is published under, as an SPDX license identifier
+    /// such as `CC-BY-4.0`.
+    pub license: Option<String>,
+    /// Credit line required when the object is shown or republished.
+    pub attribution: Option<String>,
+    /// Flags marking the object as unsuitable for some audiences.
+    pub sensitivity: Sensitivity,
+    /// The trace the object was first sent in, so its way through relays
+    /// can be followed as one trace. Nodes may strip it for privacy.
+    pub trace_parent: Option<TraceContext>,
+    /// Hierarchical topic the object is published under, with levels
+    /// separated by `/` such as `blog/rust/async`. Peers can subscribe to
+    /// topics instead of every object of a type.
+    pub topic: Option<String>,
+    /// Who the object may be pushed to, see [Audience].
+    pub audience: Audience,
+    /// How many more times the object may be relayed from node to node.
+    /// Each relay passes it on with one less, objects with none left aren't
+    /// relayed.
+    pub ttl: u8,
+    /// The encoded object.
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    /// Wrap `payload` in a new envelope originating from `origin`.
+    pub fn new(type_id: Uuid, origin: String, payload: Vec<u8>) -> Self {
+        Envelope {
+            object_id: Uuid::new_v4(),
+            type_id,
+            type_version: 1,
+            format: PayloadFormat::default(),
+            origin,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            actor: None,
+            license: None,
+            attribution: None,
+            sensitivity: Sensitivity::default(),
+            trace_parent: None,
+            topic: None,
+            audience: Audience::default(),
+            ttl: DEFAULT_TTL,
+            payload,
+        }
+    }
+
+    /// Set the version of the data type the payload is encoded as.
+    pub fn with_type_version(mut self, type_version: u16) -> Self {
+        self.type_version = type_version;
+        self
+    }
+
+    /// Set the format the payload is encoded in.
+    pub fn with_format(mut self, format: PayloadFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Set the account that created the object.
+    pub fn with_actor(mut self, actor: &str) -> Self {
+        self.actor = Some(actor.to_string());
+        self
+    }
+
+    /// Set the license the object is published under.
+    pub fn with_license(mut self, license: &str) -> Self {
+        self.license = Some(license.to_string());
+        self
+    }
+
+    /// Set the credit line for the object.
+    pub fn with_attribution(mut self, attribution: &str) -> Self {
+        self.attribution = Some(attribution.to_string());
+        self
+    }
+
+    /// Flag the object as `sensitivity`.
+    pub fn with_sensitivity(mut self, sensitivity: Sensitivity) -> Self {
+        self.sensitivity = sensitivity;
+        self
+    }
+
+    /// Set the trace the object was first sent in.
+    pub fn with_trace_parent(mut self, trace_parent: TraceContext) -> Self {
+        self.trace_parent = Some(trace_parent);
+        self
+    }
+
+    /// Publish the object under `topic`.
+    pub fn with_topic(mut self, topic: &str) -> Self {
+        self.topic = Some(topic.to_string());
+        self
+    }
+
+    /// Restrict who the object may be pushed to.
+    pub fn with_audience(mut self, audience: Audience) -> Self {
+        self.audience = audience;
+        self
+    }
+
+    /// Set how many times the object may be relayed.
+    pub fn with_ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// The envelope as passed on by a relay, with one less relay left.
+    /// `None` if the object may not be relayed anymore.
+    pub fn relayed(&self) -> Option<Self> {
+        Some(Envelope {
+            ttl: self.ttl.checked_sub(1)?,
+            ..self.clone()
+        })
+    }
+
+    /// Encode the envelope into a standalone buffer.
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut buf = BytesMut::new();
+        self.serialize(&mut buf)?;
+        Ok(buf.to_vec())
+    }
+
+    /// Decode an envelope from a buffer produced by [Envelope::to_bytes].
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        Self::deserialize(&mut BytesMut::from(bytes))
+    }
+}
+
+impl SerializePacket for Envelope {
+    fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
+        buf.put_u8(ENVELOPE_VERSION);
+        let mut bytes_written = 1;
+        bytes_written += self.write_uuid(buf, &self.object_id);
+        bytes_written += self.write_uuid(buf, &self.type_id);
+        bytes_written += self.write_string(buf, &self.origin);
+        buf.put_u64(self.created_at);
+        bytes_written += 8;
+        bytes_written += self.write_optional_string(buf, &self.license);
+        bytes_written += self.write_optional_string(buf, &self.attribution);
+        bytes_written += self.sensitivity.serialize(buf)?;
+        bytes_written += self.write_optional_string(buf, &self.actor);
+        let trace_parent = self.trace_parent.map(|context| context.to_traceparent());
+        bytes_written += self.write_optional_string(buf, &trace_parent);
+        bytes_written += self.write_optional_string(buf, &self.topic);
+        bytes_written += self.audience.serialize(buf)?;
+        buf.put_u16(self.type_version);
+        buf.put_u8(self.format as u8);
+        buf.put_u8(self.ttl);
+        bytes_written += 4;
+        bytes_written += self.write_bytes(buf, &self.payload);
+        Ok(bytes_written)
+    }
+}
+
+impl DeserializePacket for Envelope {
+    type Output = Envelope;
+
+    fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
+        let version = buf.take_u8("Envelope version")?;
+        if version == 0 || version > ENVELOPE_VERSION {
+            return Err(ProtocolError::UnsupportedEnvelope { version }.into());
+        }
+
+        let object_id = Self::read_uuid(buf)?;
+        let type_id = Self::read_uuid(buf)?;
+        let origin = Self::read_string(buf)?;
+        let created_at = buf.take_u64("Created at")?;
+        let (license, attribution) = match version {
+            1 => (None, None),
+            _ => (Self::read_optional_string(buf)?, Self::read_optional_string(buf)?),
+        };
+        let sensitivity = match version {
+            1 | 2 => Sensitivity::default(),
+            _ => Sensitivity::deserialize(buf)?,
+        };
+        let actor = match version {
+            1..=3 => None,
+            _ => Self::read_optional_string(buf)?,
+        };
+        let trace_parent = match version {
+            1..=4 => None,
+            // an unparseable context only costs the trace its parent
+            _ => Self::read_optional_string(buf)?.and_then(|traceparent| TraceContext::from_traceparent(&traceparent)),
+        };
+        let topic = match version {
+            1..=5 => None,
+            _ => Self::read_optional_string(buf)?,
+        };
+        let audience = match version {
+            1..=6 => Audience::default(),
+            _ => Audience::deserialize(buf)?,
+        };
+        let type_version = match version {
+            1..=7 => 1,
+            _ => buf.take_u16("Type version")?,
+        };
+        let format = match version {
+            1..=8 => PayloadFormat::default(),
+            _ => {
+                let id = buf.take_u8("Payload format")?;
+                PayloadFormat::from_u8(id).ok_or(ProtocolError::UnknownPayloadFormat { id })?
+            }
+        };
+        let ttl = match version {
+            1..=9 => DEFAULT_TTL,
+            _ => buf.take_u8("Ttl")?,
+        };
+        Ok(Envelope {
+            object_id,
+            type_id,
+            type_version,
+            format,
+            origin,
+            created_at,
+            actor,
+            license,
+            attribution,
+            sensitivity,
+            trace_parent,
+            topic,
+            audience,
+            ttl,
+            payload: Self::read_bytes(buf)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+    use uuid::Uuid;
+
+    use crate::{Audience, Envelope, PayloadFormat, Sensitivity};
+    use crate::packet::transfer::TraceContext;
+
+    #[test]
+    fn test_envelope_roundtrip() -> io::Result<()> {
+        let envelope = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![1, 2, 3])
+            .with_actor("author")
+            .with_license("CC-BY-4.0")
+            .with_attribution("Test Author")
+            .with_sensitivity(Sensitivity { spoiler: true, warnings: vec!["violence".to_string()], ..Default::default() })
+            .with_trace_parent(TraceContext { trace_id: [1; 16], span_id: [2; 8], sampled: true })
+            .with_topic("blog/rust/async")
+            .with_audience(Audience::Hosts(vec!["a.test".to_string(), "b.test".to_string()]))
+            .with_type_version(3)
+            .with_format(PayloadFormat::Cbor)
+            .with_ttl(3);
+        assert_eq!(Envelope::from_bytes(&envelope.to_bytes()?)?, envelope);
+
+        let envelope = envelope.with_audience(Audience::Community("friends".to_string()));
+        assert_eq!(Envelope::from_bytes(&envelope.to_bytes()?)?, envelope);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_version_1() -> io::Result<()> {
+        let envelope = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![1, 2, 3]);
+        let mut bytes = vec![1];
+        bytes.extend(envelope.object_id.as_bytes());
+        bytes.extend(envelope.type_id.as_bytes());
+        bytes.extend((envelope.origin.len() as u16).to_be_bytes());
+        bytes.extend(envelope.origin.as_bytes());
+        bytes.extend(envelope.created_at.to_be_bytes());
+        bytes.extend(3u32.to_be_bytes());
+        bytes.extend([1, 2, 3]);
+        assert_eq!(Envelope::from_bytes(&bytes)?, envelope);
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+//! # Protocol Errors
+//!
+//! Packets and frames that can't be read or written fail with a
+//! [ProtocolError]. The codecs work with [io::Error]s, so these are converted
+//! into one of the [kind](ProtocolError::kind) of the failure at the
+//! boundary, and callers holding an [io::Error] can downcast back to a
+//! [ProtocolError] to tell the failures apart.
+//!
+//! A connection that ends fails the next read with one of three errors,
+//! told apart by [Closure::of]:
+//!
+//! - [Closed](ProtocolError::Closed): the peer closed its side cleanly
+//!   (FIN), after everything it sent arrived. It may still read what is
+//!   written to it until it closes the other side as well.
+//! - [Reset](ProtocolError::Reset): the peer, or something between, reset
+//!   the connection (RST). Packets written but not read by the peer yet,
+//!   even those that were flushed, may be lost.
+//! - [IdleTimeout](ProtocolError::IdleTimeout): the peer went silent, see
+//!   [keepalive](crate::keepalive). Timeouts of the transport itself count
+//!   as the same closure.
+//!
+//! A packet cut short, [Truncated](ProtocolError::Truncated), whether it
+//! ends in a fixed width field or in the bytes a length prefix claims,
+//! converts into an [UnexpectedEof](io::ErrorKind::UnexpectedEof) like a
+//! clean close, but is no closure: the peer sent a broken packet and may
+//! well still be connected, so reading it fails with an error rather than
+//! ending the connection cleanly. Callers that took every `UnexpectedEof`
+//! for the peer disconnecting have to go by [Closure::of] instead.
+
+use std::io;
+use std::time::Duration;
+
+/// Why a packet or frame could not be read or written.
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    /// The packet starts with an id no packet of its kind has.
+    #[error("Invalid packet type {id}")]
+    InvalidPacketType {
+        id: u8,
+    },
+    /// A string in the packet is not valid UTF-8.
+    #[error("Invalid utf8")]
+    InvalidUtf8,
+    /// The packet ends before `field`, or a length prefixed field claims
+    /// more bytes than the packet has left.
+    #[error("{field} is longer than the packet")]
+    Truncated {
+        field: String,
+    },
+    /// A packet to write has more of `field` than its u16 count can hold.
+    #[error("Can't write {count} {field}, at most 65535 fit in a packet")]
+    TooMany {
+        field: String,
+        count: usize,
+    },
+    /// A frame is longer than the 8 MiB frames may be.
+    #[error("Frame of length {length} is too large.")]
+    FrameTooLarge {
+        length: usize,
+    },
+    /// An envelope was written in a version newer than this crate knows.
+    #[error("Unsupported envelope version {version}")]
+    UnsupportedEnvelope {
+        version: u8,
+    },
+    /// A challenge names a key algorithm this crate doesn't know.
+    #[error("Unknown challenge key algorithm {algorithm}")]
+    UnknownKeyAlgorithm {
+        algorithm: u8,
+    },
+    /// An envelope restricts its audience in a way this crate doesn't know.
+    #[error("Unknown audience kind {kind}")]
+    UnknownAudience {
+        kind: u8,
+    },
+    /// An envelope's payload is encoded in a format this crate doesn't know.
+    #[error("Unknown payload format {id}")]
+    UnknownPayloadFormat {
+        id: u8,
+    },
+    /// A compressed frame could not be decompressed.
+    #[error("Invalid compressed frame: {reason}")]
+    Compression {
+        reason: String,
+    },
+    /// A chunked push is malformed, or larger than the host accepts.
+    #[error("Invalid transfer {transfer_id}: {reason}")]
+    InvalidTransfer {
+        transfer_id: u32,
+        reason: String,
+    },
+    /// The peer closed the connection.
+    #[error("Connection closed by peer")]
+    Closed,
+    /// The peer, or something between, reset the connection.
+    #[error("Connection reset by peer")]
+    Reset,
+    /// The peer sent nothing for as long as connections may be idle, not
+    /// even an answer to a ping, so it is taken to be gone.
+    #[error("Peer sent nothing for {idle:?}")]
+    IdleTimeout {
+        idle: Duration,
+    },
+}
+
+impl ProtocolError {
+    /// The kind of [io::Error] this converts into.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            ProtocolError::Truncated { .. } | ProtocolError::Closed => io::ErrorKind::UnexpectedEof,
+            ProtocolError::Reset => io::ErrorKind::ConnectionReset,
+            ProtocolError::TooMany { .. } => io::ErrorKind::InvalidInput,
+            ProtocolError::IdleTimeout { .. } => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+
+    /// How the connection ended, if this is it ending.
+    pub fn closure(&self) -> Option<Closure> {
+        match self {
+            ProtocolError::Closed => Some(Closure::Fin),
+            ProtocolError::Reset => Some(Closure::Reset),
+            ProtocolError::IdleTimeout { .. } => Some(Closure::Timeout),
+            _ => None,
+        }
+    }
+}
+
+/// How a connection ended, see the [module](self) docs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Closure {
+    /// The peer closed its side cleanly.
+    Fin,
+    /// The connection was reset.
+    Reset,
+    /// The peer went silent.
+    Timeout,
+}
+
+impl Closure {
+    /// How the connection `err` happened on ended, if it did. Errors that
+    /// aren't [ProtocolError]s, such as those of other transports, are told
+    /// apart by their kind. Of those timing out, only the transport's own
+    /// count, not requests that went unanswered. A
+    /// [Truncated](ProtocolError::Truncated) packet is no closure, though it
+    /// is an [UnexpectedEof](io::ErrorKind::UnexpectedEof) too.
+    pub fn of(err: &io::Error) -> Option<Closure> {
+        if let Some(err) = err.get_ref().and_then(|inner| inner.downcast_ref::<ProtocolError>()) {
+            return err.closure();
+        }
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => Some(Closure::Fin),
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe | io::ErrorKind::NotConnected => Some(Closure::Reset),
+            io::ErrorKind::TimedOut if err.raw_os_error().is_some() => Some(Closure::Timeout),
+            _ => None,
+        }
+    }
+
+    /// The name of the closure, e.g. for labelling metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Closure::Fin => "fin",
+            Closure::Reset => "reset",
+            Closure::Timeout => "timeout",
+        }
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(err: ProtocolError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
@@ -0,0 +1,70 @@
+//! # Payload Formats
+//!
+//! Objects are encoded with bincode by default, which is compact but only
+//! readable by implementations sharing the Rust types. Payloads can also be
+//! encoded as CBOR or JSON, which implementations in other languages and
+//! people debugging a node can read without them. Every [Envelope] names the
+//! [PayloadFormat] its payload is encoded in.
+//!
+//! The guest offers the formats it can encode objects in in its Hello, and
+//! the host picks the one it prefers in its Acknowledge, see
+//! [PayloadFormat::negotiate]. The guest then pushes objects in that format
+//! where it can transcode them to it. Guests from before formats were
+//! negotiated offer nothing and get [Bincode](PayloadFormat::Bincode).
+//!
+//! [Envelope]: crate::Envelope
+
+/// A format payloads are encoded in.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum PayloadFormat {
+    #[default]
+    Bincode = 0,
+    Cbor = 1,
+    Json = 2,
+}
+
+impl PayloadFormat {
+    /// Every format, the preferred first.
+    pub const ALL: [PayloadFormat; 3] = [PayloadFormat::Bincode, PayloadFormat::Cbor, PayloadFormat::Json];
+
+    /// The format with id `id`, or `None` if it is unknown.
+    pub fn from_u8(id: u8) -> Option<PayloadFormat> {
+        match id {
+            0 => Some(PayloadFormat::Bincode),
+            1 => Some(PayloadFormat::Cbor),
+            2 => Some(PayloadFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// Whether payloads in the format can be read without knowing their
+    /// type.
+    pub fn is_self_describing(&self) -> bool {
+        *self != PayloadFormat::Bincode
+    }
+
+    /// The first format of `ours` that is also in `theirs`, or
+    /// [Bincode](PayloadFormat::Bincode) if they offer none or have none in
+    /// common.
+    pub fn negotiate(ours: &[PayloadFormat], theirs: &[PayloadFormat]) -> PayloadFormat {
+        ours.iter()
+            .find(|format| theirs.contains(format))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PayloadFormat;
+
+    #[test]
+    fn test_negotiate() {
+        let (cbor, json) = (PayloadFormat::Cbor, PayloadFormat::Json);
+        assert_eq!(PayloadFormat::negotiate(&PayloadFormat::ALL, &[json, cbor]), cbor);
+        assert_eq!(PayloadFormat::negotiate(&[json], &[cbor]), PayloadFormat::Bincode);
+        assert_eq!(PayloadFormat::negotiate(&PayloadFormat::ALL, &[]), PayloadFormat::Bincode);
+        assert_eq!(PayloadFormat::from_u8(json as u8), Some(json));
+        assert_eq!(PayloadFormat::from_u8(9), None);
+    }
+}
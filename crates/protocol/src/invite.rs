@@ -0,0 +1,77 @@
+//! # Invites
+//!
+//! Closed federations don't rely on DNS to establish who a peer is. Instead
+//! the operator of a node issues an [Invite] to a prospective peer, binding
+//! its hostname to its public key, and the peer presents the invite when it
+//! identifies itself. The issuing node checks the invite against its own
+//! records.
+
+use bytes::{BufMut, BytesMut};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use crate::packet::{DeserializePacket, SerializePacket, TakeField};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invite {
+    /// Unique id of the invite, used to look it up and revoke it.
+    pub token_id: Uuid,
+    /// Hostname of the node that issued the invite.
+    pub issuer: String,
+    /// Hostname of the invited peer.
+    pub invitee: String,
+    /// PEM encoded public key of the invited peer, used for the handshake
+    /// challenge in place of its DNS record.
+    pub public_key: String,
+    /// Unix timestamp (seconds) the invite expires at, if it does.
+    pub expires_at: Option<u64>,
+    /// The issuer's signature over every other field, see
+    /// [Invite::signed_bytes].
+    pub signature: Vec<u8>,
+}
+
+impl Invite {
+    /// The bytes the signature is computed over: the encoded invite without
+    /// its signature.
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        self.write_unsigned(&mut buf);
+        buf.to_vec()
+    }
+
+    fn write_unsigned(&self, buf: &mut BytesMut) -> usize {
+        let mut bytes_written = self.write_uuid(buf, &self.token_id);
+        bytes_written += self.write_string(buf, &self.issuer);
+        bytes_written += self.write_string(buf, &self.invitee);
+        bytes_written += self.write_string(buf, &self.public_key);
+        buf.put_u64(self.expires_at.unwrap_or(0));
+        bytes_written + 8
+    }
+}
+
+impl SerializePacket for Invite {
+    fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
+        let bytes_written = self.write_unsigned(buf);
+        Ok(bytes_written + self.write_bytes(buf, &self.signature))
+    }
+}
+
+impl DeserializePacket for Invite {
+    type Output = Invite;
+
+    fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
+        Ok(Invite {
+            token_id: Self::read_uuid(buf)?,
+            issuer: Self::read_string(buf)?,
+            invitee: Self::read_string(buf)?,
+            public_key: Self::read_string(buf)?,
+            expires_at: match buf.take_u64("Expires at")? {
+                0 => None,
+                expires_at => Some(expires_at),
+            },
+            signature: Self::read_bytes(buf)?,
+        })
+    }
+}
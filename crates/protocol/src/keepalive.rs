@@ -0,0 +1,216 @@
+//! # Keepalives
+//!
+//! NATs and firewalls forget connections that carry nothing for a while, so
+//! long-lived connections between peers die without either side noticing.
+//! From [KEEPALIVE_VERSION], a peer that heard nothing on a connection for
+//! the [interval](Keepalive::interval) of its [Keepalive] sends a Ping,
+//! which the other side answers with a Pong. Every packet counts as a sign
+//! of life, so busy connections are never pinged. Once a peer heard nothing
+//! for the [idle timeout](Keepalive::idle_timeout), not even a Pong, it
+//! closes the connection with [ProtocolError::IdleTimeout].
+//!
+//! A [KeepaliveTimer] does this for the reading side of a
+//! [Protocol], see [KeepaliveTimer::read_frame]. Answering Pings is left to
+//! whoever handles the packets read, and so is calling
+//! [KeepaliveTimer::heard] once a packet was handled or a request sent, so
+//! that neither counts as the peer falling silent.
+
+use std::future::{self, Future};
+use std::time::{Duration, Instant};
+
+use tokio::io;
+
+use crate::{Protocol, ProtocolError, ProtocolVersion};
+use crate::packet::{DeserializePacket, SerializePacket};
+use crate::packet::transfer::KEEPALIVE_VERSION;
+
+/// When a connection is pinged and when it is given up on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keepalive {
+    /// How long the peer may be silent before it is pinged, `None` to never
+    /// ping it.
+    pub interval: Option<Duration>,
+    /// How long the peer may be silent before the connection is closed,
+    /// `None` to keep it open however long it is.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Keepalive {
+    /// Connections are neither pinged nor closed.
+    pub const DISABLED: Keepalive = Keepalive { interval: None, idle_timeout: None };
+
+    /// Whether the peer is given the time to answer a ping before the
+    /// connection is closed.
+    pub fn is_valid(&self) -> bool {
+        match (self.interval, self.idle_timeout) {
+            (Some(interval), _) if interval.is_zero() => false,
+            (Some(interval), Some(idle_timeout)) => interval < idle_timeout,
+            (None, Some(idle_timeout)) => !idle_timeout.is_zero(),
+            _ => true,
+        }
+    }
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            interval: Some(Duration::from_secs(30)),
+            idle_timeout: Some(Duration::from_secs(90)),
+        }
+    }
+}
+
+/// Tracks when a connection's peer was last heard from.
+#[derive(Debug)]
+pub struct KeepaliveTimer {
+    keepalive: Keepalive,
+    heard_at: Instant,
+    /// Whether the peer was pinged since it was last heard from
+    pinged: bool,
+    last_nonce: u64,
+}
+
+impl KeepaliveTimer {
+    /// A timer for a connection speaking `version`. Peers from before
+    /// [KEEPALIVE_VERSION] can't answer pings, so connections with them are
+    /// neither pinged nor closed.
+    pub fn new(keepalive: Keepalive, version: ProtocolVersion) -> Self {
+        KeepaliveTimer {
+            keepalive: match version >= KEEPALIVE_VERSION {
+                true => keepalive,
+                false => Keepalive::DISABLED,
+            },
+            heard_at: Instant::now(),
+            pinged: false,
+            last_nonce: 0,
+        }
+    }
+
+    pub fn keepalive(&self) -> Keepalive {
+        self.keepalive
+    }
+
+    /// How long the peer has been silent.
+    pub fn idle(&self) -> Duration {
+        self.heard_at.elapsed()
+    }
+
+    /// A nonce for the next ping, which no ping on the connection had yet.
+    pub fn nonce(&mut self) -> u64 {
+        self.last_nonce += 1;
+        self.last_nonce
+    }
+
+    /// Note that the peer was heard from, restarting the idle clock. Reading
+    /// a packet does this, but so should handling one or sending a request
+    /// the peer has yet to answer. Sending a Ping mustn't, or a peer that
+    /// died would never time out.
+    pub fn heard(&mut self) {
+        self.heard_at = Instant::now();
+        self.pinged = false;
+    }
+
+    /// When the peer is due to be pinged or given up on, whichever is first.
+    fn deadline(&self) -> Option<Instant> {
+        let ping_at = self.keepalive.interval.filter(|_| !self.pinged).map(|interval| self.heard_at + interval);
+        let close_at = self.keepalive.idle_timeout.map(|idle_timeout| self.heard_at + idle_timeout);
+        ping_at.into_iter().chain(close_at).min()
+    }
+
+    /// Read the next packet from `protocol`, sending the packet `ping` makes
+    /// of a nonce whenever the peer is due to be pinged. Fails with
+    /// [ProtocolError::IdleTimeout] once the peer was silent for the idle
+    /// timeout.
+    pub async fn read_frame<In: DeserializePacket, Out: SerializePacket>(
+        &mut self,
+        protocol: &mut Protocol<In, Out>,
+        ping: impl Fn(u64) -> Out,
+    ) -> io::Result<In::Output> {
+        let packet = self.read_frame_until(protocol, ping, future::pending()).await?;
+        Ok(packet.expect("waiting on a pending future never stops"))
+    }
+
+    /// Like [read_frame](Self::read_frame), but stop waiting for the next
+    /// packet once `until` completes, returning `None`, e.g. to send the
+    /// peer something. Nothing is lost when it stops, the packet is read by
+    /// the next call.
+    pub async fn read_frame_until<In: DeserializePacket, Out: SerializePacket>(
+        &mut self,
+        protocol: &mut Protocol<In, Out>,
+        ping: impl Fn(u64) -> Out,
+        until: impl Future<Output = ()>,
+    ) -> io::Result<Option<In::Output>> {
+        tokio::pin!(until);
+        loop {
+            let deadline = self.deadline();
+            // reading frames is cancel safe, a partly read frame stays
+            // buffered for the next read
+            let read = tokio::select! {
+                read = async {
+                    match deadline {
+                        Some(deadline) => tokio::time::timeout_at(deadline.into(), protocol.read_frame()).await.ok(),
+                        None => Some(protocol.read_frame().await),
+                    }
+                } => read,
+                () = &mut until => return Ok(None),
+            };
+            if let Some(packet) = read {
+                self.heard();
+                return packet.map(Some);
+            }
+            match self.keepalive.idle_timeout {
+                Some(idle_timeout) if self.idle() >= idle_timeout => {
+                    return Err(ProtocolError::IdleTimeout { idle: idle_timeout }.into());
+                }
+                _ => {
+                    let nonce = self.nonce();
+                    protocol.send_message(ping(nonce)).await?;
+                    self.pinged = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io;
+
+    use crate::{Protocol, ProtocolError, PROTOCOL_VERSION};
+    use crate::keepalive::{Keepalive, KeepaliveTimer};
+    use crate::packet::transfer::{TransferPacketGuestToHost, TransferPacketHostToGuest};
+
+    #[tokio::test]
+    async fn test_keepalive_timer() -> io::Result<()> {
+        let (host, guest) = io::duplex(1024);
+        let mut host = Protocol::<TransferPacketGuestToHost, TransferPacketHostToGuest>::with_transport(host)?;
+        let mut guest = Protocol::<TransferPacketHostToGuest, TransferPacketGuestToHost>::with_transport(guest)?;
+        let keepalive = Keepalive { interval: Some(Duration::from_millis(20)), idle_timeout: Some(Duration::from_millis(100)) };
+        let mut timer = KeepaliveTimer::new(keepalive, PROTOCOL_VERSION);
+
+        // the guest answers the first ping, then goes silent
+        let (read, answered) = tokio::join!(
+            timer.read_frame(&mut host, |nonce| TransferPacketHostToGuest::Ping { nonce }),
+            async {
+                let TransferPacketHostToGuest::Ping { nonce } = guest.read_frame().await? else {
+                    panic!("Expected ping packet");
+                };
+                guest.send_message(TransferPacketGuestToHost::Pong { nonce }).await
+            },
+        );
+        answered?;
+        assert!(matches!(read?, TransferPacketGuestToHost::Pong { nonce: 1 }));
+        let Err(err) = timer.read_frame(&mut host, |nonce| TransferPacketHostToGuest::Ping { nonce }).await else {
+            panic!("Expected idle timeout");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(matches!(err.get_ref().and_then(|inner| inner.downcast_ref()), Some(ProtocolError::IdleTimeout { .. })));
+        assert!(matches!(guest.read_frame().await?, TransferPacketHostToGuest::Ping { nonce: 2 }));
+
+        assert!(!Keepalive { interval: Some(Duration::from_secs(90)), idle_timeout: Some(Duration::from_secs(30)) }.is_valid());
+        assert!(Keepalive::DISABLED.is_valid() && Keepalive::default().is_valid());
+        Ok(())
+    }
+}
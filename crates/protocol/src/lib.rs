@@ -12,9 +12,28 @@
 //     }
 // }
 
+// lets the packet derives refer to this crate as ::osp_protocol
+extern crate self as osp_protocol;
+
 mod protocol;
 mod utils;
 mod url;
+mod audience;
+mod compression;
+mod delegation;
+mod envelope;
+mod error;
+mod format;
+mod invite;
+mod keepalive;
+mod schema;
+mod sensitivity;
+mod tombstone;
+mod version;
 pub mod packet;
+pub mod recording;
+pub mod spec;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
-pub use {protocol::*, url::OSPUrl, utils::ConnectionType};
\ No newline at end of file
+pub use {protocol::*, audience::Audience, compression::Compression, url::{OSPUrl, Scheme, DEFAULT_PORT}, delegation::{DelegatedPeer, Delegation}, utils::ConnectionType, envelope::{Envelope, DEFAULT_TTL, ENVELOPE_VERSION}, error::{Closure, ProtocolError}, format::PayloadFormat, invite::Invite, keepalive::{Keepalive, KeepaliveTimer}, schema::{FieldDescriptor, TypeDescriptor}, sensitivity::{Sensitivity, SensitivityFilter}, tombstone::Tombstone, version::{ProtocolVersion, VersionRange, DEPRECATED_BEFORE, PROTOCOL_VERSION}};
\ No newline at end of file
@@ -0,0 +1,341 @@
+//! # Chunked Pushes
+//!
+//! A packet can't be longer than the 8 MiB a frame may be, so objects whose
+//! encoded envelope is larger than [CHUNK_LENGTH] are pushed in chunks:
+//! [PushBegin](TransferPacketGuestToHost::PushBegin) announces the
+//! transfer and its length, [PushChunk](TransferPacketGuestToHost::PushChunk)s
+//! carry the encoded envelope piece by piece, and
+//! [PushEnd](TransferPacketGuestToHost::PushEnd) completes it. The guest
+//! splits objects with [push_packets], the host puts them back together with
+//! a [Reassembler].
+//!
+//! Chunks of a transfer are sent in order, but transfers may interleave
+//! with other packets. A host reassembling transfers from many guests at
+//! once bounds the bytes they hold together with a [ReassemblyBudget]. Hosts
+//! from before chunked pushes don't know these packets, so they are only
+//! sent to hosts that speak [CHUNKED_PUSH_VERSION].
+//!
+//! Streams of bytes that aren't an envelope, such as attachments, are sent
+//! in chunks of the same length, read with [read_chunk], see
+//! [StreamBegin](TransferPacketGuestToHost::StreamBegin).
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+use crate::{Envelope, ProtocolError, ProtocolVersion};
+use crate::packet::transfer::{TraceContext, TransferPacketGuestToHost};
+
+/// The protocol version that introduced chunked pushes.
+pub const CHUNKED_PUSH_VERSION: ProtocolVersion = ProtocolVersion::new(1, 2);
+
+/// Length of the chunks objects are split into. Objects whose encoded
+/// envelope is at most this long are pushed in one packet.
+pub const CHUNK_LENGTH: usize = 1024 * 1024;
+
+/// Longest encoded envelope a [Reassembler] accepts unless configured
+/// otherwise.
+pub const MAX_OBJECT_LENGTH: u64 = 256 * 1024 * 1024;
+
+/// Transfers a [Reassembler] keeps open at once.
+const MAX_OPEN_TRANSFERS: usize = 4;
+
+/// The packets that push `envelope` with `sequence` to a host that speaks
+/// `version`, asking for an ack if `ack` is set: a single
+/// [Push](TransferPacketGuestToHost::Push) if it fits in one, otherwise a
+/// chunked transfer with id `transfer_id`. Hosts that don't speak
+/// [CHUNKED_PUSH_VERSION] always get a single Push.
+pub fn push_packets(
+    sequence: u64,
+    envelope: Envelope,
+    trace: Option<TraceContext>,
+    ack: bool,
+    transfer_id: u32,
+    version: ProtocolVersion,
+) -> io::Result<Vec<TransferPacketGuestToHost>> {
+    // the payload is most of the envelope, so small ones aren't encoded twice
+    if version < CHUNKED_PUSH_VERSION || envelope.payload.len() < CHUNK_LENGTH / 2 {
+        return Ok(vec![TransferPacketGuestToHost::Push { sequence, envelope, trace, ack }]);
+    }
+    let bytes = envelope.to_bytes()?;
+    if bytes.len() <= CHUNK_LENGTH {
+        return Ok(vec![TransferPacketGuestToHost::Push { sequence, envelope, trace, ack }]);
+    }
+    let mut packets = Vec::with_capacity(bytes.len() / CHUNK_LENGTH + 3);
+    packets.push(TransferPacketGuestToHost::PushBegin {
+        transfer_id,
+        sequence,
+        total_length: bytes.len() as u64,
+        trace,
+        ack,
+    });
+    packets.extend(bytes.chunks(CHUNK_LENGTH).map(|chunk| TransferPacketGuestToHost::PushChunk {
+        transfer_id,
+        data: chunk.to_vec(),
+    }));
+    packets.push(TransferPacketGuestToHost::PushEnd { transfer_id });
+    Ok(packets)
+}
+
+/// Read the next chunk of a stream from `reader`: [CHUNK_LENGTH] bytes, or
+/// what is left of it if that is less. Empty once the stream ended.
+pub async fn read_chunk(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(CHUNK_LENGTH);
+    // reads past those that return less than there is, so a chunk is only
+    // short at the end
+    (&mut *reader).take(CHUNK_LENGTH as u64).read_to_end(&mut chunk).await?;
+    Ok(chunk)
+}
+
+/// Bytes the [Reassembler]s sharing the budget may hold at once, for
+/// transfers that aren't complete yet. Clones share the budget.
+#[derive(Clone, Debug)]
+pub struct ReassemblyBudget {
+    limit: u64,
+    used: Arc<AtomicU64>,
+}
+
+impl ReassemblyBudget {
+    pub fn new(limit: u64) -> Self {
+        ReassemblyBudget {
+            limit,
+            used: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Bytes the reassemblers sharing the budget hold.
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Take `bytes` from the budget, if that many are left.
+    fn take(&self, bytes: u64) -> bool {
+        self.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            used.checked_add(bytes).filter(|used| *used <= self.limit)
+        }).is_ok()
+    }
+
+    fn give_back(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// A push put back together by a [Reassembler].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReassembledPush {
+    pub sequence: u64,
+    pub envelope: Envelope,
+    pub trace: Option<TraceContext>,
+    pub ack: bool,
+}
+
+struct Transfer {
+    sequence: u64,
+    total_length: u64,
+    trace: Option<TraceContext>,
+    ack: bool,
+    data: Vec<u8>,
+}
+
+/// Puts chunked pushes back together on the host. Transfers that are
+/// malformed, too long or too many, or that don't fit in the
+/// [budget](Self::with_budget), fail with [ProtocolError::InvalidTransfer].
+pub struct Reassembler {
+    max_length: u64,
+    budget: Option<ReassemblyBudget>,
+    transfers: HashMap<u32, Transfer>,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler::new(MAX_OBJECT_LENGTH)
+    }
+}
+
+impl Reassembler {
+    /// A reassembler refusing objects whose encoded envelope is longer than
+    /// `max_length`.
+    pub fn new(max_length: u64) -> Self {
+        Reassembler {
+            max_length,
+            budget: None,
+            transfers: HashMap::new(),
+        }
+    }
+
+    /// Hold the bytes of transfers that aren't complete yet within `budget`,
+    /// shared with other reassemblers.
+    pub fn with_budget(mut self, budget: ReassemblyBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Bytes received of transfers that aren't complete yet.
+    pub fn pending_bytes(&self) -> usize {
+        self.transfers.values().map(|transfer| transfer.data.len()).sum()
+    }
+
+    /// Transfers that were started and aren't complete yet.
+    pub fn open_transfers(&self) -> usize {
+        self.transfers.len()
+    }
+
+    /// Start the transfer announced by a
+    /// [PushBegin](TransferPacketGuestToHost::PushBegin) with these fields.
+    pub fn begin(&mut self, transfer_id: u32, sequence: u64, total_length: u64, trace: Option<TraceContext>, ack: bool) -> io::Result<()> {
+        let invalid = |reason: String| io::Error::from(ProtocolError::InvalidTransfer { transfer_id, reason });
+        if total_length > self.max_length {
+            return Err(invalid(format!("{total_length} bytes is more than the {} allowed", self.max_length)));
+        }
+        if self.transfers.contains_key(&transfer_id) {
+            return Err(invalid("already started".to_string()));
+        }
+        if self.transfers.len() >= MAX_OPEN_TRANSFERS {
+            return Err(invalid(format!("more than {MAX_OPEN_TRANSFERS} transfers open")));
+        }
+        self.transfers.insert(transfer_id, Transfer {
+            sequence,
+            total_length,
+            trace,
+            ack,
+            // the length is only a claim until the chunks arrive
+            data: Vec::with_capacity(total_length.min(CHUNK_LENGTH as u64) as usize),
+        });
+        Ok(())
+    }
+
+    pub fn chunk(&mut self, transfer_id: u32, data: &[u8]) -> io::Result<()> {
+        let invalid = |reason: String| io::Error::from(ProtocolError::InvalidTransfer { transfer_id, reason });
+        let Some(transfer) = self.transfers.get_mut(&transfer_id) else {
+            return Err(invalid("not started".to_string()));
+        };
+        if transfer.data.len() as u64 + data.len() as u64 > transfer.total_length {
+            self.discard(transfer_id);
+            return Err(invalid("longer than announced".to_string()));
+        }
+        if self.budget.as_ref().is_some_and(|budget| !budget.take(data.len() as u64)) {
+            self.discard(transfer_id);
+            return Err(invalid("out of memory for reassembling transfers".to_string()));
+        }
+        transfer.data.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Complete the transfer with id `transfer_id`, decoding its envelope.
+    pub fn end(&mut self, transfer_id: u32) -> io::Result<ReassembledPush> {
+        let invalid = |reason: String| io::Error::from(ProtocolError::InvalidTransfer { transfer_id, reason });
+        let Some(transfer) = self.discard(transfer_id) else {
+            return Err(invalid("not started".to_string()));
+        };
+        if transfer.data.len() as u64 != transfer.total_length {
+            return Err(invalid(format!("ended after {} of {} bytes", transfer.data.len(), transfer.total_length)));
+        }
+        Ok(ReassembledPush {
+            sequence: transfer.sequence,
+            envelope: Envelope::from_bytes(&transfer.data)?,
+            trace: transfer.trace,
+            ack: transfer.ack,
+        })
+    }
+
+    /// Stop reassembling the transfer with id `transfer_id`, giving the
+    /// bytes it held back to the budget.
+    fn discard(&mut self, transfer_id: u32) -> Option<Transfer> {
+        let transfer = self.transfers.remove(&transfer_id)?;
+        if let Some(budget) = &self.budget {
+            budget.give_back(transfer.data.len() as u64);
+        }
+        Some(transfer)
+    }
+}
+
+impl Drop for Reassembler {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.give_back(self.pending_bytes() as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use uuid::Uuid;
+
+    use crate::{Envelope, ProtocolVersion, PROTOCOL_VERSION};
+    use crate::packet::chunked::{push_packets, Reassembler, ReassemblyBudget, CHUNK_LENGTH};
+    use crate::packet::transfer::TransferPacketGuestToHost;
+
+    #[test]
+    fn test_reassembly() -> io::Result<()> {
+        let envelope = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![7; CHUNK_LENGTH * 5 / 2]);
+        let packets = push_packets(3, envelope.clone(), None, true, 1, PROTOCOL_VERSION)?;
+        assert_eq!(packets.len(), 5);
+
+        let mut reassembler = Reassembler::default();
+        let mut reassembled = None;
+        for packet in packets {
+            match packet {
+                TransferPacketGuestToHost::PushBegin { transfer_id, sequence, total_length, trace, ack } => {
+                    reassembler.begin(transfer_id, sequence, total_length, trace, ack)?;
+                }
+                TransferPacketGuestToHost::PushChunk { transfer_id, data } => {
+                    assert!(data.len() <= CHUNK_LENGTH);
+                    reassembler.chunk(transfer_id, &data)?;
+                }
+                TransferPacketGuestToHost::PushEnd { transfer_id } => reassembled = Some(reassembler.end(transfer_id)?),
+                _ => panic!("Expected chunked push packets"),
+            }
+        }
+        let reassembled = reassembled.unwrap();
+        assert_eq!((reassembled.sequence, reassembled.envelope, reassembled.ack), (3, envelope.clone(), true));
+        assert_eq!(reassembler.pending_bytes(), 0);
+
+        // hosts from before chunked pushes get one packet
+        let packets = push_packets(3, envelope, None, false, 2, ProtocolVersion::new(1, 1))?;
+        assert!(matches!(packets[..], [TransferPacketGuestToHost::Push { .. }]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_transfers() -> io::Result<()> {
+        let mut reassembler = Reassembler::new(10);
+        assert!(reassembler.begin(1, 1, 11, None, false).is_err());
+        assert!(reassembler.chunk(1, &[1]).is_err());
+
+        reassembler.begin(1, 1, 4, None, false)?;
+        assert!(reassembler.begin(1, 1, 4, None, false).is_err());
+        reassembler.chunk(1, &[1, 2])?;
+        assert!(reassembler.end(1).is_err());
+
+        reassembler.begin(2, 2, 4, None, false)?;
+        assert!(reassembler.chunk(2, &[1, 2, 3, 4, 5]).is_err());
+        assert!(reassembler.end(2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_budget() -> io::Result<()> {
+        let budget = ReassemblyBudget::new(6);
+        let mut first = Reassembler::new(10).with_budget(budget.clone());
+        let mut second = Reassembler::new(10).with_budget(budget.clone());
+        first.begin(1, 1, 4, None, false)?;
+        second.begin(1, 1, 4, None, false)?;
+        first.chunk(1, &[1, 2, 3, 4])?;
+        // the transfers together are more than the budget
+        assert!(second.chunk(1, &[1, 2, 3]).is_err());
+        second.begin(2, 2, 4, None, false)?;
+        second.chunk(2, &[1, 2])?;
+        assert_eq!(budget.used(), 6);
+
+        // discarded transfers and dropped reassemblers give their bytes back
+        assert!(first.chunk(1, &[5]).is_err());
+        assert_eq!(budget.used(), 2);
+        drop(second);
+        assert_eq!(budget.used(), 0);
+        Ok(())
+    }
+}
@@ -1,68 +1,235 @@
 //! # Handshake Packets
 //!
 
+use std::time::Duration;
+
 use bytes::{Buf, BufMut, BytesMut};
 
 use tokio::io;
 
 use uuid::Uuid;
 
-use crate::ConnectionType;
-use crate::packet::{DeserializePacket, SerializePacket};
+use crate::{Compression, ConnectionType, Invite, PayloadFormat, ProtocolError, ProtocolVersion, SensitivityFilter, VersionRange};
+use crate::packet::{DeserializePacket, SerializePacket, TaggedFields, TakeField};
+use crate::spec::DescribePackets;
+
+/// Tag of [HandshakePacketGuestToHost::Hello]'s `version`.
+const HELLO_VERSION_TAG: u8 = 1;
+/// Tag of [HandshakePacketGuestToHost::Hello]'s `compression`.
+const HELLO_COMPRESSION_TAG: u8 = 2;
+/// Tag of [HandshakePacketGuestToHost::Hello]'s `formats`.
+const HELLO_FORMATS_TAG: u8 = 3;
+/// Tag of [HandshakePacketGuestToHost::Hello]'s `software`.
+const HELLO_SOFTWARE_TAG: u8 = 4;
+/// Tag of [HandshakePacketGuestToHost::Hello]'s `timestamp`.
+const HELLO_TIMESTAMP_TAG: u8 = 5;
+/// Tag of [HandshakePacketHostToGuest::Acknowledge]'s `versions`.
+const ACKNOWLEDGE_VERSIONS_TAG: u8 = 1;
+/// Tag of [HandshakePacketHostToGuest::Acknowledge]'s `subscription_lease`.
+const ACKNOWLEDGE_LEASE_TAG: u8 = 2;
+/// Tag of [HandshakePacketHostToGuest::Acknowledge]'s `compression`.
+const ACKNOWLEDGE_COMPRESSION_TAG: u8 = 3;
+/// Tag of [HandshakePacketHostToGuest::Acknowledge]'s `format`.
+const ACKNOWLEDGE_FORMAT_TAG: u8 = 4;
+/// Tag of [HandshakePacketHostToGuest::Acknowledge]'s `software`.
+const ACKNOWLEDGE_SOFTWARE_TAG: u8 = 5;
+/// Tag of [HandshakePacketHostToGuest::Acknowledge]'s `timestamp`.
+const ACKNOWLEDGE_TIMESTAMP_TAG: u8 = 6;
+/// Tag of [HandshakePacketHostToGuest::Close]'s `reason`.
+const CLOSE_REASON_TAG: u8 = 1;
+/// Tag of [HandshakePacketHostToGuest::Close]'s `resumption`.
+const CLOSE_RESUMPTION_TAG: u8 = 2;
+/// Tag of [HandshakePacketHostToGuest::Challenge]'s `algorithm`.
+const CHALLENGE_ALGORITHM_TAG: u8 = 1;
+/// Tag of [HandshakePacketGuestToHost::Verify]'s `signature`.
+const VERIFY_SIGNATURE_TAG: u8 = 1;
+/// Tag of [HandshakePacketGuestToHost::Verify]'s `resumption`.
+const VERIFY_RESUMPTION_TAG: u8 = 2;
+
+/// Algorithm of the key a guest is challenged with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    /// The challenge is encrypted to the guest's RSA key, which proves it
+    /// holds the key by decrypting it.
+    Rsa = 0,
+    /// The challenge is sent in the clear, and the guest proves it holds the
+    /// key by signing it.
+    Ed25519 = 1,
+}
+
+impl KeyAlgorithm {
+    pub fn from_u8(algorithm: u8) -> Option<KeyAlgorithm> {
+        match algorithm {
+            0 => Some(KeyAlgorithm::Rsa),
+            1 => Some(KeyAlgorithm::Ed25519),
+            _ => None,
+        }
+    }
+}
+
+/// Why a host ended the handshake, for guests to act on without parsing the
+/// error message. Sent as a [tagged field](TaggedFields), so older guests
+/// only see the message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloseReason {
+    Other = 0,
+    /// The guest's challenge record didn't pass DNSSEC validation, on a host
+    /// that requires it.
+    InsecureChallengeRecord = 1,
+    /// The host has too many connections open, in total or from the guest's
+    /// address. Try again later.
+    TooManyConnections = 2,
+    /// The guest's address opened connections faster than the host allows.
+    /// Back off before trying again.
+    RateLimited = 3,
+}
 
+impl CloseReason {
+    pub fn from_u8(reason: u8) -> CloseReason {
+        match reason {
+            1 => CloseReason::InsecureChallengeRecord,
+            2 => CloseReason::TooManyConnections,
+            3 => CloseReason::RateLimited,
+            _ => CloseReason::Other,
+        }
+    }
+}
 
+#[derive(DescribePackets)]
 pub enum HandshakePacketGuestToHost {
     // in
+    /// Open the handshake, naming the highest protocol version we speak, and
+    /// the [Compression] algorithms we support and the [PayloadFormat]s we
+    /// read. The host picks the first of its own, in its order of preference,
+    /// that we list, falling back to no compression and
+    /// [Bincode](PayloadFormat::Bincode) if we list none it has, so the order
+    /// they are listed in doesn't matter. `software` names what we run, such as
+    /// `osp_server_sdk/0.1.0`, and `timestamp` is our clock when sending, in
+    /// unix milliseconds, for diagnostics.
+    #[packet(id = 1)]
     Hello {
         connection_type: ConnectionType,
+        #[packet(wire = "tagged field 1, u8 major and u8 minor. Guests from before versions were negotiated leave it out and speak 1.0")]
+        version: ProtocolVersion,
+        #[packet(wire = "tagged field 2, a u8 id for each algorithm, left out if empty")]
+        compression: Vec<Compression>,
+        #[packet(wire = "tagged field 3, a u8 id for each format, left out if empty")]
+        formats: Vec<PayloadFormat>,
+        #[packet(wire = "tagged field 4, UTF-8 bytes, left out if None")]
+        software: Option<String>,
+        #[packet(wire = "tagged field 5, u64 milliseconds, left out if None")]
+        timestamp: Option<u64>,
     },
-    /// Send my hostname to the other server
+    /// Send my hostname to the other server, along with an invite it issued
+    /// to us if we have one
+    #[packet(id = 2)]
     Identify {
         hostname: String,
+        #[packet(wire = "u8 presence flag, then Invite if it is 1. Guests from before invites existed end the packet after the hostname")]
+        invite: Option<Invite>,
     },
-    /// Send the client-decrypted challenge bytes back to the server
+    /// Send the client-decrypted challenge bytes back to the server, along
+    /// with their signature for [Ed25519](KeyAlgorithm::Ed25519) challenges.
+    /// `resumption` is the token of the sync state the guest kept for the
+    /// host, see [Close](HandshakePacketHostToGuest::Close).
+    #[packet(id = 3)]
     Verify {
-        challenge: Vec<u8>,
         nonce: Uuid,
+        #[packet(wire = "256 bytes, without a length prefix")]
+        challenge: Vec<u8>,
+        #[packet(wire = "tagged field 1, the bytes, left out if None")]
+        signature: Option<Vec<u8>>,
+        #[packet(wire = "tagged field 2, 16 bytes, left out if None")]
+        resumption: Option<Uuid>,
     },
 }
 
+#[derive(DescribePackets)]
 pub enum HandshakePacketHostToGuest {
     // out
+    /// Answer to [Hello](HandshakePacketGuestToHost::Hello), with the
+    /// protocol versions the host speaks. `ok` is false if the guest has
+    /// none of them in common with the host. `subscription_lease` is how
+    /// long subscriptions to the host last unless they are renewed, `None`
+    /// if they don't expire. `compression` is the algorithm the host picked
+    /// from the guest's, which frames are compressed with after this packet,
+    /// and `format` the format it picked from the guest's, which objects are
+    /// sent in. `software` and `timestamp` are the host's, as in
+    /// [Hello](HandshakePacketGuestToHost::Hello).
+    #[packet(id = 1)]
     Acknowledge {
         ok: bool,
         err: Option<String>,
+        #[packet(wire = "tagged field 1, min then max version, each u8 major and u8 minor, left out if None")]
+        versions: Option<VersionRange>,
+        #[packet(wire = "tagged field 2, u32 seconds, left out if None")]
+        subscription_lease: Option<Duration>,
+        #[packet(wire = "tagged field 3, u8 id, left out if None")]
+        compression: Compression,
+        #[packet(wire = "tagged field 4, u8 id, left out if Bincode")]
+        format: PayloadFormat,
+        #[packet(wire = "tagged field 5, UTF-8 bytes, left out if None")]
+        software: Option<String>,
+        #[packet(wire = "tagged field 6, u64 milliseconds, left out if None")]
+        timestamp: Option<u64>,
     },
 
-    /// Send the challenge bytes to the client to decrypt
+    /// Send the challenge bytes to the client to decrypt, or to sign if
+    /// `algorithm` is [Ed25519](KeyAlgorithm::Ed25519), in which case they
+    /// aren't encrypted
+    #[packet(id = 2)]
     Challenge {
+        #[packet(wire = "u16 length, then the bytes")]
         encrypted_challenge: Vec<u8>,
         nonce: Uuid,
+        #[packet(wire = "tagged field 1, u8 discriminant, left out for Rsa")]
+        algorithm: KeyAlgorithm,
     },
+    /// Ends the handshake. On success, `resumption` is the token of the
+    /// sync state the host keeps for the guest: the transfer picks up where
+    /// the last one left off if it matches the one the guest presented in
+    /// [Verify](HandshakePacketGuestToHost::Verify), and starts over
+    /// otherwise.
+    #[packet(id = 3)]
     Close {
         can_continue: bool,
-        err: Option<String>
+        err: Option<String>,
+        #[packet(wire = "tagged field 1, u8 discriminant, left out if None")]
+        reason: Option<CloseReason>,
+        #[packet(wire = "tagged field 2, 16 bytes, left out if None")]
+        resumption: Option<Uuid>,
+    },
+    /// Flagged objects the host doesn't want pushed to it. Sent after a
+    /// successful verification, before the final [Close](Self::Close).
+    #[packet(id = 4)]
+    Preferences {
+        filter: SensitivityFilter,
     },
 }
 
-impl From<&HandshakePacketGuestToHost> for u8 {
-    fn from(pkt: &HandshakePacketGuestToHost) -> Self {
-        match pkt {
-            HandshakePacketGuestToHost::Hello { .. } => 1,
-            HandshakePacketGuestToHost::Identify { .. } => 2,
-            HandshakePacketGuestToHost::Verify { .. } => 3,
-        }
+/// Put the `software` and `timestamp` of a Hello or Acknowledge under their
+/// tags, if they are set.
+fn put_diagnostics(tagged: &mut TaggedFields, software: (u8, &Option<String>), timestamp: (u8, &Option<u64>)) {
+    if let (tag, Some(software)) = software {
+        tagged.put(tag, |buf| buf.put_slice(software.as_bytes()));
+    }
+    if let (tag, Some(timestamp)) = timestamp {
+        tagged.put(tag, |buf| buf.put_u64(*timestamp));
     }
 }
 
-impl From<&HandshakePacketHostToGuest> for u8 {
-    fn from(pkt: &HandshakePacketHostToGuest) -> Self {
-        match pkt {
-            HandshakePacketHostToGuest::Acknowledge { .. } => 1,
-            HandshakePacketHostToGuest::Challenge { .. } => 2,
-            HandshakePacketHostToGuest::Close { .. } => 3
-        }
-    }
+// diagnostics that can't be read are left out, they don't affect the
+// handshake
+fn read_software(tagged: &TaggedFields, tag: u8) -> Option<String> {
+    tagged.get(tag).and_then(|value| String::from_utf8(value.to_vec()).ok())
+}
+
+fn read_timestamp(tagged: &TaggedFields, tag: u8) -> Option<u64> {
+    tagged.get(tag).filter(|value| value.remaining() >= 8).map(|mut value| value.get_u64())
+}
+
+fn read_tagged_uuid(tagged: &TaggedFields, tag: u8) -> Option<Uuid> {
+    tagged.get(tag).and_then(|value| Uuid::from_slice(&value).ok())
 }
 
 impl SerializePacket for HandshakePacketGuestToHost {
@@ -71,23 +238,75 @@ impl SerializePacket for HandshakePacketGuestToHost {
         buf.put_u8(self.into()); // Message Type byte
         let mut bytes_written: usize = 1;
         match self {
-            HandshakePacketGuestToHost::Hello { connection_type } => {
+            HandshakePacketGuestToHost::Hello { connection_type, version, compression, formats, software, timestamp } => {
                 buf.put_u8(u8::from(connection_type));
-                bytes_written += 1
+                bytes_written += 1;
+
+                let mut tagged = TaggedFields::new();
+                tagged.put(HELLO_VERSION_TAG, |buf| version.write(buf));
+                if !compression.is_empty() {
+                    tagged.put(HELLO_COMPRESSION_TAG, |buf| {
+                        for algorithm in compression {
+                            buf.put_u8(*algorithm as u8);
+                        }
+                    });
+                }
+                if !formats.is_empty() {
+                    tagged.put(HELLO_FORMATS_TAG, |buf| {
+                        for format in formats {
+                            buf.put_u8(*format as u8);
+                        }
+                    });
+                }
+                put_diagnostics(&mut tagged, (HELLO_SOFTWARE_TAG, software), (HELLO_TIMESTAMP_TAG, timestamp));
+                bytes_written += tagged.write(buf);
             }
-            HandshakePacketGuestToHost::Identify { hostname } => {
+            HandshakePacketGuestToHost::Identify { hostname, invite } => {
                 bytes_written += self.write_string(buf, hostname);
+                buf.put_u8(invite.is_some() as u8);
+                bytes_written += 1;
+                if let Some(invite) = invite {
+                    bytes_written += invite.serialize(buf)?;
+                }
             }
-            HandshakePacketGuestToHost::Verify { challenge, nonce } => {
+            HandshakePacketGuestToHost::Verify { challenge, nonce, signature, resumption } => {
                 bytes_written += self.write_uuid(buf, nonce);
 
                 // since this is always 256 bytes we can leave the len header out
                 buf.put_slice(challenge);
                 bytes_written += 256;
+
+                let mut tagged = TaggedFields::new();
+                if let Some(signature) = signature {
+                    tagged.put(VERIFY_SIGNATURE_TAG, |buf| buf.put_slice(signature));
+                }
+                if let Some(resumption) = resumption {
+                    tagged.put(VERIFY_RESUMPTION_TAG, |buf| buf.put_slice(resumption.as_bytes()));
+                }
+                bytes_written += tagged.write(buf);
             }
         }
         Ok(bytes_written)
     }
+
+    /// Records the answer to the challenge, its signature and the
+    /// signature of the invite zeroed, as they would let whoever reads the
+    /// recording pass for the guest.
+    fn serialize_redacted(&self, buf: &mut BytesMut, version: ProtocolVersion) -> io::Result<usize> {
+        match self {
+            HandshakePacketGuestToHost::Identify { hostname, invite: Some(invite) } => HandshakePacketGuestToHost::Identify {
+                hostname: hostname.clone(),
+                invite: Some(Invite { signature: vec![0; invite.signature.len()], ..invite.clone() }),
+            }.serialize_for(buf, version),
+            HandshakePacketGuestToHost::Verify { nonce, challenge, signature, resumption } => HandshakePacketGuestToHost::Verify {
+                nonce: *nonce,
+                challenge: vec![0; challenge.len()],
+                signature: signature.as_ref().map(|signature| vec![0; signature.len()]),
+                resumption: *resumption,
+            }.serialize_for(buf, version),
+            packet => packet.serialize_for(buf, version),
+        }
+    }
 }
 
 impl SerializePacket for HandshakePacketHostToGuest {
@@ -95,25 +314,61 @@ impl SerializePacket for HandshakePacketHostToGuest {
         buf.put_u8(self.into()); // Message Type byte
         let mut bytes_written: usize = 1;
         match self {
-            HandshakePacketHostToGuest::Acknowledge { ok, err } => {
+            HandshakePacketHostToGuest::Acknowledge { ok, err, versions, subscription_lease, compression, format, software, timestamp } => {
                 buf.put_u8(*ok as u8);
                 bytes_written += 1;
 
                 bytes_written += self.write_optional_string(buf, err);
+
+                let mut tagged = TaggedFields::new();
+                if let Some(versions) = versions {
+                    tagged.put(ACKNOWLEDGE_VERSIONS_TAG, |buf| versions.write(buf));
+                }
+                if let Some(lease) = subscription_lease {
+                    let seconds = lease.as_secs().min(u32::MAX as u64) as u32;
+                    tagged.put(ACKNOWLEDGE_LEASE_TAG, |buf| buf.put_u32(seconds));
+                }
+                if *compression != Compression::None {
+                    tagged.put(ACKNOWLEDGE_COMPRESSION_TAG, |buf| buf.put_u8(*compression as u8));
+                }
+                if *format != PayloadFormat::Bincode {
+                    tagged.put(ACKNOWLEDGE_FORMAT_TAG, |buf| buf.put_u8(*format as u8));
+                }
+                put_diagnostics(&mut tagged, (ACKNOWLEDGE_SOFTWARE_TAG, software), (ACKNOWLEDGE_TIMESTAMP_TAG, timestamp));
+                bytes_written += tagged.write(buf);
             }
-            HandshakePacketHostToGuest::Challenge { encrypted_challenge, nonce } => {
+            HandshakePacketHostToGuest::Challenge { encrypted_challenge, nonce, algorithm } => {
                 buf.put_u16(encrypted_challenge.len() as u16);
                 bytes_written += 2;
                 buf.put_slice(encrypted_challenge);
                 bytes_written += encrypted_challenge.len();
 
                 bytes_written += self.write_uuid(buf, nonce);
+
+                // guests from before Ed25519 keys existed only know RSA
+                let mut tagged = TaggedFields::new();
+                if *algorithm != KeyAlgorithm::Rsa {
+                    tagged.put(CHALLENGE_ALGORITHM_TAG, |buf| buf.put_u8(*algorithm as u8));
+                }
+                bytes_written += tagged.write(buf);
             }
-            HandshakePacketHostToGuest::Close { can_continue: ok, err} => {
+            HandshakePacketHostToGuest::Close { can_continue: ok, err, reason, resumption } => {
                 buf.put_u8(*ok as u8);
                 bytes_written += 1;
 
                 bytes_written += self.write_optional_string(buf, err);
+
+                let mut tagged = TaggedFields::new();
+                if let Some(reason) = reason {
+                    tagged.put(CLOSE_REASON_TAG, |buf| buf.put_u8(*reason as u8));
+                }
+                if let Some(resumption) = resumption {
+                    tagged.put(CLOSE_RESUMPTION_TAG, |buf| buf.put_slice(resumption.as_bytes()));
+                }
+                bytes_written += tagged.write(buf);
+            }
+            HandshakePacketHostToGuest::Preferences { filter } => {
+                bytes_written += filter.serialize(buf)?;
             }
         }
 
@@ -126,65 +381,257 @@ impl DeserializePacket for HandshakePacketGuestToHost {
 
     fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
         // We'll match the same `u8` that is used to recognize which request type this is
-        match buf.get_u8() {
-            1 => Ok(HandshakePacketGuestToHost::Hello {
-                connection_type: ConnectionType::from_u8(buf.get_u8()),
-            }),
+        match buf.take_u8("Packet type")? {
+            1 => {
+                let connection_type = ConnectionType::from_u8(buf.take_u8("Connection type")?);
+                let tagged = TaggedFields::read(buf)?;
+                Ok(HandshakePacketGuestToHost::Hello {
+                    connection_type,
+                    version: tagged.get(HELLO_VERSION_TAG)
+                        .and_then(|mut value| ProtocolVersion::read(&mut value))
+                        .unwrap_or(ProtocolVersion::INITIAL),
+                    // algorithms newer than this crate are skipped
+                    compression: tagged.get(HELLO_COMPRESSION_TAG)
+                        .map(|value| value.iter().filter_map(|id| Compression::from_u8(*id)).collect())
+                        .unwrap_or_default(),
+                    // and so are formats
+                    formats: tagged.get(HELLO_FORMATS_TAG)
+                        .map(|value| value.iter().filter_map(|id| PayloadFormat::from_u8(*id)).collect())
+                        .unwrap_or_default(),
+                    software: read_software(&tagged, HELLO_SOFTWARE_TAG),
+                    timestamp: read_timestamp(&tagged, HELLO_TIMESTAMP_TAG),
+                })
+            }
             2 => Ok(HandshakePacketGuestToHost::Identify {
                 hostname: Self::read_string(buf)?,
+                // guests from before invites existed end the packet here
+                invite: match buf.has_remaining() && buf.get_u8() != 0 {
+                    true => Some(Invite::deserialize(buf)?),
+                    false => None,
+                },
             }),
             3 => {
-                let nonce = Self::read_uuid(buf);
+                let nonce = Self::read_uuid(buf)?;
+                buf.need(256, "Challenge")?;
                 let mut challenge_bytes = vec![0u8; 256];
                 buf.copy_to_slice(&mut challenge_bytes);
+                let tagged = TaggedFields::read(buf)?;
 
                 Ok(HandshakePacketGuestToHost::Verify {
                     challenge: challenge_bytes,
                     nonce,
+                    signature: tagged.get(VERIFY_SIGNATURE_TAG).map(|value| value.to_vec()),
+                    resumption: read_tagged_uuid(&tagged, VERIFY_RESUMPTION_TAG),
                 })
             },
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid Request Type",
-            )),
+            id => Err(ProtocolError::InvalidPacketType { id }.into()),
         }
     }
+
+    fn redact(packet: &[u8], version: ProtocolVersion) -> io::Result<Vec<u8>> {
+        let packet = Self::deserialize_for(&mut BytesMut::from(packet), version)?;
+        let mut buf = BytesMut::new();
+        packet.serialize_redacted(&mut buf, version)?;
+        Ok(buf.to_vec())
+    }
 }
 
 impl DeserializePacket for HandshakePacketHostToGuest {
     type Output = HandshakePacketHostToGuest;
 
     fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
-        match buf.get_u8() {
-            1 => Ok(HandshakePacketHostToGuest::Acknowledge {
-                ok: buf.get_u8() != 0,
-                err: Self::read_optional_string(buf)?,
-            }),
+        match buf.take_u8("Packet type")? {
+            1 => {
+                let ok = buf.take_u8("Ok")? != 0;
+                let err = Self::read_optional_string(buf)?;
+                let tagged = TaggedFields::read(buf)?;
+                Ok(HandshakePacketHostToGuest::Acknowledge {
+                    ok,
+                    err,
+                    versions: tagged.get(ACKNOWLEDGE_VERSIONS_TAG).and_then(|mut value| VersionRange::read(&mut value)),
+                    subscription_lease: tagged.get(ACKNOWLEDGE_LEASE_TAG)
+                        .filter(|value| value.remaining() >= 4)
+                        .map(|mut value| Duration::from_secs(value.get_u32() as u64)),
+                    compression: tagged.get(ACKNOWLEDGE_COMPRESSION_TAG)
+                        .and_then(|value| value.first().copied())
+                        .and_then(Compression::from_u8)
+                        .unwrap_or_default(),
+                    format: tagged.get(ACKNOWLEDGE_FORMAT_TAG)
+                        .and_then(|value| value.first().copied())
+                        .and_then(PayloadFormat::from_u8)
+                        .unwrap_or_default(),
+                    software: read_software(&tagged, ACKNOWLEDGE_SOFTWARE_TAG),
+                    timestamp: read_timestamp(&tagged, ACKNOWLEDGE_TIMESTAMP_TAG),
+                })
+            }
             2 => {
-                let challenge_len = buf.get_u16();
+                let challenge_len = buf.take_u16("Challenge length")?;
+                buf.need(challenge_len as usize, "Challenge")?;
                 let mut challenge_encrypted = vec![0u8; challenge_len as usize];
                 buf.copy_to_slice(&mut challenge_encrypted);
 
+                let nonce = Self::read_uuid(buf)?;
+                let tagged = TaggedFields::read(buf)?;
+                let algorithm = match tagged.get(CHALLENGE_ALGORITHM_TAG).filter(|value| value.has_remaining()) {
+                    Some(mut value) => {
+                        let algorithm = value.get_u8();
+                        KeyAlgorithm::from_u8(algorithm).ok_or(ProtocolError::UnknownKeyAlgorithm { algorithm })?
+                    }
+                    None => KeyAlgorithm::Rsa,
+                };
+
                 Ok(HandshakePacketHostToGuest::Challenge {
                     encrypted_challenge: challenge_encrypted,
-                    nonce: Self::read_uuid(buf),
+                    nonce,
+                    algorithm,
                 })
             },
-            3 => Ok(HandshakePacketHostToGuest::Close {
-                can_continue: buf.get_u8() != 0,
-                err: Self::read_optional_string(buf)?,
+            3 => {
+                let can_continue = buf.take_u8("Can continue")? != 0;
+                let err = Self::read_optional_string(buf)?;
+                let tagged = TaggedFields::read(buf)?;
+                Ok(HandshakePacketHostToGuest::Close {
+                    can_continue,
+                    err,
+                    reason: tagged.get(CLOSE_REASON_TAG)
+                        .filter(|value| value.has_remaining())
+                        .map(|mut value| CloseReason::from_u8(value.get_u8())),
+                    resumption: read_tagged_uuid(&tagged, CLOSE_RESUMPTION_TAG),
+                })
+            },
+            4 => Ok(HandshakePacketHostToGuest::Preferences {
+                filter: SensitivityFilter::deserialize(buf)?,
             }),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid Request Type",
-            )),
+            id => Err(ProtocolError::InvalidPacketType { id }.into()),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
+    use bytes::{BufMut, BytesMut};
+
+    use tokio::io;
+
+    use crate::{Compression, ConnectionType, PayloadFormat, ProtocolVersion, VersionRange};
+    use crate::packet::{DeserializePacket, SerializePacket, TaggedFields};
+    use crate::packet::handshake::{CloseReason, HandshakePacketGuestToHost, HandshakePacketHostToGuest, KeyAlgorithm, HELLO_COMPRESSION_TAG};
+
     async fn serialize_handshake_packets() {
 
     }
+
+    #[test]
+    fn test_close_reason() -> io::Result<()> {
+        for reason in [None, Some(CloseReason::InsecureChallengeRecord), Some(CloseReason::RateLimited)] {
+            let buf = &mut BytesMut::new();
+            HandshakePacketHostToGuest::Close { can_continue: false, err: Some("no".to_string()), reason, resumption: None }.serialize(buf)?;
+            let HandshakePacketHostToGuest::Close { err, reason: decoded, .. } = HandshakePacketHostToGuest::deserialize(buf)? else {
+                panic!("Expected close packet");
+            };
+            assert_eq!((err.as_deref(), decoded), (Some("no"), reason));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_resumption_token() -> io::Result<()> {
+        let token = uuid::Uuid::new_v4();
+        let buf = &mut BytesMut::new();
+        HandshakePacketHostToGuest::Close { can_continue: true, err: None, reason: None, resumption: Some(token) }.serialize(buf)?;
+        let HandshakePacketHostToGuest::Close { resumption, .. } = HandshakePacketHostToGuest::deserialize(buf)? else {
+            panic!("Expected close packet");
+        };
+        assert_eq!(resumption, Some(token));
+
+        let buf = &mut BytesMut::new();
+        HandshakePacketGuestToHost::Verify { nonce: uuid::Uuid::nil(), challenge: vec![7; 256], signature: None, resumption: Some(token) }.serialize(buf)?;
+        let HandshakePacketGuestToHost::Verify { resumption, .. } = HandshakePacketGuestToHost::deserialize(buf)? else {
+            panic!("Expected verify packet");
+        };
+        assert_eq!(resumption, Some(token));
+
+        // hosts from before resumption tokens end the packet after the reason
+        let HandshakePacketHostToGuest::Close { resumption, .. } = HandshakePacketHostToGuest::deserialize(&mut BytesMut::from(&[3u8, 1, 0][..]))? else {
+            panic!("Expected close packet");
+        };
+        assert_eq!(resumption, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ed25519_challenge() -> io::Result<()> {
+        let buf = &mut BytesMut::new();
+        let nonce = uuid::Uuid::new_v4();
+        HandshakePacketHostToGuest::Challenge { encrypted_challenge: vec![7; 256], nonce, algorithm: KeyAlgorithm::Ed25519 }.serialize(buf)?;
+        let HandshakePacketHostToGuest::Challenge { algorithm, .. } = HandshakePacketHostToGuest::deserialize(buf)? else {
+            panic!("Expected challenge packet");
+        };
+        assert_eq!(algorithm, KeyAlgorithm::Ed25519);
+
+        let buf = &mut BytesMut::new();
+        HandshakePacketGuestToHost::Verify { nonce, challenge: vec![7; 256], signature: Some(vec![1; 64]), resumption: None }.serialize(buf)?;
+        let HandshakePacketGuestToHost::Verify { signature, .. } = HandshakePacketGuestToHost::deserialize(buf)? else {
+            panic!("Expected verify packet");
+        };
+        assert_eq!(signature, Some(vec![1; 64]));
+
+        // recordings don't give away the answer
+        HandshakePacketGuestToHost::Verify { nonce, challenge: vec![7; 256], signature: Some(vec![1; 64]), resumption: None }.serialize(buf)?;
+        let redacted = HandshakePacketGuestToHost::redact(&buf.split(), ProtocolVersion::INITIAL)?;
+        let HandshakePacketGuestToHost::Verify { challenge, signature, nonce: decoded, .. } = HandshakePacketGuestToHost::deserialize(&mut BytesMut::from(redacted.as_slice()))? else {
+            panic!("Expected verify packet");
+        };
+        assert_eq!((challenge, signature, decoded), (vec![0; 256], Some(vec![0; 64]), nonce));
+        Ok(())
+    }
+
+    #[test]
+    fn test_versions() -> io::Result<()> {
+        let buf = &mut BytesMut::new();
+        let software = Some("osp_server_sdk/0.1.0".to_string());
+        HandshakePacketGuestToHost::Hello {
+            connection_type: ConnectionType::Client,
+            version: ProtocolVersion::new(1, 7),
+            compression: vec![Compression::Lz4, Compression::Zstd],
+            formats: vec![PayloadFormat::Json],
+            software: software.clone(),
+            timestamp: Some(1_700_000_000_000),
+        }.serialize(buf)?;
+        let HandshakePacketGuestToHost::Hello { version, compression, formats, software: decoded, timestamp, .. } = HandshakePacketGuestToHost::deserialize(buf)? else {
+            panic!("Expected hello packet");
+        };
+        assert_eq!(version, ProtocolVersion::new(1, 7));
+        assert_eq!(compression, vec![Compression::Lz4, Compression::Zstd]);
+        assert_eq!(formats, vec![PayloadFormat::Json]);
+        assert_eq!((decoded, timestamp), (software, Some(1_700_000_000_000)));
+
+        // guests from before versions end the packet after the connection type
+        let HandshakePacketGuestToHost::Hello { version, .. } = HandshakePacketGuestToHost::deserialize(&mut BytesMut::from(&[1u8, 1][..]))? else {
+            panic!("Expected hello packet");
+        };
+        assert_eq!(version, ProtocolVersion::INITIAL);
+
+        // algorithms newer than us are skipped
+        let buf = &mut BytesMut::from(&[1u8, 1][..]);
+        let mut tagged = TaggedFields::new();
+        tagged.put(HELLO_COMPRESSION_TAG, |buf| buf.put_slice(&[9, 2]));
+        tagged.write(buf);
+        let HandshakePacketGuestToHost::Hello { compression, .. } = HandshakePacketGuestToHost::deserialize(buf)? else {
+            panic!("Expected hello packet");
+        };
+        assert_eq!(compression, vec![Compression::Lz4]);
+
+        let buf = &mut BytesMut::new();
+        let subscription_lease = Some(Duration::from_secs(86400));
+        HandshakePacketHostToGuest::Acknowledge { ok: true, err: None, versions: Some(VersionRange::SUPPORTED), subscription_lease, compression: Compression::Zstd, format: PayloadFormat::Cbor, software: None, timestamp: None }.serialize(buf)?;
+        let HandshakePacketHostToGuest::Acknowledge { versions, subscription_lease: decoded, compression, format, .. } = HandshakePacketHostToGuest::deserialize(buf)? else {
+            panic!("Expected acknowledge packet");
+        };
+        assert_eq!((versions, decoded), (Some(VersionRange::SUPPORTED), subscription_lease));
+        assert_eq!((compression, format), (Compression::Zstd, PayloadFormat::Cbor));
+        Ok(())
+    }
 }
@@ -7,18 +7,80 @@ use tokio_util::codec::{Decoder, Encoder};
 
 use uuid::Uuid;
 
+use crate::{Compression, ProtocolError, ProtocolVersion};
+use crate::recording::{FrameDirection, Recorder};
+
+pub mod chunked;
 pub mod handshake;
+mod tagged;
 pub mod transfer;
 
+pub use tagged::TaggedFields;
+
 /// The maximum length a packet can be. Any data that needs to be sent and is
 /// longer than this maximum should be chunked into multiple packets.
-const PACKET_MAX_LENGTH: usize = 8 * 1024 * 1024;
+pub(crate) const PACKET_MAX_LENGTH: usize = 8 * 1024 * 1024;
+
+/// Checked reads of the fixed width fields of a packet, which fail with
+/// [ProtocolError::Truncated] naming the field rather than panic when the
+/// packet ends before it.
+pub(crate) trait TakeField: Buf {
+    /// Fail unless `len` more bytes of `field` are left.
+    fn need(&self, len: usize, field: &str) -> io::Result<()> {
+        if self.remaining() < len {
+            return Err(ProtocolError::Truncated { field: field.to_string() }.into());
+        }
+        Ok(())
+    }
+
+    fn take_u8(&mut self, field: &str) -> io::Result<u8> {
+        self.need(1, field)?;
+        Ok(self.get_u8())
+    }
+
+    fn take_u16(&mut self, field: &str) -> io::Result<u16> {
+        self.need(2, field)?;
+        Ok(self.get_u16())
+    }
+
+    fn take_u32(&mut self, field: &str) -> io::Result<u32> {
+        self.need(4, field)?;
+        Ok(self.get_u32())
+    }
+
+    fn take_u64(&mut self, field: &str) -> io::Result<u64> {
+        self.need(8, field)?;
+        Ok(self.get_u64())
+    }
+
+    fn take_u128(&mut self, field: &str) -> io::Result<u128> {
+        self.need(16, field)?;
+        Ok(self.get_u128())
+    }
+}
+
+impl<B: Buf> TakeField for B {}
 
 /// This trait is used to serialize from a packet to a [BytesMut]
 pub trait SerializePacket {
     /// Serialize to a [BytesMut]
     fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize>;
 
+    /// Serialize for a connection that negotiated `version`. Packets that
+    /// are written differently depending on the version override this, the
+    /// others are written the same for every version.
+    fn serialize_for(&self, buf: &mut BytesMut, version: ProtocolVersion) -> io::Result<usize> {
+        let _ = version;
+        self.serialize(buf)
+    }
+
+    /// Serialize for a [recording](crate::recording) of a connection that
+    /// negotiated `version`, with the secrets the packet holds blanked out.
+    /// Packets without secrets are recorded as they are written.
+    fn serialize_redacted(&self, buf: &mut BytesMut, version: ProtocolVersion) -> io::Result<usize> {
+        self.serialize_for(buf, version)
+    }
+
     /// Write a `String` to `buf` and return how many bytes were written.
     fn write_string(&self, buf: &mut BytesMut, string: &String) -> usize where Self : Sized {
         let bytes = string.as_bytes();
@@ -27,6 +89,14 @@ pub trait SerializePacket {
         2 + bytes.len() // u16 = 2 bytes
     }
 
+    /// Write a byte slice to `buf`, prefixed with its length as a `u32`, and
+    /// return how many bytes were written.
+    fn write_bytes(&self, buf: &mut BytesMut, bytes: &[u8]) -> usize where Self: Sized {
+        buf.put_u32(bytes.len() as u32);
+        buf.put_slice(bytes);
+        4 + bytes.len() // u32 = 4 bytes
+    }
+
     /// Write an `Option<String>` to `buf` and return how many bytes were
     /// written.
     fn write_optional_string(&self, buf: &mut BytesMut, string: &Option<String>) -> usize where Self: Sized {
@@ -58,6 +128,9 @@ pub trait SerializePacket {
 }
 
 /// Trait for a packet that can be deserialized from a [BytesMut].
+///
+/// Decoding must not fail on bytes left over after the fields a packet is
+/// known to have, as newer peers append [TaggedFields] to it.
 pub trait DeserializePacket {
     /// The type that this deserializes to
     type Output;
@@ -65,53 +138,117 @@ pub trait DeserializePacket {
     /// Deserialize from a [BytesMut]
     fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output>;
 
+    /// Deserialize a packet sent over a connection that negotiated
+    /// `version`, see [SerializePacket::serialize_for].
+    fn deserialize_for(buf: &mut BytesMut, version: ProtocolVersion) -> io::Result<Self::Output> {
+        let _ = version;
+        Self::deserialize(buf)
+    }
+
+    /// The packet read as `packet` on a connection that negotiated
+    /// `version`, with its secrets blanked out for a
+    /// [recording](crate::recording), see
+    /// [SerializePacket::serialize_redacted]. Packets without secrets are
+    /// recorded as they were read.
+    fn redact(packet: &[u8], version: ProtocolVersion) -> io::Result<Vec<u8>> {
+        let _ = version;
+        Ok(packet.to_vec())
+    }
+
     /// From a given [BytesMut], read the next length (u16) and extract the
     /// string bytes, returning a [String].
     fn read_string(buf: &mut BytesMut) -> io::Result<String> {
-        let length = buf.get_u16();
+        let length = buf.take_u16("String length")?;
+        buf.need(length as usize, "String")?;
 
         // Given the length of our string, only read in that quantity of bytes
         let mut bytes = vec![0u8; length as usize];
         buf.copy_to_slice(&mut bytes);
 
         // And attempt to decode it as UTF8
-        String::from_utf8(bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid utf8"))
+        Ok(String::from_utf8(bytes).map_err(|_| ProtocolError::InvalidUtf8)?)
+    }
+
+    /// Read a `u32` length prefixed byte vector from `buf`
+    fn read_bytes(buf: &mut BytesMut) -> io::Result<Vec<u8>> {
+        let length = buf.take_u32("Byte array length")? as usize;
+        buf.need(length, "Byte array")?;
+
+        let mut bytes = vec![0u8; length];
+        buf.copy_to_slice(&mut bytes);
+        Ok(bytes)
     }
 
     /// Read an `Option<String>` from `buf`
     fn read_optional_string(buf: &mut BytesMut) -> io::Result<Option<String>> {
-        Ok(if buf.get_u8() != 0 { // if the boolean is set read the optional value
+        Ok(if buf.take_u8("Optional string flag")? != 0 { // if the boolean is set read the optional value
             Some(Self::read_string(buf)?)
         } else { None })
     }
 
     /// Read a `Uuid` from `buf`
-    fn read_uuid(buf: &mut BytesMut) -> Uuid {
-        Uuid::from_u128(buf.get_u128())
+    fn read_uuid(buf: &mut BytesMut) -> io::Result<Uuid> {
+        Ok(Uuid::from_u128(buf.take_u128("Uuid")?))
     }
 
     /// Read an `Option<Uuid>` from `buf`
-    fn read_optional_uuid(buf: &mut BytesMut) -> Option<Uuid> {
-        if buf.get_u8() != 0 { // if the boolean is set read the optional value
-            Some(Self::read_uuid(buf))
-        } else { None }
+    fn read_optional_uuid(buf: &mut BytesMut) -> io::Result<Option<Uuid>> {
+        Ok(if buf.take_u8("Optional uuid flag")? != 0 { // if the boolean is set read the optional value
+            Some(Self::read_uuid(buf)?)
+        } else { None })
     }
 }
 
 /// A tokio codec for deserializing packets that implement [DeserializePacket]
 /// in a [FramedRead]. For more information see [tokio_util::codec].
 ///
+/// Packets are read for the protocol version negotiated on the connection,
+/// [ProtocolVersion::INITIAL] until then. Once compression is negotiated,
+/// frames start with the [Compression] their packet was written with, see
+/// [compression](crate::Compression).
+///
 /// [FramedRead]: tokio_util::codec::FramedRead
 pub struct PacketDecoder<PacketType: DeserializePacket> {
+    version: ProtocolVersion,
+    compression: Compression,
+    recorder: Option<Recorder>,
     _packet_type: PhantomData<PacketType>
 }
 
 impl<PacketType: DeserializePacket> PacketDecoder<PacketType> {
     pub fn new() -> PacketDecoder<PacketType> {
         PacketDecoder::<PacketType> {
+            version: ProtocolVersion::INITIAL,
+            compression: Compression::None,
+            recorder: None,
             _packet_type: PhantomData::default(),
         }
     }
+
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    pub fn recorder(&self) -> Option<&Recorder> {
+        self.recorder.as_ref()
+    }
+
+    /// Record every packet read from now on, see [recording](crate::recording).
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.recorder = recorder;
+    }
 }
 
 impl<PacketType: DeserializePacket> Decoder for PacketDecoder<PacketType> {
@@ -132,10 +269,7 @@ impl<PacketType: DeserializePacket> Decoder for PacketDecoder<PacketType> {
         // Check that the length is not too large to avoid a denial of
         // service attack where the server runs out of memory.
         if length > PACKET_MAX_LENGTH {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", length)
-            ));
+            return Err(ProtocolError::FrameTooLarge { length }.into());
         }
 
         if src.len() < 4 + length {
@@ -152,25 +286,71 @@ impl<PacketType: DeserializePacket> Decoder for PacketDecoder<PacketType> {
 
         // Use advance to modify src such that it no longer contains
         // this frame.
-        let data = src[4..4 + length].to_vec();
+        let data = match self.compression {
+            Compression::None => src[4..4 + length].to_vec(),
+            _ => Compression::unframe(&src[4..4 + length], PACKET_MAX_LENGTH)?,
+        };
         src.advance(4 + length);
 
-        let packet = PacketType::deserialize(&mut BytesMut::from(data.as_slice()))?;
+        let packet = PacketType::deserialize_for(&mut BytesMut::from(data.as_slice()), self.version);
+        if let Some(recorder) = &self.recorder {
+            // there's no telling what in a packet that doesn't decode is
+            // secret, and it's what an incident is about, so it's recorded
+            // as read
+            let redacted = packet.as_ref().ok().and_then(|_| PacketType::redact(&data, self.version).ok());
+            recorder.record::<PacketType>(FrameDirection::Read, self.version, redacted.as_deref().unwrap_or(&data));
+        }
 
-        Ok(Some(packet))
+        Ok(Some(packet?))
     }
 }
 
+/// A tokio codec for serializing packets that implement [SerializePacket]
+/// in a [FramedWrite], for the protocol version and with the compression
+/// negotiated on the connection like [PacketDecoder].
+///
+/// [FramedWrite]: tokio_util::codec::FramedWrite
 pub struct PacketEncoder<PacketType : SerializePacket> {
+    version: ProtocolVersion,
+    compression: Compression,
+    recorder: Option<Recorder>,
     _packet_type: PhantomData<PacketType>,
 }
 
 impl<PacketType: SerializePacket> PacketEncoder<PacketType> {
     pub fn new() -> Self {
         PacketEncoder::<PacketType> {
+            version: ProtocolVersion::INITIAL,
+            compression: Compression::None,
+            recorder: None,
             _packet_type: PhantomData::default(),
         }
     }
+
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    pub fn recorder(&self) -> Option<&Recorder> {
+        self.recorder.as_ref()
+    }
+
+    /// Record every packet written from now on, see [recording](crate::recording).
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.recorder = recorder;
+    }
 }
 
 impl<PacketType: SerializePacket> Encoder<PacketType> for PacketEncoder<PacketType> {
@@ -178,13 +358,25 @@ impl<PacketType: SerializePacket> Encoder<PacketType> for PacketEncoder<PacketTy
 
     fn encode(&mut self, item: PacketType, dst: &mut BytesMut) -> Result<(), Self::Error> {
         let mut buf = &mut BytesMut::with_capacity(PACKET_MAX_LENGTH);
-        item.serialize(& mut buf)?;
+        item.serialize_for(& mut buf, self.version)?;
+        if let Some(recorder) = &self.recorder {
+            let mut redacted = BytesMut::new();
+            if item.serialize_redacted(&mut redacted, self.version).is_ok() {
+                recorder.record::<PacketType>(FrameDirection::Written, self.version, &redacted);
+            }
+        }
 
         if buf.len() > PACKET_MAX_LENGTH {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", buf.len())
-            ));
+            return Err(ProtocolError::FrameTooLarge { length: buf.len() }.into());
+        }
+        let mut framed;
+        if self.compression != Compression::None {
+            framed = BytesMut::from(self.compression.frame(buf)?.as_slice());
+            // a packet that didn't compress is a byte longer once framed
+            if framed.len() > PACKET_MAX_LENGTH {
+                return Err(ProtocolError::FrameTooLarge { length: framed.len() }.into());
+            }
+            buf = &mut framed;
         }
 
         // Convert the length into a byte array.
@@ -203,10 +395,14 @@ impl<PacketType: SerializePacket> Encoder<PacketType> for PacketEncoder<PacketTy
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
     use tokio::io;
-    use bytes::{Buf, BufMut, BytesMut};
+    use bytes::{BufMut, BytesMut};
     use uuid::Uuid;
-    use crate::packet::{DeserializePacket, PACKET_MAX_LENGTH, SerializePacket};
+    use crate::{Audience, Compression, ConnectionType, DelegatedPeer, Delegation, Envelope, FieldDescriptor, Invite, OSPUrl, PayloadFormat, ProtocolVersion, Scheme, Sensitivity, SensitivityFilter, Tombstone, TypeDescriptor, VersionRange};
+    use crate::packet::{DeserializePacket, PACKET_MAX_LENGTH, SerializePacket, TakeField};
+    use crate::packet::handshake::{CloseReason, HandshakePacketGuestToHost, HandshakePacketHostToGuest, KeyAlgorithm};
+    use crate::packet::transfer::{AckStatus, DeliveryQos, NoticeLevel, RejectCode, SubscriptionState, TraceContext, TransferPacketGuestToHost, TransferPacketHostToGuest};
 
     /// A basic test packet for validating basic serialization and
     /// deserialization of values that implement [SerializePacket] and
@@ -235,8 +431,8 @@ mod tests {
 
         fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
             Ok(TestPacket {
-                test_bool: buf.get_u8() != 0,
-                test_int: buf.get_u8(),
+                test_bool: buf.take_u8("Test bool")? != 0,
+                test_int: buf.take_u8("Test int")?,
                 test_string: Self::read_string(buf)?,
             })
         }
@@ -260,7 +456,7 @@ mod tests {
 
         fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
             Ok(TestUuidPacket {
-                test_uuid: Self::read_uuid(buf),
+                test_uuid: Self::read_uuid(buf)?,
             })
         }
     }
@@ -328,4 +524,127 @@ mod tests {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Decode every prefix of each of `packets`, which must fail with an
+    /// [UnexpectedEof](io::ErrorKind::UnexpectedEof) if it fails at all.
+    fn decode_truncated<P: SerializePacket + DeserializePacket>(packets: &[P]) -> io::Result<()> {
+        for packet in packets {
+            let buf = &mut BytesMut::new();
+            packet.serialize(buf)?;
+            for cut in 0..buf.len() {
+                if let Err(e) = P::deserialize(&mut BytesMut::from(&buf[..cut])) {
+                    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof, "cut at {cut}: {e}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_packets() -> io::Result<()> {
+        let (id, text) = (Uuid::new_v4(), Some("text".to_string()));
+        let trace = Some(TraceContext { trace_id: [1; 16], span_id: [2; 8], sampled: true });
+        let envelope = Envelope::new(id, "origin.test".to_string(), vec![1, 2, 3])
+            .with_actor("author")
+            .with_license("CC-BY-4.0")
+            .with_attribution("Test Author")
+            .with_sensitivity(Sensitivity { spoiler: true, warnings: vec!["violence".to_string()], ..Default::default() })
+            .with_trace_parent(TraceContext { trace_id: [1; 16], span_id: [2; 8], sampled: true })
+            .with_topic("blog/rust/async")
+            .with_audience(Audience::Hosts(vec!["a.test".to_string()]));
+        let delegation = Delegation {
+            delegation_id: id,
+            origin: "origin.test".to_string(),
+            relay: "relay.test".to_string(),
+            parent: "origin.test".to_string(),
+            type_id: id,
+            children: vec![DelegatedPeer { url: OSPUrl { domain: "child.test".to_string(), port: 42069, scheme: Scheme::Osp }, qos: DeliveryQos::Ordered }],
+            expires_at: Some(1),
+            signature: vec![3; 4],
+        };
+        let invite = Invite {
+            token_id: id,
+            issuer: "issuer.test".to_string(),
+            invitee: "invitee.test".to_string(),
+            public_key: "key".to_string(),
+            expires_at: Some(1),
+            signature: vec![3; 4],
+        };
+        let descriptor = TypeDescriptor {
+            type_id: id,
+            name: "Post".to_string(),
+            description: text.clone(),
+            fields: vec![FieldDescriptor { name: "title".to_string(), kind: "string".to_string(), optional: true }],
+        };
+
+        decode_truncated(&[
+            HandshakePacketGuestToHost::Hello {
+                connection_type: ConnectionType::Server,
+                version: ProtocolVersion::new(1, 2),
+                compression: vec![Compression::None],
+                formats: vec![PayloadFormat::Cbor],
+                software: text.clone(),
+                timestamp: Some(1),
+            },
+            HandshakePacketGuestToHost::Identify { hostname: "guest.test".to_string(), invite: Some(invite) },
+            HandshakePacketGuestToHost::Verify { nonce: id, challenge: vec![7; 256], signature: Some(vec![3; 4]), resumption: Some(id) },
+        ])?;
+        decode_truncated(&[
+            HandshakePacketHostToGuest::Acknowledge {
+                ok: true,
+                err: text.clone(),
+                versions: Some(VersionRange { min: ProtocolVersion::new(1, 0), max: ProtocolVersion::new(1, 2) }),
+                subscription_lease: Some(Duration::from_secs(60)),
+                compression: Compression::None,
+                format: PayloadFormat::Cbor,
+                software: text.clone(),
+                timestamp: Some(1),
+            },
+            HandshakePacketHostToGuest::Challenge { encrypted_challenge: vec![7; 32], nonce: id, algorithm: KeyAlgorithm::Ed25519 },
+            HandshakePacketHostToGuest::Close { can_continue: false, err: text.clone(), reason: Some(CloseReason::InsecureChallengeRecord), resumption: Some(id) },
+            HandshakePacketHostToGuest::Preferences { filter: SensitivityFilter { exclude_nsfw: true, exclude_spoilers: false, excluded_warnings: vec!["gore".to_string()] } },
+        ])?;
+        decode_truncated(&[
+            TransferPacketGuestToHost::Push { sequence: 1, envelope, trace, ack: true },
+            TransferPacketGuestToHost::DescribeType { type_id: id, deadline: Some(Duration::from_secs(1)) },
+            TransferPacketGuestToHost::Takedown { tombstone: Tombstone::new(id, "spam", "operator") },
+            TransferPacketGuestToHost::Delete { object_id: id },
+            TransferPacketGuestToHost::Subscribe {
+                data_types: vec![id],
+                port: Some(42069),
+                topics: vec!["news/#".to_string()],
+                qos: DeliveryQos::Ordered,
+                type_versions: vec![(id, 2)],
+                deadline: Some(Duration::from_secs(1)),
+            },
+            TransferPacketGuestToHost::Unsubscribe { data_types: vec![id] },
+            TransferPacketGuestToHost::RenewSubscription { deadline: Some(Duration::from_secs(1)) },
+            TransferPacketGuestToHost::PushBegin { transfer_id: 1, sequence: 2, total_length: 3, trace, ack: true },
+            TransferPacketGuestToHost::PushChunk { transfer_id: 1, data: vec![1, 2, 3] },
+            TransferPacketGuestToHost::PushEnd { transfer_id: 1 },
+            TransferPacketGuestToHost::Window { objects: Some(1), bytes: Some(2) },
+            TransferPacketGuestToHost::StreamBegin { transfer_id: 1, stream_id: id },
+            TransferPacketGuestToHost::StreamChunk { transfer_id: 1, data: vec![1, 2, 3] },
+            TransferPacketGuestToHost::StreamEnd { transfer_id: 1, aborted: true },
+            TransferPacketGuestToHost::Processed { sequence: 1 },
+            TransferPacketGuestToHost::Delegate { delegation },
+            TransferPacketGuestToHost::RevokeDelegation { delegation_id: id },
+            TransferPacketGuestToHost::Ping { nonce: 1 },
+            TransferPacketGuestToHost::Pong { nonce: 1 },
+        ])?;
+        decode_truncated(&[
+            TransferPacketHostToGuest::Nack { object_id: id, code: RejectCode::ReadOnly, reason: text.clone() },
+            TransferPacketHostToGuest::TypeDescription { type_id: id, descriptor: Some(descriptor) },
+            TransferPacketHostToGuest::Subscription { state: SubscriptionState::Approved, lease: Some(Duration::from_secs(60)), qos: DeliveryQos::Ordered },
+            TransferPacketHostToGuest::DeadlineExceeded { id },
+            TransferPacketHostToGuest::Overloaded { request: 5 },
+            TransferPacketHostToGuest::Ack { sequence: 1, object_id: Some(id), status: AckStatus::default() },
+            TransferPacketHostToGuest::StreamReceived { stream_id: id },
+            TransferPacketHostToGuest::Notice { level: NoticeLevel::default(), message: "text".to_string(), sunset_at: Some(1) },
+            TransferPacketHostToGuest::PushWindow { pushes: Some(1) },
+            TransferPacketHostToGuest::Ping { nonce: 1 },
+            TransferPacketHostToGuest::Pong { nonce: 1 },
+        ])?;
+        Ok(())
+    }
+}
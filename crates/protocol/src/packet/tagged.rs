@@ -0,0 +1,172 @@
+//! # Tagged Fields
+//!
+//! Packets are decoded field by field in a fixed order, so their original
+//! fields can never be renamed, reordered or removed on the wire. Fields
+//! added to a packet after its first release go into a [TaggedFields]
+//! trailer instead: `u8 tag, u32 length, value` entries written after the
+//! original fields, running to the end of the packet.
+//!
+//! This keeps peers of different versions compatible in both directions:
+//!
+//! - Older peers stop reading a packet after the fields they know, and the
+//!   frame length tells them where the next packet starts, so the trailer is
+//!   ignored.
+//! - Newer peers find no trailer in packets from older ones, and decoders
+//!   fall back to a default for every tag that is missing. Tags they don't
+//!   know are skipped.
+//!
+//! A tag identifies a field for good: once released, it must never be
+//! reused for a different field, even if the field is dropped.
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use tokio::io;
+
+use crate::ProtocolError;
+
+/// The tagged fields of a packet, see the [module](self) docs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TaggedFields {
+    fields: Vec<(u8, Vec<u8>)>,
+}
+
+impl TaggedFields {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the field `tag`, encoding its value with `write`.
+    pub fn put(&mut self, tag: u8, write: impl FnOnce(&mut BytesMut)) {
+        let mut value = BytesMut::new();
+        write(&mut value);
+        self.fields.retain(|(existing, _)| *existing != tag);
+        self.fields.push((tag, value.to_vec()));
+    }
+
+    /// The encoded value of the field `tag`, or `None` if the sender didn't
+    /// write it.
+    pub fn get(&self, tag: u8) -> Option<BytesMut> {
+        self.fields.iter()
+            .find(|(existing, _)| *existing == tag)
+            .map(|(_, value)| BytesMut::from(value.as_slice()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Write the fields to `buf` and return how many bytes were written.
+    /// Nothing is written if there are none, so the packet keeps its original
+    /// layout.
+    pub fn write(&self, buf: &mut BytesMut) -> usize {
+        let mut bytes_written = 0;
+        for (tag, value) in &self.fields {
+            buf.put_u8(*tag);
+            buf.put_u32(value.len() as u32);
+            buf.put_slice(value);
+            bytes_written += 5 + value.len();
+        }
+        bytes_written
+    }
+
+    /// Read the fields remaining in `buf`, which must hold the rest of the
+    /// packet.
+    pub fn read(buf: &mut BytesMut) -> io::Result<Self> {
+        let mut fields = Vec::new();
+        while buf.has_remaining() {
+            if buf.remaining() < 5 {
+                return Err(ProtocolError::Truncated { field: "Tagged field header".to_string() }.into());
+            }
+            let tag = buf.get_u8();
+            let length = buf.get_u32() as usize;
+            if length > buf.remaining() {
+                return Err(ProtocolError::Truncated { field: format!("Tagged field {tag}") }.into());
+            }
+            fields.push((tag, buf.split_to(length).to_vec()));
+        }
+        Ok(TaggedFields { fields })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf, BufMut, BytesMut};
+
+    use tokio::io;
+
+    use uuid::Uuid;
+
+    use crate::{ConnectionType, Envelope, ProtocolError, PROTOCOL_VERSION};
+    use crate::packet::{DeserializePacket, SerializePacket};
+    use crate::packet::handshake::HandshakePacketGuestToHost;
+    use crate::packet::tagged::TaggedFields;
+    use crate::packet::transfer::TransferPacketGuestToHost;
+
+    #[test]
+    fn test_tagged_fields() -> io::Result<()> {
+        let mut fields = TaggedFields::new();
+        fields.put(1, |buf| buf.put_u32(7));
+        fields.put(9, |buf| buf.put_slice(b"from the future"));
+
+        let buf = &mut BytesMut::new();
+        buf.put_u8(42); // an original field
+        let bytes_written = fields.write(buf);
+        assert_eq!(bytes_written, buf.len() - 1);
+
+        assert_eq!(buf.get_u8(), 42);
+        let read = TaggedFields::read(buf)?;
+        assert_eq!(read.get(1).map(|mut value| value.get_u32()), Some(7));
+        // unknown tags are kept, a missing tag is None
+        assert_eq!(read.get(9).as_deref(), Some(b"from the future".as_slice()));
+        assert!(read.get(2).is_none());
+
+        // a packet in its original layout has no trailer
+        assert!(TaggedFields::read(&mut BytesMut::new())?.is_empty());
+
+        let truncated = &mut BytesMut::from(&[1u8, 0, 0, 0, 4, 0][..]);
+        let err = TaggedFields::read(truncated).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(matches!(err.get_ref().and_then(|inner| inner.downcast_ref()), Some(ProtocolError::Truncated { .. })));
+        Ok(())
+    }
+
+    /// Packets from a newer peer, with tagged fields this version doesn't
+    /// know, still decode.
+    #[test]
+    fn test_decode_newer_layouts() -> io::Result<()> {
+        let mut newer = TaggedFields::new();
+        newer.put(1, |buf| buf.put_u64(u64::MAX));
+
+        let buf = &mut BytesMut::new();
+        HandshakePacketGuestToHost::Hello { connection_type: ConnectionType::Server, version: PROTOCOL_VERSION, compression: Vec::new(), formats: Vec::new(), software: None, timestamp: None }.serialize(buf)?;
+        newer.write(buf);
+        assert!(matches!(
+            HandshakePacketGuestToHost::deserialize(buf)?,
+            HandshakePacketGuestToHost::Hello { connection_type: ConnectionType::Server, version: PROTOCOL_VERSION, .. }
+        ));
+
+        let envelope = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![1, 2, 3]);
+        let buf = &mut BytesMut::new();
+        TransferPacketGuestToHost::Push { sequence: 3, envelope: envelope.clone(), trace: None, ack: false }.serialize(buf)?;
+        newer.write(buf);
+        let TransferPacketGuestToHost::Push { sequence, envelope: decoded, .. } = TransferPacketGuestToHost::deserialize(buf)? else {
+            panic!("Expected push packet");
+        };
+        assert_eq!((sequence, decoded), (3, envelope));
+        Ok(())
+    }
+
+    /// Identify from guests that predate invites ends after the hostname.
+    #[test]
+    fn test_decode_older_layouts() -> io::Result<()> {
+        let buf = &mut BytesMut::new();
+        buf.put_u8(2);
+        buf.put_u16(9);
+        buf.put_slice(b"peer.test");
+        let HandshakePacketGuestToHost::Identify { hostname, invite } = HandshakePacketGuestToHost::deserialize(buf)? else {
+            panic!("Expected identify packet");
+        };
+        assert_eq!((hostname.as_str(), invite.is_none()), ("peer.test", true));
+        Ok(())
+    }
+}
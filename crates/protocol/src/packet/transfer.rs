@@ -1,18 +1,824 @@
-use bytes::BytesMut;
+//! # Transfer Packets
+//!
+//! Packets exchanged once the handshake has completed.
+
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, BytesMut};
+
 use tokio::io;
-use crate::packet::{DeserializePacket, SerializePacket};
 
-pub enum TransferPacketGuestToHost {
+use uuid::Uuid;
+
+use crate::{Delegation, Envelope, ProtocolError, ProtocolVersion, Tombstone, TypeDescriptor};
+use crate::packet::{DeserializePacket, SerializePacket, TaggedFields, TakeField};
+use crate::spec::DescribePackets;
+
+const PUSH_TRACE_TAG: u8 = 1;
+const PUSH_ACK_TAG: u8 = 2;
+const PUSH_BEGIN_TRACE_TAG: u8 = 1;
+const PUSH_BEGIN_ACK_TAG: u8 = 2;
+const ACK_OBJECT_ID_TAG: u8 = 1;
+const ACK_STATUS_TAG: u8 = 2;
+const REQUEST_DEADLINE_TAG: u8 = 1;
+const SUBSCRIBE_PORT_TAG: u8 = 1;
+const SUBSCRIBE_TOPICS_TAG: u8 = 2;
+const SUBSCRIBE_QOS_TAG: u8 = 3;
+const SUBSCRIBE_TYPE_VERSIONS_TAG: u8 = 4;
+const SUBSCRIBE_DEADLINE_TAG: u8 = 5;
+const SUBSCRIPTION_LEASE_TAG: u8 = 1;
+const SUBSCRIPTION_QOS_TAG: u8 = 2;
+const WINDOW_OBJECTS_TAG: u8 = 1;
+const WINDOW_BYTES_TAG: u8 = 2;
+const STREAM_END_ABORTED_TAG: u8 = 1;
+const NOTICE_SUNSET_TAG: u8 = 1;
+const PUSH_WINDOW_PUSHES_TAG: u8 = 1;
+
+/// The protocol version that introduced acknowledged pushes. Hosts from
+/// before it never send an [Ack](TransferPacketHostToGuest::Ack), so it is
+/// only asked of hosts that speak it.
+pub const PUSH_ACK_VERSION: ProtocolVersion = ProtocolVersion::new(1, 3);
+
+/// The protocol version that introduced receive windows. Hosts from before
+/// it don't know [Window](TransferPacketGuestToHost::Window) packets, so they
+/// are only sent to hosts that speak it.
+pub const FLOW_CONTROL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 4);
+
+/// The protocol version that introduced streams. Hosts from before it don't
+/// know [StreamBegin](TransferPacketGuestToHost::StreamBegin) and the
+/// packets that follow it, so streams are only sent to hosts that speak it.
+pub const STREAM_VERSION: ProtocolVersion = ProtocolVersion::new(1, 5);
+
+/// The protocol version that introduced processed reports. Hosts from
+/// before it don't know [Processed](TransferPacketGuestToHost::Processed)
+/// packets, so they are only sent to hosts that speak it.
+pub const PROCESSED_VERSION: ProtocolVersion = ProtocolVersion::new(1, 6);
+
+/// The protocol version that introduced fan-out delegation. Hosts from
+/// before it don't know [Delegate](TransferPacketGuestToHost::Delegate)
+/// packets, so they can't relay.
+pub const FANOUT_VERSION: ProtocolVersion = ProtocolVersion::new(1, 7);
+
+/// The protocol version that introduced notices. Guests from before it
+/// don't know [Notice](TransferPacketHostToGuest::Notice) packets, so they
+/// are only sent to guests that speak it.
+pub const NOTICE_VERSION: ProtocolVersion = ProtocolVersion::new(1, 8);
+
+/// The protocol version that introduced push windows. Guests from before it
+/// don't know [PushWindow](TransferPacketHostToGuest::PushWindow) packets,
+/// so they are only sent to guests that speak it, and they push as fast as
+/// they like.
+pub const PUSH_WINDOW_VERSION: ProtocolVersion = ProtocolVersion::new(1, 9);
+
+/// The protocol version that introduced keepalives. Peers from before it
+/// don't know [Ping](TransferPacketGuestToHost::Ping) and
+/// [Pong](TransferPacketGuestToHost::Pong) packets, so they are never
+/// pinged, and connections with them stay open however long they are idle.
+pub const KEEPALIVE_VERSION: ProtocolVersion = ProtocolVersion::new(1, 10);
+
+/// Write the deadline of a request as whole milliseconds, saturating.
+fn write_deadline(tagged: &mut TaggedFields, tag: u8, deadline: &Option<Duration>) {
+    if let Some(deadline) = deadline {
+        let millis = deadline.as_millis().min(u32::MAX as u128) as u32;
+        tagged.put(tag, |buf| buf.put_u32(millis));
+    }
+}
+
+fn read_deadline(tagged: &TaggedFields, tag: u8) -> Option<Duration> {
+    tagged.get(tag)
+        .filter(|value| value.remaining() >= 4)
+        .map(|mut value| Duration::from_millis(value.get_u32() as u64))
+}
+
+/// Why a host refused an object.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RejectCode {
+    Other = 0,
+    /// The host is a read-only node and does not accept pushed content.
+    ReadOnly = 1,
+    /// Storing the object would exceed the host's storage quota.
+    QuotaExceeded = 2,
+    /// The object was refused by one of the host's validators.
+    Invalid = 3,
+    /// The host's routing policy does not accept the object.
+    Policy = 4,
+}
+
+impl RejectCode {
+    pub(crate) fn from_u8(code: u8) -> RejectCode {
+        match code {
+            1 => RejectCode::ReadOnly,
+            2 => RejectCode::QuotaExceeded,
+            3 => RejectCode::Invalid,
+            4 => RejectCode::Policy,
+            _ => RejectCode::Other,
+        }
+    }
+}
+
+/// How a host took in an object it acknowledges.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AckStatus {
+    /// The object was stored and handled.
+    #[default]
+    Processed = 0,
+    /// The object was stored, and the host's handlers process it later.
+    Deferred = 1,
+    /// The object was stored, but the host's handlers had no use for it.
+    Ignored = 2,
+    /// The host had the object already, so it was dropped.
+    Duplicate = 3,
+    /// The host refused the object, see the
+    /// [Nack](TransferPacketHostToGuest::Nack) sent before.
+    Refused = 4,
+}
+
+impl AckStatus {
+    /// The status with id `status`, or `None` if it is unknown.
+    pub fn from_u8(status: u8) -> Option<AckStatus> {
+        match status {
+            0 => Some(AckStatus::Processed),
+            1 => Some(AckStatus::Deferred),
+            2 => Some(AckStatus::Ignored),
+            3 => Some(AckStatus::Duplicate),
+            4 => Some(AckStatus::Refused),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AckStatus::Processed => "processed",
+            AckStatus::Deferred => "deferred",
+            AckStatus::Ignored => "ignored",
+            AckStatus::Duplicate => "duplicate",
+            AckStatus::Refused => "refused",
+        }
+    }
+}
+
+/// How urgent a [Notice](TransferPacketHostToGuest::Notice) is.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, PartialOrd, Ord)]
+pub enum NoticeLevel {
+    /// Worth knowing, e.g. a new data type the host serves.
+    #[default]
+    Info = 0,
+    /// Needs the operator to act eventually, e.g. a deprecated data type.
+    Warning = 1,
+    /// Needs the operator to act before the sunset, e.g. because the host
+    /// shuts down.
+    Critical = 2,
+}
+
+impl NoticeLevel {
+    /// The level with id `level`. Levels this crate doesn't know are taken
+    /// to be warnings.
+    pub fn from_u8(level: u8) -> NoticeLevel {
+        match level {
+            0 => NoticeLevel::Info,
+            1 => NoticeLevel::Warning,
+            2 => NoticeLevel::Critical,
+            _ => NoticeLevel::Warning,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NoticeLevel::Info => "info",
+            NoticeLevel::Warning => "warning",
+            NoticeLevel::Critical => "critical",
+        }
+    }
+}
+
+/// Where a subscription stands with the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SubscriptionState {
+    /// Waiting for the host's operator to approve it.
+    Pending = 0,
+    Approved = 1,
+    Denied = 2,
+    /// The guest has no subscription to renew, as it never subscribed or
+    /// unsubscribed since. Only answers
+    /// [RenewSubscription](TransferPacketGuestToHost::RenewSubscription).
+    Unsubscribed = 3,
+}
+
+impl SubscriptionState {
+    pub fn from_u8(state: u8) -> SubscriptionState {
+        match state {
+            1 => SubscriptionState::Approved,
+            2 => SubscriptionState::Denied,
+            3 => SubscriptionState::Unsubscribed,
+            _ => SubscriptionState::Pending,
+        }
+    }
+}
+
+/// How a host delivers the objects a subscription takes to the subscriber.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum DeliveryQos {
+    /// Pushed once, and dropped if that fails.
+    FireAndForget = 0,
+    /// Queued until the subscriber acknowledges it, and retried until it
+    /// does, so it may be received more than once.
+    #[default]
+    AtLeastOnce = 1,
+    /// Like [AtLeastOnce](DeliveryQos::AtLeastOnce), and received in the
+    /// order it was queued in: later objects wait while an earlier one is
+    /// retried.
+    Ordered = 2,
+}
+
+impl DeliveryQos {
+    /// The level with id `qos`, or `None` if it is unknown.
+    pub fn from_u8(qos: u8) -> Option<DeliveryQos> {
+        match qos {
+            0 => Some(DeliveryQos::FireAndForget),
+            1 => Some(DeliveryQos::AtLeastOnce),
+            2 => Some(DeliveryQos::Ordered),
+            _ => None,
+        }
+    }
+
+    /// Whether objects delivered with this level are acknowledged.
+    pub fn is_acked(&self) -> bool {
+        *self != DeliveryQos::FireAndForget
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeliveryQos::FireAndForget => "fire_and_forget",
+            DeliveryQos::AtLeastOnce => "at_least_once",
+            DeliveryQos::Ordered => "ordered",
+        }
+    }
+
+    fn write(&self, tagged: &mut TaggedFields, tag: u8) {
+        if *self != DeliveryQos::default() {
+            tagged.put(tag, |buf| buf.put_u8(*self as u8));
+        }
+    }
+
+    /// Read a level written by [write](Self::write). Levels this crate
+    /// doesn't know fall back to the default.
+    fn read(tagged: &TaggedFields, tag: u8) -> DeliveryQos {
+        tagged.get(tag)
+            .filter(|value| value.has_remaining())
+            .and_then(|mut value| DeliveryQos::from_u8(value.get_u8()))
+            .unwrap_or_default()
+    }
+}
+
+/// How much more a subscriber can take in before the host has to pause
+/// delivering to it: `objects` objects and `bytes` bytes of payload, where
+/// `None` is no limit. The default has neither.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct ReceiveWindow {
+    pub objects: Option<u32>,
+    pub bytes: Option<u64>,
+}
+
+impl ReceiveWindow {
+    /// A window of `objects` objects, however large.
+    pub fn objects(objects: u32) -> Self {
+        ReceiveWindow { objects: Some(objects), bytes: None }
+    }
+
+    /// A window of `bytes` bytes of payload, however many objects.
+    pub fn bytes(bytes: u64) -> Self {
+        ReceiveWindow { objects: None, bytes: Some(bytes) }
+    }
+
+    /// Whether the window limits neither objects nor bytes.
+    pub fn is_unbounded(&self) -> bool {
+        self.objects.is_none() && self.bytes.is_none()
+    }
+}
+
+/// Write the flag `tag`, which has no value, if `set`.
+fn write_flag(tagged: &mut TaggedFields, tag: u8, set: bool) {
+    if set {
+        tagged.put(tag, |_| {});
+    }
+}
 
+/// The trace a pushed object was sent in, so the host can continue it and
+/// the work on both nodes shows up as one trace. Follows the W3C Trace
+/// Context ids.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    /// Id of the span on the guest the object was pushed in.
+    pub span_id: [u8; 8],
+    /// Whether the guest records the trace.
+    pub sampled: bool,
 }
 
+impl TraceContext {
+    const ENCODED_LEN: usize = 25;
+
+    /// The context as a W3C `traceparent` header value, such as
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    pub fn to_traceparent(&self) -> String {
+        let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+        format!("00-{}-{}-{:02x}", hex(&self.trace_id), hex(&self.span_id), self.sampled as u8)
+    }
+
+    /// Parse a W3C `traceparent` header value. Returns `None` if it is
+    /// malformed or has an all-zero id, which the spec makes invalid.
+    pub fn from_traceparent(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.split('-');
+        let (version, trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        // later versions may append fields, version 00 may not
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        fn unhex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+            if hex.len() != N * 2 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                return None;
+            }
+            let mut bytes = [0; N];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+            }
+            Some(bytes)
+        }
+        let context = TraceContext {
+            trace_id: unhex(trace_id)?,
+            span_id: unhex(span_id)?,
+            sampled: unhex::<1>(flags)?[0] & 1 == 1,
+        };
+        (context.trace_id != [0; 16] && context.span_id != [0; 8]).then_some(context)
+    }
+
+    fn write(&self, buf: &mut BytesMut) {
+        buf.put_slice(&self.trace_id);
+        buf.put_slice(&self.span_id);
+        buf.put_u8(self.sampled as u8);
+    }
+
+    fn read(mut buf: BytesMut) -> Option<Self> {
+        if buf.remaining() < Self::ENCODED_LEN {
+            return None;
+        }
+        let mut context = TraceContext { trace_id: [0; 16], span_id: [0; 8], sampled: false };
+        buf.copy_to_slice(&mut context.trace_id);
+        buf.copy_to_slice(&mut context.span_id);
+        context.sampled = buf.get_u8() & 1 == 1;
+        Some(context)
+    }
+}
+
+#[derive(DescribePackets)]
+pub enum TransferPacketGuestToHost {
+    /// Push an object to the host. `sequence` increases by one with every
+    /// object pushed to the same host, across connections. If `ack` is set,
+    /// the host answers with an [Ack](TransferPacketHostToGuest::Ack) once it
+    /// handled the object, which is only asked of hosts that speak
+    /// [PUSH_ACK_VERSION].
+    #[packet(id = 1)]
+    Push {
+        sequence: u64,
+        envelope: Envelope,
+        #[packet(wire = "tagged field 1, 16 byte trace id, 8 byte span id and u8 sampled flag, left out if None")]
+        trace: Option<TraceContext>,
+        #[packet(wire = "tagged field 2, empty, left out if false")]
+        ack: bool,
+    },
+    /// Ask the host to describe the data type with id `type_id`. If the
+    /// guest stops waiting for the answer after `deadline`, the host gives up
+    /// once it has passed and answers with
+    /// [TransferPacketHostToGuest::DeadlineExceeded] instead.
+    #[packet(id = 2)]
+    DescribeType {
+        type_id: Uuid,
+        #[packet(wire = "tagged field 1, u32 milliseconds, left out if None")]
+        deadline: Option<Duration>,
+    },
+    /// An object was taken down. The host should remove its copy and keep
+    /// the tombstone in its place.
+    #[packet(id = 3)]
+    Takedown {
+        tombstone: Tombstone,
+    },
+    /// An object was deleted, e.g. because its actor's data was purged. The
+    /// host should delete its copy.
+    #[packet(id = 4)]
+    Delete {
+        object_id: Uuid,
+    },
+    /// Ask the host to send us objects of the given data types. Sending it
+    /// again replaces the requested types, or checks on a pending request.
+    /// Once approved, the host pushes new objects of those types to `port`
+    /// at the guest's hostname. Guests that don't listen, like clients,
+    /// leave it out and only record their interest. `topics` limits the
+    /// subscription to objects published under a topic matching one of the
+    /// filters, such as `blog/rust/*` or `blog/#`, empty for every object.
+    /// `data_types`, `topics` and `type_versions` each hold at most 65535
+    /// entries, and fewer if they would make the packet longer than the
+    /// 8 MiB packets may be. `qos` is how the host should deliver them.
+    /// `type_versions` names the latest version of data types the guest
+    /// understands, so the host translates later versions down for it;
+    /// types left out are sent as they are. If the guest stops waiting for
+    /// the answer after `deadline`, the host answers with
+    /// [TransferPacketHostToGuest::DeadlineExceeded] once it has passed.
+    #[packet(id = 5)]
+    Subscribe {
+        data_types: Vec<Uuid>,
+        #[packet(wire = "tagged field 1, u16 port, left out if None")]
+        port: Option<u16>,
+        #[packet(wire = "tagged field 2, u16 count then each filter as String, left out if empty")]
+        topics: Vec<String>,
+        #[packet(wire = "tagged field 3, u8 level, left out if AtLeastOnce")]
+        qos: DeliveryQos,
+        #[packet(wire = "tagged field 4, u16 count then each type id as UUID and u16 version, left out if empty")]
+        type_versions: Vec<(Uuid, u16)>,
+        #[packet(wire = "tagged field 5, u32 milliseconds, left out if None")]
+        deadline: Option<Duration>,
+    },
+    /// Stop sending us objects of the given data types, or of every type if
+    /// `data_types` is empty, which ends the subscription.
+    #[packet(id = 6)]
+    Unsubscribe {
+        data_types: Vec<Uuid>,
+    },
+    /// Extend the lease of our subscription, so the host keeps sending us
+    /// objects. Hosts whose subscriptions are leased stop once a lease runs
+    /// out, until the guest renews or subscribes again. `deadline` works as
+    /// for [Subscribe](TransferPacketGuestToHost::Subscribe).
+    #[packet(id = 7)]
+    RenewSubscription {
+        #[packet(wire = "tagged field 1, u32 milliseconds, left out if None")]
+        deadline: Option<Duration>,
+    },
+    /// Start pushing an object too large for one packet, in chunks of its
+    /// encoded envelope, see [chunked](crate::packet::chunked). `sequence`
+    /// and `trace` are those of a [Push](TransferPacketGuestToHost::Push),
+    /// `total_length` is the length of the encoded envelope. Only sent to
+    /// hosts that speak
+    /// [CHUNKED_PUSH_VERSION](crate::packet::chunked::CHUNKED_PUSH_VERSION).
+    /// `ack` asks for an [Ack](TransferPacketHostToGuest::Ack) once the host
+    /// handled the object, like that of a Push.
+    #[packet(id = 8)]
+    PushBegin {
+        transfer_id: u32,
+        sequence: u64,
+        total_length: u64,
+        #[packet(wire = "tagged field 1, 16 byte trace id, 8 byte span id and u8 sampled flag, left out if None")]
+        trace: Option<TraceContext>,
+        #[packet(wire = "tagged field 2, empty, left out if false")]
+        ack: bool,
+    },
+    /// The next chunk of the transfer with id `transfer_id`.
+    #[packet(id = 9)]
+    PushChunk {
+        transfer_id: u32,
+        data: Vec<u8>,
+    },
+    /// Every chunk of the transfer with id `transfer_id` was sent, the host
+    /// handles the object like a [Push](TransferPacketGuestToHost::Push).
+    #[packet(id = 10)]
+    PushEnd {
+        transfer_id: u32,
+    },
+    /// Advertise the receive window of our subscription: how many more
+    /// objects, and bytes of their payload, the guest can take in. The host
+    /// pauses delivering to the guest once it used up the window, until it
+    /// is advertised again. Leaving both out lifts the window. Only sent to
+    /// hosts that speak [FLOW_CONTROL_VERSION].
+    #[packet(id = 11)]
+    Window {
+        #[packet(wire = "tagged field 1, u32, left out if None")]
+        objects: Option<u32>,
+        #[packet(wire = "tagged field 2, u64, left out if None")]
+        bytes: Option<u64>,
+    },
+    /// Start streaming the bytes of an attachment, such as an image or an
+    /// audio enclosure, with id `stream_id`. Unlike a chunked push, the
+    /// bytes aren't an envelope and the host hands them to its stream
+    /// handler as they arrive, instead of putting them together first. The
+    /// host
+    /// answers [StreamEnd](TransferPacketGuestToHost::StreamEnd) with
+    /// [StreamReceived](TransferPacketHostToGuest::StreamReceived), or a
+    /// [Nack](TransferPacketHostToGuest::Nack) for `stream_id` if it
+    /// refused the stream. Only sent to hosts that speak [STREAM_VERSION].
+    #[packet(id = 12)]
+    StreamBegin {
+        transfer_id: u32,
+        stream_id: Uuid,
+    },
+    /// The next bytes of the stream with transfer id `transfer_id`.
+    #[packet(id = 13)]
+    StreamChunk {
+        transfer_id: u32,
+        data: Vec<u8>,
+    },
+    /// The stream with transfer id `transfer_id` ended. If `aborted` is set
+    /// the guest could not read it to the end, and the host drops what it
+    /// received of it.
+    #[packet(id = 14)]
+    StreamEnd {
+        transfer_id: u32,
+        #[packet(wire = "tagged field 1, empty, left out if false")]
+        aborted: bool,
+    },
+    /// Report that the guest durably processed every object the host
+    /// delivered to it up to the one pushed with `sequence`, so the host
+    /// knows how far behind its subscriber is. Only sent to hosts that speak
+    /// [PROCESSED_VERSION].
+    #[packet(id = 15)]
+    Processed {
+        sequence: u64,
+    },
+    /// Make the host a relay for the objects of the guest, the origin of
+    /// the delegation, see [Delegation]. Sending a delegation with the id of
+    /// one the host holds replaces it. The host answers with a
+    /// [Nack](TransferPacketHostToGuest::Nack) for the delegation id if it
+    /// refuses the delegation. Only sent to hosts that speak
+    /// [FANOUT_VERSION].
+    #[packet(id = 16)]
+    Delegate {
+        delegation: Delegation,
+    },
+    /// Stop relaying the objects of the guest along the delegation with id
+    /// `delegation_id`.
+    #[packet(id = 17)]
+    RevokeDelegation {
+        delegation_id: Uuid,
+    },
+    /// Check that the host is still there, after the guest heard nothing
+    /// from it for a while. The host answers with a
+    /// [Pong](TransferPacketHostToGuest::Pong) with the same `nonce`. Only
+    /// sent to hosts that speak [KEEPALIVE_VERSION].
+    #[packet(id = 18)]
+    Ping {
+        nonce: u64,
+    },
+    /// Answer to [TransferPacketHostToGuest::Ping].
+    #[packet(id = 19)]
+    Pong {
+        nonce: u64,
+    },
+}
+
+#[derive(DescribePackets)]
 pub enum TransferPacketHostToGuest {
+    /// The host refused the object with id `object_id`.
+    #[packet(id = 1)]
+    Nack {
+        object_id: Uuid,
+        code: RejectCode,
+        reason: Option<String>,
+    },
+    /// Answer to [TransferPacketGuestToHost::DescribeType]. `descriptor` is
+    /// `None` if the host doesn't know the type.
+    #[packet(id = 2)]
+    TypeDescription {
+        type_id: Uuid,
+        descriptor: Option<TypeDescriptor>,
+    },
+    /// Answer to [TransferPacketGuestToHost::Subscribe] and
+    /// [TransferPacketGuestToHost::RenewSubscription]. `lease` is how long
+    /// the subscription lasts unless it is renewed, `None` if it doesn't
+    /// expire. `qos` is how the host delivers to the subscription, which
+    /// hosts from before delivery levels leave out.
+    #[packet(id = 3)]
+    Subscription {
+        state: SubscriptionState,
+        #[packet(wire = "tagged field 1, u32 seconds, left out if None")]
+        lease: Option<Duration>,
+        #[packet(wire = "tagged field 2, u8 level, left out if AtLeastOnce")]
+        qos: DeliveryQos,
+    },
+    /// The host gave up on a request with a deadline, such as
+    /// [TransferPacketGuestToHost::DescribeType], because it passed. `id` is
+    /// what the request asked for, e.g. the type id, and nil for
+    /// subscription requests.
+    #[packet(id = 4)]
+    DeadlineExceeded {
+        id: Uuid,
+    },
+    /// The host is too busy to serve a request and shed it, the guest may
+    /// try again later. `request` is the packet id of the request, e.g. 2
+    /// for [TransferPacketGuestToHost::DescribeType].
+    #[packet(id = 5)]
+    Overloaded {
+        request: u8,
+    },
+    /// The host handled the object with id `object_id` pushed with
+    /// `sequence` and asking for an ack, `status` tells how. If it refused
+    /// the object, the [Nack](TransferPacketHostToGuest::Nack) was sent
+    /// before. Hosts from before statuses leave out both.
+    #[packet(id = 6)]
+    Ack {
+        sequence: u64,
+        #[packet(wire = "tagged field 1, UUID, left out if None")]
+        object_id: Option<Uuid>,
+        #[packet(wire = "tagged field 2, u8 status, left out if Processed")]
+        status: AckStatus,
+    },
+    /// The host's stream handler took in the whole stream with id
+    /// `stream_id`.
+    #[packet(id = 7)]
+    StreamReceived {
+        stream_id: Uuid,
+    },
+    /// A notice of the host for the operator of the guest, such as an
+    /// upcoming shutdown or a deprecated data type. `sunset_at` is the unix
+    /// timestamp (seconds) what it announces takes effect at, e.g. when the
+    /// host shuts down. Hosts send the notices they have once the transfer
+    /// phase starts, and those published later as the guest makes further
+    /// requests. Only sent to guests that speak [NOTICE_VERSION].
+    #[packet(id = 8)]
+    Notice {
+        level: NoticeLevel,
+        message: String,
+        #[packet(wire = "tagged field 1, u64 unix timestamp, left out if None")]
+        sunset_at: Option<u64>,
+    },
+    /// How many pushes the guest may have in flight: pushed asking for an
+    /// [Ack](TransferPacketHostToGuest::Ack) that wasn't sent yet. `pushes`
+    /// is `None` if there is no limit, a window of 0 is taken as 1. Hosts
+    /// send it as the first packet of the transfer phase, and may send it
+    /// again to change the window. Guests then ask for an ack with every
+    /// push, and wait for acks before pushing beyond the window. Only sent
+    /// to guests that speak [PUSH_WINDOW_VERSION].
+    #[packet(id = 9)]
+    PushWindow {
+        #[packet(wire = "tagged field 1, u32, left out if None")]
+        pushes: Option<u32>,
+    },
+    /// Check that the guest is still there, after the host heard nothing
+    /// from it for a while. The guest answers with a
+    /// [Pong](TransferPacketGuestToHost::Pong) with the same `nonce`. Only
+    /// sent to guests that speak [KEEPALIVE_VERSION].
+    #[packet(id = 10)]
+    Ping {
+        nonce: u64,
+    },
+    /// Answer to [TransferPacketGuestToHost::Ping].
+    #[packet(id = 11)]
+    Pong {
+        nonce: u64,
+    },
+}
+
+/// The u16 count prefix of `len` entries of `field`, failing if they don't
+/// fit in one.
+fn count(field: &str, len: usize) -> Result<u16, ProtocolError> {
+    u16::try_from(len).map_err(|_| ProtocolError::TooMany { field: field.to_string(), count: len })
+}
+
+impl TransferPacketGuestToHost {
+    fn write_type_ids(&self, buf: &mut BytesMut, type_ids: &[Uuid]) -> io::Result<usize> {
+        buf.put_u16(count("data types", type_ids.len())?);
+        let mut bytes_written = 2;
+        for type_id in type_ids {
+            bytes_written += self.write_uuid(buf, type_id);
+        }
+        Ok(bytes_written)
+    }
 
+    fn read_type_ids(buf: &mut BytesMut) -> io::Result<Vec<Uuid>> {
+        let count = buf.take_u16("Data type count")?;
+        (0..count).map(|_| Self::read_uuid(buf)).collect()
+    }
 }
 
 impl SerializePacket for TransferPacketGuestToHost {
     fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
-        todo!()
+        buf.put_u8(self.into()); // Message Type byte
+        let mut bytes_written: usize = 1;
+        match self {
+            TransferPacketGuestToHost::Push { sequence, envelope, trace, ack } => {
+                buf.put_u64(*sequence);
+                bytes_written += 8;
+                bytes_written += envelope.serialize(buf)?;
+
+                let mut tagged = TaggedFields::new();
+                if let Some(trace) = trace {
+                    tagged.put(PUSH_TRACE_TAG, |buf| trace.write(buf));
+                }
+                write_flag(&mut tagged, PUSH_ACK_TAG, *ack);
+                bytes_written += tagged.write(buf);
+            }
+            TransferPacketGuestToHost::DescribeType { type_id, deadline } => {
+                bytes_written += self.write_uuid(buf, type_id);
+
+                let mut tagged = TaggedFields::new();
+                write_deadline(&mut tagged, REQUEST_DEADLINE_TAG, deadline);
+                bytes_written += tagged.write(buf);
+            }
+            TransferPacketGuestToHost::Takedown { tombstone } => {
+                bytes_written += tombstone.serialize(buf)?;
+            }
+            TransferPacketGuestToHost::Delete { object_id } => {
+                bytes_written += self.write_uuid(buf, object_id);
+            }
+            TransferPacketGuestToHost::Subscribe { data_types, port, topics, qos, type_versions, deadline } => {
+                bytes_written += self.write_type_ids(buf, data_types)?;
+                let (topic_count, version_count) = (count("topics", topics.len())?, count("type versions", type_versions.len())?);
+
+                let mut tagged = TaggedFields::new();
+                if let Some(port) = port {
+                    tagged.put(SUBSCRIBE_PORT_TAG, |buf| buf.put_u16(*port));
+                }
+                if !topics.is_empty() {
+                    tagged.put(SUBSCRIBE_TOPICS_TAG, |buf| {
+                        buf.put_u16(topic_count);
+                        for topic in topics {
+                            self.write_string(buf, topic);
+                        }
+                    });
+                }
+                qos.write(&mut tagged, SUBSCRIBE_QOS_TAG);
+                if !type_versions.is_empty() {
+                    tagged.put(SUBSCRIBE_TYPE_VERSIONS_TAG, |buf| {
+                        buf.put_u16(version_count);
+                        for (type_id, version) in type_versions {
+                            self.write_uuid(buf, type_id);
+                            buf.put_u16(*version);
+                        }
+                    });
+                }
+                write_deadline(&mut tagged, SUBSCRIBE_DEADLINE_TAG, deadline);
+                bytes_written += tagged.write(buf);
+            }
+            TransferPacketGuestToHost::Unsubscribe { data_types } => {
+                bytes_written += self.write_type_ids(buf, data_types)?;
+            }
+            TransferPacketGuestToHost::RenewSubscription { deadline } => {
+                let mut tagged = TaggedFields::new();
+                write_deadline(&mut tagged, REQUEST_DEADLINE_TAG, deadline);
+                bytes_written += tagged.write(buf);
+            }
+            TransferPacketGuestToHost::PushBegin { transfer_id, sequence, total_length, trace, ack } => {
+                buf.put_u32(*transfer_id);
+                buf.put_u64(*sequence);
+                buf.put_u64(*total_length);
+                bytes_written += 20;
+
+                let mut tagged = TaggedFields::new();
+                if let Some(trace) = trace {
+                    tagged.put(PUSH_BEGIN_TRACE_TAG, |buf| trace.write(buf));
+                }
+                write_flag(&mut tagged, PUSH_BEGIN_ACK_TAG, *ack);
+                bytes_written += tagged.write(buf);
+            }
+            TransferPacketGuestToHost::PushChunk { transfer_id, data } => {
+                buf.put_u32(*transfer_id);
+                bytes_written += 4;
+                bytes_written += self.write_bytes(buf, data);
+            }
+            TransferPacketGuestToHost::PushEnd { transfer_id } => {
+                buf.put_u32(*transfer_id);
+                bytes_written += 4;
+            }
+            TransferPacketGuestToHost::Window { objects, bytes } => {
+                let mut tagged = TaggedFields::new();
+                if let Some(objects) = objects {
+                    tagged.put(WINDOW_OBJECTS_TAG, |buf| buf.put_u32(*objects));
+                }
+                if let Some(bytes) = bytes {
+                    tagged.put(WINDOW_BYTES_TAG, |buf| buf.put_u64(*bytes));
+                }
+                bytes_written += tagged.write(buf);
+            }
+            TransferPacketGuestToHost::StreamBegin { transfer_id, stream_id } => {
+                buf.put_u32(*transfer_id);
+                bytes_written += 4;
+                bytes_written += self.write_uuid(buf, stream_id);
+            }
+            TransferPacketGuestToHost::StreamChunk { transfer_id, data } => {
+                buf.put_u32(*transfer_id);
+                bytes_written += 4;
+                bytes_written += self.write_bytes(buf, data);
+            }
+            TransferPacketGuestToHost::StreamEnd { transfer_id, aborted } => {
+                buf.put_u32(*transfer_id);
+                bytes_written += 4;
+
+                let mut tagged = TaggedFields::new();
+                write_flag(&mut tagged, STREAM_END_ABORTED_TAG, *aborted);
+                bytes_written += tagged.write(buf);
+            }
+            TransferPacketGuestToHost::Processed { sequence } => {
+                buf.put_u64(*sequence);
+                bytes_written += 8;
+            }
+            TransferPacketGuestToHost::Delegate { delegation } => {
+                bytes_written += delegation.serialize(buf)?;
+            }
+            TransferPacketGuestToHost::RevokeDelegation { delegation_id } => {
+                bytes_written += self.write_uuid(buf, delegation_id);
+            }
+            TransferPacketGuestToHost::Ping { nonce } | TransferPacketGuestToHost::Pong { nonce } => {
+                buf.put_u64(*nonce);
+                bytes_written += 8;
+            }
+        }
+        Ok(bytes_written)
     }
 }
 
@@ -20,13 +826,211 @@ impl DeserializePacket for TransferPacketGuestToHost {
     type Output = TransferPacketGuestToHost;
 
     fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
-        todo!()
+        match buf.take_u8("Packet type")? {
+            1 => {
+                let sequence = buf.take_u64("Sequence")?;
+                let envelope = Envelope::deserialize(buf)?;
+                let tagged = TaggedFields::read(buf)?;
+                Ok(TransferPacketGuestToHost::Push {
+                    sequence,
+                    envelope,
+                    // a malformed context only costs the trace its parent
+                    trace: tagged.get(PUSH_TRACE_TAG).and_then(TraceContext::read),
+                    ack: tagged.get(PUSH_ACK_TAG).is_some(),
+                })
+            }
+            2 => {
+                let type_id = Self::read_uuid(buf)?;
+                let tagged = TaggedFields::read(buf)?;
+                Ok(TransferPacketGuestToHost::DescribeType {
+                    type_id,
+                    deadline: read_deadline(&tagged, REQUEST_DEADLINE_TAG),
+                })
+            }
+            3 => Ok(TransferPacketGuestToHost::Takedown {
+                tombstone: Tombstone::deserialize(buf)?,
+            }),
+            4 => Ok(TransferPacketGuestToHost::Delete {
+                object_id: Self::read_uuid(buf)?,
+            }),
+            5 => {
+                let data_types = Self::read_type_ids(buf)?;
+                let tagged = TaggedFields::read(buf)?;
+                Ok(TransferPacketGuestToHost::Subscribe {
+                    data_types,
+                    port: tagged.get(SUBSCRIBE_PORT_TAG)
+                        .filter(|value| value.remaining() >= 2)
+                        .map(|mut value| value.get_u16()),
+                    topics: match tagged.get(SUBSCRIBE_TOPICS_TAG) {
+                        Some(mut value) if value.remaining() >= 2 => {
+                            (0..value.get_u16()).map(|_| Self::read_string(&mut value)).collect::<io::Result<_>>()?
+                        }
+                        _ => Vec::new(),
+                    },
+                    qos: DeliveryQos::read(&tagged, SUBSCRIBE_QOS_TAG),
+                    type_versions: match tagged.get(SUBSCRIBE_TYPE_VERSIONS_TAG) {
+                        Some(mut value) if value.remaining() >= 2 => {
+                            let count = value.get_u16();
+                            (0..count)
+                                .map(|_| Ok((Self::read_uuid(&mut value)?, value.take_u16("Type version")?)))
+                                .collect::<io::Result<_>>()?
+                        }
+                        _ => Vec::new(),
+                    },
+                    deadline: read_deadline(&tagged, SUBSCRIBE_DEADLINE_TAG),
+                })
+            }
+            6 => Ok(TransferPacketGuestToHost::Unsubscribe {
+                data_types: Self::read_type_ids(buf)?,
+            }),
+            7 => Ok(TransferPacketGuestToHost::RenewSubscription {
+                deadline: read_deadline(&TaggedFields::read(buf)?, REQUEST_DEADLINE_TAG),
+            }),
+            8 => {
+                let transfer_id = buf.take_u32("Transfer id")?;
+                let sequence = buf.take_u64("Sequence")?;
+                let total_length = buf.take_u64("Total length")?;
+                let tagged = TaggedFields::read(buf)?;
+                Ok(TransferPacketGuestToHost::PushBegin {
+                    transfer_id,
+                    sequence,
+                    total_length,
+                    trace: tagged.get(PUSH_BEGIN_TRACE_TAG).and_then(TraceContext::read),
+                    ack: tagged.get(PUSH_BEGIN_ACK_TAG).is_some(),
+                })
+            }
+            9 => Ok(TransferPacketGuestToHost::PushChunk {
+                transfer_id: buf.take_u32("Transfer id")?,
+                data: Self::read_bytes(buf)?,
+            }),
+            10 => Ok(TransferPacketGuestToHost::PushEnd {
+                transfer_id: buf.take_u32("Transfer id")?,
+            }),
+            11 => {
+                let tagged = TaggedFields::read(buf)?;
+                Ok(TransferPacketGuestToHost::Window {
+                    objects: tagged.get(WINDOW_OBJECTS_TAG)
+                        .filter(|value| value.remaining() >= 4)
+                        .map(|mut value| value.get_u32()),
+                    bytes: tagged.get(WINDOW_BYTES_TAG)
+                        .filter(|value| value.remaining() >= 8)
+                        .map(|mut value| value.get_u64()),
+                })
+            }
+            12 => Ok(TransferPacketGuestToHost::StreamBegin {
+                transfer_id: buf.take_u32("Transfer id")?,
+                stream_id: Self::read_uuid(buf)?,
+            }),
+            13 => Ok(TransferPacketGuestToHost::StreamChunk {
+                transfer_id: buf.take_u32("Transfer id")?,
+                data: Self::read_bytes(buf)?,
+            }),
+            14 => {
+                let transfer_id = buf.take_u32("Transfer id")?;
+                let tagged = TaggedFields::read(buf)?;
+                Ok(TransferPacketGuestToHost::StreamEnd {
+                    transfer_id,
+                    aborted: tagged.get(STREAM_END_ABORTED_TAG).is_some(),
+                })
+            }
+            15 => Ok(TransferPacketGuestToHost::Processed {
+                sequence: buf.take_u64("Sequence")?,
+            }),
+            16 => Ok(TransferPacketGuestToHost::Delegate {
+                delegation: Delegation::deserialize(buf)?,
+            }),
+            17 => Ok(TransferPacketGuestToHost::RevokeDelegation {
+                delegation_id: Self::read_uuid(buf)?,
+            }),
+            18 => Ok(TransferPacketGuestToHost::Ping {
+                nonce: buf.take_u64("Nonce")?,
+            }),
+            19 => Ok(TransferPacketGuestToHost::Pong {
+                nonce: buf.take_u64("Nonce")?,
+            }),
+            id => Err(ProtocolError::InvalidPacketType { id }.into()),
+        }
     }
 }
 
 impl SerializePacket for TransferPacketHostToGuest {
     fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
-        todo!()
+        buf.put_u8(self.into()); // Message Type byte
+        let mut bytes_written: usize = 1;
+        match self {
+            TransferPacketHostToGuest::Nack { object_id, code, reason } => {
+                bytes_written += self.write_uuid(buf, object_id);
+                buf.put_u8(*code as u8);
+                bytes_written += 1;
+                bytes_written += self.write_optional_string(buf, reason);
+            }
+            TransferPacketHostToGuest::TypeDescription { type_id, descriptor } => {
+                bytes_written += self.write_uuid(buf, type_id);
+                buf.put_u8(descriptor.is_some() as u8);
+                bytes_written += 1;
+                if let Some(descriptor) = descriptor {
+                    bytes_written += descriptor.serialize(buf)?;
+                }
+            }
+            TransferPacketHostToGuest::Subscription { state, lease, qos } => {
+                buf.put_u8(*state as u8);
+                bytes_written += 1;
+
+                let mut tagged = TaggedFields::new();
+                if let Some(lease) = lease {
+                    let seconds = lease.as_secs().min(u32::MAX as u64) as u32;
+                    tagged.put(SUBSCRIPTION_LEASE_TAG, |buf| buf.put_u32(seconds));
+                }
+                qos.write(&mut tagged, SUBSCRIPTION_QOS_TAG);
+                bytes_written += tagged.write(buf);
+            }
+            TransferPacketHostToGuest::DeadlineExceeded { id } => {
+                bytes_written += self.write_uuid(buf, id);
+            }
+            TransferPacketHostToGuest::Overloaded { request } => {
+                buf.put_u8(*request);
+                bytes_written += 1;
+            }
+            TransferPacketHostToGuest::Ack { sequence, object_id, status } => {
+                buf.put_u64(*sequence);
+                bytes_written += 8;
+
+                let mut tagged = TaggedFields::new();
+                if let Some(object_id) = object_id {
+                    tagged.put(ACK_OBJECT_ID_TAG, |buf| buf.put_u128(object_id.as_u128()));
+                }
+                if *status != AckStatus::default() {
+                    tagged.put(ACK_STATUS_TAG, |buf| buf.put_u8(*status as u8));
+                }
+                bytes_written += tagged.write(buf);
+            }
+            TransferPacketHostToGuest::StreamReceived { stream_id } => {
+                bytes_written += self.write_uuid(buf, stream_id);
+            }
+            TransferPacketHostToGuest::Notice { level, message, sunset_at } => {
+                buf.put_u8(*level as u8);
+                bytes_written += 1;
+                bytes_written += self.write_string(buf, message);
+
+                let mut tagged = TaggedFields::new();
+                if let Some(sunset_at) = sunset_at {
+                    tagged.put(NOTICE_SUNSET_TAG, |buf| buf.put_u64(*sunset_at));
+                }
+                bytes_written += tagged.write(buf);
+            }
+            TransferPacketHostToGuest::PushWindow { pushes } => {
+                let mut tagged = TaggedFields::new();
+                if let Some(pushes) = pushes {
+                    tagged.put(PUSH_WINDOW_PUSHES_TAG, |buf| buf.put_u32(*pushes));
+                }
+                bytes_written += tagged.write(buf);
+            }
+            TransferPacketHostToGuest::Ping { nonce } | TransferPacketHostToGuest::Pong { nonce } => {
+                buf.put_u64(*nonce);
+                bytes_written += 8;
+            }
+        }
+        Ok(bytes_written)
     }
 }
 
@@ -34,6 +1038,339 @@ impl DeserializePacket for TransferPacketHostToGuest {
     type Output = TransferPacketHostToGuest;
 
     fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
-        todo!()
+        match buf.take_u8("Packet type")? {
+            1 => Ok(TransferPacketHostToGuest::Nack {
+                object_id: Self::read_uuid(buf)?,
+                code: RejectCode::from_u8(buf.take_u8("Reject code")?),
+                reason: Self::read_optional_string(buf)?,
+            }),
+            2 => Ok(TransferPacketHostToGuest::TypeDescription {
+                type_id: Self::read_uuid(buf)?,
+                descriptor: match buf.take_u8("Descriptor flag")? {
+                    0 => None,
+                    _ => Some(TypeDescriptor::deserialize(buf)?),
+                },
+            }),
+            3 => {
+                let state = SubscriptionState::from_u8(buf.take_u8("Subscription state")?);
+                let tagged = TaggedFields::read(buf)?;
+                Ok(TransferPacketHostToGuest::Subscription {
+                    state,
+                    lease: tagged.get(SUBSCRIPTION_LEASE_TAG)
+                        .filter(|value| value.remaining() >= 4)
+                        .map(|mut value| Duration::from_secs(value.get_u32() as u64)),
+                    qos: DeliveryQos::read(&tagged, SUBSCRIPTION_QOS_TAG),
+                })
+            }
+            4 => Ok(TransferPacketHostToGuest::DeadlineExceeded {
+                id: Self::read_uuid(buf)?,
+            }),
+            5 => Ok(TransferPacketHostToGuest::Overloaded {
+                request: buf.take_u8("Request")?,
+            }),
+            6 => {
+                let sequence = buf.take_u64("Sequence")?;
+                let tagged = TaggedFields::read(buf)?;
+                Ok(TransferPacketHostToGuest::Ack {
+                    sequence,
+                    object_id: tagged.get(ACK_OBJECT_ID_TAG)
+                        .filter(|value| value.remaining() >= 16)
+                        .and_then(|mut value| Self::read_uuid(&mut value).ok()),
+                    // statuses this crate doesn't know were still handled
+                    status: tagged.get(ACK_STATUS_TAG)
+                        .filter(|value| value.has_remaining())
+                        .and_then(|mut value| AckStatus::from_u8(value.get_u8()))
+                        .unwrap_or_default(),
+                })
+            }
+            7 => Ok(TransferPacketHostToGuest::StreamReceived {
+                stream_id: Self::read_uuid(buf)?,
+            }),
+            8 => {
+                let level = NoticeLevel::from_u8(buf.take_u8("Notice level")?);
+                let message = Self::read_string(buf)?;
+                let tagged = TaggedFields::read(buf)?;
+                Ok(TransferPacketHostToGuest::Notice {
+                    level,
+                    message,
+                    sunset_at: tagged.get(NOTICE_SUNSET_TAG)
+                        .filter(|value| value.remaining() >= 8)
+                        .map(|mut value| value.get_u64()),
+                })
+            }
+            9 => {
+                let tagged = TaggedFields::read(buf)?;
+                Ok(TransferPacketHostToGuest::PushWindow {
+                    pushes: tagged.get(PUSH_WINDOW_PUSHES_TAG)
+                        .filter(|value| value.remaining() >= 4)
+                        .map(|mut value| value.get_u32()),
+                })
+            }
+            10 => Ok(TransferPacketHostToGuest::Ping {
+                nonce: buf.take_u64("Nonce")?,
+            }),
+            11 => Ok(TransferPacketHostToGuest::Pong {
+                nonce: buf.take_u64("Nonce")?,
+            }),
+            id => Err(ProtocolError::InvalidPacketType { id }.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+
+    use tokio::io;
+
+    use uuid::Uuid;
+
+    use crate::{DelegatedPeer, Delegation, Envelope, OSPUrl, Scheme};
+    use crate::packet::{DeserializePacket, SerializePacket};
+    use crate::packet::transfer::{AckStatus, DeliveryQos, NoticeLevel, SubscriptionState, TraceContext, TransferPacketGuestToHost, TransferPacketHostToGuest};
+
+    #[test]
+    fn test_push_trace_context() -> io::Result<()> {
+        let envelope = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![1, 2, 3]);
+        for trace in [None, Some(TraceContext { trace_id: [7; 16], span_id: [9; 8], sampled: true })] {
+            let buf = &mut BytesMut::new();
+            let ack = trace.is_some();
+            TransferPacketGuestToHost::Push { sequence: 1, envelope: envelope.clone(), trace, ack }.serialize(buf)?;
+            let TransferPacketGuestToHost::Push { trace: decoded, ack: decoded_ack, .. } = TransferPacketGuestToHost::deserialize(buf)? else {
+                panic!("Expected push packet");
+            };
+            assert_eq!((decoded, decoded_ack), (trace, ack));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_begin() -> io::Result<()> {
+        let trace = Some(TraceContext { trace_id: [7; 16], span_id: [9; 8], sampled: false });
+        let buf = &mut BytesMut::new();
+        TransferPacketGuestToHost::PushBegin { transfer_id: 4, sequence: 12, total_length: 3 << 20, trace, ack: true }.serialize(buf)?;
+        let TransferPacketGuestToHost::PushBegin { transfer_id, sequence, total_length, trace: decoded, ack } = TransferPacketGuestToHost::deserialize(buf)? else {
+            panic!("Expected push begin packet");
+        };
+        assert_eq!((transfer_id, sequence, total_length, decoded, ack), (4, 12, 3 << 20, trace, true));
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_deadline() -> io::Result<()> {
+        let type_id = Uuid::new_v4();
+        for deadline in [None, Some(Duration::from_millis(1500))] {
+            let buf = &mut BytesMut::new();
+            TransferPacketGuestToHost::DescribeType { type_id, deadline }.serialize(buf)?;
+            let TransferPacketGuestToHost::DescribeType { type_id: decoded_id, deadline: decoded } = TransferPacketGuestToHost::deserialize(buf)? else {
+                panic!("Expected describe type packet");
+            };
+            assert_eq!((decoded_id, decoded), (type_id, deadline));
+
+            let buf = &mut BytesMut::new();
+            TransferPacketGuestToHost::RenewSubscription { deadline }.serialize(buf)?;
+            let TransferPacketGuestToHost::RenewSubscription { deadline: decoded } = TransferPacketGuestToHost::deserialize(buf)? else {
+                panic!("Expected renew subscription packet");
+            };
+            assert_eq!(decoded, deadline);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscribe_port() -> io::Result<()> {
+        let data_types = vec![Uuid::new_v4(), Uuid::new_v4()];
+        for port in [None, Some(4270)] {
+            let buf = &mut BytesMut::new();
+            let topics = port.map_or_else(Vec::new, |_| vec!["blog/rust/*".to_string(), "news/#".to_string()]);
+            let qos = port.map_or(DeliveryQos::AtLeastOnce, |_| DeliveryQos::Ordered);
+            let type_versions = port.map_or_else(Vec::new, |_| vec![(data_types[0], 2)]);
+            let deadline = port.map(|_| Duration::from_secs(10));
+            TransferPacketGuestToHost::Subscribe { data_types: data_types.clone(), port, topics: topics.clone(), qos, type_versions: type_versions.clone(), deadline }.serialize(buf)?;
+            let TransferPacketGuestToHost::Subscribe { data_types: decoded_types, port: decoded, topics: decoded_topics, qos: decoded_qos, type_versions: decoded_versions, deadline: decoded_deadline } = TransferPacketGuestToHost::deserialize(buf)? else {
+                panic!("Expected subscribe packet");
+            };
+            assert_eq!((decoded_types, decoded, decoded_topics, decoded_qos), (data_types.clone(), port, topics, qos));
+            assert_eq!((decoded_versions, decoded_deadline), (type_versions, deadline));
+        }
+
+        // the counts would wrap around
+        let topics = vec!["news/#".to_string(); u16::MAX as usize + 1];
+        let subscribe = TransferPacketGuestToHost::Subscribe { data_types: data_types.clone(), port: None, topics, qos: DeliveryQos::default(), type_versions: Vec::new(), deadline: None };
+        assert_eq!(subscribe.serialize(&mut BytesMut::new()).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        let type_versions = vec![(data_types[0], 1); u16::MAX as usize + 1];
+        let subscribe = TransferPacketGuestToHost::Subscribe { data_types: data_types.clone(), port: None, topics: Vec::new(), qos: DeliveryQos::default(), type_versions, deadline: None };
+        assert_eq!(subscribe.serialize(&mut BytesMut::new()).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        let unsubscribe = TransferPacketGuestToHost::Unsubscribe { data_types: vec![data_types[0]; u16::MAX as usize + 1] };
+        assert_eq!(unsubscribe.serialize(&mut BytesMut::new()).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        Ok(())
+    }
+
+    #[test]
+    fn test_window() -> io::Result<()> {
+        for (objects, bytes) in [(None, None), (Some(16), None), (Some(0), Some(1 << 40))] {
+            let buf = &mut BytesMut::new();
+            TransferPacketGuestToHost::Window { objects, bytes }.serialize(buf)?;
+            let TransferPacketGuestToHost::Window { objects: decoded_objects, bytes: decoded_bytes } = TransferPacketGuestToHost::deserialize(buf)? else {
+                panic!("Expected window packet");
+            };
+            assert_eq!((decoded_objects, decoded_bytes), (objects, bytes));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream() -> io::Result<()> {
+        let stream_id = Uuid::new_v4();
+        let buf = &mut BytesMut::new();
+        TransferPacketGuestToHost::StreamBegin { transfer_id: 2, stream_id }.serialize(buf)?;
+        let TransferPacketGuestToHost::StreamBegin { transfer_id, stream_id: decoded } = TransferPacketGuestToHost::deserialize(buf)? else {
+            panic!("Expected stream begin packet");
+        };
+        assert_eq!((transfer_id, decoded), (2, stream_id));
+
+        for aborted in [false, true] {
+            let buf = &mut BytesMut::new();
+            TransferPacketGuestToHost::StreamEnd { transfer_id: 2, aborted }.serialize(buf)?;
+            let TransferPacketGuestToHost::StreamEnd { aborted: decoded, .. } = TransferPacketGuestToHost::deserialize(buf)? else {
+                panic!("Expected stream end packet");
+            };
+            assert_eq!(decoded, aborted);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_processed() -> io::Result<()> {
+        let buf = &mut BytesMut::new();
+        TransferPacketGuestToHost::Processed { sequence: 42 }.serialize(buf)?;
+        let TransferPacketGuestToHost::Processed { sequence } = TransferPacketGuestToHost::deserialize(buf)? else {
+            panic!("Expected processed packet");
+        };
+        assert_eq!(sequence, 42);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delegate() -> io::Result<()> {
+        let delegation = Delegation {
+            delegation_id: Uuid::new_v4(),
+            origin: "origin.test".to_string(),
+            relay: "relay.test".to_string(),
+            parent: "origin.test".to_string(),
+            type_id: Uuid::new_v4(),
+            children: vec![
+                DelegatedPeer { url: OSPUrl { domain: "a.test".to_string(), port: 57400, scheme: Scheme::Osp }, qos: DeliveryQos::Ordered },
+                DelegatedPeer { url: OSPUrl { domain: "b.test".to_string(), port: 57400, scheme: Scheme::Osp }, qos: DeliveryQos::AtLeastOnce },
+            ],
+            expires_at: Some(1_700_000_000),
+            signature: vec![1, 2, 3],
+        };
+        let buf = &mut BytesMut::new();
+        TransferPacketGuestToHost::Delegate { delegation: delegation.clone() }.serialize(buf)?;
+        let TransferPacketGuestToHost::Delegate { delegation: decoded } = TransferPacketGuestToHost::deserialize(buf)? else {
+            panic!("Expected delegate packet");
+        };
+        assert_eq!(decoded, delegation);
+        assert!(decoded.delegates_to("b.test") && !decoded.delegates_to("relay.test"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscription_lease() -> io::Result<()> {
+        for (state, lease, qos) in [
+            (SubscriptionState::Approved, None, DeliveryQos::FireAndForget),
+            (SubscriptionState::Unsubscribed, Some(Duration::from_secs(3600)), DeliveryQos::AtLeastOnce),
+        ] {
+            let buf = &mut BytesMut::new();
+            TransferPacketHostToGuest::Subscription { state, lease, qos }.serialize(buf)?;
+            let TransferPacketHostToGuest::Subscription { state: decoded_state, lease: decoded, qos: decoded_qos } = TransferPacketHostToGuest::deserialize(buf)? else {
+                panic!("Expected subscription packet");
+            };
+            assert_eq!((decoded_state, decoded, decoded_qos), (state, lease, qos));
+        }
+
+        // hosts from before leases end the packet after the state
+        let old = &mut BytesMut::from(&[3u8, 1][..]);
+        assert!(matches!(
+            TransferPacketHostToGuest::deserialize(old)?,
+            TransferPacketHostToGuest::Subscription { lease: None, qos: DeliveryQos::AtLeastOnce, .. }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ack_status() -> io::Result<()> {
+        let object_id = Uuid::new_v4();
+        for (object_id, status) in [(Some(object_id), AckStatus::Deferred), (Some(object_id), AckStatus::Processed), (None, AckStatus::Duplicate)] {
+            let buf = &mut BytesMut::new();
+            TransferPacketHostToGuest::Ack { sequence: 7, object_id, status }.serialize(buf)?;
+            let TransferPacketHostToGuest::Ack { sequence, object_id: decoded, status: decoded_status } = TransferPacketHostToGuest::deserialize(buf)? else {
+                panic!("Expected ack packet");
+            };
+            assert_eq!((sequence, decoded, decoded_status), (7, object_id, status));
+        }
+
+        // hosts from before statuses end the packet after the sequence
+        let old = &mut BytesMut::from(&[6u8, 0, 0, 0, 0, 0, 0, 0, 9][..]);
+        assert!(matches!(
+            TransferPacketHostToGuest::deserialize(old)?,
+            TransferPacketHostToGuest::Ack { sequence: 9, object_id: None, status: AckStatus::Processed }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_notice() -> io::Result<()> {
+        for (level, sunset_at) in [(NoticeLevel::Critical, Some(1_800_000_000)), (NoticeLevel::Info, None)] {
+            let buf = &mut BytesMut::new();
+            TransferPacketHostToGuest::Notice { level, message: "Shutting down".to_string(), sunset_at }.serialize(buf)?;
+            let TransferPacketHostToGuest::Notice { level: decoded_level, message, sunset_at: decoded } = TransferPacketHostToGuest::deserialize(buf)? else {
+                panic!("Expected notice packet");
+            };
+            assert_eq!((decoded_level, message.as_str(), decoded), (level, "Shutting down", sunset_at));
+        }
+        assert_eq!(NoticeLevel::from_u8(9), NoticeLevel::Warning);
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_window() -> io::Result<()> {
+        for pushes in [None, Some(64)] {
+            let buf = &mut BytesMut::new();
+            TransferPacketHostToGuest::PushWindow { pushes }.serialize(buf)?;
+            let TransferPacketHostToGuest::PushWindow { pushes: decoded } = TransferPacketHostToGuest::deserialize(buf)? else {
+                panic!("Expected push window packet");
+            };
+            assert_eq!(decoded, pushes);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_ping_pong() -> io::Result<()> {
+        let buf = &mut BytesMut::new();
+        TransferPacketGuestToHost::Ping { nonce: 7 }.serialize(buf)?;
+        TransferPacketHostToGuest::Pong { nonce: 7 }.serialize(buf)?;
+        assert!(matches!(TransferPacketGuestToHost::deserialize(&mut buf.split_to(9))?, TransferPacketGuestToHost::Ping { nonce: 7 }));
+        assert!(matches!(TransferPacketHostToGuest::deserialize(buf)?, TransferPacketHostToGuest::Pong { nonce: 7 }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_traceparent() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::from_traceparent(traceparent).unwrap();
+        assert_eq!(context.trace_id[..2], [0x4b, 0xf9]);
+        assert_eq!(context.span_id[7], 0xb7);
+        assert!(context.sampled);
+        assert_eq!(context.to_traceparent(), traceparent);
+
+        assert!(TraceContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra").is_none());
+        assert!(TraceContext::from_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(TraceContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01").is_none());
+        assert!(TraceContext::from_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra").is_some());
     }
 }
@@ -1,30 +1,58 @@
 use std::net::{SocketAddr};
 
-use tokio::io::{self};
+use tokio::io::{self, AsyncRead, AsyncWrite};
 use tokio::net::{TcpStream};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 
 use tokio_stream::StreamExt;
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::WebSocketStream;
 use tokio_util::codec::{FramedRead, FramedWrite};
 use futures_util::{SinkExt};
 
+use crate::{Compression, ProtocolError, ProtocolVersion};
 use crate::packet::{DeserializePacket, PacketDecoder, PacketEncoder, SerializePacket};
+use crate::recording::Recorder;
+#[cfg(feature = "websocket")]
+use crate::websocket::WebSocketTransport;
+
+/// Read half of the stream a [Protocol] runs over.
+pub type TransportRead = Box<dyn AsyncRead + Send + Unpin>;
+/// Write half of the stream a [Protocol] runs over.
+pub type TransportWrite = Box<dyn AsyncWrite + Send + Unpin>;
 
 pub struct Protocol<InPacketType: DeserializePacket, OutPacketType : SerializePacket> {
-    pub read: FramedRead<OwnedReadHalf, PacketDecoder<InPacketType>>,
-    pub write: FramedWrite<OwnedWriteHalf, PacketEncoder<OutPacketType>>
+    pub read: FramedRead<TransportRead, PacketDecoder<InPacketType>>,
+    pub write: FramedWrite<TransportWrite, PacketEncoder<OutPacketType>>
 }
 
 impl<InPacketType: DeserializePacket, OutPacketType : SerializePacket> Protocol<InPacketType, OutPacketType> {
     /// Wrap a TcpStream with Protocol
     pub fn with_stream(stream: TcpStream) -> io::Result<Self> {
+        let (read, write) = stream.into_split();
+        Ok(Self::with_halves(Box::new(read), Box::new(write)))
+    }
+
+    /// Wrap any stream with Protocol, such as a TLS stream over a
+    /// TcpStream.
+    pub fn with_transport<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(stream: S) -> io::Result<Self> {
+        let (read, write) = io::split(stream);
+        Ok(Self::with_halves(Box::new(read), Box::new(write)))
+    }
+
+    /// Run Protocol over a WebSocket whose upgrade is done, see
+    /// [websocket](crate::websocket).
+    #[cfg(feature = "websocket")]
+    pub fn with_websocket<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(socket: WebSocketStream<S>) -> io::Result<Self> {
+        Self::with_transport(WebSocketTransport::new(socket))
+    }
+
+    fn with_halves(read: TransportRead, write: TransportWrite) -> Self {
         let read_codec: PacketDecoder<InPacketType> = PacketDecoder::new();
         let write_codec: PacketEncoder<OutPacketType> = PacketEncoder::new();
-        let (read, write) = stream.into_split();
-        Ok(Self {
+        Self {
             read: FramedRead::new(read, read_codec),
             write: FramedWrite::new(write, write_codec),
-        })
+        }
     }
 
     /// Establish a connection, and wrap the stream in a new [Protocol].
@@ -33,8 +61,43 @@ impl<InPacketType: DeserializePacket, OutPacketType : SerializePacket> Protocol<
         Self::with_stream(stream)
     }
 
+    /// The protocol version negotiated on the connection, see
+    /// [set_version](Self::set_version).
+    pub fn version(&self) -> ProtocolVersion {
+        self.read.decoder().version()
+    }
+
+    /// Read and write packets for `version` from now on, once the handshake
+    /// negotiated it.
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.read.decoder_mut().set_version(version);
+        self.write.encoder_mut().set_version(version);
+    }
+
+    /// The compression negotiated on the connection, see
+    /// [set_compression](Self::set_compression).
+    pub fn compression(&self) -> Compression {
+        self.read.decoder().compression()
+    }
+
+    /// Frame packets read and written with `compression` from now on, once
+    /// the handshake negotiated it.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.read.decoder_mut().set_compression(compression);
+        self.write.encoder_mut().set_compression(compression);
+    }
+
+    /// Record every packet read and written to `recorder` from now on, see
+    /// [recording](crate::recording).
+    pub fn record(&mut self, recorder: Recorder) {
+        self.read.decoder_mut().set_recorder(Some(recorder.clone()));
+        self.write.encoder_mut().set_recorder(Some(recorder));
+    }
+
     /// Change the codecs being used for incoming and outgoing packets,
-    /// returning a new [Protocol].
+    /// returning a new [Protocol]. The new codecs keep the negotiated
+    /// [version](Self::version) and [compression](Self::compression), and
+    /// the connection stays [recorded](Self::record).
     ///
     /// Calls the underlying [FramedWrite::map_encoder] and Framed
     pub fn map_codecs<NewInPacketType, NewOutPacketType, FnInPacket, FnOutPacket>(self, map_in: FnInPacket, map_out: FnOutPacket) -> Protocol<NewInPacketType, NewOutPacketType>
@@ -44,23 +107,85 @@ impl<InPacketType: DeserializePacket, OutPacketType : SerializePacket> Protocol<
         NewInPacketType: DeserializePacket,
         NewOutPacketType: SerializePacket,
     {
-        Protocol::<NewInPacketType, NewOutPacketType> {
+        let version = self.version();
+        let compression = self.compression();
+        let recorder = self.read.decoder().recorder().cloned();
+        let mut protocol = Protocol::<NewInPacketType, NewOutPacketType> {
             read: self.read.map_decoder(map_in),
             write: self.write.map_encoder(map_out),
+        };
+        protocol.set_version(version);
+        protocol.set_compression(compression);
+        if let Some(recorder) = recorder {
+            protocol.record(recorder);
         }
+        protocol
     }
 
-    /// Serialize a message to the server and write it to the inner [FramedWrite]
+    /// Serialize a message to the server and write it to the inner [FramedWrite].
+    /// Fails with [ProtocolError::Reset] if the connection was reset.
     pub async fn send_message(&mut self, message: OutPacketType) -> io::Result<()> {
-        self.write.send(message).await
+        self.write.send(message).await.map_err(transport_error)
     }
 
-    /// Read a message from the inner [FramedRead]
+    /// Read a message from the inner [FramedRead]. Fails with
+    /// [ProtocolError::Closed] once the peer closes the connection, or
+    /// [ProtocolError::Reset] if it was reset, see [Closure](crate::Closure).
     pub async fn read_frame(&mut self) -> io::Result<InPacketType::Output> {
-        loop {
-            if let Some(packet) = self.read.next().await {
-                return Ok(packet?);
-            }
+        match self.read.next().await {
+            Some(packet) => packet.map_err(transport_error),
+            None => Err(ProtocolError::Closed.into()),
         }
     }
+
+    /// Flush what was written and close our side of the connection, so the
+    /// peer reads every packet before it sees the connection close. Packets
+    /// can still be read until the peer closes its side, e.g. once it saw
+    /// ours close.
+    pub async fn close(&mut self) -> io::Result<()> {
+        self.write.close().await.map_err(transport_error)
+    }
+}
+
+/// Turn the ways a transport fails when it was reset into
+/// [ProtocolError::Reset], leaving other failures as they are.
+fn transport_error(err: io::Error) -> io::Error {
+    match err.kind() {
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe => ProtocolError::Reset.into(),
+        _ => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::{Closure, Protocol};
+    use crate::packet::handshake::{HandshakePacketGuestToHost, HandshakePacketHostToGuest};
+
+    type Host = Protocol<HandshakePacketGuestToHost, HandshakePacketHostToGuest>;
+
+    #[tokio::test]
+    async fn test_closures() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // a guest closing its side is a FIN, after which it still reads
+        let mut guest = TcpStream::connect(addr).await.unwrap();
+        let mut host = Host::with_stream(listener.accept().await.unwrap().0).unwrap();
+        tokio::io::AsyncWriteExt::shutdown(&mut guest).await.unwrap();
+        let err = host.read_frame().await.map(|_| ()).unwrap_err();
+        assert_eq!(Closure::of(&err), Some(Closure::Fin));
+        host.close().await.unwrap();
+        assert_eq!(tokio::io::AsyncReadExt::read(&mut guest, &mut [0; 8]).await.unwrap(), 0);
+
+        // a guest dropping the connection without lingering resets it
+        let guest = TcpStream::connect(addr).await.unwrap();
+        let mut host = Host::with_stream(listener.accept().await.unwrap().0).unwrap();
+        guest.set_zero_linger().unwrap();
+        drop(guest);
+        let err = host.read_frame().await.map(|_| ()).unwrap_err();
+        assert_eq!(Closure::of(&err), Some(Closure::Reset));
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,263 @@
+//! # Connection Recordings
+//!
+//! Interop problems between implementations are hard to chase down once the
+//! connection they happened on is gone. A [Recorder] set on a connection's
+//! [Protocol], see [Protocol::record], writes every packet read and written
+//! on it to a file as it goes, one JSON line per packet. The file is written
+//! on a thread of its own, so a slow disk doesn't hold up the connection.
+//! Packets are recorded uncompressed, with the protocol version they were
+//! read or written for, and with the secrets they hold blanked out, see
+//! [SerializePacket::serialize_redacted](crate::packet::SerializePacket::serialize_redacted).
+//! Packets read that don't decode are recorded as they were read, since
+//! there is no telling what in them is secret.
+//!
+//! A [Recording] can be fed back through the decoder offline, see
+//! [RecordedFrame::decode], and the `osp-replay` binary prints what every
+//! packet of one decodes to:
+//!
+//! ```text
+//! cargo run -p osp_protocol --bin osp-replay -- guest.example-1700000000000.jsonl
+//! ```
+//!
+//! Recordings hold the objects pushed on the connection, so they are as
+//! sensitive as the data store of the node that made them.
+//!
+//! [Protocol]: crate::Protocol
+//! [Protocol::record]: crate::Protocol::record
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::BytesMut;
+
+use serde::{Deserialize, Serialize};
+
+use tokio::io;
+
+use crate::ProtocolVersion;
+use crate::packet::DeserializePacket;
+use crate::packet::handshake::{HandshakePacketGuestToHost, HandshakePacketHostToGuest};
+use crate::packet::transfer::{TransferPacketGuestToHost, TransferPacketHostToGuest};
+use crate::spec::DescribePackets;
+
+/// Whether a recorded packet was read from the peer or written to it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameDirection {
+    Read,
+    Written,
+}
+
+/// A packet of a [Recording].
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RecordedFrame {
+    /// Unix timestamp (milliseconds) the packet was read or written at.
+    pub at: u64,
+    pub direction: FrameDirection,
+    /// The packet enum the packet is one of, e.g.
+    /// `TransferPacketGuestToHost`.
+    pub packet: String,
+    /// Major and minor protocol version the packet was read or written for.
+    pub version: [u8; 2],
+    /// The packet as serialized, hex encoded.
+    #[serde(with = "hex")]
+    pub data: Vec<u8>,
+}
+
+impl RecordedFrame {
+    pub fn version(&self) -> ProtocolVersion {
+        ProtocolVersion::new(self.version[0], self.version[1])
+    }
+
+    /// Decode the packet as a `P`, which should be the packet enum it was
+    /// recorded as.
+    pub fn decode<P: DeserializePacket>(&self) -> io::Result<P::Output> {
+        P::deserialize_for(&mut BytesMut::from(self.data.as_slice()), self.version())
+    }
+
+    /// Decode the packet as the packet enum it was recorded as, returning
+    /// the name of the packet it is, e.g. `Push`. Fails with
+    /// [InvalidData](io::ErrorKind::InvalidData) if it doesn't decode, or
+    /// was recorded as a packet enum this crate doesn't know.
+    pub fn describe(&self) -> io::Result<&'static str> {
+        match self.packet.as_str() {
+            "HandshakePacketGuestToHost" => packet_name::<HandshakePacketGuestToHost>(self),
+            "HandshakePacketHostToGuest" => packet_name::<HandshakePacketHostToGuest>(self),
+            "TransferPacketGuestToHost" => packet_name::<TransferPacketGuestToHost>(self),
+            "TransferPacketHostToGuest" => packet_name::<TransferPacketHostToGuest>(self),
+            packet => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown packet enum {packet}"))),
+        }
+    }
+}
+
+fn packet_name<P>(frame: &RecordedFrame) -> io::Result<&'static str>
+where
+    P: DeserializePacket<Output = P> + DescribePackets,
+    for<'a> u8: From<&'a P>,
+{
+    let id = u8::from(&frame.decode::<P>()?);
+    Ok(P::describe().packets.iter().find(|packet| packet.id == id).map_or("unknown", |packet| packet.name))
+}
+
+/// Writes the packets of a connection to a file, see the [module](self)
+/// docs. Clones write to the same file, which is written in full by the
+/// time the last of them is dropped.
+#[derive(Clone)]
+pub struct Recorder {
+    writer: Arc<Writer>,
+}
+
+/// The thread writing a recording, and the way to hand it frames.
+struct Writer {
+    frames: Option<Sender<RecordedFrame>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // the thread stops once there are no more frames to write
+        self.frames.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Recorder {
+    /// Record to a new file at `path`. Fails with
+    /// [AlreadyExists](io::ErrorKind::AlreadyExists) if there is one, so
+    /// recordings are never overwritten.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let out = BufWriter::new(File::create_new(path)?);
+        let (frames, received) = mpsc::channel();
+        let thread = thread::Builder::new().name("osp-recorder".to_string()).spawn(move || write_frames(out, received))?;
+        Ok(Recorder { writer: Arc::new(Writer { frames: Some(frames), thread: Some(thread) }) })
+    }
+
+    /// Record the packet serialized in `data`, one of the packet enum `P`.
+    pub(crate) fn record<P>(&self, direction: FrameDirection, version: ProtocolVersion, data: &[u8]) {
+        let frame = RecordedFrame {
+            at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+            direction,
+            packet: std::any::type_name::<P>().rsplit("::").next().unwrap_or_default().to_string(),
+            version: [version.major, version.minor],
+            data: data.to_vec(),
+        };
+        // the writer only goes away once the recording can't be written,
+        // which must not break the connection it records
+        if let Some(frames) = &self.writer.frames {
+            let _ = frames.send(frame);
+        }
+    }
+}
+
+/// Write the frames `received` to `out` until every [Recorder] sending them
+/// is dropped, flushing whenever there are none waiting, so the recording
+/// is never far behind the connection.
+fn write_frames(mut out: BufWriter<File>, received: Receiver<RecordedFrame>) {
+    let write = |out: &mut BufWriter<File>, frame: &RecordedFrame| -> io::Result<()> {
+        serde_json::to_writer(&mut *out, frame)?;
+        out.write_all(b"\n")
+    };
+    while let Ok(frame) = received.recv() {
+        let written = std::iter::once(frame).chain(received.try_iter()).try_for_each(|frame| write(&mut out, &frame));
+        if written.and_then(|_| out.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+/// The packets a [Recorder] wrote, in the order they were read and written.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Recording {
+    pub frames: Vec<RecordedFrame>,
+}
+
+impl Recording {
+    /// Read the recording at `path`. Fails with
+    /// [InvalidData](io::ErrorKind::InvalidData) naming the line that isn't
+    /// a recorded packet. A last line cut short, as the process recording
+    /// died while writing it, is left out.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let lines = BufReader::new(File::open(path)?).lines().collect::<io::Result<Vec<_>>>()?;
+        let mut frames = Vec::with_capacity(lines.len());
+        for (number, line) in lines.iter().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            match serde_json::from_str(line) {
+                Ok(frame) => frames.push(frame),
+                Err(e) if e.is_eof() && number + 1 == lines.len() => break,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Line {} is not a recorded packet: {e}", number + 1))),
+            }
+        }
+        Ok(Recording { frames })
+    }
+}
+
+/// Packet bytes as a lowercase hex string in recordings.
+mod hex {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&bytes.iter().map(|byte| format!("{byte:02x}")).collect::<String>())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+        (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use uuid::Uuid;
+
+    use crate::{Protocol, PROTOCOL_VERSION};
+    use crate::packet::handshake::HandshakePacketGuestToHost;
+    use crate::packet::transfer::{TransferPacketGuestToHost, TransferPacketHostToGuest};
+    use crate::recording::{FrameDirection, Recorder, Recording};
+
+    #[tokio::test]
+    async fn test_recording() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("osp-recording-{}.jsonl", Uuid::new_v4()));
+        let (host, guest) = io::duplex(1024);
+        let mut host = Protocol::<HandshakePacketGuestToHost, TransferPacketHostToGuest>::with_transport(host)?;
+        let mut guest = Protocol::<TransferPacketHostToGuest, HandshakePacketGuestToHost>::with_transport(guest)?;
+        host.record(Recorder::create(&path)?);
+        assert_eq!(Recorder::create(&path).err().map(|e| e.kind()), Some(io::ErrorKind::AlreadyExists));
+
+        guest.send_message(HandshakePacketGuestToHost::Verify { nonce: Uuid::nil(), challenge: vec![7; 256], signature: None, resumption: None }).await?;
+        host.read_frame().await?;
+        let mut host = host.map_codecs(|_| crate::packet::PacketDecoder::<TransferPacketGuestToHost>::new(), |encoder| encoder);
+        host.set_version(PROTOCOL_VERSION);
+        host.send_message(TransferPacketHostToGuest::Pong { nonce: 3 }).await?;
+        // the recording is written in full once the connection is dropped
+        drop(host);
+
+        let recording = Recording::open(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(recording.frames.iter().map(|frame| (frame.direction, frame.packet.as_str())).collect::<Vec<_>>(), [
+            (FrameDirection::Read, "HandshakePacketGuestToHost"),
+            (FrameDirection::Written, "TransferPacketHostToGuest"),
+        ]);
+        // the answer to the challenge is blanked out
+        let HandshakePacketGuestToHost::Verify { challenge, .. } = recording.frames[0].decode::<HandshakePacketGuestToHost>()? else {
+            panic!("Expected verify packet");
+        };
+        assert_eq!(challenge, vec![0; 256]);
+        assert_eq!(recording.frames[1].version(), PROTOCOL_VERSION);
+        assert_eq!(recording.frames[1].describe()?, "Pong");
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+//! # Type Descriptors
+//!
+//! Machine-readable descriptions of data types, exchanged between peers so
+//! operators can inspect types flowing through their node that it doesn't
+//! itself understand.
+
+use bytes::{BufMut, BytesMut};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use crate::packet::{DeserializePacket, SerializePacket, TakeField};
+
+/// A single field of a data type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldDescriptor {
+    pub name: String,
+    /// Type of the field, e.g. `string`, `u64` or `list<string>`.
+    pub kind: String,
+    pub optional: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeDescriptor {
+    pub type_id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    /// The fields of the type, in encoding order. Empty if the type doesn't
+    /// describe its fields.
+    pub fields: Vec<FieldDescriptor>,
+}
+
+impl SerializePacket for TypeDescriptor {
+    fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
+        let mut bytes_written = self.write_uuid(buf, &self.type_id);
+        bytes_written += self.write_string(buf, &self.name);
+        bytes_written += self.write_optional_string(buf, &self.description);
+        buf.put_u16(self.fields.len() as u16);
+        bytes_written += 2;
+        for field in &self.fields {
+            bytes_written += self.write_string(buf, &field.name);
+            bytes_written += self.write_string(buf, &field.kind);
+            buf.put_u8(field.optional as u8);
+            bytes_written += 1;
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl DeserializePacket for TypeDescriptor {
+    type Output = TypeDescriptor;
+
+    fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
+        let type_id = Self::read_uuid(buf)?;
+        let name = Self::read_string(buf)?;
+        let description = Self::read_optional_string(buf)?;
+        let field_count = buf.take_u16("Field count")?;
+        let mut fields = Vec::with_capacity(field_count as usize);
+        for _ in 0..field_count {
+            fields.push(FieldDescriptor {
+                name: Self::read_string(buf)?,
+                kind: Self::read_string(buf)?,
+                optional: buf.take_u8("Field optional flag")? != 0,
+            });
+        }
+        Ok(TypeDescriptor {
+            type_id,
+            name,
+            description,
+            fields,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio::io;
+    use uuid::Uuid;
+
+    use crate::{FieldDescriptor, TypeDescriptor};
+    use crate::packet::{DeserializePacket, SerializePacket};
+
+    #[test]
+    fn test_descriptor_roundtrip() -> io::Result<()> {
+        let descriptor = TypeDescriptor {
+            type_id: Uuid::new_v4(),
+            name: "test.note".to_string(),
+            description: None,
+            fields: vec![FieldDescriptor { name: "text".to_string(), kind: "string".to_string(), optional: false }],
+        };
+        let mut buf = BytesMut::new();
+        descriptor.serialize(&mut buf)?;
+        assert_eq!(TypeDescriptor::deserialize(&mut buf)?, descriptor);
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! # Sensitivity Flags
+//!
+//! Standard flags an object can carry to mark it as unsuitable for some
+//! audiences, and the preferences a subscriber announces to exclude flagged
+//! objects. The publishing node enforces those preferences before pushing
+//! anything to the subscriber.
+
+use bytes::{BufMut, BytesMut};
+
+use tokio::io;
+
+use crate::packet::{DeserializePacket, SerializePacket, TakeField};
+
+const FLAG_NSFW: u8 = 0b01;
+const FLAG_SPOILER: u8 = 0b10;
+
+/// How sensitive an object is. The default is an object with no flags set.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sensitivity {
+    /// The object is not safe for work, e.g. adult content.
+    pub nsfw: bool,
+    /// The object spoils a story, game or similar.
+    pub spoiler: bool,
+    /// Free-form content warnings, such as `violence` or `flashing lights`.
+    pub warnings: Vec<String>,
+}
+
+impl Sensitivity {
+    /// Whether any flag or warning is set.
+    pub fn is_flagged(&self) -> bool {
+        self.nsfw || self.spoiler || !self.warnings.is_empty()
+    }
+}
+
+/// Categories of flagged objects a subscriber doesn't want to receive.
+/// Custom warnings are compared case-insensitively.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SensitivityFilter {
+    pub exclude_nsfw: bool,
+    pub exclude_spoilers: bool,
+    pub excluded_warnings: Vec<String>,
+}
+
+impl SensitivityFilter {
+    /// Whether the filter lets every object through.
+    pub fn is_empty(&self) -> bool {
+        !self.exclude_nsfw && !self.exclude_spoilers && self.excluded_warnings.is_empty()
+    }
+
+    /// Why an object flagged as `sensitivity` is excluded, or `None` if it
+    /// passes the filter.
+    pub fn excludes(&self, sensitivity: &Sensitivity) -> Option<String> {
+        if self.exclude_nsfw && sensitivity.nsfw {
+            return Some("Object is marked NSFW".to_string());
+        }
+        if self.exclude_spoilers && sensitivity.spoiler {
+            return Some("Object is marked as a spoiler".to_string());
+        }
+        sensitivity.warnings.iter()
+            .find(|warning| self.excluded_warnings.iter().any(|excluded| excluded.eq_ignore_ascii_case(warning)))
+            .map(|warning| format!("Object carries the content warning {warning:?}"))
+    }
+}
+
+fn write_flags<P: SerializePacket>(packet: &P, buf: &mut BytesMut, flags: u8, strings: &[String]) -> usize {
+    buf.put_u8(flags);
+    buf.put_u16(strings.len() as u16);
+    3 + strings.iter().map(|string| packet.write_string(buf, string)).sum::<usize>()
+}
+
+fn read_flags<P: DeserializePacket>(buf: &mut BytesMut) -> io::Result<(u8, Vec<String>)> {
+    let flags = buf.take_u8("Sensitivity flags")?;
+    let count = buf.take_u16("Content warning count")?;
+    let strings = (0..count).map(|_| P::read_string(buf)).collect::<io::Result<_>>()?;
+    Ok((flags, strings))
+}
+
+impl SerializePacket for Sensitivity {
+    fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
+        let flags = (self.nsfw as u8 * FLAG_NSFW) | (self.spoiler as u8 * FLAG_SPOILER);
+        Ok(write_flags(self, buf, flags, &self.warnings))
+    }
+}
+
+impl DeserializePacket for Sensitivity {
+    type Output = Sensitivity;
+
+    fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
+        let (flags, warnings) = read_flags::<Self>(buf)?;
+        Ok(Sensitivity {
+            nsfw: flags & FLAG_NSFW != 0,
+            spoiler: flags & FLAG_SPOILER != 0,
+            warnings,
+        })
+    }
+}
+
+impl SerializePacket for SensitivityFilter {
+    fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
+        let flags = (self.exclude_nsfw as u8 * FLAG_NSFW) | (self.exclude_spoilers as u8 * FLAG_SPOILER);
+        Ok(write_flags(self, buf, flags, &self.excluded_warnings))
+    }
+}
+
+impl DeserializePacket for SensitivityFilter {
+    type Output = SensitivityFilter;
+
+    fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
+        let (flags, excluded_warnings) = read_flags::<Self>(buf)?;
+        Ok(SensitivityFilter {
+            exclude_nsfw: flags & FLAG_NSFW != 0,
+            exclude_spoilers: flags & FLAG_SPOILER != 0,
+            excluded_warnings,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio::io;
+
+    use crate::{Sensitivity, SensitivityFilter};
+    use crate::packet::{DeserializePacket, SerializePacket};
+
+    #[test]
+    fn test_filter() -> io::Result<()> {
+        let filter = SensitivityFilter {
+            exclude_nsfw: true,
+            exclude_spoilers: false,
+            excluded_warnings: vec!["Violence".to_string()],
+        };
+        let mut buf = BytesMut::new();
+        filter.serialize(&mut buf)?;
+        assert_eq!(SensitivityFilter::deserialize(&mut buf)?, filter);
+
+        assert!(filter.excludes(&Sensitivity::default()).is_none());
+        assert!(filter.excludes(&Sensitivity { spoiler: true, ..Default::default() }).is_none());
+        assert!(filter.excludes(&Sensitivity { nsfw: true, ..Default::default() }).is_some());
+        assert!(filter.excludes(&Sensitivity { warnings: vec!["violence".to_string()], ..Default::default() }).is_some());
+        Ok(())
+    }
+}
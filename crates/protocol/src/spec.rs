@@ -0,0 +1,197 @@
+//! # Protocol Specification
+//!
+//! A machine-readable description of the wire protocol, for keeping
+//! implementations in other languages in sync with this one. The packets are
+//! described by the packet enums themselves through
+//! `#[derive(DescribePackets)]`, which also assigns the packet ids they are
+//! sent with, so the description can't drift from the code.
+//!
+//! [protocol_spec] gathers the framing, packets and handshake sequence, and
+//! the `osp-spec` binary prints them as JSON:
+//!
+//! ```text
+//! cargo run -p osp_protocol --bin osp-spec > osp-spec.json
+//! ```
+
+use serde::Serialize;
+
+pub use osp_protocol_derive::DescribePackets;
+
+use crate::VersionRange;
+use crate::packet::PACKET_MAX_LENGTH;
+use crate::packet::handshake::{HandshakePacketGuestToHost, HandshakePacketHostToGuest};
+use crate::packet::transfer::{TransferPacketGuestToHost, TransferPacketHostToGuest};
+
+/// A packet enum that can describe its variants, see the [module](self)
+/// docs.
+pub trait DescribePackets {
+    fn describe() -> PacketSetSpec;
+}
+
+/// The whole protocol.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProtocolSpec {
+    pub framing: FramingSpec,
+    pub phases: Vec<PhaseSpec>,
+    /// The order packets are exchanged in during the handshake.
+    pub handshake: Vec<HandshakeStep>,
+    /// The protocol versions described, as `major.minor`.
+    pub min_version: String,
+    pub max_version: String,
+}
+
+/// How packets are delimited on the stream.
+#[derive(Clone, Debug, Serialize)]
+pub struct FramingSpec {
+    /// Prefix of every packet, holding the length of the rest of it.
+    pub length_prefix: &'static str,
+    /// Longest packet, not counting the length prefix.
+    pub max_length: usize,
+    /// First field of every packet, telling the packets of a set apart.
+    pub packet_id: &'static str,
+    /// Byte order of the integers inside a packet.
+    pub byte_order: &'static str,
+    /// How fields added to a packet after its first release are written,
+    /// see [TaggedFields](crate::packet::TaggedFields).
+    pub tagged_fields: &'static str,
+    /// How packets are framed once compression was negotiated, see
+    /// [Compression](crate::Compression).
+    pub compression: &'static str,
+}
+
+/// A phase of a connection, with the packets either side may send in it.
+#[derive(Clone, Debug, Serialize)]
+pub struct PhaseSpec {
+    pub name: &'static str,
+    pub doc: &'static str,
+    pub guest_to_host: PacketSetSpec,
+    pub host_to_guest: PacketSetSpec,
+}
+
+/// The packets of one packet enum.
+#[derive(Clone, Debug, Serialize)]
+pub struct PacketSetSpec {
+    pub name: &'static str,
+    pub doc: &'static str,
+    pub packets: Vec<PacketSpec>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PacketSpec {
+    pub id: u8,
+    pub name: &'static str,
+    pub doc: &'static str,
+    /// Fields in the order they are written, after the packet id.
+    pub fields: Vec<FieldSpec>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FieldSpec {
+    pub name: &'static str,
+    pub rust_type: &'static str,
+    /// How the field is written, see [encoding_of].
+    pub encoding: String,
+    pub doc: &'static str,
+}
+
+/// The side of a connection sending a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sender {
+    Guest,
+    Host,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HandshakeStep {
+    pub sender: Sender,
+    pub packet: &'static str,
+    /// Whether the step may be left out.
+    pub optional: bool,
+    pub doc: &'static str,
+}
+
+impl ProtocolSpec {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("protocol spec is always serializable")
+    }
+}
+
+/// How a field of type `rust_type` is written, for fields without a
+/// `#[packet(wire = "...")]` of their own. Types that write themselves,
+/// such as [Envelope](crate::Envelope), are referred to by name.
+pub fn encoding_of(rust_type: &str) -> String {
+    let inner = |prefix: &str| rust_type.strip_prefix(prefix).and_then(|rest| rest.strip_suffix('>'));
+    match rust_type {
+        "bool" => "u8, 1 for true and 0 for false".to_string(),
+        "u8" | "u16" | "u32" | "u64" | "u128" => rust_type.to_string(),
+        "String" => "u16 byte length, then the UTF-8 bytes".to_string(),
+        "Uuid" => "u128".to_string(),
+        "Vec<u8>" => "u32 length, then the bytes".to_string(),
+        "ConnectionType" | "RejectCode" | "SubscriptionState" | "CloseReason" | "KeyAlgorithm" => "u8 discriminant".to_string(),
+        _ => match (inner("Option<"), inner("Vec<")) {
+            (Some(inner), _) => format!("u8 presence flag, then {} if it is 1", encoding_of(inner)),
+            (_, Some(inner)) => format!("u16 count, then each element as {}", encoding_of(inner)),
+            _ => format!("{rust_type}, see its definition"),
+        },
+    }
+}
+
+/// Describe the protocol implemented by this crate.
+pub fn protocol_spec() -> ProtocolSpec {
+    use Sender::{Guest, Host};
+    let step = |sender, packet, optional, doc| HandshakeStep { sender, packet, optional, doc };
+    ProtocolSpec {
+        framing: FramingSpec {
+            length_prefix: "u32 little endian",
+            max_length: PACKET_MAX_LENGTH,
+            packet_id: "u8",
+            byte_order: "big endian",
+            tagged_fields: "after the fields of a packet, u8 tag, u32 length and value entries up to the end of the packet. Unknown tags are skipped, missing ones take a default",
+            compression: "once the handshake negotiated an algorithm, u8 algorithm id (0 none, 1 zstd, 2 lz4 with a u32 little endian size prefix) then the packet, compressed with it. Packets shorter than 256 bytes, or that don't get shorter, are sent uncompressed with id 0",
+        },
+        phases: vec![
+            PhaseSpec {
+                name: "handshake",
+                doc: "The guest, which opened the connection, proves its identity to the host.",
+                guest_to_host: HandshakePacketGuestToHost::describe(),
+                host_to_guest: HandshakePacketHostToGuest::describe(),
+            },
+            PhaseSpec {
+                name: "transfer",
+                doc: "After a successful handshake, the guest pushes objects to the host and makes requests of it. From version 1.2, objects whose encoded envelope is longer than 1 MiB are pushed in chunks, with PushBegin, PushChunk and PushEnd. From version 1.3, the guest may ask the host to Ack a push once it handled the object, and subscribers choose how objects are delivered to them: fire-and-forget, at-least-once or ordered. From version 1.4, subscribers may advertise a receive Window, and the host pauses delivering to them once it is used up. From version 1.5, the guest may stream the bytes of attachments to the host, with StreamBegin, StreamChunk and StreamEnd. From version 1.6, subscribers may report the highest sequence they durably Processed, so the host can tell how far behind they are. From version 1.7, an origin may Delegate delivering its objects of a type to relays, which deliver them on to the subscribers named in the delegation, until the origin revokes it. From version 1.8, the host may send its guests Notices for their operators, such as an upcoming shutdown or a deprecated data type. From version 1.9, the host sends its guests a PushWindow, the number of pushes they may have waiting for an Ack, and they wait for acks before pushing beyond it. From version 1.10, either side may Ping the other once it heard nothing from it for a while, and the other answers with a Pong, which keeps connections open through NATs and lets both sides close connections whose peer is gone.",
+                guest_to_host: TransferPacketGuestToHost::describe(),
+                host_to_guest: TransferPacketHostToGuest::describe(),
+            },
+        ],
+        handshake: vec![
+            step(Guest, "Hello", false, "Opens the handshake, with the highest protocol version the guest speaks and the compression algorithms it supports."),
+            step(Host, "Acknowledge", false, "The versions the host speaks, and how long subscriptions to it last unless renewed. The handshake ends unless ok is true, otherwise both sides use the highest version they have in common, and compress frames after this one with the algorithm the host picked."),
+            step(Guest, "Identify", false, "Names the guest, optionally with an invite issued by the host."),
+            step(Host, "Challenge", false, "256 random bytes, encrypted with RSAES-PKCS1-v1_5 for the key of the guest's `_osp` DNS record or invite."),
+            step(Guest, "Verify", false, "The decrypted challenge, with the nonce of the Challenge."),
+            step(Host, "Preferences", true, "Flagged objects the host doesn't want, if it excludes any."),
+            step(Host, "Close", false, "The connection moves to the transfer phase if can_continue is true."),
+        ],
+        min_version: VersionRange::SUPPORTED.min.to_string(),
+        max_version: VersionRange::SUPPORTED.max.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::handshake::HandshakePacketGuestToHost;
+    use crate::spec::{encoding_of, protocol_spec, DescribePackets};
+
+    #[test]
+    fn test_protocol_spec() {
+        let hello = HandshakePacketGuestToHost::Hello { connection_type: crate::ConnectionType::Server, version: crate::PROTOCOL_VERSION, compression: Vec::new(), formats: Vec::new(), software: None, timestamp: None };
+        let set = HandshakePacketGuestToHost::describe();
+        let described = set.packets.iter().find(|packet| packet.name == "Hello").unwrap();
+        assert_eq!(described.id, u8::from(&hello));
+        assert_eq!(described.fields[0].encoding, "u8 discriminant");
+
+        assert_eq!(encoding_of("Option<Vec<Uuid>>"), "u8 presence flag, then u16 count, then each element as u128 if it is 1");
+        assert!(protocol_spec().to_json().contains("\"name\": \"transfer\""));
+    }
+}
@@ -0,0 +1,118 @@
+//! # Tombstones
+//!
+//! A [Tombstone] records that an object was taken down, e.g. in response to
+//! a legal request. Nodes keep tombstones instead of the objects they replace
+//! so that the object is not accepted again, and can pass them on to their
+//! peers so the takedown reaches every copy.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{BufMut, BytesMut};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use crate::packet::{DeserializePacket, SerializePacket, TaggedFields, TakeField};
+
+const SIGNATURE_TAG: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tombstone {
+    /// Id of the object that was taken down.
+    pub object_id: Uuid,
+    /// Why the object was taken down, e.g. `copyright infringement`.
+    pub reason: String,
+    /// Who requested the takedown, such as a court or rights holder.
+    pub authority: String,
+    /// Unix timestamp (seconds) the object was taken down at.
+    pub taken_down_at: u64,
+    /// Ed25519 signature of [Tombstone::signed_bytes] by the node that
+    /// issued the takedown. Peers that trust the issuer apply signed
+    /// takedowns of objects that originate elsewhere.
+    pub signature: Option<Vec<u8>>,
+}
+
+impl Tombstone {
+    /// A tombstone for `object_id`, taken down now.
+    pub fn new(object_id: Uuid, reason: &str, authority: &str) -> Self {
+        Tombstone {
+            object_id,
+            reason: reason.to_string(),
+            authority: authority.to_string(),
+            taken_down_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            signature: None,
+        }
+    }
+
+    /// The bytes covered by [Tombstone::signature]: the tombstone as sent,
+    /// without the signature.
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let unsigned = Tombstone { signature: None, ..self.clone() };
+        let mut buf = BytesMut::new();
+        // Serializing into a buffer can't fail.
+        let _ = unsigned.serialize(&mut buf);
+        buf.to_vec()
+    }
+}
+
+impl SerializePacket for Tombstone {
+    fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
+        let mut bytes_written = self.write_uuid(buf, &self.object_id);
+        bytes_written += self.write_string(buf, &self.reason);
+        bytes_written += self.write_string(buf, &self.authority);
+        buf.put_u64(self.taken_down_at);
+        bytes_written += 8;
+        let mut tagged = TaggedFields::new();
+        if let Some(signature) = &self.signature {
+            tagged.put(SIGNATURE_TAG, |buf| buf.put_slice(signature));
+        }
+        Ok(bytes_written + tagged.write(buf))
+    }
+}
+
+/// A tombstone ends with [TaggedFields], so it has to be the last field of
+/// a packet.
+impl DeserializePacket for Tombstone {
+    type Output = Tombstone;
+
+    fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
+        let object_id = Self::read_uuid(buf)?;
+        let reason = Self::read_string(buf)?;
+        let authority = Self::read_string(buf)?;
+        let taken_down_at = buf.take_u64("Taken down at")?;
+        let tagged = TaggedFields::read(buf)?;
+        Ok(Tombstone {
+            object_id,
+            reason,
+            authority,
+            taken_down_at,
+            signature: tagged.get(SIGNATURE_TAG).map(|value| value.to_vec()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_is_optional() -> io::Result<()> {
+        let mut tombstone = Tombstone::new(Uuid::new_v4(), "spam", "operator");
+        // the layout from before tombstones were signed
+        let mut old = BytesMut::new();
+        old.put_slice(tombstone.object_id.as_bytes());
+        tombstone.write_string(&mut old, &tombstone.reason);
+        tombstone.write_string(&mut old, &tombstone.authority);
+        old.put_u64(tombstone.taken_down_at);
+        assert_eq!(old.to_vec(), tombstone.signed_bytes());
+        assert_eq!(Tombstone::deserialize(&mut old)?, tombstone);
+
+        tombstone.signature = Some(vec![1, 2, 3]);
+        let mut buf = BytesMut::new();
+        tombstone.serialize(&mut buf)?;
+        assert!(buf.starts_with(&tombstone.signed_bytes()));
+        assert_eq!(Tombstone::deserialize(&mut buf)?, tombstone);
+        Ok(())
+    }
+}
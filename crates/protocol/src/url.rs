@@ -1,26 +1,82 @@
 use std::fmt::{Display, Formatter};
+use tokio::io;
 use url::Url;
 
-#[derive(PartialEq, Debug)]
+/// The port of `osp://` URLs that leave it out, where the host has no
+/// `_osp._tcp` SRV record naming another.
+pub const DEFAULT_PORT: u16 = 42069;
+
+/// How a node is connected to: over raw TCP, or over a WebSocket for hosts
+/// that only allow HTTP(S) traffic, see [websocket](crate::websocket).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum Scheme {
+    /// `osp://`, raw TCP.
+    #[default]
+    Osp,
+    /// `ws://`, a WebSocket.
+    Ws,
+    /// `wss://`, a WebSocket over TLS.
+    Wss,
+}
+
+impl Scheme {
+    /// The scheme of URLs starting with `scheme`, if it is one.
+    pub fn parse(scheme: &str) -> Option<Scheme> {
+        match scheme {
+            "osp" => Some(Scheme::Osp),
+            "ws" => Some(Scheme::Ws),
+            "wss" => Some(Scheme::Wss),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Osp => "osp",
+            Scheme::Ws => "ws",
+            Scheme::Wss => "wss",
+        }
+    }
+
+    pub fn is_websocket(&self) -> bool {
+        *self != Scheme::Osp
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct OSPUrl {
     pub domain: String,
     pub port: u16,
+    /// Only carried by URLs given to a node, those in packets are always
+    /// [Osp](Scheme::Osp).
+    pub scheme: Scheme,
 }
 
-impl From<Url> for OSPUrl {
-    fn from(value: Url) -> Self {
-        assert_eq!(value.scheme(), "osp");
+impl TryFrom<Url> for OSPUrl {
+    type Error = io::Error;
 
-        OSPUrl {
-            domain: value.domain().unwrap().to_string(),
-            port: value.port().unwrap()
-        }
+    /// Fails with [InvalidInput](io::ErrorKind::InvalidInput) if `value`
+    /// isn't an `osp://`, `ws://` or `wss://` URL with a host.
+    fn try_from(value: Url) -> io::Result<Self> {
+        let Some(scheme) = Scheme::parse(value.scheme()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{value} is not an osp://, ws:// or wss:// URL")));
+        };
+        let Some(domain) = value.host_str() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{value} has no host")));
+        };
+
+        Ok(OSPUrl {
+            domain: domain.to_string(),
+            // WebSockets default to the ports of HTTP(S)
+            port: value.port_or_known_default().unwrap_or(DEFAULT_PORT),
+            scheme,
+        })
     }
 }
 
 impl Display for OSPUrl {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str(format!("osp://{}:{}", self.domain, self.port).as_str())
+        f.write_str(format!("{}://{}:{}", self.scheme.as_str(), self.domain, self.port).as_str())
     }
 }
 
@@ -28,16 +84,27 @@ impl Display for OSPUrl {
 mod tests {
     use tokio::io;
     use url::Url;
-    use crate::OSPUrl;
+    use crate::{OSPUrl, Scheme};
+    use crate::url::DEFAULT_PORT;
 
     #[test]
     fn test_url_parse() {
         let expected = OSPUrl {
             domain: "test-url.com".to_string(),
             port: 42069,
+            scheme: Scheme::Osp,
         };
 
-        let test_val = OSPUrl::from(Url::parse("osp://test-url.com:42069").unwrap());
+        let test_val = OSPUrl::try_from(Url::parse("osp://test-url.com:42069").unwrap()).unwrap();
         assert_eq!(expected, test_val);
+
+        let websocket = OSPUrl::try_from(Url::parse("wss://test-url.com").unwrap()).unwrap();
+        assert_eq!((websocket.port, websocket.scheme), (443, Scheme::Wss));
+        assert_eq!(websocket.to_string(), "wss://test-url.com:443");
+
+        let portless = OSPUrl::try_from(Url::parse("osp://test-url.com").unwrap()).unwrap();
+        assert_eq!(portless.port, DEFAULT_PORT);
+        let https = OSPUrl::try_from(Url::parse("https://test-url.com").unwrap());
+        assert_eq!(https.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
     }
-}
\ No newline at end of file
+}
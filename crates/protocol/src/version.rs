@@ -0,0 +1,132 @@
+//! # Protocol Versions
+//!
+//! The guest names the highest [ProtocolVersion] it speaks in its Hello, and
+//! the host answers with the [VersionRange] it supports in its Acknowledge.
+//! Both then use the highest version they have in common, see
+//! [VersionRange::negotiate], and the host refuses guests it has none in
+//! common with. Peers from before versions were negotiated send neither,
+//! and are taken to speak [ProtocolVersion::INITIAL] only.
+//!
+//! The negotiated version is kept by the codecs of a connection's
+//! [Protocol](crate::Protocol), which hand it to packets that are written
+//! differently depending on it, see
+//! [SerializePacket::serialize_for](crate::packet::SerializePacket::serialize_for).
+
+use std::fmt::{Display, Formatter};
+
+use bytes::{Buf, BufMut, BytesMut};
+
+/// A version of the wire protocol. Versions with the same `major` only
+/// differ in what newer peers understand, a new `major` may change what
+/// packets mean.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ProtocolVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl ProtocolVersion {
+    /// The version spoken by peers from before versions were negotiated.
+    pub const INITIAL: ProtocolVersion = ProtocolVersion::new(1, 0);
+
+    pub const fn new(major: u8, minor: u8) -> Self {
+        ProtocolVersion { major, minor }
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.major);
+        buf.put_u8(self.minor);
+    }
+
+    /// Read a version written by [write](Self::write), or `None` if `buf` is
+    /// too short.
+    pub(crate) fn read(buf: &mut BytesMut) -> Option<Self> {
+        if buf.remaining() < 2 {
+            return None;
+        }
+        Some(ProtocolVersion::new(buf.get_u8(), buf.get_u8()))
+    }
+}
+
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// The version implemented by this crate.
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 10);
+
+/// Peers speaking a version before this one are still served, but are
+/// deprecated, as they can't take chunked pushes of large objects. Nodes
+/// warn about them so their operators upgrade.
+pub const DEPRECATED_BEFORE: ProtocolVersion = ProtocolVersion::new(1, 2);
+
+/// The versions a peer speaks, from `min` up to and including `max`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VersionRange {
+    pub min: ProtocolVersion,
+    pub max: ProtocolVersion,
+}
+
+impl VersionRange {
+    /// The versions spoken by peers from before versions were negotiated.
+    pub const INITIAL: VersionRange = VersionRange {
+        min: ProtocolVersion::INITIAL,
+        max: ProtocolVersion::INITIAL,
+    };
+
+    /// The versions spoken by this crate.
+    pub const SUPPORTED: VersionRange = VersionRange {
+        min: ProtocolVersion::INITIAL,
+        max: PROTOCOL_VERSION,
+    };
+
+    pub fn contains(&self, version: ProtocolVersion) -> bool {
+        self.min <= version && version <= self.max
+    }
+
+    /// The highest version in both ranges, or `None` if they don't overlap.
+    pub fn negotiate(&self, other: &VersionRange) -> Option<ProtocolVersion> {
+        let version = self.max.min(other.max);
+        (version >= self.min && version >= other.min).then_some(version)
+    }
+
+    pub(crate) fn write(&self, buf: &mut BytesMut) {
+        self.min.write(buf);
+        self.max.write(buf);
+    }
+
+    pub(crate) fn read(buf: &mut BytesMut) -> Option<Self> {
+        Some(VersionRange {
+            min: ProtocolVersion::read(buf)?,
+            max: ProtocolVersion::read(buf)?,
+        })
+    }
+}
+
+impl Display for VersionRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} to {}", self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::version::{ProtocolVersion, VersionRange};
+
+    #[test]
+    fn test_negotiate() {
+        let range = |min: (u8, u8), max: (u8, u8)| VersionRange {
+            min: ProtocolVersion::new(min.0, min.1),
+            max: ProtocolVersion::new(max.0, max.1),
+        };
+        let ours = range((1, 0), (1, 4));
+        assert_eq!(ours.negotiate(&range((1, 2), (2, 0))), Some(ProtocolVersion::new(1, 4)));
+        assert_eq!(ours.negotiate(&range((1, 0), (1, 1))), Some(ProtocolVersion::new(1, 1)));
+        assert_eq!(ours.negotiate(&range((2, 0), (2, 3))), None);
+        assert_eq!(range((1, 3), (1, 4)).negotiate(&range((1, 0), (1, 2))), None);
+        assert!(ProtocolVersion::new(1, 10) > ProtocolVersion::new(1, 9));
+        assert!(ProtocolVersion::new(2, 0) > ProtocolVersion::new(1, 9));
+    }
+}
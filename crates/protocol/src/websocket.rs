@@ -0,0 +1,137 @@
+//! # WebSocket Transport
+//!
+//! Some hosts only allow HTTP(S) traffic in and out. With the `websocket`
+//! feature, a [Protocol](crate::Protocol) can run over a WebSocket instead
+//! of raw TCP, see [Protocol::with_websocket](crate::Protocol::with_websocket).
+//! Nodes are connected to with `ws://` and `wss://` URLs, see
+//! [Scheme](crate::Scheme), and accept the WebSocket upgrade at
+//! [WEBSOCKET_PATH].
+//!
+//! The frames of the connection are carried in binary messages as the
+//! stream they'd be over TCP: a message may hold several frames or part of
+//! one, so messages are never parsed on their own. A text message fails the
+//! connection, and a close message ends it like a FIN does a TCP
+//! connection, see [Closure](crate::Closure).
+
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures_util::{Sink, Stream};
+
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+/// The path nodes accept the WebSocket upgrade at.
+pub const WEBSOCKET_PATH: &str = "/osp";
+
+/// A WebSocket read and written like the byte stream of a TCP connection.
+pub struct WebSocketTransport<S> {
+    socket: WebSocketStream<S>,
+    /// The last binary message read
+    message: Vec<u8>,
+    /// How much of `message` was read
+    read: usize,
+}
+
+impl<S> WebSocketTransport<S> {
+    pub fn new(socket: WebSocketStream<S>) -> Self {
+        WebSocketTransport { socket, message: Vec::new(), read: 0 }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketTransport<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if self.read < self.message.len() {
+                let end = self.message.len().min(self.read + buf.remaining());
+                buf.put_slice(&self.message[self.read..end]);
+                self.read = end;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut self.socket).poll_next(cx)) {
+                Some(Ok(Message::Binary(message))) => {
+                    self.message = message;
+                    self.read = 0;
+                }
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "Frames are carried in binary messages, not text")));
+                }
+                // nothing read is the end of the stream
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // pings are answered by the socket
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Err(tungstenite::Error::ConnectionClosed)) => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketTransport<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.socket).poll_ready(cx)).map_err(io_error)?;
+        Pin::new(&mut self.socket).start_send(Message::Binary(buf.to_vec())).map_err(io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_flush(cx).map_err(io_error)
+    }
+
+    /// Send a close message, after which the peer's messages are read
+    /// until it answers with its own.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match ready!(Pin::new(&mut self.socket).poll_close(cx)) {
+            Ok(()) | Err(tungstenite::Error::ConnectionClosed) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(io_error(e))),
+        }
+    }
+}
+
+/// Turn the errors of a WebSocket into those its transport would fail with.
+fn io_error(err: tungstenite::Error) -> io::Error {
+    use tungstenite::error::ProtocolError;
+
+    match err {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::AlreadyClosed => io::Error::new(io::ErrorKind::NotConnected, err),
+        tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake) => {
+            io::Error::new(io::ErrorKind::ConnectionReset, err)
+        }
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::{Closure, ConnectionType, Protocol, PROTOCOL_VERSION};
+    use crate::packet::handshake::{HandshakePacketGuestToHost, HandshakePacketHostToGuest};
+    use crate::websocket::WEBSOCKET_PATH;
+
+    #[tokio::test]
+    async fn test_websocket_transport() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let host = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut protocol = Protocol::<HandshakePacketGuestToHost, HandshakePacketHostToGuest>::with_websocket(socket).unwrap();
+            let hello = protocol.read_frame().await.unwrap();
+            let closed = protocol.read_frame().await.map(|_| ()).unwrap_err();
+            (hello, Closure::of(&closed))
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = tokio_tungstenite::client_async(format!("ws://{addr}{WEBSOCKET_PATH}"), stream).await.unwrap();
+        let mut guest = Protocol::<HandshakePacketHostToGuest, HandshakePacketGuestToHost>::with_websocket(socket).unwrap();
+        guest.send_message(HandshakePacketGuestToHost::Hello { connection_type: ConnectionType::Server, version: PROTOCOL_VERSION, compression: Vec::new(), formats: Vec::new(), software: None, timestamp: None }).await.unwrap();
+        guest.close().await.unwrap();
+        let (hello, closure) = host.await.unwrap();
+        assert!(matches!(hello, HandshakePacketGuestToHost::Hello { connection_type: ConnectionType::Server, .. }));
+        assert_eq!(closure, Some(Closure::Fin));
+    }
+}
@@ -0,0 +1,467 @@
+//! # Admin API
+//!
+//! Operator facing queries and actions on a running node.
+
+use std::io::{BufRead, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, LevelFilter};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_data::Data;
+use osp_protocol::{Envelope, Invite, Tombstone, TypeDescriptor};
+
+use crate::OSProtocolNode;
+use crate::compliance::{self, ComplianceReport};
+use crate::dedup::DedupStats;
+use crate::connection::accounting::PeerCost;
+use crate::connection::challenge::ChallengeRecord;
+use crate::connection::diagnostics::PeerDiagnostics;
+use crate::connection::registry::ConnectionInfo;
+use crate::federation::{FederationAction, FederationRule};
+use crate::invite::InviteRecord;
+use crate::notice::{Notice, PublishedNotice, ReceivedNotice};
+use crate::rollout::RolloutStats;
+use crate::schema::PeerTypeDescriptor;
+use crate::store::{AuditEntry, TypeUsage};
+use crate::subscription::{Subscription, SubscriptionState};
+use crate::store::archive::{self, ArchiveFormat};
+use crate::store::quota::StorageQuota;
+
+/// Storage used by a data type, alongside the quota it is held to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StorageReport {
+    pub usage: TypeUsage,
+    pub quota: Option<StorageQuota>,
+}
+
+/// Handle for administering a node, obtained from [OSProtocolNode::admin].
+#[derive(Clone)]
+pub struct AdminApi {
+    node: OSProtocolNode,
+}
+
+impl AdminApi {
+    pub(crate) fn new(node: OSProtocolNode) -> Self {
+        AdminApi { node }
+    }
+
+    /// Object counts and byte usage for every stored data type.
+    pub fn storage_usage(&self) -> io::Result<Vec<StorageReport>> {
+        Ok(self.node.data_store().usage()?
+            .into_iter()
+            .map(|usage| StorageReport {
+                quota: self.node.storage_quota(usage.type_id),
+                usage,
+            })
+            .collect())
+    }
+
+    /// Usage for a single data type.
+    pub fn type_usage(&self, type_id: Uuid) -> io::Result<StorageReport> {
+        Ok(StorageReport {
+            usage: self.node.data_store().type_usage(type_id)?,
+            quota: self.node.storage_quota(type_id),
+        })
+    }
+
+    /// Write every stored object to an archive. See [archive] for the
+    /// formats.
+    pub fn export_archive<W: Write>(&self, writer: W, format: ArchiveFormat) -> io::Result<usize> {
+        archive::export(self.node.data_store().as_ref(), writer, format)
+    }
+
+    /// Import the objects in an archive, validating, transforming and
+    /// storing them within the storage quotas as if they had been received
+    /// from a peer. Objects that were taken down, or that the node refuses,
+    /// are skipped.
+    pub fn import_archive<R: BufRead>(&self, reader: R, format: ArchiveFormat) -> io::Result<usize> {
+        archive::import(reader, format, |envelope| match self.node.ingest(envelope.clone()) {
+            Err(e) if matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::InvalidData) => {
+                debug!("Skipping archived object: {e}");
+                Ok(())
+            }
+            result => result,
+        })
+    }
+
+    /// Take down `object_id`, e.g. in response to a legal request from
+    /// `authority`. The object is removed and a tombstone kept in its place,
+    /// so it is neither served, pushed to peers nor accepted again. The
+    /// takedown is recorded in the audit log. The tombstone is signed with
+    /// the node's [Ed25519 key](crate::node::OSProtocolNodeBuilder::ed25519_key),
+    /// if it has one.
+    ///
+    /// Pass the returned tombstone to
+    /// [OutboundConnection::push_takedown](crate::connection::outbound::OutboundConnection::push_takedown)
+    /// to propagate the takedown to peers.
+    pub fn take_down(&self, object_id: Uuid, reason: &str, authority: &str) -> io::Result<Tombstone> {
+        let mut tombstone = Tombstone::new(object_id, reason, authority);
+        self.node.sign_tombstone(&mut tombstone)?;
+        self.node.apply_takedown(&tombstone, "operator")?;
+        Ok(tombstone)
+    }
+
+    /// The tombstone left for `object_id`, if it was taken down.
+    pub fn tombstone(&self, object_id: Uuid) -> io::Result<Option<Tombstone>> {
+        self.node.data_store().tombstone(object_id)
+    }
+
+    /// Up to `limit` audit log entries, oldest first, skipping the first
+    /// `offset`.
+    pub fn audit_log(&self, offset: usize, limit: usize) -> io::Result<Vec<AuditEntry>> {
+        self.node.data_store().audit_log(offset, limit)
+    }
+
+    /// Report what this node held and removed between the unix timestamps
+    /// `from` and `to` (seconds, `to` excluded). See [compliance] for what
+    /// it covers, and [ComplianceReport::write] to export it.
+    pub fn compliance_report(&self, from: u64, to: u64) -> io::Result<ComplianceReport> {
+        compliance::generate(self.node.data_store().as_ref(), self.node.storage_quotas(), from, to)
+    }
+
+    /// How many received objects were dropped as duplicates.
+    pub fn dedup_stats(&self) -> DedupStats {
+        self.node.dedup_stats()
+    }
+
+    /// What this node learned about each peer in its latest handshake with
+    /// it: the software it runs, the protocol version spoken and how far its
+    /// clock is off.
+    pub fn peer_diagnostics(&self) -> Vec<PeerDiagnostics> {
+        self.node.peer_diagnostics()
+    }
+
+    /// How handshakes fared with and without each feature being rolled out,
+    /// to tell whether rolling it out further is safe.
+    pub fn rollout_stats(&self) -> Vec<RolloutStats> {
+        self.node.rollout_stats()
+    }
+
+    /// Tell the guests of this node about `notice`, such as an upcoming
+    /// shutdown, returning the id to withdraw it with.
+    pub fn publish_notice(&self, notice: Notice) -> u64 {
+        self.node.publish_notice(notice)
+    }
+
+    pub fn withdraw_notice(&self, id: u64) -> bool {
+        self.node.withdraw_notice(id)
+    }
+
+    pub fn published_notices(&self) -> Vec<PublishedNotice> {
+        self.node.published_notices()
+    }
+
+    /// The notices peers sent this node, such as their upcoming shutdowns.
+    pub fn received_notices(&self) -> Vec<ReceivedNotice> {
+        self.node.received_notices()
+    }
+
+    /// Descriptors of the data types this node serves.
+    pub fn data_types(&self) -> Vec<TypeDescriptor> {
+        self.node.schemas().all_local()
+    }
+
+    /// Descriptors fetched from peers for the types flowing through this
+    /// node.
+    pub fn peer_data_types(&self) -> Vec<PeerTypeDescriptor> {
+        self.node.schemas().all_remote()
+    }
+
+    /// Describe `type_id`, from this node's own types or else from the
+    /// descriptors fetched from peers.
+    pub fn describe_type(&self, type_id: Uuid) -> Option<TypeDescriptor> {
+        self.node.schemas().local(type_id)
+            .or_else(|| self.node.schemas().remote(type_id).map(|remote| remote.descriptor))
+    }
+
+    /// Every peer's subscription, in any state.
+    pub fn subscriptions(&self) -> io::Result<Vec<Subscription>> {
+        self.node.data_store().subscriptions()
+    }
+
+    /// Subscriptions awaiting approval.
+    pub fn pending_subscriptions(&self) -> io::Result<Vec<Subscription>> {
+        Ok(self.subscriptions()?
+            .into_iter()
+            .filter(|subscription| subscription.state == SubscriptionState::Pending)
+            .collect())
+    }
+
+    /// Approve the subscription of `peer`. Denied subscriptions can be
+    /// approved too.
+    pub fn approve_subscription(&self, peer: &str) -> io::Result<()> {
+        self.node.decide_subscription(peer, true)
+    }
+
+    /// Deny the subscription of `peer`. Its later requests are denied until
+    /// it is approved.
+    pub fn deny_subscription(&self, peer: &str) -> io::Result<()> {
+        self.node.decide_subscription(peer, false)
+    }
+
+    /// Invite the peer `invitee`, whose PEM encoded public key is
+    /// `public_key`, for `valid_for` or indefinitely. Hand the invitee the
+    /// invite as a token, see [invite::to_token](crate::invite::to_token).
+    pub fn issue_invite(&self, invitee: &str, public_key: &str, valid_for: Option<Duration>) -> io::Result<Invite> {
+        let expires_at = valid_for.map(|valid_for| {
+            (SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + valid_for).as_secs()
+        });
+        self.node.issue_invite(invitee, public_key, expires_at)
+    }
+
+    /// Revoke an invite, refusing connections that present it from now on.
+    pub fn revoke_invite(&self, token_id: Uuid) -> io::Result<()> {
+        self.node.revoke_invite(token_id)
+    }
+
+    /// Every invite this node issued.
+    pub fn invites(&self) -> io::Result<Vec<InviteRecord>> {
+        self.node.data_store().invites()
+    }
+
+    /// Federate with `host`, even if the node only federates with allowed
+    /// hosts.
+    pub fn allow_host(&self, host: &str) -> io::Result<FederationRule> {
+        self.node.set_federation_rule(host, FederationAction::Allow)
+    }
+
+    /// Refuse to federate with `host`, in either direction.
+    pub fn deny_host(&self, host: &str) -> io::Result<FederationRule> {
+        self.node.set_federation_rule(host, FederationAction::Deny)
+    }
+
+    /// The TXT record to publish at `_osp.<hostname>` so peers can challenge
+    /// this node, announcing its key as valid for `valid_for` or
+    /// indefinitely.
+    pub fn challenge_record(&self, valid_for: Option<Duration>) -> io::Result<ChallengeRecord> {
+        let expires_at = valid_for.map(|valid_for| {
+            (SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + valid_for).as_secs()
+        });
+        ChallengeRecord::rsa(&self.node.public_key()?, expires_at)
+    }
+
+    /// The TXT record publishing the node's [Ed25519 key](crate::builder::OSProtocolNodeBuilder::ed25519_key)
+    /// like [challenge_record](Self::challenge_record), if it has one.
+    pub fn ed25519_challenge_record(&self, valid_for: Option<Duration>) -> io::Result<Option<ChallengeRecord>> {
+        let expires_at = valid_for.map(|valid_for| {
+            (SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + valid_for).as_secs()
+        });
+        self.node.ed25519_public_key()?.map(|key| ChallengeRecord::ed25519(&key, expires_at)).transpose()
+    }
+
+    /// Every federation rule, whether set on this node or merged from its
+    /// cluster.
+    pub fn federation_rules(&self) -> io::Result<Vec<FederationRule>> {
+        self.node.data_store().federation_rules()
+    }
+
+    /// A signed update carrying every federation rule of this node, for
+    /// pushing to the other members of its cluster like any other object.
+    pub fn federation_update(&self) -> io::Result<Envelope> {
+        let update = self.node.federation_update()?;
+        Ok(update.to_envelope(update.signer.clone())?)
+    }
+
+    /// Every inbound connection currently open, oldest first.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.node.connections().connections()
+    }
+
+    /// Close the inbound connection with id `id`. Returns whether it was
+    /// open.
+    pub fn disconnect(&self, id: u64) -> bool {
+        self.node.connections().disconnect(id)
+    }
+
+    /// Log what happens on the inbound connection with id `id` at `level`
+    /// and above, or at the level of the node again if `None`, to debug the
+    /// peer on it, see
+    /// [Debugging a Connection](crate::logging#debugging-a-connection).
+    /// Returns whether it was open.
+    pub fn set_connection_log_level(&self, id: u64, level: Option<LevelFilter>) -> bool {
+        self.node.connections().set_log_level(id, level)
+    }
+
+    /// What serving each peer cost recently, the most expensive first.
+    pub fn peer_costs(&self) -> Vec<PeerCost> {
+        self.node.accounting().stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_protocol::Envelope;
+
+    use crate::OSProtocolNode;
+    use crate::crypto::{Ed25519Key, PrivateKey};
+    use crate::invite;
+    use crate::store::AuditAction;
+    use crate::store::archive::{ArchiveFormat, ArchiveWriter};
+    use crate::subscription::{DeliveryQos, SubscriptionApproval, SubscriptionState};
+
+    #[test]
+    fn test_take_down() -> io::Result<()> {
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let envelope = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![1, 2, 3]);
+        node.store_object(&envelope)?;
+
+        let tombstone = node.admin().take_down(envelope.object_id, "copyright infringement", "Rights Holder Ltd")?;
+        assert_eq!(node.data_store().get_object(envelope.object_id)?, None);
+        assert_eq!(node.admin().tombstone(envelope.object_id)?, Some(tombstone));
+        assert_eq!(node.store_object(&envelope).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        let audit = node.admin().audit_log(0, 10)?;
+        assert_eq!(audit.len(), 1);
+        assert_eq!((audit[0].action, audit[0].object_id), (AuditAction::Takedown, Some(envelope.object_id)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_takedown_authority() -> io::Result<()> {
+        let key = Ed25519Key::generate()?;
+        let authority = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("authority.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .ed25519_key(key.clone())
+            .build()?;
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .honor_takedowns(true)
+            .takedown_authority(key.public_key()?)
+            .build()?;
+        let envelope = Envelope::new(Uuid::new_v4(), "Origin.test".to_string(), vec![1, 2, 3]);
+        node.store_object(&envelope)?;
+
+        let mut tombstone = authority.admin().take_down(envelope.object_id, "spam", "Moderators")?;
+        assert!(node.accepts_takedown(&tombstone, "relay.test")?);
+        tombstone.reason = "something else".to_string();
+        assert!(!node.accepts_takedown(&tombstone, "relay.test")?);
+        tombstone.signature = None;
+        assert!(!node.accepts_takedown(&tombstone, "relay.test")?);
+        assert!(node.accepts_takedown(&tombstone, "origin.test")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_archive() -> io::Result<()> {
+        let type_id = Uuid::new_v4();
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .validator("non-empty", type_id, |envelope: &Envelope| match envelope.payload.is_empty() {
+                true => Err(io::Error::new(io::ErrorKind::InvalidInput, "empty payload")),
+                false => Ok(()),
+            })
+            .build()?;
+        let (valid, empty) = (Envelope::new(type_id, "origin.test".to_string(), vec![1]), Envelope::new(type_id, "origin.test".to_string(), Vec::new()));
+        let mut archive = ArchiveWriter::new(Vec::new(), ArchiveFormat::Jsonl)?;
+        archive.write(&valid)?;
+        archive.write(&empty)?;
+
+        // objects are validated as if a peer pushed them
+        node.admin().import_archive(archive.finish()?.as_slice(), ArchiveFormat::Jsonl)?;
+        assert!(node.data_store().get_object(valid.object_id)?.is_some());
+        assert_eq!(node.data_store().get_object(empty.object_id)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscription_approval() -> io::Result<()> {
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .subscription_approval(SubscriptionApproval::Manual)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let data_types = vec![Uuid::new_v4()];
+
+        assert_eq!(node.request_subscription("peer.test", data_types.clone(), Vec::new(), DeliveryQos::default(), None, Vec::new())?, SubscriptionState::Pending);
+        assert_eq!(node.admin().pending_subscriptions()?.len(), 1);
+
+        node.admin().approve_subscription("peer.test")?;
+        assert_eq!(node.request_subscription("peer.test", data_types.clone(), Vec::new(), DeliveryQos::default(), None, Vec::new())?, SubscriptionState::Approved);
+        // asking for more types needs approval again
+        assert_eq!(node.request_subscription("peer.test", vec![Uuid::new_v4()], Vec::new(), DeliveryQos::default(), None, Vec::new())?, SubscriptionState::Pending);
+
+        node.admin().deny_subscription("peer.test")?;
+        assert_eq!(node.request_subscription("peer.test", data_types, Vec::new(), DeliveryQos::default(), None, Vec::new())?, SubscriptionState::Denied);
+        assert!(node.admin().approve_subscription("other.test").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invites() -> io::Result<()> {
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("issuer.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let invitee_key = PrivateKey::generate(1024)?.public_key()?.to_pem()?;
+
+        let invite = node.admin().issue_invite("invitee.test", &invitee_key, None)?;
+        let presented = invite::from_token(&invite::to_token(&invite)?)?;
+        assert!(node.verify_invite("invitee.test", &presented).is_ok());
+        assert!(node.verify_invite("other.test", &presented).is_err());
+
+        let mut forged = presented.clone();
+        forged.invitee = "other.test".to_string();
+        assert!(node.verify_invite("other.test", &forged).is_err());
+
+        node.admin().revoke_invite(invite.token_id)?;
+        assert!(node.verify_invite("invitee.test", &presented).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_federation_sync() -> io::Result<()> {
+        let key = PrivateKey::generate(1024)?;
+        let publisher = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("a.cluster.test".to_string())
+            .private_key(key.clone())
+            .build()?;
+        let member = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("b.cluster.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .allowlist_only(true)
+            .federation_peer("a.cluster.test", key.public_key()?)
+            .build()?;
+
+        publisher.admin().allow_host("Friend.test")?;
+        publisher.admin().deny_host("spam.test")?;
+        assert!(publisher.check_federation("spam.test").is_err());
+        assert!(member.check_federation("friend.test").is_err());
+
+        let update = publisher.admin().federation_update()?;
+        assert_eq!(member.merge_federation_update(&update)?, 2);
+        assert!(member.check_federation("friend.test").is_ok());
+        assert!(member.check_federation("spam.test").is_err());
+        assert_eq!(member.merge_federation_update(&update)?, 0);
+
+        let outsider = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("a.cluster.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        outsider.admin().allow_host("spam.test")?;
+        assert!(member.merge_federation_update(&outsider.admin().federation_update()?).is_err());
+        assert!(member.check_federation("spam.test").is_err());
+        Ok(())
+    }
+}
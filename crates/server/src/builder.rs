@@ -0,0 +1,223 @@
+//! # Node Builder
+//!
+//! [OSProtocolNodeBuilder] tracks in its type whether the bind address,
+//! hostname and identity of the node were set, and only offers `build` once
+//! all three are:
+//!
+//! ```compile_fail
+//! use osp_server_sdk::OSProtocolNode;
+//!
+//! // no bind address or identity
+//! let node = OSProtocolNode::builder()
+//!     .hostname("example.com".to_string())
+//!     .build();
+//! ```
+//!
+//! Constraints that can only be checked at runtime are checked by `build`,
+//! which reports every problem it finds in a [BuildError].
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use tokio::io;
+
+use crate::rollout::Feature;
+
+pub use crate::node::OSProtocolNodeBuilder;
+
+/// A required builder setting that wasn't set yet.
+pub struct Missing;
+
+/// A required builder setting that was set.
+pub struct Provided;
+
+/// A setting that keeps the node from being built.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigProblem {
+    /// The hostname is not a valid DNS name.
+    InvalidHostname { hostname: String, reason: String },
+    /// The private key could not be loaded, or is not a valid RSA key.
+    InvalidPrivateKey { path: Option<String>, reason: String },
+    /// The identity endpoint would be bound to the node's own address.
+    IdentityEndpointConflict,
+    /// The WebSocket endpoint would be bound to the node's own address, or
+    /// that of its identity endpoint.
+    WebSocketEndpointConflict,
+    /// Maintenance would run in a busy loop.
+    ZeroMaintenanceInterval,
+    /// Every connection would be refused.
+    ZeroMaxConnections,
+    /// Every connection would be refused, as no address may have one open.
+    ZeroMaxConnectionsPerIp,
+    /// The rate limit would refuse every connection, or not be a number.
+    InvalidRateLimit,
+    /// Every handshake would time out.
+    ZeroHandshakeTimeout,
+    /// Errors about peers would never be suppressed.
+    ZeroErrorSamplingInterval,
+    /// Failed deliveries would never be retried, or the backoff makes no
+    /// sense.
+    InvalidRetryPolicy,
+    /// Cost accounting windows would have no length, or the weights or
+    /// fair share capacity are negative or not numbers.
+    InvalidAccountingPolicy,
+    /// Subscriptions would expire before they could be renewed.
+    ZeroSubscriptionLease,
+    /// Guests could never push.
+    ZeroPushWindow,
+    /// Chunked pushes couldn't be reassembled, as not even one chunk fits
+    /// in the reassembly budget.
+    SmallReassemblyBudget,
+    /// Connections would be pinged in a busy loop, or closed before their
+    /// peer could answer a ping.
+    InvalidKeepalive,
+    /// A feature would be rolled out to more than every peer.
+    InvalidRollout { feature: Feature },
+    /// Peers would be recorded with no directory to record them to.
+    RecordingsWithoutDirectory,
+    /// Idle connections would be closed before new ones are refused, or
+    /// only once no files are left.
+    InvalidResourceLimits,
+    /// A buffer size, keepalive setting or user timeout of the `listener`
+    /// or `outbound` socket options is zero.
+    InvalidSocketOptions { sockets: &'static str },
+    /// Keepalive probes of the `listener` or `outbound` sockets are given
+    /// an interval or retries in a config file, but not turned on with
+    /// `keepalive_secs`, so they would be ignored.
+    KeepaliveWithoutTime { sockets: &'static str },
+    /// An RSS feed would be polled in a busy loop.
+    #[cfg(feature = "rss-bridge")]
+    ZeroPollInterval { url: String },
+    /// A Nostr bridge has no relays to bridge with.
+    #[cfg(feature = "nostr-bridge")]
+    NoNostrRelays,
+    /// A Matrix bridge has no rooms to post into.
+    #[cfg(feature = "matrix-bridge")]
+    NoMatrixRooms,
+    /// Signed objects are checked, but the node can't look up the records
+    /// to check them against, so it would refuse every one.
+    #[cfg(not(feature = "dns-auth"))]
+    UnverifiableSignedTypes,
+}
+
+impl Display for ConfigProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigProblem::InvalidHostname { hostname, reason } => write!(f, "invalid hostname {hostname:?}: {reason}"),
+            ConfigProblem::InvalidPrivateKey { path: Some(path), reason } => write!(f, "invalid private key in {path}: {reason}"),
+            ConfigProblem::InvalidPrivateKey { path: None, reason } => write!(f, "invalid private key: {reason}"),
+            ConfigProblem::IdentityEndpointConflict => write!(f, "the identity endpoint uses the bind address of the node"),
+            ConfigProblem::WebSocketEndpointConflict => write!(f, "the WebSocket endpoint uses the bind address of the node or its identity endpoint"),
+            ConfigProblem::ZeroMaintenanceInterval => write!(f, "the maintenance interval is zero"),
+            ConfigProblem::ZeroMaxConnections => write!(f, "the connection limit is zero"),
+            ConfigProblem::ZeroMaxConnectionsPerIp => write!(f, "the connection limit per address is zero"),
+            ConfigProblem::InvalidRateLimit => write!(f, "the connection rate limit needs a positive burst and rate"),
+            ConfigProblem::ZeroHandshakeTimeout => write!(f, "the handshake timeout is zero"),
+            ConfigProblem::ZeroErrorSamplingInterval => write!(f, "the error sampling interval is zero"),
+            ConfigProblem::InvalidRetryPolicy => write!(f, "the retry policy needs a multiplier of at least 1, a jitter between 0 and 1 and at least one attempt"),
+            ConfigProblem::InvalidAccountingPolicy => write!(f, "the cost accounting policy needs a window, weights that aren't negative and a positive fair share capacity"),
+            ConfigProblem::ZeroSubscriptionLease => write!(f, "the subscription lease is shorter than a second"),
+            ConfigProblem::ZeroPushWindow => write!(f, "the push window is zero"),
+            ConfigProblem::SmallReassemblyBudget => write!(f, "the reassembly budget is smaller than a chunk"),
+            ConfigProblem::InvalidKeepalive => write!(f, "the keepalive interval is zero, or not shorter than the idle timeout"),
+            ConfigProblem::InvalidRollout { feature } => write!(f, "the rollout of {feature} is to more than 100 percent of peers"),
+            ConfigProblem::RecordingsWithoutDirectory => write!(f, "peers are recorded, but there is no directory to record them to"),
+            ConfigProblem::InvalidResourceLimits => write!(f, "the resource limits need shares of open files up to 1, refusing connections before shedding them"),
+            ConfigProblem::InvalidSocketOptions { sockets } => write!(f, "the {sockets} socket options have a setting of 0"),
+            ConfigProblem::KeepaliveWithoutTime { sockets } => write!(f, "the {sockets} socket options tune keepalive probes without turning them on with keepalive_secs"),
+            #[cfg(feature = "rss-bridge")]
+            ConfigProblem::ZeroPollInterval { url } => write!(f, "the RSS feed {url} is polled every 0 seconds"),
+            #[cfg(feature = "nostr-bridge")]
+            ConfigProblem::NoNostrRelays => write!(f, "the Nostr bridge has no relays"),
+            #[cfg(feature = "matrix-bridge")]
+            ConfigProblem::NoMatrixRooms => write!(f, "the Matrix bridge has no rooms"),
+            #[cfg(not(feature = "dns-auth"))]
+            ConfigProblem::UnverifiableSignedTypes => write!(f, "signed data types are checked, but the node is built without the dns-auth feature"),
+        }
+    }
+}
+
+/// Why a node could not be built.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuildError {
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unable to build node: ")?;
+        for (i, problem) in self.problems.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for BuildError {}
+
+impl From<BuildError> for io::Error {
+    fn from(error: BuildError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+/// Check `hostname` is a DNS name a peer could look up.
+pub(crate) fn check_hostname(hostname: &str) -> Result<(), String> {
+    if hostname.is_empty() {
+        return Err("empty".to_string());
+    }
+    if hostname.len() > 253 {
+        return Err("longer than 253 characters".to_string());
+    }
+    for label in hostname.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("label {label:?} is not 1 to 63 characters long"));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(format!("label {label:?} starts or ends with a hyphen"));
+        }
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(format!("label {label:?} contains characters other than letters, digits and hyphens"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io;
+
+    use crate::OSProtocolNode;
+    use crate::crypto::PrivateKey;
+    use crate::builder::ConfigProblem;
+
+    #[test]
+    fn test_build_validation() -> io::Result<()> {
+        let addr = "127.0.0.1:57401".parse().unwrap();
+        let error = OSProtocolNode::builder()
+            .bind_to(addr)
+            .hostname("-bad_host.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .identity_endpoint(addr)
+            .maintenance_interval(Duration::ZERO)
+            .build()
+            .err().unwrap();
+        assert_eq!(error.problems.len(), 3);
+        assert!(matches!(error.problems[0], ConfigProblem::InvalidHostname { .. }));
+        assert!(error.problems.contains(&ConfigProblem::IdentityEndpointConflict));
+
+        let error = OSProtocolNode::builder()
+            .bind_to(addr)
+            .hostname("node.test".to_string())
+            .private_key_file("/nonexistent/key.pem".to_string())
+            .build()
+            .err().unwrap();
+        assert!(matches!(error.problems[..], [ConfigProblem::InvalidPrivateKey { path: Some(_), .. }]));
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+//! # Compliance Reports
+//!
+//! Operators with compliance obligations have to account for what their node
+//! holds and removed over a period. A [ComplianceReport] summarizes, for the
+//! objects stored between two timestamps:
+//!
+//! - the content still held, by data type and origin
+//! - the storage quotas applied as retention policies, and how many objects
+//!   each evicted
+//! - the takedowns executed
+//! - the purges of actors, and deletions requested by origins
+//!
+//! Reports are generated from the store and its audit log, through
+//! [AdminApi::compliance_report](crate::admin::AdminApi::compliance_report) or
+//! [generate] for a store that isn't served by a node, and exported as JSON
+//! or CSV, see [ReportFormat].
+
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+use serde_json::{json, Value};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use crate::embargo;
+use crate::store::{AuditAction, AuditEntry, DataStore, OriginUsage};
+use crate::store::quota::{EvictionPolicy, StorageQuota, StorageQuotas};
+use crate::time::rfc3339;
+
+/// Audit log entries read at once while generating a report.
+const AUDIT_PAGE: usize = 1000;
+
+/// A format reports are exported in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    /// A single JSON document, with a member for each section.
+    Json,
+    /// A single table, each row naming the section it belongs to in its
+    /// first column.
+    Csv,
+}
+
+impl FromStr for ReportFormat {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(ReportFormat::Json),
+            "csv" => Ok(ReportFormat::Csv),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown report format {s}"))),
+        }
+    }
+}
+
+impl fmt::Display for ReportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReportFormat::Json => write!(f, "json"),
+            ReportFormat::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+/// A storage quota applied to a data type, and what it evicted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetentionSummary {
+    pub type_id: Uuid,
+    /// `None` for types that had objects evicted but whose quota isn't known,
+    /// e.g. because it was removed since.
+    pub quota: Option<StorageQuota>,
+    /// Number of objects evicted in the period.
+    pub evicted: u64,
+}
+
+/// What a node held and removed over a period, see the [module](self) docs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ComplianceReport {
+    /// Unix timestamp (seconds) the report was generated at.
+    pub generated_at: u64,
+    /// Start of the period, a unix timestamp (seconds).
+    pub from: u64,
+    /// End of the period, excluded.
+    pub to: u64,
+    /// Objects stored in the period and still held, by type and origin.
+    pub content: Vec<OriginUsage>,
+    pub retention: Vec<RetentionSummary>,
+    pub takedowns: Vec<AuditEntry>,
+    /// Purges of actors and deletions requested by origins.
+    pub deletions: Vec<AuditEntry>,
+}
+
+/// Generate the report for the period between the unix timestamps `from`
+/// and `to` (seconds, `to` excluded) from `store`, with the retention
+/// policies `quotas`.
+pub fn generate(store: &dyn DataStore, quotas: &StorageQuotas, from: u64, to: u64) -> io::Result<ComplianceReport> {
+    let mut content = store.origin_usage(from, to)?;
+    content.sort_by(|a, b| (a.type_id, &a.origin).cmp(&(b.type_id, &b.origin)));
+
+    let mut retention: Vec<_> = quotas.iter()
+        .map(|(type_id, quota)| RetentionSummary { type_id, quota: Some(quota.clone()), evicted: 0 })
+        .collect();
+    let (mut takedowns, mut deletions) = (Vec::new(), Vec::new());
+    let mut offset = 0;
+    loop {
+        let page = store.audit_log_between(from, to, offset, AUDIT_PAGE)?;
+        offset += page.len();
+        for entry in &page {
+            match entry.action {
+                AuditAction::Takedown => takedowns.push(entry.clone()),
+                AuditAction::Purge | AuditAction::Delete => deletions.push(entry.clone()),
+                AuditAction::Evict => {
+                    let Some(type_id) = entry.type_id else { continue };
+                    match retention.iter_mut().find(|summary| summary.type_id == type_id) {
+                        Some(summary) => summary.evicted += 1,
+                        None => retention.push(RetentionSummary { type_id, quota: None, evicted: 1 }),
+                    }
+                }
+                _ => {}
+            }
+        }
+        if page.len() < AUDIT_PAGE {
+            break;
+        }
+    }
+    retention.sort_by_key(|summary| summary.type_id);
+
+    Ok(ComplianceReport {
+        generated_at: embargo::now(),
+        from,
+        to,
+        content,
+        retention,
+        takedowns,
+        deletions,
+    })
+}
+
+fn describe_quota(quota: &StorageQuota) -> String {
+    let limit = |max: Option<u64>, unit: &str| max.map_or_else(|| format!("unlimited {unit}"), |max| format!("at most {max} {unit}"));
+    let eviction = match quota.eviction {
+        EvictionPolicy::OldestFirst => "evicting the oldest first",
+        EvictionPolicy::RejectNew => "rejecting new objects",
+    };
+    format!("{}, {}, {eviction}", limit(quota.max_objects, "objects"), limit(quota.max_bytes, "bytes"))
+}
+
+fn audit_json(entry: &AuditEntry) -> Value {
+    json!({
+        "recorded_at": rfc3339(entry.recorded_at),
+        "action": entry.action.to_string(),
+        "object_id": entry.object_id.map(|id| id.to_string()),
+        "type_id": entry.type_id.map(|id| id.to_string()),
+        "actor": entry.actor,
+        "detail": entry.detail,
+    })
+}
+
+/// A CSV field, quoted if it has to be.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+const CSV_HEADER: [&str; 10] = ["section", "recorded_at", "action", "type_id", "origin", "object_id", "actor", "objects", "bytes", "detail"];
+
+impl ComplianceReport {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "generated_at": rfc3339(self.generated_at),
+            "from": rfc3339(self.from),
+            "to": rfc3339(self.to),
+            "content": self.content.iter().map(|usage| json!({
+                "type_id": usage.type_id.to_string(),
+                "origin": usage.origin,
+                "objects": usage.objects,
+                "bytes": usage.bytes,
+            })).collect::<Vec<_>>(),
+            "retention": self.retention.iter().map(|summary| json!({
+                "type_id": summary.type_id.to_string(),
+                "quota": summary.quota.as_ref().map(describe_quota),
+                "evicted": summary.evicted,
+            })).collect::<Vec<_>>(),
+            "takedowns": self.takedowns.iter().map(audit_json).collect::<Vec<_>>(),
+            "deletions": self.deletions.iter().map(audit_json).collect::<Vec<_>>(),
+        })
+    }
+
+    /// The rows of the report as a CSV table, without its header.
+    fn csv_rows(&self) -> Vec<[String; 10]> {
+        let mut rows = Vec::new();
+        let period = format!("{} to {}", rfc3339(self.from), rfc3339(self.to));
+        rows.push(["period".to_string(), rfc3339(self.generated_at), String::new(), String::new(), String::new(),
+            String::new(), String::new(), String::new(), String::new(), period]);
+        for usage in &self.content {
+            rows.push(["content".to_string(), String::new(), String::new(), usage.type_id.to_string(), usage.origin.clone(),
+                String::new(), String::new(), usage.objects.to_string(), usage.bytes.to_string(), String::new()]);
+        }
+        for summary in &self.retention {
+            let quota = summary.quota.as_ref().map_or_else(|| "quota unknown".to_string(), describe_quota);
+            rows.push(["retention".to_string(), String::new(), AuditAction::Evict.to_string(), summary.type_id.to_string(),
+                String::new(), String::new(), String::new(), summary.evicted.to_string(), String::new(), quota]);
+        }
+        let entries = self.takedowns.iter().map(|entry| ("takedown", entry))
+            .chain(self.deletions.iter().map(|entry| ("deletion", entry)));
+        for (section, entry) in entries {
+            rows.push([section.to_string(), rfc3339(entry.recorded_at), entry.action.to_string(),
+                entry.type_id.map(|id| id.to_string()).unwrap_or_default(), String::new(),
+                entry.object_id.map(|id| id.to_string()).unwrap_or_default(), entry.actor.clone(), String::new(), String::new(),
+                entry.detail.clone()]);
+        }
+        rows
+    }
+
+    /// Write the report to `writer` in `format`.
+    pub fn write<W: Write>(&self, mut writer: W, format: ReportFormat) -> io::Result<()> {
+        match format {
+            ReportFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, &self.to_json())?;
+                writeln!(writer)?;
+            }
+            ReportFormat::Csv => {
+                writeln!(writer, "{}", CSV_HEADER.join(","))?;
+                for row in self.csv_rows() {
+                    writeln!(writer, "{}", row.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(","))?;
+                }
+            }
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use uuid::Uuid;
+
+    use osp_protocol::Envelope;
+
+    use crate::compliance::{self, ReportFormat};
+    use crate::embargo;
+    use crate::store::{AuditAction, AuditEntry, DataStore, MemoryStore};
+    use crate::store::quota::{EvictionPolicy, StorageQuota, StorageQuotas};
+
+    #[test]
+    fn test_report() -> io::Result<()> {
+        let (store, type_id) = (MemoryStore::new(), Uuid::new_v4());
+        let mut quotas = StorageQuotas::default();
+        quotas.set(type_id, StorageQuota { max_objects: Some(1), max_bytes: None, eviction: EvictionPolicy::OldestFirst });
+        let now = embargo::now();
+        for origin in ["a.test", "a.test", "b.test"] {
+            quotas.store(&store, &Envelope::new(type_id, origin.to_string(), vec![0]))?;
+        }
+        store.append_audit(&AuditEntry {
+            recorded_at: now,
+            action: AuditAction::Takedown,
+            object_id: Some(Uuid::new_v4()),
+            actor: "operator".to_string(),
+            detail: "Court order, \"case 1\"".to_string(),
+            type_id: None,
+        })?;
+        store.append_audit(&AuditEntry {
+            recorded_at: now - 3600,
+            action: AuditAction::Purge,
+            object_id: None,
+            actor: "operator".to_string(),
+            detail: String::new(),
+            type_id: None,
+        })?;
+
+        let report = compliance::generate(&store, &quotas, now - 60, now + 60)?;
+        assert_eq!(report.content.iter().map(|usage| (usage.origin.as_str(), usage.objects)).collect::<Vec<_>>(), [("b.test", 1)]);
+        assert_eq!((report.retention.len(), report.retention[0].evicted), (1, 2));
+        assert_eq!((report.takedowns.len(), report.deletions.len()), (1, 0));
+
+        let mut json = Vec::new();
+        report.write(&mut json, ReportFormat::Json)?;
+        let json: serde_json::Value = serde_json::from_slice(&json)?;
+        assert_eq!(json["retention"][0]["quota"], "at most 1 objects, unlimited bytes, evicting the oldest first");
+        let mut csv = Vec::new();
+        report.write(&mut csv, "CSV".parse()?)?;
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 5);
+        assert!(csv.lines().any(|line| line.starts_with("content,,,") && line.contains(",b.test,,,1,")));
+        assert!(csv.contains(",\"Court order, \"\"case 1\"\"\""));
+        Ok(())
+    }
+}
@@ -0,0 +1,725 @@
+//! # Config Files
+//!
+//! With the `config` feature, a node can be set up from a TOML file instead
+//! of code, so deployments can be managed declaratively:
+//!
+//! ```toml
+//! bind = "0.0.0.0:42069"
+//! hostname = "node.example"
+//! private_key = "~/.config/osp/key.pem"
+//! push_to = ["osp://mirror.example:42069", "wss://relay.example"]
+//! allowlist_only = false
+//! # with the websocket feature
+//! websocket_bind = "0.0.0.0:8080"
+//!
+//! [access]
+//! deny_hosts = ["*.spam.example"]
+//! allow_networks = ["10.0.0.0/8"]
+//!
+//! [limits]
+//! max_connections_per_ip = 8
+//! rate_limit = { burst = 10, per_second = 0.5 }
+//!
+//! [resolver]
+//! nameservers = ["127.0.0.1:53"]
+//! timeout_secs = 2
+//!
+//! [rollout.compression]
+//! percent = 10
+//! peers = ["canary.example"]
+//!
+//! [keepalive]
+//! interval_secs = 20
+//! idle_timeout_secs = 60
+//!
+//! [recording]
+//! directory = "/var/lib/osp/recordings"
+//! peers = ["peer.example"]
+//!
+//! [self_check]
+//! directories = ["/var/lib/osp"]
+//! min_free_mb = 500
+//!
+//! [socket.listener]
+//! nodelay = true
+//! recv_buffer = 4194304
+//!
+//! [socket.outbound]
+//! keepalive_secs = 60
+//! keepalive_interval_secs = 10
+//! user_timeout_ms = 30000
+//! ```
+//!
+//! [OSProtocolNodeBuilder::from_config_file] reads such a file into a
+//! builder, which takes every other setting, such as handlers and the data
+//! store, in code as usual.
+//!
+//! Environment variables starting with `OSP_` override the settings of the
+//! file: the rest of the name, lowercased, is the setting, with `__`
+//! separating a table from its keys. `OSP_HOSTNAME=other.example` overrides
+//! `hostname`, `OSP_RESOLVER__TIMEOUT_SECS=10` the `timeout_secs` of
+//! `[resolver]`. Values are read as TOML, e.g.
+//! `OSP_PUSH_TO='["osp://a.example:42069"]'`, and as a string if they aren't
+//! valid TOML. Variables that name no setting, like those of other programs
+//! sharing the prefix, are ignored.
+//!
+//! [OSProtocolNode::watch_config_file] reloads the `push_to`,
+//! `allowlist_only`, `[access]` and `[limits]` settings of a running node
+//! whenever the file changes or the process receives `SIGHUP`, see
+//! [reload](crate::reload). The other settings take a restart.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error, info};
+
+use serde::{de, Deserialize, Deserializer};
+
+use tokio::io;
+
+use url::Url;
+
+use osp_protocol::{Keepalive, OSPUrl};
+
+use crate::builder::{BuildError, ConfigProblem, OSProtocolNodeBuilder, Provided};
+#[cfg(feature = "dns-auth")]
+use crate::connection::dns::ChallengeResolver;
+use crate::connection::registry::{ConnectionLimits, RateLimit};
+use crate::connection::socket::{SocketOptions, TcpKeepalive};
+use crate::crypto::Ed25519Key;
+use crate::platform::{expand_path, Hangups};
+use crate::policy::access::AccessPolicy;
+use crate::reload::LiveSettings;
+use crate::reporting::{Fault, FaultKind};
+use crate::rollout::{Feature, Rollout};
+use crate::selfcheck::SelfChecks;
+use crate::OSProtocolNode;
+
+/// Prefix of the environment variables overriding settings.
+const ENV_PREFIX: &str = "OSP_";
+
+/// How often a watched config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The settings of a node read from a config file, see the [module](self)
+/// docs.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeConfig {
+    pub bind: SocketAddr,
+    pub hostname: String,
+    /// PEM file holding the node's RSA key.
+    pub private_key: PathBuf,
+    /// PEM file holding the node's Ed25519 key, if it has one.
+    #[serde(default)]
+    pub ed25519_key: Option<PathBuf>,
+    /// OSP URLs of the peers every object the node publishes is pushed to,
+    /// see [push_target](OSProtocolNodeBuilder::push_target). `osp://` URLs
+    /// without a port are connected to at the `_osp._tcp` SRV record of
+    /// their host, or at [DEFAULT_PORT](osp_protocol::DEFAULT_PORT).
+    #[serde(default)]
+    pub push_to: Vec<String>,
+    /// Only federate with hosts allowed by a federation rule, see
+    /// [allowlist_only](OSProtocolNodeBuilder::allowlist_only).
+    #[serde(default)]
+    pub allowlist_only: bool,
+    /// Accept connections over WebSockets on this address as well, see
+    /// [websocket_endpoint](OSProtocolNodeBuilder::websocket_endpoint).
+    #[cfg(feature = "websocket")]
+    #[serde(default)]
+    pub websocket_bind: Option<SocketAddr>,
+    #[serde(default)]
+    pub access: AccessConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[cfg(feature = "dns-auth")]
+    #[serde(default)]
+    pub resolver: Option<ResolverConfig>,
+    /// The features being rolled out, see [rollout](crate::rollout).
+    #[serde(default)]
+    pub rollout: HashMap<Feature, RolloutConfig>,
+    #[serde(default)]
+    pub keepalive: Option<KeepaliveConfig>,
+    #[serde(default)]
+    pub recording: Option<RecordingConfig>,
+    /// Check the environment before listening, see
+    /// [selfcheck](crate::selfcheck).
+    #[serde(default)]
+    pub self_check: Option<SelfCheckConfig>,
+    #[serde(default)]
+    pub socket: SocketsConfig,
+}
+
+/// The `[access]` table, see [AccessPolicy].
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessConfig {
+    #[serde(default)]
+    pub allow_hosts: Vec<String>,
+    #[serde(default)]
+    pub deny_hosts: Vec<String>,
+    /// Networks in CIDR notation.
+    #[serde(default)]
+    pub allow_networks: Vec<String>,
+    #[serde(default)]
+    pub deny_networks: Vec<String>,
+}
+
+/// The `[limits]` table, see [ConnectionLimits].
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+/// See [RateLimit].
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+/// A `[rollout.<feature>]` table, see [Rollout].
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RolloutConfig {
+    pub percent: u8,
+    #[serde(default)]
+    pub peers: Vec<String>,
+}
+
+/// The `[keepalive]` table, see [Keepalive]. Settings left out keep their
+/// defaults, 0 turns them off.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeepaliveConfig {
+    pub interval_secs: Option<u64>,
+    pub idle_timeout_secs: Option<u64>,
+}
+
+/// The `[recording]` table, see [recording](crate::recording).
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordingConfig {
+    pub directory: PathBuf,
+    /// Hostnames of peers, or addresses of guests, to record connections
+    /// with.
+    #[serde(default)]
+    pub peers: Vec<String>,
+}
+
+/// The `[self_check]` table, see [SelfChecks]. Settings left out keep
+/// their defaults.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SelfCheckConfig {
+    pub refuse_on_failure: Option<bool>,
+    /// Directories the data store keeps its data in.
+    #[serde(default)]
+    pub directories: Vec<PathBuf>,
+    pub min_free_mb: Option<u64>,
+    pub min_open_files: Option<u64>,
+    pub dns_probe: Option<String>,
+}
+
+/// The `[socket]` table, with the options of the sockets the node listens
+/// on and of those it connects to peers with, see
+/// [socket](crate::connection::socket).
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SocketsConfig {
+    #[serde(default)]
+    pub listener: SocketConfig,
+    #[serde(default)]
+    pub outbound: SocketConfig,
+}
+
+/// The `[socket.listener]` and `[socket.outbound]` tables, see
+/// [SocketOptions]. Settings left out keep the defaults of the system.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SocketConfig {
+    pub nodelay: Option<bool>,
+    /// Idle seconds before keepalive probes are sent, which turns them on.
+    pub keepalive_secs: Option<u64>,
+    /// Only with `keepalive_secs`.
+    pub keepalive_interval_secs: Option<u64>,
+    /// Only with `keepalive_secs`.
+    pub keepalive_retries: Option<u32>,
+    /// Bytes.
+    pub send_buffer: Option<usize>,
+    /// Bytes.
+    pub recv_buffer: Option<usize>,
+    pub user_timeout_ms: Option<u64>,
+}
+
+/// The `[resolver]` table, see [ChallengeResolver]. Settings left out keep
+/// the defaults of the resolver.
+#[cfg(feature = "dns-auth")]
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResolverConfig {
+    /// Nameservers to look up challenge records with, instead of public
+    /// resolvers.
+    #[serde(default)]
+    pub nameservers: Vec<SocketAddr>,
+    /// Use the resolver configured for the system, if no nameservers are
+    /// given.
+    #[serde(default)]
+    pub system: bool,
+    pub timeout_secs: Option<u64>,
+    pub attempts: Option<u32>,
+    pub backoff_ms: Option<u64>,
+    pub negative_ttl_secs: Option<u64>,
+}
+
+impl NodeConfig {
+    /// Read the config file at `path`, with the overrides of the
+    /// environment of the process. A leading `~` in `path` is resolved to
+    /// the home directory.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<NodeConfig> {
+        let path = expand_path(path);
+        let toml = std::fs::read_to_string(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Unable to read config file {}: {e}", path.display())))?;
+        Self::from_toml(&toml, std::env::vars())
+    }
+
+    /// Parse the config in `toml`, overridden by the `OSP_` variables among
+    /// `env`.
+    pub fn from_toml(toml: &str, env: impl IntoIterator<Item = (String, String)>) -> io::Result<NodeConfig> {
+        let mut table: toml::Table = toml.parse().map_err(invalid)?;
+        let settings = settings();
+        for (name, value) in env {
+            let Some(setting) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let setting = setting.to_ascii_lowercase();
+            let key = setting.split("__").next().unwrap_or_default();
+            if !settings.contains(&key) {
+                debug!("Ignoring {name}, which names no setting");
+                continue;
+            }
+            override_setting(&mut table, &setting, &value)
+                .map_err(|reason| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid override {name}: {reason}")))?;
+        }
+        NodeConfig::deserialize(table).map_err(invalid)
+    }
+
+    /// A builder with the settings of the config. Fails with a
+    /// [BuildError] for settings that would be ignored.
+    pub fn builder(&self) -> io::Result<OSProtocolNodeBuilder<Provided, Provided, Provided>> {
+        let problems = [("listener", &self.socket.listener), ("outbound", &self.socket.outbound)].into_iter()
+            .filter_map(|(sockets, socket)| socket.problem(sockets))
+            .collect::<Vec<_>>();
+        if !problems.is_empty() {
+            return Err(BuildError { problems }.into());
+        }
+        let mut builder = OSProtocolNode::builder()
+            .bind_to(self.bind)
+            .hostname(self.hostname.clone())
+            .private_key_file(&self.private_key);
+        if let Some(path) = &self.ed25519_key {
+            builder = builder.ed25519_key(Ed25519Key::from_pem(&std::fs::read(expand_path(path))?)?);
+        }
+        #[cfg(feature = "websocket")]
+        if let Some(addr) = self.websocket_bind {
+            builder = builder.websocket_endpoint(addr);
+        }
+        let settings = self.live_settings()?;
+        for url in settings.push_targets {
+            builder = builder.push_target(url);
+        }
+        builder = builder
+            .access_policy(settings.access)
+            .allowlist_only(settings.allowlist_only)
+            .connection_limits(ConnectionLimits {
+                max_connections: settings.max_connections,
+                max_connections_per_ip: settings.max_connections_per_ip,
+                rate_limit: settings.rate_limit,
+                ..ConnectionLimits::default()
+            });
+        #[cfg(feature = "dns-auth")]
+        if let Some(resolver) = &self.resolver {
+            builder = builder.challenge_resolver(resolver.resolver()?);
+        }
+        for (feature, rollout) in &self.rollout {
+            builder = builder.rollout(*feature, rollout.rollout());
+        }
+        if let Some(keepalive) = &self.keepalive {
+            builder = builder.keepalive(keepalive.keepalive());
+        }
+        if let Some(recording) = &self.recording {
+            builder = builder.recordings(expand_path(&recording.directory));
+            for peer in &recording.peers {
+                builder = builder.record_peer(peer);
+            }
+        }
+        if let Some(self_check) = &self.self_check {
+            builder = builder.self_checks(self_check.self_checks());
+        }
+        builder = builder
+            .listener_socket_options(self.socket.listener.socket_options())
+            .outbound_socket_options(self.socket.outbound.socket_options());
+        Ok(builder)
+    }
+
+    /// The settings of the config a running node can be
+    /// [reloaded](OSProtocolNode::reload) with.
+    pub fn live_settings(&self) -> io::Result<LiveSettings> {
+        Ok(LiveSettings {
+            access: self.access.policy()?,
+            allowlist_only: self.allowlist_only,
+            push_targets: self.push_to.iter().map(|url| parse_url(url)).collect::<io::Result<_>>()?,
+            max_connections: self.limits.max_connections,
+            max_connections_per_ip: self.limits.max_connections_per_ip,
+            rate_limit: self.limits.rate_limit.map(|limit| RateLimit { burst: limit.burst, per_second: limit.per_second }),
+        })
+    }
+}
+
+impl AccessConfig {
+    pub fn policy(&self) -> io::Result<AccessPolicy> {
+        let mut policy = AccessPolicy::new();
+        for pattern in &self.allow_hosts {
+            policy = policy.allow_host(pattern);
+        }
+        for pattern in &self.deny_hosts {
+            policy = policy.deny_host(pattern);
+        }
+        for network in &self.allow_networks {
+            policy = policy.allow_network(network.parse()?);
+        }
+        for network in &self.deny_networks {
+            policy = policy.deny_network(network.parse()?);
+        }
+        Ok(policy)
+    }
+}
+
+impl RolloutConfig {
+    pub fn rollout(&self) -> Rollout {
+        self.peers.iter().fold(Rollout::percent(self.percent), |rollout, peer| rollout.peer(peer))
+    }
+}
+
+impl KeepaliveConfig {
+    pub fn keepalive(&self) -> Keepalive {
+        let setting = |secs: Option<u64>, default: Option<Duration>| match secs {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => default,
+        };
+        let default = Keepalive::default();
+        Keepalive {
+            interval: setting(self.interval_secs, default.interval),
+            idle_timeout: setting(self.idle_timeout_secs, default.idle_timeout),
+        }
+    }
+}
+
+impl SelfCheckConfig {
+    pub fn self_checks(&self) -> SelfChecks {
+        let default = SelfChecks::default();
+        SelfChecks {
+            refuse_on_failure: self.refuse_on_failure.unwrap_or(default.refuse_on_failure),
+            directories: self.directories.iter().map(expand_path).collect(),
+            min_free_space: self.min_free_mb.map_or(default.min_free_space, |mb| mb << 20),
+            min_open_files: self.min_open_files.unwrap_or(default.min_open_files),
+            dns_probe: self.dns_probe.clone(),
+        }
+    }
+}
+
+impl SocketConfig {
+    /// What is wrong with the options of the `sockets`, beyond what
+    /// [SocketOptions] can tell.
+    fn problem(&self, sockets: &'static str) -> Option<ConfigProblem> {
+        let tuned = self.keepalive_interval_secs.is_some() || self.keepalive_retries.is_some();
+        (tuned && self.keepalive_secs.is_none()).then_some(ConfigProblem::KeepaliveWithoutTime { sockets })
+    }
+
+    pub fn socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            nodelay: self.nodelay,
+            keepalive: self.keepalive_secs.map(|secs| TcpKeepalive {
+                time: Duration::from_secs(secs),
+                interval: self.keepalive_interval_secs.map(Duration::from_secs),
+                retries: self.keepalive_retries,
+            }),
+            send_buffer: self.send_buffer,
+            recv_buffer: self.recv_buffer,
+            user_timeout: self.user_timeout_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+#[cfg(feature = "dns-auth")]
+impl ResolverConfig {
+    pub fn resolver(&self) -> io::Result<ChallengeResolver> {
+        let mut resolver = match (self.nameservers.is_empty(), self.system) {
+            (false, _) => ChallengeResolver::nameservers(&self.nameservers),
+            (true, true) => ChallengeResolver::system()?,
+            (true, false) => ChallengeResolver::default(),
+        };
+        if let Some(timeout) = self.timeout_secs {
+            resolver = resolver.timeout(Duration::from_secs(timeout));
+        }
+        if self.attempts.is_some() || self.backoff_ms.is_some() {
+            resolver = resolver.retries(self.attempts.unwrap_or(3), Duration::from_millis(self.backoff_ms.unwrap_or(250)));
+        }
+        if let Some(ttl) = self.negative_ttl_secs {
+            resolver = resolver.negative_ttl(Duration::from_secs(ttl));
+        }
+        Ok(resolver)
+    }
+}
+
+impl OSProtocolNodeBuilder {
+    /// A builder with the settings of the config file at `path`, overridden
+    /// by the environment, see [config](crate::config).
+    pub fn from_config_file(path: impl AsRef<Path>) -> io::Result<OSProtocolNodeBuilder<Provided, Provided, Provided>> {
+        NodeConfig::from_file(path)?.builder()
+    }
+}
+
+impl OSProtocolNode {
+    /// Reload the live settings of the node from the config file at `path`,
+    /// overridden by the environment, see [reload](crate::reload).
+    pub fn reload_config_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.reload(NodeConfig::from_file(path)?.live_settings()?)
+    }
+
+    /// [Reload](Self::reload_config_file) the config file at `path` whenever
+    /// it changes or the process receives `SIGHUP`, until the node stops.
+    /// A config that fails to load is reported, and the node keeps its
+    /// settings until the file is fixed.
+    pub async fn watch_config_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = expand_path(path);
+        let mut hangups = Hangups::new()?;
+        let mut modified = modified_at(&path);
+        loop {
+            tokio::select! {
+                _ = hangups.recv() => info!("Reloading {} on SIGHUP", path.display()),
+                _ = tokio::time::sleep(WATCH_INTERVAL) => {
+                    if modified_at(&path) == modified {
+                        continue;
+                    }
+                    info!("Reloading {} as it changed", path.display());
+                }
+            }
+            modified = modified_at(&path);
+            if let Err(e) = self.reload_config_file(&path) {
+                let message = format!("Unable to reload {}: {e}", path.display());
+                error!("{message}");
+                self.report_fault(Fault::new(FaultKind::Internal, message));
+            }
+        }
+    }
+}
+
+/// When the file at `path` was last modified, if it can be told.
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Set the setting at `path`, e.g. `resolver__timeout_secs`, to `value`.
+/// The names of the top-level settings, the fields of [NodeConfig] as
+/// serde sees them.
+fn settings() -> &'static [&'static str] {
+    /// Only asks the fields of the struct it deserializes.
+    struct Fields(&'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for &mut Fields {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: de::Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: de::Visitor<'de>>(self, _: &'static str, fields: &'static [&'static str], _: V) -> Result<V::Value, Self::Error> {
+            self.0 = fields;
+            Err(de::Error::custom("only the fields are needed"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields = Fields(&[]);
+    let _ = NodeConfig::deserialize(&mut fields);
+    fields.0
+}
+
+fn override_setting(table: &mut toml::Table, path: &str, value: &str) -> Result<(), String> {
+    let (key, rest) = match path.split_once("__") {
+        Some((key, rest)) => (key, Some(rest)),
+        None => (path, None),
+    };
+    if key.is_empty() {
+        return Err("empty setting name".to_string());
+    }
+    match rest {
+        Some(rest) => {
+            let entry = table.entry(key).or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            let Some(table) = entry.as_table_mut() else {
+                return Err(format!("{key} is not a table"));
+            };
+            override_setting(table, rest, value)
+        }
+        None => {
+            // `v = <value>` only parses if the value is valid TOML
+            let value = format!("v = {value}").parse::<toml::Table>().ok()
+                .and_then(|mut parsed| parsed.remove("v"))
+                .unwrap_or_else(|| toml::Value::String(value.to_string()));
+            table.insert(key.to_string(), value);
+            Ok(())
+        }
+    }
+}
+
+fn parse_url(url: &str) -> io::Result<OSPUrl> {
+    let invalid_url = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid push target {url:?}: {reason}"));
+    let parsed = Url::parse(url).map_err(|e| invalid_url(&e.to_string()))?;
+    OSPUrl::try_from(parsed).map_err(|e| io::Error::new(e.kind(), format!("Invalid push target: {e}")))
+}
+
+fn invalid<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io;
+
+    use osp_protocol::Keepalive;
+
+    use crate::builder::{BuildError, ConfigProblem};
+    use crate::config::NodeConfig;
+    use crate::connection::socket::SocketOptions;
+    use crate::rollout::Feature;
+
+    const CONFIG: &str = r#"
+        bind = "127.0.0.1:57401"
+        hostname = "node.test"
+        private_key = "/etc/osp/key.pem"
+        push_to = ["osp://mirror.test:57400"]
+
+        [access]
+        deny_hosts = ["*.spam.test"]
+        allow_networks = ["10.0.0.0/8"]
+
+        [rollout.payload_formats]
+        percent = 5
+        peers = ["canary.test"]
+
+        [keepalive]
+        interval_secs = 20
+        idle_timeout_secs = 0
+
+        [recording]
+        directory = "~/recordings"
+        peers = ["peer.test"]
+
+        [self_check]
+        directories = ["~/store"]
+        min_free_mb = 500
+
+        [socket.outbound]
+        nodelay = true
+        keepalive_secs = 60
+        recv_buffer = 4194304
+    "#;
+
+    #[test]
+    fn test_config() -> io::Result<()> {
+        let config = NodeConfig::from_toml(CONFIG, [("HOME".to_string(), "/root".to_string())])?;
+        assert_eq!(config.hostname, "node.test");
+        assert_eq!(config.push_to, ["osp://mirror.test:57400"]);
+        let policy = config.access.policy()?;
+        assert!(policy.check_host("a.spam.test").is_err());
+        assert!(policy.check_ip("192.168.0.1".parse().unwrap()).is_err());
+        assert!(config.rollout[&Feature::PayloadFormats].rollout().includes(Feature::PayloadFormats, "canary.test"));
+        assert_eq!(config.keepalive.as_ref().unwrap().keepalive(), Keepalive { interval: Some(Duration::from_secs(20)), idle_timeout: None });
+        assert_eq!(config.recording.as_ref().unwrap().peers, ["peer.test"]);
+        let self_checks = config.self_check.as_ref().unwrap().self_checks();
+        assert_eq!((self_checks.min_free_space, self_checks.refuse_on_failure), (500 << 20, true));
+        let outbound = config.socket.outbound.socket_options();
+        assert_eq!((outbound.nodelay, outbound.recv_buffer), (Some(true), Some(4 << 20)));
+        assert_eq!(outbound.keepalive.map(|keepalive| keepalive.time), Some(Duration::from_secs(60)));
+        assert_eq!(config.socket.listener.socket_options(), SocketOptions::default());
+        config.builder()?;
+
+        let unknown = format!("{CONFIG}\nmystery = true");
+        assert_eq!(NodeConfig::from_toml(&unknown, []).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // probes tuned without being turned on would be ignored
+        let untimed = format!("{CONFIG}\n[socket.listener]\nkeepalive_retries = 3");
+        let err = NodeConfig::from_toml(&untimed, [])?.builder().err().unwrap();
+        let problems = err.get_ref().and_then(|inner| inner.downcast_ref::<BuildError>()).map(|err| err.problems.clone());
+        assert_eq!(problems, Some(vec![ConfigProblem::KeepaliveWithoutTime { sockets: "listener" }]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_overrides() -> io::Result<()> {
+        let env = [
+            ("OSP_HOSTNAME", "other.test"),
+            ("OSP_PUSH_TO", r#"["osp://a.test:57400", "osp://b.test:57400"]"#),
+            ("OSP_ACCESS__DENY_HOSTS", r#"["bad.test"]"#),
+            // not a setting
+            ("OSP_LOG_FORMAT", "json"),
+        ];
+        let config = NodeConfig::from_toml(CONFIG, env.map(|(name, value)| (name.to_string(), value.to_string())))?;
+        assert_eq!(config.hostname, "other.test");
+        assert_eq!(config.push_to.len(), 2);
+        assert_eq!(config.access.deny_hosts, ["bad.test"]);
+        assert_eq!(config.access.allow_networks, ["10.0.0.0/8"]);
+
+        let bad_url = NodeConfig::from_toml(CONFIG, [("OSP_PUSH_TO".to_string(), r#"["https://a.test"]"#.to_string())])?;
+        assert!(bad_url.builder().is_err());
+        assert!(NodeConfig::from_toml(CONFIG, [("OSP_HOSTNAME__X".to_string(), "1".to_string())]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reload() -> io::Result<()> {
+        use crate::OSProtocolNode;
+        use crate::crypto::PrivateKey;
+        use crate::events::NodeEvent;
+
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let mut events = node.events();
+        let path = std::env::temp_dir().join(format!("osp-config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, format!("allowlist_only = true\n{CONFIG}\n[limits]\nmax_connections_per_ip = 4\nrate_limit = {{ burst = 2, per_second = 1.0 }}"))?;
+        assert!(node.check_federation("peer.test").is_ok());
+
+        node.reload_config_file(&path)?;
+        let settings = node.live_settings();
+        assert_eq!(settings.push_targets.len(), 1);
+        assert!(settings.access.check_host("a.spam.test").is_err());
+        assert!(settings.allowlist_only);
+        assert_eq!(node.check_federation("peer.test").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!((settings.max_connections, settings.max_connections_per_ip), (None, Some(4)));
+        assert_eq!(events.try_recv().ok(), Some(NodeEvent::ConfigReloaded));
+
+        // settings refusing every connection are rejected, keeping the others
+        std::fs::write(&path, format!("{CONFIG}\n[limits]\nmax_connections = 0"))?;
+        assert_eq!(node.reload_config_file(&path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(node.live_settings(), settings);
+        std::fs::remove_file(&path)
+    }
+}
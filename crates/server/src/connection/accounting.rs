@@ -0,0 +1,277 @@
+//! # Cost Accounting
+//!
+//! A node keeps track of what serving each peer costs it: the bytes of the
+//! answers it sends, the work of the queries the peer makes and the bytes of
+//! the object payloads delivered to it. Costs are added up over windows of
+//! [AccountingPolicy::window], and the last few windows are kept so
+//! operators can see who the node has been busy with, see
+//! [CostAccounting::stats].
+//!
+//! Public nodes, like archives, can also enforce a [FairShare]: the
+//! capacity of each window is split evenly between the peers active in it,
+//! and requests of peers that used up their share are shed until the next
+//! window, like requests of a node that is overloaded. A peer that is the
+//! only one active may use the whole capacity, so nobody is held back while
+//! there is no one to be fair to.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+
+/// What serving a peer cost the node.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Cost {
+    /// Bytes of answers sent to the peer.
+    pub bytes_served: u64,
+    /// Work of the peer's queries, a unit for every item looked up.
+    pub query_units: u64,
+    /// Bytes of object payloads delivered to the peer.
+    pub blob_bytes: u64,
+}
+
+impl Cost {
+    /// The cost of answering a query for `query_units` items with
+    /// `bytes_served` bytes.
+    pub(crate) fn query(query_units: u64, bytes_served: u64) -> Self {
+        Cost {
+            bytes_served,
+            query_units,
+            blob_bytes: 0,
+        }
+    }
+
+    /// The cost in units, weighing each kind by `weights`.
+    pub fn units(&self, weights: &CostWeights) -> f64 {
+        self.bytes_served as f64 / 1024.0 * weights.per_kib_served
+            + self.query_units as f64 * weights.per_query_unit
+            + self.blob_bytes as f64 / 1024.0 * weights.per_kib_blob
+    }
+
+    fn add(&mut self, other: &Cost) {
+        self.bytes_served += other.bytes_served;
+        self.query_units += other.query_units;
+        self.blob_bytes += other.blob_bytes;
+    }
+}
+
+/// How much each kind of cost weighs, in units.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CostWeights {
+    pub per_kib_served: f64,
+    pub per_query_unit: f64,
+    pub per_kib_blob: f64,
+}
+
+impl Default for CostWeights {
+    fn default() -> Self {
+        CostWeights {
+            per_kib_served: 1.0,
+            per_query_unit: 1.0,
+            per_kib_blob: 1.0,
+        }
+    }
+}
+
+/// Units a node is willing to spend per window, split evenly between the
+/// peers active in it, see the [module](self) docs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FairShare {
+    pub capacity: f64,
+}
+
+/// How costs are accounted for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccountingPolicy {
+    /// How long each window lasts.
+    pub window: Duration,
+    /// Finished windows kept for each peer.
+    pub history: usize,
+    pub weights: CostWeights,
+    /// The share of each peer that is enforced, if any.
+    pub fair_share: Option<FairShare>,
+}
+
+impl Default for AccountingPolicy {
+    fn default() -> Self {
+        AccountingPolicy {
+            window: Duration::from_secs(60),
+            history: 60,
+            weights: CostWeights::default(),
+            fair_share: None,
+        }
+    }
+}
+
+impl AccountingPolicy {
+    /// Whether windows have a length and the weights and capacity are
+    /// numbers that can't go negative.
+    pub(crate) fn is_valid(&self) -> bool {
+        let weights = [self.weights.per_kib_served, self.weights.per_query_unit, self.weights.per_kib_blob];
+        !self.window.is_zero()
+            && weights.iter().all(|weight| weight.is_finite() && *weight >= 0.0)
+            && self.fair_share.is_none_or(|share| share.capacity.is_finite() && share.capacity > 0.0)
+    }
+}
+
+/// What serving a peer cost recently.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerCost {
+    /// Hostname of the peer.
+    pub peer: String,
+    /// Cost in the current window.
+    pub current: Cost,
+    /// Cost in the finished windows kept, the most recent first.
+    pub previous: Vec<Cost>,
+    /// Units the current cost is worth.
+    pub units: f64,
+}
+
+struct Ledger {
+    current: Cost,
+    previous: VecDeque<Cost>,
+}
+
+struct Windows {
+    started: Instant,
+    ledgers: HashMap<String, Ledger>,
+}
+
+/// Accounts for what serving each peer costs, according to an
+/// [AccountingPolicy].
+pub struct CostAccounting {
+    policy: AccountingPolicy,
+    windows: Mutex<Windows>,
+}
+
+impl CostAccounting {
+    pub(crate) fn new(policy: AccountingPolicy) -> Self {
+        CostAccounting {
+            policy,
+            windows: Mutex::new(Windows {
+                started: Instant::now(),
+                ledgers: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn policy(&self) -> &AccountingPolicy {
+        &self.policy
+    }
+
+    /// What serving each peer cost recently, the most expensive peer in the
+    /// current window first.
+    pub fn stats(&self) -> Vec<PeerCost> {
+        self.stats_at(Instant::now())
+    }
+
+    /// Add `cost` to what serving `peer` cost.
+    pub(crate) fn charge(&self, peer: &str, cost: Cost) {
+        self.charge_at(peer, cost, Instant::now());
+    }
+
+    /// Whether `peer` may still be served in the current window under the
+    /// [FairShare], if one is enforced.
+    pub(crate) fn allows(&self, peer: &str) -> bool {
+        self.allows_at(peer, Instant::now())
+    }
+
+    fn stats_at(&self, now: Instant) -> Vec<PeerCost> {
+        let mut windows = self.windows.lock().unwrap();
+        self.advance(&mut windows, now);
+        let mut stats = windows.ledgers.iter()
+            .map(|(peer, ledger)| PeerCost {
+                peer: peer.clone(),
+                current: ledger.current,
+                previous: ledger.previous.iter().copied().collect(),
+                units: ledger.current.units(&self.policy.weights),
+            })
+            .collect::<Vec<_>>();
+        stats.sort_by(|a, b| b.units.total_cmp(&a.units).then_with(|| a.peer.cmp(&b.peer)));
+        stats
+    }
+
+    fn charge_at(&self, peer: &str, cost: Cost, now: Instant) {
+        metrics::cost_charged(&cost);
+        let mut windows = self.windows.lock().unwrap();
+        self.advance(&mut windows, now);
+        windows.ledgers.entry(peer.to_string())
+            .or_insert_with(|| Ledger { current: Cost::default(), previous: VecDeque::new() })
+            .current.add(&cost);
+    }
+
+    fn allows_at(&self, peer: &str, now: Instant) -> bool {
+        let Some(share) = self.policy.fair_share else {
+            return true;
+        };
+        let mut windows = self.windows.lock().unwrap();
+        self.advance(&mut windows, now);
+        let others = windows.ledgers.iter()
+            .filter(|(other, ledger)| other.as_str() != peer && ledger.current != Cost::default())
+            .count();
+        let used = windows.ledgers.get(peer)
+            .map_or(0.0, |ledger| ledger.current.units(&self.policy.weights));
+        used < share.capacity / (others + 1) as f64
+    }
+
+    /// Finish the windows that passed by `now`, dropping peers with no cost
+    /// left in any window kept.
+    fn advance(&self, windows: &mut Windows, now: Instant) {
+        let passed = (now.saturating_duration_since(windows.started).as_nanos() / self.policy.window.as_nanos()) as u64;
+        if passed == 0 {
+            return;
+        }
+        windows.started += self.policy.window * passed.min(u32::MAX as u64) as u32;
+        for ledger in windows.ledgers.values_mut() {
+            // windows without cost in between are kept as such
+            for _ in 0..passed.min(self.policy.history as u64 + 1) {
+                let finished = std::mem::take(&mut ledger.current);
+                ledger.previous.push_front(finished);
+            }
+            ledger.previous.truncate(self.policy.history);
+        }
+        windows.ledgers.retain(|_, ledger| ledger.previous.iter().any(|cost| *cost != Cost::default()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::connection::accounting::{AccountingPolicy, Cost, CostAccounting, FairShare};
+
+    #[test]
+    fn test_fair_share() {
+        let accounting = CostAccounting::new(AccountingPolicy {
+            window: Duration::from_secs(10),
+            history: 2,
+            fair_share: Some(FairShare { capacity: 100.0 }),
+            ..AccountingPolicy::default()
+        });
+        let start = accounting.windows.lock().unwrap().started;
+
+        // alone, a peer may use the whole capacity
+        accounting.charge_at("greedy.test", Cost::query(60, 0), start);
+        assert!(accounting.allows_at("greedy.test", start));
+        // but only half of it once someone else shows up
+        accounting.charge_at("other.test", Cost::query(1, 1024), start);
+        assert!(!accounting.allows_at("greedy.test", start));
+        assert!(accounting.allows_at("other.test", start));
+        assert!(accounting.allows_at("new.test", start));
+
+        let stats = accounting.stats_at(start);
+        assert_eq!(stats[0].peer, "greedy.test");
+        assert_eq!(stats[1].units, 2.0);
+
+        // the next window starts over, and keeps the last one
+        let next = start + Duration::from_secs(10);
+        assert!(accounting.allows_at("greedy.test", next));
+        let stats = accounting.stats_at(next);
+        assert_eq!(stats[0].current, Cost::default());
+        assert_eq!(stats[0].previous[0].query_units, 60);
+
+        // peers are forgotten once their windows are gone
+        assert!(accounting.stats_at(start + Duration::from_secs(40)).is_empty());
+    }
+}
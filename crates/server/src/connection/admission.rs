@@ -0,0 +1,177 @@
+//! # Request Admission
+//!
+//! Requests a peer makes of a node, unlike the objects it pushes, have the
+//! node work on the peer's behalf. [AdmissionLimits] bound how many
+//! requests of each [RequestKind] the node serves at once. Requests over the
+//! limit wait for their turn in a queue shared by every kind, and are shed
+//! if it doesn't come in time, or before the deadline the peer set for the
+//! request passes. Nothing else the peer sends is read while one of its
+//! requests waits, so the wait is kept short.
+//!
+//! As the queue fills up, the expensive kinds are shed first: each kind only
+//! queues while the queue is less full than its share, which is smaller the
+//! more expensive the kind is, so cheap requests are still served when
+//! expensive ones are refused. Shed requests are answered with an
+//! `Overloaded` packet, so the peer can try again later. Pushed objects
+//! never wait for admission, so live content keeps flowing while requests
+//! are shed.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// A kind of request peers make of a node.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RequestKind {
+    /// Describing a data type, answered from memory.
+    DescribeType,
+    /// Subscribing to data types, which is written to the store.
+    Subscribe,
+}
+
+impl RequestKind {
+    /// Every kind, the most expensive first.
+    const BY_COST: [RequestKind; 2] = [RequestKind::Subscribe, RequestKind::DescribeType];
+
+    /// Name of the kind in metrics.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            RequestKind::DescribeType => "describe_type",
+            RequestKind::Subscribe => "subscribe",
+        }
+    }
+
+    /// How many requests may be queued before requests of this kind are
+    /// shed, out of `max_queued`.
+    fn queue_share(&self, max_queued: usize) -> usize {
+        let rank = Self::BY_COST.iter().position(|kind| kind == self).unwrap() + 1;
+        max_queued * rank / Self::BY_COST.len()
+    }
+}
+
+/// Limits on the requests a node serves, see the [module](self) docs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdmissionLimits {
+    /// Requests of each kind served at once. Kinds that aren't listed are
+    /// served without a limit, a limit of zero sheds every request of the
+    /// kind.
+    pub concurrency: HashMap<RequestKind, usize>,
+    /// Requests waiting for their turn at most, of every kind together.
+    pub max_queued: usize,
+    /// How long a request waits for its turn before it is shed.
+    pub max_wait: Duration,
+}
+
+impl Default for AdmissionLimits {
+    fn default() -> Self {
+        AdmissionLimits {
+            concurrency: HashMap::from([
+                (RequestKind::DescribeType, 64),
+                (RequestKind::Subscribe, 16),
+            ]),
+            max_queued: 256,
+            max_wait: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Admits requests according to [AdmissionLimits].
+pub(crate) struct Admission {
+    limits: AdmissionLimits,
+    slots: HashMap<RequestKind, Arc<Semaphore>>,
+    queued: AtomicUsize,
+}
+
+/// A request's turn to be served, which lasts until it is dropped.
+pub(crate) struct AdmissionPermit {
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+/// Counts a request as queued until it is dropped, also when the request is
+/// cancelled while waiting.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Admission {
+    pub(crate) fn new(limits: AdmissionLimits) -> Self {
+        let slots = limits.concurrency.iter()
+            .map(|(kind, limit)| (*kind, Arc::new(Semaphore::new(*limit))))
+            .collect();
+        Admission {
+            limits,
+            slots,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait for the turn of a request of `kind`, for at most `deadline` if
+    /// the peer set one. Returns `None` if the request is shed.
+    pub(crate) async fn admit(&self, kind: RequestKind, deadline: Option<Duration>) -> Option<AdmissionPermit> {
+        let Some(slots) = self.slots.get(&kind) else {
+            return Some(AdmissionPermit { _slot: None });
+        };
+        if let Ok(slot) = slots.clone().try_acquire_owned() {
+            return Some(AdmissionPermit { _slot: Some(slot) });
+        }
+
+        let share = kind.queue_share(self.limits.max_queued);
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed);
+        let _queued = Queued(&self.queued);
+        if queued >= share || self.limits.concurrency[&kind] == 0 {
+            return None;
+        }
+        let max_wait = deadline.map_or(self.limits.max_wait, |deadline| deadline.min(self.limits.max_wait));
+        match tokio::time::timeout(max_wait, slots.clone().acquire_owned()).await {
+            Ok(Ok(slot)) => Some(AdmissionPermit { _slot: Some(slot) }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use crate::connection::admission::{Admission, AdmissionLimits, RequestKind};
+
+    #[test]
+    fn test_admission() -> std::io::Result<()> {
+        let admission = Admission::new(AdmissionLimits {
+            concurrency: HashMap::from([(RequestKind::DescribeType, 1), (RequestKind::Subscribe, 1)]),
+            max_queued: 2,
+            max_wait: Duration::from_millis(50),
+        });
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let describing = admission.admit(RequestKind::DescribeType, None).await;
+            let subscribing = admission.admit(RequestKind::Subscribe, None).await;
+            assert!(describing.is_some() && subscribing.is_some());
+
+            // while one request waits, the queue is too full for the expensive kind
+            let waiting = admission.admit(RequestKind::DescribeType, None);
+            let (waited, shed) = tokio::join!(waiting, async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                admission.admit(RequestKind::Subscribe, None).await
+            });
+            assert!(waited.is_none(), "timed out waiting");
+            assert!(shed.is_none(), "shed right away");
+
+            // a request doesn't wait past its deadline
+            let started = std::time::Instant::now();
+            assert!(admission.admit(RequestKind::DescribeType, Some(Duration::from_millis(5))).await.is_none());
+            assert!(started.elapsed() < Duration::from_millis(50));
+
+            drop(describing);
+            assert!(admission.admit(RequestKind::DescribeType, None).await.is_some());
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,437 @@
+//! # Challenge Records
+//!
+//! A node publishes its public keys in TXT records at `_osp.<hostname>`,
+//! one key per record, in the format
+//!
+//! ```text
+//! v=osp1; k=rsa; f=<fingerprint>; exp=<unix timestamp>; p=<base64 DER public key>
+//! ```
+//!
+//! `v` must come first. `k` names the key algorithm, `rsa` or `ed25519`,
+//! and `p` holds the key as base64 encoded DER (SubjectPublicKeyInfo). The
+//! optional `f` is the lowercase hex SHA-256 digest of the DER key, so a
+//! truncated or garbled key is rejected with a clear error. The optional
+//! `exp` is the unix timestamp (seconds) the key stops being accepted at,
+//! which lets operators rotate keys by publishing the new one next to the
+//! old one before it expires. Unknown tags are ignored so the format can
+//! grow, but a record with a missing, repeated or malformed tag is rejected
+//! as a whole.
+//!
+//! A record longer than 255 bytes is published as several strings of one
+//! TXT record, which are joined in order, see [ChallengeRecord::txt_strings].
+//! Where a DNS provider limits the length of a record, the key can instead
+//! be split across several records, each carrying the same tags plus `f`
+//! and its position `c=<index>/<total>`, see [ChallengeRecord::chunks].
+//!
+//! Peers are challenged with either kind of key, see [ChallengeKey]. RSA
+//! keys are preferred when a peer publishes both, as nodes from before
+//! Ed25519 keys were supported can only answer challenges to RSA keys.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use tokio::io;
+
+use uuid::Uuid;
+
+pub use osp_protocol::packet::handshake::KeyAlgorithm;
+
+use crate::crypto::{self, Ed25519PublicKey, PublicKey};
+
+/// Version tag of the record format.
+pub const RECORD_VERSION: &str = "osp1";
+
+/// DER encoding of an Ed25519 SubjectPublicKeyInfo up to the key itself,
+/// see RFC 8410.
+const ED25519_SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+/// Prefix of the message an Ed25519 handshake challenge is signed as, so the
+/// signature can't be passed off as one made for another purpose.
+const CHALLENGE_CONTEXT: &[u8] = b"osp handshake challenge v1\0";
+
+/// Value of the `k` tag for `algorithm`.
+fn algorithm_tag(algorithm: KeyAlgorithm) -> &'static str {
+    match algorithm {
+        KeyAlgorithm::Rsa => "rsa",
+        KeyAlgorithm::Ed25519 => "ed25519",
+    }
+}
+
+/// A key a peer can be challenged with in the handshake. RSA keys are sent
+/// the challenge encrypted and decrypt it, Ed25519 keys are sent the
+/// challenge in the clear and sign it, see [challenge_message].
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChallengeKey {
+    Rsa(PublicKey),
+    Ed25519(Ed25519PublicKey),
+}
+
+impl ChallengeKey {
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        match self {
+            ChallengeKey::Rsa(_) => KeyAlgorithm::Rsa,
+            ChallengeKey::Ed25519(_) => KeyAlgorithm::Ed25519,
+        }
+    }
+}
+
+/// The message a guest signs to answer an Ed25519 challenge of
+/// `challenge` bytes on the connection with `nonce`.
+pub(crate) fn challenge_message(nonce: Uuid, challenge: &[u8]) -> Vec<u8> {
+    [CHALLENGE_CONTEXT, nonce.as_bytes(), challenge].concat()
+}
+
+/// A public key published in a node's `_osp` TXT record.
+#[derive(Clone, Debug)]
+pub struct ChallengeRecord {
+    pub algorithm: KeyAlgorithm,
+    /// DER encoded SubjectPublicKeyInfo of the key.
+    pub key: Vec<u8>,
+    /// Unix timestamp (seconds) the key stops being accepted at, if it does.
+    pub expires_at: Option<u64>,
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+impl ChallengeRecord {
+    /// A record publishing the RSA key `key`, valid until `expires_at` if
+    /// given.
+    pub fn rsa(key: &PublicKey, expires_at: Option<u64>) -> io::Result<Self> {
+        Ok(Self {
+            algorithm: KeyAlgorithm::Rsa,
+            key: key.to_der()?,
+            expires_at,
+        })
+    }
+
+    /// A record publishing the Ed25519 key `key`, valid until `expires_at`
+    /// if given.
+    pub fn ed25519(key: &Ed25519PublicKey, expires_at: Option<u64>) -> io::Result<Self> {
+        Ok(Self {
+            algorithm: KeyAlgorithm::Ed25519,
+            key: key.to_der()?,
+            expires_at,
+        })
+    }
+
+    /// Whether the key has expired.
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// The key to challenge the publisher of the record with, if the
+    /// crypto backend accepts it.
+    pub fn challenge_key(&self) -> Option<ChallengeKey> {
+        match self.algorithm {
+            KeyAlgorithm::Rsa => PublicKey::from_der(&self.key).ok().map(ChallengeKey::Rsa),
+            KeyAlgorithm::Ed25519 => Ed25519PublicKey::from_der(&self.key).ok().map(ChallengeKey::Ed25519),
+        }
+    }
+}
+
+type Tags<'a> = Vec<(&'a str, &'a str)>;
+
+fn tag<'a>(tags: &Tags<'a>, name: &str) -> Option<&'a str> {
+    tags.iter().find(|(seen, _)| *seen == name).map(|(_, value)| *value)
+}
+
+/// Split a record into its tags, checking that it starts with a supported
+/// version tag.
+fn parse_tags(record: &str) -> io::Result<Tags<'_>> {
+    let mut tags = Vec::new();
+    for tag in record.split(';').map(str::trim).filter(|tag| !tag.is_empty()) {
+        let Some((name, value)) = tag.split_once('=') else {
+            return Err(invalid(format!("Malformed tag {tag:?}")));
+        };
+        let (name, value) = (name.trim(), value.trim());
+        if value.is_empty() {
+            return Err(invalid(format!("Tag {name} has no value")));
+        }
+        if tags.iter().any(|(seen, _)| *seen == name) {
+            return Err(invalid(format!("Tag {name} is repeated")));
+        }
+        tags.push((name, value));
+    }
+
+    match tags.first() {
+        Some(("v", RECORD_VERSION)) => Ok(tags),
+        Some(("v", version)) => Err(invalid(format!("Unsupported record version {version}"))),
+        _ => Err(invalid("Record does not start with a version tag".to_string())),
+    }
+}
+
+/// Lowercase hex SHA-256 digest of `der`.
+fn fingerprint_of(der: &[u8]) -> String {
+    crypto::hex(&crypto::sha256(der))
+}
+
+fn from_tags(tags: &Tags, public_key: &str) -> io::Result<ChallengeRecord> {
+    let algorithm = match tag(tags, "k") {
+        Some("rsa") => KeyAlgorithm::Rsa,
+        Some("ed25519") => KeyAlgorithm::Ed25519,
+        Some(algorithm) => return Err(invalid(format!("Unsupported key algorithm {algorithm}"))),
+        None => return Err(invalid("Record has no key algorithm".to_string())),
+    };
+    let der = BASE64.decode(public_key)
+        .map_err(|e| invalid(format!("Public key is not valid base64: {e}")))?;
+    if let Some(fingerprint) = tag(tags, "f") {
+        if !fingerprint.eq_ignore_ascii_case(&fingerprint_of(&der)) {
+            return Err(invalid(format!(
+                "Public key does not match its fingerprint {fingerprint}, the record may be truncated or garbled"
+            )));
+        }
+    }
+    match algorithm {
+        KeyAlgorithm::Rsa => {
+            PublicKey::from_der(&der).map_err(|e| invalid(format!("Public key is not a valid DER rsa key: {e}")))?;
+        }
+        KeyAlgorithm::Ed25519 => {
+            if der.len() != ED25519_SPKI_PREFIX.len() + 32 || !der.starts_with(&ED25519_SPKI_PREFIX) {
+                return Err(invalid("Public key is not a valid DER ed25519 key".to_string()));
+            }
+        }
+    }
+    let expires_at = tag(tags, "exp")
+        .map(|exp| exp.parse::<u64>().map_err(|_| invalid(format!("Invalid expiry {exp:?}"))))
+        .transpose()?;
+
+    Ok(ChallengeRecord {
+        algorithm,
+        key: der,
+        expires_at,
+    })
+}
+
+/// Most records a key may be split across. Bounds the work a hostile
+/// record claiming to be one of billions of chunks can cause.
+const MAX_CHUNKS: usize = 999;
+
+/// A record carrying one chunk of a key split across several records.
+struct Chunk<'a> {
+    index: usize,
+    total: usize,
+    tags: Tags<'a>,
+}
+
+fn parse_chunk_position(position: &str) -> io::Result<(usize, usize)> {
+    let parsed = position.split_once('/')
+        .and_then(|(index, total)| Some((index.parse().ok()?, total.parse().ok()?)));
+    match parsed {
+        Some((index, total)) if index >= 1 && index <= total && total <= MAX_CHUNKS => Ok((index, total)),
+        _ => Err(invalid(format!("Invalid chunk position {position:?}"))),
+    }
+}
+
+/// Reassemble the chunks of the key with `fingerprint`, ordered by their
+/// position.
+fn assemble(fingerprint: &str, mut chunks: Vec<Chunk>) -> io::Result<ChallengeRecord> {
+    chunks.sort_by_key(|chunk| chunk.index);
+    let total = chunks[0].total;
+    if chunks.iter().any(|chunk| chunk.total != total) {
+        return Err(invalid(format!("Chunks of key {fingerprint} disagree on their number")));
+    }
+    if let Some(pair) = chunks.windows(2).find(|pair| pair[0].index == pair[1].index) {
+        return Err(invalid(format!("Key {fingerprint} repeats chunk {}", pair[0].index)));
+    }
+    if let Some(missing) = (1..=total).find(|index| chunks.iter().all(|chunk| chunk.index != *index)) {
+        return Err(invalid(format!("Key {fingerprint} is missing chunk {missing} of {total}")));
+    }
+
+    let first = &chunks[0].tags;
+    for name in ["k", "exp"] {
+        if chunks.iter().any(|chunk| tag(&chunk.tags, name) != tag(first, name)) {
+            return Err(invalid(format!("Chunks of key {fingerprint} disagree on tag {name}")));
+        }
+    }
+    let public_key = chunks.iter()
+        .map(|chunk| tag(&chunk.tags, "p").ok_or_else(|| invalid(format!("Chunk {} of key {fingerprint} has no public key", chunk.index))))
+        .collect::<io::Result<String>>()?;
+    from_tags(first, &public_key)
+}
+
+/// Parse the TXT records published at `_osp.<hostname>`, reassembling keys
+/// split across several records. Chunked keys are returned after the
+/// records holding a whole key, ordered by fingerprint. A record, or a
+/// chunked key, that fails to parse yields an error in its place.
+pub fn parse_records<S: AsRef<str>>(records: &[S]) -> Vec<io::Result<ChallengeRecord>> {
+    let mut parsed = Vec::new();
+    let mut chunked: BTreeMap<&str, Vec<Chunk>> = BTreeMap::new();
+    for record in records {
+        let tags = match parse_tags(record.as_ref()) {
+            Ok(tags) => tags,
+            Err(e) => {
+                parsed.push(Err(e));
+                continue;
+            }
+        };
+        let Some(position) = tag(&tags, "c") else {
+            parsed.push(tag(&tags, "p")
+                .ok_or_else(|| invalid("Record has no public key".to_string()))
+                .and_then(|public_key| from_tags(&tags, public_key)));
+            continue;
+        };
+
+        let chunk = parse_chunk_position(position).and_then(|(index, total)| match tag(&tags, "f") {
+            Some(fingerprint) => Ok((fingerprint, Chunk { index, total, tags })),
+            None => Err(invalid("Chunked record has no fingerprint".to_string())),
+        });
+        match chunk {
+            Ok((fingerprint, chunk)) => chunked.entry(fingerprint).or_default().push(chunk),
+            Err(e) => parsed.push(Err(e)),
+        }
+    }
+
+    parsed.extend(chunked.into_iter().map(|(fingerprint, chunks)| assemble(fingerprint, chunks)));
+    parsed
+}
+
+impl ChallengeRecord {
+    /// Lowercase hex SHA-256 digest of the DER encoded key, published in the
+    /// `f` tag.
+    pub fn fingerprint(&self) -> io::Result<String> {
+        Ok(fingerprint_of(&self.key))
+    }
+
+    /// The record split into the strings of a single TXT record, each at
+    /// most 255 bytes long as DNS requires.
+    pub fn txt_strings(&self) -> Vec<String> {
+        split_strings(&self.to_string())
+    }
+
+    /// The record split across several TXT records of at most `max_len`
+    /// bytes each, for DNS providers that limit the length of a record.
+    /// Returns a single record if the whole record fits.
+    pub fn chunks(&self, max_len: usize) -> io::Result<Vec<String>> {
+        let record = self.to_string();
+        if record.len() <= max_len {
+            return Ok(vec![record]);
+        }
+
+        let fingerprint = self.fingerprint()?;
+        let public_key = BASE64.encode(&self.key);
+        let expiry = self.expires_at.map(|expires_at| format!("; exp={expires_at}")).unwrap_or_default();
+        // leaves room for chunk positions of up to MAX_CHUNKS
+        let overhead = format!("v={RECORD_VERSION}; k={}; f={fingerprint}; c=999/999{expiry}; p=", algorithm_tag(self.algorithm)).len();
+        if max_len <= overhead {
+            return Err(invalid(format!("Records must be longer than {overhead} bytes to hold a key chunk")));
+        }
+
+        let parts = public_key.as_bytes().chunks(max_len - overhead).collect::<Vec<_>>();
+        if parts.len() > MAX_CHUNKS {
+            return Err(invalid(format!("Key doesn't fit in {MAX_CHUNKS} records of {max_len} bytes")));
+        }
+        Ok(parts.iter().enumerate().map(|(index, part)| format!(
+            "v={RECORD_VERSION}; k={}; f={fingerprint}; c={}/{}{expiry}; p={}",
+            algorithm_tag(self.algorithm),
+            index + 1,
+            parts.len(),
+            // base64 is ASCII, so any split is valid UTF-8
+            std::str::from_utf8(part).unwrap(),
+        )).collect())
+    }
+}
+
+/// Split `record` into strings of at most 255 bytes.
+fn split_strings(record: &str) -> Vec<String> {
+    const MAX_STRING: usize = 255;
+
+    let mut strings = Vec::new();
+    let mut rest = record;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(MAX_STRING);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        strings.push(rest[..end].to_string());
+        rest = &rest[end..];
+    }
+    strings
+}
+
+impl FromStr for ChallengeRecord {
+    type Err = io::Error;
+
+    /// Parse a record holding a whole key.
+    fn from_str(record: &str) -> Result<Self, Self::Err> {
+        let tags = parse_tags(record)?;
+        if tag(&tags, "c").is_some() {
+            return Err(invalid("Record holds one chunk of a key".to_string()));
+        }
+        let public_key = tag(&tags, "p").ok_or_else(|| invalid("Record has no public key".to_string()))?;
+        from_tags(&tags, public_key)
+    }
+}
+
+impl fmt::Display for ChallengeRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v={RECORD_VERSION}; k={}; f={}", algorithm_tag(self.algorithm), fingerprint_of(&self.key))?;
+        if let Some(expires_at) = self.expires_at {
+            write!(f, "; exp={expires_at}")?;
+        }
+        write!(f, "; p={}", BASE64.encode(&self.key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use crate::connection::challenge::{parse_records, ChallengeKey, ChallengeRecord, KeyAlgorithm, ED25519_SPKI_PREFIX};
+    use crate::crypto::{Ed25519Key, PrivateKey};
+
+    #[test]
+    fn test_challenge_record() -> io::Result<()> {
+        let public = PrivateKey::generate(1024)?.public_key()?;
+        let record: ChallengeRecord = ChallengeRecord::rsa(&public, Some(1))?.to_string().parse()?;
+        assert_eq!(record.algorithm, KeyAlgorithm::Rsa);
+        assert!(record.is_expired());
+        assert_eq!(record.challenge_key().unwrap(), ChallengeKey::Rsa(public));
+
+        let ed25519 = Ed25519Key::generate()?.public_key()?;
+        assert!(ed25519.to_der()?.starts_with(&ED25519_SPKI_PREFIX));
+        let p = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, ed25519.to_der()?);
+        let record: ChallengeRecord = format!("v=osp1; k=ed25519; p={p}; future=tag").parse()?;
+        assert!(!record.is_expired());
+        assert_eq!(record.challenge_key().unwrap(), ChallengeKey::Ed25519(ed25519));
+
+        assert!(format!("k=ed25519; v=osp1; p={p}").parse::<ChallengeRecord>().is_err());
+        assert!(format!("v=osp1; k=rsa; p={p}").parse::<ChallengeRecord>().is_err());
+        assert!(format!("v=osp1; k=ed25519; p={p}; p={p}").parse::<ChallengeRecord>().is_err());
+        assert!(format!("v=osp1; k=ed25519; p={p}; exp=soon").parse::<ChallengeRecord>().is_err());
+        assert!("v=osp2; k=rsa; p=AAAA".parse::<ChallengeRecord>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunked_records() -> io::Result<()> {
+        let record = ChallengeRecord::rsa(&PrivateKey::generate(2048)?.public_key()?, None)?;
+        assert!(record.txt_strings().iter().all(|string| string.len() <= 255));
+        assert_eq!(record.txt_strings().concat(), record.to_string());
+
+        let mut chunks = record.chunks(200)?;
+        assert!(chunks.len() > 1 && chunks.iter().all(|chunk| chunk.len() <= 200));
+        chunks.reverse();
+        let parsed = parse_records(&chunks);
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].as_ref().unwrap().fingerprint()?, record.fingerprint()?);
+
+        let missing = parse_records(&chunks[1..]);
+        assert!(missing[0].as_ref().unwrap_err().to_string().contains("missing chunk"));
+        let endless = parse_records(&[format!("v=osp1; k=rsa; f=00; c=1/{}; p=AAAA", usize::MAX)]);
+        assert!(endless[0].as_ref().unwrap_err().to_string().contains("Invalid chunk position"));
+
+        let mut garbled = record.to_string();
+        garbled.truncate(garbled.len() - 8);
+        let error = garbled.parse::<ChallengeRecord>().unwrap_err().to_string();
+        assert!(error.contains("fingerprint"), "{error}");
+        Ok(())
+    }
+}
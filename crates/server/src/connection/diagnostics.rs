@@ -0,0 +1,150 @@
+//! # Handshake Diagnostics
+//!
+//! Both sides of a handshake tell each other the software they run and the
+//! time on their clock, along with the protocol versions they speak. A node
+//! keeps what it learned in its latest handshake with each peer as
+//! [PeerDiagnostics], so operators coordinating an upgrade across a network
+//! can see who still runs what, see
+//! [OSProtocolNode::peer_diagnostics](crate::OSProtocolNode::peer_diagnostics).
+//!
+//! Nodes warn when they speak a version before
+//! [DEPRECATED_BEFORE](osp_protocol::DEPRECATED_BEFORE) with a peer, or when
+//! a peer's clock is more than [CLOCK_SKEW_WARNING] off theirs, which breaks
+//! embargoes, signatures and anything else with timestamps.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use osp_protocol::{ProtocolVersion, DEPRECATED_BEFORE};
+
+/// What this SDK tells peers it runs, unless the node is built with other
+/// [software](crate::OSProtocolNodeBuilder::software).
+pub const SOFTWARE: &str = concat!("osp_server_sdk/", env!("CARGO_PKG_VERSION"));
+
+/// How many characters of the software a peer says it runs are kept.
+pub const MAX_SOFTWARE_LENGTH: usize = 64;
+
+/// How far a peer's clock may be off before it is warned about.
+pub const CLOCK_SKEW_WARNING: Duration = Duration::from_secs(30);
+
+/// The current time in unix milliseconds, as sent in handshakes.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// What a node learned about a peer in its latest handshake with it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerDiagnostics {
+    /// Hostname of the peer.
+    pub peer: String,
+    /// The software the peer runs, if it said, cut to
+    /// [MAX_SOFTWARE_LENGTH] characters with control characters replaced.
+    pub software: Option<String>,
+    /// The protocol version spoken with the peer.
+    pub version: ProtocolVersion,
+    /// How far the peer's clock is ahead of ours in milliseconds, negative
+    /// if it is behind, `None` if it didn't send its time. It includes the
+    /// time the handshake packet took to arrive.
+    pub clock_skew: Option<i64>,
+    /// Unix timestamp (seconds) of the handshake.
+    pub seen_at: u64,
+}
+
+impl PeerDiagnostics {
+    /// The diagnostics of a handshake with `peer` speaking `version`, which
+    /// sent `timestamp` in a packet received at `received_at` (both unix
+    /// milliseconds).
+    pub(crate) fn new(peer: &str, software: Option<String>, version: ProtocolVersion, timestamp: Option<u64>, received_at: u64) -> Self {
+        PeerDiagnostics {
+            peer: peer.to_string(),
+            software: software.map(|software| sanitize(&software)),
+            version,
+            // a hostile timestamp may not fit
+            clock_skew: timestamp.and_then(|timestamp| i64::try_from(timestamp).ok()?.checked_sub(i64::try_from(received_at).ok()?)),
+            seen_at: received_at / 1000,
+        }
+    }
+
+    /// Whether the version spoken with the peer is deprecated.
+    pub fn is_deprecated(&self) -> bool {
+        self.version < DEPRECATED_BEFORE
+    }
+
+    /// Whether the peer's clock is more than [CLOCK_SKEW_WARNING] off.
+    pub fn is_skewed(&self) -> bool {
+        self.clock_skew.is_some_and(|skew| skew.unsigned_abs() > CLOCK_SKEW_WARNING.as_millis() as u64)
+    }
+}
+
+/// `software` as it is safe to log and show.
+fn sanitize(software: &str) -> String {
+    software.chars()
+        .take(MAX_SOFTWARE_LENGTH)
+        .map(|c| if c.is_control() { '\u{fffd}' } else { c })
+        .collect()
+}
+
+/// The diagnostics of every peer a node shook hands with.
+#[derive(Default)]
+pub struct HandshakeDiagnostics {
+    peers: Mutex<HashMap<String, PeerDiagnostics>>,
+}
+
+impl HandshakeDiagnostics {
+    /// Keep `diagnostics` as the latest for its peer, warning if it speaks
+    /// a deprecated version or its clock is off.
+    pub(crate) fn record(&self, diagnostics: PeerDiagnostics) {
+        let (peer, software) = (&diagnostics.peer, diagnostics.software.as_deref().unwrap_or("unknown software"));
+        if diagnostics.is_deprecated() {
+            warn!("{peer} ({software}) speaks deprecated protocol version {}, versions before {DEPRECATED_BEFORE} should be upgraded", diagnostics.version);
+        }
+        if let Some(skew) = diagnostics.clock_skew.filter(|_| diagnostics.is_skewed()) {
+            warn!("The clock of {peer} ({software}) is {:.1}s {}", skew.unsigned_abs() as f64 / 1000.0, if skew > 0 { "ahead" } else { "behind" });
+        }
+        self.peers.lock().unwrap().insert(diagnostics.peer.clone(), diagnostics);
+    }
+
+    /// The latest diagnostics of `peer`, if the node shook hands with it.
+    pub fn peer(&self, peer: &str) -> Option<PeerDiagnostics> {
+        self.peers.lock().unwrap().get(peer).cloned()
+    }
+
+    /// The latest diagnostics of every peer, by hostname.
+    pub fn peers(&self) -> Vec<PeerDiagnostics> {
+        let mut peers = self.peers.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        peers.sort_by(|a, b| a.peer.cmp(&b.peer));
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use osp_protocol::{ProtocolVersion, PROTOCOL_VERSION};
+
+    use crate::connection::diagnostics::{HandshakeDiagnostics, PeerDiagnostics};
+
+    #[test]
+    fn test_peer_diagnostics() {
+        let received_at = 1_700_000_000_000;
+        let ahead = PeerDiagnostics::new("a.test", None, PROTOCOL_VERSION, Some(received_at + 45_000), received_at);
+        assert_eq!((ahead.clock_skew, ahead.seen_at), (Some(45_000), 1_700_000_000));
+        assert!(ahead.is_skewed() && !ahead.is_deprecated());
+        let old = PeerDiagnostics::new("b.test", Some("osp_server_sdk/0.0.1".to_string()), ProtocolVersion::INITIAL, Some(received_at - 200), received_at);
+        assert!(!old.is_skewed() && old.is_deprecated());
+        assert!(!PeerDiagnostics::new("c.test", None, PROTOCOL_VERSION, None, received_at).is_skewed());
+        let hostile = PeerDiagnostics::new("d.test", Some(format!("evil\n{}", "x".repeat(100))), PROTOCOL_VERSION, Some(u64::MAX), received_at);
+        assert_eq!(hostile.clock_skew, None);
+        assert_eq!(hostile.software.as_deref().map(|software| (software.chars().count(), software.contains('\n'))), Some((64, false)));
+
+        let diagnostics = HandshakeDiagnostics::default();
+        diagnostics.record(old.clone());
+        diagnostics.record(ahead.clone());
+        diagnostics.record(PeerDiagnostics { version: PROTOCOL_VERSION, ..old });
+        assert_eq!(diagnostics.peers().iter().map(|peer| (peer.peer.as_str(), peer.version)).collect::<Vec<_>>(),
+            [("a.test", PROTOCOL_VERSION), ("b.test", PROTOCOL_VERSION)]);
+        assert_eq!(diagnostics.peer("a.test"), Some(ahead));
+    }
+}
@@ -0,0 +1,659 @@
+//! # Challenge Record Lookup
+//!
+//! Peers publish the public keys they are challenged with in TXT records at
+//! `_osp.<hostname>`, see [ChallengeRecord]. They are looked up with a
+//! [ChallengeResolver], which by default asks public resolvers over plain
+//! DNS. It can use the system's resolver configuration or nameservers of its
+//! own instead, e.g. behind split-horizon DNS. With the `secure-dns`
+//! feature, lookups can go to a DNS-over-HTTPS or DNS-over-TLS upstream
+//! instead, see [SecureUpstream], so that nodes on hostile networks can't
+//! have their lookups tampered with by a local resolver. Records can also be
+//! overridden locally, like in a hosts file, for tests and CI where peers
+//! have no real DNS records. With the `dnssec` feature, a resolver can
+//! [require](ChallengeResolver::require_dnssec) records to be signed, so
+//! that a spoofed answer can't hand a node the wrong key to challenge a peer
+//! with.
+//!
+//! Timeouts and server failures are retried with exponential backoff, and
+//! hosts without a record are remembered for a short while so that repeated
+//! connection attempts don't hammer the resolver. Records found are kept
+//! for a short while too, so that checking the signatures of a stream of
+//! objects from the same origin doesn't look them up for each one. Failed
+//! lookups surface as a [LookupError], which tells a missing record apart
+//! from a resolver that is down.
+//!
+//! The resolver also finds where a peer's node listens: operators can run
+//! it on any host and port, and move it without breaking peers, by
+//! publishing SRV records at `_osp._tcp.<hostname>`, see
+//! [ChallengeResolver::lookup_endpoints]. Hosts without one are connected
+//! to at the port of their URL.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use tokio::io;
+
+use trust_dns_resolver::TokioAsyncResolver;
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::config::NameServerConfigGroup;
+use trust_dns_resolver::proto::error::ProtoErrorKind;
+use trust_dns_resolver::proto::op::ResponseCode;
+
+use crate::connection::challenge::{parse_records, ChallengeKey, ChallengeRecord, KeyAlgorithm};
+#[cfg(feature = "secure-dns")]
+use crate::connection::transport;
+use crate::crypto::PublicKey;
+use crate::metrics;
+
+/// Why a challenge record lookup failed. Converts into an [io::Error] of
+/// kind [NotFound](io::ErrorKind::NotFound), [Other](io::ErrorKind::Other),
+/// [InvalidData](io::ErrorKind::InvalidData) or
+/// [PermissionDenied](io::ErrorKind::PermissionDenied) respectively, which
+/// callers holding an [io::Error] can downcast back to this type.
+#[derive(Debug)]
+pub enum LookupError {
+    /// The host publishes no challenge record.
+    NoRecord {
+        hostname: String,
+    },
+    /// The resolver could not be reached or failed to answer, even after
+    /// retrying.
+    Unavailable {
+        hostname: String,
+        reason: String,
+    },
+    /// The host's challenge records are invalid, expired or hold no key
+    /// usable for the challenge.
+    Invalid {
+        hostname: String,
+        reason: String,
+    },
+    /// DNSSEC is [required](ChallengeResolver::require_dnssec), and the
+    /// host's challenge records failed validation or aren't signed.
+    Insecure {
+        hostname: String,
+        reason: String,
+    },
+}
+
+impl Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LookupError::NoRecord { hostname } => {
+                write!(f, "No challenge record found for {hostname}. Is it located at _osp.{hostname}?")
+            }
+            LookupError::Unavailable { hostname, reason } => {
+                write!(f, "Failed to resolve the challenge record for {hostname}: {reason}")
+            }
+            LookupError::Invalid { hostname, reason } => write!(f, "Invalid challenge record for {hostname}: {reason}"),
+            LookupError::Insecure { hostname, reason } => {
+                write!(f, "The challenge record for {hostname} failed DNSSEC validation: {reason}")
+            }
+        }
+    }
+}
+
+impl Error for LookupError {}
+
+impl LookupError {
+    /// The kind of [io::Error] this converts into.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            LookupError::NoRecord { .. } => io::ErrorKind::NotFound,
+            LookupError::Unavailable { .. } => io::ErrorKind::Other,
+            LookupError::Invalid { .. } => io::ErrorKind::InvalidData,
+            LookupError::Insecure { .. } => io::ErrorKind::PermissionDenied,
+        }
+    }
+}
+
+impl From<LookupError> for io::Error {
+    fn from(err: LookupError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// The resolver challenge records are looked up with. Clones share the
+/// resolver, its cache and the negative cache.
+#[derive(Clone)]
+pub struct ChallengeResolver {
+    resolver: TokioAsyncResolver,
+    config: ResolverConfig,
+    opts: ResolverOpts,
+    /// Records answered locally instead of asking the resolver.
+    overrides: HashMap<String, Vec<String>>,
+    /// Endpoints answered locally instead of asking the resolver.
+    endpoint_overrides: HashMap<String, (String, u16)>,
+    /// Whether answers must pass DNSSEC validation.
+    dnssec: bool,
+    attempts: u32,
+    backoff: Duration,
+    negative_ttl: Duration,
+    /// Hosts without a record, and until when that is assumed to hold.
+    negative: Arc<Mutex<HashMap<String, Instant>>>,
+    record_ttl: Duration,
+    /// Records found, and until when they are used without looking again.
+    records: Arc<Mutex<HashMap<String, CachedRecords>>>,
+}
+
+/// The records of a host, and until when they are used.
+type CachedRecords = (Vec<ChallengeRecord>, Instant);
+
+impl Default for ChallengeResolver {
+    /// Public resolvers over plain DNS.
+    fn default() -> Self {
+        Self::with_config(ResolverConfig::default(), ResolverOpts::default())
+    }
+}
+
+impl ChallengeResolver {
+    fn with_config(config: ResolverConfig, opts: ResolverOpts) -> Self {
+        Self {
+            resolver: TokioAsyncResolver::tokio(config.clone(), opts),
+            config,
+            opts,
+            overrides: HashMap::new(),
+            endpoint_overrides: HashMap::new(),
+            dnssec: false,
+            attempts: 3,
+            backoff: Duration::from_millis(250),
+            negative_ttl: Duration::from_secs(30),
+            negative: Arc::new(Mutex::new(HashMap::new())),
+            record_ttl: Duration::from_secs(60),
+            records: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Resolve through the encrypted `upstream` only.
+    #[cfg(feature = "secure-dns")]
+    pub fn secure(upstream: SecureUpstream) -> Self {
+        let group = match upstream.https {
+            true => NameServerConfigGroup::from_ips_https(&upstream.ips, upstream.port, upstream.tls_name, true),
+            false => NameServerConfigGroup::from_ips_tls(&upstream.ips, upstream.port, upstream.tls_name, true),
+        };
+        let mut config = ResolverConfig::from_parts(None, Vec::new(), group);
+        if let Some(roots) = upstream.pinned {
+            let client_config = rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            config.set_tls_client_config(Arc::new(client_config));
+        }
+        Self::with_config(config, ResolverOpts::default())
+    }
+
+    /// The resolver configured for the system, from `/etc/resolv.conf` on
+    /// Unix or the registry on Windows.
+    pub fn system() -> io::Result<Self> {
+        let (config, opts) = trust_dns_resolver::system_conf::read_system_conf()?;
+        Ok(Self::with_config(config, opts))
+    }
+
+    /// Resolve through the nameservers at `addrs` only, over UDP with a
+    /// fallback to TCP.
+    pub fn nameservers(addrs: &[SocketAddr]) -> Self {
+        let mut group = NameServerConfigGroup::new();
+        for addr in addrs {
+            group.merge(NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true));
+        }
+        Self::with_config(ResolverConfig::from_parts(None, Vec::new(), group), ResolverOpts::default())
+    }
+
+    /// How long to wait for an answer to a single query before it counts as
+    /// timed out and is [retried](Self::retries). Defaults to 5 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.opts.timeout = timeout;
+        self.resolver = TokioAsyncResolver::tokio(self.config.clone(), self.opts);
+        self
+    }
+
+    /// Only accept challenge records that pass DNSSEC validation, failing
+    /// lookups of unsigned or badly signed records with
+    /// [LookupError::Insecure]. The nameservers must return DNSSEC records,
+    /// which most public and system resolvers do. Overridden records are
+    /// trusted as is. Off by default.
+    #[cfg(feature = "dnssec")]
+    pub fn require_dnssec(mut self, require: bool) -> Self {
+        self.dnssec = require;
+        self.opts.validate = require;
+        self.resolver = TokioAsyncResolver::tokio(self.config.clone(), self.opts);
+        self
+    }
+
+    /// Answer lookups for `hostname` with `record` instead of asking the
+    /// resolver, like an entry in a hosts file. Overriding a host again adds
+    /// another record.
+    pub fn override_record(mut self, hostname: &str, record: &ChallengeRecord) -> Self {
+        self.overrides.entry(hostname.to_ascii_lowercase()).or_default().push(record.to_string());
+        self
+    }
+
+    /// Answer [endpoint lookups](Self::lookup_endpoints) for `hostname` with
+    /// `target` and `port` instead of asking the resolver, like an SRV
+    /// record.
+    pub fn override_endpoint(mut self, hostname: &str, target: &str, port: u16) -> Self {
+        self.endpoint_overrides.insert(hostname.to_ascii_lowercase(), (target.to_string(), port));
+        self
+    }
+
+    /// The hosts and ports the node of `hostname` listens at, from the SRV
+    /// records at `_osp._tcp.<hostname>`, in the order to try them in: the
+    /// lowest priority first, and those of the same priority picked at
+    /// random by their weight, as RFC 2782 has it. Empty if there is no
+    /// record, the record says there is no such service, or the lookup
+    /// failed, for the caller to fall back to the port of the host's URL.
+    pub async fn lookup_endpoints(&self, hostname: &str) -> Vec<(String, u16)> {
+        if let Some(endpoint) = self.endpoint_overrides.get(&hostname.to_ascii_lowercase()) {
+            debug!("Using the overridden endpoint of {hostname}");
+            return vec![endpoint.clone()];
+        }
+        let lookup = match self.resolver.srv_lookup(format!("_osp._tcp.{hostname}")).await {
+            Ok(lookup) => lookup,
+            Err(e) => {
+                debug!("No endpoint record found for {hostname}: {e}");
+                return Vec::new();
+            }
+        };
+        // a target of "." means the host offers no such service
+        let records = lookup.iter()
+            .filter(|srv| !srv.target().is_root())
+            .map(|srv| (srv.priority(), srv.weight(), (srv.target().to_utf8().trim_end_matches('.').to_string(), srv.port())))
+            .collect();
+        let endpoints = order_endpoints(records, || crate::delivery::random_unit().unwrap_or_default());
+        if let Some((target, port)) = endpoints.first() {
+            info!("Found endpoint {target}:{port} of {hostname}, of {}", endpoints.len());
+        }
+        endpoints
+    }
+
+    /// The IPv4 address of `hostname`, if it has one.
+    pub(crate) async fn lookup_ipv4(&self, hostname: &str) -> io::Result<Option<IpAddr>> {
+        let lookup = self.resolver.ipv4_lookup(hostname).await?;
+        Ok(lookup.iter().next().map(|ip| IpAddr::from(ip.0)))
+    }
+
+    /// Try a lookup that times out or fails on the server up to `attempts`
+    /// times, waiting `backoff` before the first retry and twice as long
+    /// before each one after. Defaults to 3 attempts and 250ms.
+    pub fn retries(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.backoff = backoff;
+        self
+    }
+
+    /// How long a host found to have no record is assumed to still have
+    /// none, without asking the resolver again. Defaults to 30 seconds.
+    pub fn negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// How long the records found for a host are used without asking the
+    /// resolver again. Defaults to 60 seconds, zero looks them up every
+    /// time.
+    pub fn record_ttl(mut self, ttl: Duration) -> Self {
+        self.record_ttl = ttl;
+        self
+    }
+
+    /// The TXT records at `_osp.<hostname>`, with the strings of each record
+    /// joined in order.
+    async fn txt_lookup(&self, hostname: &str) -> Result<Vec<String>, LookupError> {
+        if let Some(records) = self.overrides.get(&hostname.to_ascii_lowercase()) {
+            debug!("Using the overridden challenge records of {hostname}");
+            return Ok(records.clone());
+        }
+
+        let no_record = || LookupError::NoRecord { hostname: hostname.to_string() };
+        if self.negative.lock().unwrap().get(hostname).is_some_and(|until| *until > Instant::now()) {
+            debug!("{hostname} is known to have no challenge record");
+            return Err(no_record());
+        }
+
+        let mut attempt = 1;
+        let mut backoff = self.backoff;
+        loop {
+            let err = match self.resolver.txt_lookup(format!("_osp.{}", hostname)).await {
+                // long keys don't fit in a single string
+                Ok(lookup) => return Ok(lookup.iter()
+                    .map(|record| record.txt_data().iter().map(|string| String::from_utf8_lossy(string)).collect())
+                    .collect()),
+                Err(err) => err,
+            };
+            match err.kind() {
+                ResolveErrorKind::NoRecordsFound { response_code: ResponseCode::NXDomain | ResponseCode::NoError, .. } => {
+                    let now = Instant::now();
+                    let mut negative = self.negative.lock().unwrap();
+                    negative.retain(|_, until| *until > now);
+                    negative.insert(hostname.to_string(), now + self.negative_ttl);
+                    return Err(no_record());
+                }
+                // validation failures are final, retrying won't sign the zone
+                ResolveErrorKind::Proto(proto) if self.dnssec && is_validation_error(proto.kind()) => {
+                    return Err(LookupError::Insecure { hostname: hostname.to_string(), reason: err.to_string() });
+                }
+                _ if attempt < self.attempts => {
+                    debug!("Looking up the challenge record for {hostname} failed, retrying in {backoff:?}: {err}");
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                    backoff *= 2;
+                }
+                _ => return Err(LookupError::Unavailable { hostname: hostname.to_string(), reason: err.to_string() }),
+            }
+        }
+    }
+}
+
+/// Messages the validating resolver fails lookups it can't validate with.
+/// Other errors it reports as messages too, so only these are final.
+const VALIDATION_FAILURES: &[&str] = &[
+    "could not validate negative response missing SOA",
+    "could not validate negative response with NSEC",
+    "no results to verify",
+    "Could not validate all DNSKEYs",
+    "self-signed dnskey is invalid",
+    "validation failed",
+    "revoked",
+    "is not a zone key",
+    "mismatched algorithm",
+];
+
+/// Whether a lookup failed DNSSEC validation, rather than on the network or
+/// the resolver.
+fn is_validation_error(kind: &ProtoErrorKind) -> bool {
+    match kind {
+        ProtoErrorKind::RrsigsNotPresent { .. } => true,
+        ProtoErrorKind::Message(message) => VALIDATION_FAILURES.contains(message),
+        _ => false,
+    }
+}
+
+/// An encrypted DNS upstream for a [ChallengeResolver].
+#[cfg(feature = "secure-dns")]
+pub struct SecureUpstream {
+    https: bool,
+    ips: Vec<IpAddr>,
+    port: u16,
+    tls_name: String,
+    pinned: Option<rustls::RootCertStore>,
+}
+
+#[cfg(feature = "secure-dns")]
+impl SecureUpstream {
+    /// The DNS-over-HTTPS endpoint at `url`, such as
+    /// `https://cloudflare-dns.com/dns-query`, reached at `ips`. The
+    /// addresses may be left out if the host of `url` is an address, and are
+    /// needed otherwise as resolving the host would go through the local
+    /// resolver. Only the standard `/dns-query` path is supported.
+    pub fn https(url: &str, ips: &[IpAddr]) -> io::Result<Self> {
+        let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidInput, reason);
+        let url = url::Url::parse(url).map_err(|e| invalid(format!("Invalid upstream URL {url}: {e}")))?;
+        if url.scheme() != "https" {
+            return Err(invalid(format!("Upstream URL {url} is not an https URL")));
+        }
+        if url.path() != "/dns-query" {
+            return Err(invalid(format!("Upstream URL {url} does not use the /dns-query path")));
+        }
+
+        let mut ips = ips.to_vec();
+        let tls_name = match url.host() {
+            Some(url::Host::Ipv4(ip)) if ips.is_empty() => {
+                ips.push(IpAddr::V4(ip));
+                ip.to_string()
+            }
+            Some(url::Host::Ipv6(ip)) if ips.is_empty() => {
+                ips.push(IpAddr::V6(ip));
+                ip.to_string()
+            }
+            Some(_) if ips.is_empty() => return Err(invalid(format!("No addresses given for upstream {url}"))),
+            Some(host) => host.to_string(),
+            None => return Err(invalid(format!("Upstream URL {url} has no host"))),
+        };
+
+        Ok(Self {
+            https: true,
+            ips,
+            port: url.port_or_known_default().unwrap_or(443),
+            tls_name,
+            pinned: None,
+        })
+    }
+
+    /// The DNS-over-TLS upstream presenting a certificate for `tls_name`,
+    /// reached at `ips` on port 853.
+    pub fn tls(tls_name: &str, ips: &[IpAddr]) -> Self {
+        Self {
+            https: false,
+            ips: ips.to_vec(),
+            port: 853,
+            tls_name: tls_name.to_string(),
+            pinned: None,
+        }
+    }
+
+    /// Only trust the PEM encoded `certificates`, such as the upstream's CA
+    /// or its self-signed certificate, instead of the public web PKI.
+    pub fn pin_certificates(mut self, certificates: &[u8]) -> io::Result<Self> {
+        self.pinned = Some(transport::pinned_roots(certificates)?);
+        Ok(self)
+    }
+}
+
+/// Look up every challenge record of `hostname`, reassembling keys split
+/// across several strings or records. Records that fail to parse are
+/// skipped, unless none is valid. Records holding a bare PEM key, the format
+/// used before [ChallengeRecord], are still accepted as non-expiring RSA
+/// keys.
+pub(crate) async fn lookup_challenge_records(resolver: &ChallengeResolver, hostname: &str) -> Result<Vec<ChallengeRecord>, LookupError> {
+    let key = hostname.to_ascii_lowercase();
+    if let Some((records, _)) = resolver.records.lock().unwrap().get(&key).filter(|(_, until)| *until > Instant::now()) {
+        debug!("Using the challenge records of {hostname} found before");
+        return Ok(records.clone());
+    }
+
+    info!("Looking up challenge record for {hostname}");
+    let started = Instant::now();
+    let txt_resp = resolver.txt_lookup(hostname).await;
+    metrics::challenge_lookup(started.elapsed(), txt_resp.is_ok());
+    let txt_resp = txt_resp?;
+
+    let mut texts = Vec::new();
+    let mut records = Vec::new();
+    let mut last_error = None;
+    for text in txt_resp {
+        debug!("Challenge record: {text}");
+        if text.trim_start().starts_with("-----BEGIN") {
+            warn!("Challenge record holds a bare PEM key, which is deprecated in favour of the v=osp1 format");
+            match PublicKey::from_pem(text.as_bytes()).and_then(|key| ChallengeRecord::rsa(&key, None)) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    warn!("Ignoring invalid challenge record of {hostname}: {e}");
+                    last_error = Some(e);
+                }
+            }
+        } else {
+            texts.push(text);
+        }
+    }
+
+    for record in parse_records(&texts) {
+        match record {
+            Ok(record) => records.push(record),
+            Err(e) => {
+                warn!("Ignoring invalid challenge record of {hostname}: {e}");
+                last_error = Some(e);
+            }
+        }
+    }
+
+    // without any valid record, why the last one was invalid is more useful
+    // than being told there are none
+    match last_error {
+        Some(e) if records.is_empty() => Err(LookupError::Invalid { hostname: hostname.to_string(), reason: e.to_string() }),
+        _ if records.is_empty() => Err(LookupError::NoRecord { hostname: hostname.to_string() }),
+        _ => {
+            if !resolver.record_ttl.is_zero() {
+                let now = Instant::now();
+                let mut cached = resolver.records.lock().unwrap();
+                cached.retain(|_, (_, until)| *until > now);
+                cached.insert(key, (records.clone(), now + resolver.record_ttl));
+            }
+            Ok(records)
+        }
+    }
+}
+
+/// Look up the key to challenge `hostname` with in its `_osp` DNS records.
+/// Expired keys are skipped, and RSA keys are preferred over Ed25519 ones,
+/// see [challenge](crate::connection::challenge). When several keys are
+/// usable the one that stays valid longest is picked.
+pub(crate) async fn lookup_challenge_key(resolver: &ChallengeResolver, hostname: &str) -> Result<ChallengeKey, LookupError> {
+    let records = lookup_challenge_records(resolver, hostname).await?;
+    let usable = records.iter()
+        .filter(|record| !record.is_expired())
+        .filter_map(|record| record.challenge_key().map(|key| {
+            let rsa = key.algorithm() == KeyAlgorithm::Rsa;
+            ((rsa, record.expires_at.unwrap_or(u64::MAX)), key)
+        }))
+        .max_by_key(|(preference, _)| *preference);
+    let invalid = |reason: &str| LookupError::Invalid { hostname: hostname.to_string(), reason: reason.to_string() };
+    match usable {
+        Some((_, key)) => {
+            info!("Challenge record found");
+            Ok(key)
+        }
+        None if records.iter().all(ChallengeRecord::is_expired) => Err(invalid("every key has expired")),
+        None => Err(invalid("no key to challenge it with")),
+    }
+}
+
+/// Look up the RSA key of `hostname`, which signs its identity document,
+/// like [lookup_challenge_key].
+#[cfg_attr(not(feature = "identity-fetch"), allow(dead_code))]
+pub(crate) async fn lookup_public_key(resolver: &ChallengeResolver, hostname: &str) -> Result<PublicKey, LookupError> {
+    match lookup_challenge_key(resolver, hostname).await? {
+        ChallengeKey::Rsa(key) => Ok(key),
+        ChallengeKey::Ed25519(_) => Err(LookupError::Invalid { hostname: hostname.to_string(), reason: "no RSA key".to_string() }),
+    }
+}
+
+/// Order the endpoints of SRV `records` of `(priority, weight, endpoint)`
+/// as RFC 2782 has it, picking among those of the same priority with
+/// `random` numbers in `0..1`.
+fn order_endpoints<T>(mut records: Vec<(u16, u16, T)>, mut random: impl FnMut() -> f64) -> Vec<T> {
+    // those weighing nothing go first, so they are only picked when all
+    // that are left weigh nothing
+    records.sort_by_key(|(priority, weight, _)| (*priority, *weight != 0));
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].0;
+        let same = records.iter().take_while(|(other, _, _)| *other == priority).count();
+        let total = records[..same].iter().map(|(_, weight, _)| u64::from(*weight)).sum::<u64>();
+        let pick = ((random() * (total + 1) as f64) as u64).min(total);
+        let mut running = 0;
+        let picked = records[..same].iter()
+            .position(|(_, weight, _)| {
+                running += u64::from(*weight);
+                running >= pick
+            })
+            .unwrap_or(0);
+        ordered.push(records.remove(picked).2);
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tokio::io;
+
+    use trust_dns_resolver::proto::error::ProtoErrorKind;
+
+    use crate::connection::challenge::ChallengeRecord;
+    use crate::connection::dns::{is_validation_error, lookup_public_key, order_endpoints, ChallengeResolver, LookupError};
+    use crate::crypto::PrivateKey;
+
+    #[test]
+    fn test_negative_cache() -> io::Result<()> {
+        let resolver = ChallengeResolver::default().negative_ttl(Duration::from_secs(60));
+        resolver.negative.lock().unwrap().insert("missing.test".to_string(), Instant::now() + Duration::from_secs(60));
+
+        let err = tokio::runtime::Runtime::new()?.block_on(lookup_public_key(&resolver, "missing.test")).unwrap_err();
+        assert!(matches!(err, LookupError::NoRecord { .. }));
+
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.get_ref().is_some_and(|inner| inner.downcast_ref::<LookupError>().is_some()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_cache() -> io::Result<()> {
+        let key = PrivateKey::generate(1024)?.public_key()?;
+        // nothing listens there, so only the cache can answer
+        let resolver = ChallengeResolver::nameservers(&["127.0.0.1:9".parse().unwrap()])
+            .timeout(Duration::from_millis(100))
+            .retries(1, Duration::ZERO);
+        let record = ChallengeRecord::rsa(&key, None)?;
+        resolver.records.lock().unwrap().insert("peer.test".to_string(), (vec![record], Instant::now() + Duration::from_secs(60)));
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let found = runtime.block_on(lookup_public_key(&resolver, "Peer.test"))?;
+        assert_eq!(found.to_der()?, key.to_der()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        assert!(is_validation_error(&ProtoErrorKind::Message("self-signed dnskey is invalid")));
+        assert!(!is_validation_error(&ProtoErrorKind::Message("no connections available")));
+        assert!(!is_validation_error(&ProtoErrorKind::Msg("connection refused".to_string())));
+    }
+
+    #[test]
+    fn test_override_endpoint() -> io::Result<()> {
+        // nothing listens there, so lookups fail and fall back
+        let resolver = ChallengeResolver::nameservers(&["127.0.0.1:9".parse().unwrap()])
+            .timeout(Duration::from_millis(100))
+            .override_endpoint("Peer.test", "node.peer.test", 57400);
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        assert_eq!(runtime.block_on(resolver.lookup_endpoints("peer.test")), [("node.peer.test".to_string(), 57400)]);
+        assert!(runtime.block_on(resolver.lookup_endpoints("other.test")).is_empty());
+        Ok(())
+    }
+
+    /// Endpoints are tried by priority, and by a pick weighted at random
+    /// among those of the same priority.
+    #[test]
+    fn test_order_endpoints() {
+        let records = vec![(10, 0, "a"), (10, 60, "b"), (10, 40, "c"), (20, 100, "d"), (5, 1, "e")];
+        assert_eq!(order_endpoints(records.clone(), || 0.0), ["e", "a", "b", "c", "d"]);
+        assert_eq!(order_endpoints(records, || 0.99), ["e", "c", "b", "a", "d"]);
+    }
+
+    #[test]
+    fn test_override_record() -> io::Result<()> {
+        let key = PrivateKey::generate(1024)?.public_key()?;
+        // nothing listens there, so only the override can answer
+        let resolver = ChallengeResolver::nameservers(&["127.0.0.1:9".parse().unwrap()])
+            .timeout(Duration::from_millis(100))
+            .retries(1, Duration::ZERO)
+            .override_record("Peer.test", &ChallengeRecord::rsa(&key, None)?);
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let found = runtime.block_on(lookup_public_key(&resolver, "peer.test"))?;
+        assert_eq!(found.to_der()?, key.to_der()?);
+        let err = runtime.block_on(lookup_public_key(&resolver, "other.test")).unwrap_err();
+        assert!(matches!(err, LookupError::Unavailable { .. }));
+        Ok(())
+    }
+}
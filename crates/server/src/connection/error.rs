@@ -0,0 +1,214 @@
+//! # Connection Errors
+//!
+//! Handshakes fail with a [HandshakeError] and requests in the transfer
+//! phase with a [TransferError]. Connections keep returning [io::Error]s, so
+//! both are converted into one of the [kind](HandshakeError::kind) of the
+//! failure, which callers holding an [io::Error] can downcast back to the
+//! error to tell the failures apart. Errors that already were an
+//! [io::Error], or a [LookupError], are converted back into what they were.
+
+use std::io;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use osp_protocol::{ProtocolVersion, VersionRange};
+use osp_protocol::packet::transfer::RejectCode;
+
+#[cfg(feature = "dns-auth")]
+use crate::connection::dns::LookupError;
+
+/// Why a handshake failed, on either side of it.
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    /// The peer sent a packet out of turn.
+    #[error("Expected {expected} packet")]
+    UnexpectedPacket {
+        expected: &'static str,
+    },
+    /// The peer has no protocol version in common with this node.
+    #[error("{peer} speaks protocol versions {versions}, this node speaks {}", VersionRange::SUPPORTED)]
+    UnsupportedVersion {
+        peer: String,
+        versions: VersionRange,
+    },
+    /// The guest answered the challenge for a different connection.
+    #[error("Invalid nonce")]
+    InvalidNonce,
+    /// The guest could not prove it holds the key it was challenged with.
+    #[error("Challenge failed")]
+    ChallengeFailed,
+    /// The host challenged this node with an Ed25519 key, but it has none.
+    #[error("{peer} challenged us with an Ed25519 key, but none is configured")]
+    MissingKey {
+        peer: String,
+    },
+    /// This node turned the guest away, for instance as its invite is
+    /// invalid.
+    #[error("{reason}")]
+    Denied {
+        reason: String,
+    },
+    /// The host turned this node away.
+    #[error("Handshake with {peer} was rejected")]
+    Rejected {
+        peer: String,
+    },
+    /// The challenge record of the guest could not be looked up.
+    #[cfg(feature = "dns-auth")]
+    #[error(transparent)]
+    Lookup(#[from] LookupError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl HandshakeError {
+    /// The kind of [io::Error] this converts into.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            HandshakeError::UnexpectedPacket { .. } => io::ErrorKind::InvalidInput,
+            HandshakeError::UnsupportedVersion { .. } => io::ErrorKind::Unsupported,
+            HandshakeError::InvalidNonce => io::ErrorKind::InvalidData,
+            HandshakeError::ChallengeFailed
+            | HandshakeError::MissingKey { .. }
+            | HandshakeError::Denied { .. }
+            | HandshakeError::Rejected { .. } => io::ErrorKind::PermissionDenied,
+            #[cfg(feature = "dns-auth")]
+            HandshakeError::Lookup(err) => err.kind(),
+            HandshakeError::Io(err) => err.kind(),
+        }
+    }
+}
+
+impl From<HandshakeError> for io::Error {
+    fn from(err: HandshakeError) -> Self {
+        match err {
+            #[cfg(feature = "dns-auth")]
+            HandshakeError::Lookup(err) => err.into(),
+            HandshakeError::Io(err) => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+/// Why a request in the transfer phase failed.
+#[derive(Debug, thiserror::Error)]
+pub enum TransferError {
+    /// The object was taken down, so it isn't pushed anymore.
+    #[error("Not pushing object {object_id}: it was taken down")]
+    TakenDown {
+        object_id: Uuid,
+    },
+    /// A policy of this node keeps the object from being pushed.
+    #[error("Not pushing object {object_id}: {reason}")]
+    Policy {
+        object_id: Uuid,
+        reason: String,
+    },
+    /// The peer's preferences exclude the object.
+    #[error("Not pushing object {object_id} to {peer}: {reason}")]
+    Excluded {
+        object_id: Uuid,
+        peer: String,
+        reason: String,
+    },
+    /// The object's audience doesn't include the peer.
+    #[error("Not pushing object {object_id} to {peer}: it is outside the object's audience")]
+    OutsideAudience {
+        object_id: Uuid,
+        peer: String,
+    },
+    /// The peer refused an object pushed to it.
+    #[error("{peer} refused object {object_id} ({code:?}): {reason}")]
+    Rejected {
+        object_id: Uuid,
+        peer: String,
+        code: RejectCode,
+        reason: String,
+    },
+    /// The peer did not acknowledge a pushed object in time.
+    #[error("{peer} did not acknowledge object {object_id} within {timeout:?}")]
+    Unacknowledged {
+        object_id: Uuid,
+        peer: String,
+        timeout: Duration,
+    },
+    /// The peer sent an object out of sequence.
+    #[error("Sequence violation: expected {expected}, got {actual}")]
+    SequenceViolation {
+        expected: u64,
+        actual: u64,
+    },
+    /// The peer did not answer in time.
+    #[error("{peer} did not describe type {type_id} within {timeout:?}")]
+    DeadlineExceeded {
+        peer: String,
+        type_id: Uuid,
+        timeout: Duration,
+    },
+    /// The peer shed the request as it is overloaded.
+    #[error("{peer} is overloaded, try again later")]
+    Overloaded {
+        peer: String,
+    },
+    /// The peer speaks a protocol version from before the request.
+    #[error("{peer} speaks protocol version {version}, {request} needs {required}")]
+    Unsupported {
+        peer: String,
+        request: &'static str,
+        version: ProtocolVersion,
+        required: ProtocolVersion,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl TransferError {
+    /// The kind of [io::Error] this converts into.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            TransferError::TakenDown { .. }
+            | TransferError::Policy { .. }
+            | TransferError::Excluded { .. }
+            | TransferError::OutsideAudience { .. } => io::ErrorKind::PermissionDenied,
+            // the peer may store it once the failure is fixed
+            TransferError::Rejected { code: RejectCode::Other, .. } => io::ErrorKind::Other,
+            TransferError::Rejected { .. } => io::ErrorKind::PermissionDenied,
+            TransferError::SequenceViolation { .. } => io::ErrorKind::InvalidData,
+            TransferError::DeadlineExceeded { .. } | TransferError::Unacknowledged { .. } => io::ErrorKind::TimedOut,
+            TransferError::Overloaded { .. } => io::ErrorKind::ResourceBusy,
+            TransferError::Unsupported { .. } => io::ErrorKind::Unsupported,
+            TransferError::Io(err) => err.kind(),
+        }
+    }
+}
+
+impl From<TransferError> for io::Error {
+    fn from(err: TransferError) -> Self {
+        match err {
+            TransferError::Io(err) => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use uuid::Uuid;
+
+    use crate::connection::error::{HandshakeError, TransferError};
+
+    #[test]
+    fn test_into_io_error() {
+        let err = io::Error::from(TransferError::TakenDown { object_id: Uuid::nil() });
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(matches!(err.get_ref().and_then(|inner| inner.downcast_ref()), Some(TransferError::TakenDown { .. })));
+
+        // io::Errors come back out as they went in
+        let err = io::Error::from(HandshakeError::from(io::Error::new(io::ErrorKind::TimedOut, "slow")));
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "slow");
+    }
+}
@@ -1,40 +1,160 @@
-use log::{debug, error, info};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use openssl::rand::rand_bytes;
-use openssl::rsa::{Padding, Rsa};
+use bytes::BytesMut;
+
+use log::{debug, error, info, warn, Level};
 
 use tokio::io;
 use tokio::net::TcpStream;
 
-use trust_dns_resolver::{TokioAsyncResolver};
-use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
-
 use uuid::Uuid;
 
-use osp_protocol::{ConnectionType, Protocol};
-use osp_protocol::packet::{PacketDecoder, PacketEncoder};
-use osp_protocol::packet::handshake::{HandshakePacketGuestToHost, HandshakePacketHostToGuest};
-use osp_protocol::packet::transfer::{TransferPacketGuestToHost, TransferPacketHostToGuest};
+use osp_data::HandlerError;
+use osp_protocol::{Closure, Compression, ConnectionType, Invite, KeepaliveTimer, PayloadFormat, Protocol, ProtocolError, ProtocolVersion, SensitivityFilter, VersionRange};
+use osp_protocol::packet::{PacketDecoder, PacketEncoder, SerializePacket};
+use osp_protocol::packet::handshake::{CloseReason, HandshakePacketGuestToHost, HandshakePacketHostToGuest};
+use osp_protocol::Envelope;
+use osp_protocol::packet::chunked::Reassembler;
+use osp_protocol::packet::transfer::{AckStatus, ReceiveWindow, RejectCode, SubscriptionState, TraceContext, TransferPacketGuestToHost, TransferPacketHostToGuest, NOTICE_VERSION, PUSH_WINDOW_VERSION};
+use osp_protocol::recording::Recorder;
+
+use crate::OSProtocolNode;
+use crate::connection::accounting::Cost;
+use crate::connection::admission::{AdmissionPermit, RequestKind};
+use crate::connection::diagnostics::{self, PeerDiagnostics};
+use crate::connection::error::HandshakeError;
+use crate::connection::registry::Activity;
+#[cfg(feature = "dns-auth")]
+use crate::connection::dns::{self, LookupError};
+use crate::connection::sync::SyncSession;
+use crate::dedup;
+use crate::delivery;
+use crate::embargo;
+use crate::connection::challenge::{self, ChallengeKey};
+use crate::reporting::{Fault, FaultKind};
+use crate::rollout::{Feature, Features};
+use crate::crypto;
+use crate::metrics;
+use crate::middleware::Verdict;
+use crate::routing::TopicFilter;
+use crate::store::DataStore;
+use crate::stream::StreamSender;
+use crate::trace;
+
+/// Streams a guest may have open at once.
+const MAX_OPEN_STREAMS: usize = 4;
+
+/// How many bytes of chunked pushes all guests together may be in the
+/// middle of unless configured otherwise, see
+/// [reassembly_budget](crate::OSProtocolNodeBuilder::reassembly_budget).
+pub const DEFAULT_REASSEMBLY_BUDGET: u64 = 1024 * 1024 * 1024;
 
 pub struct InboundConnection<TState> {
     connection_type: ConnectionType,
+    hostname: Option<String>,
     state: TState
 }
 
 pub struct HandshakeState {
     nonce: Uuid,
-    protocol: Protocol<HandshakePacketGuestToHost, HandshakePacketHostToGuest>
+    protocol: Protocol<HandshakePacketGuestToHost, HandshakePacketHostToGuest>,
+    preferences: SensitivityFilter,
+    /// The node accepting the connection, if any, which checks invites and
+    /// federation rules
+    node: Option<OSProtocolNode>,
+    /// What the guest told about itself, kept once it verified its hostname
+    diagnostics: Option<PeerDiagnostics>,
+    /// The rolled out features enabled for the connection
+    features: Features,
+    /// The sync session with the guest, loaded from the store of the node
+    /// once it verified its hostname
+    sync: Option<SyncSession>,
 }
 pub struct TransferState {
-    protocol: Protocol<TransferPacketGuestToHost, TransferPacketHostToGuest>
+    protocol: Protocol<TransferPacketGuestToHost, TransferPacketHostToGuest>,
+    sync: SyncSession,
+    /// Chunked pushes the peer is in the middle of.
+    reassembler: Reassembler,
+    /// Streams the peer is in the middle of, by transfer id. `None` for
+    /// those that were refused, whose chunks are dropped.
+    streams: HashMap<u32, Option<StreamSender>>,
+    /// Id of the last notice sent to the peer
+    last_notice: u64,
+    /// Where the activity of the connection is tracked, if it is listed in
+    /// the registry of a node
+    activity: Option<Arc<Activity>>,
 }
 
-impl From<InboundConnection<HandshakeState>> for InboundConnection<TransferState> {
-    fn from(value: InboundConnection<HandshakeState>) -> Self {
-        InboundConnection {
-            connection_type: value.connection_type,
+impl InboundConnection<HandshakeState> {
+    pub fn with_stream(stream: TcpStream) -> io::Result<Self> {
+        Ok(Self::with_protocol(Protocol::with_stream(stream)?))
+    }
+
+    /// Start the handshake on a connection already wrapped with its
+    /// [TransportSecurity](crate::connection::transport::TransportSecurity).
+    pub(crate) fn with_protocol(protocol: Protocol<HandshakePacketGuestToHost, HandshakePacketHostToGuest>) -> Self {
+        Self {
+            connection_type: ConnectionType::Unknown,
+            hostname: None,
+            state: HandshakeState {
+                nonce: Uuid::new_v4(),
+                protocol,
+                preferences: SensitivityFilter::default(),
+                node: None,
+                diagnostics: None,
+                features: Features::default(),
+                sync: None,
+            }
+        }
+    }
+
+    /// Accept the connection on behalf of `node`, which checks the peer
+    /// against its federation rules and the invites it issued.
+    pub(crate) fn with_node(mut self, node: OSProtocolNode) -> Self {
+        self.state.node = Some(node);
+        self
+    }
+
+    /// Only negotiate the rolled out features in `features`, see
+    /// [rollout](crate::rollout).
+    pub(crate) fn with_features(mut self, features: Features) -> Self {
+        self.state.features = features;
+        self
+    }
+
+    /// Record every packet of the connection to `recorder`, see
+    /// [recording](crate::recording).
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.state.protocol.record(recorder);
+        self
+    }
+
+    /// Announce `preferences` to the peer once it has verified itself, so it
+    /// doesn't push flagged objects we don't want.
+    pub fn with_preferences(mut self, preferences: SensitivityFilter) -> Self {
+        self.state.preferences = preferences;
+        self
+    }
+
+    /// Move a connection that completed the handshake into the transfer
+    /// phase, resuming the sync session with the peer from `store`.
+    pub fn into_transfer(self, store: Arc<dyn DataStore>) -> io::Result<InboundConnection<TransferState>> {
+        let hostname = self.hostname.ok_or(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "Handshake has not completed"
+        ))?;
+        let reassembler = match &self.state.node {
+            Some(node) => Reassembler::default().with_budget(node.reassembly_budget().clone()),
+            None => Reassembler::default(),
+        };
+
+        Ok(InboundConnection {
+            connection_type: self.connection_type,
+            hostname: Some(hostname.clone()),
             state: TransferState {
-                protocol: value.state.protocol.map_codecs(
+                protocol: self.state.protocol.map_codecs(
                     |_| {
                         PacketDecoder::new() // Transfer packet types implied!
                     },
@@ -42,114 +162,751 @@ impl From<InboundConnection<HandshakeState>> for InboundConnection<TransferState
                         PacketEncoder::new()
                     }
                 ),
+                sync: match self.state.sync {
+                    Some(sync) => sync,
+                    None => SyncSession::load(store, hostname)?,
+                },
+                reassembler,
+                streams: HashMap::new(),
+                last_notice: 0,
+                activity: None,
             },
-        }
+        })
     }
-}
 
-impl InboundConnection<HandshakeState> {
-    pub fn with_stream(stream: TcpStream) -> io::Result<Self> {
-        Ok(Self {
-            connection_type: ConnectionType::Unknown,
-            state: HandshakeState {
-                nonce: Uuid::new_v4(),
-                protocol: Protocol::with_stream(stream)?,
-            }
-        })
+    async fn send_close_err(&mut self, err: HandshakeError) -> io::Error {
+        self.send_close_reason(err, None).await
     }
 
-    async fn send_close_err(&mut self, error_kind: io::ErrorKind, err: String) -> io::Error {
-        error!("Closing connection with error: {}", err.clone());
+    /// [send_close_err](Self::send_close_err), telling the guest why in a
+    /// way it can act on.
+    async fn send_close_reason(&mut self, err: HandshakeError, reason: Option<CloseReason>) -> io::Error {
+        // the node logs why the handshake failed, sampled per peer
+        debug!("Closing connection with error: {err}");
         self.state.protocol.send_message(HandshakePacketHostToGuest::Close {
             can_continue: false,
-            err: Some(err.clone()),
+            err: Some(err.to_string()),
+            reason,
+            resumption: None,
         }).await.unwrap();
-        io::Error::new(error_kind, err)
+        err.into()
+    }
+
+    /// Turn the guest away before the handshake, telling it why in a Close
+    /// packet.
+    pub(crate) async fn refuse(mut self, reason: CloseReason, err: String) -> io::Result<()> {
+        self.state.protocol.send_message(HandshakePacketHostToGuest::Close {
+            can_continue: false,
+            err: Some(err),
+            reason: Some(reason),
+            resumption: None,
+        }).await
+    }
+
+    /// Look up the public key of `hostname` in its `_osp` DNS record,
+    /// closing the connection if there is none.
+    #[cfg(feature = "dns-auth")]
+    async fn lookup_public_key(&mut self, hostname: &str) -> io::Result<ChallengeKey> {
+        let resolver = self.state.node.as_ref().map(|node| node.resolver().clone()).unwrap_or_default();
+        match dns::lookup_challenge_key(&resolver, hostname).await {
+            Ok(pub_key) => Ok(pub_key),
+            Err(e) => {
+                let reason = matches!(e, LookupError::Insecure { .. }).then_some(CloseReason::InsecureChallengeRecord);
+                Err(self.send_close_reason(HandshakeError::Lookup(e), reason).await)
+            }
+        }
+    }
+
+    /// Without DNS authentication, peers have to present an invite.
+    #[cfg(not(feature = "dns-auth"))]
+    async fn lookup_public_key(&mut self, hostname: &str) -> io::Result<ChallengeKey> {
+        let reason = format!("{hostname} presented no invite, and this node does not look up challenge records");
+        Err(self.send_close_err(HandshakeError::Denied { reason }).await)
+    }
+
+    /// The public key to challenge `hostname` with: the one vouched for by
+    /// its invite if it presented one, otherwise the one in its DNS record.
+    async fn public_key(&mut self, hostname: &str, invite: Option<Invite>) -> io::Result<ChallengeKey> {
+        let invite_only = self.state.node.as_ref().is_some_and(|node| node.is_invite_only());
+        match (invite, &self.state.node) {
+            (Some(invite), Some(node)) => {
+                info!("{hostname} presented invite {}", invite.token_id);
+                match node.verify_invite(hostname, &invite) {
+                    Ok(pub_key) => Ok(ChallengeKey::Rsa(pub_key)),
+                    Err(e) => Err(self.send_close_err(HandshakeError::Denied { reason: format!("Invalid invite: {e}") }).await),
+                }
+            }
+            (Some(_), None) => {
+                Err(self.send_close_err(HandshakeError::Denied { reason: "This node does not accept invites".to_string() }).await)
+            }
+            (None, _) if invite_only => {
+                Err(self.send_close_err(HandshakeError::Denied { reason: "This node only accepts invited peers".to_string() }).await)
+            }
+            (None, _) => self.lookup_public_key(hostname).await,
+        }
     }
 
     pub async fn begin(&mut self) -> io::Result<()> {
-        if let HandshakePacketGuestToHost::Hello { connection_type } = self.state.protocol.read_frame().await? {
+        if let HandshakePacketGuestToHost::Hello { connection_type, version, compression, formats, software, timestamp } = self.state.protocol.read_frame().await? {
+            let received_at = diagnostics::now_millis();
             self.connection_type = connection_type;
+            let host_software = self.state.node.as_ref().map_or(diagnostics::SOFTWARE, |node| node.software()).to_string();
 
+            // the guest is the one to give up if it doesn't speak as far back
+            let guest = VersionRange { min: ProtocolVersion::INITIAL, max: version };
+            let Some(version) = VersionRange::SUPPORTED.negotiate(&guest) else {
+                let err = HandshakeError::UnsupportedVersion { peer: "The guest".to_string(), versions: guest };
+                self.state.protocol.send_message(HandshakePacketHostToGuest::Acknowledge {
+                    ok: false,
+                    err: Some(err.to_string()),
+                    versions: Some(VersionRange::SUPPORTED),
+                    subscription_lease: None,
+                    compression: Compression::None,
+                    format: PayloadFormat::default(),
+                    software: Some(host_software),
+                    timestamp: Some(diagnostics::now_millis()),
+                }).await?;
+                return Err(err.into());
+            };
+            // the host's preference wins, the guest's order is only a hint
+            let compression = match &self.state.node {
+                _ if !self.state.features.is_enabled(Feature::Compression) => Compression::None,
+                Some(node) => Compression::negotiate(node.compression(), &compression),
+                None => Compression::negotiate(&Compression::supported(), &compression),
+            };
+            let format = match &self.state.node {
+                _ if !self.state.features.is_enabled(Feature::PayloadFormats) => PayloadFormat::default(),
+                Some(node) => PayloadFormat::negotiate(node.payload_formats(), &formats),
+                None => PayloadFormat::negotiate(&PayloadFormat::ALL, &formats),
+            };
             self.state.protocol.send_message(HandshakePacketHostToGuest::Acknowledge {
                 ok: true,
-                err: None
+                err: None,
+                versions: Some(VersionRange::SUPPORTED),
+                subscription_lease: self.state.node.as_ref().and_then(|node| node.subscriptions().lease()),
+                compression,
+                format,
+                software: Some(host_software),
+                timestamp: Some(diagnostics::now_millis()),
             }).await?;
+            debug!("Speaking protocol version {version}, compressing with {compression:?}, objects in {format:?}");
+            self.state.protocol.set_version(version);
+            self.state.protocol.set_compression(compression);
+
+            if let HandshakePacketGuestToHost::Identify { hostname, invite } = self.state.protocol.read_frame().await? {
+                self.state.diagnostics = Some(PeerDiagnostics::new(&hostname, software, version, timestamp, received_at));
+                let federation = self.state.node.as_ref().map_or(Ok(()), |node| node.check_federation(&hostname));
+                if let Err(e) = federation {
+                    return Err(self.send_close_err(HandshakeError::Io(e)).await);
+                }
+                let pub_key = self.public_key(&hostname, invite).await?;
+
+                info!("Generating challenge bytes for a {:?} key", pub_key.algorithm());
+                let mut challenge_bytes = [0; 256];
+                crypto::random_bytes(&mut challenge_bytes)?;
+                let encrypted_challenge = match &pub_key {
+                    ChallengeKey::Rsa(key) => key.encrypt(&challenge_bytes)?,
+                    // the guest signs the challenge instead of decrypting it
+                    ChallengeKey::Ed25519(_) => challenge_bytes.to_vec(),
+                };
+
+                info!("Sending challenge bytes");
+                self.state.protocol.send_message(HandshakePacketHostToGuest::Challenge {
+                    encrypted_challenge,
+                    nonce: self.state.nonce,
+                    algorithm: pub_key.algorithm(),
+                }).await?;
+
+                if let HandshakePacketGuestToHost::Verify { challenge, nonce, signature, resumption } = self.state.protocol.read_frame().await? {
+                    info!("Received challenge verification");
+                    if nonce != self.state.nonce {
+                        error!("Challenge response had invalid nonce. Expected: {} Actual: {}. Rejecting...", self.state.nonce, nonce);
+                        return Err(self.send_close_err(HandshakeError::InvalidNonce).await);
+                    }
 
-            if let HandshakePacketGuestToHost::Identify { hostname } = self.state.protocol.read_frame().await? {
-                // todo: check whitelist/blacklist
-                info!("Looking up challenge record for {hostname}");
-                let resolver = TokioAsyncResolver::tokio(
-                    ResolverConfig::default(),
-                    ResolverOpts::default());
-                let txt_resp = resolver.txt_lookup(format!("_osp.{}", hostname)).await;
-                match txt_resp {
-                    Ok(txt_resp) => {
-                        if let Some(record) = txt_resp.iter().next() {
-                            info!("Challenge record found");
-                            debug!("Challenge record: {record}");
-                            let pub_key = Rsa::public_key_from_pem(record.to_string().as_bytes())?;
-
-                            info!("Generating and encrypting challenge bytes");
-                            let mut challenge_bytes = [0; 256];
-                            rand_bytes(&mut challenge_bytes).unwrap();
-                            let mut encrypted_challenge = vec![0u8; pub_key.size() as usize];
-                            pub_key.public_encrypt(&challenge_bytes, &mut encrypted_challenge, Padding::PKCS1)?;
-
-                            info!("Sending challenge bytes");
-                            self.state.protocol.send_message(HandshakePacketHostToGuest::Challenge {
-                                encrypted_challenge,
-                                nonce: self.state.nonce,
+                    let signed = match (&pub_key, signature) {
+                        (ChallengeKey::Rsa(_), _) => true,
+                        (ChallengeKey::Ed25519(key), Some(signature)) => {
+                            key.verify(&challenge::challenge_message(nonce, &challenge_bytes), &signature)?
+                        }
+                        (ChallengeKey::Ed25519(_), None) => false,
+                    };
+                    if signed && challenge == challenge_bytes {
+                        info!("Challenge verification successful");
+                        if !self.state.preferences.is_empty() {
+                            self.state.protocol.send_message(HandshakePacketHostToGuest::Preferences {
+                                filter: self.state.preferences.clone(),
                             }).await?;
+                        }
+                        // resume the sync session the guest kept, or tell it
+                        // to start over
+                        if let Some(node) = &self.state.node {
+                            let mut sync = SyncSession::load(node.data_store(), hostname.clone())?;
+                            sync.resume(resumption)?;
+                            self.state.sync = Some(sync);
+                        }
+                        self.state.protocol.send_message(HandshakePacketHostToGuest::Close {
+                            can_continue: true,
+                            err: None,
+                            reason: None,
+                            resumption: self.state.sync.as_ref().map(SyncSession::resumption_token),
+                        }).await?;
+                        debug!("Sent success packet.");
+                        if let (Some(node), Some(diagnostics)) = (&self.state.node, self.state.diagnostics.take()) {
+                            node.handshake_diagnostics().record(diagnostics);
+                        }
+                        self.hostname = Some(hostname);
+                        Ok(())
+                    } else {
+                        error!("Challenge failed as bytes did not match. Rejecting...");
+                        return Err(self.send_close_err(HandshakeError::ChallengeFailed).await)
+                    }
+                } else {
+                    return Err(self.send_close_err(HandshakeError::UnexpectedPacket { expected: "challenge verification" }).await);
+                }
+            } else {
+                return Err(self.send_close_err(HandshakeError::UnexpectedPacket { expected: "identify" }).await);
+            }
+        } else {
+            return Err(self.send_close_err(HandshakeError::UnexpectedPacket { expected: "hello" }).await);
+        }
+    }
+}
+
+impl InboundConnection<TransferState> {
+    /// A connection in the transfer phase with `hostname` over `protocol`,
+    /// with no handshake before it, for
+    /// [replaying](crate::OSProtocolNode::replay_recording) recordings.
+    pub(crate) fn replaying(
+        protocol: Protocol<TransferPacketGuestToHost, TransferPacketHostToGuest>,
+        store: Arc<dyn DataStore>,
+        hostname: &str,
+    ) -> io::Result<Self> {
+        Ok(InboundConnection {
+            connection_type: ConnectionType::Unknown,
+            hostname: Some(hostname.to_string()),
+            state: TransferState {
+                protocol,
+                sync: SyncSession::load(store, hostname.to_string())?,
+                reassembler: Reassembler::default(),
+                streams: HashMap::new(),
+                last_notice: 0,
+                activity: None,
+            },
+        })
+    }
+
+    /// Record every packet of the connection to `recorder` from now on, see
+    /// [recording](crate::recording).
+    pub(crate) fn record(&mut self, recorder: Recorder) {
+        self.state.protocol.record(recorder);
+    }
+
+    /// Keep `activity` up to date with the packets read and the transfers
+    /// open, for the node to tell idle connections from busy ones, see
+    /// [resources](crate::connection::resources).
+    pub(crate) fn track_activity(&mut self, activity: Arc<Activity>) {
+        self.state.activity = Some(activity);
+    }
+
+    /// The sync session with the connected peer.
+    pub fn sync(&mut self) -> &mut SyncSession {
+        &mut self.state.sync
+    }
+
+    /// Handle packets from the peer until it disconnects.
+    pub async fn serve(&mut self, node: &OSProtocolNode) -> io::Result<()> {
+        if self.state.protocol.version() >= PUSH_WINDOW_VERSION {
+            self.state.protocol.send_message(TransferPacketHostToGuest::PushWindow { pushes: node.push_window() }).await?;
+        }
+        let mut keepalive = KeepaliveTimer::new(node.keepalive(), self.state.protocol.version());
+        let mut notices = node.notices().subscribe();
+        loop {
+            if let Some(activity) = &self.state.activity {
+                activity.set_open_transfers(self.state.reassembler.open_transfers() + self.state.streams.len());
+            }
+            self.send_notices(node).await?;
+            // time spent handling the last packet, or sending, isn't the
+            // peer falling silent
+            keepalive.heard();
+            // notices published while waiting are sent right away
+            let published = async {
+                if notices.changed().await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            };
+            let packet = match keepalive.read_frame_until(&mut self.state.protocol, |nonce| TransferPacketHostToGuest::Ping { nonce }, published).await {
+                Ok(Some(packet)) => packet,
+                Ok(None) => continue,
+                Err(e) => {
+                    let Some(closure) = Closure::of(&e) else {
+                        return Err(e);
+                    };
+                    metrics::connection_closed(closure);
+                    match closure {
+                        Closure::Fin => {
+                            info!("{} disconnected", self.state.sync.hostname());
+                            // the peer may only have closed its side, and
+                            // still reads the answers written before
+                            if let Err(e) = self.state.protocol.close().await {
+                                debug!("Unable to close the connection with {}: {e}", self.state.sync.hostname());
+                            }
+                            return Ok(());
+                        }
+                        Closure::Reset => info!("{} reset the connection", self.state.sync.hostname()),
+                        Closure::Timeout => {
+                            info!("{} stopped responding, closing", self.state.sync.hostname());
+                            metrics::connection_timed_out();
+                        }
+                    }
+                    return Err(e);
+                }
+            };
+            if let Some(activity) = &self.state.activity {
+                activity.read();
+            }
 
-                            if let HandshakePacketGuestToHost::Verify { challenge, nonce } = self.state.protocol.read_frame().await? {
-                                info!("Received challenge verification");
-                                if nonce != self.state.nonce {
-                                    error!("Challenge response had invalid nonce. Expected: {} Actual: {}. Rejecting...", self.state.nonce, nonce);
-                                    return Err(self.send_close_err(io::ErrorKind::InvalidData, "Invalid nonce".to_string()).await);
-                                }
-
-                                if challenge == challenge_bytes {
-                                    info!("Challenge verification successful");
-                                    self.state.protocol.send_message(HandshakePacketHostToGuest::Close {
-                                        can_continue: true,
-                                        err: None,
-                                    }).await?;
-                                    debug!("Sent success packet.");
-                                    Ok(())
-                                } else {
-                                    error!("Challenge failed as bytes did not match. Rejecting...");
-                                    return Err(self.send_close_err(io::ErrorKind::PermissionDenied, "Challenge failed".to_string()).await)
-                                }
-                            } else {
-                                return Err(self.send_close_err(io::ErrorKind::InvalidInput, "Expected challenge verification packet".to_string()).await);
+            let request = u8::from(&packet);
+            match packet {
+                TransferPacketGuestToHost::Push { sequence, envelope, trace, ack } => {
+                    self.receive_push(node, sequence, envelope, trace, ack).await?;
+                }
+                // a malformed transfer closes the connection, as the chunks
+                // that follow it can't be made sense of
+                TransferPacketGuestToHost::PushBegin { transfer_id, sequence, total_length, trace, ack } => {
+                    self.state.reassembler.begin(transfer_id, sequence, total_length, trace, ack)?;
+                }
+                TransferPacketGuestToHost::PushChunk { transfer_id, data } => {
+                    self.state.reassembler.chunk(transfer_id, &data)?;
+                }
+                TransferPacketGuestToHost::PushEnd { transfer_id } => {
+                    let push = self.state.reassembler.end(transfer_id)?;
+                    self.receive_push(node, push.sequence, push.envelope, push.trace, push.ack).await?;
+                }
+                TransferPacketGuestToHost::StreamBegin { transfer_id, stream_id } => {
+                    self.begin_stream(node, transfer_id, stream_id).await?;
+                }
+                TransferPacketGuestToHost::StreamChunk { transfer_id, data } => {
+                    match self.state.streams.get_mut(&transfer_id) {
+                        Some(Some(stream)) => {
+                            if let Err(e) = stream.send(data).await {
+                                self.refuse_stream(transfer_id, e).await?;
                             }
-                        } else {
-                            return Err(
-                                self.send_close_err(
-                                    io::ErrorKind::InvalidData,
-                                    format!("Failed to resolve SRV record for {}. Is it located at _osp.{}?", hostname, hostname)
-                                ).await
-                            );
                         }
+                        Some(None) => {}
+                        None => return Err(ProtocolError::InvalidTransfer { transfer_id, reason: "not started".to_string() }.into()),
                     }
-                    Err(e) => {
-                        return Err(
-                            self.send_close_err(
-                                io::ErrorKind::Other,
-                                format!(
-                                    "Failed to resolve SRV record for {}. Is it located at _osp.{}?\n\nFurther Details: {}",
-                                    hostname, hostname, e.to_string()
-                                )
-                            ).await
+                }
+                TransferPacketGuestToHost::StreamEnd { transfer_id, aborted } => {
+                    self.end_stream(transfer_id, aborted).await?;
+                }
+                TransferPacketGuestToHost::DescribeType { type_id, deadline } => {
+                    let Some((_permit, deadline)) = self.admit(node, RequestKind::DescribeType, request, deadline).await? else {
+                        continue;
+                    };
+                    let schemas = node.clone();
+                    let answer = match within_deadline(deadline, move || schemas.schemas().local(type_id)).await? {
+                        Some(descriptor) => TransferPacketHostToGuest::TypeDescription { type_id, descriptor },
+                        None => {
+                            metrics::request_deadline_exceeded("describe_type");
+                            TransferPacketHostToGuest::DeadlineExceeded { id: type_id }
+                        }
+                    };
+                    self.answer(node, 1, answer).await?;
+                }
+                TransferPacketGuestToHost::Takedown { tombstone } => {
+                    let peer = self.state.sync.hostname();
+                    if !node.honors_takedowns() {
+                        warn!("Ignoring takedown of object {} sent by {peer}: {}", tombstone.object_id, tombstone.reason);
+                    } else if !node.accepts_takedown(&tombstone, peer)? {
+                        warn!(
+                            "Ignoring takedown of object {} sent by {peer}, which is neither its origin nor a takedown authority",
+                            tombstone.object_id,
                         );
+                    } else {
+                        node.apply_takedown(&tombstone, peer)?;
                     }
                 }
-            } else {
-                return Err(self.send_close_err(io::ErrorKind::InvalidInput, "Expected identify packet".to_string()).await);
+                TransferPacketGuestToHost::Delete { object_id } => {
+                    node.delete_object(object_id, self.state.sync.hostname())?;
+                }
+                TransferPacketGuestToHost::Subscribe { data_types, port, topics, qos, type_versions, deadline } => {
+                    let Some((_permit, deadline)) = self.admit(node, RequestKind::Subscribe, request, deadline).await? else {
+                        continue;
+                    };
+                    let query_units = (data_types.len() + topics.len()) as u64;
+                    let state = match topics.iter().map(|filter| filter.parse()).collect::<io::Result<Vec<TopicFilter>>>() {
+                        Ok(topics) => {
+                            let (subscriptions, peer) = (node.clone(), self.state.sync.hostname().to_string());
+                            within_deadline(deadline, move || {
+                                subscriptions.request_subscription(&peer, data_types, topics, qos, port, type_versions)
+                            }).await?.transpose()?
+                        }
+                        // the subscription the guest had is left as it was
+                        Err(e) => {
+                            warn!("Denying subscription of {}: {e}", self.state.sync.hostname());
+                            Some(SubscriptionState::Denied)
+                        }
+                    };
+                    let answer = match state {
+                        Some(state) => TransferPacketHostToGuest::Subscription { state, lease: node.subscriptions().lease(), qos },
+                        None => {
+                            metrics::request_deadline_exceeded("subscribe");
+                            TransferPacketHostToGuest::DeadlineExceeded { id: Uuid::nil() }
+                        }
+                    };
+                    self.answer(node, query_units, answer).await?;
+                }
+                // not admitted, it is cheap and saves the node work later
+                TransferPacketGuestToHost::Unsubscribe { data_types } => {
+                    node.subscriptions().unsubscribe(self.state.sync.hostname(), &data_types)?;
+                }
+                // not admitted either, a renewal that is shed would let the
+                // lease run out
+                TransferPacketGuestToHost::RenewSubscription { deadline } => {
+                    let (subscriptions, peer) = (node.clone(), self.state.sync.hostname().to_string());
+                    let answer = match within_deadline(deadline, move || subscriptions.subscriptions().renew(&peer)).await?.transpose()? {
+                        Some((state, qos)) => TransferPacketHostToGuest::Subscription { state, lease: node.subscriptions().lease(), qos },
+                        None => {
+                            metrics::request_deadline_exceeded("renew_subscription");
+                            TransferPacketHostToGuest::DeadlineExceeded { id: Uuid::nil() }
+                        }
+                    };
+                    self.answer(node, 1, answer).await?;
+                }
+                // not admitted, a window that is shed would leave deliveries
+                // to the guest paused
+                TransferPacketGuestToHost::Window { objects, bytes } => {
+                    let window = ReceiveWindow { objects, bytes };
+                    match node.subscriptions().advertise_window(self.state.sync.hostname(), window)? {
+                        true => node.window_advertised(self.state.sync.hostname())?,
+                        false => debug!("Ignoring the window of {}, it has no subscription", self.state.sync.hostname()),
+                    }
+                }
+                TransferPacketGuestToHost::Processed { sequence } => {
+                    let peer = self.state.sync.hostname();
+                    if !node.subscriptions().report_processed(peer, sequence)? {
+                        debug!("Ignoring processed report {sequence} of {peer}: it has no subscription, or wasn't sent that many objects");
+                        continue;
+                    }
+                    delivery::trim(node, peer, sequence)?;
+                    if let Some(lag) = node.subscriptions().lag(peer)? {
+                        metrics::peer_lag(peer, lag);
+                    }
+                }
+                TransferPacketGuestToHost::Delegate { delegation } => {
+                    let delegation_id = delegation.delegation_id;
+                    if let Err(e) = node.accept_delegation(self.state.sync.hostname(), delegation) {
+                        warn!("Refusing delegation {delegation_id} of {}: {e}", self.state.sync.hostname());
+                        self.send_nack(delegation_id, RejectCode::Policy, Some(e.to_string())).await?;
+                    }
+                }
+                TransferPacketGuestToHost::RevokeDelegation { delegation_id } => {
+                    if !node.revoke_delegation(self.state.sync.hostname(), delegation_id)? {
+                        debug!("Ignoring revocation of unknown delegation {delegation_id} by {}", self.state.sync.hostname());
+                    }
+                }
+                TransferPacketGuestToHost::Ping { nonce } => {
+                    self.state.protocol.send_message(TransferPacketHostToGuest::Pong { nonce }).await?;
+                }
+                // reading it was all it took
+                TransferPacketGuestToHost::Pong { .. } => {}
             }
+        }
+    }
+
+    /// Send the peer the notices published since the last it was sent.
+    async fn send_notices(&mut self, node: &OSProtocolNode) -> io::Result<()> {
+        if self.state.protocol.version() < NOTICE_VERSION {
+            return Ok(());
+        }
+        for published in node.notices().since(self.state.last_notice, embargo::now()) {
+            self.state.protocol.send_message(published.notice.to_packet()).await?;
+            self.state.last_notice = published.id;
+        }
+        Ok(())
+    }
+
+    /// Wait for the turn of a request of `kind` with packet id `request`,
+    /// returning the permit with what is left of the request's `deadline`.
+    /// Returns `None` if it was shed, because the node is overloaded, the
+    /// deadline passed while waiting, or the peer used up its fair share.
+    async fn admit(
+        &mut self,
+        node: &OSProtocolNode,
+        kind: RequestKind,
+        request: u8,
+        deadline: Option<Duration>,
+    ) -> io::Result<Option<(AdmissionPermit, Option<Duration>)>> {
+        if !node.accounting().allows(self.state.sync.hostname()) {
+            debug!("{} used up its fair share", self.state.sync.hostname());
+            self.shed(kind, request).await?;
+            return Ok(None);
+        }
+        let started = Instant::now();
+        let Some(permit) = node.admission().admit(kind, deadline).await else {
+            self.shed(kind, request).await?;
+            return Ok(None);
+        };
+        Ok(Some((permit, deadline.map(|deadline| deadline.saturating_sub(started.elapsed())))))
+    }
+
+    /// Send the answer to a request that looked up `query_units` items, and
+    /// charge the peer for it.
+    async fn answer(&mut self, node: &OSProtocolNode, query_units: u64, answer: TransferPacketHostToGuest) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        let bytes_served = answer.serialize(&mut buf)? as u64;
+        node.accounting().charge(self.state.sync.hostname(), Cost::query(query_units, bytes_served));
+        self.state.protocol.send_message(answer).await
+    }
+
+    /// Tell the peer its request with packet id `request` was shed.
+    async fn shed(&mut self, kind: RequestKind, request: u8) -> io::Result<()> {
+        debug!("Shedding {} request of {}", kind.name(), self.state.sync.hostname());
+        metrics::request_shed(kind.name());
+        self.state.protocol.send_message(TransferPacketHostToGuest::Overloaded { request }).await
+    }
+
+    /// Handle a pushed object in a span continuing the trace it was sent in,
+    /// acknowledging it afterwards if the guest asked for an `ack`.
+    async fn receive_push(
+        &mut self,
+        node: &OSProtocolNode,
+        sequence: u64,
+        mut envelope: Envelope,
+        remote: Option<TraceContext>,
+        ack: bool,
+    ) -> io::Result<()> {
+        let remote = if node.propagates_traces(self.state.sync.hostname()) {
+            remote.or(envelope.trace_parent)
         } else {
-            return Err(self.send_close_err(io::ErrorKind::InvalidInput, "Expected hello packet".to_string()).await);
+            // not stored either, so it isn't relayed
+            envelope.trace_parent = None;
+            None
+        };
+        let span = trace::Span::continue_remote("osp.receive", remote);
+        span.attribute("osp.object_id", envelope.object_id);
+        span.attribute("osp.peer", self.state.sync.hostname());
+        let object_id = envelope.object_id;
+        let status = span.instrument(self.handle_push(node, sequence, envelope)).await?;
+        // objects pushed again are acked as well, as the guest may have
+        // missed the first ack
+        if ack {
+            metrics::push_acked(status);
+            self.state.protocol.send_message(TransferPacketHostToGuest::Ack { sequence, object_id: Some(object_id), status }).await?;
+        }
+        Ok(())
+    }
+
+    /// Take in a pushed object, returning the status to acknowledge it
+    /// with.
+    async fn handle_push(&mut self, node: &OSProtocolNode, sequence: u64, mut envelope: Envelope) -> io::Result<AckStatus> {
+        if !self.state.sync.accept_sequence(sequence)? {
+            return Ok(AckStatus::Duplicate);
+        }
+
+        // objects of subscriptions the node made are what it mirrors
+        if node.is_read_only() && !node.subscriptions().solicited(self.state.sync.hostname(), envelope.type_id) {
+            debug!("Refusing object {} pushed to a read-only node", envelope.object_id);
+            return self.refuse(envelope.object_id, RejectCode::ReadOnly, None).await;
+        }
+
+        match node.route(&envelope, self.state.sync.hostname()) {
+            Ok(Verdict::Accept) => {}
+            Ok(Verdict::Tag(topic)) => {
+                debug!("Tagging object {} with topic {topic}", envelope.object_id);
+                envelope.topic = Some(topic);
+            }
+            Ok(Verdict::Drop(reason)) => {
+                debug!("Dropping object {}: {reason}", envelope.object_id);
+                return self.refuse(envelope.object_id, RejectCode::Policy, Some(reason)).await;
+            }
+            Err(e) => {
+                let peer = self.state.sync.hostname();
+                node.errors().report(Level::Error, peer, format_args!("Unable to route object {} from {peer}", envelope.object_id), &e);
+                return self.refuse(envelope.object_id, RejectCode::Other, Some(e.to_string())).await;
+            }
+        }
+
+        // relays in a mesh receive the same object along several paths
+        if node.relayed_already(&envelope)? {
+            debug!("Dropping object {} from {}, it was relayed already", envelope.object_id, envelope.origin);
+            return Ok(AckStatus::Duplicate);
+        }
+        // taken before the object is transformed, as its copies will be
+        let dedup_key = dedup::key(&envelope);
+        if node.dedup().is_duplicate(&dedup_key) {
+            debug!("Dropping object {} from {}, it was received already", envelope.object_id, self.state.sync.hostname());
+            return Ok(AckStatus::Duplicate);
+        }
+
+        if let Err(e) = node.validate(&envelope) {
+            return self.refuse(envelope.object_id, RejectCode::Invalid, Some(e.to_string())).await;
+        }
+
+        if let Err(e) = node.check_signature(&envelope).await {
+            debug!("Refusing object {} from {}: {e}", envelope.object_id, envelope.origin);
+            // the origin's record may just not be reachable right now
+            let code = match e.kind() {
+                io::ErrorKind::InvalidData => RejectCode::Invalid,
+                _ => RejectCode::Other,
+            };
+            return self.refuse(envelope.object_id, code, Some(e.to_string())).await;
+        }
+
+        let object_id = envelope.object_id;
+        let envelope = match node.prepare(envelope) {
+            Ok(envelope) => envelope,
+            Err(e) => return self.refuse(object_id, RejectCode::Invalid, Some(e.to_string())).await,
+        };
+
+        // an RSS item the node may have bridged before its publisher
+        // pushed it
+        let bridged_key = node.bridged_key(&envelope);
+        if let Some(key) = &bridged_key {
+            if node.data_store().bridged_item(&envelope.origin, key)?.is_some() {
+                debug!("Dropping object {}, its RSS item {key} was bridged already", envelope.object_id);
+                return Ok(AckStatus::Duplicate);
+            }
+        }
+
+        if let Err(e) = node.store_object(&envelope) {
+            let peer = self.state.sync.hostname();
+            node.errors().report(Level::Error, peer, format_args!("Unable to store object {} from {peer}", envelope.object_id), &e);
+            let code = match e.kind() {
+                io::ErrorKind::QuotaExceeded => RejectCode::QuotaExceeded,
+                io::ErrorKind::PermissionDenied => RejectCode::Policy,
+                _ => {
+                    node.report_fault(Fault::new(FaultKind::Internal, format!("Unable to store object: {e}"))
+                        .peer(Some(peer))
+                        .object(envelope.object_id));
+                    RejectCode::Other
+                }
+            };
+            return self.refuse(envelope.object_id, code, Some(e.to_string())).await;
+        }
+
+        debug!("Stored object {} from {}", envelope.object_id, self.state.sync.hostname());
+        let dispatched = node.dispatch(&envelope, self.state.sync.hostname()).await;
+        // objects a handler failed on stay stored, so they can be replayed
+        // once it is fixed, but ones it rejected are invalid
+        if let Err(HandlerError::Rejected(_)) = &dispatched {
+            debug!("Removing object {}, its handler rejected it", envelope.object_id);
+            node.data_store().remove_object(envelope.object_id)?;
+        } else if let Some(key) = &bridged_key {
+            node.data_store().put_bridged_item(&envelope.origin, key, envelope.object_id)?;
+        }
+        let status = match dispatched {
+            Ok(status) => status,
+            Err(e) => return self.refuse(envelope.object_id, reject_code(&e), Some(e.to_string())).await,
+        };
+        node.dedup().record(&dedup_key);
+
+        let peer = self.state.sync.hostname();
+        // read-only nodes mirror what they receive, they don't pass it on
+        if node.is_read_only() {
+            debug!("Not queueing object {} for subscribers of a read-only node", envelope.object_id);
+        } else if let Err(e) = node.syndicate(&envelope, Some(peer)) {
+            error!("Unable to queue object {} for subscribers: {e}", envelope.object_id);
+            node.report_fault(Fault::new(FaultKind::Internal, format!("Unable to queue object for subscribers: {e}"))
+                .peer(Some(peer))
+                .object(envelope.object_id));
+        }
+        node.send_to_nostr(&envelope);
+        node.send_to_matrix(&envelope);
+        Ok(status.into())
+    }
+
+    /// Start handing the stream `stream_id` to the node's stream handler,
+    /// or refuse it if the node takes no streams.
+    async fn begin_stream(&mut self, node: &OSProtocolNode, transfer_id: u32, stream_id: Uuid) -> io::Result<()> {
+        let invalid = |reason: String| io::Error::from(ProtocolError::InvalidTransfer { transfer_id, reason });
+        if self.state.streams.contains_key(&transfer_id) {
+            return Err(invalid("already started".to_string()));
+        }
+        if self.state.streams.len() >= MAX_OPEN_STREAMS {
+            return Err(invalid(format!("more than {MAX_OPEN_STREAMS} streams open")));
+        }
+        let stream = match node.stream_handler() {
+            _ if node.is_read_only() => {
+                debug!("Refusing stream {stream_id} sent to a read-only node");
+                self.send_nack(stream_id, RejectCode::ReadOnly, None).await?;
+                None
+            }
+            Some(handler) => Some(StreamSender::open(handler, stream_id, self.state.sync.hostname())),
+            None => {
+                self.send_nack(stream_id, RejectCode::Policy, Some("This node takes no streams".to_string())).await?;
+                None
+            }
+        };
+        self.state.streams.insert(transfer_id, stream);
+        Ok(())
+    }
+
+    /// End the stream with `transfer_id` and tell the guest whether the
+    /// handler took it in. Aborted streams aren't answered, the guest knows.
+    async fn end_stream(&mut self, transfer_id: u32, aborted: bool) -> io::Result<()> {
+        let Some(stream) = self.state.streams.remove(&transfer_id) else {
+            return Err(ProtocolError::InvalidTransfer { transfer_id, reason: "not started".to_string() }.into());
+        };
+        let Some(stream) = stream else {
+            return Ok(());
+        };
+        let stream_id = stream.stream_id();
+        match stream.finish(aborted).await {
+            _ if aborted => Ok(()),
+            Ok(()) => self.state.protocol.send_message(TransferPacketHostToGuest::StreamReceived { stream_id }).await,
+            Err(e) => self.send_nack(stream_id, reject_code(&e), Some(e.to_string())).await,
         }
     }
-}
\ No newline at end of file
+
+    /// Refuse the stream with `transfer_id` the handler couldn't take in
+    /// because of `err`, dropping the rest of its chunks.
+    async fn refuse_stream(&mut self, transfer_id: u32, err: HandlerError) -> io::Result<()> {
+        let Some(Some(stream)) = self.state.streams.insert(transfer_id, None) else {
+            return Ok(());
+        };
+        let stream_id = stream.cancel();
+        debug!("Refusing stream {stream_id} of {}: {err}", self.state.sync.hostname());
+        self.send_nack(stream_id, reject_code(&err), Some(err.to_string())).await
+    }
+
+    /// Refuse a pushed object, which is acknowledged as refused.
+    async fn refuse(&mut self, object_id: Uuid, code: RejectCode, reason: Option<String>) -> io::Result<AckStatus> {
+        self.send_nack(object_id, code, reason).await?;
+        Ok(AckStatus::Refused)
+    }
+
+    async fn send_nack(&mut self, object_id: Uuid, code: RejectCode, reason: Option<String>) -> io::Result<()> {
+        self.state.protocol.send_message(TransferPacketHostToGuest::Nack {
+            object_id,
+            code,
+            reason,
+        }).await
+    }
+}
+
+/// How a peer is told a handler failed with `err`.
+fn reject_code(err: &HandlerError) -> RejectCode {
+    match err {
+        HandlerError::Rejected(_) => RejectCode::Invalid,
+        HandlerError::Failed(_) => RejectCode::Other,
+    }
+}
+
+/// Run the work of a request on a blocking thread, as it may query the data
+/// store, and stop waiting for it once the guest's `deadline` passes.
+/// Returns `None` if it did. Work that started can't be stopped and runs to
+/// the end, but work still waiting for a blocking thread once the deadline
+/// passed is skipped.
+async fn within_deadline<T: Send + 'static>(deadline: Option<Duration>, work: impl FnOnce() -> T + Send + 'static) -> io::Result<Option<T>> {
+    let Some(deadline) = deadline else {
+        return tokio::task::spawn_blocking(work).await.map(Some).map_err(io::Error::other);
+    };
+    let expires = Instant::now() + deadline;
+    let task = tokio::task::spawn_blocking(move || (Instant::now() < expires).then(work));
+    match tokio::time::timeout_at(expires.into(), task).await {
+        Ok(result) => result.map_err(io::Error::other),
+        Err(_) => Ok(None),
+    }
+}
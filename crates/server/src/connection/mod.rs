@@ -1,4 +1,16 @@
 use osp_protocol::packet::{DeserializePacket, SerializePacket};
 
+pub mod accounting;
+pub mod admission;
+pub mod challenge;
+pub mod diagnostics;
+#[cfg(feature = "dns-auth")]
+pub mod dns;
+pub mod error;
 pub mod inbound;
 pub mod outbound;
+pub mod registry;
+pub mod resources;
+pub mod socket;
+pub mod sync;
+pub mod transport;
@@ -1,133 +1,1276 @@
-use tokio::io;
+use tokio::io::{self, AsyncRead};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
 
-use std::net::{IpAddr, SocketAddr};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
 
-use log::{error, info};
+use futures_util::Sink;
 
-use openssl::pkey::Private;
-use openssl::rsa::{Padding, Rsa};
+use log::{error, info, warn};
 
-use trust_dns_resolver::{TokioAsyncResolver};
-use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use uuid::Uuid;
 
-use osp_protocol::{ConnectionType, OSPUrl, Protocol};
-use osp_protocol::packet::handshake::{HandshakePacketGuestToHost, HandshakePacketHostToGuest};
+use osp_protocol::{Compression, ConnectionType, Delegation, Envelope, Invite, Keepalive, KeepaliveTimer, OSPUrl, PayloadFormat, Protocol, ProtocolVersion, Scheme, SensitivityFilter, Tombstone, TypeDescriptor, VersionRange, PROTOCOL_VERSION};
+use osp_protocol::packet::{chunked, PacketDecoder, PacketEncoder};
+use osp_protocol::packet::handshake::{CloseReason, HandshakePacketGuestToHost, HandshakePacketHostToGuest, KeyAlgorithm};
+use osp_protocol::packet::transfer::{AckStatus, DeliveryQos, ReceiveWindow, SubscriptionState, TransferPacketGuestToHost, TransferPacketHostToGuest, FANOUT_VERSION, FLOW_CONTROL_VERSION, KEEPALIVE_VERSION, PROCESSED_VERSION, PUSH_ACK_VERSION, PUSH_WINDOW_VERSION, STREAM_VERSION};
+use osp_protocol::recording::Recorder;
+
+use crate::connection::challenge;
+use crate::connection::diagnostics::{self, PeerDiagnostics};
+#[cfg(feature = "dns-auth")]
+use crate::connection::dns::ChallengeResolver;
+use crate::connection::error::{HandshakeError, TransferError};
+use crate::connection::socket::SocketOptions;
+use crate::connection::sync::SyncSession;
+use crate::connection::transport::TransportSecurity;
+use crate::OSProtocolNode;
+use crate::convert::Stage;
+use crate::crypto::{Ed25519Key, PrivateKey};
+use crate::notice::{self, Notice};
+use crate::store::DataStore;
+use crate::trace;
+
+/// How long requests wait for their answer unless configured otherwise.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What a peer answered to an object pushed with
+/// [push_with_receipt](OutboundConnection::push_with_receipt).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeliveryReceipt {
+    pub object_id: Uuid,
+    /// The sequence number the object was pushed with.
+    pub sequence: u64,
+    /// How the peer took in the object, `None` if it doesn't speak
+    /// [PUSH_ACK_VERSION], so the object was only sent.
+    pub status: Option<AckStatus>,
+}
+
+type ReceiptSender = oneshot::Sender<io::Result<DeliveryReceipt>>;
 
 pub struct OutboundConnection<TState> {
-    private_key: Rsa<Private>,
+    private_key: PrivateKey,
     hostname: String,
+    /// Hostname of the node we are connecting to
+    peer: String,
     addr: SocketAddr,
     state: TState
 }
 
-pub struct WaitingState {}
+pub struct WaitingState {
+    invite: Option<Invite>,
+    transport: TransportSecurity,
+    /// Whether the peer is connected to over raw TCP or a WebSocket
+    scheme: Scheme,
+    socket_options: SocketOptions,
+    ed25519_key: Option<Ed25519Key>,
+    compression: Vec<Compression>,
+    formats: Vec<PayloadFormat>,
+    software: String,
+    recorder: Option<Recorder>,
+    /// Token of the sync session we kept with the peer
+    resumption: Option<Uuid>,
+    /// Endpoints of the peer tried in turn should `addr` not take the
+    /// connection
+    fallbacks: Vec<SocketAddr>,
+}
 
 pub struct HandshakeState {
     protocol: Protocol<HandshakePacketHostToGuest, HandshakePacketGuestToHost>, // packet types reversed
+    /// Invite issued by the peer, presented when identifying
+    invite: Option<Invite>,
+    /// Key to sign Ed25519 challenges with
+    ed25519_key: Option<Ed25519Key>,
+    /// Compression algorithms offered to the peer
+    compression: Vec<Compression>,
+    /// Payload formats offered to the peer
+    formats: Vec<PayloadFormat>,
+    /// The payload format the peer picked
+    format: PayloadFormat,
+    /// Flagged objects the peer announced it doesn't want
+    preferences: SensitivityFilter,
+    /// How long subscriptions to the peer last unless renewed
+    subscription_lease: Option<Duration>,
+    /// The software we tell the peer we run
+    software: String,
+    /// What the peer told about itself in its acknowledgement
+    diagnostics: Option<PeerDiagnostics>,
+    /// Token of the sync session we kept with the peer, presented when
+    /// verifying
+    resumption: Option<Uuid>,
+    /// Token of the sync session the peer keeps for us, sent once the
+    /// handshake succeeded
+    peer_resumption: Option<Uuid>,
+}
+
+pub struct TransferState {
+    protocol: Protocol<TransferPacketHostToGuest, TransferPacketGuestToHost>, // packet types reversed
+    sync: SyncSession,
+    preferences: SensitivityFilter,
+    subscription_lease: Option<Duration>,
+    /// The node the connection was opened by, if any
+    node: Option<OSProtocolNode>,
+    /// How long requests wait for their answer
+    request_timeout: Duration,
+    /// Id of the next chunked push
+    next_transfer_id: u32,
+    /// How the peer delivers to our subscription
+    subscription_qos: DeliveryQos,
+    /// The format objects are pushed in
+    format: PayloadFormat,
+    /// Receipts waiting for the peer's answer, by sequence number
+    receipts: HashMap<u64, (Uuid, ReceiptSender)>,
+    /// How many pushes the peer lets us have waiting for an ack, `None` if
+    /// it doesn't limit them
+    push_window: Option<u32>,
+    /// Whether the peer is yet to send its push window
+    awaiting_window: bool,
+    /// Sequence numbers of the pushes waiting for an ack
+    in_flight: HashSet<u64>,
+    /// When the peer was last heard from
+    keepalive: KeepaliveTimer,
 }
 
 impl OutboundConnection<WaitingState> {
-    pub async fn create(url: OSPUrl, private_key: Rsa<Private>, hostname: String) -> io::Result<Self> {
+    /// Connect to the node at `url`. With the `dns-auth` feature, it is
+    /// connected to at the endpoints the SRV records of its host name, if it
+    /// has any, see [ChallengeResolver::lookup_endpoints], as looked up by
+    /// public resolvers.
+    #[cfg(feature = "dns-auth")]
+    pub async fn create(url: OSPUrl, private_key: PrivateKey, hostname: String) -> io::Result<Self> {
+        Self::create_with_resolver(url, private_key, hostname, &ChallengeResolver::default()).await
+    }
+
+    /// Connect to the node at `url`, at the IPv4 address the system
+    /// resolver has for its host.
+    #[cfg(not(feature = "dns-auth"))]
+    pub async fn create(url: OSPUrl, private_key: PrivateKey, hostname: String) -> io::Result<Self> {
         info!("Resolving osp connection to {url}");
-        let resolver = TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default());
-
-        let ip_resp = resolver.ipv4_lookup(url.domain.clone()).await?;
-        if let Some(ip) = ip_resp.iter().next() {
-            info!("Lookup successful, opening connection");
-            Self::create_with_socket_addr(
-                SocketAddr::new(IpAddr::from(ip.0), url.port),
-                private_key,
-                hostname
-            )
-        } else {
-            error!("Lookup failed");
-            Err(io::Error::new(io::ErrorKind::NotConnected, format!("Failed to resolve address {}", url.domain)))
+        let addr = tokio::net::lookup_host((url.domain.as_str(), url.port)).await?.find(SocketAddr::is_ipv4);
+        let host = url.domain.clone();
+        Self::create_resolved(url, &host, addr, private_key, hostname)
+    }
+
+    /// Connect to the node at `url`, at the endpoints the SRV records of
+    /// its host name if it has any, see
+    /// [ChallengeResolver::lookup_endpoints], or at the port of `url`.
+    /// Should an endpoint not take the connection, [begin](Self::begin)
+    /// tries the next. `ws://` and `wss://` URLs are always connected to at
+    /// their port. Hosts are resolved to their IPv4 address with `resolver`.
+    #[cfg(feature = "dns-auth")]
+    pub async fn create_with_resolver(url: OSPUrl, private_key: PrivateKey, hostname: String, resolver: &ChallengeResolver) -> io::Result<Self> {
+        info!("Resolving osp connection to {url}");
+        // the SRV records name the endpoints for raw TCP
+        let mut endpoints = match url.scheme {
+            Scheme::Osp => resolver.lookup_endpoints(&url.domain).await,
+            Scheme::Ws | Scheme::Wss => Vec::new(),
+        };
+        if endpoints.is_empty() {
+            endpoints.push((url.domain.clone(), url.port));
         }
+        let mut addrs = Vec::with_capacity(endpoints.len());
+        let mut failed = None;
+        for (host, port) in &endpoints {
+            // endpoints whose host doesn't resolve are left out
+            match resolver.lookup_ipv4(host).await {
+                Ok(ip) => addrs.extend(ip.map(|ip| SocketAddr::new(ip, *port))),
+                Err(e) => failed = Some(e),
+            }
+        }
+        if let (true, Some(e)) = (addrs.is_empty(), failed) {
+            return Err(e);
+        }
+        let mut addrs = addrs.into_iter();
+        let mut conn = Self::create_resolved(url, &endpoints[0].0, addrs.next(), private_key, hostname)?;
+        conn.state.fallbacks = addrs.collect();
+        Ok(conn)
     }
 
-    pub fn create_with_socket_addr(addr: SocketAddr, private_key: Rsa<Private>, hostname: String) -> io::Result<Self> {
+    /// Connect to the node at `url`, which was resolved to `addr` by the
+    /// address of `host`, if it has one.
+    fn create_resolved(url: OSPUrl, host: &str, addr: Option<SocketAddr>, private_key: PrivateKey, hostname: String) -> io::Result<Self> {
+        let Some(addr) = addr else {
+            error!("Lookup failed");
+            return Err(io::Error::new(io::ErrorKind::NotConnected, format!("Failed to resolve address {host}")));
+        };
+        info!("Lookup successful, opening connection");
+        let mut conn = Self::create_with_socket_addr(addr, private_key, hostname)?;
+        conn.peer = url.domain;
+        conn.state.scheme = url.scheme;
+        Ok(conn)
+    }
+
+    pub fn create_with_socket_addr(addr: SocketAddr, private_key: PrivateKey, hostname: String) -> io::Result<Self> {
         info!("Opening connection to {addr}");
 
         Ok(Self {
             private_key,
             hostname,
+            peer: addr.ip().to_string(),
             addr,
-            state: WaitingState {}
+            state: WaitingState {
+                invite: None,
+                transport: TransportSecurity::Plaintext,
+                scheme: Scheme::Osp,
+                socket_options: SocketOptions::default(),
+                ed25519_key: None,
+                compression: Compression::supported(),
+                formats: PayloadFormat::ALL.to_vec(),
+                software: diagnostics::SOFTWARE.to_string(),
+                recorder: None,
+                resumption: None,
+                fallbacks: Vec::new(),
+            }
         })
     }
 
+    /// Present `invite` to the peer when identifying, for peers that only
+    /// accept invited nodes or to federate without an `_osp` DNS record.
+    pub fn with_invite(mut self, invite: Invite) -> Self {
+        self.state.invite = Some(invite);
+        self
+    }
+
+    /// Answer challenges of peers that challenge us with an Ed25519 key,
+    /// because our `_osp` record publishes one, by signing with `key`.
+    pub fn with_ed25519_key(mut self, key: Ed25519Key) -> Self {
+        self.state.ed25519_key = Some(key);
+        self
+    }
+
+    /// Offer the peer to compress frames with one of `compression`, the
+    /// preferred first. Defaults to every algorithm this build supports.
+    pub fn with_compression(mut self, compression: Vec<Compression>) -> Self {
+        self.state.compression = compression;
+        self
+    }
+
+    /// Offer the peer to push objects in one of `formats`, the preferred
+    /// first. Defaults to every format.
+    pub fn with_formats(mut self, formats: Vec<PayloadFormat>) -> Self {
+        self.state.formats = formats;
+        self
+    }
+
+    /// Tell the peer we run `software`. Defaults to
+    /// [SOFTWARE](diagnostics::SOFTWARE).
+    pub fn with_software(mut self, software: &str) -> Self {
+        self.state.software = software.to_string();
+        self
+    }
+
+    /// Secure the connection with `transport`. Defaults to plaintext.
+    pub fn with_transport(mut self, transport: TransportSecurity) -> Self {
+        self.state.transport = transport;
+        self
+    }
+
+    /// Open the connection with `options`, see
+    /// [socket](crate::connection::socket). Defaults to those of the system.
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.state.socket_options = options;
+        self
+    }
+
+    /// Record every packet of the connection to `recorder`, see
+    /// [recording](crate::recording).
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.state.recorder = Some(recorder);
+        self
+    }
+
+    /// Present the resumption `token` of the [SyncSession] we kept with the
+    /// peer, which it resumes if it kept the same one. Without one, peers
+    /// resume whatever session they kept.
+    pub fn with_resumption_token(mut self, token: Uuid) -> Self {
+        self.state.resumption = Some(token);
+        self
+    }
+
     pub async fn begin(&mut self) -> io::Result<OutboundConnection<HandshakeState>> {
         info!("Starting outbound connection");
+        let mut fallbacks = self.state.fallbacks.clone().into_iter();
+        let mut protocol = loop {
+            match self.state.transport.connect(self.addr, &self.peer, self.state.scheme, &self.state.socket_options).await {
+                Ok(protocol) => break protocol,
+                Err(e) => {
+                    let Some(next) = fallbacks.next() else {
+                        return Err(e);
+                    };
+                    warn!("Unable to connect to {} at {}, trying {next}: {e}", self.peer, self.addr);
+                    self.addr = next;
+                }
+            }
+        };
+        if let Some(recorder) = self.state.recorder.clone() {
+            protocol.record(recorder);
+        }
         Ok(OutboundConnection {
             private_key: self.private_key.clone(),
             hostname: self.hostname.clone(),
+            peer: self.peer.clone(),
             addr: self.addr.clone(),
             state: HandshakeState {
-                protocol: Protocol::connect(self.addr).await?,
+                protocol,
+                invite: self.state.invite.clone(),
+                ed25519_key: self.state.ed25519_key.clone(),
+                compression: self.state.compression.clone(),
+                formats: self.state.formats.clone(),
+                format: PayloadFormat::default(),
+                preferences: SensitivityFilter::default(),
+                subscription_lease: None,
+                software: self.state.software.clone(),
+                diagnostics: None,
+                resumption: self.state.resumption,
+                peer_resumption: None,
             },
         })
     }
 }
 
 impl OutboundConnection<HandshakeState> {
+    /// What the peer told about itself, once it acknowledged the handshake.
+    pub fn peer_diagnostics(&self) -> Option<&PeerDiagnostics> {
+        self.state.diagnostics.as_ref()
+    }
+
     async fn read_frame_and_handle_err(&mut self) -> io::Result<Option<HandshakePacketHostToGuest>> {
         let packet = self.state.protocol.read_frame().await?;
         match packet {
-            HandshakePacketHostToGuest::Close { can_continue: false, err } => {
+            HandshakePacketHostToGuest::Close { can_continue: false, err, reason, .. } => {
                 error!("Connection cannot continue.");
                 if let Some(msg) = err {
                     error!("Error message received: {msg}");
                 }
+                match reason {
+                    Some(CloseReason::InsecureChallengeRecord) => {
+                        error!("The peer requires our _osp challenge record to be signed with DNSSEC");
+                    }
+                    Some(CloseReason::TooManyConnections | CloseReason::RateLimited) => {
+                        error!("The peer is refusing connections from us for now, try again later");
+                    }
+                    Some(CloseReason::Other) | None => {}
+                }
                 Ok(None)
             },
             packet => Ok(Some(packet))
         }
     }
 
+    /// Perform the handshake as the guest. Fails if the host rejects us.
     pub async fn handshake(&mut self) -> io::Result<()> {
         let addr = self.addr;
         info!("<{addr}> Starting outbound handshake");
         let hostname = self.hostname.clone();
         let private_key = self.private_key.clone();
-        self.state.protocol.send_message(HandshakePacketGuestToHost::Hello { connection_type: ConnectionType::Server }).await?;
+        self.state.protocol.send_message(HandshakePacketGuestToHost::Hello {
+            connection_type: ConnectionType::Server,
+            version: PROTOCOL_VERSION,
+            compression: self.state.compression.clone(),
+            formats: self.state.formats.clone(),
+            software: Some(self.state.software.clone()),
+            timestamp: Some(diagnostics::now_millis()),
+        }).await?;
 
         if let Some(HandshakePacketHostToGuest::Acknowledge {
             ok,
-            err
+            err,
+            versions,
+            subscription_lease,
+            compression,
+            format,
+            software,
+            timestamp,
         }) = self.read_frame_and_handle_err().await? {
+            let received_at = diagnostics::now_millis();
             if ok {
-                info!("Handshake acknowledged");
+                let versions = versions.unwrap_or(VersionRange::INITIAL);
+                let Some(version) = VersionRange::SUPPORTED.negotiate(&versions) else {
+                    return Err(HandshakeError::UnsupportedVersion { peer: addr.to_string(), versions }.into());
+                };
+                info!("Handshake acknowledged, speaking protocol version {version}, compressing with {compression:?}, objects in {format:?}");
+                self.state.protocol.set_version(version);
+                self.state.protocol.set_compression(compression);
+                self.state.format = format;
+                self.state.subscription_lease = subscription_lease;
+                self.state.diagnostics = Some(PeerDiagnostics::new(&self.peer, software, version, timestamp, received_at));
+                let invite = self.state.invite.clone();
                 self.state.protocol.send_message(HandshakePacketGuestToHost::Identify {
                     hostname,
+                    invite,
                 }).await?;
 
                 if let Some(HandshakePacketHostToGuest::Challenge {
                     nonce,
-                    encrypted_challenge
+                    encrypted_challenge,
+                    algorithm,
                 }) = self.read_frame_and_handle_err().await? {
-                    info!("Challenge received, decrypting");
                     info!("Connection Nonce: {nonce}");
-                    let mut decrypt_buf = vec![0u8; private_key.size() as usize];
-                    private_key.private_decrypt(&*encrypted_challenge, &mut *decrypt_buf, Padding::PKCS1)?;
+                    let (challenge, signature) = match algorithm {
+                        KeyAlgorithm::Rsa => {
+                            info!("Challenge received, decrypting");
+                            (private_key.decrypt(&encrypted_challenge)?, None)
+                        }
+                        KeyAlgorithm::Ed25519 => {
+                            info!("Ed25519 challenge received, signing");
+                            let Some(key) = &self.state.ed25519_key else {
+                                return Err(HandshakeError::MissingKey { peer: addr.to_string() }.into());
+                            };
+                            let signature = key.sign(&challenge::challenge_message(nonce, &encrypted_challenge))?;
+                            (encrypted_challenge, Some(signature))
+                        }
+                    };
 
-                    info!("Sending decrypted challenge");
+                    info!("Sending challenge response");
                     self.state.protocol.send_message(HandshakePacketGuestToHost::Verify {
                         nonce,
-                        challenge: decrypt_buf,
+                        challenge,
+                        signature,
+                        resumption: self.state.resumption,
                     }).await?;
 
-                    if let Some(HandshakePacketHostToGuest::Close {
-                        can_continue: true,
-                        err: _,
-                    }) = self.read_frame_and_handle_err().await? {
-                        info!("Handshake successful!")
+                    let mut packet = self.read_frame_and_handle_err().await?;
+                    if let Some(HandshakePacketHostToGuest::Preferences { filter }) = packet {
+                        info!("Peer excludes flagged objects: {filter:?}");
+                        self.state.preferences = filter;
+                        packet = self.read_frame_and_handle_err().await?;
+                    }
+
+                    if let Some(HandshakePacketHostToGuest::Close { can_continue: true, resumption, .. }) = packet {
+                        info!("Handshake successful!");
+                        self.state.peer_resumption = resumption;
+                        return Ok(());
                     }
                 }
             } else {
                 error!("Hello failed: {}", err.unwrap());
             }
         }
+        Err(HandshakeError::Rejected { peer: addr.to_string() }.into())
+    }
+
+    /// Move a connection that completed the handshake into the transfer
+    /// phase, resuming the sync session with the peer from `store`, or
+    /// starting over if the peer started a new one.
+    pub fn into_transfer(self, store: Arc<dyn DataStore>) -> io::Result<OutboundConnection<TransferState>> {
+        let version = self.state.protocol.version();
+        let mut sync = SyncSession::load(store, self.peer.clone())?;
+        if let Some(token) = self.state.peer_resumption {
+            sync.adopt(token)?;
+        }
+        Ok(OutboundConnection {
+            private_key: self.private_key,
+            hostname: self.hostname,
+            peer: self.peer.clone(),
+            addr: self.addr,
+            state: TransferState {
+                protocol: self.state.protocol.map_codecs(
+                    |_| PacketDecoder::new(),
+                    |_| PacketEncoder::new(),
+                ),
+                sync,
+                preferences: self.state.preferences,
+                subscription_lease: self.state.subscription_lease,
+                node: None,
+                request_timeout: DEFAULT_REQUEST_TIMEOUT,
+                next_transfer_id: 0,
+                subscription_qos: DeliveryQos::default(),
+                format: self.state.format,
+                receipts: HashMap::new(),
+                push_window: None,
+                awaiting_window: version >= PUSH_WINDOW_VERSION,
+                in_flight: HashSet::new(),
+                keepalive: KeepaliveTimer::new(Keepalive::default(), version),
+            },
+        })
+    }
+}
+
+impl OutboundConnection<TransferState> {
+    /// The sync session with the connected peer.
+    pub fn sync(&mut self) -> &mut SyncSession {
+        &mut self.state.sync
+    }
+
+    /// Flagged objects the peer doesn't want. [push](Self::push) refuses to
+    /// send objects they exclude.
+    pub fn preferences(&self) -> &SensitivityFilter {
+        &self.state.preferences
+    }
+
+    /// The protocol version negotiated with the peer.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.state.protocol.version()
+    }
+
+    /// The compression frames to and from the peer are framed with.
+    pub fn compression(&self) -> Compression {
+        self.state.protocol.compression()
+    }
+
+    /// The format objects are pushed to the peer in, as it picked from
+    /// those we offered.
+    pub fn format(&self) -> PayloadFormat {
+        self.state.format
+    }
+
+    /// How long subscriptions to the peer last unless they are
+    /// [renewed](Self::renew_subscription), `None` if they don't expire.
+    pub fn subscription_lease(&self) -> Option<Duration> {
+        self.state.subscription_lease
+    }
+
+    /// How the peer delivers objects to our subscription, as it reported
+    /// when it last answered a subscription request or renewal.
+    pub fn subscription_qos(&self) -> DeliveryQos {
+        self.state.subscription_qos
+    }
+
+    /// How many pushes the peer lets us have waiting for an ack, `None` if it
+    /// doesn't limit them or didn't say yet, see [ready](Self::ready).
+    pub fn push_window(&self) -> Option<u32> {
+        self.state.push_window
+    }
+
+    /// How many pushes are waiting for the peer's ack.
+    pub fn pushes_in_flight(&self) -> usize {
+        self.state.in_flight.len()
+    }
+
+    /// Attach the connection to `node`, so pushed objects go through its
+    /// egress converters and fetched type descriptors are cached by it.
+    pub(crate) fn with_node(mut self, node: OSProtocolNode) -> Self {
+        let keepalive = node.keepalive();
+        self.state.node = Some(node);
+        self.with_keepalive(keepalive)
+    }
+
+    /// When the peer is pinged while we wait for its packets, and when the
+    /// connection is given up on with
+    /// [IdleTimeout](osp_protocol::ProtocolError::IdleTimeout) as the peer
+    /// stopped responding, see [Keepalive]. Pings the peer sends are
+    /// answered as packets are read, so connections held open without
+    /// reading should be [pinged](Self::ping) to keep the peer from closing
+    /// them. Defaults to [Keepalive::default], or the node's
+    /// [keepalive](crate::OSProtocolNodeBuilder::keepalive) for connections
+    /// it opened.
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.state.keepalive = KeepaliveTimer::new(keepalive, self.state.protocol.version());
+        self
+    }
+
+    /// How long requests such as [describe_type](Self::describe_type) wait
+    /// for their answer before failing with
+    /// [TimedOut](io::ErrorKind::TimedOut). The peer is told, so it stops
+    /// working on requests we gave up on. Defaults to 30 seconds.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.state.request_timeout = timeout;
+        self
+    }
+
+    /// Push an object to the peer, returning the sequence number it was sent
+    /// with. Objects the peer's [preferences](Self::preferences) exclude, or
+    /// whose [audience](osp_protocol::Audience) doesn't include the peer, are
+    /// refused with [io::ErrorKind::PermissionDenied].
+    pub async fn push(&mut self, envelope: Envelope) -> io::Result<u64> {
+        self.send_push(envelope, false).await
+    }
+
+    /// Wait until the peer's
+    /// [push window](TransferPacketHostToGuest::PushWindow) has room for
+    /// another push, reading its answers. Pushes wait on their own, this
+    /// lets callers hold off producing objects while the peer falls behind,
+    /// see [into_sink](Self::into_sink). Fails with
+    /// [TimedOut](io::ErrorKind::TimedOut) if there is no room within the
+    /// [request timeout](Self::with_request_timeout).
+    pub async fn ready(&mut self) -> io::Result<()> {
+        let timeout = self.state.request_timeout;
+        let ready = async {
+            while !self.has_room() {
+                let packet = self.read_frame().await?;
+                if let (false, TransferPacketHostToGuest::Nack { object_id, code, reason }) = (self.take_in(&packet), packet) {
+                    warn!("{} refused object {object_id} ({code:?}): {}", self.peer, reason.unwrap_or_default());
+                }
+            }
+            io::Result::Ok(())
+        };
+        tokio::time::timeout(timeout, ready).await.unwrap_or_else(|_| Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} did not acknowledge our pushes within {timeout:?}", self.peer),
+        )))
+    }
+
+    fn has_room(&self) -> bool {
+        !self.state.awaiting_window
+            && self.state.push_window.is_none_or(|pushes| self.state.in_flight.len() < pushes.max(1) as usize)
+    }
+
+    /// Push objects through a [Sink] of envelopes, which is only ready once
+    /// the peer's push window has room, see [ready](Self::ready).
+    pub fn into_sink(self) -> PushSink {
+        PushSink { conn: Some(self), pending: None }
+    }
+
+    /// [Push](Self::push) an object and wait until the peer acknowledges
+    /// it handled the object. Fails with [TransferError::Rejected] if the
+    /// peer refused it, or [TimedOut](io::ErrorKind::TimedOut) if there is
+    /// no ack within the [request timeout](Self::with_request_timeout).
+    /// Peers that don't speak [PUSH_ACK_VERSION] can't acknowledge
+    /// objects, so pushes to them are only sent.
+    pub async fn push_acked(&mut self, envelope: Envelope) -> io::Result<u64> {
+        let object_id = envelope.object_id;
+        let (sequence, receipt) = self.send_with_receipt(envelope).await?;
+        let timeout = self.state.request_timeout;
+        let acked = async {
+            while self.state.receipts.contains_key(&sequence) {
+                let packet = self.read_frame().await?;
+                if let (false, TransferPacketHostToGuest::Nack { object_id, code, reason }) = (self.take_in(&packet), packet) {
+                    warn!("{} refused object {object_id} ({code:?}): {}", self.peer, reason.unwrap_or_default());
+                }
+            }
+            io::Result::Ok(())
+        };
+        tokio::time::timeout(timeout, acked).await.unwrap_or_else(|_| Err(TransferError::Unacknowledged {
+            object_id,
+            peer: self.peer.clone(),
+            timeout,
+        }.into()))?;
+        Ok(receipt.await?.sequence)
+    }
+
+    /// [Push](Self::push) an object, returning a future of the
+    /// [DeliveryReceipt] the peer answers it with, so several objects can be
+    /// pushed before waiting for their receipts. Receipts resolve as the
+    /// connection reads the peer's answers, which every request waiting for
+    /// an answer does, as does [settle_receipts](Self::settle_receipts).
+    /// They fail like [push_acked](Self::push_acked), or with
+    /// [ConnectionAborted](io::ErrorKind::ConnectionAborted) if the
+    /// connection is dropped first.
+    pub async fn push_with_receipt(&mut self, envelope: Envelope) -> io::Result<impl Future<Output = io::Result<DeliveryReceipt>> + Send + 'static> {
+        Ok(self.send_with_receipt(envelope).await?.1)
+    }
+
+    /// Read the peer's answers until every pending
+    /// [receipt](Self::push_with_receipt) resolved. Fails with
+    /// [TimedOut](io::ErrorKind::TimedOut) if they don't within the
+    /// [request timeout](Self::with_request_timeout).
+    pub async fn settle_receipts(&mut self) -> io::Result<()> {
+        let timeout = self.state.request_timeout;
+        let settled = async {
+            while self.state.receipts.values().any(|(_, sender)| !sender.is_closed()) {
+                self.read_packet().await?;
+            }
+            Ok(())
+        };
+        tokio::time::timeout(timeout, settled).await.unwrap_or_else(|_| Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} did not acknowledge every object within {timeout:?}", self.peer),
+        )))
+    }
+
+    async fn send_with_receipt(&mut self, envelope: Envelope) -> io::Result<(u64, impl Future<Output = io::Result<DeliveryReceipt>> + Send + 'static)> {
+        let ack = self.state.protocol.version() >= PUSH_ACK_VERSION;
+        let object_id = envelope.object_id;
+        let sequence = self.send_push(envelope, ack).await?;
+        let (sender, receiver) = oneshot::channel();
+        if ack {
+            // receipts given up on are never answered
+            self.state.receipts.retain(|_, (_, sender)| !sender.is_closed());
+            self.state.receipts.insert(sequence, (object_id, sender));
+        } else {
+            let _ = sender.send(Ok(DeliveryReceipt { object_id, sequence, status: None }));
+        }
+        let (peer, timeout) = (self.peer.clone(), self.state.request_timeout);
+        let deadline = Instant::now() + timeout;
+        Ok((sequence, async move {
+            match tokio::time::timeout_at(deadline, receiver).await {
+                Ok(Ok(receipt)) => receipt,
+                Ok(Err(_)) => Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    format!("The connection to {peer} closed before it acknowledged object {object_id}"),
+                )),
+                Err(_) => Err(TransferError::Unacknowledged { object_id, peer, timeout }.into()),
+            }
+        }))
+    }
+
+    /// Resolve the receipt `packet` answers, if any, returning whether it
+    /// did.
+    fn settle(&mut self, packet: &TransferPacketHostToGuest) -> bool {
+        let (object_id, sender, result) = match packet {
+            TransferPacketHostToGuest::Ack { sequence, status, .. } => {
+                let Some((object_id, sender)) = self.state.receipts.remove(sequence) else { return false };
+                (object_id, sender, Ok(DeliveryReceipt { object_id, sequence: *sequence, status: Some(*status) }))
+            }
+            TransferPacketHostToGuest::Nack { object_id, code, reason } => {
+                // Nacks carry no sequence, but hosts answer pushes in order,
+                // so one for an object pushed more than once answers the
+                // earliest push still pending
+                let sequence = self.state.receipts.iter()
+                    .filter(|(_, (id, _))| id == object_id)
+                    .map(|(sequence, _)| *sequence)
+                    .min();
+                let Some(sequence) = sequence else { return false };
+                let (object_id, sender) = self.state.receipts.remove(&sequence).unwrap();
+                (object_id, sender, Err(TransferError::Rejected {
+                    object_id,
+                    peer: self.peer.clone(),
+                    code: *code,
+                    reason: reason.clone().unwrap_or_default(),
+                }.into()))
+            }
+            _ => return false,
+        };
+        if sender.send(result).is_err() {
+            warn!("Dropping the receipt of object {object_id} from {}, it was given up on", self.peer);
+        }
+        true
+    }
+
+    async fn send_push(&mut self, envelope: Envelope, ack: bool) -> io::Result<u64> {
+        let mut envelope = match &self.state.node {
+            Some(node) => {
+                if node.data_store().tombstone(envelope.object_id)?.is_some() {
+                    return Err(TransferError::TakenDown { object_id: envelope.object_id }.into());
+                }
+                let envelope = node.translate_for(&self.peer, node.convert(Stage::Egress, envelope)?)?;
+                let envelope = node.transcode(envelope, self.state.format);
+                node.check_policies(&envelope).map_err(|reason| TransferError::Policy {
+                    object_id: envelope.object_id,
+                    reason,
+                })?;
+                envelope
+            }
+            None => envelope,
+        };
+        // without a node, no community is known
+        let in_audience = match &self.state.node {
+            Some(node) => node.in_audience(&envelope, &self.peer),
+            None => envelope.audience.includes(&self.peer, |_| false),
+        };
+        if !in_audience {
+            return Err(TransferError::OutsideAudience {
+                object_id: envelope.object_id,
+                peer: self.peer.clone(),
+            }.into());
+        }
+        if let Some(reason) = self.state.preferences.excludes(&envelope.sensitivity) {
+            return Err(TransferError::Excluded {
+                object_id: envelope.object_id,
+                peer: self.peer.clone(),
+                reason,
+            }.into());
+        }
+        // pushes within the peer's window are acked, so we know when it has
+        // room again
+        self.ready().await?;
+        let ack = ack || self.state.push_window.is_some();
+        let sequence = self.state.sync.next_sequence()?;
+        // the peer continues the trace when it handles the object, relays
+        // continue the trace the object was first pushed in
+        let span = trace::Span::start_or_continue("osp.push", envelope.trace_parent);
+        span.attribute("osp.object_id", envelope.object_id);
+        span.attribute("osp.peer", &self.peer);
+        let mut trace = span.trace_context();
+        if self.state.node.as_ref().is_some_and(|node| !node.propagates_traces(&self.peer)) {
+            envelope.trace_parent = None;
+            trace = None;
+        }
+        envelope.trace_parent = envelope.trace_parent.or(trace);
+        // objects too large for one packet are pushed in chunks
+        let transfer_id = self.state.next_transfer_id;
+        self.state.next_transfer_id = transfer_id.wrapping_add(1);
+        for packet in chunked::push_packets(sequence, envelope, trace, ack, transfer_id, self.state.protocol.version())? {
+            self.send_request(packet).await?;
+        }
+        if ack {
+            self.state.in_flight.insert(sequence);
+        }
+        Ok(sequence)
+    }
+
+    /// Stream the bytes `reader` yields to the peer as the attachment with id
+    /// `stream_id`, see [stream](crate::stream), returning how many were
+    /// sent once the peer's stream handler took them all in. Fails with
+    /// [TransferError::Rejected] if the peer refused the stream,
+    /// [Unsupported](io::ErrorKind::Unsupported) if it doesn't speak
+    /// [STREAM_VERSION], or [TimedOut](io::ErrorKind::TimedOut) if it doesn't
+    /// answer within the [request timeout](Self::with_request_timeout) once
+    /// the stream ended. If reading fails, or the peer refuses the stream
+    /// before it ended, the stream is aborted.
+    pub async fn send_stream(&mut self, stream_id: Uuid, mut reader: impl AsyncRead + Unpin) -> io::Result<u64> {
+        let version = self.state.protocol.version();
+        if version < STREAM_VERSION {
+            return Err(TransferError::Unsupported {
+                peer: self.peer.clone(),
+                request: "streaming",
+                version,
+                required: STREAM_VERSION,
+            }.into());
+        }
+        let transfer_id = self.state.next_transfer_id;
+        self.state.next_transfer_id = transfer_id.wrapping_add(1);
+        self.send_request(TransferPacketGuestToHost::StreamBegin { transfer_id, stream_id }).await?;
+        let mut sent = 0;
+        loop {
+            // no use sending the rest of a stream the peer refused, reading
+            // is cancel safe, so this only takes in what already arrived
+            if let Ok(packet) = tokio::time::timeout(Duration::ZERO, self.read_packet()).await {
+                if let Some(Err(e)) = self.stream_answer(stream_id, packet?) {
+                    self.send_request(TransferPacketGuestToHost::StreamEnd { transfer_id, aborted: true }).await?;
+                    return Err(e);
+                }
+            }
+            let data = match chunked::read_chunk(&mut reader).await {
+                Ok(data) if data.is_empty() => break,
+                Ok(data) => data,
+                Err(e) => {
+                    self.send_request(TransferPacketGuestToHost::StreamEnd { transfer_id, aborted: true }).await?;
+                    return Err(e);
+                }
+            };
+            sent += data.len() as u64;
+            self.send_request(TransferPacketGuestToHost::StreamChunk { transfer_id, data }).await?;
+        }
+        self.send_request(TransferPacketGuestToHost::StreamEnd { transfer_id, aborted: false }).await?;
+
+        let timeout = self.state.request_timeout;
+        let received = async {
+            loop {
+                let packet = self.read_packet().await?;
+                if let Some(answer) = self.stream_answer(stream_id, packet) {
+                    return answer.map(|_| sent);
+                }
+            }
+        };
+        tokio::time::timeout(timeout, received).await.unwrap_or_else(|_| Err(TransferError::Unacknowledged {
+            object_id: stream_id,
+            peer: self.peer.clone(),
+            timeout,
+        }.into()))
+    }
+
+    /// What `packet` tells about the stream with id `stream_id`: `Ok` once
+    /// the peer took it in, an error if it refused it, `None` if nothing.
+    fn stream_answer(&self, stream_id: Uuid, packet: TransferPacketHostToGuest) -> Option<io::Result<()>> {
+        match packet {
+            TransferPacketHostToGuest::StreamReceived { stream_id: received } if received == stream_id => Some(Ok(())),
+            TransferPacketHostToGuest::Nack { object_id, code, reason } if object_id == stream_id => {
+                Some(Err(TransferError::Rejected {
+                    object_id,
+                    peer: self.peer.clone(),
+                    code,
+                    reason: reason.unwrap_or_default(),
+                }.into()))
+            }
+            TransferPacketHostToGuest::Nack { object_id, code, reason } => {
+                warn!("{} refused object {object_id} ({code:?}): {}", self.peer, reason.unwrap_or_default());
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Pass a takedown on to the peer, so it removes its copy of the object.
+    pub async fn push_takedown(&mut self, tombstone: Tombstone) -> io::Result<()> {
+        self.send_request(TransferPacketGuestToHost::Takedown { tombstone }).await
+    }
+
+    /// Tell the peer that an object originating from this node was deleted,
+    /// e.g. after [OSProtocolNode::purge_actor]. Peers ignore deletions of
+    /// objects that originate elsewhere.
+    pub async fn push_delete(&mut self, object_id: Uuid) -> io::Result<()> {
+        self.send_request(TransferPacketGuestToHost::Delete { object_id }).await
+    }
+
+    /// Ask the peer to describe the data type `type_id`, returning `None` if
+    /// it doesn't know the type. Fails with [TimedOut](io::ErrorKind::TimedOut)
+    /// if there is no answer within the
+    /// [request timeout](Self::with_request_timeout),
+    /// or with [ResourceBusy](io::ErrorKind::ResourceBusy) if the peer is too
+    /// busy.
+    pub async fn describe_type(&mut self, type_id: Uuid) -> io::Result<Option<TypeDescriptor>> {
+        let timeout = self.state.request_timeout;
+        let request = TransferPacketGuestToHost::DescribeType { type_id, deadline: Some(timeout) };
+        let request_id = u8::from(&request);
+        self.send_request(request).await?;
+        let deadline_exceeded = |peer: &str| io::Error::from(TransferError::DeadlineExceeded {
+            peer: peer.to_string(),
+            type_id,
+            timeout,
+        });
+        let answer = async {
+            loop {
+                match self.read_packet().await? {
+                    TransferPacketHostToGuest::TypeDescription { type_id: described, descriptor } if described == type_id => {
+                        if let (Some(node), Some(descriptor)) = (&self.state.node, &descriptor) {
+                            node.schemas().cache(&self.peer, descriptor.clone());
+                        }
+                        return Ok(descriptor);
+                    }
+                    TransferPacketHostToGuest::DeadlineExceeded { id } if id == type_id => {
+                        return Err(deadline_exceeded(&self.peer));
+                    }
+                    TransferPacketHostToGuest::Overloaded { request } if request == request_id => {
+                        return Err(overloaded(&self.peer));
+                    }
+                    TransferPacketHostToGuest::Nack { object_id, code, reason } => {
+                        warn!("{} refused object {object_id} ({code:?}): {}", self.peer, reason.unwrap_or_default());
+                    }
+                    _ => {}
+                }
+            }
+        };
+        match tokio::time::timeout(timeout, answer).await {
+            Ok(result) => result,
+            Err(_) => Err(deadline_exceeded(&self.peer)),
+        }
+    }
+
+    /// Subscribe to objects of `data_types` from the peer, returning whether
+    /// it approved the subscription. A pending subscription can be checked on
+    /// by subscribing again later. Fails with
+    /// [ResourceBusy](io::ErrorKind::ResourceBusy) if the peer is too busy.
+    ///
+    /// If the connection was opened by a node, the peer pushes new objects
+    /// of those types to the port it listens on.
+    pub async fn subscribe(&mut self, data_types: &[Uuid]) -> io::Result<SubscriptionState> {
+        self.subscribe_topics(data_types, &[]).await
+    }
+
+    /// [Subscribe](Self::subscribe) to objects of `data_types` published
+    /// under a topic matching one of `topics`, such as `blog/rust/*` or
+    /// `blog/#`, see [routing](crate::routing).
+    pub async fn subscribe_topics(&mut self, data_types: &[Uuid], topics: &[&str]) -> io::Result<SubscriptionState> {
+        self.subscribe_with(data_types, topics, DeliveryQos::default()).await
+    }
+
+    /// [Subscribe](Self::subscribe_topics) to objects of `data_types`
+    /// under `topics`, asking the peer to deliver them with `qos`. The level
+    /// it delivers with is kept as the
+    /// [subscription_qos](Self::subscription_qos).
+    pub async fn subscribe_with(&mut self, data_types: &[Uuid], topics: &[&str], qos: DeliveryQos) -> io::Result<SubscriptionState> {
+        let request = TransferPacketGuestToHost::Subscribe {
+            data_types: data_types.to_vec(),
+            port: self.state.node.as_ref().map(|node| node.port()),
+            topics: topics.iter().map(|topic| topic.to_string()).collect(),
+            qos,
+            type_versions: self.state.node.as_ref().map(|node| node.type_versions(data_types)).unwrap_or_default(),
+            deadline: Some(self.state.request_timeout),
+        };
+        let request_id = u8::from(&request);
+        self.send_request(request).await?;
+        let state = self.await_subscription(Some(request_id)).await?;
+        if let (Some(node), SubscriptionState::Pending | SubscriptionState::Approved) = (&self.state.node, state) {
+            node.subscriptions().subscribed_upstream(&self.peer, data_types);
+        }
+        Ok(state)
+    }
+
+    /// Extend the lease of our subscription to the peer, returning the state
+    /// of the subscription, which is
+    /// [Unsubscribed](SubscriptionState::Unsubscribed) if there is none to
+    /// renew. Subscriptions have to be renewed within the peer's
+    /// [subscription lease](Self::subscription_lease) to keep receiving
+    /// objects.
+    pub async fn renew_subscription(&mut self) -> io::Result<SubscriptionState> {
+        let deadline = Some(self.state.request_timeout);
+        self.send_request(TransferPacketGuestToHost::RenewSubscription { deadline }).await?;
+        self.await_subscription(None).await
+    }
+
+    /// Wait for the peer to answer a subscription request, which was shed
+    /// if the peer is overloaded with `request`. Fails with
+    /// [TimedOut](io::ErrorKind::TimedOut) if there is no answer within the
+    /// [request timeout](Self::with_request_timeout).
+    async fn await_subscription(&mut self, request: Option<u8>) -> io::Result<SubscriptionState> {
+        let (timeout, peer) = (self.state.request_timeout, self.peer.clone());
+        let answer = async {
+            loop {
+                match self.read_packet().await? {
+                    TransferPacketHostToGuest::Subscription { state, qos, .. } => {
+                        self.state.subscription_qos = qos;
+                        return Ok(state);
+                    }
+                    TransferPacketHostToGuest::DeadlineExceeded { id } if id.is_nil() => return Err(unanswered(&peer, timeout)),
+                    TransferPacketHostToGuest::Overloaded { request: shed } if Some(shed) == request => {
+                        return Err(overloaded(&self.peer));
+                    }
+                    TransferPacketHostToGuest::Nack { object_id, code, reason } => {
+                        warn!("{} refused object {object_id} ({code:?}): {}", self.peer, reason.unwrap_or_default());
+                    }
+                    _ => {}
+                }
+            }
+        };
+        tokio::time::timeout(timeout, answer).await.unwrap_or_else(|_| Err(unanswered(&peer, timeout)))
+    }
+
+    /// Advertise the receive window of our subscription to the peer, which
+    /// pauses delivering to this node once it is used up, until it is
+    /// advertised again. An [unbounded](ReceiveWindow::is_unbounded) window
+    /// lifts it. Fails with [Unsupported](io::ErrorKind::Unsupported) if the
+    /// peer doesn't speak [FLOW_CONTROL_VERSION].
+    pub async fn advertise_window(&mut self, window: ReceiveWindow) -> io::Result<()> {
+        let version = self.state.protocol.version();
+        if version < FLOW_CONTROL_VERSION {
+            return Err(TransferError::Unsupported {
+                peer: self.peer.clone(),
+                request: "advertising a receive window",
+                version,
+                required: FLOW_CONTROL_VERSION,
+            }.into());
+        }
+        self.send_request(TransferPacketGuestToHost::Window {
+            objects: window.objects,
+            bytes: window.bytes,
+        }).await
+    }
+
+    /// Report to the peer the highest sequence of the objects it delivered to
+    /// this node that were durably processed, returning it. Subscribers
+    /// report now and then, so the peer can tell how far behind they are and
+    /// stops retrying deliveries they took in. Fails with
+    /// [Unsupported](io::ErrorKind::Unsupported) if the peer doesn't speak
+    /// [PROCESSED_VERSION].
+    pub async fn report_processed(&mut self) -> io::Result<u64> {
+        let version = self.state.protocol.version();
+        if version < PROCESSED_VERSION {
+            return Err(TransferError::Unsupported {
+                peer: self.peer.clone(),
+                request: "reporting processed objects",
+                version,
+                required: PROCESSED_VERSION,
+            }.into());
+        }
+        let sequence = self.state.sync.processed()?;
+        self.send_request(TransferPacketGuestToHost::Processed { sequence }).await?;
+        Ok(sequence)
+    }
+
+    /// Make the peer a relay of this node's objects along `delegation`, see
+    /// [fanout](crate::fanout). The peer answers with a
+    /// [Nack](TransferPacketHostToGuest::Nack) for the delegation id if it
+    /// refuses it. Fails with [Unsupported](io::ErrorKind::Unsupported) if
+    /// the peer doesn't speak [FANOUT_VERSION].
+    pub async fn delegate(&mut self, delegation: &Delegation) -> io::Result<()> {
+        let version = self.state.protocol.version();
+        if version < FANOUT_VERSION {
+            return Err(TransferError::Unsupported {
+                peer: self.peer.clone(),
+                request: "relaying",
+                version,
+                required: FANOUT_VERSION,
+            }.into());
+        }
+        self.send_request(TransferPacketGuestToHost::Delegate { delegation: delegation.clone() }).await
+    }
+
+    /// Revoke the delegation with id `delegation_id` the peer relays along.
+    /// Fails with [Unsupported](io::ErrorKind::Unsupported) if the peer
+    /// doesn't speak [FANOUT_VERSION], so it can't relay anything.
+    pub async fn revoke_delegation(&mut self, delegation_id: Uuid) -> io::Result<()> {
+        let version = self.state.protocol.version();
+        if version < FANOUT_VERSION {
+            return Err(TransferError::Unsupported {
+                peer: self.peer.clone(),
+                request: "revoking a delegation",
+                version,
+                required: FANOUT_VERSION,
+            }.into());
+        }
+        self.send_request(TransferPacketGuestToHost::RevokeDelegation { delegation_id }).await
+    }
+
+    /// Stop receiving objects of `data_types` from the peer, or of every
+    /// type if `data_types` is empty.
+    pub async fn unsubscribe(&mut self, data_types: &[Uuid]) -> io::Result<()> {
+        self.send_request(TransferPacketGuestToHost::Unsubscribe {
+            data_types: data_types.to_vec(),
+        }).await?;
+        if let Some(node) = &self.state.node {
+            node.subscriptions().unsubscribed_upstream(&self.peer, data_types);
+        }
+        Ok(())
+    }
+
+    /// Read the next packet sent by the peer, resolving the
+    /// [receipt](Self::push_with_receipt) it answers, if any, and keeping
+    /// the [notice](crate::notice) or push window it is.
+    pub async fn read_packet(&mut self) -> io::Result<TransferPacketHostToGuest> {
+        let packet = self.read_frame().await?;
+        self.take_in(&packet);
+        Ok(packet)
+    }
+
+    /// Check the peer is still there, returning how long it took to answer.
+    /// Fails with [TransferError::Unsupported] if the peer doesn't speak
+    /// [KEEPALIVE_VERSION], or [TimedOut](io::ErrorKind::TimedOut) if there
+    /// is no answer within the [request timeout](Self::with_request_timeout).
+    pub async fn ping(&mut self) -> io::Result<Duration> {
+        let version = self.state.protocol.version();
+        if version < KEEPALIVE_VERSION {
+            return Err(TransferError::Unsupported {
+                peer: self.peer.clone(),
+                request: "ping",
+                version,
+                required: KEEPALIVE_VERSION,
+            }.into());
+        }
+        let nonce = self.state.keepalive.nonce();
+        let sent_at = Instant::now();
+        self.state.protocol.send_message(TransferPacketGuestToHost::Ping { nonce }).await?;
+        let timeout = self.state.request_timeout;
+        let pong = async {
+            loop {
+                let packet = self.read_frame().await?;
+                if matches!(packet, TransferPacketHostToGuest::Pong { nonce: answered } if answered == nonce) {
+                    return io::Result::Ok(sent_at.elapsed());
+                }
+                if let (false, TransferPacketHostToGuest::Nack { object_id, code, reason }) = (self.take_in(&packet), packet) {
+                    warn!("{} refused object {object_id} ({code:?}): {}", self.peer, reason.unwrap_or_default());
+                }
+            }
+        };
+        tokio::time::timeout(timeout, pong).await.unwrap_or_else(|_| Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} did not answer a ping within {timeout:?}", self.peer),
+        )))
+    }
+
+    /// Read the next packet from the peer, pinging it while it is silent
+    /// and answering its pings.
+    async fn read_frame(&mut self) -> io::Result<TransferPacketHostToGuest> {
+        loop {
+            match self.state.keepalive.read_frame(&mut self.state.protocol, |nonce| TransferPacketGuestToHost::Ping { nonce }).await? {
+                TransferPacketHostToGuest::Ping { nonce } => {
+                    self.state.protocol.send_message(TransferPacketGuestToHost::Pong { nonce }).await?;
+                }
+                packet => return Ok(packet),
+            }
+        }
+    }
+
+    /// Send the peer `packet`, restarting the idle clock: the time the peer
+    /// takes to answer isn't it falling silent. Pings mustn't be sent this
+    /// way, or a peer that died would never time out.
+    async fn send_request(&mut self, packet: TransferPacketGuestToHost) -> io::Result<()> {
+        self.state.protocol.send_message(packet).await?;
+        self.state.keepalive.heard();
+        Ok(())
+    }
+
+    /// Take in what `packet` tells about the connection, returning whether
+    /// it resolved a receipt.
+    fn take_in(&mut self, packet: &TransferPacketHostToGuest) -> bool {
+        match packet {
+            TransferPacketHostToGuest::Ack { sequence, .. } => {
+                self.state.in_flight.remove(sequence);
+            }
+            TransferPacketHostToGuest::PushWindow { pushes } => {
+                self.state.push_window = *pushes;
+                self.state.awaiting_window = false;
+            }
+            TransferPacketHostToGuest::Notice { .. } => self.receive_notice(packet),
+            _ => {}
+        }
+        self.settle(packet)
+    }
+
+    /// Keep the notice `packet` is, if any, with the node the connection is
+    /// attached to, or log it.
+    fn receive_notice(&self, packet: &TransferPacketHostToGuest) {
+        let TransferPacketHostToGuest::Notice { level, message, sunset_at } = packet else { return };
+        let notice = Notice { level: *level, message: message.clone(), sunset_at: *sunset_at };
+        match &self.state.node {
+            Some(node) => node.receive_notice(&self.peer, notice),
+            None => notice::log_notice(&self.peer, &notice),
+        }
+    }
+}
+
+type PendingPush = Pin<Box<dyn Future<Output = (OutboundConnection<TransferState>, io::Result<()>)> + Send>>;
+
+/// Pushes objects to a peer as a [Sink] of envelopes, see
+/// [into_sink](OutboundConnection::into_sink). Each object is
+/// [pushed](OutboundConnection::push) once the sink is ready, and flushing
+/// waits until the last one was sent.
+pub struct PushSink {
+    /// `None` while a push, or waiting for room, is pending
+    conn: Option<OutboundConnection<TransferState>>,
+    pending: Option<PendingPush>,
+}
+
+impl PushSink {
+    /// The connection back, or `None` if a push is still pending, so the
+    /// sink should be flushed first.
+    pub fn into_inner(self) -> Option<OutboundConnection<TransferState>> {
+        self.conn
+    }
+
+    fn start(&mut self, mut conn: OutboundConnection<TransferState>, push: Option<Envelope>) {
+        self.pending = Some(Box::pin(async move {
+            let result = match push {
+                Some(envelope) => conn.push(envelope).await.map(|_| ()),
+                None => conn.ready().await,
+            };
+            (conn, result)
+        }));
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(pending) = self.pending.as_mut() else { return Poll::Ready(Ok(())) };
+        let (conn, result) = ready!(pending.as_mut().poll(cx));
+        self.pending = None;
+        self.conn = Some(conn);
+        Poll::Ready(result)
+    }
+}
+
+impl Sink<Envelope> for PushSink {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        match self.conn.take() {
+            Some(conn) if conn.has_room() => {
+                self.conn = Some(conn);
+                Poll::Ready(Ok(()))
+            }
+            Some(conn) => {
+                self.start(conn, None);
+                self.poll_pending(cx)
+            }
+            None => unreachable!("the connection is back once nothing is pending"),
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, envelope: Envelope) -> io::Result<()> {
+        let conn = self.conn.take().ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "The push sink is not ready"))?;
+        self.start(conn, Some(envelope));
         Ok(())
     }
 
-}
\ No newline at end of file
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// The error for a request `peer` shed as it is overloaded.
+fn overloaded(peer: &str) -> io::Error {
+    TransferError::Overloaded { peer: peer.to_string() }.into()
+}
+
+fn unanswered(peer: &str, timeout: Duration) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("{peer} did not answer our subscription request within {timeout:?}"))
+}
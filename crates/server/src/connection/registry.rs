@@ -0,0 +1,480 @@
+//! # Connection Registry
+//!
+//! Every inbound connection a node accepts runs on its own task, supervised
+//! by a second task that logs a panic instead of letting it go unnoticed.
+//! While a connection is open it is listed in the node's
+//! [ConnectionRegistry], which operators can query and use to disconnect
+//! peers. [ConnectionLimits] bound how many connections are open at once,
+//! in total and from a single address, how fast an address may open them
+//! and how long each may stay open. Connections over a limit are told why
+//! in a Close packet before they are dropped.
+//!
+//! Operators can also raise the log level of a single connection to debug
+//! the peer on it, see [ConnectionRegistry::set_log_level].
+//!
+//! When the node runs out of files, the connection idle the longest is
+//! closed first, see [resources](crate::connection::resources).
+
+use std::collections::{hash_map, HashMap, VecDeque};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{info, LevelFilter};
+
+use tokio::task::AbortHandle;
+
+use crate::logging::ConnectionLog;
+
+/// Limits on the inbound connections of a node.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionLimits {
+    /// Connections accepted while this many are open are closed right away.
+    pub max_connections: Option<usize>,
+    /// Connections accepted while this many are open from the same address
+    /// are closed right away.
+    pub max_connections_per_ip: Option<usize>,
+    /// How fast a single address may open connections, if bounded.
+    pub rate_limit: Option<RateLimit>,
+    /// How long a peer has to complete the handshake.
+    pub handshake_timeout: Duration,
+    /// How long a connection may stay open in total, if bounded.
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_connections: None,
+            max_connections_per_ip: None,
+            rate_limit: None,
+            handshake_timeout: Duration::from_secs(30),
+            max_lifetime: None,
+        }
+    }
+}
+
+/// A token bucket per address: each connection takes a token, and an
+/// address starts with `burst` tokens and gets `per_second` back every
+/// second, up to `burst` again. IPv6 addresses share a bucket per /64, as
+/// that is what a single host is usually given.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    /// Whether an address can ever open a connection.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.burst > 0 && self.per_second > 0.0 && self.per_second.is_finite()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Most buckets kept at once. Beyond it the bucket updated the longest ago
+/// is dropped, letting its address start over with a full one.
+const MAX_BUCKETS: usize = 65536;
+
+#[derive(Default)]
+struct Buckets {
+    by_source: HashMap<IpAddr, Bucket>,
+    // least recently updated first, each bucket listed once with when it
+    // was updated as of listing it
+    order: VecDeque<(IpAddr, Instant)>,
+}
+
+impl Buckets {
+    /// Drop the buckets that filled up again, which take `full_after` to,
+    /// and the least recently updated beyond [MAX_BUCKETS].
+    fn prune(&mut self, now: Instant, full_after: Duration) {
+        while let Some((source, listed)) = self.order.front().copied() {
+            self.order.pop_front();
+            let Some(updated) = self.by_source.get(&source).map(|bucket| bucket.updated) else { continue };
+            if updated != listed {
+                // updated since it was listed
+                self.order.push_back((source, updated));
+                continue;
+            }
+            // a full bucket is no different from a missing one
+            if self.by_source.len() <= MAX_BUCKETS && now.saturating_duration_since(updated) < full_after {
+                self.order.push_front((source, listed));
+                break;
+            }
+            self.by_source.remove(&source);
+        }
+    }
+}
+
+/// The source a connection from `ip` is counted against: the address
+/// itself, or its /64 for IPv6.
+fn source(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128))),
+        ip => ip,
+    }
+}
+
+/// Tracks the [RateLimit] of every address that opened a connection
+/// recently.
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Take a token for a connection from `ip`. Returns whether there was
+    /// one.
+    pub(crate) fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let burst = self.limit.burst as f64;
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * self.limit.per_second).min(burst)
+        };
+
+        let full_after = Duration::try_from_secs_f64(burst / self.limit.per_second).unwrap_or(Duration::MAX);
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets { by_source, order } = &mut *buckets;
+        let bucket = match by_source.entry(source(ip)) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                order.push_back((*entry.key(), now));
+                entry.insert(Bucket { tokens: burst, updated: now })
+            }
+        };
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        buckets.prune(now, full_after);
+        allowed
+    }
+}
+
+/// What a connection is doing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionPhase {
+    Handshake,
+    Transfer,
+}
+
+/// An open inbound connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    /// Id of the connection, unique for the lifetime of the node.
+    pub id: u64,
+    pub remote_addr: SocketAddr,
+    /// Country the connection comes from, if known.
+    pub country: Option<String>,
+    /// Hostname of the peer, once it completed the handshake.
+    pub peer: Option<String>,
+    pub phase: ConnectionPhase,
+    /// Unix timestamp (seconds) the connection was accepted at.
+    pub opened_at: u64,
+    /// The level the log of the connection was raised to, if it was.
+    pub log_level: Option<LevelFilter>,
+    /// Unix timestamp (seconds) the last packet was read at, or the
+    /// connection was accepted at if none was yet.
+    pub active_at: u64,
+    /// Chunked pushes and streams the peer is in the middle of.
+    pub open_transfers: usize,
+}
+
+/// When a connection last read a packet, and how many transfers it is in
+/// the middle of, kept up to date by the task serving it.
+#[derive(Debug, Default)]
+pub(crate) struct Activity {
+    active_at: AtomicU64,
+    open_transfers: AtomicUsize,
+}
+
+impl Activity {
+    /// Note that a packet was read.
+    pub(crate) fn read(&self) {
+        self.active_at.store(now_secs(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_open_transfers(&self, open: usize) {
+        self.open_transfers.store(open, Ordering::Relaxed);
+    }
+}
+
+struct Entry {
+    info: ConnectionInfo,
+    abort: Option<AbortHandle>,
+    log: Arc<ConnectionLog>,
+    activity: Arc<Activity>,
+}
+
+impl Entry {
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            active_at: self.activity.active_at.load(Ordering::Relaxed),
+            open_transfers: self.activity.open_transfers.load(Ordering::Relaxed),
+            ..self.info.clone()
+        }
+    }
+}
+
+/// The open inbound connections of a node. Clones share the registry.
+#[derive(Clone, Default)]
+pub struct ConnectionRegistry {
+    next_id: Arc<AtomicU64>,
+    entries: Arc<Mutex<HashMap<u64, Entry>>>,
+}
+
+impl ConnectionRegistry {
+    /// Every open connection, oldest first.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections = self.entries.lock().unwrap().values()
+            .map(Entry::info)
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|info| info.id);
+        connections
+    }
+
+    /// Number of open connections.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of open connections from `ip`.
+    pub fn len_from(&self, ip: IpAddr) -> usize {
+        self.entries.lock().unwrap().values()
+            .filter(|entry| entry.info.remote_addr.ip() == ip)
+            .count()
+    }
+
+    /// Close the connection with id `id`. Returns whether it was open.
+    pub fn disconnect(&self, id: u64) -> bool {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(&id).and_then(|entry| entry.abort.as_ref()) {
+            Some(abort) => {
+                abort.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Log what happens on connection `id` at `level` and above, or at the
+    /// level of the node again if `None`, see
+    /// [Debugging a Connection](crate::logging#debugging-a-connection).
+    /// Returns whether it was open.
+    pub fn set_log_level(&self, id: u64, level: Option<LevelFilter>) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get_mut(&id) else {
+            return false;
+        };
+        entry.log.set_level(level);
+        entry.info.log_level = level;
+        match level {
+            Some(level) => info!("Logging connection {id} at {level}"),
+            None => info!("Logging connection {id} at the level of the node again"),
+        }
+        true
+    }
+
+    /// Close the connection that was idle the longest, of those that aren't
+    /// in the middle of a transfer. Returns what it was, if there was one.
+    pub(crate) fn shed_idle(&self) -> Option<ConnectionInfo> {
+        let entries = self.entries.lock().unwrap();
+        let idlest = entries.values()
+            .filter(|entry| entry.abort.is_some())
+            .map(Entry::info)
+            .filter(|info| info.open_transfers == 0)
+            .min_by_key(|info| (info.active_at, info.id))?;
+        entries[&idlest.id].abort.as_ref()?.abort();
+        Some(idlest)
+    }
+
+    /// Register a connection from `remote_addr`, listed until the returned
+    /// guard is dropped.
+    pub(crate) fn register(&self, remote_addr: SocketAddr, country: Option<String>) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = ConnectionInfo {
+            id,
+            remote_addr,
+            country,
+            peer: None,
+            phase: ConnectionPhase::Handshake,
+            opened_at: now_secs(),
+            log_level: None,
+            active_at: 0,
+            open_transfers: 0,
+        };
+        let activity = Arc::new(Activity::default());
+        activity.read();
+        self.entries.lock().unwrap().insert(id, Entry { info, abort: None, log: Arc::default(), activity });
+        ConnectionGuard {
+            id,
+            registry: self.clone(),
+        }
+    }
+
+    /// Let [disconnect](Self::disconnect) abort the task serving connection
+    /// `id` through `abort`.
+    pub(crate) fn register_task(&self, id: u64, abort: AbortHandle) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&id) {
+            entry.abort = Some(abort);
+        }
+    }
+}
+
+/// Keeps a connection listed in the registry. Dropping the guard, which
+/// also happens when the connection task panics or is aborted, removes it.
+pub(crate) struct ConnectionGuard {
+    id: u64,
+    registry: ConnectionRegistry,
+}
+
+impl ConnectionGuard {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The log of the connection, to [scope](ConnectionLog::scope) the task
+    /// serving it with.
+    pub(crate) fn log(&self) -> Arc<ConnectionLog> {
+        self.registry.entries.lock().unwrap().get(&self.id)
+            .map_or_else(Arc::default, |entry| entry.log.clone())
+    }
+
+    /// The activity of the connection, for the task serving it to keep up
+    /// to date.
+    pub(crate) fn activity(&self) -> Arc<Activity> {
+        self.registry.entries.lock().unwrap().get(&self.id)
+            .map_or_else(Arc::default, |entry| entry.activity.clone())
+    }
+
+    /// Record that the connection completed the handshake with `peer`.
+    pub(crate) fn transferring(&self, peer: &str) {
+        if let Some(entry) = self.registry.entries.lock().unwrap().get_mut(&self.id) {
+            entry.info.peer = Some(peer.to_string());
+            entry.info.phase = ConnectionPhase::Transfer;
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.registry.entries.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    use log::LevelFilter;
+
+    use crate::connection::registry::{ConnectionPhase, ConnectionRegistry, RateLimit, RateLimiter, MAX_BUCKETS};
+
+    #[test]
+    fn test_registry() -> std::io::Result<()> {
+        let registry = ConnectionRegistry::default();
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let first = registry.register(addr, None);
+        let second = registry.register(addr, Some("DE".to_string()));
+        second.transferring("peer.test");
+        assert_eq!(registry.len_from(addr.ip()), 2);
+        assert_eq!(registry.len_from("127.0.0.2".parse().unwrap()), 0);
+
+        let connections = registry.connections();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].phase, ConnectionPhase::Handshake);
+        assert_eq!(connections[1].peer.as_deref(), Some("peer.test"));
+        assert!(!registry.disconnect(first.id()));
+        assert!(registry.set_log_level(second.id(), Some(LevelFilter::Debug)));
+        assert_eq!(second.log().level(), Some(LevelFilter::Debug));
+        assert_eq!(registry.connections()[1].log_level, Some(LevelFilter::Debug));
+        assert!(!registry.set_log_level(99, None));
+
+        drop(first);
+        assert_eq!(registry.len(), 1);
+
+        let activity = second.activity();
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let task = tokio::spawn(async move {
+                let _second = second;
+                std::future::pending::<()>().await
+            });
+            registry.register_task(1, task.abort_handle());
+            // connections in the middle of a transfer aren't shed
+            activity.set_open_transfers(1);
+            assert!(registry.shed_idle().is_none());
+            activity.set_open_transfers(0);
+            assert_eq!(registry.shed_idle().map(|info| info.id), Some(1));
+            assert!(registry.disconnect(1));
+            assert!(task.await.unwrap_err().is_cancelled());
+        });
+        assert!(registry.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(RateLimit { burst: 2, per_second: 0.5 });
+        let ip = "127.0.0.1".parse().unwrap();
+        let start = Instant::now();
+        let allowed: Vec<bool> = (0..3).map(|_| limiter.allow_at(ip, start)).collect();
+        assert_eq!(allowed, [true, true, false]);
+        assert!(limiter.allow_at("127.0.0.2".parse().unwrap(), start));
+
+        // a token every two seconds
+        assert!(!limiter.allow_at(ip, start + Duration::from_secs(1)));
+        assert!(limiter.allow_at(ip, start + Duration::from_secs(2)));
+        assert!(!limiter.allow_at(ip, start + Duration::from_secs(2)));
+        // but never more than the burst
+        let later = start + Duration::from_secs(60);
+        let allowed: Vec<bool> = (0..3).map(|_| limiter.allow_at(ip, later)).collect();
+        assert_eq!(allowed, [true, true, false]);
+    }
+
+    #[test]
+    fn test_rate_limiter_buckets() {
+        let limiter = RateLimiter::new(RateLimit { burst: 1, per_second: 0.5 });
+        let start = Instant::now();
+        // addresses of the same /64 share a bucket
+        assert!(limiter.allow_at("2001:db8::1".parse().unwrap(), start));
+        assert!(!limiter.allow_at("2001:db8::2".parse().unwrap(), start));
+        assert!(limiter.allow_at("2001:db8:0:1::1".parse().unwrap(), start));
+
+        // never more than the cap, dropping the oldest
+        for i in 0..MAX_BUCKETS as u32 {
+            limiter.allow_at(IpAddr::from(i.to_be_bytes()), start);
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().by_source.len(), MAX_BUCKETS);
+        assert!(limiter.allow_at("2001:db8::1".parse().unwrap(), start));
+        // and buckets that filled up again are dropped
+        limiter.allow_at("127.0.0.1".parse().unwrap(), start + Duration::from_secs(2));
+        assert_eq!(limiter.buckets.lock().unwrap().by_source.len(), 1);
+    }
+}
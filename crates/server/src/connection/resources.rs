@@ -0,0 +1,223 @@
+//! # Resource Limits
+//!
+//! Every connection holds a file descriptor, as do the files of the data
+//! store, backups and recordings. A process that runs out of them can
+//! neither accept connections nor open the files the transfers it is in the
+//! middle of need, and fails far from the cause. A node counts the files it
+//! has open against the limit of the process, see [ResourceLimits]:
+//!
+//! - once [refuse_at](ResourceLimits::refuse_at) of the limit is open, new
+//!   connections are closed as soon as they are accepted, without telling
+//!   the peer why, which would hold on to the descriptor for longer.
+//! - once [shed_at](ResourceLimits::shed_at) of the limit is open, or
+//!   accepting a connection fails as there are no descriptors left, the
+//!   connection that was idle the longest is closed, so those in the middle
+//!   of a transfer can finish. Connections in the middle of a chunked push
+//!   or a stream are never closed to make room.
+//!
+//! The open files are counted every [SAMPLE_INTERVAL] while the node
+//! listens, rather than for every connection accepted, and at most one idle
+//! connection is closed per count, as closing it only shows in the next, so
+//! a flood of connections can't empty the node of idle ones.
+//!
+//! Moving between these is logged once, and the open files are reported as
+//! the `osp_open_files` and `osp_open_files_limit` gauges. Refused
+//! connections are counted in `osp_connections_refused_total`, closed ones
+//! in `osp_connections_shed_total`.
+//!
+//! Open files are only counted on Unix, see
+//! [open_files](crate::platform::open_files).
+
+use std::fmt::{self, Display};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use tokio::io;
+
+use crate::metrics;
+use crate::platform;
+
+/// How often the open files of a listening node are counted.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// [Resources::open] while the open files weren't counted.
+const NOT_COUNTED: u64 = u64::MAX;
+
+/// When a node stops accepting connections, and when it closes idle ones,
+/// as shares of the files the process may open.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResourceLimits {
+    /// Defaults to 0.9.
+    pub refuse_at: f64,
+    /// Defaults to 0.95.
+    pub shed_at: f64,
+}
+
+impl ResourceLimits {
+    /// Whether connections are refused before idle ones are closed, and
+    /// both happen before the limit is reached.
+    pub(crate) fn is_valid(&self) -> bool {
+        0.0 < self.refuse_at && self.refuse_at <= self.shed_at && self.shed_at <= 1.0
+    }
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        ResourceLimits {
+            refuse_at: 0.9,
+            shed_at: 0.95,
+        }
+    }
+}
+
+/// How close the node is to running out of files.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub(crate) enum Pressure {
+    Normal,
+    /// New connections are refused.
+    Refusing,
+    /// Idle connections are closed, and new ones refused.
+    Shedding,
+}
+
+/// How many files the process has open, and may open.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct FileUsage {
+    pub(crate) open: u64,
+    pub(crate) limit: u64,
+}
+
+impl Display for FileUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} files open", self.open, self.limit)
+    }
+}
+
+/// Tracks the open files of a node against its [ResourceLimits].
+pub(crate) struct Resources {
+    limits: ResourceLimits,
+    /// The limit of the process, read once as it doesn't change while it runs
+    limit: Option<u64>,
+    /// The files open when they were last counted, or [NOT_COUNTED]
+    open: AtomicU64,
+    /// The [Pressure] last seen, to log when it changes
+    pressure: AtomicU8,
+    /// When an idle connection was last closed to make room
+    shed_at: Mutex<Option<Instant>>,
+}
+
+impl Resources {
+    pub(crate) fn new(limits: ResourceLimits) -> Self {
+        Resources {
+            limits,
+            limit: platform::open_files_limit().ok().flatten(),
+            open: AtomicU64::new(NOT_COUNTED),
+            pressure: AtomicU8::new(Pressure::Normal as u8),
+            shed_at: Mutex::new(None),
+        }
+    }
+
+    /// Count the open files, if there is a limit to count them against.
+    pub(crate) fn sample(&self) {
+        let Some(limit) = self.limit else {
+            return;
+        };
+        let open = platform::open_files();
+        self.open.store(open.unwrap_or(NOT_COUNTED), Ordering::Relaxed);
+        if let Some(open) = open {
+            metrics::open_files(open, limit);
+        }
+    }
+
+    /// How close the node is to running out of files, and how many it had
+    /// open when they were last [counted](Self::sample), if that is known.
+    /// Logs when the pressure changes.
+    pub(crate) fn pressure(&self) -> (Pressure, Option<FileUsage>) {
+        let open = self.open.load(Ordering::Relaxed);
+        let Some(limit) = self.limit.filter(|_| open != NOT_COUNTED) else {
+            return (Pressure::Normal, None);
+        };
+        let usage = FileUsage { open, limit };
+        let pressure = self.pressure_at(usage);
+        if self.pressure.swap(pressure as u8, Ordering::Relaxed) != pressure as u8 {
+            match pressure {
+                Pressure::Normal => info!("{usage}, accepting connections again"),
+                Pressure::Refusing => warn!("{usage}, refusing new connections"),
+                Pressure::Shedding => warn!("{usage}, closing idle connections and refusing new ones"),
+            }
+        }
+        (pressure, Some(usage))
+    }
+
+    /// Whether an idle connection may be closed to make room, which is
+    /// once per [SAMPLE_INTERVAL].
+    pub(crate) fn may_shed(&self) -> bool {
+        let mut shed_at = self.shed_at.lock().unwrap();
+        if shed_at.is_some_and(|shed_at| shed_at.elapsed() < SAMPLE_INTERVAL) {
+            return false;
+        }
+        *shed_at = Some(Instant::now());
+        true
+    }
+
+    fn pressure_at(&self, usage: FileUsage) -> Pressure {
+        let share = usage.open as f64 / usage.limit as f64;
+        if share >= self.limits.shed_at {
+            Pressure::Shedding
+        } else if share >= self.limits.refuse_at {
+            Pressure::Refusing
+        } else {
+            Pressure::Normal
+        }
+    }
+}
+
+/// Count the open files of `resources` every [SAMPLE_INTERVAL], off the
+/// task accepting connections, as counting takes longer the more are open.
+pub(crate) async fn run(resources: Arc<Resources>) {
+    if resources.limit.is_none() {
+        return;
+    }
+    loop {
+        tokio::time::sleep(SAMPLE_INTERVAL).await;
+        let sampling = resources.clone();
+        if tokio::task::spawn_blocking(move || sampling.sample()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Whether `e` is the process or the system running out of file
+/// descriptors.
+#[cfg(unix)]
+pub(crate) fn is_exhausted(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+/// Whether `e` is the process or the system running out of file
+/// descriptors.
+#[cfg(not(unix))]
+pub(crate) fn is_exhausted(_e: &io::Error) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::resources::{FileUsage, Pressure, ResourceLimits, Resources};
+
+    #[test]
+    fn test_pressure() {
+        let resources = Resources::new(ResourceLimits::default());
+        assert_eq!(resources.pressure_at(FileUsage { open: 10, limit: 1024 }), Pressure::Normal);
+        assert_eq!(resources.pressure_at(FileUsage { open: 930, limit: 1024 }), Pressure::Refusing);
+        assert_eq!(resources.pressure_at(FileUsage { open: 1024, limit: 1024 }), Pressure::Shedding);
+        assert!(!ResourceLimits { refuse_at: 0.95, shed_at: 0.9 }.is_valid());
+        assert!(ResourceLimits::default().is_valid());
+        // a single idle connection is closed until the files are counted again
+        assert!(resources.may_shed());
+        assert!(!resources.may_shed());
+    }
+}
@@ -0,0 +1,189 @@
+//! # Socket Options
+//!
+//! The defaults of the operating system suit a home server on a residential
+//! line, not a relay moving large pushes over links with a high
+//! bandwidth-delay product, whose buffers cap the throughput of every
+//! connection, or a node behind a NAT that forgets idle connections. A node
+//! can be built with the [SocketOptions] of the connections it accepts,
+//! [listener_socket_options](crate::OSProtocolNodeBuilder::listener_socket_options),
+//! and of those it opens,
+//! [outbound_socket_options](crate::OSProtocolNodeBuilder::outbound_socket_options).
+//!
+//! Buffer sizes are set before the connection is established, on the
+//! listening socket for accepted connections, as the TCP window scale is
+//! agreed on in the handshake. The kernel may round them, Linux doubles
+//! them to account for its own bookkeeping.
+//!
+//! The [user timeout](SocketOptions::user_timeout) is only set on Linux and
+//! Android, keepalive [intervals](TcpKeepalive::interval) and
+//! [retries](TcpKeepalive::retries) only where the platform supports them,
+//! and are left at the defaults of the system elsewhere.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use socket2::SockRef;
+
+use tokio::io;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Connections waiting to be accepted, as with [TcpListener::bind].
+const BACKLOG: u32 = 1024;
+
+/// Options of the TCP sockets of a node, see the [module](self) docs.
+/// Options left out keep the defaults of the system.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SocketOptions {
+    /// Send segments right away rather than coalescing small writes
+    /// (`TCP_NODELAY`).
+    pub nodelay: Option<bool>,
+    /// Probe idle connections to notice peers that went away, and keep NAT
+    /// mappings alive.
+    pub keepalive: Option<TcpKeepalive>,
+    /// Bytes of the send buffer (`SO_SNDBUF`).
+    pub send_buffer: Option<usize>,
+    /// Bytes of the receive buffer (`SO_RCVBUF`), which bounds the TCP
+    /// window.
+    pub recv_buffer: Option<usize>,
+    /// How long written data may go unacknowledged before the connection
+    /// is dropped (`TCP_USER_TIMEOUT`).
+    pub user_timeout: Option<Duration>,
+}
+
+/// TCP keepalive probes, see [SocketOptions::keepalive].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TcpKeepalive {
+    /// How long a connection is idle before it is probed.
+    pub time: Duration,
+    /// How long to wait between unanswered probes.
+    pub interval: Option<Duration>,
+    /// How many probes go unanswered before the connection is dropped.
+    pub retries: Option<u32>,
+}
+
+impl SocketOptions {
+    /// Whether no option is zero, which the system would refuse or treat
+    /// as its default.
+    pub(crate) fn is_valid(&self) -> bool {
+        let keepalive = self.keepalive.is_none_or(|keepalive| {
+            !keepalive.time.is_zero()
+                && keepalive.interval.is_none_or(|interval| !interval.is_zero())
+                && keepalive.retries != Some(0)
+        });
+        keepalive
+            && self.send_buffer != Some(0)
+            && self.recv_buffer != Some(0)
+            && self.user_timeout.is_none_or(|timeout| !timeout.is_zero())
+    }
+
+    /// Listen on `addr` with the buffer sizes, which the connections
+    /// accepted on it inherit.
+    pub(crate) fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = new_socket(addr)?;
+        #[cfg(not(windows))]
+        socket.set_reuseaddr(true)?;
+        self.set_buffers(&socket)?;
+        socket.bind(addr)?;
+        socket.listen(BACKLOG)
+    }
+
+    /// Connect to `addr` with the options.
+    pub(crate) async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = new_socket(addr)?;
+        self.set_buffers(&socket)?;
+        let stream = socket.connect(addr).await?;
+        self.apply(&stream)?;
+        Ok(stream)
+    }
+
+    /// Set the options of an established connection, such as one just
+    /// accepted, but the buffer sizes, see [bind](Self::bind).
+    pub(crate) fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        if let Some(nodelay) = self.nodelay {
+            socket.set_tcp_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            socket.set_tcp_keepalive(&keepalive.params())?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(timeout) = self.user_timeout {
+            socket.set_tcp_user_timeout(Some(timeout))?;
+        }
+        Ok(())
+    }
+
+    fn set_buffers(&self, socket: &TcpSocket) -> io::Result<()> {
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(u32::try_from(size).unwrap_or(u32::MAX))?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(u32::try_from(size).unwrap_or(u32::MAX))?;
+        }
+        Ok(())
+    }
+}
+
+impl TcpKeepalive {
+    #[cfg_attr(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd", windows)), allow(unused_mut))]
+    fn params(&self) -> socket2::TcpKeepalive {
+        let mut params = socket2::TcpKeepalive::new().with_time(self.time);
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd", windows))]
+        if let Some(interval) = self.interval {
+            params = params.with_interval(interval);
+        }
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd", windows))]
+        if let Some(retries) = self.retries {
+            params = params.with_retries(retries);
+        }
+        params
+    }
+}
+
+fn new_socket(addr: SocketAddr) -> io::Result<TcpSocket> {
+    match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use socket2::SockRef;
+
+    use tokio::io;
+
+    use crate::connection::socket::{SocketOptions, TcpKeepalive};
+
+    #[test]
+    fn test_socket_options() -> io::Result<()> {
+        let options = SocketOptions {
+            nodelay: Some(true),
+            keepalive: Some(TcpKeepalive { time: Duration::from_secs(30), interval: Some(Duration::from_secs(5)), retries: Some(3) }),
+            send_buffer: Some(64 << 10),
+            recv_buffer: Some(64 << 10),
+            user_timeout: Some(Duration::from_secs(20)),
+        };
+        assert!(options.is_valid());
+        assert!(!SocketOptions { send_buffer: Some(0), ..options }.is_valid());
+
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let listener = options.bind("127.0.0.1:0".parse().unwrap())?;
+            let outbound = options.connect(listener.local_addr()?).await?;
+            let (accepted, _) = listener.accept().await?;
+            options.apply(&accepted)?;
+            for stream in [&outbound, &accepted] {
+                let socket = SockRef::from(stream);
+                assert!(socket.tcp_nodelay()?);
+                assert!(socket.keepalive()?);
+                // the kernel may round, or double, the sizes asked for
+                assert!(socket.recv_buffer_size()? >= 64 << 10);
+                #[cfg(target_os = "linux")]
+                assert_eq!(socket.tcp_user_timeout()?, Some(Duration::from_secs(20)));
+            }
+            Ok(())
+        })
+    }
+}
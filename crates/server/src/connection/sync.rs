@@ -0,0 +1,184 @@
+use std::sync::Arc;
+
+use log::{debug, error};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use crate::connection::error::TransferError;
+use crate::store::{DataStore, PeerSyncState};
+
+/// Tracks the sequence numbers exchanged with a single peer, writing every
+/// change through to the [DataStore] so a restarted node picks up where it
+/// left off instead of re-syncing or tripping the peer's sequence checks.
+///
+/// The inbound and outbound connections with a peer each hold a session on
+/// the same state, so each direction only ever updates its own part of it,
+/// in the store, rather than writing back a copy of the whole.
+pub struct SyncSession {
+    hostname: String,
+    cursor: u64,
+    last_sent: u64,
+    resumption_token: Uuid,
+    store: Arc<dyn DataStore>,
+}
+
+impl SyncSession {
+    /// Load the session for `hostname`, starting a fresh one if the store has
+    /// no state for the peer yet.
+    pub fn load(store: Arc<dyn DataStore>, hostname: String) -> io::Result<Self> {
+        let state = match store.peer_state(&hostname)? {
+            Some(state) => {
+                debug!("Resuming sync session with {hostname} at cursor {}", state.cursor);
+                state
+            }
+            None => {
+                let state = PeerSyncState::default();
+                store.put_peer_state(&hostname, &state)?;
+                state
+            }
+        };
+
+        Ok(Self {
+            hostname,
+            cursor: state.cursor,
+            last_sent: state.next_sequence - 1,
+            resumption_token: state.resumption_token,
+            store,
+        })
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// The highest sequence number received from the peer, as this session
+    /// last saw it.
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+
+    /// The highest sequence number received from the peer and processed on
+    /// any connection with it. Unlike [cursor](Self::cursor) it is read
+    /// from the store, as the sessions of the connections the peer delivers
+    /// over move it on.
+    pub fn processed(&self) -> io::Result<u64> {
+        Ok(self.store.peer_state(&self.hostname)?.map_or(self.cursor, |state| state.cursor))
+    }
+
+    /// The sequence number of the last object this session sent to the
+    /// peer, or the one sent before it was loaded.
+    pub fn last_sent(&self) -> u64 {
+        self.last_sent
+    }
+
+    pub fn resumption_token(&self) -> Uuid {
+        self.resumption_token
+    }
+
+    /// Record that the object with `sequence` was received from the peer.
+    ///
+    /// Returns `false` if the sequence was already processed (the peer is
+    /// resending something we have), and an error if the peer skipped ahead.
+    pub fn accept_sequence(&mut self, sequence: u64) -> io::Result<bool> {
+        let cursor = self.store.advance_peer_cursor(&self.hostname, sequence)?;
+        self.cursor = cursor;
+        if sequence <= cursor {
+            debug!("Ignoring already processed sequence {sequence} from {}", self.hostname);
+            return Ok(false);
+        }
+
+        if sequence != cursor + 1 {
+            error!("Sequence violation from {}. Expected: {} Actual: {}", self.hostname, cursor + 1, sequence);
+            return Err(TransferError::SequenceViolation { expected: cursor + 1, actual: sequence }.into());
+        }
+
+        self.cursor = sequence;
+        Ok(true)
+    }
+
+    /// Allocate the sequence number for the next object sent to the peer.
+    pub fn next_sequence(&mut self) -> io::Result<u64> {
+        self.last_sent = self.store.take_peer_sequence(&self.hostname)?;
+        Ok(self.last_sent)
+    }
+
+    /// Throw away the session state, e.g. when the peer presented a
+    /// resumption token that does not match ours.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.start_over(PeerSyncState::default())
+    }
+
+    /// Resume the session the peer presented the resumption `token` of,
+    /// starting over if it is not ours. Peers from before resumption tokens
+    /// present none, and resume.
+    pub fn resume(&mut self, token: Option<Uuid>) -> io::Result<()> {
+        match token {
+            Some(token) if token != self.resumption_token => {
+                debug!("Resumption token of {} does not match ours, starting over", self.hostname);
+                self.reset()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Take on the resumption `token` the peer keeps for us, starting over
+    /// if it is not the one we presented.
+    pub fn adopt(&mut self, token: Uuid) -> io::Result<()> {
+        if token == self.resumption_token {
+            return Ok(());
+        }
+        debug!("{} started a new sync session, starting over", self.hostname);
+        self.start_over(PeerSyncState { resumption_token: token, ..PeerSyncState::default() })
+    }
+
+    /// Replace the state of both directions with `state`, as starting a new
+    /// sync session with the peer does.
+    fn start_over(&mut self, state: PeerSyncState) -> io::Result<()> {
+        self.store.put_peer_state(&self.hostname, &state)?;
+        self.cursor = state.cursor;
+        self.last_sent = state.next_sequence - 1;
+        self.resumption_token = state.resumption_token;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io;
+
+    use crate::connection::sync::SyncSession;
+    use crate::store::{DataStore, MemoryStore, PeerSyncState};
+    #[cfg(feature = "storage-sqlite")]
+    use crate::store::SqliteStore;
+
+    #[test]
+    fn test_directions_dont_overwrite_each_other() -> io::Result<()> {
+        #[cfg_attr(not(feature = "storage-sqlite"), allow(unused_mut))]
+        let mut stores: Vec<Arc<dyn DataStore>> = vec![Arc::new(MemoryStore::new())];
+        #[cfg(feature = "storage-sqlite")]
+        stores.push(Arc::new(SqliteStore::open_in_memory()?));
+
+        for store in stores {
+            let mut inbound = SyncSession::load(store.clone(), "peer.test".to_string())?;
+            let mut outbound = SyncSession::load(store.clone(), "peer.test".to_string())?;
+            let token = inbound.resumption_token();
+            for sequence in 1..=3 {
+                assert!(inbound.accept_sequence(sequence)?);
+                assert_eq!(outbound.next_sequence()?, sequence);
+            }
+            // a second inbound session picks up where the first left off
+            let mut again = SyncSession::load(store.clone(), "peer.test".to_string())?;
+            assert!(!again.accept_sequence(3)?);
+            assert!(again.accept_sequence(4)?);
+            assert!(!inbound.accept_sequence(4)?);
+            assert_eq!(inbound.cursor(), 4);
+
+            assert_eq!(store.peer_state("peer.test")?, Some(PeerSyncState { cursor: 4, next_sequence: 4, resumption_token: token }));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+//! # Transport Security
+//!
+//! Connections between nodes run over plain TCP by default, relying on the
+//! handshake challenge to authenticate peers. With the `tls` feature, a node
+//! can be built with [TransportSecurity::Tls] to encrypt its connections:
+//! it accepts connections with its certificate, and verifies the
+//! certificates of the nodes it connects to, against the public web PKI or
+//! pinned certificates.
+//!
+//! There is no negotiation: a node listening with TLS only accepts TLS
+//! connections, so peers have to agree on the transport beforehand.
+//!
+//! With the `websocket` feature, connections can be carried over a
+//! WebSocket as well, see [osp_protocol::websocket]. A node built with a
+//! [websocket endpoint](crate::OSProtocolNodeBuilder::websocket_endpoint)
+//! accepts WebSocket upgrades on it, over TLS if it uses TLS, and nodes are
+//! connected to over a WebSocket when their URL is a `ws://` or `wss://`
+//! one. `wss://` URLs are always connected to over TLS, verified against
+//! the web PKI if the node doesn't use TLS itself, and `ws://` ones never.
+
+use std::net::SocketAddr;
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::pin::Pin;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+#[cfg(feature = "tls")]
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::tungstenite::http::StatusCode;
+
+use osp_protocol::{Protocol, Scheme};
+use osp_protocol::packet::{DeserializePacket, SerializePacket};
+#[cfg(feature = "websocket")]
+use osp_protocol::websocket::WEBSOCKET_PATH;
+
+use crate::connection::socket::SocketOptions;
+use crate::metrics;
+
+/// How a node secures its connections, see the [module](self) docs.
+#[derive(Clone, Default)]
+pub enum TransportSecurity {
+    #[default]
+    Plaintext,
+    #[cfg(feature = "tls")]
+    Tls(TlsConfig),
+}
+
+impl TransportSecurity {
+    /// Wrap an accepted connection, completing the TLS handshake if needed,
+    /// and the WebSocket upgrade if it was accepted on the `websocket`
+    /// endpoint.
+    pub(crate) async fn accept<I: DeserializePacket, O: SerializePacket>(&self, stream: TcpStream, websocket: bool) -> io::Result<Protocol<I, O>> {
+        let stream = Metered(stream);
+        match self {
+            TransportSecurity::Plaintext => accept_upgrade(stream, websocket).await,
+            #[cfg(feature = "tls")]
+            TransportSecurity::Tls(config) => accept_upgrade(config.acceptor.accept(stream).await?, websocket).await,
+        }
+    }
+
+    /// Connect to the node at `addr` over `scheme` with `options`, verifying
+    /// that it presents a certificate for `peer` when using TLS.
+    pub(crate) async fn connect<I: DeserializePacket, O: SerializePacket>(&self, addr: SocketAddr, peer: &str, scheme: Scheme, options: &SocketOptions) -> io::Result<Protocol<I, O>> {
+        let stream = Metered(options.connect(addr).await?);
+        match (self, scheme) {
+            (TransportSecurity::Plaintext, Scheme::Osp) | (_, Scheme::Ws) => upgrade(stream, peer, addr.port(), scheme).await,
+            #[cfg(feature = "tls")]
+            (TransportSecurity::Tls(config), Scheme::Osp | Scheme::Wss) => {
+                upgrade(tls_connect(&config.connector, stream, peer).await?, peer, addr.port(), scheme).await
+            }
+            #[cfg(feature = "tls")]
+            (TransportSecurity::Plaintext, Scheme::Wss) => {
+                upgrade(tls_connect(&connector(web_pki_roots()), stream, peer).await?, peer, addr.port(), scheme).await
+            }
+            #[cfg(not(feature = "tls"))]
+            (TransportSecurity::Plaintext, Scheme::Wss) => {
+                Err(io::Error::new(io::ErrorKind::Unsupported, format!("Unable to connect to {peer} over wss:// without the tls feature")))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+async fn tls_connect<S: AsyncRead + AsyncWrite + Unpin>(connector: &TlsConnector, stream: S, peer: &str) -> io::Result<tokio_rustls::client::TlsStream<S>> {
+    let server_name = rustls::ServerName::try_from(peer)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid TLS server name {peer}: {e}")))?;
+    connector.connect(server_name, stream).await
+}
+
+/// Run Protocol over an accepted `stream`, once the guest upgraded it to a
+/// WebSocket if it was accepted on the `websocket` endpoint.
+async fn accept_upgrade<S, I, O>(stream: S, websocket: bool) -> io::Result<Protocol<I, O>>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    I: DeserializePacket,
+    O: SerializePacket,
+{
+    if !websocket {
+        return Protocol::with_transport(stream);
+    }
+    #[cfg(feature = "websocket")]
+    {
+        let socket = tokio_tungstenite::accept_hdr_async(stream, check_path).await.map_err(upgrade_error)?;
+        Protocol::with_websocket(socket)
+    }
+    #[cfg(not(feature = "websocket"))]
+    Err(io::Error::new(io::ErrorKind::Unsupported, "WebSockets need the websocket feature"))
+}
+
+/// Refuse WebSocket upgrades at paths other than [WEBSOCKET_PATH].
+#[cfg(feature = "websocket")]
+// the signature is that of tungstenite's callbacks
+#[allow(clippy::result_large_err)]
+fn check_path(request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+    if request.uri().path() == WEBSOCKET_PATH {
+        return Ok(response);
+    }
+    let mut refusal = ErrorResponse::new(Some(format!("Connect at {WEBSOCKET_PATH}")));
+    *refusal.status_mut() = StatusCode::NOT_FOUND;
+    Err(refusal)
+}
+
+/// Run Protocol over `stream` to `peer` at `port`, upgrading it to a
+/// WebSocket first if `scheme` is one.
+#[cfg_attr(not(feature = "websocket"), allow(unused_variables))]
+async fn upgrade<S, I, O>(stream: S, peer: &str, port: u16, scheme: Scheme) -> io::Result<Protocol<I, O>>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    I: DeserializePacket,
+    O: SerializePacket,
+{
+    if !scheme.is_websocket() {
+        return Protocol::with_transport(stream);
+    }
+    #[cfg(feature = "websocket")]
+    {
+        let host = match peer.contains(':') {
+            true => format!("[{peer}]"),
+            false => peer.to_string(),
+        };
+        let url = format!("{}://{host}:{port}{WEBSOCKET_PATH}", scheme.as_str());
+        let (socket, _) = tokio_tungstenite::client_async(url, stream).await.map_err(upgrade_error)?;
+        Protocol::with_websocket(socket)
+    }
+    #[cfg(not(feature = "websocket"))]
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("Unable to connect to {peer} over {} without the websocket feature", scheme.as_str())))
+}
+
+/// Why a WebSocket upgrade failed.
+#[cfg(feature = "websocket")]
+fn upgrade_error(err: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    match err {
+        tokio_tungstenite::tungstenite::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::ConnectionRefused, format!("WebSocket upgrade failed: {e}")),
+    }
+}
+
+/// A connection counting the bytes read from and written to it, TLS records
+/// included, in the transfer bytes metrics.
+struct Metered<S>(S);
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.0).poll_read(cx, buf))?;
+        metrics::transfer_bytes("in", buf.filled().len() - before);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.0).poll_write(cx, buf))?;
+        metrics::transfer_bytes("out", written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Certificate and trusted roots of a node using TLS.
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+pub struct TlsConfig {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+}
+
+#[cfg(feature = "tls")]
+impl TlsConfig {
+    /// Accept connections with the PEM encoded certificate chain
+    /// `certificates` and its `private_key`, and verify peers against the
+    /// public web PKI.
+    pub fn new(certificates: &[u8], private_key: &[u8]) -> io::Result<Self> {
+        let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+        let chain = rustls_pemfile::certs(&mut &certificates[..])?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect::<Vec<_>>();
+        if chain.is_empty() {
+            return Err(invalid("No certificates in the certificate chain".to_string()));
+        }
+        let key = rustls_pemfile::read_all(&mut &private_key[..])?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::RSAKey(der)
+                | rustls_pemfile::Item::PKCS8Key(der)
+                | rustls_pemfile::Item::ECKey(der) => Some(rustls::PrivateKey(der)),
+                _ => None,
+            })
+            .ok_or_else(|| invalid("No private key for the certificate".to_string()))?;
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(|e| invalid(format!("Invalid certificate: {e}")))?;
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            connector: connector(web_pki_roots()),
+        })
+    }
+
+    /// [new](Self::new), reading the certificate chain and private key from
+    /// PEM files.
+    pub fn from_files(certificates: impl AsRef<Path>, private_key: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(&std::fs::read(certificates)?, &std::fs::read(private_key)?)
+    }
+
+    /// Only trust the PEM encoded `certificates`, such as a private CA or
+    /// the self-signed certificates of known peers, instead of the public
+    /// web PKI.
+    pub fn pin_certificates(mut self, certificates: &[u8]) -> io::Result<Self> {
+        self.connector = connector(pinned_roots(certificates)?);
+        Ok(self)
+    }
+}
+
+#[cfg(feature = "tls")]
+fn connector(roots: rustls::RootCertStore) -> TlsConnector {
+    let client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(client_config))
+}
+
+/// A root store trusting the public web PKI.
+#[cfg(feature = "tls")]
+fn web_pki_roots() -> rustls::RootCertStore {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+    roots
+}
+
+/// A root store trusting only the PEM encoded `certificates`.
+#[cfg(feature = "tls")]
+pub(crate) fn pinned_roots(certificates: &[u8]) -> io::Result<rustls::RootCertStore> {
+    let mut roots = rustls::RootCertStore::empty();
+    for der in rustls_pemfile::certs(&mut &certificates[..])? {
+        roots.add(&rustls::Certificate(der))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid pinned certificate: {e}")))?;
+    }
+    if roots.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "No certificates to pin"));
+    }
+    Ok(roots)
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use tokio::io;
+    use tokio::net::TcpListener;
+
+    use osp_protocol::{ConnectionType, Scheme, PROTOCOL_VERSION};
+    use osp_protocol::packet::handshake::{HandshakePacketGuestToHost, HandshakePacketHostToGuest};
+
+    use crate::connection::socket::SocketOptions;
+    use crate::connection::transport::{TlsConfig, TransportSecurity};
+
+    #[test]
+    fn test_tls_transport() -> io::Result<()> {
+        let mut params = rcgen::CertificateParams::default();
+        params.subject_alt_names = vec![rcgen::SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST))];
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        let key_pem = cert.serialize_private_key_pem();
+        let security = TransportSecurity::Tls(TlsConfig::new(cert_pem.as_bytes(), key_pem.as_bytes())?
+            .pin_certificates(cert_pem.as_bytes())?);
+
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let host = tokio::spawn({
+                let security = security.clone();
+                async move {
+                    // the first guest gives up on the TLS handshake
+                    let mut protocol = loop {
+                        let (stream, _) = listener.accept().await?;
+                        if let Ok(protocol) = security.accept::<HandshakePacketGuestToHost, HandshakePacketHostToGuest>(stream, false).await {
+                            break protocol;
+                        }
+                    };
+                    let HandshakePacketGuestToHost::Hello { connection_type, .. } = protocol.read_frame().await? else {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Expected hello packet"));
+                    };
+                    io::Result::Ok(connection_type)
+                }
+            });
+
+            // the pinned certificate is for 127.0.0.1, not another name
+            let wrong_name = security.connect::<HandshakePacketHostToGuest, HandshakePacketGuestToHost>(addr, "node.test", Scheme::Osp, &SocketOptions::default()).await;
+            assert!(wrong_name.is_err());
+
+            let mut guest = security.connect::<HandshakePacketHostToGuest, HandshakePacketGuestToHost>(addr, "127.0.0.1", Scheme::Osp, &SocketOptions::default()).await?;
+            guest.send_message(HandshakePacketGuestToHost::Hello { connection_type: ConnectionType::Server, version: PROTOCOL_VERSION, compression: Vec::new(), formats: Vec::new(), software: None, timestamp: None }).await?;
+            assert!(matches!(host.await??, ConnectionType::Server));
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,114 @@
+//! # Type Conversion
+//!
+//! Converters map objects of one data type into another, e.g. items from an
+//! RSS bridge into the standard [Article](osp_data::standard::Article), so
+//! bridges and legacy types can coexist with the types a node works with.
+//!
+//! A converter runs at one [Stage]: on ingest, received objects are converted
+//! before they are stored and handled; on egress, objects are converted just
+//! before they are pushed to a peer. Converted objects keep the id, origin
+//! and creation time of the original.
+//!
+//! Registering converters requires the `bridges` feature.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::debug;
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_data::Data;
+use osp_protocol::Envelope;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Stage {
+    /// Convert objects received from peers.
+    Ingest,
+    /// Convert objects pushed to peers.
+    Egress,
+}
+
+type ConvertFn = dyn Fn(&Envelope) -> io::Result<Envelope> + Send + Sync;
+
+/// The converters registered on a node, keyed by stage and source type.
+#[derive(Clone, Default)]
+pub(crate) struct Converters {
+    converters: HashMap<(Stage, Uuid), Arc<ConvertFn>>,
+}
+
+impl Converters {
+    #[cfg_attr(not(feature = "bridges"), allow(dead_code))]
+    pub(crate) fn register<A, B, F>(&mut self, stage: Stage, convert: F)
+    where
+        A: Data,
+        B: Data,
+        F: Fn(A) -> io::Result<B> + Send + Sync + 'static,
+    {
+        self.converters.insert((stage, A::TYPE_ID), Arc::new(move |envelope: &Envelope| {
+            Ok(Envelope {
+                type_id: B::TYPE_ID,
+                type_version: B::VERSION,
+                payload: convert(A::from_envelope(envelope)?)?.encode()?,
+                ..envelope.clone()
+            })
+        }));
+    }
+
+    /// Convert `envelope` if a converter for its type is registered at
+    /// `stage`. Objects are converted at most once per stage.
+    pub(crate) fn apply(&self, stage: Stage, envelope: Envelope) -> io::Result<Envelope> {
+        match self.converters.get(&(stage, envelope.type_id)) {
+            Some(convert) => {
+                let converted = convert(&envelope)?;
+                debug!("Converted object {} from type {} to {}", envelope.object_id, envelope.type_id, converted.type_id);
+                Ok(converted)
+            }
+            None => Ok(envelope),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use tokio::io;
+
+    use osp_data::{impl_data, Data};
+    use osp_data::standard::Article;
+
+    use crate::convert::{Converters, Stage};
+
+    #[derive(Serialize, Deserialize)]
+    struct RssItem {
+        title: String,
+        description: String,
+        link: String,
+    }
+
+    impl_data!(RssItem, "2f4d8c71-6b3a-4e9d-8a52-c1e07b9f4d36");
+
+    #[test]
+    fn test_ingest_conversion() -> io::Result<()> {
+        let mut converters = Converters::default();
+        converters.register(Stage::Ingest, |item: RssItem| Ok(Article {
+            title: item.title,
+            content: item.description,
+            url: Some(item.link),
+            ..Default::default()
+        }));
+
+        let item = RssItem { title: "Title".to_string(), description: "Body".to_string(), link: "https://example.com".to_string() };
+        let envelope = item.to_envelope("origin.test".to_string())?;
+
+        let converted = converters.apply(Stage::Ingest, envelope.clone())?;
+        assert_eq!((converted.object_id, converted.type_id), (envelope.object_id, Article::TYPE_ID));
+        assert_eq!(Article::from_envelope(&converted)?.url.as_deref(), Some("https://example.com"));
+
+        // nothing registered for egress
+        assert_eq!(converters.apply(Stage::Egress, envelope.clone())?, envelope);
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+//! # Cryptography
+//!
+//! Every cryptographic operation of a node goes through this module, so the
+//! backend can be picked at compile time:
+//!
+//! - `openssl` (default) links the system OpenSSL.
+//! - `rust-crypto` uses the pure Rust [RustCrypto](https://github.com/RustCrypto)
+//!   crates, for targets OpenSSL is hard to build for, such as musl or ARM.
+//!   Build the crate with `default-features = false` to drop OpenSSL.
+//!
+//! If both are enabled, OpenSSL is used. The backends are interchangeable on
+//! the wire: keys are RSA, signatures RSASSA-PKCS1-v1_5 with SHA-256 and
+//! handshake challenges are encrypted with RSAES-PKCS1-v1_5. Nodes can also
+//! hold an [Ed25519Key], to answer handshake challenges by signing them.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use tokio::io;
+
+#[cfg(feature = "openssl")]
+#[path = "openssl.rs"]
+mod backend;
+
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+#[path = "rust.rs"]
+mod backend;
+
+#[cfg(not(any(feature = "openssl", feature = "rust-crypto")))]
+compile_error!("osp_server_sdk needs a crypto backend, enable the `openssl` or `rust-crypto` feature");
+
+/// The private RSA key of a node.
+#[derive(Clone)]
+pub struct PrivateKey(backend::PrivateKey);
+
+/// A public RSA key, such as one published in an `_osp` DNS record.
+#[derive(Clone)]
+pub struct PublicKey(backend::PublicKey);
+
+impl PrivateKey {
+    /// Generate a new key of `bits` bits.
+    pub fn generate(bits: u32) -> io::Result<Self> {
+        backend::generate(bits).map(Self)
+    }
+
+    /// Parse a PEM encoded key, in PKCS#1 or PKCS#8 format.
+    pub fn from_pem(pem: &[u8]) -> io::Result<Self> {
+        backend::private_from_pem(&normalize_pem(pem)).map(Self)
+    }
+
+    /// The key encoded as PKCS#1 PEM.
+    pub fn to_pem(&self) -> io::Result<String> {
+        backend::private_to_pem(&self.0)
+    }
+
+    pub fn public_key(&self) -> io::Result<PublicKey> {
+        backend::public_of(&self.0).map(PublicKey)
+    }
+
+    /// Whether the key is consistent, e.g. its primes multiply to its
+    /// modulus.
+    pub(crate) fn check(&self) -> io::Result<bool> {
+        backend::check(&self.0)
+    }
+
+    /// Sign `data` with SHA-256.
+    pub(crate) fn sign(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        backend::sign(&self.0, data)
+    }
+
+    /// Decrypt a handshake challenge encrypted with the public half of the
+    /// key.
+    pub(crate) fn decrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        backend::decrypt(&self.0, data)
+    }
+}
+
+impl PublicKey {
+    /// Parse a PEM encoded SubjectPublicKeyInfo (`BEGIN PUBLIC KEY`).
+    pub fn from_pem(pem: &[u8]) -> io::Result<Self> {
+        backend::public_from_pem(&normalize_pem(pem)).map(Self)
+    }
+
+    /// Parse a DER encoded SubjectPublicKeyInfo.
+    pub fn from_der(der: &[u8]) -> io::Result<Self> {
+        backend::public_from_der(der).map(Self)
+    }
+
+    pub fn to_pem(&self) -> io::Result<String> {
+        backend::public_to_pem(&self.0)
+    }
+
+    pub fn to_der(&self) -> io::Result<Vec<u8>> {
+        backend::public_to_der(&self.0)
+    }
+
+    /// Whether `signature` is a valid SHA-256 signature of `data`, made
+    /// with the private half of the key.
+    pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> io::Result<bool> {
+        backend::verify(&self.0, data, signature)
+    }
+
+    /// Encrypt a handshake challenge for the holder of the private half of
+    /// the key.
+    pub(crate) fn encrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        backend::encrypt(&self.0, data)
+    }
+}
+
+/// The private Ed25519 key of a node, which peers that publish an Ed25519
+/// challenge record for it challenge the node to sign with.
+#[derive(Clone)]
+pub struct Ed25519Key(backend::Ed25519Private);
+
+/// A public Ed25519 key, such as one published in an `_osp` DNS record.
+#[derive(Clone)]
+pub struct Ed25519PublicKey(backend::Ed25519Public);
+
+impl Ed25519Key {
+    pub fn generate() -> io::Result<Self> {
+        backend::ed25519_generate().map(Self)
+    }
+
+    /// Parse a PEM encoded PKCS#8 key (`BEGIN PRIVATE KEY`).
+    pub fn from_pem(pem: &[u8]) -> io::Result<Self> {
+        backend::ed25519_from_pem(&normalize_pem(pem)).map(Self)
+    }
+
+    /// The key encoded as PKCS#8 PEM.
+    pub fn to_pem(&self) -> io::Result<String> {
+        backend::ed25519_to_pem(&self.0)
+    }
+
+    pub fn public_key(&self) -> io::Result<Ed25519PublicKey> {
+        backend::ed25519_public_of(&self.0).map(Ed25519PublicKey)
+    }
+
+    pub(crate) fn sign(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        backend::ed25519_sign(&self.0, data)
+    }
+}
+
+impl Ed25519PublicKey {
+    /// Parse a DER encoded SubjectPublicKeyInfo.
+    pub fn from_der(der: &[u8]) -> io::Result<Self> {
+        backend::ed25519_public_from_der(der).map(Self)
+    }
+
+    pub fn to_der(&self) -> io::Result<Vec<u8>> {
+        backend::ed25519_public_to_der(&self.0)
+    }
+
+    /// Whether `signature` is a valid signature of `data`, made with the
+    /// private half of the key.
+    pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> io::Result<bool> {
+        backend::ed25519_verify(&self.0, data, signature)
+    }
+}
+
+impl PartialEq for Ed25519PublicKey {
+    fn eq(&self, other: &Self) -> bool {
+        matches!((self.to_der(), other.to_der()), (Ok(a), Ok(b)) if a == b)
+    }
+}
+
+impl fmt::Debug for Ed25519PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let der = self.to_der().map_err(|_| fmt::Error)?;
+        write!(f, "Ed25519PublicKey({})", hex(&sha256(&der)))
+    }
+}
+
+impl PartialEq for PublicKey {
+    fn eq(&self, other: &Self) -> bool {
+        matches!((self.to_der(), other.to_der()), (Ok(a), Ok(b)) if a == b)
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let der = self.to_der().map_err(|_| fmt::Error)?;
+        write!(f, "PublicKey({})", hex(&sha256(&der)))
+    }
+}
+
+/// Strip the byte order mark and CRLF line endings that editors on Windows
+/// tend to save PEM files with, which not every backend accepts.
+fn normalize_pem(pem: &[u8]) -> Cow<'_, [u8]> {
+    let pem = pem.strip_prefix(b"\xef\xbb\xbf").unwrap_or(pem);
+    if pem.contains(&b'\r') {
+        Cow::Owned(pem.iter().copied().filter(|&byte| byte != b'\r').collect())
+    } else {
+        Cow::Borrowed(pem)
+    }
+}
+
+/// Incremental SHA-256, for data too large to hash in one go.
+pub(crate) struct Sha256(backend::Sha256);
+
+impl Sha256 {
+    pub(crate) fn new() -> Self {
+        Self(backend::hasher())
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        backend::update(&mut self.0, data);
+    }
+
+    pub(crate) fn finish(self) -> [u8; 32] {
+        backend::finish(self.0)
+    }
+}
+
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    backend::sha256(data)
+}
+
+/// HMAC-SHA256 of `data` under `key`.
+#[cfg_attr(not(feature = "tiering-s3"), allow(dead_code))]
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    backend::hmac_sha256(key, data)
+}
+
+/// Fill `buf` with cryptographically secure random bytes.
+pub(crate) fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    backend::random_bytes(buf)
+}
+
+/// Lowercase hex encoding of `bytes`.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use crate::crypto::{Ed25519Key, Ed25519PublicKey, PrivateKey, PublicKey};
+
+    #[test]
+    fn test_keys() -> io::Result<()> {
+        let key = PrivateKey::generate(2048)?;
+        let public = key.public_key()?;
+        assert_eq!(PrivateKey::from_pem(key.to_pem()?.as_bytes())?.public_key()?, public);
+        let windows_pem = format!("\u{feff}{}", key.to_pem()?.replace('\n', "\r\n"));
+        assert_eq!(PrivateKey::from_pem(windows_pem.as_bytes())?.public_key()?, public);
+        assert_eq!(PublicKey::from_der(&public.to_der()?)?, public);
+
+        let signature = key.sign(b"data")?;
+        assert!(public.verify(b"data", &signature)?);
+        assert!(!public.verify(b"other data", &signature)?);
+
+        let challenge = [7; 32];
+        assert_eq!(key.decrypt(&public.encrypt(&challenge)?)?, challenge);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ed25519_keys() -> io::Result<()> {
+        let key = Ed25519Key::generate()?;
+        let public = key.public_key()?;
+        assert_eq!(Ed25519Key::from_pem(key.to_pem()?.as_bytes())?.public_key()?, public);
+        assert_eq!(public.to_der()?.len(), 44);
+        assert_eq!(Ed25519PublicKey::from_der(&public.to_der()?)?, public);
+
+        let signature = key.sign(b"data")?;
+        assert_eq!(signature.len(), 64);
+        assert!(public.verify(b"data", &signature)?);
+        assert!(!public.verify(b"other data", &signature)?);
+        assert!(!public.verify(b"data", b"too short")?);
+        Ok(())
+    }
+}
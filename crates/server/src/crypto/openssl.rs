@@ -0,0 +1,150 @@
+//! OpenSSL backend.
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Private, Public};
+use openssl::rsa::{Padding, Rsa};
+use openssl::sign::{Signer, Verifier};
+
+use tokio::io;
+
+pub(crate) type PrivateKey = Rsa<Private>;
+pub(crate) type PublicKey = Rsa<Public>;
+pub(crate) type Sha256 = openssl::sha::Sha256;
+pub(crate) type Ed25519Private = PKey<Private>;
+pub(crate) type Ed25519Public = PKey<Public>;
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+pub(crate) fn generate(bits: u32) -> io::Result<PrivateKey> {
+    Ok(Rsa::generate(bits)?)
+}
+
+pub(crate) fn private_from_pem(pem: &[u8]) -> io::Result<PrivateKey> {
+    Ok(Rsa::private_key_from_pem(pem)?)
+}
+
+pub(crate) fn private_to_pem(key: &PrivateKey) -> io::Result<String> {
+    Ok(String::from_utf8(key.private_key_to_pem()?).unwrap())
+}
+
+pub(crate) fn public_of(key: &PrivateKey) -> io::Result<PublicKey> {
+    Ok(Rsa::from_public_components(key.n().to_owned()?, key.e().to_owned()?)?)
+}
+
+pub(crate) fn check(key: &PrivateKey) -> io::Result<bool> {
+    Ok(key.check_key()?)
+}
+
+pub(crate) fn public_from_pem(pem: &[u8]) -> io::Result<PublicKey> {
+    Ok(Rsa::public_key_from_pem(pem)?)
+}
+
+pub(crate) fn public_from_der(der: &[u8]) -> io::Result<PublicKey> {
+    Ok(Rsa::public_key_from_der(der)?)
+}
+
+pub(crate) fn public_to_pem(key: &PublicKey) -> io::Result<String> {
+    Ok(String::from_utf8(key.public_key_to_pem()?).unwrap())
+}
+
+pub(crate) fn public_to_der(key: &PublicKey) -> io::Result<Vec<u8>> {
+    Ok(key.public_key_to_der()?)
+}
+
+pub(crate) fn sign(key: &PrivateKey, data: &[u8]) -> io::Result<Vec<u8>> {
+    let key = PKey::from_rsa(key.clone())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    Ok(signer.sign_oneshot_to_vec(data)?)
+}
+
+pub(crate) fn verify(key: &PublicKey, data: &[u8], signature: &[u8]) -> io::Result<bool> {
+    let key = PKey::from_rsa(key.clone())?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+    Ok(verifier.verify_oneshot(signature, data)?)
+}
+
+pub(crate) fn encrypt(key: &PublicKey, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encrypted = vec![0; key.size() as usize];
+    let len = key.public_encrypt(data, &mut encrypted, Padding::PKCS1)?;
+    encrypted.truncate(len);
+    Ok(encrypted)
+}
+
+pub(crate) fn decrypt(key: &PrivateKey, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decrypted = vec![0; key.size() as usize];
+    let len = key.private_decrypt(data, &mut decrypted, Padding::PKCS1)?;
+    decrypted.truncate(len);
+    Ok(decrypted)
+}
+
+pub(crate) fn ed25519_generate() -> io::Result<Ed25519Private> {
+    Ok(PKey::generate_ed25519()?)
+}
+
+pub(crate) fn ed25519_from_pem(pem: &[u8]) -> io::Result<Ed25519Private> {
+    let key = PKey::private_key_from_pem(pem)?;
+    match key.id() {
+        Id::ED25519 => Ok(key),
+        _ => Err(invalid("Not an Ed25519 key")),
+    }
+}
+
+pub(crate) fn ed25519_to_pem(key: &Ed25519Private) -> io::Result<String> {
+    Ok(String::from_utf8(key.private_key_to_pem_pkcs8()?).unwrap())
+}
+
+pub(crate) fn ed25519_public_of(key: &Ed25519Private) -> io::Result<Ed25519Public> {
+    Ok(PKey::public_key_from_raw_bytes(&key.raw_public_key()?, Id::ED25519)?)
+}
+
+pub(crate) fn ed25519_public_from_der(der: &[u8]) -> io::Result<Ed25519Public> {
+    let key = PKey::public_key_from_der(der)?;
+    match key.id() {
+        Id::ED25519 => Ok(key),
+        _ => Err(invalid("Not an Ed25519 key")),
+    }
+}
+
+pub(crate) fn ed25519_public_to_der(key: &Ed25519Public) -> io::Result<Vec<u8>> {
+    Ok(key.public_key_to_der()?)
+}
+
+pub(crate) fn ed25519_sign(key: &Ed25519Private, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut signer = Signer::new_without_digest(key)?;
+    Ok(signer.sign_oneshot_to_vec(data)?)
+}
+
+pub(crate) fn ed25519_verify(key: &Ed25519Public, data: &[u8], signature: &[u8]) -> io::Result<bool> {
+    let mut verifier = Verifier::new_without_digest(key)?;
+    // OpenSSL reports malformed signatures as errors rather than a mismatch
+    Ok(verifier.verify_oneshot(signature, data).unwrap_or(false))
+}
+
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    openssl::sha::sha256(data)
+}
+
+pub(crate) fn hasher() -> Sha256 {
+    Sha256::new()
+}
+
+pub(crate) fn update(hasher: &mut Sha256, data: &[u8]) {
+    hasher.update(data);
+}
+
+pub(crate) fn finish(hasher: Sha256) -> [u8; 32] {
+    hasher.finish()
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+pub(crate) fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    Ok(openssl::rand::rand_bytes(buf)?)
+}
@@ -0,0 +1,150 @@
+//! RustCrypto backend.
+
+use ed25519_dalek::pkcs8::EncodePrivateKey;
+
+use hmac::{Hmac, Mac};
+
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+use rsa::pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey};
+use rsa::pkcs1v15::{Signature, SigningKey, VerifyingKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePublicKey, LineEnding};
+use rsa::signature::{SignatureEncoding, Signer, Verifier};
+
+use sha2::Digest;
+
+use tokio::io;
+
+pub(crate) type PrivateKey = RsaPrivateKey;
+pub(crate) type PublicKey = RsaPublicKey;
+pub(crate) type Sha256 = sha2::Sha256;
+pub(crate) type Ed25519Private = ed25519_dalek::SigningKey;
+pub(crate) type Ed25519Public = ed25519_dalek::VerifyingKey;
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn other<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+pub(crate) fn generate(bits: u32) -> io::Result<PrivateKey> {
+    RsaPrivateKey::new(&mut OsRng, bits as usize).map_err(other)
+}
+
+pub(crate) fn private_from_pem(pem: &[u8]) -> io::Result<PrivateKey> {
+    let pem = std::str::from_utf8(pem).map_err(invalid)?;
+    RsaPrivateKey::from_pkcs1_pem(pem)
+        .or_else(|_| RsaPrivateKey::from_pkcs8_pem(pem))
+        .map_err(invalid)
+}
+
+pub(crate) fn private_to_pem(key: &PrivateKey) -> io::Result<String> {
+    Ok(key.to_pkcs1_pem(LineEnding::LF).map_err(other)?.to_string())
+}
+
+pub(crate) fn public_of(key: &PrivateKey) -> io::Result<PublicKey> {
+    Ok(key.to_public_key())
+}
+
+pub(crate) fn check(key: &PrivateKey) -> io::Result<bool> {
+    Ok(key.validate().is_ok())
+}
+
+pub(crate) fn public_from_pem(pem: &[u8]) -> io::Result<PublicKey> {
+    let pem = std::str::from_utf8(pem).map_err(invalid)?;
+    RsaPublicKey::from_public_key_pem(pem).map_err(invalid)
+}
+
+pub(crate) fn public_from_der(der: &[u8]) -> io::Result<PublicKey> {
+    RsaPublicKey::from_public_key_der(der).map_err(invalid)
+}
+
+pub(crate) fn public_to_pem(key: &PublicKey) -> io::Result<String> {
+    key.to_public_key_pem(LineEnding::LF).map_err(other)
+}
+
+pub(crate) fn public_to_der(key: &PublicKey) -> io::Result<Vec<u8>> {
+    Ok(key.to_public_key_der().map_err(other)?.into_vec())
+}
+
+pub(crate) fn sign(key: &PrivateKey, data: &[u8]) -> io::Result<Vec<u8>> {
+    let signer = SigningKey::<Sha256>::new(key.clone());
+    Ok(signer.try_sign(data).map_err(other)?.to_vec())
+}
+
+pub(crate) fn verify(key: &PublicKey, data: &[u8], signature: &[u8]) -> io::Result<bool> {
+    let Ok(signature) = Signature::try_from(signature) else { return Ok(false) };
+    Ok(VerifyingKey::<Sha256>::new(key.clone()).verify(data, &signature).is_ok())
+}
+
+pub(crate) fn encrypt(key: &PublicKey, data: &[u8]) -> io::Result<Vec<u8>> {
+    key.encrypt(&mut OsRng, Pkcs1v15Encrypt, data).map_err(other)
+}
+
+pub(crate) fn decrypt(key: &PrivateKey, data: &[u8]) -> io::Result<Vec<u8>> {
+    key.decrypt(Pkcs1v15Encrypt, data).map_err(invalid)
+}
+
+pub(crate) fn ed25519_generate() -> io::Result<Ed25519Private> {
+    Ok(ed25519_dalek::SigningKey::generate(&mut OsRng))
+}
+
+pub(crate) fn ed25519_from_pem(pem: &[u8]) -> io::Result<Ed25519Private> {
+    let pem = std::str::from_utf8(pem).map_err(invalid)?;
+    ed25519_dalek::SigningKey::from_pkcs8_pem(pem).map_err(invalid)
+}
+
+pub(crate) fn ed25519_to_pem(key: &Ed25519Private) -> io::Result<String> {
+    Ok(key.to_pkcs8_pem(LineEnding::LF).map_err(other)?.to_string())
+}
+
+pub(crate) fn ed25519_public_of(key: &Ed25519Private) -> io::Result<Ed25519Public> {
+    Ok(key.verifying_key())
+}
+
+pub(crate) fn ed25519_public_from_der(der: &[u8]) -> io::Result<Ed25519Public> {
+    ed25519_dalek::VerifyingKey::from_public_key_der(der).map_err(invalid)
+}
+
+pub(crate) fn ed25519_public_to_der(key: &Ed25519Public) -> io::Result<Vec<u8>> {
+    Ok(ed25519_dalek::pkcs8::EncodePublicKey::to_public_key_der(key).map_err(other)?.into_vec())
+}
+
+pub(crate) fn ed25519_sign(key: &Ed25519Private, data: &[u8]) -> io::Result<Vec<u8>> {
+    Ok(key.sign(data).to_vec())
+}
+
+pub(crate) fn ed25519_verify(key: &Ed25519Public, data: &[u8], signature: &[u8]) -> io::Result<bool> {
+    let Ok(signature) = ed25519_dalek::Signature::from_slice(signature) else { return Ok(false) };
+    Ok(key.verify(data, &signature).is_ok())
+}
+
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+pub(crate) fn hasher() -> Sha256 {
+    Sha256::new()
+}
+
+pub(crate) fn update(hasher: &mut Sha256, data: &[u8]) {
+    hasher.update(data);
+}
+
+pub(crate) fn finish(hasher: Sha256) -> [u8; 32] {
+    hasher.finalize().into()
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).map_err(other)?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+pub(crate) fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    OsRng.try_fill_bytes(buf).map_err(other)
+}
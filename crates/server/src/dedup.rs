@@ -0,0 +1,243 @@
+//! # Deduplication
+//!
+//! The same object reaches a node more than once when several peers push
+//! it, or a relay loops it back. The [DedupCache] remembers the objects a
+//! node took in for a while, so their duplicates are dropped on receipt
+//! instead of running the handlers again.
+//!
+//! Objects are told apart by their [dedup key](key): their origin, id and
+//! content, so an origin sending an object again with other content isn't
+//! suppressed. Objects are only remembered once they were stored and
+//! handled, so a push that failed can be retried.
+//!
+//! Keys are kept in memory by default, up to a number of them and for a
+//! while. Nodes that should keep dropping duplicates across restarts keep
+//! them in a [DedupBackend] of their own, such as a
+//! [SqliteStore](crate::store::SqliteStore).
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use log::warn;
+
+use tokio::io;
+
+use osp_data::{ContentHash, ObjectId};
+use osp_protocol::Envelope;
+
+use crate::embargo;
+use crate::metrics;
+
+/// How many keys the default cache holds.
+pub const DEFAULT_CAPACITY: usize = 10_000;
+/// How long the default cache holds keys.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+/// Keys recorded between two prunings of the backend.
+const PRUNE_EVERY: u64 = 64;
+
+/// The key `envelope` is deduplicated by, a hash of its origin, object id
+/// and [content id](ObjectId::of_envelope).
+pub fn key(envelope: &Envelope) -> ContentHash {
+    let mut bytes = Vec::with_capacity(envelope.origin.len() + 32);
+    bytes.extend(envelope.origin.to_ascii_lowercase().as_bytes());
+    bytes.extend(envelope.object_id.as_bytes());
+    bytes.extend(ObjectId::of_envelope(envelope).uuid().as_bytes());
+    ContentHash::of(&bytes)
+}
+
+/// Where a [DedupCache] keeps its keys.
+pub trait DedupBackend: Send + Sync {
+    /// Whether `key` was recorded at or after the unix timestamp `since`
+    /// (seconds).
+    fn contains(&self, key: &ContentHash, since: u64) -> io::Result<bool>;
+
+    /// Record `key` at the unix timestamp `at` (seconds).
+    fn insert(&self, key: &ContentHash, at: u64) -> io::Result<()>;
+
+    /// Forget the keys recorded before `before`, and the oldest keys beyond
+    /// the `capacity` latest. Returns how many were forgotten.
+    fn prune(&self, before: u64, capacity: usize) -> io::Result<usize>;
+}
+
+/// Keeps keys in memory, the default backend.
+#[derive(Default)]
+pub struct MemoryDedup {
+    keys: Mutex<MemoryKeys>,
+}
+
+#[derive(Default)]
+struct MemoryKeys {
+    recorded_at: HashMap<ContentHash, u64>,
+    // oldest first, keys recorded again are listed again
+    order: VecDeque<(ContentHash, u64)>,
+}
+
+impl MemoryDedup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl DedupBackend for MemoryDedup {
+    fn contains(&self, key: &ContentHash, since: u64) -> io::Result<bool> {
+        Ok(self.keys.lock().unwrap().recorded_at.get(key).is_some_and(|at| *at >= since))
+    }
+
+    fn insert(&self, key: &ContentHash, at: u64) -> io::Result<()> {
+        let mut keys = self.keys.lock().unwrap();
+        keys.recorded_at.insert(*key, at);
+        keys.order.push_back((*key, at));
+        Ok(())
+    }
+
+    fn prune(&self, before: u64, capacity: usize) -> io::Result<usize> {
+        let mut keys = self.keys.lock().unwrap();
+        let mut pruned = 0;
+        while let Some((oldest, at)) = keys.order.front().copied() {
+            // recorded again since
+            if keys.recorded_at.get(&oldest) != Some(&at) {
+                keys.order.pop_front();
+                continue;
+            }
+            if keys.recorded_at.len() <= capacity && at >= before {
+                break;
+            }
+            keys.order.pop_front();
+            keys.recorded_at.remove(&oldest);
+            pruned += 1;
+        }
+        Ok(pruned)
+    }
+}
+
+/// How many received objects were found to be duplicates.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DedupStats {
+    /// Objects dropped as duplicates.
+    pub hits: u64,
+    /// Objects that weren't.
+    pub misses: u64,
+}
+
+/// Remembers the objects a node took in, see the [module](self) docs.
+pub struct DedupCache {
+    backend: Box<dyn DedupBackend>,
+    capacity: usize,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    recorded: AtomicU64,
+}
+
+impl Default for DedupCache {
+    fn default() -> Self {
+        Self::new(MemoryDedup::new(), DEFAULT_CAPACITY, DEFAULT_TTL)
+    }
+}
+
+impl DedupCache {
+    /// A cache keeping up to `capacity` keys in `backend`, each for `ttl`.
+    pub fn new(backend: impl DedupBackend + 'static, capacity: usize, ttl: Duration) -> Self {
+        DedupCache {
+            backend: Box::new(backend),
+            capacity,
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            recorded: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether the object with the dedup key `key` was taken in already.
+    /// Objects are let through if the backend fails.
+    pub fn is_duplicate(&self, key: &ContentHash) -> bool {
+        let since = embargo::now().saturating_sub(self.ttl.as_secs());
+        let duplicate = self.backend.contains(key, since).unwrap_or_else(|e| {
+            warn!("Unable to look up dedup key {key}: {e}");
+            false
+        });
+        let counter = match duplicate {
+            true => &self.hits,
+            false => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        metrics::dedup_lookup(duplicate);
+        duplicate
+    }
+
+    /// Remember the object with the dedup key `key` was taken in.
+    pub fn record(&self, key: &ContentHash) {
+        let now = embargo::now();
+        if let Err(e) = self.backend.insert(key, now) {
+            warn!("Unable to record dedup key {key}: {e}");
+        }
+        if self.recorded.fetch_add(1, Ordering::Relaxed) % PRUNE_EVERY == PRUNE_EVERY - 1 {
+            if let Err(e) = self.backend.prune(now.saturating_sub(self.ttl.as_secs()), self.capacity) {
+                warn!("Unable to prune dedup keys: {e}");
+            }
+        }
+    }
+
+    pub fn stats(&self) -> DedupStats {
+        DedupStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io;
+
+    use uuid::Uuid;
+
+    use osp_protocol::Envelope;
+
+    use crate::dedup::{self, DedupBackend, DedupCache, DedupStats, MemoryDedup};
+    #[cfg(feature = "storage-sqlite")]
+    use crate::store::SqliteStore;
+
+    #[test]
+    fn test_dedup_cache() -> io::Result<()> {
+        let cache = DedupCache::new(MemoryDedup::new(), 10, Duration::from_secs(60));
+        let envelope = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![1]);
+        let key = dedup::key(&envelope);
+        assert!(!cache.is_duplicate(&key));
+        cache.record(&key);
+        assert!(cache.is_duplicate(&key));
+        // relayed copies are duplicates, other content under the same id isn't
+        assert_eq!(dedup::key(&envelope.clone().with_ttl(1)), key);
+        assert!(!cache.is_duplicate(&dedup::key(&Envelope { payload: vec![2], ..envelope.clone() })));
+        assert_eq!(cache.stats(), DedupStats { hits: 1, misses: 2 });
+        Ok(())
+    }
+
+    #[test]
+    fn test_backends() -> io::Result<()> {
+        let keys: Vec<_> = (0..3)
+            .map(|i| dedup::key(&Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![i])))
+            .collect();
+        #[cfg_attr(not(feature = "storage-sqlite"), allow(unused_mut))]
+        let mut backends: Vec<Box<dyn DedupBackend>> = vec![Box::new(MemoryDedup::new())];
+        #[cfg(feature = "storage-sqlite")]
+        backends.push(Box::new(SqliteStore::open_in_memory()?));
+        for backend in backends {
+            for (at, key) in keys.iter().enumerate() {
+                backend.insert(key, 100 + at as u64)?;
+            }
+            assert!(backend.contains(&keys[1], 101)?);
+            assert!(!backend.contains(&keys[1], 102)?);
+
+            assert_eq!(backend.prune(101, 10)?, 1);
+            assert_eq!(backend.prune(0, 1)?, 1);
+            assert!(!backend.contains(&keys[1], 0)?);
+            assert!(backend.contains(&keys[2], 0)?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,467 @@
+//! # Outbound Delivery
+//!
+//! [OSProtocolNode::deliver] queues an object for a peer instead of pushing
+//! it right away, so it isn't lost when the peer is down or its hostname
+//! doesn't resolve for a moment. The queue lives in the node's
+//! [DataStore](crate::store::DataStore), so with a persistent store it
+//! survives restarts as well.
+//!
+//! While the node listens, it pushes the deliveries that are due, sending
+//! those to the same peer over one connection. Failed deliveries are
+//! retried with exponential backoff and jitter, as set by the node's
+//! [RetryPolicy], until they go through, the peer refuses the object or they
+//! run out of attempts.
+//!
+//! How a delivery goes through depends on its [DeliveryQos], which
+//! deliveries to subscribers take from the subscription:
+//!
+//! - [FireAndForget](DeliveryQos::FireAndForget) deliveries are pushed once
+//!   and dropped if that fails.
+//! - [AtLeastOnce](DeliveryQos::AtLeastOnce) deliveries only go through
+//!   once the peer acknowledged the object, if it speaks
+//!   [PUSH_ACK_VERSION](osp_protocol::packet::transfer::PUSH_ACK_VERSION),
+//!   and are retried until then.
+//! - [Ordered](DeliveryQos::Ordered) deliveries are acknowledged as well,
+//!   and pushed in the order they were queued in: while one is retried, the
+//!   ordered deliveries queued after it for the same peer wait for it.
+//!
+//! Deliveries to a subscriber whose
+//! [receive window](crate::subscription::ReceiveWindow) is used up are
+//! paused: they stay queued as they are, and are pushed as soon as the
+//! subscriber advertises its window again.
+//!
+//! Pushes that weren't acknowledged in time may still have reached the
+//! peer. Once a subscriber reports it
+//! [processed](crate::subscription::SubscriptionManager::processed) the
+//! sequence such a delivery was pushed with, the delivery is trimmed from
+//! the queue instead of being pushed again.
+//!
+//! A connection may be lost, reset or closed by the peer, while a push is
+//! written but not yet acknowledged, or not even flushed. Such a push may
+//! or may not have reached the peer, so it is handled by its level instead
+//! of being pushed again as if it was never sent: fire-and-forget
+//! deliveries were pushed once and are dropped, acknowledged ones stay
+//! queued with the sequence they were pushed with, to be trimmed like
+//! those that weren't acknowledged in time.
+//!
+//! Deliveries to the node's push targets are pushed over the connections
+//! the node keeps with them instead, which are reconnected when they are
+//! lost, see [supervisor](crate::supervisor).
+//!
+//! The number of queued deliveries is reported as the
+//! `osp_delivery_queue_depth` gauge, and every attempt is counted in
+//! `osp_deliveries_total` by its outcome and level, as is every delivery
+//! that was paused or trimmed. How many pushed objects each subscriber
+//! didn't report it processed yet is the `osp_peer_lag` gauge.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, warn};
+
+use tokio::io;
+use tokio::sync::Notify;
+
+use uuid::Uuid;
+
+use osp_protocol::{Closure, Envelope, OSPUrl, Scheme};
+
+use crate::OSProtocolNode;
+use crate::connection::accounting::Cost;
+use crate::connection::outbound::{OutboundConnection, TransferState};
+use crate::crypto;
+use crate::metrics;
+use crate::reporting::{Fault, FaultKind};
+use crate::subscription::DeliveryQos;
+
+/// How often the queue is checked for deliveries that became due.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Deliveries pushed per check of the queue at most.
+pub(crate) const BATCH_SIZE: usize = 256;
+
+/// An object waiting to be pushed to a peer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingDelivery {
+    pub id: Uuid,
+    /// Hostname of the peer.
+    pub peer: String,
+    pub port: u16,
+    /// Whether the peer is connected to over raw TCP or a WebSocket.
+    pub scheme: Scheme,
+    pub envelope: Envelope,
+    /// Number of attempts that failed so far.
+    pub attempts: u32,
+    /// Unix timestamp (milliseconds) of the next attempt.
+    pub next_attempt_at: u64,
+    /// Why the last attempt failed, if one did.
+    pub last_error: Option<String>,
+    pub qos: DeliveryQos,
+    /// Sequence the object was first pushed with if that push wasn't
+    /// acknowledged in time, as the peer may have taken it in anyway.
+    pub pushed_sequence: Option<u64>,
+}
+
+impl PendingDelivery {
+    pub(crate) fn new(url: &OSPUrl, envelope: Envelope, qos: DeliveryQos) -> Self {
+        PendingDelivery {
+            id: Uuid::new_v4(),
+            peer: url.domain.clone(),
+            port: url.port,
+            scheme: url.scheme,
+            envelope,
+            attempts: 0,
+            next_attempt_at: now_millis(),
+            last_error: None,
+            qos,
+            pushed_sequence: None,
+        }
+    }
+}
+
+/// When failed deliveries are retried. The `n`th retry waits
+/// `initial_backoff * multiplier^(n-1)`, up to `max_backoff`, less a random
+/// fraction of up to `jitter` of it, so deliveries that failed together
+/// don't all retry at once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// Between 0 for no jitter and 1 for anywhere up to the full backoff.
+    pub jitter: f64,
+    /// Deliveries are dropped after this many failed attempts, if bounded.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(3600),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: Some(20),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether the policy retries at all and its numbers make sense.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.multiplier >= 1.0 && self.multiplier.is_finite()
+            && (0.0..=1.0).contains(&self.jitter)
+            && self.initial_backoff <= self.max_backoff
+            && self.max_attempts != Some(0)
+    }
+
+    /// How long to wait after `attempts` failed attempts, with `random` in
+    /// `[0, 1)` picking the jitter.
+    pub(crate) fn backoff(&self, attempts: u32, random: f64) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let backoff = (self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_backoff.as_secs_f64());
+        Duration::from_secs_f64(backoff * (1.0 - self.jitter * random))
+    }
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// A random number in `[0, 1)`.
+pub(crate) fn random_unit() -> io::Result<f64> {
+    let mut bytes = [0; 8];
+    crypto::random_bytes(&mut bytes)?;
+    Ok((u64::from_be_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64)
+}
+
+/// Push due deliveries until the node stops, checking the queue every
+/// [POLL_INTERVAL] or as soon as `queued` is notified.
+pub(crate) async fn run(node: OSProtocolNode, queued: Arc<Notify>) {
+    loop {
+        if let Err(e) = deliver_due(&node).await {
+            let message = format!("Delivering queued objects failed: {e}");
+            error!("{message}");
+            node.report_fault(Fault::new(FaultKind::Internal, message));
+        }
+        let _ = tokio::time::timeout(POLL_INTERVAL, queued.notified()).await;
+    }
+}
+
+/// Push the deliveries that are due, one connection per peer.
+pub(crate) async fn deliver_due(node: &OSProtocolNode) -> io::Result<()> {
+    let store = node.data_store();
+    let mut by_peer = BTreeMap::<(String, u16), Vec<PendingDelivery>>::new();
+    for delivery in store.due_deliveries(now_millis(), BATCH_SIZE, &node.subscriptions().paused())? {
+        by_peer.entry((delivery.peer.clone(), delivery.port)).or_default().push(delivery);
+    }
+
+    for ((peer, port), deliveries) in by_peer {
+        // pushed over the connection kept with the target
+        if node.supervisor().supervises(&peer, port) {
+            continue;
+        }
+        // no use connecting if not even the first one may be delivered
+        if !node.subscriptions().fits_window(&peer, deliveries[0].envelope.payload.len() as u64) {
+            pause(node, &peer, deliveries);
+            continue;
+        }
+        let url = OSPUrl { domain: peer.clone(), port, scheme: deliveries[0].scheme };
+        let connect = tokio::time::timeout(node.handshake_timeout(), node.create_outbound(url)).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Connecting timed out")));
+        let mut conn = match connect {
+            Ok(conn) => conn,
+            Err(e) => {
+                fail_all(node, deliveries, &e)?;
+                continue;
+            }
+        };
+        if let Pushed::Lost { err, unpushed } = push(node, &mut conn, deliveries).await? {
+            if let Some(closure) = Closure::of(&err) {
+                metrics::connection_closed(closure);
+            }
+            fail_all(node, unpushed, &err)?;
+        }
+    }
+    metrics::delivery_queue_depth(store.delivery_count()?);
+    Ok(())
+}
+
+/// What came of [pushing](push) deliveries over a connection.
+pub(crate) enum Pushed {
+    /// Every delivery was pushed, or failed or was paused by itself.
+    All,
+    /// The connection was lost with `err`. The deliveries in `unpushed`,
+    /// starting with the one it was lost pushing unless that was dropped,
+    /// are left queued, see [lost_in_flight].
+    Lost { err: io::Error, unpushed: Vec<PendingDelivery> },
+}
+
+/// Push `deliveries` to their peer over `conn`, in order.
+pub(crate) async fn push(
+    node: &OSProtocolNode,
+    conn: &mut OutboundConnection<TransferState>,
+    deliveries: Vec<PendingDelivery>,
+) -> io::Result<Pushed> {
+    let Some(first) = deliveries.first() else {
+        return Ok(Pushed::All);
+    };
+    let (peer, store) = (first.peer.clone(), node.data_store());
+    // whether an ordered delivery failed, holding back those after it
+    let mut held = false;
+    let mut deliveries = deliveries.into_iter();
+    while let Some(delivery) = deliveries.next() {
+        if held && delivery.qos == DeliveryQos::Ordered {
+            continue;
+        }
+        let bytes = delivery.envelope.payload.len() as u64;
+        if !node.subscriptions().fits_window(&delivery.peer, bytes) {
+            pause(node, &peer, std::iter::once(delivery).chain(deliveries));
+            break;
+        }
+        let last_sent = conn.sync().last_sent();
+        let pushed = match delivery.qos {
+            DeliveryQos::FireAndForget => conn.push(delivery.envelope.clone()).await,
+            DeliveryQos::AtLeastOnce | DeliveryQos::Ordered => conn.push_acked(delivery.envelope.clone()).await,
+        };
+        match pushed {
+            Ok(_) => {
+                debug!("Delivered object {} to {}", delivery.envelope.object_id, delivery.peer);
+                store.remove_delivery(delivery.id)?;
+                node.subscriptions().take_window(&delivery.peer, bytes);
+                metrics::delivery("delivered", delivery.qos);
+                node.accounting().charge(&delivery.peer, Cost {
+                    blob_bytes: bytes,
+                    ..Cost::default()
+                });
+            }
+            Err(err) if is_lost(&err) => {
+                let sent = conn.sync().last_sent();
+                let delivery = match sent > last_sent {
+                    true => lost_in_flight(node, delivery, sent, &err)?,
+                    false => Some(delivery),
+                };
+                // the ordered ones held back were rescheduled already
+                let unpushed = delivery.into_iter()
+                    .chain(deliveries.filter(|delivery| !(held && delivery.qos == DeliveryQos::Ordered)))
+                    .collect();
+                return Ok(Pushed::Lost { err, unpushed });
+            }
+            Err(e) => {
+                // sent, but the ack didn't arrive in time
+                let unacked = e.kind() == io::ErrorKind::TimedOut && conn.sync().last_sent() > last_sent;
+                let pushed_sequence = unacked.then(|| conn.sync().last_sent());
+                held |= failed(node, delivery, &e, pushed_sequence)?;
+            }
+        }
+    }
+    if let Some(lag) = node.subscriptions().lag(&peer)? {
+        metrics::peer_lag(&peer, lag);
+    }
+    Ok(Pushed::All)
+}
+
+/// Whether `err` means the connection it happened on is gone.
+pub(crate) fn is_lost(err: &io::Error) -> bool {
+    Closure::of(err).is_some()
+}
+
+/// Handle `delivery` by its level after the connection was lost with `err`
+/// once it was pushed with `sequence`, see the [module](self) docs. Returns
+/// the delivery if it stays queued.
+pub(crate) fn lost_in_flight(node: &OSProtocolNode, mut delivery: PendingDelivery, sequence: u64, err: &io::Error) -> io::Result<Option<PendingDelivery>> {
+    if delivery.qos == DeliveryQos::FireAndForget {
+        debug!("Lost the connection to {} after pushing object {}: {err}", delivery.peer, delivery.envelope.object_id);
+        node.data_store().remove_delivery(delivery.id)?;
+        metrics::delivery("dropped", delivery.qos);
+        return Ok(None);
+    }
+    delivery.pushed_sequence = delivery.pushed_sequence.or(Some(sequence));
+    delivery.last_error = Some(err.to_string());
+    node.data_store().put_delivery(&delivery)?;
+    Ok(Some(delivery))
+}
+
+/// Reschedule or drop `deliveries` to a peer that couldn't be pushed to
+/// because of `err`, like [failed].
+fn fail_all(node: &OSProtocolNode, deliveries: Vec<PendingDelivery>, err: &io::Error) -> io::Result<()> {
+    let mut held = false;
+    for delivery in deliveries {
+        // rescheduled after the ordered delivery that failed
+        if !(held && delivery.qos == DeliveryQos::Ordered) {
+            held |= failed(node, delivery, err, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Schedule the next attempt of `delivery` after it failed with `err`, or
+/// drop it if it shouldn't be retried. `pushed_sequence` is the sequence it
+/// was pushed with if it was pushed but not acknowledged. Returns whether it
+/// was an ordered delivery that was rescheduled, along with the ordered
+/// deliveries to the same peer that were queued after it.
+fn failed(node: &OSProtocolNode, mut delivery: PendingDelivery, err: &io::Error, pushed_sequence: Option<u64>) -> io::Result<bool> {
+    let policy = node.retry_policy();
+    delivery.attempts += 1;
+    delivery.last_error = Some(err.to_string());
+    // the first push is the first a report can cover
+    delivery.pushed_sequence = delivery.pushed_sequence.or(pushed_sequence);
+    // refusals don't change by retrying
+    let refused = err.kind() == io::ErrorKind::PermissionDenied;
+    if refused
+        || delivery.qos == DeliveryQos::FireAndForget
+        || policy.max_attempts.is_some_and(|max| delivery.attempts >= max)
+    {
+        warn!(
+            "Dropping delivery of object {} to {} after {} attempt(s): {err}",
+            delivery.envelope.object_id, delivery.peer, delivery.attempts
+        );
+        node.data_store().remove_delivery(delivery.id)?;
+        metrics::delivery("dropped", delivery.qos);
+        return Ok(false);
+    }
+
+    let backoff = policy.backoff(delivery.attempts, random_unit()?);
+    debug!(
+        "Delivering object {} to {} failed, retrying in {backoff:?}: {err}",
+        delivery.envelope.object_id, delivery.peer
+    );
+    delivery.next_attempt_at = now_millis() + backoff.as_millis() as u64;
+    node.data_store().put_delivery(&delivery)?;
+    metrics::delivery("retried", delivery.qos);
+    if delivery.qos != DeliveryQos::Ordered {
+        return Ok(false);
+    }
+    hold_back(node, &delivery)?;
+    Ok(true)
+}
+
+/// Pause the deliveries to `peer`, as its receive window is used up, until
+/// it advertises its window again. The `due` ones stay queued as they are.
+fn pause(node: &OSProtocolNode, peer: &str, due: impl IntoIterator<Item = PendingDelivery>) {
+    node.subscriptions().pause(peer);
+    for delivery in due {
+        metrics::delivery("paused", delivery.qos);
+    }
+    debug!("Paused deliveries to {peer}, its receive window is used up");
+}
+
+/// Drop the queued deliveries to the subscriber `peer` that were pushed
+/// with a sequence up to `processed`, which it reported it processed.
+/// Returns how many were dropped.
+pub(crate) fn trim(node: &OSProtocolNode, peer: &str, processed: u64) -> io::Result<usize> {
+    let store = node.data_store();
+    let Some(port) = store.subscription(peer)?.and_then(|subscription| subscription.port) else {
+        return Ok(0);
+    };
+    let mut trimmed = 0;
+    for queued in store.queued_deliveries(peer, port)? {
+        if queued.pushed_sequence.is_some_and(|pushed| pushed <= processed) {
+            store.remove_delivery(queued.id)?;
+            metrics::delivery("trimmed", queued.qos);
+            trimmed += 1;
+        }
+    }
+    if trimmed > 0 {
+        debug!("Trimmed {trimmed} deliveries {peer} reported it processed");
+        metrics::delivery_queue_depth(store.delivery_count()?);
+    }
+    Ok(trimmed)
+}
+
+/// Reschedule the ordered deliveries to the peer of `delivery` that are
+/// due before it, so they keep their order after it.
+fn hold_back(node: &OSProtocolNode, delivery: &PendingDelivery) -> io::Result<()> {
+    let store = node.data_store();
+    let mut next_attempt_at = delivery.next_attempt_at;
+    for mut later in store.queued_deliveries(&delivery.peer, delivery.port)? {
+        if later.id == delivery.id || later.qos != DeliveryQos::Ordered || later.next_attempt_at > next_attempt_at {
+            continue;
+        }
+        // a millisecond apart, so they are due in the order they were queued
+        next_attempt_at += 1;
+        later.next_attempt_at = next_attempt_at;
+        store.put_delivery(&later)?;
+    }
+    Ok(())
+}
+
+/// When an ordered delivery to `url` queued now is first attempted: after
+/// the ordered deliveries queued before it.
+pub(crate) fn ordered_attempt_at(node: &OSProtocolNode, url: &OSPUrl) -> io::Result<u64> {
+    let last = node.data_store().queued_deliveries(&url.domain, url.port)?.into_iter()
+        .filter(|queued| queued.qos == DeliveryQos::Ordered)
+        .map(|queued| queued.next_attempt_at + 1)
+        .max();
+    Ok(last.unwrap_or_default().max(now_millis()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::delivery::RetryPolicy;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: None,
+        };
+        let backoffs: Vec<u64> = (1..=5).map(|attempts| policy.backoff(attempts, 0.0).as_secs()).collect();
+        assert_eq!(backoffs, [1, 2, 4, 8, 10]);
+        assert_eq!(policy.backoff(u32::MAX, 0.0), Duration::from_secs(10));
+        // jitter takes off up to half
+        assert_eq!(policy.backoff(3, 0.5), Duration::from_secs(3));
+        assert!(policy.backoff(3, 0.999) > Duration::from_secs(2));
+
+        assert!(policy.is_valid());
+        assert!(!RetryPolicy { jitter: 1.5, ..policy }.is_valid());
+        assert!(!RetryPolicy { max_attempts: Some(0), ..policy }.is_valid());
+    }
+}
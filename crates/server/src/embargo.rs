@@ -0,0 +1,96 @@
+//! # Scheduled Publishing
+//!
+//! Objects can be drafted ahead of time and held back until a set time, e.g.
+//! an announcement under embargo. [schedule_publish](OSProtocolNode::schedule_publish)
+//! stores the object in the node's [DataStore](crate::store::DataStore)
+//! apart from the published ones, so it is neither served nor syndicated,
+//! and while the node listens it is [published](OSProtocolNode::publish) once
+//! its `publish_at` time has come.
+//!
+//! Until then, the release can be moved with
+//! [reschedule_publish](OSProtocolNode::reschedule_publish) or called off
+//! with [cancel_publish](OSProtocolNode::cancel_publish). Every change is
+//! emitted as a [NodeEvent]: the object being scheduled, rescheduled or
+//! cancelled, and released or failing to be released, e.g. because it was
+//! taken down in the meantime. An object that fails to be released is
+//! dropped rather than retried.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, warn};
+
+use tokio::io;
+
+use osp_protocol::Envelope;
+
+use crate::OSProtocolNode;
+use crate::events::NodeEvent;
+use crate::reporting::{Fault, FaultKind};
+
+/// How often the node checks for objects that are due.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Objects released per check at most.
+const BATCH_SIZE: usize = 256;
+
+/// An object held back until its release.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduledObject {
+    pub envelope: Envelope,
+    /// Unix timestamp (seconds) the object is published at.
+    pub publish_at: u64,
+    /// Unix timestamp (seconds) the object was scheduled at.
+    pub scheduled_at: u64,
+}
+
+impl ScheduledObject {
+    pub(crate) fn new(envelope: Envelope, publish_at: u64) -> Self {
+        ScheduledObject {
+            envelope,
+            publish_at,
+            scheduled_at: now(),
+        }
+    }
+}
+
+/// Release the objects that are due until the node stops.
+pub(crate) async fn run(node: OSProtocolNode) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = release_due(&node, now()) {
+            let message = format!("Releasing scheduled objects failed: {e}");
+            error!("{message}");
+            node.report_fault(Fault::new(FaultKind::Internal, message));
+        }
+    }
+}
+
+/// Publish the objects due at or before the unix timestamp `now`
+/// (seconds), returning how many were published.
+pub(crate) fn release_due(node: &OSProtocolNode, now: u64) -> io::Result<usize> {
+    let store = node.data_store();
+    let mut released = 0;
+    for scheduled in store.due_scheduled(now, BATCH_SIZE)? {
+        let object_id = scheduled.envelope.object_id;
+        // cancelled since it was looked up
+        if !store.remove_scheduled(object_id)? {
+            continue;
+        }
+        match node.publish(&scheduled.envelope) {
+            Ok(()) => {
+                released += 1;
+                node.emit(NodeEvent::ObjectReleased { object_id });
+            }
+            Err(e) => {
+                warn!("Dropping scheduled object {object_id}: {e}");
+                node.emit(NodeEvent::ReleaseFailed { object_id, reason: e.to_string() });
+            }
+        }
+    }
+    Ok(released)
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
@@ -0,0 +1,74 @@
+//! # Node Events
+//!
+//! Changes to a node's content that embedders may want to react to, e.g. to
+//! update a search index or to forward deletions to peers. Subscribe with
+//! [OSProtocolNode::events](crate::OSProtocolNode::events).
+
+use uuid::Uuid;
+
+use osp_protocol::OSPUrl;
+
+use crate::notice::Notice;
+use crate::selfcheck::SelfCheckReport;
+use crate::store::AuditAction;
+use crate::supervisor::TargetState;
+
+/// Number of events buffered for each subscriber. Subscribers that fall
+/// further behind miss the oldest events.
+pub(crate) const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeEvent {
+    /// An object was removed from the node's store.
+    ObjectDeleted {
+        object_id: Uuid,
+        /// Why it was removed.
+        cause: AuditAction,
+    },
+    /// A peer requested a subscription that awaits the operator's approval.
+    SubscriptionRequested {
+        peer: String,
+    },
+    /// The node switched to new [LiveSettings](crate::reload::LiveSettings).
+    ConfigReloaded,
+    /// An object was held back to be published at `publish_at`, see
+    /// [embargo](crate::embargo).
+    PublishScheduled {
+        object_id: Uuid,
+        publish_at: u64,
+    },
+    /// The release of a scheduled object was moved to `publish_at`.
+    PublishRescheduled {
+        object_id: Uuid,
+        publish_at: u64,
+    },
+    /// A scheduled object was dropped before its release.
+    PublishCancelled {
+        object_id: Uuid,
+    },
+    /// A scheduled object was published.
+    ObjectReleased {
+        object_id: Uuid,
+    },
+    /// Publishing a scheduled object failed, and it was dropped.
+    ReleaseFailed {
+        object_id: Uuid,
+        reason: String,
+    },
+    /// A peer sent a [notice](crate::notice) this node didn't have from it.
+    NoticeReceived {
+        peer: String,
+        notice: Notice,
+    },
+    /// The connection with the push target at `url` moved to `state`, see
+    /// [supervisor](crate::supervisor).
+    PushTargetChanged {
+        url: OSPUrl,
+        state: TargetState,
+    },
+    /// The node checked its environment before it started listening, see
+    /// [selfcheck](crate::selfcheck).
+    SelfChecked {
+        report: SelfCheckReport,
+    },
+}
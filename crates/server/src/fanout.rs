@@ -0,0 +1,346 @@
+//! # Fan-out
+//!
+//! An origin with hundreds of subscribers to a type pushes every object it
+//! publishes hundreds of times. Instead, it can hand delivering them to
+//! designated relays with
+//! [delegate_fanout](crate::OSProtocolNode::delegate_fanout): the origin
+//! only pushes to the relays at the top of a [FanoutTree], and each relay
+//! delivers on to the peers its [Delegation] names, which include the
+//! relays below it. Subscribers the tree has no room for, and those that
+//! subscribe later, are still delivered to by the origin.
+//!
+//! Delegations are signed by the origin, and a relay only accepts them from
+//! an origin it trusts as a
+//! [federation peer](crate::builder::OSProtocolNodeBuilder::federation_peer).
+//! It refuses delegations whose children include itself, the origin or its
+//! parent, and only forwards objects it received from its parent, so
+//! objects can't loop between relays.
+//!
+//! The tree is a snapshot of the subscriptions it was built from. Rebuilding
+//! it, or [revoking](crate::OSProtocolNode::revoke_fanout) it, revokes the
+//! delegations it was made of. As the origin stops pushing to the relays
+//! right away, objects stop flowing down the tree even before a relay that
+//! couldn't be reached learns of the revocation.
+//!
+//! Both the trees a node built and the delegations it relays along are kept
+//! in its [DataStore], so they survive a restart until they expire or are
+//! revoked.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use bytes::BytesMut;
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_protocol::{DelegatedPeer, Delegation, Envelope, OSPUrl};
+use osp_protocol::packet::{DeserializePacket, SerializePacket};
+use osp_protocol::packet::transfer::DeliveryQos;
+
+use crate::crypto::{PrivateKey, PublicKey};
+use crate::store::DataStore;
+
+/// The relays an origin delegates delivering its objects of one type to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FanoutTree {
+    pub type_id: Uuid,
+    /// The relays, in the order they were placed: those the origin pushes
+    /// to first, then those below them, level by level.
+    pub relays: Vec<OSPUrl>,
+    /// The delegation of each of the `relays`, in the same order.
+    pub delegations: Vec<Delegation>,
+}
+
+impl FanoutTree {
+    /// Build the tree delegating the `subscribers` of `type_id` to `relays`,
+    /// each delivering to `fanout` peers at most. Relays are placed level by
+    /// level, the first `fanout` of them below the origin, and subscribers
+    /// are spread over the relays with room left. The delegations are not
+    /// signed yet.
+    pub fn build(
+        origin: &str,
+        type_id: Uuid,
+        subscribers: &[(OSPUrl, DeliveryQos)],
+        relays: &[OSPUrl],
+        fanout: usize,
+        expires_at: Option<u64>,
+    ) -> io::Result<FanoutTree> {
+        let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidInput, reason);
+        if fanout == 0 {
+            return Err(invalid("A fanout of 0 leaves the relays no one to deliver to".to_string()));
+        }
+        for (i, relay) in relays.iter().enumerate() {
+            if relay.domain.eq_ignore_ascii_case(origin) {
+                return Err(invalid(format!("{origin} can't relay its own objects")));
+            }
+            if relays[..i].iter().any(|other| other.domain.eq_ignore_ascii_case(&relay.domain)) {
+                return Err(invalid(format!("{} is listed as a relay twice", relay.domain)));
+            }
+        }
+
+        // the relays are numbered from 1 below the origin, so relay `n`
+        // hangs below `(n - 1) / fanout`, like in a heap
+        let mut delegations: Vec<Delegation> = relays.iter().enumerate().map(|(i, relay)| Delegation {
+            delegation_id: Uuid::new_v4(),
+            origin: origin.to_string(),
+            relay: relay.domain.clone(),
+            parent: match i / fanout {
+                0 => origin.to_string(),
+                parent => relays[parent - 1].domain.clone(),
+            },
+            type_id,
+            children: Vec::new(),
+            expires_at,
+            signature: Vec::new(),
+        }).collect();
+        for (i, relay) in relays.iter().enumerate().skip(fanout) {
+            delegations[i / fanout - 1].children.push(DelegatedPeer { url: relay.clone(), qos: DeliveryQos::AtLeastOnce });
+        }
+
+        // relays get the objects as part of the tree already
+        let mut subscribers = subscribers.iter()
+            .filter(|(url, _)| !url.domain.eq_ignore_ascii_case(origin) && !relays.iter().any(|relay| relay.domain.eq_ignore_ascii_case(&url.domain)));
+        'spread: loop {
+            let mut placed = false;
+            for delegation in delegations.iter_mut().filter(|delegation| delegation.children.len() < fanout) {
+                let Some((url, qos)) = subscribers.next() else {
+                    break 'spread;
+                };
+                delegation.children.push(DelegatedPeer { url: url.clone(), qos: *qos });
+                placed = true;
+            }
+            if !placed {
+                break;
+            }
+        }
+
+        Ok(FanoutTree {
+            type_id,
+            relays: relays.to_vec(),
+            delegations,
+        })
+    }
+
+    /// Whether a relay delivers to `hostname`, so the origin doesn't.
+    pub fn delegates_to(&self, hostname: &str) -> bool {
+        self.delegations.iter().any(|delegation| delegation.delegates_to(hostname))
+    }
+
+    /// The relays the origin pushes to itself, with their delegations.
+    pub fn top(&self) -> impl Iterator<Item = (&OSPUrl, &Delegation)> {
+        self.relays.iter().zip(&self.delegations)
+            .filter(|(_, delegation)| delegation.parent == delegation.origin)
+    }
+
+    /// Whether any of the delegations expired, leaving the peers below its
+    /// relay without anyone delivering to them.
+    fn is_expired(&self, now: u64) -> bool {
+        self.delegations.iter().any(|delegation| is_expired(delegation, now))
+    }
+}
+
+fn is_expired(delegation: &Delegation, now: u64) -> bool {
+    delegation.expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+/// A delegation as kept in a node's records.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DelegationRecord {
+    pub delegation: Delegation,
+    /// Where the relay listens, for delegations this node issued, `None` for
+    /// those it relays along.
+    pub relay_url: Option<OSPUrl>,
+}
+
+#[derive(Default)]
+struct Delegations {
+    /// By type
+    trees: HashMap<Uuid, FanoutTree>,
+    /// By id
+    relayed: HashMap<Uuid, Delegation>,
+}
+
+/// The fan-out trees of a node's own objects, and the delegations it
+/// relays along, stored in its [DataStore].
+pub(crate) struct Fanout {
+    store: Arc<dyn DataStore>,
+    /// Loaded from the store when first needed, and kept in step with it
+    /// after.
+    loaded: RwLock<Option<Delegations>>,
+}
+
+impl Fanout {
+    pub(crate) fn new(store: Arc<dyn DataStore>) -> Self {
+        Fanout {
+            store,
+            loaded: RwLock::new(None),
+        }
+    }
+
+    /// Run `f` on the delegations, loading them from the store first if
+    /// they weren't yet.
+    fn with<T>(&self, f: impl FnOnce(&mut Delegations) -> io::Result<T>) -> io::Result<T> {
+        let mut loaded = self.loaded.write().unwrap();
+        if loaded.is_none() {
+            let mut delegations = Delegations::default();
+            for record in self.store.delegations()? {
+                let delegation = record.delegation;
+                match record.relay_url {
+                    Some(url) => {
+                        let tree = delegations.trees.entry(delegation.type_id).or_insert_with(|| FanoutTree {
+                            type_id: delegation.type_id,
+                            relays: Vec::new(),
+                            delegations: Vec::new(),
+                        });
+                        tree.relays.push(url);
+                        tree.delegations.push(delegation);
+                    }
+                    None => {
+                        delegations.relayed.insert(delegation.delegation_id, delegation);
+                    }
+                }
+            }
+            *loaded = Some(delegations);
+        }
+        f(loaded.as_mut().unwrap())
+    }
+
+    /// The tree of `type_id` objects, unless there is none or it expired.
+    pub(crate) fn tree(&self, type_id: Uuid, now: u64) -> io::Result<Option<FanoutTree>> {
+        self.with(|delegations| Ok(delegations.trees.get(&type_id)
+            .filter(|tree| !tree.is_expired(now))
+            .cloned()))
+    }
+
+    pub(crate) fn insert_tree(&self, tree: FanoutTree) -> io::Result<()> {
+        self.with(|delegations| {
+            for (url, delegation) in tree.relays.iter().zip(&tree.delegations) {
+                self.store.put_delegation(&DelegationRecord {
+                    delegation: delegation.clone(),
+                    relay_url: Some(url.clone()),
+                })?;
+            }
+            delegations.trees.insert(tree.type_id, tree);
+            Ok(())
+        })
+    }
+
+    pub(crate) fn remove_tree(&self, type_id: Uuid) -> io::Result<Option<FanoutTree>> {
+        self.with(|delegations| {
+            let Some(tree) = delegations.trees.remove(&type_id) else {
+                return Ok(None);
+            };
+            for delegation in &tree.delegations {
+                self.store.remove_delegation(delegation.delegation_id)?;
+            }
+            Ok(Some(tree))
+        })
+    }
+
+    pub(crate) fn accept(&self, delegation: Delegation) -> io::Result<()> {
+        self.with(|delegations| {
+            self.store.put_delegation(&DelegationRecord { delegation: delegation.clone(), relay_url: None })?;
+            delegations.relayed.insert(delegation.delegation_id, delegation);
+            Ok(())
+        })
+    }
+
+    /// Drop the delegation with id `delegation_id` issued by `origin`.
+    /// Returns whether there was one.
+    pub(crate) fn revoke(&self, origin: &str, delegation_id: Uuid) -> io::Result<bool> {
+        self.with(|delegations| {
+            match delegations.relayed.get(&delegation_id) {
+                Some(delegation) if delegation.origin.eq_ignore_ascii_case(origin) => {
+                    self.store.remove_delegation(delegation_id)?;
+                    Ok(delegations.relayed.remove(&delegation_id).is_some())
+                }
+                _ => Ok(false),
+            }
+        })
+    }
+
+    /// The peers to forward `envelope` to, received from `peer`, along the
+    /// delegations of its origin that name `peer` as the parent.
+    pub(crate) fn children(&self, envelope: &Envelope, peer: &str, now: u64) -> io::Result<Vec<DelegatedPeer>> {
+        self.with(|delegations| {
+            let expired: Vec<_> = delegations.relayed.values()
+                .filter(|delegation| is_expired(delegation, now))
+                .map(|delegation| delegation.delegation_id)
+                .collect();
+            for delegation_id in expired {
+                self.store.remove_delegation(delegation_id)?;
+                delegations.relayed.remove(&delegation_id);
+            }
+            Ok(delegations.relayed.values()
+                .filter(|delegation| delegation.origin.eq_ignore_ascii_case(&envelope.origin)
+                    && delegation.type_id == envelope.type_id
+                    && delegation.parent.eq_ignore_ascii_case(peer))
+                .flat_map(|delegation| delegation.children.iter().cloned())
+                .collect())
+        })
+    }
+}
+
+/// Encode `delegation` for storing it.
+pub(crate) fn encode(delegation: &Delegation) -> io::Result<Vec<u8>> {
+    let mut buf = BytesMut::new();
+    delegation.serialize(&mut buf)?;
+    Ok(buf.to_vec())
+}
+
+/// Decode a delegation encoded with [encode].
+pub(crate) fn decode(bytes: &[u8]) -> io::Result<Delegation> {
+    Delegation::deserialize(&mut BytesMut::from(bytes))
+}
+
+pub(crate) fn sign(delegation: &mut Delegation, key: &PrivateKey) -> io::Result<()> {
+    delegation.signature = key.sign(&delegation.signed_bytes())?;
+    Ok(())
+}
+
+/// Whether `delegation` carries a valid signature made with the private key
+/// matching `key`.
+pub(crate) fn verify(delegation: &Delegation, key: &PublicKey) -> io::Result<bool> {
+    key.verify(&delegation.signed_bytes(), &delegation.signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use uuid::Uuid;
+
+    use osp_protocol::{OSPUrl, Scheme};
+    use osp_protocol::packet::transfer::DeliveryQos;
+
+    use crate::fanout::FanoutTree;
+
+    fn url(domain: &str) -> OSPUrl {
+        OSPUrl { domain: domain.to_string(), port: 57400, scheme: Scheme::Osp }
+    }
+
+    #[test]
+    fn test_build() -> io::Result<()> {
+        let relays: Vec<_> = ["r1.test", "r2.test", "r3.test"].into_iter().map(url).collect();
+        let subscribers: Vec<_> = (0..8).map(|i| (url(&format!("s{i}.test")), DeliveryQos::default()))
+            .chain([(url("r3.test"), DeliveryQos::default())])
+            .collect();
+        let tree = FanoutTree::build("origin.test", Uuid::new_v4(), &subscribers, &relays, 2, None)?;
+
+        let parents: Vec<_> = tree.delegations.iter().map(|delegation| delegation.parent.as_str()).collect();
+        assert_eq!(parents, ["origin.test", "origin.test", "r1.test"]);
+        assert_eq!(tree.top().count(), 2);
+        // r1 has r3 below it and room for one subscriber, the others two
+        let children: Vec<_> = tree.delegations.iter().map(|delegation| delegation.children.len()).collect();
+        assert_eq!(children, [2, 2, 2]);
+        assert!(tree.delegations[0].delegates_to("r3.test"));
+        assert!(tree.delegates_to("s4.test") && !tree.delegates_to("s5.test"));
+
+        assert!(FanoutTree::build("origin.test", Uuid::new_v4(), &subscribers, &relays, 0, None).is_err());
+        assert!(FanoutTree::build("origin.test", Uuid::new_v4(), &subscribers, &[url("origin.test")], 2, None).is_err());
+        assert!(FanoutTree::build("origin.test", Uuid::new_v4(), &subscribers, &[url("r1.test"), url("r1.test")], 2, None).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+//! # Federation Rules
+//!
+//! Allow and deny lists deciding which hosts a node federates with. A denied
+//! host is refused in both directions. A node built with
+//! `allowlist_only(true)` additionally refuses every host that isn't
+//! allowed.
+//!
+//! Nodes operated together can share their rules as a trusted cluster: each
+//! member is built with the hostnames and public keys of the others through
+//! `federation_peer`, and publishes [FederationUpdate]s signed with its own
+//! key like any other object. Members merge the updates they receive from
+//! each other, keeping the most recently set rule for every host, so rules
+//! configured on one node reach the whole cluster.
+
+use tokio::io;
+
+pub use osp_data::standard::{FederationAction, FederationRule, FederationUpdate};
+
+use crate::crypto::{PrivateKey, PublicKey};
+
+#[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+pub(crate) fn sign(update: &mut FederationUpdate, key: &PrivateKey) -> io::Result<()> {
+    update.signature = key.sign(&update.signed_bytes()?)?;
+    Ok(())
+}
+
+/// Whether `update` carries a valid signature made with the private key
+/// matching `key`.
+pub(crate) fn verify(update: &FederationUpdate, key: &PublicKey) -> io::Result<bool> {
+    key.verify(&update.signed_bytes()?, &update.signature)
+}
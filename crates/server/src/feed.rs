@@ -0,0 +1,233 @@
+//! # RSS Bridge
+//!
+//! Publishers moving from RSS to OSP can't switch every reader over at once.
+//! With the `rss-bridge` feature, a node can keep polling a publisher's
+//! legacy feed with an [RssSource] and advertise its items over OSP in the
+//! meantime: each new item is stored as an object of the source's type,
+//! with the node itself as its origin, as it can't vouch for the publisher,
+//! and the item, as a [FeedItem] in JSON, as its payload, and syndicated to
+//! the node's subscribers like an object it published. Feeds longer than
+//! [MAX_FEED_LENGTH] are refused.
+//!
+//! Once the publisher runs a node of its own and pushes the same items
+//! natively, both paths carry the same content. Items are matched by their
+//! [key](FeedItem::key): an object the publisher pushes is dropped if the
+//! node bridged its item already, and an item the publisher pushed first is
+//! not bridged again, so subscribers get every item once whichever way it
+//! arrived. For this, the publisher pushes its items as objects of the
+//! source's type whose payloads are [FeedItem]s.
+
+use std::io::Read;
+use std::time::Duration;
+
+use log::{debug, error, info};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_protocol::Envelope;
+
+use crate::OSProtocolNode;
+use crate::reporting::{Fault, FaultKind};
+
+/// How often a source is polled by default.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// The longest feed document read, in bytes.
+pub const MAX_FEED_LENGTH: u64 = 8 * 1024 * 1024;
+
+/// A legacy RSS feed to bridge, see the [module](self) docs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RssSource {
+    pub url: String,
+    /// Hostname of the publisher, the origin of the objects it pushes
+    /// natively.
+    pub origin: String,
+    /// Type of the bridged objects.
+    pub type_id: Uuid,
+    pub interval: Duration,
+}
+
+impl RssSource {
+    /// Bridge the feed at `url` of the publisher `origin` as objects of
+    /// `type_id`, polled every [DEFAULT_POLL_INTERVAL].
+    pub fn new(url: impl Into<String>, origin: impl Into<String>, type_id: Uuid) -> Self {
+        RssSource {
+            url: url.into(),
+            origin: origin.into(),
+            type_id,
+            interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    pub fn poll_every(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+/// An item of a feed, the payload of bridged objects.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeedItem {
+    pub guid: Option<String>,
+    pub title: Option<String>,
+    pub link: Option<String>,
+    pub description: Option<String>,
+    /// Publication date, as given in the feed.
+    pub published: Option<String>,
+}
+
+impl FeedItem {
+    /// What tells the item apart from the others of its feed: its guid, or
+    /// its link if it has none.
+    pub fn key(&self) -> Option<&str> {
+        self.guid.as_deref().or(self.link.as_deref())
+    }
+}
+
+impl From<&rss::Item> for FeedItem {
+    fn from(item: &rss::Item) -> Self {
+        FeedItem {
+            guid: item.guid().map(|guid| guid.value().to_string()),
+            title: item.title().map(str::to_string),
+            link: item.link().map(str::to_string),
+            description: item.description().map(str::to_string),
+            published: item.pub_date().map(str::to_string),
+        }
+    }
+}
+
+/// Read the items of the RSS document `xml`, in the order the feed lists
+/// them.
+pub fn parse(xml: &[u8]) -> io::Result<Vec<FeedItem>> {
+    let channel = rss::Channel::read_from(xml)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid RSS feed: {e}")))?;
+    Ok(channel.items().iter().map(FeedItem::from).collect())
+}
+
+/// Poll `source` and bridge its new items until the node stops.
+pub(crate) async fn run(node: OSProtocolNode, source: RssSource) {
+    let mut interval = tokio::time::interval(source.interval);
+    loop {
+        interval.tick().await;
+        let polled = match fetch(&source.url).await {
+            Ok(items) => bridge(&node, &source, &items),
+            Err(e) => Err(e),
+        };
+        match polled {
+            Ok(0) => {}
+            Ok(bridged) => info!("Bridged {bridged} new item(s) from {}", source.url),
+            Err(e) => {
+                let message = format!("Polling RSS feed {} failed: {e}", source.url);
+                error!("{message}");
+                node.report_fault(Fault::new(FaultKind::Internal, message).peer(Some(&source.origin)));
+            }
+        }
+    }
+}
+
+async fn fetch(url: &str) -> io::Result<Vec<FeedItem>> {
+    let url = url.to_string();
+    tokio::task::spawn_blocking(move || {
+        let response = ureq::get(&url)
+            .timeout(Duration::from_secs(30))
+            .call()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        let mut xml = Vec::new();
+        response.into_reader().take(MAX_FEED_LENGTH + 1).read_to_end(&mut xml)?;
+        if xml.len() as u64 > MAX_FEED_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The feed is longer than {MAX_FEED_LENGTH} bytes")));
+        }
+        parse(&xml)
+    }).await?
+}
+
+/// Store and syndicate the `items` of `source` that were neither bridged
+/// nor pushed by the publisher yet, oldest first. Returns how many were
+/// bridged.
+pub(crate) fn bridge(node: &OSProtocolNode, source: &RssSource, items: &[FeedItem]) -> io::Result<usize> {
+    let store = node.data_store();
+    let mut bridged = 0;
+    // feeds list the newest items first
+    for item in items.iter().rev() {
+        let Some(key) = item.key() else {
+            debug!("Skipping an item of {} without a guid or link", source.url);
+            continue;
+        };
+        if store.bridged_item(&source.origin, key)?.is_some() {
+            continue;
+        }
+        let payload = serde_json::to_vec(item).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let envelope = Envelope::new(source.type_id, node.hostname().to_string(), payload);
+        node.store_object(&envelope)?;
+        store.put_bridged_item(&source.origin, key, envelope.object_id)?;
+        node.syndicate(&envelope, None)?;
+        bridged += 1;
+    }
+    Ok(bridged)
+}
+
+/// The key of the item `envelope` carries, if it is an object a publisher
+/// bridged by one of `sources` pushed natively.
+pub(crate) fn native_key(sources: &[RssSource], envelope: &Envelope) -> Option<String> {
+    if !sources.iter().any(|source| source.origin == envelope.origin && source.type_id == envelope.type_id) {
+        return None;
+    }
+    let item: FeedItem = serde_json::from_slice(&envelope.payload).ok()?;
+    item.key().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use uuid::Uuid;
+
+    use osp_protocol::Envelope;
+
+    use crate::OSProtocolNode;
+    use crate::crypto::PrivateKey;
+    use crate::feed::{self, FeedItem, RssSource};
+    use crate::store::SqliteStore;
+
+    const FEED: &str = r#"<?xml version="1.0"?>
+        <rss version="2.0"><channel>
+            <title>Legacy</title><link>https://legacy.test</link><description>News</description>
+            <item><guid>3</guid><title>Third</title></item>
+            <item><title>Second</title><link>https://legacy.test/2</link></item>
+            <item><guid>1</guid><title>First</title></item>
+            <item><title>Untitled</title></item>
+        </channel></rss>"#;
+
+    #[test]
+    fn test_bridge() -> io::Result<()> {
+        let source = RssSource::new("https://legacy.test/feed.xml", "legacy.test", Uuid::new_v4());
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .rss_source(source.clone())
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let items = feed::parse(FEED.as_bytes())?;
+        assert_eq!(items.iter().map(FeedItem::key).collect::<Vec<_>>(), [Some("3"), Some("https://legacy.test/2"), Some("1"), None]);
+
+        // the publisher pushed the newest item natively before it was polled
+        let native = FeedItem { guid: Some("3".to_string()), ..FeedItem::default() };
+        let pushed = Envelope::new(source.type_id, "legacy.test".to_string(), serde_json::to_vec(&native).unwrap());
+        assert_eq!(node.bridged_key(&pushed).as_deref(), Some("3"));
+        assert_eq!(node.bridged_key(&Envelope { origin: "other.test".to_string(), ..pushed.clone() }), None);
+        node.data_store().put_bridged_item("legacy.test", "3", pushed.object_id)?;
+
+        assert_eq!(feed::bridge(&node, &source, &items)?, 2);
+        assert_eq!(feed::bridge(&node, &source, &items)?, 0);
+        let bridged = node.data_store().bridged_item("legacy.test", "1")?.unwrap();
+        let envelope = node.data_store().get_object(bridged)?.unwrap();
+        assert_eq!(envelope.origin, "node.test");
+        assert_eq!(serde_json::from_slice::<FeedItem>(&envelope.payload).unwrap().title.as_deref(), Some("First"));
+        Ok(())
+    }
+}
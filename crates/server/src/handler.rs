@@ -0,0 +1,506 @@
+//! # Handlers
+//!
+//! Application code registered on a node to run for every received object of
+//! a data type. Handlers are registered with an id, which identifies them in
+//! logs and lets a [replay](crate::OSProtocolNode::replay) target a single
+//! handler. Handlers are async and can fail, in which case the node refuses
+//! the object with a [Nack](osp_protocol::packet::transfer::TransferPacketHostToGuest::Nack).
+//! The object stays stored, so it can be replayed once the handler is fixed.
+//! Otherwise they tell how they took in the object with a [HandlerStatus]
+//! when wrapped in [WithStatus](osp_data::WithStatus),
+//! which the node sends back in its
+//! [Ack](osp_protocol::packet::transfer::TransferPacketHostToGuest::Ack).
+//!
+//! [Validator]s run before an object is stored and can refuse it, after which
+//! [Transform]s can rewrite its payload, e.g. to sanitize it.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{error, warn};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_data::{Data, DataHandler, HandlerError, HandlerStatus};
+use osp_protocol::Envelope;
+
+use crate::metrics;
+use crate::reporting::{self, Fault, FaultKind, Reporters};
+use crate::stream::ErasedStreamHandler;
+use crate::trace;
+
+pub(crate) type HandlerFuture = Pin<Box<dyn Future<Output = Result<HandlerStatus, HandlerError>> + Send>>;
+pub(crate) type ErasedHandler = dyn Fn(Envelope) -> HandlerFuture + Send + Sync;
+
+struct RegisteredHandler {
+    id: String,
+    handler: Box<ErasedHandler>,
+}
+
+/// How a single handler invocation ended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum HandlerOutcome {
+    Ok,
+    Error,
+    Panic,
+}
+
+/// The handlers registered on a node, keyed by data type id.
+#[derive(Clone)]
+pub(crate) struct Handlers {
+    handlers: HashMap<Uuid, Vec<Arc<RegisteredHandler>>>,
+    /// Invocations taking longer than this are logged as slow.
+    slow_threshold: Duration,
+    /// Told about handlers that panic.
+    reporters: Reporters,
+    /// Takes in the streams peers send, see [stream](crate::stream).
+    stream: Option<Arc<ErasedStreamHandler>>,
+}
+
+impl Default for Handlers {
+    fn default() -> Self {
+        Handlers {
+            handlers: HashMap::new(),
+            slow_threshold: Duration::from_secs(1),
+            reporters: Reporters::default(),
+            stream: None,
+        }
+    }
+}
+
+impl Handlers {
+    pub(crate) fn set_slow_threshold(&mut self, threshold: Duration) {
+        self.slow_threshold = threshold;
+    }
+
+    pub(crate) fn set_reporters(&mut self, reporters: Reporters) {
+        self.reporters = reporters;
+    }
+
+    pub(crate) fn set_stream_handler(&mut self, handler: Arc<ErasedStreamHandler>) {
+        self.stream = Some(handler);
+    }
+
+    pub(crate) fn stream_handler(&self) -> Option<&ErasedStreamHandler> {
+        self.stream.as_deref()
+    }
+
+    pub(crate) fn register<T: Data, H: DataHandler<T> + 'static>(&mut self, id: &str, handler: H) {
+        let handler = Arc::new(handler);
+        self.register_raw(id, T::TYPE_ID, Box::new(move |envelope| {
+            let handler = handler.clone();
+            Box::pin(async move { handler.handle(T::from_envelope(&envelope)?, envelope).await })
+        }));
+    }
+
+    /// Register a handler working on undecoded envelopes of `type_id`.
+    pub(crate) fn register_raw(&mut self, id: &str, type_id: Uuid, handler: Box<ErasedHandler>) {
+        self.handlers.entry(type_id).or_default().push(Arc::new(RegisteredHandler {
+            id: id.to_string(),
+            handler,
+        }));
+    }
+
+    /// Run the handlers for the type of `envelope`, or only the handler
+    /// `only` if given, returning how those that succeeded took in the
+    /// object, see [combine], and the errors of those that failed. `peer`
+    /// is the peer that sent the object, if it wasn't replayed.
+    pub(crate) async fn dispatch(&self, envelope: &Envelope, only: Option<&str>, peer: Option<&str>) -> (HandlerStatus, Vec<HandlerError>) {
+        let Some(handlers) = self.handlers.get(&envelope.type_id) else { return (HandlerStatus::Processed, Vec::new()) };
+
+        let (mut statuses, mut errors) = (Vec::new(), Vec::new());
+        for registered in handlers.iter().filter(|h| only.is_none_or(|id| h.id == id)) {
+            match self.invoke(registered, envelope, peer).await {
+                Ok(status) => statuses.push(status),
+                Err(e) => errors.push(e),
+            }
+        }
+        (combine(&statuses), errors)
+    }
+
+    async fn invoke(&self, registered: &RegisteredHandler, envelope: &Envelope, peer: Option<&str>) -> Result<HandlerStatus, HandlerError> {
+        let started = Instant::now();
+        let span = trace::Span::start("osp.dispatch");
+        span.attribute("osp.handler", &registered.id);
+        span.attribute("osp.object_id", envelope.object_id);
+        // a panicking handler must not take the connection down with it, so
+        // it runs as a task of its own
+        let (outcome, result) = match tokio::spawn(span.instrument((registered.handler)(envelope.clone()))).await {
+            Ok(Ok(status)) => (HandlerOutcome::Ok, Ok(status)),
+            Ok(Err(e)) => {
+                error!("Handler {} failed on object {}: {e}", registered.id, envelope.object_id);
+                span.fail(&e);
+                (HandlerOutcome::Error, Err(e))
+            }
+            Err(e) => {
+                let message = e.try_into_panic().map_or_else(|e| e.to_string(), |payload| reporting::panic_message(&*payload));
+                error!("Handler {} panicked on object {}: {message}", registered.id, envelope.object_id);
+                span.fail(&message);
+                self.reporters.report(Fault::new(FaultKind::HandlerPanic, message)
+                    .peer(peer)
+                    .handler(&registered.id)
+                    .object(envelope.object_id));
+                let e = io::Error::other(format!("Handler {} panicked", registered.id));
+                (HandlerOutcome::Panic, Err(e.into()))
+            }
+        };
+
+        let elapsed = started.elapsed();
+        if elapsed > self.slow_threshold {
+            warn!(
+                "Handler {} took {elapsed:?} on object {}, over the {:?} slow handler threshold",
+                registered.id, envelope.object_id, self.slow_threshold
+            );
+            metrics::handler_slow(&registered.id);
+        }
+        metrics::handler_invocation(&registered.id, elapsed, outcome);
+        result
+    }
+}
+
+/// The status of an object several handlers took in: deferred if any of
+/// them deferred it, ignored if all of them ignored it, and processed
+/// otherwise, including when there are no handlers.
+fn combine(statuses: &[HandlerStatus]) -> HandlerStatus {
+    if statuses.contains(&HandlerStatus::Deferred) {
+        HandlerStatus::Deferred
+    } else if !statuses.is_empty() && statuses.iter().all(|status| *status == HandlerStatus::Ignored) {
+        HandlerStatus::Ignored
+    } else {
+        HandlerStatus::Processed
+    }
+}
+
+/// Decides whether a received object is accepted. A refused object is not
+/// stored and the error is sent back to the peer as the reason.
+pub trait Validator: Send + Sync {
+    fn validate(&self, envelope: &Envelope) -> io::Result<()>;
+}
+
+impl<F> Validator for F
+where
+    F: Fn(&Envelope) -> io::Result<()> + Send + Sync,
+{
+    fn validate(&self, envelope: &Envelope) -> io::Result<()> {
+        self(envelope)
+    }
+}
+
+/// Hooks registered on a node with their ids, keyed by data type id.
+type ByType<T> = HashMap<Uuid, Vec<(String, Arc<T>)>>;
+
+/// The validators registered on a node, keyed by data type id.
+#[derive(Clone, Default)]
+pub(crate) struct Validators {
+    validators: ByType<dyn Validator>,
+}
+
+impl Validators {
+    pub(crate) fn register(&mut self, id: &str, type_id: Uuid, validator: Arc<dyn Validator>) {
+        self.validators.entry(type_id).or_default().push((id.to_string(), validator));
+    }
+
+    /// Run the validators for the type of `envelope`, stopping at the first
+    /// that refuses it.
+    pub(crate) fn validate(&self, envelope: &Envelope) -> io::Result<()> {
+        for (id, validator) in self.validators.get(&envelope.type_id).into_iter().flatten() {
+            validator.validate(envelope).map_err(|e| {
+                warn!("Validator {id} refused object {}: {e}", envelope.object_id);
+                e
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Rewrites received objects before they are stored and handled.
+pub trait Transform: Send + Sync {
+    fn transform(&self, envelope: Envelope) -> io::Result<Envelope>;
+}
+
+impl<F> Transform for F
+where
+    F: Fn(Envelope) -> io::Result<Envelope> + Send + Sync,
+{
+    fn transform(&self, envelope: Envelope) -> io::Result<Envelope> {
+        self(envelope)
+    }
+}
+
+/// The transforms registered on a node, keyed by data type id.
+#[derive(Clone, Default)]
+pub(crate) struct Transforms {
+    transforms: ByType<dyn Transform>,
+}
+
+impl Transforms {
+    pub(crate) fn register(&mut self, id: &str, type_id: Uuid, transform: Arc<dyn Transform>) {
+        self.transforms.entry(type_id).or_default().push((id.to_string(), transform));
+    }
+
+    /// Run the transforms for the type of `envelope` in the order they were
+    /// registered.
+    pub(crate) fn apply(&self, mut envelope: Envelope) -> io::Result<Envelope> {
+        let Some(transforms) = self.transforms.get(&envelope.type_id) else { return Ok(envelope) };
+        for (id, transform) in transforms {
+            let object_id = envelope.object_id;
+            envelope = transform.transform(envelope).map_err(|e| {
+                warn!("Transform {id} failed on object {object_id}: {e}");
+                e
+            })?;
+        }
+        Ok(envelope)
+    }
+}
+
+/// Which stored objects to [replay](crate::OSProtocolNode::replay). Every
+/// field left as `None` matches all objects.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayFilter {
+    pub type_id: Option<Uuid>,
+    pub origin: Option<String>,
+    /// Only objects created at or after this unix timestamp.
+    pub created_after: Option<u64>,
+    /// Only objects created before this unix timestamp.
+    pub created_before: Option<u64>,
+    /// Only run the handler registered with this id.
+    pub handler: Option<String>,
+}
+
+impl ReplayFilter {
+    pub(crate) fn matches(&self, envelope: &Envelope) -> bool {
+        self.type_id.is_none_or(|id| envelope.type_id == id)
+            && self.origin.as_ref().is_none_or(|origin| &envelope.origin == origin)
+            && self.created_after.is_none_or(|after| envelope.created_at >= after)
+            && self.created_before.is_none_or(|before| envelope.created_at < before)
+    }
+}
+
+/// The outcome of a replay.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReplayReport {
+    /// Number of objects that matched the filter and were dispatched.
+    pub objects: usize,
+    /// Number of handler invocations that failed.
+    pub failures: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde::{Deserialize, Serialize};
+    use tokio::io;
+
+    use osp_data::{impl_data, Data, DataTypeRegistry, HandlerError, HandlerStatus, WithStatus};
+    use osp_protocol::{Envelope, PayloadFormat};
+
+    use crate::OSProtocolNode;
+    use crate::crypto::PrivateKey;
+    use crate::handler::ReplayFilter;
+    use crate::reporting::{Fault, FaultKind};
+    use crate::store::MemoryStore;
+    use crate::subscription::DeliveryQos;
+
+    #[derive(Serialize, Deserialize)]
+    struct Note {
+        text: String,
+    }
+
+    impl_data!(Note, "9b0c3b7e-8d0e-4f59-a4f4-0a5e6b2f9d21");
+
+    #[derive(Serialize, Deserialize)]
+    struct TitledNote {
+        title: String,
+        text: String,
+    }
+
+    impl_data!(TitledNote, "9b0c3b7e-8d0e-4f59-a4f4-0a5e6b2f9d21", "Note", 2);
+
+    impl From<Note> for TitledNote {
+        fn from(note: Note) -> Self {
+            TitledNote { title: String::new(), text: note.text }
+        }
+    }
+
+    impl From<TitledNote> for Note {
+        fn from(note: TitledNote) -> Self {
+            Note { text: note.text }
+        }
+    }
+
+    #[test]
+    fn test_replay() -> io::Result<()> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .data_store(MemoryStore::new())
+            .handler("record", {
+                let seen = seen.clone();
+                move |note: Note, _: Envelope| {
+                    seen.lock().unwrap().push(note.text);
+                    async { Ok(()) }
+                }
+            })
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+
+        for (text, origin) in [("a", "one.test"), ("b", "two.test"), ("c", "one.test")] {
+            let envelope = Note { text: text.to_string() }.to_envelope(origin.to_string())?;
+            node.data_store().put_object(&envelope)?;
+        }
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let report = runtime.block_on(node.replay(ReplayFilter { origin: Some("one.test".to_string()), ..Default::default() }))?;
+        assert_eq!((report.objects, report.failures), (2, 0));
+        assert_eq!(*seen.lock().unwrap(), vec!["a", "c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_handler_status() -> io::Result<()> {
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .handler("queue", WithStatus(|note: Note, _: Envelope| async move {
+                Ok(match note.text.as_str() {
+                    "later" => HandlerStatus::Deferred,
+                    _ => HandlerStatus::Ignored,
+                })
+            }))
+            .handler("log", WithStatus(|note: Note, _: Envelope| async move {
+                Ok(match note.text.as_str() {
+                    "" => HandlerStatus::Ignored,
+                    _ => HandlerStatus::Processed,
+                })
+            }))
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        for (text, status) in [("later", HandlerStatus::Deferred), ("now", HandlerStatus::Processed), ("", HandlerStatus::Ignored)] {
+            let envelope = Note { text: text.to_string() }.to_envelope("origin.test".to_string())?;
+            assert_eq!(runtime.block_on(node.dispatch(&envelope, "peer.test")).ok(), Some(status));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_panicking_handler_is_contained() -> io::Result<()> {
+        let faults = Arc::new(Mutex::new(Vec::new()));
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .handler("panics", |_: Note, _: Envelope| async { panic!("bad handler") })
+            .handler("works", |_: Note, _: Envelope| async { Ok(()) })
+            .error_reporter({
+                let faults = faults.clone();
+                move |fault: &Fault| faults.lock().unwrap().push(fault.clone())
+            })
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+
+        let envelope = Note { text: "a".to_string() }.to_envelope("origin.test".to_string())?;
+        node.data_store().put_object(&envelope)?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        assert_eq!(runtime.block_on(node.replay(ReplayFilter::default()))?.failures, 1);
+
+        let faults = faults.lock().unwrap();
+        assert_eq!(faults.len(), 1);
+        assert_eq!((faults[0].kind, faults[0].message.as_str()), (FaultKind::HandlerPanic, "bad handler"));
+        assert_eq!((faults[0].handler.as_deref(), faults[0].object_id), (Some("panics"), Some(envelope.object_id)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_failing_handler_refuses_object() -> io::Result<()> {
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .handler("picky", |note: Note, _: Envelope| async move {
+                match note.text.is_empty() {
+                    true => Err(HandlerError::rejected("Empty note")),
+                    false => Ok(()),
+                }
+            })
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let note = Note { text: "a".to_string() }.to_envelope("origin.test".to_string())?;
+            assert!(node.dispatch(&note, "peer.test").await.is_ok());
+            let empty = Note { text: String::new() }.to_envelope("origin.test".to_string())?;
+            assert!(matches!(node.dispatch(&empty, "peer.test").await, Err(HandlerError::Rejected(_))));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_registry_handlers() -> io::Result<()> {
+        let mut registry = DataTypeRegistry::new();
+        registry.handler("picky", |note: Note, _: Envelope| async move {
+            match note.text.is_empty() {
+                true => Err(HandlerError::rejected("Empty note")),
+                false => Ok(()),
+            }
+        })?;
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .data_types(&registry)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let empty = Note { text: String::new() }.to_envelope("origin.test".to_string())?;
+            assert!(matches!(node.dispatch(&empty, "peer.test").await, Err(HandlerError::Rejected(_))));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_registry_versions() -> io::Result<()> {
+        let mut registry = DataTypeRegistry::new();
+        registry.upgrade::<Note, TitledNote>()?.downgrade::<TitledNote, Note>()?;
+        registry.handler("picky", |note: Note, _: Envelope| async move {
+            match note.text.is_empty() {
+                true => Err(HandlerError::rejected("Empty note")),
+                false => Ok(()),
+            }
+        })?;
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .data_types(&registry)
+            .data_store(MemoryStore::new())
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        node.request_subscription("old.test", vec![Note::TYPE_ID], Vec::new(), DeliveryQos::default(), Some(57400), vec![(Note::TYPE_ID, 1)])?;
+        node.request_subscription("new.test", vec![Note::TYPE_ID], Vec::new(), DeliveryQos::default(), Some(57400), node.type_versions(&[Note::TYPE_ID]))?;
+
+        // received objects are kept as the latest version
+        let received = node.upgrade(Note { text: "Hi".to_string() }.to_envelope("origin.test".to_string())?);
+        assert_eq!(received.type_version, 2);
+        assert_eq!(TitledNote::from_envelope(&received)?.text, "Hi");
+
+        // and pushed in the version each subscriber understands
+        assert_eq!(node.translate_for("old.test", received.clone())?.type_version, 1);
+        assert_eq!(node.translate_for("new.test", received.clone())?.type_version, 2);
+        assert_eq!(node.translate_for("other.test", received.clone())?.type_version, 2);
+        let transcoded = node.transcode(received, PayloadFormat::Json);
+        assert_eq!(transcoded.format, PayloadFormat::Json);
+        assert_eq!(TitledNote::from_envelope(&transcoded)?.text, "Hi");
+
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let empty = TitledNote { title: "Empty".to_string(), text: String::new() }.to_envelope("origin.test".to_string())?;
+            assert!(matches!(node.dispatch(&empty, "peer.test").await, Err(HandlerError::Rejected(_))));
+            Ok(())
+        })
+    }
+}
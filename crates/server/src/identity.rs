@@ -0,0 +1,235 @@
+//! # Identity Document
+//!
+//! A signed JSON document describing a node, served over HTTP at
+//! [WELL_KNOWN_PATH]. It complements the `_osp` DNS record with what a
+//! prospective peer wants to know before connecting: where to reach the
+//! node, which envelope versions and data types it supports, its public
+//! keys, who operates it and how it treats peers.
+//!
+//! The document is signed with the node's private key. The keys it lists
+//! are only a claim, so a peer checks the signature against the key in the
+//! node's DNS record instead, see `fetch` (with the `identity-fetch`
+//! feature).
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use log::{debug, info};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+use uuid::Uuid;
+
+use crate::crypto::{PrivateKey, PublicKey};
+#[cfg(feature = "identity-fetch")]
+use crate::connection::dns::{self, ChallengeResolver};
+
+/// Path the identity document is served at.
+pub const WELL_KNOWN_PATH: &str = "/.well-known/osp";
+
+/// Largest request head accepted by the identity endpoint.
+const MAX_REQUEST_HEAD: usize = 8192;
+
+/// How many requests the identity endpoint serves at once. Further clients
+/// wait in the listen backlog.
+const MAX_CONNECTIONS: usize = 64;
+
+/// How long a client gets to send its request and read the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IdentityDocument {
+    pub hostname: String,
+    /// OSP URLs the node accepts connections at, e.g.
+    /// `osp://example.com:57401`.
+    pub urls: Vec<String>,
+    /// Envelope versions the node can decode.
+    pub envelope_versions: Vec<u8>,
+    /// PEM encoded public keys of the node.
+    pub public_keys: Vec<String>,
+    /// Data types the node handles.
+    pub data_types: Vec<DataTypeSummary>,
+    /// How to reach the node's operator, such as an email address.
+    pub contact: Option<String>,
+    pub policies: IdentityPolicies,
+    /// Unix timestamp (seconds) the document was issued at.
+    pub issued_at: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DataTypeSummary {
+    pub type_id: Uuid,
+    pub name: String,
+}
+
+/// How a node treats its peers.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct IdentityPolicies {
+    /// The node refuses every object pushed to it.
+    pub read_only: bool,
+    /// The node only accepts peers it invited.
+    pub invite_only: bool,
+    /// The node only federates with hosts it allowed.
+    pub allowlist_only: bool,
+    /// The node applies takedowns sent by its peers.
+    pub honors_takedowns: bool,
+    /// Subscriptions wait for the operator's approval.
+    pub manual_subscription_approval: bool,
+}
+
+/// An [IdentityDocument] with its signature, as served.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignedIdentityDocument {
+    pub document: IdentityDocument,
+    /// Base64 encoded signature over the
+    /// [canonical encoding](osp_data::canonical) of the document. Unlike its
+    /// JSON encoding, that doesn't depend on how the document was
+    /// serialized.
+    pub signature: String,
+}
+
+impl SignedIdentityDocument {
+    pub(crate) fn sign(document: IdentityDocument, key: &PrivateKey) -> io::Result<Self> {
+        let signature = key.sign(&osp_data::canonical::to_vec(&document)?)?;
+        Ok(Self {
+            document,
+            signature: BASE64.encode(signature),
+        })
+    }
+
+    /// Whether the document carries a valid signature made with the private
+    /// key matching `key`.
+    pub fn verify(&self, key: &PublicKey) -> io::Result<bool> {
+        let Ok(signature) = BASE64.decode(&self.signature) else { return Ok(false) };
+        key.verify(&osp_data::canonical::to_vec(&self.document)?, &signature)
+    }
+}
+
+/// Serve `document` at [WELL_KNOWN_PATH] to HTTP clients connecting to
+/// `addr`. The endpoint speaks plain HTTP, and is meant to sit behind a
+/// reverse proxy that terminates TLS.
+pub(crate) async fn serve(addr: SocketAddr, document: SignedIdentityDocument) -> io::Result<()> {
+    let body = Arc::new(serde_json::to_vec(&document)?);
+    let listener = TcpListener::bind(addr).await?;
+    let slots = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    info!("Serving the identity document on {addr}");
+    loop {
+        let slot = slots.clone().acquire_owned().await.expect("semaphore is never closed");
+        let (stream, peer) = listener.accept().await?;
+        let body = body.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &body)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Identity document request from {peer} failed: {e}"),
+                Err(_) => debug!("Identity document request from {peer} timed out"),
+            }
+            drop(slot);
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, body: &[u8]) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return write_response(&mut stream, "431 Request Header Fields Too Large", None).await;
+        }
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default().split('?').next().unwrap_or_default();
+    match (method, path) {
+        ("GET", WELL_KNOWN_PATH) => write_response(&mut stream, "200 OK", Some(body)).await,
+        ("HEAD", WELL_KNOWN_PATH) => write_response(&mut stream, "200 OK", None).await,
+        ("GET" | "HEAD", _) => write_response(&mut stream, "404 Not Found", None).await,
+        _ => write_response(&mut stream, "405 Method Not Allowed", None).await,
+    }
+}
+
+async fn write_response(stream: &mut TcpStream, status: &str, body: Option<&[u8]>) -> io::Result<()> {
+    let body = body.unwrap_or_default();
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+/// Fetch the identity document of `hostname` from
+/// `https://<hostname>/.well-known/osp`, checking its signature against the
+/// key in the `_osp` DNS record of `hostname`, as looked up with `resolver`.
+#[cfg(feature = "identity-fetch")]
+pub async fn fetch(resolver: &ChallengeResolver, hostname: &str) -> io::Result<IdentityDocument> {
+    let url = format!("https://{hostname}{WELL_KNOWN_PATH}");
+    let signed: SignedIdentityDocument = tokio::task::spawn_blocking(move || {
+        let response = ureq::get(&url)
+            .timeout(Duration::from_secs(10))
+            .call()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        serde_json::from_reader(response.into_reader())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }).await??;
+
+    if signed.document.hostname != hostname {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Identity document describes {} instead of {hostname}", signed.document.hostname)
+        ));
+    }
+    let key = dns::lookup_public_key(resolver, hostname).await?;
+    if !signed.verify(&key)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Identity document of {hostname} is not signed with the key in its DNS record")
+        ));
+    }
+    Ok(signed.document)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use crate::OSProtocolNode;
+    use crate::crypto::PrivateKey;
+    use crate::identity::SignedIdentityDocument;
+
+    #[test]
+    fn test_identity_document() -> io::Result<()> {
+        let key = PrivateKey::generate(1024)?;
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .private_key(key.clone())
+            .contact("ops@node.test".to_string())
+            .build()?;
+
+        let json = serde_json::to_string(&node.identity_document()?)?;
+        let mut served: SignedIdentityDocument = serde_json::from_str(&json)?;
+        assert_eq!(served.document.urls, vec!["osp://node.test:57401".to_string()]);
+        assert!(served.verify(&key.public_key()?)?);
+        assert!(!served.verify(&PrivateKey::generate(1024)?.public_key()?)?);
+
+        served.document.policies.read_only = true;
+        assert!(!served.verify(&key.public_key()?)?);
+        Ok(())
+    }
+}
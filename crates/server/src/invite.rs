@@ -0,0 +1,57 @@
+//! # Invites
+//!
+//! Invites let a node federate with peers that have no `_osp` DNS record, or
+//! restrict federation to peers its operator invited by building the node
+//! with `invite_only(true)`.
+//!
+//! Invites are signed with the issuing node's private key and shared as
+//! opaque tokens, see [to_token] and [from_token].
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+
+use bytes::BytesMut;
+
+use tokio::io;
+
+use osp_protocol::Invite;
+use osp_protocol::packet::{DeserializePacket, SerializePacket};
+
+use crate::crypto::PrivateKey;
+
+/// An invite issued by this node, as kept in its records.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InviteRecord {
+    pub invite: Invite,
+    /// Unix timestamp (seconds) the invite was issued at.
+    pub issued_at: u64,
+    /// Unix timestamp (seconds) the invitee last connected with the invite.
+    pub last_used_at: Option<u64>,
+    /// A revoked invite is no longer accepted.
+    pub revoked: bool,
+}
+
+/// Encode `invite` as a token for handing to the invitee.
+pub fn to_token(invite: &Invite) -> io::Result<String> {
+    let mut buf = BytesMut::new();
+    invite.serialize(&mut buf)?;
+    Ok(BASE64.encode(buf))
+}
+
+/// Decode a token produced by [to_token].
+pub fn from_token(token: &str) -> io::Result<Invite> {
+    let bytes = BASE64.decode(token.trim())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Invite::deserialize(&mut BytesMut::from(bytes.as_slice()))
+}
+
+#[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+pub(crate) fn sign(invite: &mut Invite, key: &PrivateKey) -> io::Result<()> {
+    invite.signature = key.sign(&invite.signed_bytes())?;
+    Ok(())
+}
+
+/// Whether `invite` carries a valid signature made with `key`.
+pub(crate) fn verify(invite: &Invite, key: &PrivateKey) -> io::Result<bool> {
+    key.public_key()?.verify(&invite.signed_bytes(), &invite.signature)
+}
@@ -1,4 +1,53 @@
 mod node;
+mod metrics;
+mod time;
+mod trace;
+#[cfg(feature = "admin-api")]
+pub mod admin;
+pub mod builder;
+pub mod compliance;
+#[cfg(feature = "config")]
+pub mod config;
 pub mod connection;
+pub mod convert;
+pub mod crypto;
+pub mod dedup;
+pub mod delivery;
+pub mod embargo;
+pub mod events;
+pub mod fanout;
+#[cfg(feature = "rss-bridge")]
+pub mod feed;
+pub mod federation;
+pub mod handler;
+pub mod identity;
+pub mod invite;
+pub mod logging;
+#[cfg(feature = "matrix-bridge")]
+pub mod matrix;
+pub mod middleware;
+#[cfg(feature = "nostr-bridge")]
+pub mod nostr;
+pub mod notice;
+pub mod platform;
+pub mod plugin;
+pub mod policy;
+pub mod preview;
+pub mod provenance;
+pub mod reload;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod recording;
+pub mod reporting;
+pub mod rollout;
+pub mod routing;
+pub mod schema;
+pub mod selfcheck;
+pub mod store;
+pub mod stream;
+pub mod subscription;
+pub mod supervisor;
+#[cfg(feature = "otel")]
+pub mod telemetry;
 
 pub use {node::OSProtocolNode};
\ No newline at end of file
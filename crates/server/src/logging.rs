@@ -0,0 +1,292 @@
+//! # Error Sampling
+//!
+//! A misbehaving peer, or an attacker opening connection after connection,
+//! can fail the same way thousands of times a minute and bury everything
+//! else in the logs. Errors the node logs about peers therefore go through
+//! an error log that only writes the first few of each kind from each peer
+//! per interval, see [ErrorSampling]. The rest are summarized in a single
+//! line once the interval is over.
+//!
+//! Every error is still counted in the `osp_errors_total` metric, and the
+//! ones left out of the logs in `osp_errors_suppressed_total`, both labelled
+//! with the error kind.
+//!
+//! ## Debugging a Connection
+//!
+//! Logging everything at debug level on a busy node to find out what one
+//! misbehaving peer does drowns it in noise. An operator can instead raise
+//! the level of a single open inbound connection while the node runs, see
+//! [ConnectionRegistry::set_log_level](crate::connection::registry::ConnectionRegistry::set_log_level).
+//! This takes the embedder's logger to be wrapped in a [ConnectionLogger],
+//! which logs at its own level everywhere else:
+//!
+//! ```ignore
+//! let inner = colog::default_builder().filter_level(LevelFilter::Trace).build();
+//! ConnectionLogger::new(inner, LevelFilter::Info).install()?;
+//! ```
+//!
+//! Only what is logged on the task serving the connection is raised, not
+//! the work it hands off to other tasks, such as handlers run on blocking
+//! threads.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{log, Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use tokio::io;
+
+use crate::metrics;
+
+/// How many errors of the same kind from the same peer are logged per
+/// interval, see the [module](self) docs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ErrorSampling {
+    /// Errors logged per peer and kind before the rest are suppressed.
+    pub burst: u32,
+    /// How long until errors of a peer and kind are logged again.
+    pub interval: Duration,
+}
+
+impl Default for ErrorSampling {
+    fn default() -> Self {
+        ErrorSampling {
+            burst: 5,
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The errors of one peer and kind in the current interval.
+struct Window {
+    started: Instant,
+    logged: u32,
+    suppressed: u32,
+    level: Level,
+}
+
+pub(crate) struct ErrorLog {
+    sampling: ErrorSampling,
+    windows: Mutex<HashMap<(String, io::ErrorKind), Window>>,
+}
+
+impl ErrorLog {
+    pub(crate) fn new(sampling: ErrorSampling) -> Self {
+        ErrorLog {
+            sampling,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Log `err` about `peer` at `level`, prefixed with `context`, unless
+    /// too many errors of its kind were logged about `peer` already. Returns
+    /// whether it was logged.
+    pub(crate) fn report(&self, level: Level, peer: &str, context: impl Display, err: &io::Error) -> bool {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry((peer.to_string(), err.kind())).or_insert(Window {
+            started: now,
+            logged: 0,
+            suppressed: 0,
+            level,
+        });
+        if now.duration_since(window.started) >= self.sampling.interval {
+            summarize(peer, err.kind(), window);
+            *window = Window { started: now, logged: 0, suppressed: 0, level };
+        }
+
+        let logged = window.logged < self.sampling.burst;
+        if logged {
+            window.logged += 1;
+            log!(level, "{context}: {err}");
+        } else {
+            window.suppressed += 1;
+        }
+        metrics::error_reported(err.kind(), !logged);
+        logged
+    }
+
+    /// Summarize and forget the intervals that are over. Called
+    /// periodically, so the summary of a peer that went quiet isn't held
+    /// back until it fails again.
+    pub(crate) fn flush(&self) {
+        let now = Instant::now();
+        self.windows.lock().unwrap().retain(|(peer, kind), window| {
+            let over = now.duration_since(window.started) >= self.sampling.interval;
+            if over {
+                summarize(peer, *kind, window);
+            }
+            !over
+        });
+    }
+
+    /// Flush every `interval` until the node stops.
+    pub(crate) async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.sampling.interval);
+        loop {
+            interval.tick().await;
+            self.flush();
+        }
+    }
+}
+
+fn summarize(peer: &str, kind: io::ErrorKind, window: &Window) {
+    if window.suppressed > 0 {
+        log!(
+            window.level,
+            "{} more {kind:?} errors about {peer} in the last {}s were not logged",
+            window.suppressed,
+            window.started.elapsed().as_secs()
+        );
+    }
+}
+
+tokio::task_local! {
+    /// The log of the connection served on the current task
+    static CONNECTION: Arc<ConnectionLog>;
+}
+
+/// The level an operator raised the log of a connection to, if any.
+#[derive(Debug)]
+pub(crate) struct ConnectionLog {
+    /// The level as a `usize`, [NOT_RAISED] if it wasn't raised
+    level: AtomicUsize,
+}
+
+const NOT_RAISED: usize = usize::MAX;
+
+impl Default for ConnectionLog {
+    fn default() -> Self {
+        ConnectionLog { level: AtomicUsize::new(NOT_RAISED) }
+    }
+}
+
+impl ConnectionLog {
+    pub(crate) fn level(&self) -> Option<LevelFilter> {
+        match self.level.load(Ordering::Relaxed) {
+            NOT_RAISED => None,
+            level => LevelFilter::iter().nth(level),
+        }
+    }
+
+    pub(crate) fn set_level(&self, level: Option<LevelFilter>) {
+        self.level.store(level.map_or(NOT_RAISED, |level| level as usize), Ordering::Relaxed);
+    }
+
+    /// Run `future` as the task serving the connection with this log.
+    pub(crate) fn scope<F: Future>(self: Arc<Self>, future: F) -> impl Future<Output = F::Output> {
+        CONNECTION.scope(self, future)
+    }
+}
+
+/// Wraps the logger of the embedder, logging records at `level` and above
+/// as it, and those of connections whose level was raised at theirs, see
+/// [Debugging a Connection](self#debugging-a-connection). The wrapped
+/// logger should log records of every level it is passed.
+pub struct ConnectionLogger<L> {
+    inner: L,
+    level: LevelFilter,
+}
+
+impl<L: Log + 'static> ConnectionLogger<L> {
+    pub fn new(inner: L, level: LevelFilter) -> Self {
+        ConnectionLogger { inner, level }
+    }
+
+    /// Install as the global logger. The most verbose level is enabled for
+    /// the [log] macros, as any connection may be raised to it, so the
+    /// records of every level are built and passed to this logger.
+    pub fn install(self) -> Result<(), SetLoggerError> {
+        log::set_logger(Box::leak(Box::new(self)))?;
+        log::set_max_level(LevelFilter::Trace);
+        Ok(())
+    }
+}
+
+impl<L: Log> Log for ConnectionLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+            || CONNECTION.try_with(|log| log.level()).ok().flatten().is_some_and(|raised| metadata.level() <= raised)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use std::sync::{Arc, Mutex};
+
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+
+    use tokio::io;
+
+    use crate::logging::{ConnectionLog, ConnectionLogger, ErrorLog, ErrorSampling};
+
+    #[test]
+    fn test_error_sampling() {
+        let log = ErrorLog::new(ErrorSampling { burst: 2, interval: Duration::from_secs(60) });
+        let refused = io::Error::new(io::ErrorKind::PermissionDenied, "refused");
+        let logged: Vec<bool> = (0..4).map(|_| log.report(Level::Error, "peer.test", "Handshake failed", &refused)).collect();
+        assert_eq!(logged, [true, true, false, false]);
+
+        // other peers and kinds are counted separately
+        assert!(log.report(Level::Error, "other.test", "Handshake failed", &refused));
+        assert!(log.report(Level::Error, "peer.test", "Transfer failed", &io::Error::from(io::ErrorKind::InvalidData)));
+
+        let log = ErrorLog::new(ErrorSampling { burst: 1, interval: Duration::ZERO });
+        assert!(log.report(Level::Warn, "peer.test", "Handshake failed", &refused));
+        assert!(log.report(Level::Warn, "peer.test", "Handshake failed", &refused));
+        log.flush();
+        assert!(log.windows.lock().unwrap().is_empty());
+    }
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<String>>>);
+
+    impl Log for Captured {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[tokio::test]
+    async fn test_connection_logger() {
+        let captured = Captured::default();
+        let logger = ConnectionLogger::new(captured.clone(), LevelFilter::Info);
+        let log = |level: Level, message: &str| logger.log(&Record::builder().level(level).args(format_args!("{message}")).build());
+
+        let raised = Arc::new(ConnectionLog::default());
+        raised.clone().scope(async {
+            log(Level::Debug, "not raised yet");
+            raised.set_level(Some(LevelFilter::Debug));
+            log(Level::Debug, "raised");
+            log(Level::Trace, "above the raised level");
+        }).await;
+        log(Level::Debug, "outside of the connection");
+        log(Level::Info, "at the level of the node");
+        assert_eq!(raised.level(), Some(LevelFilter::Debug));
+        raised.set_level(None);
+        assert_eq!(raised.level(), None);
+        assert_eq!(*captured.0.lock().unwrap(), ["raised", "at the level of the node"]);
+    }
+}
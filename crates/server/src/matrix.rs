@@ -0,0 +1,434 @@
+//! # Matrix Bridge
+//!
+//! With the `matrix-bridge` feature, a node can post the objects it
+//! publishes or is pushed into Matrix rooms with a [MatrixBridge]. Each
+//! [MatrixRoom] takes the objects of the types it is configured for, and
+//! formats them as a text message with its [template](MatrixRoom::template).
+//! The bridge posts as a bot user with its access token, or as a user of an
+//! application service it is registered as. Anyone may be in a room, so only
+//! objects meant for everyone are posted, not those restricted to an
+//! [audience](osp_protocol::Audience).
+//!
+//! Templates name what goes into the message between braces: `{origin}`,
+//! `{object_id}`, `{type_id}`, `{topic}`, `{actor}` and `{created_at}` take
+//! the fields of the envelope, and `{payload.title}` the `title` field of
+//! the payload, read as JSON, with dots going into nested fields. Fields
+//! that are missing are left empty, and `{{` and `}}` stand for the braces
+//! themselves:
+//!
+//! ```
+//! # use uuid::Uuid;
+//! # use osp_server_sdk::matrix::{MatrixBridge, MatrixRoom};
+//! # let article_type = Uuid::new_v4();
+//! let bridge = MatrixBridge::bot("https://matrix.example.com", "syt_bot_token")
+//!     .room(MatrixRoom::new("!news:example.com", &[article_type])
+//!         .template("{payload.title} by {origin}: {payload.url}"));
+//! ```
+//!
+//! Homeservers limit how fast users may post, so each room sends at most a
+//! message every [interval](MatrixRoom::at_most_every), and waits as long as
+//! the homeserver asks when it is rate limited anyway. Messages queue up in
+//! the meantime, and the newest are dropped once a room falls too far
+//! behind.
+
+use std::fmt::{self, Debug};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, error, warn};
+
+use serde_json::{json, Value};
+
+use tokio::io;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use url::form_urlencoded;
+
+use uuid::Uuid;
+
+use osp_protocol::{Envelope, PayloadFormat};
+
+use crate::OSProtocolNode;
+use crate::reporting::{Fault, FaultKind};
+
+/// Template of rooms that don't set one.
+pub const DEFAULT_TEMPLATE: &str = "{origin} published {object_id}";
+/// How long rooms wait between messages by default.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of messages queued for each room. Rooms that fall further behind
+/// miss the newest messages.
+const QUEUE_CAPACITY: usize = 256;
+/// How often a message is tried again when the homeserver rate limits it.
+const MAX_RATE_LIMITED: u32 = 5;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Who the bridge posts as.
+#[derive(Clone, PartialEq)]
+pub enum MatrixAuth {
+    /// A bot user, with the access token of its login.
+    Bot { access_token: String },
+    /// A user of an application service, with the `as_token` of its
+    /// registration. `user_id` must be in the namespace of the service.
+    AppService { as_token: String, user_id: String },
+}
+
+impl Debug for MatrixAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatrixAuth::Bot { .. } => write!(f, "Bot"),
+            MatrixAuth::AppService { user_id, .. } => write!(f, "AppService({user_id})"),
+        }
+    }
+}
+
+/// A room to post objects into, see the [module](self) docs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatrixRoom {
+    /// Id of the room, e.g. `!abc:example.com`. The user the bridge posts
+    /// as must have joined it.
+    pub room_id: String,
+    /// Types of the objects posted into the room.
+    pub type_ids: Vec<Uuid>,
+    pub template: String,
+    /// The least time between two messages in the room.
+    pub interval: Duration,
+}
+
+impl MatrixRoom {
+    /// Post the objects of `type_ids` into the room `room_id`, formatted with
+    /// [DEFAULT_TEMPLATE] at most every [DEFAULT_INTERVAL].
+    pub fn new(room_id: impl Into<String>, type_ids: &[Uuid]) -> Self {
+        MatrixRoom {
+            room_id: room_id.into(),
+            type_ids: type_ids.to_vec(),
+            template: DEFAULT_TEMPLATE.to_string(),
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+
+    pub fn template(mut self, template: impl Into<String>) -> Self {
+        self.template = template.into();
+        self
+    }
+
+    pub fn at_most_every(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+/// The homeserver to post to, as whom, and the rooms to post into, see the
+/// [module](self) docs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatrixBridge {
+    /// Base url of the client-server API, e.g. `https://matrix.example.com`.
+    pub homeserver: String,
+    pub auth: MatrixAuth,
+    pub rooms: Vec<MatrixRoom>,
+}
+
+impl MatrixBridge {
+    /// Post as the bot user logged in with `access_token`.
+    pub fn bot(homeserver: impl Into<String>, access_token: impl Into<String>) -> Self {
+        Self::new(homeserver, MatrixAuth::Bot { access_token: access_token.into() })
+    }
+
+    /// Post as `user_id` of the application service registered with
+    /// `as_token`.
+    pub fn appservice(homeserver: impl Into<String>, as_token: impl Into<String>, user_id: impl Into<String>) -> Self {
+        Self::new(homeserver, MatrixAuth::AppService {
+            as_token: as_token.into(),
+            user_id: user_id.into(),
+        })
+    }
+
+    fn new(homeserver: impl Into<String>, auth: MatrixAuth) -> Self {
+        MatrixBridge {
+            homeserver: homeserver.into().trim_end_matches('/').to_string(),
+            auth,
+            rooms: Vec::new(),
+        }
+    }
+
+    /// Post into `room`. Can be called several times.
+    pub fn room(mut self, room: MatrixRoom) -> Self {
+        self.rooms.push(room);
+        self
+    }
+
+    /// The url a message with the transaction id `txn_id` is sent to
+    /// `room_id` at.
+    fn send_url(&self, room_id: &str, txn_id: &str) -> String {
+        let room_id: String = form_urlencoded::byte_serialize(room_id.as_bytes()).collect();
+        let mut url = format!("{}/_matrix/client/v3/rooms/{room_id}/send/m.room.message/{txn_id}", self.homeserver);
+        if let MatrixAuth::AppService { user_id, .. } = &self.auth {
+            url.push_str("?user_id=");
+            url.extend(form_urlencoded::byte_serialize(user_id.as_bytes()));
+        }
+        url
+    }
+
+    fn token(&self) -> &str {
+        match &self.auth {
+            MatrixAuth::Bot { access_token } => access_token,
+            MatrixAuth::AppService { as_token, .. } => as_token,
+        }
+    }
+}
+
+/// Format `envelope` with `template`, taking the fields of `payload`, if the
+/// payload could be read as JSON.
+pub fn render(template: &str, envelope: &Envelope, payload: Option<&Value>) -> String {
+    let mut message = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(at) = rest.find(['{', '}']) {
+        message.push_str(&rest[..at]);
+        let (brace, after) = (&rest[at..at + 1], &rest[at + 1..]);
+        if after.starts_with(brace) {
+            message.push_str(brace);
+            rest = &after[1..];
+            continue;
+        }
+        match (brace, after.find('}')) {
+            ("{", Some(end)) => {
+                message.push_str(&field(&after[..end], envelope, payload));
+                rest = &after[end + 1..];
+            }
+            _ => {
+                message.push_str(brace);
+                rest = after;
+            }
+        }
+    }
+    message.push_str(rest);
+    message
+}
+
+/// The value of the field `name` of a template.
+fn field(name: &str, envelope: &Envelope, payload: Option<&Value>) -> String {
+    match name.trim() {
+        "origin" => envelope.origin.clone(),
+        "object_id" => envelope.object_id.to_string(),
+        "type_id" => envelope.type_id.to_string(),
+        "topic" => envelope.topic.clone().unwrap_or_default(),
+        "actor" => envelope.actor.clone().unwrap_or_default(),
+        "created_at" => envelope.created_at.to_string(),
+        name => {
+            let value = name.strip_prefix("payload.").and_then(|path| {
+                path.split('.').try_fold(payload?, |value, key| value.get(key))
+            });
+            value.map(text).unwrap_or_default()
+        }
+    }
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        Value::Array(values) => values.iter().map(text).collect::<Vec<_>>().join(", "),
+        value => value.to_string(),
+    }
+}
+
+/// A message waiting to be posted into a room.
+#[derive(Debug)]
+struct Post {
+    object_id: Uuid,
+    body: String,
+}
+
+/// A node's bridge, and the messages waiting to be posted into each of its
+/// rooms.
+pub(crate) struct Matrix {
+    pub(crate) bridge: MatrixBridge,
+    /// By room, in the order of the bridge
+    queues: Vec<mpsc::Sender<Post>>,
+    /// Taken by the tasks posting into the rooms once the node listens
+    pending: Mutex<Vec<mpsc::Receiver<Post>>>,
+}
+
+impl Matrix {
+    pub(crate) fn new(bridge: MatrixBridge) -> Self {
+        let (queues, pending) = bridge.rooms.iter().map(|_| mpsc::channel(QUEUE_CAPACITY)).unzip();
+        Matrix {
+            bridge,
+            queues,
+            pending: Mutex::new(pending),
+        }
+    }
+
+    /// Queue `envelope` for the rooms taking its type, if it is meant for
+    /// everyone. Returns how many rooms it was queued for.
+    pub(crate) fn send(&self, node: &OSProtocolNode, envelope: &Envelope) -> usize {
+        if !envelope.audience.is_public() {
+            debug!("Not posting object {} for a restricted audience to Matrix", envelope.object_id);
+            return 0;
+        }
+        let mut payload = None;
+        let mut queued = 0;
+        for (room, queue) in self.bridge.rooms.iter().zip(self.queues.iter()) {
+            if !room.type_ids.contains(&envelope.type_id) {
+                continue;
+            }
+            let payload = payload.get_or_insert_with(|| read_payload(node, envelope));
+            let post = Post {
+                object_id: envelope.object_id,
+                body: render(&room.template, envelope, payload.as_ref()),
+            };
+            match queue.try_send(post) {
+                Ok(()) => queued += 1,
+                Err(TrySendError::Full(_)) => warn!("Matrix room {} is falling behind, dropping object {}", room.room_id, envelope.object_id),
+                // the node isn't listening anymore
+                Err(TrySendError::Closed(_)) => {}
+            }
+        }
+        queued
+    }
+
+    /// The queues of the rooms, by their index in the bridge, the first time
+    /// it is called.
+    fn take_pending(&self) -> Vec<(usize, mpsc::Receiver<Post>)> {
+        let mut pending = self.pending.lock().unwrap();
+        std::mem::take(&mut *pending).into_iter().enumerate().collect()
+    }
+}
+
+/// The payload of `envelope` as JSON, if it can be transcoded to it.
+fn read_payload(node: &OSProtocolNode, envelope: &Envelope) -> Option<Value> {
+    let envelope = node.transcode(envelope.clone(), PayloadFormat::Json);
+    if envelope.format != PayloadFormat::Json {
+        return None;
+    }
+    serde_json::from_slice(&envelope.payload).ok()
+}
+
+/// Start posting the messages queued for the rooms of `matrix`, each in a
+/// task of its own.
+pub(crate) fn spawn(node: &OSProtocolNode, matrix: &Arc<Matrix>) {
+    for (room, queue) in matrix.take_pending() {
+        tokio::spawn(run(node.clone(), matrix.clone(), room, queue));
+    }
+}
+
+/// Post the messages queued for the `room`th room of the bridge until the
+/// node stops, waiting the interval of the room between them.
+async fn run(node: OSProtocolNode, matrix: Arc<Matrix>, room: usize, mut queue: mpsc::Receiver<Post>) {
+    let room = &matrix.bridge.rooms[room];
+    while let Some(post) = queue.recv().await {
+        if let Err(e) = deliver(&matrix.bridge, &room.room_id, &post).await {
+            let message = format!("Unable to post object {} into Matrix room {}: {e}", post.object_id, room.room_id);
+            error!("{message}");
+            node.report_fault(Fault::new(FaultKind::Internal, message).object(post.object_id));
+        }
+        tokio::time::sleep(room.interval).await;
+    }
+}
+
+/// Post `post` into `room_id`, waiting as long as the homeserver asks when
+/// it is rate limited.
+async fn deliver(bridge: &MatrixBridge, room_id: &str, post: &Post) -> io::Result<()> {
+    // the object id makes retries idempotent, the homeserver drops repeated
+    // transactions
+    let url = bridge.send_url(room_id, &format!("osp-{}", post.object_id));
+    let content = json!({ "msgtype": "m.text", "body": post.body }).to_string();
+    for _ in 0..MAX_RATE_LIMITED {
+        let (url, token, content) = (url.clone(), bridge.token().to_string(), content.clone());
+        match tokio::task::spawn_blocking(move || put(&url, &token, &content)).await?? {
+            None => {
+                debug!("Posted object {} into Matrix room {room_id}", post.object_id);
+                return Ok(());
+            }
+            Some(retry_after) => {
+                warn!("Rate limited by the Matrix homeserver, retrying in {}ms", retry_after.as_millis());
+                tokio::time::sleep(retry_after).await;
+            }
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::WouldBlock, "rate limited by the homeserver"))
+}
+
+/// Send a message event. Returns how long to wait before trying again if
+/// the homeserver rate limited it.
+fn put(url: &str, token: &str, content: &str) -> io::Result<Option<Duration>> {
+    let response = ureq::put(url)
+        .set("Authorization", &format!("Bearer {token}"))
+        .set("Content-Type", "application/json")
+        .timeout(REQUEST_TIMEOUT)
+        .send_string(content);
+    match response {
+        Ok(_) => Ok(None),
+        Err(ureq::Error::Status(429, response)) => {
+            let mut body = Vec::new();
+            response.into_reader().take(64 * 1024).read_to_end(&mut body)?;
+            let retry_after = serde_json::from_slice::<Value>(&body).ok()
+                .and_then(|error| error.get("retry_after_ms")?.as_u64())
+                .unwrap_or(1000);
+            Ok(Some(Duration::from_millis(retry_after)))
+        }
+        Err(ureq::Error::Status(code, response)) => {
+            let body = response.into_string().unwrap_or_default();
+            Err(io::Error::other(format!("the homeserver answered {code}: {body}")))
+        }
+        Err(ureq::Error::Transport(t)) => Err(io::Error::other(t.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use tokio::io;
+
+    use uuid::Uuid;
+
+    use osp_data::{Data, DataTypeRegistry};
+    use osp_data::standard::Article;
+    use osp_protocol::{Audience, Envelope};
+
+    use crate::OSProtocolNode;
+    use crate::crypto::PrivateKey;
+    use crate::matrix::{self, MatrixBridge, MatrixRoom};
+    use crate::store::SqliteStore;
+
+    #[test]
+    fn test_bridge() -> io::Result<()> {
+        let envelope = Envelope::new(Uuid::new_v4(), "blog.test".to_string(), Vec::new());
+        let payload = json!({ "title": "Hello", "meta": { "tags": ["a", "b"], "views": 3 } });
+        let rendered = matrix::render("{{{payload.title}}} {payload.meta.tags} ({payload.meta.views}){payload.missing} by {origin}}", &envelope, Some(&payload));
+        assert_eq!(rendered, "{Hello} a, b (3) by blog.test}");
+        assert_eq!(matrix::render("{topic}{payload.title} {", &envelope, None), " {");
+
+        let bridge = MatrixBridge::appservice("https://matrix.test/", "as_token", "@osp:matrix.test")
+            .room(MatrixRoom::new("!news:matrix.test", &[Article::TYPE_ID]).template("{payload.title} by {origin}"))
+            .room(MatrixRoom::new("!all:matrix.test", &[Article::TYPE_ID, Uuid::nil()]));
+        assert_eq!(bridge.send_url("!news:matrix.test", "1"),
+            "https://matrix.test/_matrix/client/v3/rooms/%21news%3Amatrix.test/send/m.room.message/1?user_id=%40osp%3Amatrix.test");
+
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .data_types(DataTypeRegistry::new().register::<Article>().unwrap())
+            .matrix_bridge(bridge)
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let rooms = node.matrix().unwrap();
+        let mut queues = rooms.take_pending();
+        assert!(rooms.take_pending().is_empty());
+
+        let article = Article { title: "Hello".to_string(), ..Article::default() }.to_envelope("blog.test".to_string())?;
+        assert_eq!(rooms.send(&node, &article), 2);
+        assert_eq!(rooms.send(&node, &Envelope::new(Uuid::nil(), "blog.test".to_string(), Vec::new())), 1);
+        assert_eq!(rooms.send(&node, &envelope), 0);
+        let restricted = Envelope { audience: Audience::Community("staff".to_string()), ..article.clone() };
+        assert_eq!(rooms.send(&node, &restricted), 0);
+        assert_eq!(queues[0].1.try_recv().unwrap().body, "Hello by blog.test");
+        assert!(queues[0].1.try_recv().is_err());
+        assert_eq!(queues[1].1.try_recv().unwrap().body, format!("blog.test published {}", article.object_id));
+        assert_eq!(queues[1].1.try_recv().unwrap().body.split_once(' ').unwrap().1.split_once(' ').unwrap().0, "published");
+        Ok(())
+    }
+}
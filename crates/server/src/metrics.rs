@@ -0,0 +1,214 @@
+//! # Metrics
+//!
+//! Node metrics are reported through the [metrics](::metrics) facade. They
+//! cost nothing until the embedder installs a recorder/exporter, and are
+//! compiled out without the `metrics` feature. The `prometheus` feature
+//! comes with an exporter serving them to Prometheus, see
+//! `prometheus::Prometheus`.
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+
+use std::time::Duration;
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_protocol::Closure;
+use osp_protocol::packet::transfer::AckStatus;
+
+use crate::connection::accounting::Cost;
+use crate::handler::HandlerOutcome;
+use crate::rollout::Feature;
+use crate::store::TypeUsage;
+use crate::subscription::DeliveryQos;
+
+pub(crate) fn storage_usage(usage: &TypeUsage) {
+    #[cfg(feature = "metrics")]
+    {
+        let data_type = usage.type_id.to_string();
+        ::metrics::gauge!("osp_storage_objects", "data_type" => data_type.clone()).set(usage.objects as f64);
+        ::metrics::gauge!("osp_storage_bytes", "data_type" => data_type).set(usage.bytes as f64);
+    }
+}
+
+pub(crate) fn storage_evicted(type_id: Uuid) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_storage_evictions_total", "data_type" => type_id.to_string()).increment(1);
+}
+
+pub(crate) fn storage_rejected(type_id: Uuid) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_storage_rejections_total", "data_type" => type_id.to_string()).increment(1);
+}
+
+pub(crate) fn handler_invocation(handler: &str, elapsed: Duration, outcome: HandlerOutcome) {
+    #[cfg(feature = "metrics")]
+    {
+        let handler = handler.to_string();
+        ::metrics::counter!("osp_handler_invocations_total", "handler" => handler.clone()).increment(1);
+        ::metrics::histogram!("osp_handler_duration_seconds", "handler" => handler.clone()).record(elapsed.as_secs_f64());
+        match outcome {
+            HandlerOutcome::Ok => {}
+            HandlerOutcome::Error => ::metrics::counter!("osp_handler_errors_total", "handler" => handler).increment(1),
+            HandlerOutcome::Panic => ::metrics::counter!("osp_handler_panics_total", "handler" => handler).increment(1),
+        }
+    }
+}
+
+pub(crate) fn handler_slow(handler: &str) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_handler_slow_total", "handler" => handler.to_string()).increment(1);
+}
+
+pub(crate) fn connection_accepted(country: Option<&str>) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_connections_total", "country" => country.unwrap_or("unknown").to_string()).increment(1);
+}
+
+pub(crate) fn connection_refused(country: Option<&str>) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_connections_refused_total", "country" => country.unwrap_or("unknown").to_string()).increment(1);
+}
+
+pub(crate) fn connections_open(open: usize) {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!("osp_connections_open").set(open as f64);
+}
+
+/// A connection closed to make room, as the node was running out of files.
+pub(crate) fn connection_shed() {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_connections_shed_total").increment(1);
+}
+
+pub(crate) fn open_files(open: u64, limit: u64) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::gauge!("osp_open_files").set(open as f64);
+        ::metrics::gauge!("osp_open_files_limit").set(limit as f64);
+    }
+}
+
+/// A handshake on a connection in `direction`, `inbound` or `outbound`,
+/// that succeeded or failed with an error of `result`'s kind.
+pub(crate) fn handshake(direction: &'static str, result: Result<(), io::ErrorKind>) {
+    #[cfg(feature = "metrics")]
+    {
+        let outcome = match result {
+            Ok(()) => "ok".to_string(),
+            Err(kind) => format!("{kind:?}"),
+        };
+        ::metrics::counter!("osp_handshakes_total", "direction" => direction, "outcome" => outcome).increment(1);
+    }
+}
+
+#[cfg_attr(not(feature = "dns-auth"), allow(dead_code))]
+pub(crate) fn challenge_lookup(elapsed: Duration, found: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let outcome = if found { "found" } else { "failed" };
+        ::metrics::histogram!("osp_challenge_lookup_duration_seconds", "outcome" => outcome).record(elapsed.as_secs_f64());
+    }
+}
+
+/// Bytes read from or written to the connections of the node, in
+/// `direction` `in` or `out`.
+pub(crate) fn transfer_bytes(direction: &'static str, bytes: usize) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_transfer_bytes_total", "direction" => direction).increment(bytes as u64);
+}
+
+/// A connection that ended, by how it did.
+pub(crate) fn connection_closed(closure: Closure) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_connections_closed_total", "closure" => closure.as_str()).increment(1);
+}
+
+pub(crate) fn connection_timed_out() {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_connections_timed_out_total").increment(1);
+}
+
+pub(crate) fn connection_panicked() {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_connections_panicked_total").increment(1);
+}
+
+pub(crate) fn request_deadline_exceeded(request: &'static str) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_requests_deadline_exceeded_total", "request" => request).increment(1);
+}
+
+pub(crate) fn delivery_queue_depth(depth: u64) {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!("osp_delivery_queue_depth").set(depth as f64);
+}
+
+pub(crate) fn delivery(outcome: &'static str, qos: DeliveryQos) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_deliveries_total", "outcome" => outcome, "qos" => qos.name()).increment(1);
+}
+
+pub(crate) fn peer_lag(peer: &str, lag: u64) {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!("osp_peer_lag", "peer" => peer.to_string()).set(lag as f64);
+}
+
+pub(crate) fn cost_charged(cost: &Cost) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!("osp_served_bytes_total", "kind" => "answer").increment(cost.bytes_served);
+        ::metrics::counter!("osp_served_bytes_total", "kind" => "blob").increment(cost.blob_bytes);
+        ::metrics::counter!("osp_served_query_units_total").increment(cost.query_units);
+    }
+}
+
+pub(crate) fn request_shed(request: &'static str) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_requests_shed_total", "request" => request).increment(1);
+}
+
+pub(crate) fn error_reported(kind: io::ErrorKind, suppressed: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let kind = format!("{kind:?}");
+        ::metrics::counter!("osp_errors_total", "kind" => kind.clone()).increment(1);
+        if suppressed {
+            ::metrics::counter!("osp_errors_suppressed_total", "kind" => kind).increment(1);
+        }
+    }
+}
+
+pub(crate) fn dedup_lookup(duplicate: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let result = if duplicate { "hit" } else { "miss" };
+        ::metrics::counter!("osp_dedup_lookups_total", "result" => result).increment(1);
+    }
+}
+
+pub(crate) fn push_acked(status: AckStatus) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_pushes_acked_total", "status" => status.name()).increment(1);
+}
+
+pub(crate) fn push_target_connected(peer: &str, port: u16, connected: bool) {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!("osp_push_target_connected", "peer" => peer.to_string(), "port" => port.to_string()).set(if connected { 1.0 } else { 0.0 });
+}
+
+pub(crate) fn push_target_reconnect(peer: &str, port: u16) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("osp_push_target_reconnects_total", "peer" => peer.to_string(), "port" => port.to_string()).increment(1);
+}
+
+/// A handshake of a connection `feature` was rolled out to if `enabled`,
+/// which succeeded or not.
+pub(crate) fn rollout_handshake(feature: Feature, enabled: bool, succeeded: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let enabled = if enabled { "true" } else { "false" };
+        let outcome = if succeeded { "ok" } else { "failed" };
+        ::metrics::counter!("osp_rollout_handshakes_total", "feature" => feature.name(), "enabled" => enabled, "outcome" => outcome).increment(1);
+    }
+}
@@ -0,0 +1,51 @@
+//! # Middleware
+//!
+//! Routing decisions for received objects. Every object pushed by a peer
+//! passes through the node's middleware chain before it is validated and
+//! stored. The first middleware to drop the object ends the chain and the
+//! object is refused. Middleware may instead tag the object with the topic
+//! it is stored and relayed under.
+//!
+//! Middleware can also be written as Rhai scripts, see [script]. Scripts
+//! require the `scripting` feature.
+
+use tokio::io;
+
+use osp_protocol::Envelope;
+
+#[cfg(feature = "scripting")]
+pub mod script;
+
+/// What to do with a received object.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    /// Hand the object to the next middleware, then on to storage.
+    Accept,
+    /// Refuse the object, with the reason sent back to the peer.
+    Drop(String),
+    /// Hand the object on under the topic given, such as to relay it to
+    /// the subscribers of that topic. Later middleware see the object under
+    /// its new topic.
+    Tag(String),
+}
+
+/// A received object on its way through the middleware chain.
+#[derive(Clone, Copy, Debug)]
+pub struct Incoming<'a> {
+    pub envelope: &'a Envelope,
+    /// Hostname of the peer that pushed the object.
+    pub peer: &'a str,
+}
+
+pub trait Middleware: Send + Sync {
+    fn route(&self, incoming: Incoming) -> io::Result<Verdict>;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Incoming) -> io::Result<Verdict> + Send + Sync,
+{
+    fn route(&self, incoming: Incoming) -> io::Result<Verdict> {
+        self(incoming)
+    }
+}
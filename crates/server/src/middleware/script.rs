@@ -0,0 +1,198 @@
+//! # Routing Scripts
+//!
+//! [Middleware] written in [Rhai](https://rhai.rs). A script defines a
+//! `route` function, which is called with a map describing each received
+//! object:
+//!
+//! ```rhai
+//! fn route(object) {
+//!     // object.object_id, object.type_id, object.origin, object.created_at,
+//!     // object.topic, object.peer and object.size are available, the topic
+//!     // being () if the object has none
+//!     if object.peer == "spam.example" {
+//!         return "Not accepting content from spam.example";
+//!     }
+//!     true
+//! }
+//! ```
+//!
+//! Returning `true` or nothing accepts the object. Returning `false` or a
+//! string drops it, the string being the reason given to the peer. Returning
+//! a map with a `tag` accepts the object under that topic, e.g.
+//! `#{ tag: "blog/rust" }` to relay it to the subscribers of `blog/rust`,
+//! see [Verdict::Tag].
+//!
+//! Scripts loaded from a file are reloaded when the file changes, so routing
+//! can be adjusted without restarting the node. The file is checked for
+//! changes at most once a second.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{error, info};
+
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+
+use tokio::io;
+
+use crate::middleware::{Incoming, Middleware, Verdict};
+
+/// Upper bound on the operations a single `route` call may perform, so a
+/// buggy script can't stall the node.
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// How long the script file is trusted not to have changed since it was
+/// last checked.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct LoadedScript {
+    ast: AST,
+    /// Modification time of the script file when it was loaded.
+    modified: Option<SystemTime>,
+}
+
+pub struct ScriptMiddleware {
+    engine: Engine,
+    path: Option<PathBuf>,
+    script: RwLock<LoadedScript>,
+    /// When the script file was last checked for changes
+    checked: Mutex<Instant>,
+}
+
+fn script_err<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+}
+
+fn new_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine
+}
+
+impl ScriptMiddleware {
+    /// Compile a routing script from source.
+    pub fn new(source: &str) -> io::Result<Self> {
+        let engine = new_engine();
+        let ast = engine.compile(source).map_err(script_err)?;
+        Ok(ScriptMiddleware {
+            engine,
+            path: None,
+            script: RwLock::new(LoadedScript { ast, modified: None }),
+            checked: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Compile the routing script at `path`. The script is reloaded whenever
+    /// the file changes.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let engine = new_engine();
+        let modified = fs::metadata(path.as_ref())?.modified().ok();
+        let ast = engine.compile(fs::read_to_string(path.as_ref())?).map_err(script_err)?;
+        Ok(ScriptMiddleware {
+            engine,
+            path: Some(path.as_ref().to_path_buf()),
+            script: RwLock::new(LoadedScript { ast, modified }),
+            checked: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Recompile the script from its file. If the new script fails to
+    /// compile, the previous one stays in use.
+    pub fn reload(&self) -> io::Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let modified = fs::metadata(path)?.modified().ok();
+        let ast = self.engine.compile(fs::read_to_string(path)?).map_err(script_err)?;
+        *self.script.write().unwrap() = LoadedScript { ast, modified };
+        info!("Reloaded routing script {}", path.display());
+        Ok(())
+    }
+
+    fn reload_if_changed(&self) {
+        let Some(path) = &self.path else { return };
+        {
+            let mut checked = self.checked.lock().unwrap();
+            if checked.elapsed() < RELOAD_CHECK_INTERVAL {
+                return;
+            }
+            *checked = Instant::now();
+        }
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified != self.script.read().unwrap().modified {
+            if let Err(e) = self.reload() {
+                error!("Unable to reload routing script {}: {e}", path.display());
+                // don't retry until the file changes again
+                self.script.write().unwrap().modified = modified;
+            }
+        }
+    }
+}
+
+impl Middleware for ScriptMiddleware {
+    fn route(&self, incoming: Incoming) -> io::Result<Verdict> {
+        self.reload_if_changed();
+
+        let envelope = incoming.envelope;
+        let mut object = Map::new();
+        object.insert("object_id".into(), envelope.object_id.to_string().into());
+        object.insert("type_id".into(), envelope.type_id.to_string().into());
+        object.insert("origin".into(), envelope.origin.clone().into());
+        object.insert("created_at".into(), (envelope.created_at as i64).into());
+        object.insert("topic".into(), envelope.topic.clone().map_or(Dynamic::UNIT, Dynamic::from));
+        object.insert("peer".into(), incoming.peer.to_string().into());
+        object.insert("size".into(), (envelope.payload.len() as i64).into());
+
+        let script = self.script.read().unwrap();
+        let result: Dynamic = self.engine.call_fn(&mut Scope::new(), &script.ast, "route", (object,))
+            .map_err(|e| io::Error::other(format!("Routing script failed: {e}")))?;
+
+        if result.is_unit() {
+            Ok(Verdict::Accept)
+        } else if let Ok(accept) = result.as_bool() {
+            Ok(match accept {
+                true => Verdict::Accept,
+                false => Verdict::Drop("Dropped by routing script".to_string()),
+            })
+        } else if result.is_map() {
+            match result.cast::<Map>().get("tag").and_then(|tag| tag.clone().into_string().ok()) {
+                Some(topic) => Ok(Verdict::Tag(topic)),
+                None => Err(io::Error::new(io::ErrorKind::InvalidData, "Routing script returned a map without a tag")),
+            }
+        } else if let Ok(reason) = result.into_string() {
+            Ok(Verdict::Drop(reason))
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "Routing script must return a bool, a string, a map with a tag or nothing"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_protocol::Envelope;
+
+    use crate::middleware::{Incoming, Middleware, Verdict};
+    use crate::middleware::script::ScriptMiddleware;
+
+    #[test]
+    fn test_routing_script() -> io::Result<()> {
+        let script = ScriptMiddleware::new(r#"
+            fn route(object) {
+                if object.peer == "spam.test" { return "No spam"; }
+                if object.peer == "blog.test" { return #{ tag: "blog/rust" }; }
+                object.size < 4
+            }
+        "#)?;
+        let small = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![0; 2]);
+        let large = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![0; 8]);
+
+        assert_eq!(script.route(Incoming { envelope: &small, peer: "peer.test" })?, Verdict::Accept);
+        assert_eq!(script.route(Incoming { envelope: &small, peer: "spam.test" })?, Verdict::Drop("No spam".to_string()));
+        assert!(matches!(script.route(Incoming { envelope: &large, peer: "peer.test" })?, Verdict::Drop(_)));
+        assert_eq!(script.route(Incoming { envelope: &large, peer: "blog.test" })?, Verdict::Tag("blog/rust".to_string()));
+        Ok(())
+    }
+}
@@ -1,48 +1,1000 @@
-use std::{fs, net::{SocketAddr, IpAddr, Ipv4Addr}};
+use std::{fs, net::{SocketAddr, IpAddr}};
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use log::info;
+use futures_util::StreamExt;
 
-use openssl::pkey::Private;
-use openssl::rsa::Rsa;
+use log::{debug, error, info, warn, Level};
 
-use tokio::io;
+use tokio::io::{self, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Notify, Semaphore};
 
-use osp_protocol::OSPUrl;
+use uuid::Uuid;
 
-use crate::connection::inbound::{InboundConnection, TransferState};
-use crate::connection::outbound::OutboundConnection;
+use osp_data::{Data, DataHandler, DataTypeRegistry, HandlerError, HandlerStatus};
+use osp_protocol::{Compression, Delegation, Envelope, Invite, Keepalive, OSPUrl, PayloadFormat, Protocol, Scheme, SensitivityFilter, Tombstone, ENVELOPE_VERSION};
+use osp_protocol::packet::chunked::{ReassemblyBudget, CHUNK_LENGTH};
+use osp_protocol::packet::handshake::CloseReason;
+use osp_protocol::packet::transfer::{TransferPacketGuestToHost, TransferPacketHostToGuest};
+use osp_protocol::recording::{FrameDirection, Recording};
 
+#[cfg(feature = "admin-api")]
+use crate::admin::AdminApi;
+use crate::builder::{self, BuildError, ConfigProblem, Missing, Provided};
+use crate::metrics;
+use crate::connection::accounting::{AccountingPolicy, CostAccounting};
+use crate::connection::admission::{Admission, AdmissionLimits};
+use crate::connection::diagnostics::{self, HandshakeDiagnostics, PeerDiagnostics};
+#[cfg(feature = "dns-auth")]
+use crate::connection::challenge::ChallengeRecord;
+#[cfg(feature = "dns-auth")]
+use crate::connection::dns::{self, ChallengeResolver};
+use crate::connection::inbound::{InboundConnection, DEFAULT_REASSEMBLY_BUDGET};
+use crate::connection::registry::{ConnectionGuard, ConnectionLimits, ConnectionRegistry};
+use crate::connection::resources::{self, Pressure, ResourceLimits, Resources};
+use crate::connection::socket::SocketOptions;
+use crate::connection::sync::SyncSession;
+use crate::connection::transport::TransportSecurity;
+use crate::convert::{Converters, Stage};
+use crate::dedup::{DedupCache, DedupStats};
+use crate::delivery::{self, PendingDelivery, RetryPolicy};
+use crate::embargo::{self, ScheduledObject};
+use crate::crypto::{Ed25519Key, Ed25519PublicKey, PrivateKey, PublicKey};
+use crate::events::{NodeEvent, EVENT_CAPACITY};
+use crate::fanout::{self, Fanout, FanoutTree};
+use crate::federation::{self, FederationAction, FederationRule, FederationUpdate};
+use crate::identity::{self, DataTypeSummary, IdentityDocument, IdentityPolicies, SignedIdentityDocument};
+use crate::invite::{self, InviteRecord};
+use crate::logging::{ErrorLog, ErrorSampling};
+use crate::handler::{Handlers, ReplayFilter, ReplayReport, Transform, Transforms, Validator, Validators};
+use crate::middleware::{Incoming, Middleware, Verdict};
+use crate::notice::{self, Notice, Notices, PublishedNotice, ReceivedNotice};
+use crate::platform::expand_path;
+use crate::plugin::Plugin;
+use crate::policy::{ContentPolicy, TracePropagation};
+use crate::policy::access::AccessPolicy;
+use crate::preview::{BroadcastPreview, Recipient, Refusal, Route};
+use crate::provenance::{self, Attestation, Attester, SignedData, Signer};
+use crate::recording::{RecordingReplay, Recordings};
+use crate::reload::{Live, LiveSettings};
+use crate::reporting::{self, ErrorReporter, Fault, FaultKind, Reporters};
+use crate::rollout::{Feature, Rollout, RolloutStats, Rollouts};
+use crate::routing::TopicFilter;
+#[cfg(feature = "geoip")]
+use crate::policy::geoip::GeoIpPolicy;
+#[cfg(feature = "rss-bridge")]
+use crate::feed::{self, RssSource};
+#[cfg(feature = "nostr-bridge")]
+use crate::nostr::{self, Nostr, NostrBridge};
+#[cfg(feature = "matrix-bridge")]
+use crate::matrix::{self, Matrix, MatrixBridge};
+use crate::schema::SchemaRegistry;
+use crate::selfcheck::{Environment, SelfCheckReport, SelfChecks};
+use crate::subscription::{DeliveryQos, SubscriptionApproval, SubscriptionManager, SubscriptionState};
+use crate::supervisor::{self, Supervisor, TargetState};
+use crate::trace;
+use crate::connection::outbound::{self, OutboundConnection};
+use crate::store::{AuditAction, AuditEntry, DataStore, MemoryStore, PurgeReport};
+use crate::store::backup::{BackupManifest, BackupSchedule};
+use crate::store::quota::{StorageQuota, StorageQuotas};
+use crate::stream::{self, ErasedStreamHandler, StreamHandler};
 
-pub struct OSProtocolNodeBuilder {
-    bind_addr: SocketAddr,
+
+/// How long a refused connection is given to receive the reason.
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many refused connections are told why at once. Beyond that, they
+/// are closed without a reason.
+const MAX_REFUSALS: usize = 64;
+
+/// How long accepting connections pauses when there are no file
+/// descriptors left.
+const EXHAUSTED_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Builder of [OSProtocolNode]s, see [builder](crate::builder). The type
+/// parameters track whether the bind address, hostname and identity were set.
+pub struct OSProtocolNodeBuilder<Bind = Missing, Host = Missing, Key = Missing> {
+    bind_addr: Option<SocketAddr>,
     hostname: String,
-    private_key: Option<Rsa<Private>>,
+    private_key: Option<KeySource>,
+    ed25519_key: Option<Ed25519Key>,
+    store: Arc<dyn DataStore>,
+    backup_schedule: Option<BackupSchedule>,
+    quotas: StorageQuotas,
+    maintenance_interval: Duration,
+    read_only: bool,
+    relay: bool,
+    dedup: DedupCache,
+    software: String,
+    push_window: Option<u32>,
+    reassembly_budget: u64,
+    keepalive: Keepalive,
+    rollouts: HashMap<Feature, Rollout>,
+    recordings: Option<PathBuf>,
+    recorded_peers: HashSet<String>,
+    self_checks: Option<SelfChecks>,
+    handlers: Handlers,
+    validators: Validators,
+    transforms: Transforms,
+    middleware: Vec<Arc<dyn Middleware>>,
+    converters: Converters,
+    schemas: SchemaRegistry,
+    types: DataTypeRegistry,
+    signed_types: HashMap<Uuid, Attester>,
+    policies: Vec<Arc<dyn ContentPolicy>>,
+    reporters: Reporters,
+    preferences: SensitivityFilter,
+    #[cfg(feature = "geoip")]
+    geoip: Option<GeoIpPolicy>,
+    #[cfg(feature = "rss-bridge")]
+    rss_sources: Vec<RssSource>,
+    #[cfg(feature = "nostr-bridge")]
+    nostr_bridge: Option<NostrBridge>,
+    #[cfg(feature = "matrix-bridge")]
+    matrix_bridge: Option<MatrixBridge>,
+    access: AccessPolicy,
+    trace_propagation: TracePropagation,
+    retry_policy: RetryPolicy,
+    honor_takedowns: bool,
+    takedown_authorities: Vec<Ed25519PublicKey>,
+    subscription_approval: SubscriptionApproval,
+    subscription_lease: Option<Duration>,
+    compression: Vec<Compression>,
+    payload_formats: Vec<PayloadFormat>,
+    invite_only: bool,
+    invites: Vec<Invite>,
+    allowlist_only: bool,
+    federation_peers: HashMap<String, PublicKey>,
+    communities: HashMap<String, HashSet<String>>,
+    identity_addr: Option<SocketAddr>,
+    websocket_addr: Option<SocketAddr>,
+    advertised_urls: Vec<String>,
+    push_targets: Vec<OSPUrl>,
+    contact: Option<String>,
+    #[cfg(feature = "dns-auth")]
+    resolver: Option<ChallengeResolver>,
+    connection_limits: ConnectionLimits,
+    admission_limits: AdmissionLimits,
+    resource_limits: ResourceLimits,
+    accounting_policy: AccountingPolicy,
+    error_sampling: ErrorSampling,
+    transport: TransportSecurity,
+    listener_socket_options: SocketOptions,
+    outbound_socket_options: SocketOptions,
+    state: PhantomData<(Bind, Host, Key)>,
 }
 
-impl OSProtocolNodeBuilder {
-    pub fn bind_to(mut self, addr: SocketAddr) -> Self {
-        self.bind_addr = addr;
-        self
+/// Where the private key of a node comes from.
+enum KeySource {
+    Key(PrivateKey),
+    File(PathBuf),
+}
+
+impl<Bind, Host, Key> OSProtocolNodeBuilder<Bind, Host, Key> {
+    pub fn bind_to(mut self, addr: SocketAddr) -> OSProtocolNodeBuilder<Provided, Host, Key> {
+        self.bind_addr = Some(addr);
+        self.into_state()
     }
 
-    pub fn hostname(mut self, hostname: String) -> Self {
+    pub fn hostname(mut self, hostname: String) -> OSProtocolNodeBuilder<Bind, Provided, Key> {
         self.hostname = hostname;
-        self
+        self.into_state()
+    }
+
+    /// Load the node's private key from the PEM file at `path` when the node
+    /// is built. A leading `~` is resolved to the home directory, see
+    /// [expand_path].
+    pub fn private_key_file(mut self, path: impl Into<PathBuf>) -> OSProtocolNodeBuilder<Bind, Host, Provided> {
+        self.private_key = Some(KeySource::File(expand_path(path.into())));
+        self.into_state()
+    }
+
+    pub fn private_key(mut self, key: PrivateKey) -> OSProtocolNodeBuilder<Bind, Host, Provided> {
+        self.private_key = Some(KeySource::Key(key));
+        self.into_state()
     }
 
-    pub fn private_key_file(mut self, path: String) -> Self {
-        let key_contents = fs::read_to_string(path.clone()).expect(format!("Unable to open private key file {}", path).as_str());
-        self.private_key = Some(Rsa::private_key_from_pem(key_contents.as_bytes()).unwrap());
+    /// Also answer handshake challenges with `key`, for peers that
+    /// challenge the node with the Ed25519 key in its `_osp` record instead
+    /// of the RSA one. The record to publish for it is built by the admin
+    /// API.
+    pub fn ed25519_key(mut self, key: Ed25519Key) -> Self {
+        self.ed25519_key = Some(key);
         self
     }
 
-    pub fn build(self) -> OSProtocolNode {
-        OSProtocolNode {
+    fn into_state<B, H, K>(self) -> OSProtocolNodeBuilder<B, H, K> {
+        OSProtocolNodeBuilder {
             bind_addr: self.bind_addr,
             hostname: self.hostname,
-            private_key: self.private_key.unwrap(),
+            private_key: self.private_key,
+            ed25519_key: self.ed25519_key,
+            store: self.store,
+            backup_schedule: self.backup_schedule,
+            quotas: self.quotas,
+            maintenance_interval: self.maintenance_interval,
+            read_only: self.read_only,
+            relay: self.relay,
+            dedup: self.dedup,
+            software: self.software,
+            push_window: self.push_window,
+            reassembly_budget: self.reassembly_budget,
+            keepalive: self.keepalive,
+            rollouts: self.rollouts,
+            recordings: self.recordings,
+            recorded_peers: self.recorded_peers,
+            self_checks: self.self_checks,
+            handlers: self.handlers,
+            validators: self.validators,
+            transforms: self.transforms,
+            middleware: self.middleware,
+            converters: self.converters,
+            schemas: self.schemas,
+            types: self.types,
+            signed_types: self.signed_types,
+            policies: self.policies,
+            reporters: self.reporters,
+            preferences: self.preferences,
+            #[cfg(feature = "geoip")]
+            geoip: self.geoip,
+            #[cfg(feature = "rss-bridge")]
+            rss_sources: self.rss_sources,
+            #[cfg(feature = "nostr-bridge")]
+            nostr_bridge: self.nostr_bridge,
+            #[cfg(feature = "matrix-bridge")]
+            matrix_bridge: self.matrix_bridge,
+            access: self.access,
+            trace_propagation: self.trace_propagation,
+            retry_policy: self.retry_policy,
+            honor_takedowns: self.honor_takedowns,
+            takedown_authorities: self.takedown_authorities,
+            subscription_approval: self.subscription_approval,
+            subscription_lease: self.subscription_lease,
+            compression: self.compression,
+            payload_formats: self.payload_formats,
+            invite_only: self.invite_only,
+            invites: self.invites,
+            allowlist_only: self.allowlist_only,
+            federation_peers: self.federation_peers,
+            communities: self.communities,
+            identity_addr: self.identity_addr,
+            websocket_addr: self.websocket_addr,
+            advertised_urls: self.advertised_urls,
+            push_targets: self.push_targets,
+            contact: self.contact,
+            #[cfg(feature = "dns-auth")]
+            resolver: self.resolver,
+            connection_limits: self.connection_limits,
+            admission_limits: self.admission_limits,
+            resource_limits: self.resource_limits,
+            accounting_policy: self.accounting_policy,
+            error_sampling: self.error_sampling,
+            transport: self.transport,
+            listener_socket_options: self.listener_socket_options,
+            outbound_socket_options: self.outbound_socket_options,
+            state: PhantomData,
+        }
+    }
+
+    /// Set the [DataStore] the node keeps its state in. Defaults to a
+    /// [MemoryStore], which forgets everything when the node stops.
+    pub fn data_store<S: DataStore + 'static>(mut self, store: S) -> Self {
+        self.store = Arc::new(store);
+        self
+    }
+
+    /// Back up the node's store on a schedule while it is listening.
+    pub fn backup_schedule(mut self, schedule: BackupSchedule) -> Self {
+        self.backup_schedule = Some(schedule);
+        self
+    }
+
+    /// How often the store's background maintenance (such as moving old
+    /// objects to cold storage) runs. Defaults to once a minute.
+    pub fn maintenance_interval(mut self, interval: Duration) -> Self {
+        self.maintenance_interval = interval;
+        self
+    }
+
+    /// Limit how much storage objects of `type_id` may use.
+    pub fn storage_quota(mut self, type_id: Uuid, quota: StorageQuota) -> Self {
+        self.quotas.set(type_id, quota);
+        self
+    }
+
+    /// Run as a read-only node, such as a public archive or mirror. A
+    /// read-only node refuses every stream peers send it, and every object
+    /// they push to it except those of the types it subscribed to at that
+    /// peer. It stores and handles those, but doesn't pass them on to its
+    /// own subscribers. Which types it subscribed to is only remembered
+    /// while the node runs, so after a restart it refuses objects from a
+    /// peer until it subscribes at it again.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Relay objects across a mesh of nodes, dropping the copies of an
+    /// origin's objects this node holds already when they come back along
+    /// another path. Every node passes objects received from peers on to its
+    /// subscribers with one less [ttl](Envelope::ttl), until it runs out.
+    pub fn relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Remember received objects in `cache` instead of the default one,
+    /// which keeps [DEFAULT_CAPACITY](crate::dedup::DEFAULT_CAPACITY) keys in
+    /// memory for [DEFAULT_TTL](crate::dedup::DEFAULT_TTL). See
+    /// [dedup](crate::dedup) for how duplicates are dropped.
+    pub fn dedup_cache(mut self, cache: DedupCache) -> Self {
+        self.dedup = cache;
+        self
+    }
+
+    /// Tell peers this node runs `software`, such as `my-blog/2.1.0`, in
+    /// handshakes. Defaults to the name and version of this SDK, see
+    /// [diagnostics](crate::connection::diagnostics).
+    pub fn software(mut self, software: &str) -> Self {
+        self.software = software.to_string();
+        self
+    }
+
+    /// How many pushes each guest may have waiting for an ack, so guests
+    /// pushing faster than this node takes objects in wait instead of
+    /// piling them up. `None` lets them push as fast as they like, as do
+    /// guests from before
+    /// [PUSH_WINDOW_VERSION](osp_protocol::packet::transfer::PUSH_WINDOW_VERSION).
+    /// Guests with a window ask for an ack with every push. The window is
+    /// only advice this node gives, guests that push beyond it aren't
+    /// refused. Defaults to `None`.
+    pub fn push_window(mut self, pushes: Option<u32>) -> Self {
+        self.push_window = pushes;
+        self
+    }
+
+    /// How many bytes of chunked pushes all guests together may be in the
+    /// middle of, see [chunked](osp_protocol::packet::chunked). Guests whose
+    /// chunks would go over it are disconnected. Defaults to
+    /// [DEFAULT_REASSEMBLY_BUDGET], and can't be less than a chunk.
+    pub fn reassembly_budget(mut self, bytes: u64) -> Self {
+        self.reassembly_budget = bytes;
+        self
+    }
+
+    /// When connections are pinged and when they are closed as their peer
+    /// is gone, in both directions, see
+    /// [Keepalive](osp_protocol::Keepalive). Connections with peers from
+    /// before [KEEPALIVE_VERSION](osp_protocol::packet::transfer::KEEPALIVE_VERSION)
+    /// stay open however long they are idle. Defaults to a ping after 30
+    /// seconds and closing after 90.
+    pub fn keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Only enable `feature` for the peers `rollout` includes, see
+    /// [rollout](crate::rollout). Features are enabled for every peer
+    /// unless they are rolled out.
+    pub fn rollout(mut self, feature: Feature, rollout: Rollout) -> Self {
+        self.rollouts.insert(feature, rollout);
+        self
+    }
+
+    /// Record connections to `directory`, see [recording](crate::recording).
+    /// Only connections with the peers set with
+    /// [record_peer](Self::record_peer), or
+    /// [OSProtocolNode::record_peer] while the node runs, are recorded.
+    pub fn recordings(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.recordings = Some(directory.into());
+        self
+    }
+
+    /// Record connections with `peer`, a hostname or the address of a
+    /// guest, see [recording](crate::recording).
+    pub fn record_peer(mut self, peer: &str) -> Self {
+        self.recorded_peers.insert(peer.to_ascii_lowercase());
+        self
+    }
+
+    /// Check the environment of the node before it starts listening, and
+    /// refuse to listen if `checks` fail, see [selfcheck](crate::selfcheck).
+    /// Nodes don't check their environment by default.
+    pub fn self_checks(mut self, checks: SelfChecks) -> Self {
+        self.self_checks = Some(checks);
+        self
+    }
+
+    /// Whether to apply takedowns sent by peers, removing the objects they
+    /// name. Defaults to `false`. Even when enabled, a peer can only take
+    /// down objects that originate from it, unless the takedown is signed by
+    /// one of the [takedown authorities](Self::takedown_authority).
+    /// Takedowns are recorded in the audit log either way.
+    pub fn honor_takedowns(mut self, honor: bool) -> Self {
+        self.honor_takedowns = honor;
+        self
+    }
+
+    /// Trust takedowns signed with the private half of `key`, such as the
+    /// [Ed25519 key](Self::ed25519_key) of a moderating node, whichever peer
+    /// they are received from. Can be called more than once.
+    pub fn takedown_authority(mut self, key: Ed25519PublicKey) -> Self {
+        self.takedown_authorities.push(key);
+        self
+    }
+
+    /// How subscription requests from peers are decided. Defaults to
+    /// [SubscriptionApproval::Automatic].
+    pub fn subscription_approval(mut self, approval: SubscriptionApproval) -> Self {
+        self.subscription_approval = approval;
+        self
+    }
+
+    /// How long subscriptions last unless the peer renews them, after which
+    /// it is no longer sent objects until it does. `None` keeps them until
+    /// the peer unsubscribes. Defaults to `None`, as nodes don't renew the
+    /// subscriptions they make at their peers on their own, only clients do.
+    /// [DEFAULT_SUBSCRIPTION_LEASE](crate::subscription::DEFAULT_SUBSCRIPTION_LEASE)
+    /// suits nodes whose subscribers are clients.
+    pub fn subscription_lease(mut self, lease: Option<Duration>) -> Self {
+        self.subscription_lease = lease;
+        self
+    }
+
+    /// The algorithms frames to and from peers may be compressed with, the
+    /// preferred first. Defaults to every algorithm this build supports, see
+    /// [Compression::supported]. Empty turns compression off.
+    pub fn compression(mut self, compression: Vec<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// The formats peers may push objects in, the preferred first, see
+    /// [PayloadFormat]. Defaults to every format, bincode first. Objects
+    /// pushed to peers are transcoded to the format they picked where the
+    /// node can.
+    pub fn payload_formats(mut self, formats: Vec<PayloadFormat>) -> Self {
+        self.payload_formats = formats;
+        self
+    }
+
+    /// Only accept peers that present an invite issued by this node, instead
+    /// of any peer with an `_osp` DNS record. Defaults to `false`.
+    pub fn invite_only(mut self, invite_only: bool) -> Self {
+        self.invite_only = invite_only;
+        self
+    }
+
+    /// Present `invite` when connecting to the node that issued it.
+    pub fn invite(mut self, invite: Invite) -> Self {
+        self.invites.push(invite);
+        self
+    }
+
+    /// Look up the challenge records of peers with `resolver` instead of
+    /// public resolvers over plain DNS.
+    #[cfg(feature = "dns-auth")]
+    pub fn challenge_resolver(mut self, resolver: ChallengeResolver) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// Bound the number, rate and lifetime of inbound connections, see
+    /// [ConnectionLimits].
+    pub fn connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.connection_limits = limits;
+        self
+    }
+
+    /// How many errors of the same kind about the same peer are logged per
+    /// interval, see [ErrorSampling]. Defaults to 5 a minute.
+    pub fn error_sampling(mut self, sampling: ErrorSampling) -> Self {
+        self.error_sampling = sampling;
+        self
+    }
+
+    /// Secure the node's connections with `transport`, see
+    /// [TransportSecurity]. Defaults to plaintext.
+    pub fn transport_security(mut self, transport: TransportSecurity) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Listen with `options`, and set them on every connection accepted,
+    /// see [socket](crate::connection::socket). Defaults to those of the
+    /// system.
+    pub fn listener_socket_options(mut self, options: SocketOptions) -> Self {
+        self.listener_socket_options = options;
+        self
+    }
+
+    /// Connect to peers with `options`, see
+    /// [socket](crate::connection::socket). Defaults to those of the
+    /// system.
+    pub fn outbound_socket_options(mut self, options: SocketOptions) -> Self {
+        self.outbound_socket_options = options;
+        self
+    }
+
+    /// Serve the node's identity document over HTTP on `addr`, see
+    /// [identity](crate::identity).
+    pub fn identity_endpoint(mut self, addr: SocketAddr) -> Self {
+        self.identity_addr = Some(addr);
+        self
+    }
+
+    /// Accept connections carried over a WebSocket on `addr` as well, for
+    /// peers that can only reach the node over HTTP(S), see
+    /// [transport](crate::connection::transport). Upgrades are accepted at
+    /// [WEBSOCKET_PATH](osp_protocol::websocket::WEBSOCKET_PATH).
+    #[cfg(feature = "websocket")]
+    pub fn websocket_endpoint(mut self, addr: SocketAddr) -> Self {
+        self.websocket_addr = Some(addr);
+        self
+    }
+
+    /// List `url` in the identity document as an address the node accepts
+    /// connections at. Defaults to the hostname and the port the node is
+    /// bound to, and that of its WebSocket endpoint if it has one.
+    pub fn advertise_url(mut self, url: OSPUrl) -> Self {
+        self.advertised_urls.push(url.to_string());
+        self
+    }
+
+    /// Push every object this node publishes to the peer at `url`, as if it
+    /// subscribed to every type. Can be called several times.
+    pub fn push_target(mut self, url: OSPUrl) -> Self {
+        self.push_targets.push(url);
+        self
+    }
+
+    /// How to reach the node's operator, listed in the identity document.
+    pub fn contact(mut self, contact: String) -> Self {
+        self.contact = Some(contact);
+        self
+    }
+
+    /// Only federate with hosts allowed by a federation rule, instead of
+    /// every host that isn't denied. Defaults to `false`.
+    pub fn allowlist_only(mut self, allowlist_only: bool) -> Self {
+        self.allowlist_only = allowlist_only;
+        self
+    }
+
+    /// Trust `hostname` as a member of this node's cluster, merging the
+    /// federation updates it signs with the private key matching
+    /// `public_key` into this node's federation rules, and relaying its
+    /// objects along the [fan-out](crate::fanout) delegations it signs.
+    pub fn federation_peer(mut self, hostname: &str, public_key: PublicKey) -> Self {
+        self.schemas.register(FederationUpdate::descriptor());
+        self.federation_peers.insert(hostname.to_ascii_lowercase(), public_key);
+        self
+    }
+
+    /// Count `members` as members of the community `name`, the only peers
+    /// objects with the audience
+    /// [Community](osp_protocol::Audience::Community) `name` are pushed to.
+    /// Can be called several times, adding to the members.
+    pub fn community(mut self, name: &str, members: &[&str]) -> Self {
+        self.communities.entry(name.to_string()).or_default()
+            .extend(members.iter().map(|member| member.to_ascii_lowercase()));
+        self
+    }
+
+    /// Run `handler` for every received object of the data type `T`. `id`
+    /// identifies the handler in logs and replays.
+    pub fn handler<T: Data, H: DataHandler<T> + 'static>(mut self, id: &str, handler: H) -> Self {
+        self.schemas.register(T::descriptor());
+        self.handlers.register(id, handler);
+        self
+    }
+
+    /// Hand the streams peers send to `handler`, see [stream](crate::stream).
+    /// Replaces the stream handler set before, if any.
+    pub fn stream_handler<H: StreamHandler + 'static>(mut self, handler: H) -> Self {
+        self.handlers.set_stream_handler(stream::erase(handler));
+        self
+    }
+
+    /// Describe the data type `T` to peers that ask about it. Types with
+    /// handlers or converters registered are described automatically.
+    pub fn data_type<T: Data>(mut self) -> Self {
+        self.schemas.register(T::descriptor());
+        self
+    }
+
+    /// Take [SignedData] objects of `T`, refusing those pushed to the node
+    /// whose signature doesn't verify against the `_osp` record of their
+    /// origin, see [provenance](crate::provenance). Nodes built without the
+    /// `dns-auth` feature can't check them, and refuse to build.
+    pub fn signed_data_type<T: Data>(mut self) -> Self {
+        self.schemas.register(SignedData::<T>::descriptor());
+        self.signed_types.insert(SignedData::<T>::TYPE_ID, provenance::attester::<T>());
+        self
+    }
+
+    /// Describe the data types in `registry` to peers, and run the handlers
+    /// registered in it for received objects of their types, alongside those
+    /// registered on the node.
+    ///
+    /// Received objects of a version of their type the registry can
+    /// translate are stored as the latest version, and objects are pushed
+    /// to subscribers in the latest version they understand, see
+    /// [translate_for](DataTypeRegistry::translate_for). This replaces the
+    /// registry given before, if any.
+    pub fn data_types(mut self, registry: &DataTypeRegistry) -> Self {
+        for descriptor in registry.descriptors() {
+            self.schemas.register(descriptor.clone());
+        }
+        for data_type in registry.types() {
+            for (id, version, handler) in registry.handlers(data_type.id) {
+                let (handler, types) = (handler.clone(), registry.clone());
+                self.handlers.register_raw(id, data_type.id, Box::new(move |envelope| match types.translate(&envelope, version) {
+                    Ok(envelope) => handler(envelope),
+                    Err(e) => Box::pin(std::future::ready(Err(e.into()))),
+                }));
+            }
+        }
+        self.types = registry.clone();
+        self
+    }
+
+    /// Run `validator` on every received object of `type_id` before it is
+    /// stored. Objects it refuses are not stored.
+    pub fn validator<V: Validator + 'static>(mut self, id: &str, type_id: Uuid, validator: V) -> Self {
+        self.validators.register(id, type_id, Arc::new(validator));
+        self
+    }
+
+    /// Refuse objects that don't satisfy `policy`, both when receiving them
+    /// and when pushing them to peers.
+    pub fn content_policy<P: ContentPolicy + 'static>(mut self, policy: P) -> Self {
+        self.policies.push(Arc::new(policy));
+        self
+    }
+
+    /// Refuse flagged objects excluded by `preferences`. Peers are told about
+    /// the preferences during the handshake, so they don't push such objects
+    /// in the first place.
+    pub fn sensitivity_preferences(mut self, preferences: SensitivityFilter) -> Self {
+        self.preferences = preferences;
+        self
+    }
+
+    /// Look up the country of inbound connections with `policy`, refusing
+    /// those it doesn't allow. Connections are labelled with their country in
+    /// logs and metrics.
+    #[cfg(feature = "geoip")]
+    pub fn geoip_policy(mut self, policy: GeoIpPolicy) -> Self {
+        self.geoip = Some(policy);
+        self
+    }
+
+    /// Keep polling the legacy RSS feed `source` and republish its items
+    /// over OSP, see [feed](crate::feed). Can be called several times.
+    #[cfg(feature = "rss-bridge")]
+    pub fn rss_source(mut self, source: RssSource) -> Self {
+        self.rss_sources.push(source);
+        self
+    }
+
+    /// Carry articles to and from Nostr relays, see [nostr](crate::nostr).
+    /// Replaces the bridge set before, if any.
+    #[cfg(feature = "nostr-bridge")]
+    pub fn nostr_bridge(mut self, bridge: NostrBridge) -> Self {
+        self.nostr_bridge = Some(bridge);
+        self
+    }
+
+    /// Post objects into Matrix rooms, see [matrix](crate::matrix). Replaces
+    /// the bridge set before, if any.
+    #[cfg(feature = "matrix-bridge")]
+    pub fn matrix_bridge(mut self, bridge: MatrixBridge) -> Self {
+        self.matrix_bridge = Some(bridge);
+        self
+    }
+
+    /// Accept only the peers `policy` allows, by hostname and by the address
+    /// they connect from, see [AccessPolicy].
+    pub fn access_policy(mut self, policy: AccessPolicy) -> Self {
+        self.access = policy;
+        self
+    }
+
+    /// Choose which peers trace contexts are exchanged with, to keep how
+    /// objects travel private at the boundary of the cluster. Defaults to
+    /// [TracePropagation::Propagate].
+    pub fn trace_propagation(mut self, propagation: TracePropagation) -> Self {
+        self.trace_propagation = propagation;
+        self
+    }
+
+    /// How deliveries queued with [OSProtocolNode::deliver] are retried
+    /// when they fail, see [RetryPolicy].
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Bound how many requests of each kind peers may have the node serve
+    /// at once, shedding expensive kinds first when overloaded, see
+    /// [admission](crate::connection::admission).
+    pub fn admission_limits(mut self, limits: AdmissionLimits) -> Self {
+        self.admission_limits = limits;
+        self
+    }
+
+    /// Set how close to the open file limit of the process the node refuses
+    /// new connections, and closes idle ones, see
+    /// [resources](crate::connection::resources).
+    pub fn resource_limits(mut self, limits: ResourceLimits) -> Self {
+        self.resource_limits = limits;
+        self
+    }
+
+    /// Set how the cost of serving peers is accounted for and whether a
+    /// fair share is enforced, see [accounting](crate::connection::accounting).
+    pub fn cost_accounting(mut self, policy: AccountingPolicy) -> Self {
+        self.accounting_policy = policy;
+        self
+    }
+
+    /// Add `middleware` to the end of the middleware chain every received
+    /// object passes through.
+    pub fn middleware<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Rewrite every received object of `type_id` with `transform` before it
+    /// is stored and handled. Transforms run in the order they were added.
+    pub fn transform<T: Transform + 'static>(mut self, id: &str, type_id: Uuid, transform: T) -> Self {
+        self.transforms.register(id, type_id, Arc::new(transform));
+        self
+    }
+
+    /// Convert objects of type `A` into type `B` at `stage`. Only one
+    /// converter can be registered per source type and stage.
+    #[cfg(feature = "bridges")]
+    pub fn converter<A, B, F>(mut self, stage: Stage, convert: F) -> Self
+    where
+        A: Data,
+        B: Data,
+        F: Fn(A) -> io::Result<B> + Send + Sync + 'static,
+    {
+        self.schemas.register(A::descriptor());
+        self.schemas.register(B::descriptor());
+        self.converters.register(stage, convert);
+        self
+    }
+
+    /// Attach `plugin` to the data types in `data_types`, registering it as a
+    /// handler, validator and/or transform for them depending on what it
+    /// provides.
+    pub fn plugin<P: Plugin>(mut self, plugin: P, data_types: &[Uuid]) -> Self {
+        let plugin = Arc::new(plugin);
+        let id = plugin.name().to_string();
+        for type_id in data_types {
+            if plugin.handles() {
+                let plugin = plugin.clone();
+                self.handlers.register_raw(&id, *type_id, Box::new(move |envelope| {
+                    let plugin = plugin.clone();
+                    Box::pin(async move {
+                        plugin.handle(&envelope)?;
+                        Ok(HandlerStatus::Processed)
+                    })
+                }));
+            }
+            if plugin.validates() {
+                let plugin = plugin.clone();
+                self.validators.register(&id, *type_id, Arc::new(move |envelope: &Envelope| plugin.validate(envelope)));
+            }
+            if plugin.transforms() {
+                let plugin = plugin.clone();
+                self.transforms.register(&id, *type_id, Arc::new(move |envelope| plugin.transform(envelope)));
+            }
+        }
+        self
+    }
+
+    /// Hand faults of the node, such as panicking handlers, to `reporter`,
+    /// see [reporting](crate::reporting). Several reporters can be
+    /// registered.
+    pub fn error_reporter<R: ErrorReporter + 'static>(mut self, reporter: R) -> Self {
+        self.reporters.register(Arc::new(reporter));
+        self
+    }
+
+    /// Log a warning whenever a handler takes longer than `threshold` on a
+    /// single object. Defaults to one second.
+    pub fn slow_handler_threshold(mut self, threshold: Duration) -> Self {
+        self.handlers.set_slow_threshold(threshold);
+        self
+    }
+}
+
+impl OSProtocolNodeBuilder<Provided, Provided, Provided> {
+    /// Build the node, checking its settings for problems the type system
+    /// can't catch.
+    pub fn build(mut self) -> Result<OSProtocolNode, BuildError> {
+        let mut problems = Vec::new();
+        self.handlers.set_reporters(self.reporters.clone());
+        let bind_addr = self.bind_addr.expect("bind address is set in this state");
+        if let Err(reason) = builder::check_hostname(&self.hostname) {
+            problems.push(ConfigProblem::InvalidHostname { hostname: self.hostname.clone(), reason });
+        }
+        let private_key = match self.private_key.expect("private key is set in this state") {
+            KeySource::Key(key) => Ok(key),
+            KeySource::File(path) => fs::read(&path)
+                .and_then(|pem| PrivateKey::from_pem(&pem))
+                .map_err(|e| ConfigProblem::InvalidPrivateKey { path: Some(path.display().to_string()), reason: e.to_string() }),
+        }.and_then(|key| match key.check() {
+            Ok(true) => Ok(key),
+            Ok(false) => Err(ConfigProblem::InvalidPrivateKey { path: None, reason: "inconsistent key".to_string() }),
+            Err(e) => Err(ConfigProblem::InvalidPrivateKey { path: None, reason: e.to_string() }),
+        });
+        if self.identity_addr == Some(bind_addr) {
+            problems.push(ConfigProblem::IdentityEndpointConflict);
+        }
+        if self.websocket_addr.is_some_and(|addr| addr == bind_addr || Some(addr) == self.identity_addr) {
+            problems.push(ConfigProblem::WebSocketEndpointConflict);
+        }
+        if self.maintenance_interval.is_zero() {
+            problems.push(ConfigProblem::ZeroMaintenanceInterval);
+        }
+        if self.connection_limits.max_connections == Some(0) {
+            problems.push(ConfigProblem::ZeroMaxConnections);
+        }
+        if self.connection_limits.max_connections_per_ip == Some(0) {
+            problems.push(ConfigProblem::ZeroMaxConnectionsPerIp);
+        }
+        if self.connection_limits.rate_limit.is_some_and(|limit| !limit.is_valid()) {
+            problems.push(ConfigProblem::InvalidRateLimit);
+        }
+        if self.connection_limits.handshake_timeout.is_zero() {
+            problems.push(ConfigProblem::ZeroHandshakeTimeout);
+        }
+        if !self.retry_policy.is_valid() {
+            problems.push(ConfigProblem::InvalidRetryPolicy);
+        }
+        if !self.accounting_policy.is_valid() {
+            problems.push(ConfigProblem::InvalidAccountingPolicy);
+        }
+        if self.subscription_lease.is_some_and(|lease| lease.as_secs() == 0) {
+            problems.push(ConfigProblem::ZeroSubscriptionLease);
+        }
+        if self.push_window == Some(0) {
+            problems.push(ConfigProblem::ZeroPushWindow);
+        }
+        if self.reassembly_budget < CHUNK_LENGTH as u64 {
+            problems.push(ConfigProblem::SmallReassemblyBudget);
+        }
+        if !self.keepalive.is_valid() {
+            problems.push(ConfigProblem::InvalidKeepalive);
+        }
+        for feature in Feature::ALL {
+            if self.rollouts.get(&feature).is_some_and(|rollout| !rollout.is_valid()) {
+                problems.push(ConfigProblem::InvalidRollout { feature });
+            }
+        }
+        if self.recordings.is_none() && !self.recorded_peers.is_empty() {
+            problems.push(ConfigProblem::RecordingsWithoutDirectory);
+        }
+        if !self.resource_limits.is_valid() {
+            problems.push(ConfigProblem::InvalidResourceLimits);
+        }
+        if !self.listener_socket_options.is_valid() {
+            problems.push(ConfigProblem::InvalidSocketOptions { sockets: "listener" });
+        }
+        if !self.outbound_socket_options.is_valid() {
+            problems.push(ConfigProblem::InvalidSocketOptions { sockets: "outbound" });
+        }
+        #[cfg(feature = "rss-bridge")]
+        for source in self.rss_sources.iter().filter(|source| source.interval.is_zero()) {
+            problems.push(ConfigProblem::ZeroPollInterval { url: source.url.clone() });
         }
+        #[cfg(feature = "nostr-bridge")]
+        if self.nostr_bridge.as_ref().is_some_and(|bridge| bridge.relays.is_empty()) {
+            problems.push(ConfigProblem::NoNostrRelays);
+        }
+        #[cfg(feature = "matrix-bridge")]
+        if self.matrix_bridge.as_ref().is_some_and(|bridge| bridge.rooms.is_empty()) {
+            problems.push(ConfigProblem::NoMatrixRooms);
+        }
+        if self.error_sampling.interval.is_zero() {
+            problems.push(ConfigProblem::ZeroErrorSamplingInterval);
+        }
+        #[cfg(not(feature = "dns-auth"))]
+        if !self.signed_types.is_empty() {
+            problems.push(ConfigProblem::UnverifiableSignedTypes);
+        }
+        let private_key = match private_key {
+            Ok(key) if problems.is_empty() => key,
+            Ok(_) => return Err(BuildError { problems }),
+            Err(problem) => {
+                problems.insert(0, problem);
+                return Err(BuildError { problems });
+            }
+        };
+        let subscriptions = SubscriptionManager::new(self.store.clone(), self.subscription_approval, self.subscription_lease);
+        let fanout = Fanout::new(self.store.clone());
+
+        Ok(OSProtocolNode {
+            bind_addr,
+            hostname: self.hostname,
+            private_key,
+            ed25519_key: self.ed25519_key,
+            store: self.store,
+            backup_schedule: self.backup_schedule,
+            quotas: Arc::new(self.quotas),
+            maintenance_interval: self.maintenance_interval,
+            read_only: self.read_only,
+            relay: self.relay,
+            dedup: Arc::new(self.dedup),
+            software: self.software.into(),
+            push_window: self.push_window,
+            reassembly_budget: ReassemblyBudget::new(self.reassembly_budget),
+            keepalive: self.keepalive,
+            rollouts: Arc::new(Rollouts::new(self.rollouts)),
+            recordings: Arc::new(Recordings::new(self.recordings, self.recorded_peers)),
+            self_checks: self.self_checks.map(Arc::new),
+            diagnostics: Arc::new(HandshakeDiagnostics::default()),
+            notices: Arc::new(Notices::default()),
+            handlers: Arc::new(self.handlers),
+            validators: Arc::new(self.validators),
+            transforms: Arc::new(self.transforms),
+            middleware: Arc::new(self.middleware),
+            converters: Arc::new(self.converters),
+            schemas: Arc::new(self.schemas),
+            types: Arc::new(self.types),
+            signed_types: Arc::new(self.signed_types),
+            policies: Arc::new(self.policies),
+            reporters: self.reporters,
+            preferences: self.preferences,
+            #[cfg(feature = "geoip")]
+            geoip: self.geoip.map(Arc::new),
+            #[cfg(feature = "rss-bridge")]
+            rss_sources: Arc::new(self.rss_sources),
+            #[cfg(feature = "nostr-bridge")]
+            nostr: self.nostr_bridge.map(|bridge| Arc::new(Nostr::new(bridge))),
+            #[cfg(feature = "matrix-bridge")]
+            matrix: self.matrix_bridge.map(|bridge| Arc::new(Matrix::new(bridge))),
+            trace_propagation: self.trace_propagation,
+            retry_policy: self.retry_policy,
+            deliveries_queued: Arc::new(Notify::new()),
+            refusals: Arc::new(Semaphore::new(MAX_REFUSALS)),
+            supervisor: Arc::new(Supervisor::default()),
+            honor_takedowns: self.honor_takedowns,
+            takedown_authorities: self.takedown_authorities,
+            subscriptions: Arc::new(subscriptions),
+            fanout: Arc::new(fanout),
+            compression: Arc::new(self.compression),
+            payload_formats: Arc::new(self.payload_formats),
+            invite_only: self.invite_only,
+            invites: Arc::new(self.invites),
+            federation_peers: Arc::new(self.federation_peers),
+            communities: Arc::new(self.communities),
+            identity_addr: self.identity_addr,
+            websocket_addr: self.websocket_addr,
+            advertised_urls: Arc::new(self.advertised_urls),
+            contact: self.contact,
+            #[cfg(feature = "dns-auth")]
+            resolver: self.resolver.unwrap_or_default(),
+            live: Arc::new(RwLock::new(Live::new(self.access, self.allowlist_only, self.push_targets, self.connection_limits))),
+            admission: Arc::new(Admission::new(self.admission_limits)),
+            resources: Arc::new(Resources::new(self.resource_limits)),
+            accounting: Arc::new(CostAccounting::new(self.accounting_policy)),
+            transport: self.transport,
+            listener_socket_options: self.listener_socket_options,
+            outbound_socket_options: self.outbound_socket_options,
+            connections: ConnectionRegistry::default(),
+            errors: Arc::new(ErrorLog::new(self.error_sampling)),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        })
     }
 }
 
@@ -50,50 +1002,2930 @@ impl OSProtocolNodeBuilder {
 pub struct OSProtocolNode {
     bind_addr: SocketAddr,
     hostname: String,
-    private_key: Rsa<Private>,
+    private_key: PrivateKey,
+    ed25519_key: Option<Ed25519Key>,
+    store: Arc<dyn DataStore>,
+    backup_schedule: Option<BackupSchedule>,
+    quotas: Arc<StorageQuotas>,
+    maintenance_interval: Duration,
+    read_only: bool,
+    relay: bool,
+    dedup: Arc<DedupCache>,
+    software: Arc<str>,
+    push_window: Option<u32>,
+    /// Shared by the reassemblers of every inbound connection
+    reassembly_budget: ReassemblyBudget,
+    keepalive: Keepalive,
+    rollouts: Arc<Rollouts>,
+    recordings: Arc<Recordings>,
+    self_checks: Option<Arc<SelfChecks>>,
+    diagnostics: Arc<HandshakeDiagnostics>,
+    notices: Arc<Notices>,
+    handlers: Arc<Handlers>,
+    validators: Arc<Validators>,
+    transforms: Arc<Transforms>,
+    middleware: Arc<Vec<Arc<dyn Middleware>>>,
+    converters: Arc<Converters>,
+    schemas: Arc<SchemaRegistry>,
+    types: Arc<DataTypeRegistry>,
+    signed_types: Arc<HashMap<Uuid, Attester>>,
+    policies: Arc<Vec<Arc<dyn ContentPolicy>>>,
+    reporters: Reporters,
+    preferences: SensitivityFilter,
+    #[cfg(feature = "geoip")]
+    geoip: Option<Arc<GeoIpPolicy>>,
+    #[cfg(feature = "rss-bridge")]
+    rss_sources: Arc<Vec<RssSource>>,
+    #[cfg(feature = "nostr-bridge")]
+    nostr: Option<Arc<Nostr>>,
+    #[cfg(feature = "matrix-bridge")]
+    matrix: Option<Arc<Matrix>>,
+    trace_propagation: TracePropagation,
+    retry_policy: RetryPolicy,
+    /// Wakes the delivery task when something is queued
+    deliveries_queued: Arc<Notify>,
+    /// Bounds the tasks telling refused connections why
+    refusals: Arc<Semaphore>,
+    supervisor: Arc<Supervisor>,
+    honor_takedowns: bool,
+    takedown_authorities: Vec<Ed25519PublicKey>,
+    subscriptions: Arc<SubscriptionManager>,
+    fanout: Arc<Fanout>,
+    compression: Arc<Vec<Compression>>,
+    payload_formats: Arc<Vec<PayloadFormat>>,
+    invite_only: bool,
+    invites: Arc<Vec<Invite>>,
+    federation_peers: Arc<HashMap<String, PublicKey>>,
+    communities: Arc<HashMap<String, HashSet<String>>>,
+    identity_addr: Option<SocketAddr>,
+    /// Where WebSocket upgrades are accepted, if anywhere
+    websocket_addr: Option<SocketAddr>,
+    advertised_urls: Arc<Vec<String>>,
+    contact: Option<String>,
+    #[cfg(feature = "dns-auth")]
+    resolver: ChallengeResolver,
+    /// The access policy, push targets and connection limits
+    live: Arc<RwLock<Live>>,
+    admission: Arc<Admission>,
+    resources: Arc<Resources>,
+    accounting: Arc<CostAccounting>,
+    transport: TransportSecurity,
+    /// Options of the listening sockets and the connections accepted on them
+    listener_socket_options: SocketOptions,
+    outbound_socket_options: SocketOptions,
+    connections: ConnectionRegistry,
+    errors: Arc<ErrorLog>,
+    events: broadcast::Sender<NodeEvent>,
 }
 
 impl OSProtocolNode {
     pub fn builder() -> OSProtocolNodeBuilder {
         OSProtocolNodeBuilder {
-            bind_addr: SocketAddr::new(IpAddr::from(Ipv4Addr::LOCALHOST), 57401),
+            bind_addr: None,
             hostname: "".to_string(),
             private_key: None,
+            ed25519_key: None,
+            store: Arc::new(MemoryStore::new()),
+            backup_schedule: None,
+            quotas: StorageQuotas::default(),
+            maintenance_interval: Duration::from_secs(60),
+            read_only: false,
+            relay: false,
+            dedup: DedupCache::default(),
+            software: diagnostics::SOFTWARE.to_string(),
+            push_window: None,
+            reassembly_budget: DEFAULT_REASSEMBLY_BUDGET,
+            keepalive: Keepalive::default(),
+            rollouts: HashMap::new(),
+            recordings: None,
+            recorded_peers: HashSet::new(),
+            self_checks: None,
+            handlers: Handlers::default(),
+            validators: Validators::default(),
+            transforms: Transforms::default(),
+            middleware: Vec::new(),
+            converters: Converters::default(),
+            schemas: SchemaRegistry::default(),
+            types: DataTypeRegistry::default(),
+            signed_types: HashMap::new(),
+            policies: Vec::new(),
+            reporters: Reporters::default(),
+            preferences: SensitivityFilter::default(),
+            #[cfg(feature = "geoip")]
+            geoip: None,
+            #[cfg(feature = "rss-bridge")]
+            rss_sources: Vec::new(),
+            #[cfg(feature = "nostr-bridge")]
+            nostr_bridge: None,
+            #[cfg(feature = "matrix-bridge")]
+            matrix_bridge: None,
+            access: AccessPolicy::default(),
+            trace_propagation: TracePropagation::default(),
+            retry_policy: RetryPolicy::default(),
+            honor_takedowns: false,
+            takedown_authorities: Vec::new(),
+            subscription_approval: SubscriptionApproval::Automatic,
+            subscription_lease: None,
+            compression: Compression::supported(),
+            payload_formats: PayloadFormat::ALL.to_vec(),
+            invite_only: false,
+            invites: Vec::new(),
+            allowlist_only: false,
+            federation_peers: HashMap::new(),
+            communities: HashMap::new(),
+            identity_addr: None,
+            websocket_addr: None,
+            advertised_urls: Vec::new(),
+            push_targets: Vec::new(),
+            contact: None,
+            #[cfg(feature = "dns-auth")]
+            resolver: None,
+            connection_limits: ConnectionLimits::default(),
+            admission_limits: AdmissionLimits::default(),
+            resource_limits: ResourceLimits::default(),
+            accounting_policy: AccountingPolicy::default(),
+            error_sampling: ErrorSampling::default(),
+            transport: TransportSecurity::default(),
+            listener_socket_options: SocketOptions::default(),
+            outbound_socket_options: SocketOptions::default(),
+            state: PhantomData,
         }
     }
 
-    pub async fn listen(&self) -> io::Result<()> {
-        let port = self.bind_addr.port();
-        let listener = TcpListener::bind(self.bind_addr).await?;
-        info!("Listening started on port {port}, ready to accept connections");
-        loop {
-            // The second item contains the IP and port of the new connection.
-            let (stream, _) = listener.accept().await?;
+    /// Operator facing queries and actions on this node.
+    #[cfg(feature = "admin-api")]
+    pub fn admin(&self) -> AdminApi {
+        AdminApi::new(self.clone())
+    }
 
-            info!(
-                "Accepting a new connection from {}",
-                stream
-                    .peer_addr()
-                    .map(|addr| addr.to_string())
-                    .unwrap_or("unknown address".to_string())
-            );
+    /// The inbound connections currently open.
+    pub fn connections(&self) -> &ConnectionRegistry {
+        &self.connections
+    }
 
-            self.start_connection(stream);
-        }
+    /// The hostname this node federates as.
+    pub fn hostname(&self) -> &str {
+        &self.hostname
     }
 
-    fn start_connection(&self, stream: TcpStream) {
-        tokio::spawn(async move {
-            let mut connection_handshake = InboundConnection::with_stream(stream).unwrap();
-            connection_handshake.begin().await?;
-            let mut connection_transfer = InboundConnection::<TransferState>::from(connection_handshake);
-        });
+    pub fn data_store(&self) -> Arc<dyn DataStore> {
+        self.store.clone()
     }
 
-    pub async fn create_outbound(&self, url: OSPUrl) -> io::Result<()> {
-        info!("Starting outbound connection to {url}");
-        let mut conn = OutboundConnection::create(url, self.private_key.clone(), self.hostname.clone()).await?;
-        let mut conn_in_handshake = conn.begin().await?;
-        conn_in_handshake.handshake().await
+    /// The storage quota configured for `type_id`, if any.
+    pub fn storage_quota(&self, type_id: Uuid) -> Option<StorageQuota> {
+        self.quotas.get(type_id).cloned()
+    }
+
+    /// The storage quotas configured on this node.
+    #[cfg(feature = "admin-api")]
+    pub(crate) fn storage_quotas(&self) -> &StorageQuotas {
+        &self.quotas
+    }
+
+    /// Whether this node refuses content pushed by peers.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// How many received objects were dropped as duplicates, see
+    /// [dedup](crate::dedup).
+    pub fn dedup_stats(&self) -> DedupStats {
+        self.dedup.stats()
+    }
+
+    pub(crate) fn dedup(&self) -> &DedupCache {
+        &self.dedup
+    }
+
+    /// The software this node tells peers it runs.
+    pub fn software(&self) -> &str {
+        &self.software
+    }
+
+    /// How many pushes each guest may have waiting for an ack, see
+    /// [push_window](OSProtocolNodeBuilder::push_window).
+    pub fn push_window(&self) -> Option<u32> {
+        self.push_window
+    }
+
+    /// When connections are pinged and closed, see
+    /// [keepalive](OSProtocolNodeBuilder::keepalive).
+    pub fn keepalive(&self) -> Keepalive {
+        self.keepalive
+    }
+
+    /// How handshakes fared with and without each feature that is being
+    /// [rolled out](crate::rollout).
+    pub fn rollout_stats(&self) -> Vec<RolloutStats> {
+        self.rollouts.stats()
+    }
+
+    /// Record connections with `peer`, a hostname or the address of a guest,
+    /// from their next packet on, see [recording](crate::recording).
+    /// Nothing is recorded unless the node was built with a
+    /// [directory](OSProtocolNodeBuilder::recordings) to record to.
+    pub fn record_peer(&self, peer: &str) {
+        self.recordings.add(peer);
+    }
+
+    /// Stop recording new connections with `peer`. Returns whether it was
+    /// recorded.
+    pub fn stop_recording_peer(&self, peer: &str) -> bool {
+        self.recordings.remove(peer)
+    }
+
+    /// The hostnames and addresses of the peers whose connections are
+    /// recorded.
+    pub fn recorded_peers(&self) -> Vec<String> {
+        self.recordings.peers()
+    }
+
+    /// Check the environment of the node, with the
+    /// [checks](OSProtocolNodeBuilder::self_checks) it was built with, or
+    /// the default ones, see [selfcheck](crate::selfcheck). The addresses
+    /// of a node that is listening are reported as taken.
+    pub async fn self_check(&self) -> SelfCheckReport {
+        let environment = Environment {
+            hostname: &self.hostname,
+            addrs: [self.bind_addr].into_iter().chain(self.identity_addr).chain(self.websocket_addr).collect(),
+            directories: self.backup_schedule.iter().map(|schedule| schedule.directory.clone())
+                .chain(self.recordings.directory().map(Path::to_path_buf))
+                .collect(),
+            max_connections: self.live_settings().max_connections,
+        };
+        match &self.self_checks {
+            Some(checks) => checks.run(environment).await,
+            None => SelfChecks::default().run(environment).await,
+        }
+    }
+
+    /// What this node learned about each peer in its latest handshake with
+    /// it, see [diagnostics](crate::connection::diagnostics).
+    pub fn peer_diagnostics(&self) -> Vec<PeerDiagnostics> {
+        self.diagnostics.peers()
+    }
+
+    pub(crate) fn handshake_diagnostics(&self) -> &HandshakeDiagnostics {
+        &self.diagnostics
+    }
+
+    /// Send `notice` to the guests of this node, see [notice](crate::notice).
+    /// Returns the id to [withdraw](Self::withdraw_notice) it with.
+    pub fn publish_notice(&self, notice: Notice) -> u64 {
+        info!("Publishing {} notice {}", notice.level.name(), notice.message);
+        self.notices.publish(notice)
+    }
+
+    /// Stop sending the notice with `id` to guests, returning whether it was
+    /// published.
+    pub fn withdraw_notice(&self, id: u64) -> bool {
+        self.notices.withdraw(id)
+    }
+
+    /// The notices published on this node.
+    pub fn published_notices(&self) -> Vec<PublishedNotice> {
+        self.notices.published(embargo::now())
+    }
+
+    /// The notices peers sent this node, oldest first.
+    pub fn received_notices(&self) -> Vec<ReceivedNotice> {
+        self.notices.received()
+    }
+
+    pub(crate) fn notices(&self) -> &Notices {
+        &self.notices
+    }
+
+    /// Take in `notice` sent by `peer`, telling the operator if it is new.
+    pub(crate) fn receive_notice(&self, peer: &str, notice: Notice) {
+        if self.notices.receive(peer, notice.clone(), embargo::now()) {
+            notice::log_notice(peer, &notice);
+            self.emit(NodeEvent::NoticeReceived { peer: peer.to_string(), notice });
+        }
+    }
+
+    /// Whether this node relays objects received from peers to its
+    /// subscribers, see [relay](OSProtocolNodeBuilder::relay).
+    pub fn is_relay(&self) -> bool {
+        self.relay
+    }
+
+    /// Whether `envelope` is a copy of an object this node relayed already,
+    /// come back to it along another path: one of the same origin, id and
+    /// creation time is stored. Always `false` if this node isn't a relay.
+    pub(crate) fn relayed_already(&self, envelope: &Envelope) -> io::Result<bool> {
+        if !self.relay {
+            return Ok(false);
+        }
+        Ok(self.store.get_object(envelope.object_id)?
+            .is_some_and(|stored| stored.origin == envelope.origin && stored.created_at == envelope.created_at))
+    }
+
+    /// Store an object, enforcing the storage quota for its type. Objects
+    /// that were taken down are refused with
+    /// [io::ErrorKind::PermissionDenied].
+    pub fn store_object(&self, envelope: &Envelope) -> io::Result<()> {
+        if self.store.tombstone(envelope.object_id)?.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Object {} was taken down", envelope.object_id)
+            ));
+        }
+        self.quotas.store(self.store.as_ref(), envelope)
+    }
+
+    /// Sign `data` as published by this node, with the RSA key of its `_osp`
+    /// record, see [provenance](crate::provenance).
+    pub fn sign<T: Data>(&self, data: T) -> io::Result<SignedData<T>> {
+        provenance::sign(data, &self.hostname, embargo::now(), Signer::Rsa(&self.private_key))
+    }
+
+    /// Check that `signed` was signed by its origin, with one of the
+    /// unexpired keys of its `_osp` record.
+    pub async fn verify_signed<T: Data>(&self, signed: &SignedData<T>) -> io::Result<()> {
+        self.verify_attestation(&signed.attestation()?).await
+    }
+
+    /// Check the signature of `envelope`, if it is a signed object of a type
+    /// the node checks signatures of.
+    pub(crate) async fn check_signature(&self, envelope: &Envelope) -> io::Result<()> {
+        let Some(attester) = self.signed_types.get(&envelope.type_id) else {
+            return Ok(());
+        };
+        let attestation = attester(envelope)?;
+        provenance::check_attribution(&attestation, &envelope.origin, embargo::now())?;
+        self.verify_attestation(&attestation).await
+    }
+
+    #[cfg(feature = "dns-auth")]
+    async fn verify_attestation(&self, attestation: &Attestation) -> io::Result<()> {
+        provenance::check_attribution(attestation, &attestation.origin, embargo::now())?;
+        let records = dns::lookup_challenge_records(&self.resolver, &attestation.origin).await?;
+        for key in records.iter().filter(|record| !record.is_expired()).filter_map(ChallengeRecord::challenge_key) {
+            if provenance::verify(attestation, &key)? {
+                return Ok(());
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("The signature matches no key in the challenge records of {}", attestation.origin),
+        ))
+    }
+
+    #[cfg(not(feature = "dns-auth"))]
+    async fn verify_attestation(&self, attestation: &Attestation) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unable to verify the signature of {}, this node does not look up challenge records", attestation.origin),
+        ))
+    }
+
+    /// Store an object of this node's own and queue it for the peers
+    /// subscribed to its type, see [subscription](crate::subscription).
+    pub fn publish(&self, envelope: &Envelope) -> io::Result<()> {
+        self.store_object(envelope)?;
+        self.syndicate(envelope, None)?;
+        self.send_to_nostr(envelope);
+        self.send_to_matrix(envelope);
+        Ok(())
+    }
+
+    /// Hold back an object of this node's own and [publish](Self::publish)
+    /// it at the unix timestamp `publish_at` (seconds), see
+    /// [embargo](crate::embargo). Objects that are already stored or
+    /// scheduled are refused with [io::ErrorKind::AlreadyExists].
+    pub fn schedule_publish(&self, envelope: Envelope, publish_at: u64) -> io::Result<()> {
+        let object_id = envelope.object_id;
+        if self.store.get_object(object_id)?.is_some() || self.store.scheduled(object_id)?.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Object {object_id} is already published or scheduled")
+            ));
+        }
+        self.store.put_scheduled(&ScheduledObject::new(envelope, publish_at))?;
+        self.emit(NodeEvent::PublishScheduled { object_id, publish_at });
+        Ok(())
+    }
+
+    /// Move the release of the scheduled object `object_id` to the unix
+    /// timestamp `publish_at` (seconds). Returns whether it was scheduled.
+    /// An object released or cancelled meanwhile isn't scheduled again.
+    pub fn reschedule_publish(&self, object_id: Uuid, publish_at: u64) -> io::Result<bool> {
+        if !self.store.reschedule(object_id, publish_at)? {
+            return Ok(false);
+        }
+        self.emit(NodeEvent::PublishRescheduled { object_id, publish_at });
+        Ok(true)
+    }
+
+    /// Drop the scheduled object `object_id` without publishing it. Returns
+    /// whether it was scheduled.
+    pub fn cancel_publish(&self, object_id: Uuid) -> io::Result<bool> {
+        let cancelled = self.store.remove_scheduled(object_id)?;
+        if cancelled {
+            self.emit(NodeEvent::PublishCancelled { object_id });
+        }
+        Ok(cancelled)
+    }
+
+    /// Queue `envelope` to be pushed to the peer at `url`, returning the id
+    /// of the delivery. It is pushed while the node listens and retried
+    /// until it goes through, see [delivery](crate::delivery).
+    pub fn deliver(&self, url: &OSPUrl, envelope: Envelope) -> io::Result<Uuid> {
+        self.deliver_with(url, envelope, DeliveryQos::default())
+    }
+
+    /// [Deliver](Self::deliver) `envelope` with the level of service `qos`.
+    pub fn deliver_with(&self, url: &OSPUrl, envelope: Envelope, qos: DeliveryQos) -> io::Result<Uuid> {
+        let mut delivery = PendingDelivery::new(url, envelope, qos);
+        if qos == DeliveryQos::Ordered {
+            delivery.next_attempt_at = delivery::ordered_attempt_at(self, url)?;
+        }
+        self.store.put_delivery(&delivery)?;
+        metrics::delivery_queue_depth(self.store.delivery_count()?);
+        self.deliveries_queued.notify_one();
+        self.supervisor.queued(url);
+        Ok(delivery.id)
+    }
+
+    /// Wake up the deliveries to the subscriber `peer`, which advertised its
+    /// receive window, so those that were paused go out right away.
+    pub(crate) fn window_advertised(&self, peer: &str) -> io::Result<()> {
+        self.deliveries_queued.notify_one();
+        if let Some(port) = self.store.subscription(peer)?.and_then(|subscription| subscription.port) {
+            self.supervisor.queued(&OSPUrl { domain: peer.to_string(), port, scheme: Scheme::Osp });
+        }
+        Ok(())
+    }
+
+    /// The state of the connection with each push target, see
+    /// [supervisor](crate::supervisor). Empty until the node
+    /// [listens](Self::listen).
+    pub fn push_target_states(&self) -> Vec<(OSPUrl, TargetState)> {
+        self.supervisor.states()
+    }
+
+    pub(crate) fn supervisor(&self) -> &Supervisor {
+        &self.supervisor
+    }
+
+    /// The subscriptions of peers to this node.
+    pub fn subscriptions(&self) -> &SubscriptionManager {
+        &self.subscriptions
+    }
+
+    /// The algorithms frames to and from peers may be compressed with, the
+    /// preferred first.
+    pub fn compression(&self) -> &[Compression] {
+        &self.compression
+    }
+
+    /// The formats peers may push objects in, the preferred first.
+    pub fn payload_formats(&self) -> &[PayloadFormat] {
+        &self.payload_formats
+    }
+
+    /// Queue a stored object for the subscribers of its type, except the
+    /// peer it was received from, if any, and its origin, with the level of
+    /// service each subscribed with. Objects this node publishes are also
+    /// queued for its [push targets](OSProtocolNodeBuilder::push_target).
+    /// Peers outside the [audience](osp_protocol::Audience) of the object
+    /// are skipped. Subscribers a [fan-out](crate::fanout) relay delivers to
+    /// are left to it, unless the audience is restricted, and objects
+    /// received from the parent of a delegation this node relays along are
+    /// queued for its children. Objects received from a peer are queued for
+    /// subscribers with one less [ttl](Envelope::ttl), and not at all once
+    /// it ran out.
+    /// Returns how many deliveries were queued.
+    pub(crate) fn syndicate(&self, envelope: &Envelope, peer: Option<&str>) -> io::Result<usize> {
+        let relayed = peer.and_then(|_| envelope.relayed());
+        let mut queued = 0;
+        for recipient in self.recipients(envelope, peer)? {
+            // held back, or delivered by a relay
+            if recipient.refusal.is_some() || matches!(recipient.route, Route::Delegated { .. }) {
+                continue;
+            }
+            // fan-out children are still the origin's own delivery, not a hop
+            let envelope = match (&recipient.route, &relayed) {
+                (Route::Subscription, Some(relayed)) => relayed.clone(),
+                _ => envelope.clone(),
+            };
+            self.deliver_with(&recipient.url, envelope, recipient.qos)?;
+            queued += 1;
+        }
+        Ok(queued)
+    }
+
+    /// The peers [syndicating](Self::syndicate) `envelope`, received from
+    /// `peer` if it was, would queue it for, each listed once. The
+    /// subscribers relays deliver to are listed too, as are those it is held
+    /// back from for its origin or audience, with the reason.
+    fn recipients(&self, envelope: &Envelope, peer: Option<&str>) -> io::Result<Vec<Recipient>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        // relays may be outside a restricted audience
+        let tree = match envelope.origin == self.hostname && envelope.audience.is_public() {
+            true => self.fanout.tree(envelope.type_id, now)?,
+            false => None,
+        };
+        let mut targets = Vec::<(OSPUrl, DeliveryQos, Route)>::new();
+        let mut delegated = Vec::<(OSPUrl, DeliveryQos, Route)>::new();
+        let to_subscribers = peer.is_none() || envelope.ttl > 0;
+        let subscribers = match to_subscribers {
+            true => self.subscriptions.deliveries(envelope.type_id, envelope.topic.as_deref())?,
+            false => Vec::new(),
+        };
+        for (url, qos) in subscribers {
+            let relay = tree.as_ref()
+                .and_then(|tree| tree.delegations.iter().find(|delegation| delegation.delegates_to(&url.domain)));
+            match relay {
+                Some(delegation) => delegated.push((url, qos, Route::Delegated { relay: delegation.relay.clone() })),
+                None => targets.push((url, qos, Route::Subscription)),
+            }
+        }
+        if let Some(tree) = &tree {
+            targets.extend(tree.top().map(|(url, _)| (url.clone(), DeliveryQos::AtLeastOnce, Route::Relay)));
+        }
+        match peer {
+            None => targets.extend(self.live.read().unwrap().push_targets.iter().map(|url| (url.clone(), DeliveryQos::default(), Route::PushTarget))),
+            Some(peer) => targets.extend(self.fanout.children(envelope, peer, now)?.into_iter().map(|child| (child.url, child.qos, Route::Child))),
+        }
+
+        let mut recipients = Vec::<Recipient>::new();
+        for (url, qos, route) in targets.into_iter().chain(delegated) {
+            if peer == Some(url.domain.as_str()) || recipients.iter().any(|recipient| recipient.url.domain == url.domain) {
+                continue;
+            }
+            let refusal = if url.domain == envelope.origin {
+                Some(Refusal::Origin)
+            } else if !self.in_audience(envelope, &url.domain) {
+                Some(Refusal::OutsideAudience)
+            } else {
+                None
+            };
+            recipients.push(Recipient { url, route, qos, refusal });
+        }
+        Ok(recipients)
+    }
+
+    /// Work out who `envelope` would be syndicated to if this node
+    /// [published](Self::publish) it, and why any peer wouldn't receive it,
+    /// without storing or sending anything, see [preview](crate::preview).
+    pub fn preview_broadcast(&self, envelope: &Envelope) -> io::Result<BroadcastPreview> {
+        let mut recipients = self.recipients(envelope, None)?;
+        let (converted, refusal) = if self.store.tombstone(envelope.object_id)?.is_some() {
+            (None, Some(Refusal::TakenDown))
+        } else {
+            match self.convert(Stage::Egress, envelope.clone()) {
+                Ok(converted) => {
+                    let refusal = self.check_policies(&converted).err().map(Refusal::Policy);
+                    (Some(converted), refusal)
+                }
+                Err(e) => (None, Some(Refusal::Conversion(e.to_string()))),
+            }
+        };
+        for recipient in recipients.iter_mut().filter(|recipient| recipient.refusal.is_none()) {
+            recipient.refusal = refusal.clone();
+            // relays check the peers they deliver to themselves
+            if recipient.refusal.is_none() && !matches!(recipient.route, Route::Delegated { .. }) {
+                recipient.refusal = self.check_federation(&recipient.url.domain).err()
+                    .map(|e| Refusal::Denied(e.to_string()));
+            }
+            // peers on an earlier version of the type get it translated
+            if let (None, Some(converted)) = (&recipient.refusal, &converted) {
+                recipient.refusal = self.translate_for(&recipient.url.domain, converted.clone()).err()
+                    .map(|e| Refusal::Conversion(e.to_string()));
+            }
+        }
+        Ok(BroadcastPreview {
+            object_id: envelope.object_id,
+            recipients,
+        })
+    }
+
+    /// Delegate delivering this node's objects of `type_id` to `relays`,
+    /// each delivering to `fanout` peers at most, until `expires_at` if
+    /// given, see [fanout](crate::fanout). The delegations of a tree built
+    /// for the type before are revoked first. Fails if a relay can't be
+    /// reached, revoking the delegations already sent.
+    pub async fn delegate_fanout(&self, type_id: Uuid, relays: &[OSPUrl], fanout: usize, expires_at: Option<u64>) -> io::Result<FanoutTree> {
+        let subscribers = self.subscriptions.deliveries(type_id, None)?;
+        let mut tree = FanoutTree::build(&self.hostname, type_id, &subscribers, relays, fanout, expires_at)?;
+        for delegation in &mut tree.delegations {
+            fanout::sign(delegation, &self.private_key)?;
+        }
+        if let Err(e) = self.revoke_fanout(type_id).await {
+            warn!("Unable to revoke the previous fan-out of {type_id}: {e}");
+        }
+
+        for (i, (relay, delegation)) in tree.relays.iter().zip(&tree.delegations).enumerate() {
+            let sent = async {
+                let mut conn = self.create_outbound(relay.clone()).await?;
+                conn.delegate(delegation).await
+            };
+            if let Err(e) = sent.await {
+                for (relay, delegation) in tree.relays.iter().zip(&tree.delegations).take(i) {
+                    if let Err(e) = self.send_revocation(relay, delegation.delegation_id).await {
+                        warn!("Unable to revoke delegation {} to {relay}: {e}", delegation.delegation_id);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        info!("Delegated delivering {type_id} objects to {} relays", tree.relays.len());
+        self.fanout.insert_tree(tree.clone())?;
+        Ok(tree)
+    }
+
+    /// Stop delegating delivering this node's objects of `type_id`, revoking
+    /// the delegations of its [FanoutTree]. The node delivers to every
+    /// subscriber itself again right away, even if a relay can't be reached,
+    /// which fails with the first error once every relay was tried. Returns
+    /// whether there was a tree.
+    pub async fn revoke_fanout(&self, type_id: Uuid) -> io::Result<bool> {
+        let Some(tree) = self.fanout.remove_tree(type_id)? else {
+            return Ok(false);
+        };
+        let mut result = Ok(true);
+        for (relay, delegation) in tree.relays.iter().zip(&tree.delegations) {
+            if let Err(e) = self.send_revocation(relay, delegation.delegation_id).await {
+                warn!("Unable to revoke delegation {} to {relay}: {e}", delegation.delegation_id);
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    async fn send_revocation(&self, relay: &OSPUrl, delegation_id: Uuid) -> io::Result<()> {
+        self.create_outbound(relay.clone()).await?.revoke_delegation(delegation_id).await
+    }
+
+    /// Whether the [audience](osp_protocol::Audience) of `envelope` includes `hostname`.
+    pub(crate) fn in_audience(&self, envelope: &Envelope, hostname: &str) -> bool {
+        envelope.audience.includes(hostname, |community| {
+            self.communities.get(community).is_some_and(|members| members.contains(&hostname.to_ascii_lowercase()))
+        })
+    }
+
+    /// Relay the objects of `peer` along `delegation`, if `peer` is its
+    /// origin and signed it, see [fanout](crate::fanout).
+    pub(crate) fn accept_delegation(&self, peer: &str, delegation: Delegation) -> io::Result<()> {
+        let denied = |reason: String| io::Error::new(io::ErrorKind::PermissionDenied, reason);
+        if !delegation.origin.eq_ignore_ascii_case(peer) {
+            return Err(denied(format!("{peer} can't delegate the objects of {}", delegation.origin)));
+        }
+        if !delegation.relay.eq_ignore_ascii_case(&self.hostname) {
+            return Err(denied(format!("The delegation is for {}", delegation.relay)));
+        }
+        let Some(key) = self.federation_peers.get(&peer.to_ascii_lowercase()) else {
+            return Err(denied(format!("{peer} is not a federation peer")));
+        };
+        if !fanout::verify(&delegation, key)? {
+            return Err(denied("Invalid signature".to_string()));
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if delegation.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(denied("The delegation has expired".to_string()));
+        }
+        if delegation.parent.eq_ignore_ascii_case(&self.hostname) || [&self.hostname, &delegation.origin, &delegation.parent].into_iter().any(|host| delegation.delegates_to(host)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The delegation would loop"));
+        }
+
+        info!("Relaying {} objects of {peer} to {} peers", delegation.type_id, delegation.children.len());
+        self.fanout.accept(delegation)
+    }
+
+    /// Stop relaying along the delegation with id `delegation_id`, if
+    /// `peer` issued it. Returns whether it did.
+    pub(crate) fn revoke_delegation(&self, peer: &str, delegation_id: Uuid) -> io::Result<bool> {
+        self.fanout.revoke(peer, delegation_id)
+    }
+
+    /// The settings the node currently runs with that can be
+    /// [reloaded](Self::reload).
+    pub fn live_settings(&self) -> LiveSettings {
+        self.live.read().unwrap().settings()
+    }
+
+    /// Switch to `settings` without dropping the open connections, see
+    /// [reload](crate::reload). Settings that would refuse every connection
+    /// are rejected with [io::ErrorKind::InvalidInput], keeping the current
+    /// ones.
+    pub fn reload(&self, settings: LiveSettings) -> io::Result<()> {
+        let problems = settings.problems();
+        if !problems.is_empty() {
+            let problems: Vec<_> = problems.iter().map(ToString::to_string).collect();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unable to reload settings: {}", problems.join("; "))
+            ));
+        }
+        self.live.write().unwrap().apply(settings);
+        info!("Reloaded settings");
+        self.emit(NodeEvent::ConfigReloaded);
+        Ok(())
+    }
+
+    /// Subscribe to changes to the node's content. Only events emitted
+    /// after subscribing are received.
+    pub fn events(&self) -> broadcast::Receiver<NodeEvent> {
+        self.events.subscribe()
+    }
+
+    pub(crate) fn emit(&self, event: NodeEvent) {
+        // sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    fn audit(&self, action: AuditAction, object_id: Option<Uuid>, actor: &str, detail: String) -> io::Result<()> {
+        self.store.append_audit(&AuditEntry {
+            recorded_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            action,
+            object_id,
+            actor: actor.to_string(),
+            detail,
+            type_id: None,
+        })
+    }
+
+    /// Whether a takedown sent by `peer` may be applied: the object must
+    /// originate from `peer`, or the tombstone must be signed by one of the
+    /// node's takedown authorities.
+    pub(crate) fn accepts_takedown(&self, tombstone: &Tombstone, peer: &str) -> io::Result<bool> {
+        if let Some(signature) = &tombstone.signature {
+            let signed = tombstone.signed_bytes();
+            for authority in &self.takedown_authorities {
+                if authority.verify(&signed, signature)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(self.store.get_object(tombstone.object_id)?
+            .is_some_and(|envelope| envelope.origin.eq_ignore_ascii_case(peer)))
+    }
+
+    /// Sign `tombstone` with the node's Ed25519 key, if it has one, so
+    /// peers that trust the node as a takedown authority apply it.
+    #[cfg(feature = "admin-api")]
+    pub(crate) fn sign_tombstone(&self, tombstone: &mut Tombstone) -> io::Result<()> {
+        if let Some(key) = &self.ed25519_key {
+            tombstone.signature = Some(key.sign(&tombstone.signed_bytes())?);
+        }
+        Ok(())
+    }
+
+    /// Remove the object named by `tombstone` and keep the tombstone in its
+    /// place, recording `actor` as having requested it in the audit log.
+    /// Returns whether a stored object was removed.
+    pub(crate) fn apply_takedown(&self, tombstone: &Tombstone, actor: &str) -> io::Result<bool> {
+        let removed = self.store.remove_object(tombstone.object_id)?;
+        self.store.put_tombstone(tombstone)?;
+        self.audit(
+            AuditAction::Takedown,
+            Some(tombstone.object_id),
+            actor,
+            format!("{} (authority: {})", tombstone.reason, tombstone.authority),
+        )?;
+        if removed {
+            self.emit(NodeEvent::ObjectDeleted { object_id: tombstone.object_id, cause: AuditAction::Takedown });
+        }
+        info!("Object {} taken down at the request of {actor}: {}", tombstone.object_id, tombstone.reason);
+        Ok(removed)
+    }
+
+    /// Delete an object at the request of `peer`. Only the object's origin
+    /// may delete it. Returns whether the object was deleted.
+    pub(crate) fn delete_object(&self, object_id: Uuid, peer: &str) -> io::Result<bool> {
+        let Some(envelope) = self.store.get_object(object_id)? else { return Ok(false) };
+        if envelope.origin != peer {
+            debug!("Ignoring deletion of object {object_id} by {peer}, which is not its origin");
+            return Ok(false);
+        }
+
+        let removed = self.store.remove_object(object_id)?;
+        if removed {
+            self.audit(AuditAction::Delete, Some(object_id), peer, "Deleted by its origin".to_string())?;
+            self.emit(NodeEvent::ObjectDeleted { object_id, cause: AuditAction::Delete });
+        }
+        Ok(removed)
+    }
+
+    /// Handle a subscription request from `peer` for `data_types`, see
+    /// [SubscriptionManager].
+    pub(crate) fn request_subscription(
+        &self,
+        peer: &str,
+        data_types: Vec<Uuid>,
+        topics: Vec<TopicFilter>,
+        qos: DeliveryQos,
+        port: Option<u16>,
+        type_versions: Vec<(Uuid, u16)>,
+    ) -> io::Result<SubscriptionState> {
+        let (state, previous) = self.subscriptions.request(peer, data_types, topics, qos, port, type_versions)?;
+        if state == SubscriptionState::Pending && previous != Some(SubscriptionState::Pending) {
+            info!("Subscription from {peer} is awaiting approval");
+            self.emit(NodeEvent::SubscriptionRequested { peer: peer.to_string() });
+        }
+        Ok(state)
+    }
+
+    /// Approve or deny the subscription of `peer` on behalf of the operator.
+    #[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+    pub(crate) fn decide_subscription(&self, peer: &str, approve: bool) -> io::Result<()> {
+        let state = self.subscriptions.decide(peer, approve)?;
+        let action = match approve {
+            true => AuditAction::ApproveSubscription,
+            false => AuditAction::DenySubscription,
+        };
+        self.audit(action, None, "operator", format!("Subscription of {peer}"))?;
+        info!("Subscription of {peer} is now {state:?}");
+        Ok(())
+    }
+
+    /// Port the node listens on.
+    pub(crate) fn port(&self) -> u16 {
+        self.bind_addr.port()
+    }
+
+    /// Whether only invited peers are accepted.
+    pub(crate) fn is_invite_only(&self) -> bool {
+        self.invite_only
+    }
+
+    /// Issue an invite to `invitee`, whose public key is `public_key`
+    /// (PEM encoded), valid until `expires_at` if given.
+    #[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+    pub(crate) fn issue_invite(&self, invitee: &str, public_key: &str, expires_at: Option<u64>) -> io::Result<Invite> {
+        PublicKey::from_pem(public_key.as_bytes())?;
+        let mut invite = Invite {
+            token_id: Uuid::new_v4(),
+            issuer: self.hostname.clone(),
+            invitee: invitee.to_string(),
+            public_key: public_key.to_string(),
+            expires_at,
+            signature: Vec::new(),
+        };
+        invite::sign(&mut invite, &self.private_key)?;
+        self.store.put_invite(&InviteRecord {
+            invite: invite.clone(),
+            issued_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            last_used_at: None,
+            revoked: false,
+        })?;
+        self.audit(AuditAction::IssueInvite, None, "operator", format!("Invite {} for {invitee}", invite.token_id))?;
+        Ok(invite)
+    }
+
+    /// Check an invite presented by `hostname` against this node's records,
+    /// returning the public key it vouches for.
+    pub(crate) fn verify_invite(&self, hostname: &str, invite: &Invite) -> io::Result<PublicKey> {
+        let denied = |reason: &str| io::Error::new(io::ErrorKind::PermissionDenied, reason.to_string());
+        let Some(mut record) = self.store.invite(invite.token_id)? else {
+            return Err(denied("Unknown invite"));
+        };
+        if record.invite != *invite || !invite::verify(invite, &self.private_key)? {
+            return Err(denied("Invite does not match the issued one"));
+        }
+        if record.revoked {
+            return Err(denied("Invite was revoked"));
+        }
+        if invite.invitee != hostname {
+            return Err(denied("Invite was issued to another host"));
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if invite.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(denied("Invite has expired"));
+        }
+
+        record.last_used_at = Some(now);
+        self.store.put_invite(&record)?;
+        PublicKey::from_pem(invite.public_key.as_bytes())
+    }
+
+    /// Revoke the invite with id `token_id`.
+    #[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+    pub(crate) fn revoke_invite(&self, token_id: Uuid) -> io::Result<()> {
+        let Some(mut record) = self.store.invite(token_id)? else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No invite {token_id}")));
+        };
+        record.revoked = true;
+        self.store.put_invite(&record)?;
+        self.audit(AuditAction::RevokeInvite, None, "operator", format!("Invite {token_id} for {}", record.invite.invitee))
+    }
+
+    /// The resolver challenge records of peers are looked up with.
+    #[cfg(feature = "dns-auth")]
+    pub(crate) fn resolver(&self) -> &ChallengeResolver {
+        &self.resolver
+    }
+
+    /// The public half of the node's key.
+    #[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+    pub(crate) fn ed25519_public_key(&self) -> io::Result<Option<Ed25519PublicKey>> {
+        self.ed25519_key.as_ref().map(Ed25519Key::public_key).transpose()
+    }
+
+    pub(crate) fn public_key(&self) -> io::Result<PublicKey> {
+        self.private_key.public_key()
+    }
+
+    /// The scheme of the node's WebSocket endpoint, `wss` if the node uses
+    /// TLS.
+    fn websocket_scheme(&self) -> Scheme {
+        match self.transport {
+            TransportSecurity::Plaintext => Scheme::Ws,
+            #[cfg(feature = "tls")]
+            TransportSecurity::Tls(_) => Scheme::Wss,
+        }
+    }
+
+    /// The node's identity document, signed with its private key.
+    pub fn identity_document(&self) -> io::Result<SignedIdentityDocument> {
+        let urls = match self.advertised_urls.is_empty() {
+            true => [(Scheme::Osp, self.bind_addr)].into_iter()
+                .chain(self.websocket_addr.map(|addr| (self.websocket_scheme(), addr)))
+                .map(|(scheme, addr)| format!("{}://{}:{}", scheme.as_str(), self.hostname, addr.port()))
+                .collect(),
+            false => self.advertised_urls.to_vec(),
+        };
+        let document = IdentityDocument {
+            hostname: self.hostname.clone(),
+            urls,
+            envelope_versions: (1..=ENVELOPE_VERSION).collect(),
+            public_keys: vec![self.private_key.public_key()?.to_pem()?],
+            data_types: self.schemas.all_local().into_iter()
+                .map(|descriptor| DataTypeSummary { type_id: descriptor.type_id, name: descriptor.name })
+                .collect(),
+            contact: self.contact.clone(),
+            policies: IdentityPolicies {
+                read_only: self.read_only,
+                invite_only: self.invite_only,
+                allowlist_only: self.live.read().unwrap().allowlist_only,
+                honors_takedowns: self.honor_takedowns,
+                manual_subscription_approval: self.subscriptions.approval() == SubscriptionApproval::Manual,
+            },
+            issued_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        };
+        SignedIdentityDocument::sign(document, &self.private_key)
+    }
+
+    /// Whether trace contexts are exchanged with `peer` under the node's
+    /// [TracePropagation].
+    pub(crate) fn propagates_traces(&self, peer: &str) -> bool {
+        match self.trace_propagation {
+            TracePropagation::Propagate => true,
+            TracePropagation::ClusterOnly => self.federation_peers.contains_key(&peer.to_ascii_lowercase()),
+            TracePropagation::Strip => false,
+        }
+    }
+
+    /// Refuse `host` with [io::ErrorKind::PermissionDenied] if the access
+    /// policy or the federation rules don't let it federate with this node.
+    pub(crate) fn check_federation(&self, host: &str) -> io::Result<()> {
+        let denied = |reason: String| io::Error::new(io::ErrorKind::PermissionDenied, reason);
+        let allowlist_only = {
+            let live = self.live.read().unwrap();
+            live.access.check_host(host).map_err(denied)?;
+            live.allowlist_only
+        };
+        match self.store.federation_rule(&host.to_ascii_lowercase())?.map(|rule| rule.action) {
+            Some(FederationAction::Allow) => Ok(()),
+            Some(FederationAction::Deny) => Err(denied(format!("{host} is denied federation"))),
+            None if allowlist_only => Err(denied(format!("{host} is not on the allow list"))),
+            None => Ok(()),
+        }
+    }
+
+    /// Allow or deny `host` on behalf of the operator.
+    #[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+    pub(crate) fn set_federation_rule(&self, host: &str, action: FederationAction) -> io::Result<FederationRule> {
+        let rule = FederationRule {
+            host: host.to_ascii_lowercase(),
+            action,
+            updated_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        };
+        self.store.put_federation_rule(&rule)?;
+        self.audit(AuditAction::UpdateFederation, None, "operator", format!("{action:?} {}", rule.host))?;
+        Ok(rule)
+    }
+
+    /// An update carrying every federation rule this node knows of, signed
+    /// with its private key.
+    #[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+    pub(crate) fn federation_update(&self) -> io::Result<FederationUpdate> {
+        let mut update = FederationUpdate {
+            signer: self.hostname.clone(),
+            rules: self.store.federation_rules()?,
+            signature: Vec::new(),
+        };
+        federation::sign(&mut update, &self.private_key)?;
+        Ok(update)
+    }
+
+    /// Merge the federation update held by `envelope` into this node's rules.
+    /// Only updates signed by a cluster member are accepted. Returns the
+    /// number of rules that changed.
+    pub(crate) fn merge_federation_update(&self, envelope: &Envelope) -> io::Result<usize> {
+        let update = FederationUpdate::from_envelope(envelope)?;
+        let Some(key) = self.federation_peers.get(&update.signer.to_ascii_lowercase()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is not a member of this node's cluster", update.signer)
+            ));
+        };
+        if !federation::verify(&update, key)? {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Invalid signature"));
+        }
+
+        let mut merged = 0;
+        for mut rule in update.rules {
+            rule.host.make_ascii_lowercase();
+            let existing = self.store.federation_rule(&rule.host)?;
+            if existing.is_none_or(|existing| rule.supersedes(&existing)) {
+                self.store.put_federation_rule(&rule)?;
+                merged += 1;
+            }
+        }
+        if merged > 0 {
+            self.audit(AuditAction::UpdateFederation, None, &update.signer, format!("Merged {merged} federation rules"))?;
+            info!("Merged {merged} federation rules from {}", update.signer);
+        }
+        Ok(merged)
+    }
+
+    /// Delete every stored object created by `actor`, including payloads
+    /// moved to cold storage, e.g. to honour a data erasure request. An
+    /// [NodeEvent::ObjectDeleted] is emitted for each deleted object, and the
+    /// purge is recorded in the audit log.
+    pub fn purge_actor(&self, actor: &str) -> io::Result<PurgeReport> {
+        const BATCH_SIZE: usize = 256;
+
+        let mut report = PurgeReport { actor: actor.to_string(), ..Default::default() };
+        loop {
+            let batch = self.store.objects_by_actor(actor, BATCH_SIZE)?;
+            if batch.is_empty() {
+                break;
+            }
+            let purged = report.objects.len();
+            for object_id in batch {
+                let cold = self.store.cold_key(object_id)?.is_some();
+                if self.store.remove_object(object_id)? {
+                    report.objects.push(object_id);
+                    report.cold_objects += cold as usize;
+                    self.emit(NodeEvent::ObjectDeleted { object_id, cause: AuditAction::Purge });
+                }
+            }
+            // A store that keeps listing objects it can't remove would
+            // otherwise have us loop forever.
+            if report.objects.len() == purged {
+                warn!("Stopped purging actor {actor}: none of a batch of their objects could be removed");
+                break;
+            }
+        }
+
+        self.audit(AuditAction::Purge, None, "operator", format!("Purged {} objects of actor {actor}", report.objects.len()))?;
+        report.purged_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        info!("Purged {} objects of actor {actor}", report.objects.len());
+        Ok(report)
+    }
+
+    /// Hand `fault` to the registered [ErrorReporter]s.
+    pub(crate) fn report_fault(&self, fault: Fault) {
+        self.reporters.report(fault);
+    }
+
+    /// Where errors about peers are logged, see [logging](crate::logging).
+    pub(crate) fn errors(&self) -> &ErrorLog {
+        &self.errors
+    }
+
+    pub(crate) fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub(crate) fn admission(&self) -> &Admission {
+        &self.admission
+    }
+
+    pub(crate) fn reassembly_budget(&self) -> &ReassemblyBudget {
+        &self.reassembly_budget
+    }
+
+    /// What serving each peer costs the node.
+    pub fn accounting(&self) -> &CostAccounting {
+        &self.accounting
+    }
+
+    pub(crate) fn handshake_timeout(&self) -> Duration {
+        self.live.read().unwrap().connection_limits.handshake_timeout
+    }
+
+    /// Whether takedowns sent by peers are applied.
+    pub(crate) fn honors_takedowns(&self) -> bool {
+        self.honor_takedowns
+    }
+
+    /// Check an object against the node's content policies.
+    pub(crate) fn check_policies(&self, envelope: &Envelope) -> Result<(), String> {
+        self.policies.iter().try_for_each(|policy| policy.check(envelope))
+    }
+
+    /// Pass an object received from `peer` through the content policies, the
+    /// sensitivity preferences and then the middleware chain. The object is
+    /// [tagged](Verdict::Tag) with the topic the last middleware to tag it
+    /// gave.
+    pub(crate) fn route(&self, envelope: &Envelope, peer: &str) -> io::Result<Verdict> {
+        if let Err(reason) = self.check_policies(envelope) {
+            return Ok(Verdict::Drop(reason));
+        }
+        if let Some(reason) = self.preferences.excludes(&envelope.sensitivity) {
+            return Ok(Verdict::Drop(reason));
+        }
+        let mut tagged = None;
+        for middleware in self.middleware.iter() {
+            let incoming = match &tagged {
+                Some(tagged) => Incoming { envelope: tagged, peer },
+                None => Incoming { envelope, peer },
+            };
+            match middleware.route(incoming)? {
+                Verdict::Accept => {}
+                Verdict::Drop(reason) => return Ok(Verdict::Drop(reason)),
+                Verdict::Tag(topic) => tagged = Some(Envelope { topic: Some(topic), ..envelope.clone() }),
+            }
+        }
+        Ok(match tagged.and_then(|envelope| envelope.topic) {
+            Some(topic) => Verdict::Tag(topic),
+            None => Verdict::Accept,
+        })
+    }
+
+    /// Run the registered validators for a received object.
+    pub(crate) fn validate(&self, envelope: &Envelope) -> io::Result<()> {
+        self.validators.validate(envelope)
+    }
+
+    /// Bring a received object, once it was validated, into the shape it is
+    /// stored in: upgraded to the latest version of its type, transformed
+    /// and converted for [Stage::Ingest].
+    pub(crate) fn prepare(&self, envelope: Envelope) -> io::Result<Envelope> {
+        self.transform(self.upgrade(envelope)).and_then(|envelope| self.convert(Stage::Ingest, envelope))
+    }
+
+    /// Store an object that wasn't pushed by a peer, such as one imported
+    /// from an archive, as if it was: it is validated, [prepared](Self::prepare)
+    /// and stored within the quotas. Objects the node won't take are refused
+    /// with [InvalidData](io::ErrorKind::InvalidData).
+    #[cfg(feature = "admin-api")]
+    pub(crate) fn ingest(&self, envelope: Envelope) -> io::Result<()> {
+        let envelope = self.validate(&envelope)
+            .and_then(|()| self.prepare(envelope))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.store_object(&envelope)
+    }
+
+    /// Run the registered transforms for a received object.
+    pub(crate) fn transform(&self, envelope: Envelope) -> io::Result<Envelope> {
+        self.transforms.apply(envelope)
+    }
+
+    /// Run the converter registered for the type of `envelope` at `stage`.
+    pub(crate) fn convert(&self, stage: Stage, envelope: Envelope) -> io::Result<Envelope> {
+        self.converters.apply(stage, envelope)
+    }
+
+    /// Translate a received object to the latest registered version of its
+    /// type. Objects of types or versions the node can't translate are kept
+    /// as they are.
+    pub(crate) fn upgrade(&self, envelope: Envelope) -> Envelope {
+        let Some(latest) = self.types.get(envelope.type_id) else {
+            return envelope;
+        };
+        match self.types.translate(&envelope, latest.version) {
+            Ok(upgraded) => {
+                if upgraded.type_version != envelope.type_version {
+                    debug!("Upgraded object {} from version {} to {}", envelope.object_id, envelope.type_version, upgraded.type_version);
+                }
+                upgraded
+            }
+            Err(e) => {
+                debug!("Keeping object {} as it is: {e}", envelope.object_id);
+                envelope
+            }
+        }
+    }
+
+    /// Translate `envelope` for `peer`, to the latest version of its type
+    /// the peer said it understands when it subscribed.
+    pub(crate) fn translate_for(&self, peer: &str, envelope: Envelope) -> io::Result<Envelope> {
+        if !self.types.contains(envelope.type_id) {
+            return Ok(envelope);
+        }
+        match self.subscriptions.type_version(peer, envelope.type_id)? {
+            Some(version) => Ok(self.types.translate_for(&envelope, version)?),
+            None => Ok(envelope),
+        }
+    }
+
+    /// Re-encode `envelope` in `format`, for a peer that picked it. Objects
+    /// the node can't transcode are sent as they are, their envelope names
+    /// their format.
+    pub(crate) fn transcode(&self, envelope: Envelope, format: PayloadFormat) -> Envelope {
+        match self.types.transcode(&envelope, format) {
+            Ok(transcoded) => transcoded,
+            Err(e) => {
+                debug!("Sending object {} as {:?}: {e}", envelope.object_id, envelope.format);
+                envelope
+            }
+        }
+    }
+
+    /// The latest registered version of each of `data_types`.
+    pub(crate) fn type_versions(&self, data_types: &[Uuid]) -> Vec<(Uuid, u16)> {
+        data_types.iter()
+            .filter_map(|type_id| self.types.get(*type_id).map(|data_type| (*type_id, data_type.version)))
+            .collect()
+    }
+
+    pub(crate) fn schemas(&self) -> &SchemaRegistry {
+        &self.schemas
+    }
+
+    /// The handler taking in streams, if the node has one.
+    pub(crate) fn stream_handler(&self) -> Option<&ErasedStreamHandler> {
+        self.handlers.stream_handler()
+    }
+
+    /// Run the registered handlers for an object received from `peer`,
+    /// returning how they took it in, or the error of the first that failed.
+    pub(crate) async fn dispatch(&self, envelope: &Envelope, peer: &str) -> Result<HandlerStatus, HandlerError> {
+        if envelope.type_id == FederationUpdate::TYPE_ID {
+            if let Err(e) = self.merge_federation_update(envelope) {
+                warn!("Ignoring federation update {}: {e}", envelope.object_id);
+            }
+        }
+        let (status, errors) = self.handlers.dispatch(envelope, None, Some(peer)).await;
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(status),
+        }
+    }
+
+    /// Run stored objects matching `filter` through the registered handlers
+    /// again, in the order they were stored. Useful after fixing a handler,
+    /// or to feed historical objects to a newly added one.
+    pub async fn replay(&self, filter: ReplayFilter) -> io::Result<ReplayReport> {
+        const BATCH_SIZE: usize = 256;
+
+        let mut report = ReplayReport::default();
+        let mut offset = 0;
+        loop {
+            let batch = self.store.list_objects(offset, BATCH_SIZE)?;
+            for object_id in &batch {
+                let Some(envelope) = self.store.get_object(*object_id)? else { continue };
+                if filter.matches(&envelope) {
+                    report.objects += 1;
+                    report.failures += self.handlers.dispatch(&envelope, filter.handler.as_deref(), None).await.1.len();
+                }
+            }
+            offset += batch.len();
+            if batch.len() < BATCH_SIZE {
+                break;
+            }
+        }
+
+        info!("Replayed {} objects, {} handler failures", report.objects, report.failures);
+        Ok(report)
+    }
+
+    /// Feed the packets a guest sent in the transfer phase of a
+    /// [recorded](crate::recording) connection through the decoding and
+    /// dispatch of this node, as if `peer` sent them on a connection of its
+    /// own. They are handled like those of a live connection, pushed objects
+    /// are stored and so on, so recordings are best replayed on a node built
+    /// for it, with the handlers of the node that made the recording and a
+    /// data store of its own.
+    ///
+    /// Fails with [InvalidInput](io::ErrorKind::InvalidInput) if the
+    /// recording has no such packets. What the connection failed with, such
+    /// as a packet that doesn't decode, is reported rather than returned.
+    pub async fn replay_recording(&self, recording: &Recording, peer: &str) -> io::Result<RecordingReplay> {
+        let frames = recording.frames.iter()
+            .filter(|frame| frame.direction == FrameDirection::Read && frame.packet == "TransferPacketGuestToHost")
+            .collect::<Vec<_>>();
+        let Some(version) = frames.first().map(|frame| frame.version()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The recording has no packets a guest sent in the transfer phase"));
+        };
+        let (host, guest) = io::duplex(64 * 1024);
+        let mut protocol = Protocol::with_transport(host)?;
+        protocol.set_version(version);
+        let mut conn = InboundConnection::replaying(protocol, self.data_store(), peer)?;
+        let mut guest = Protocol::<TransferPacketHostToGuest, TransferPacketGuestToHost>::with_transport(guest)?;
+        guest.set_version(version);
+        let Protocol { read: mut answers, write } = guest;
+        let mut feed = write.into_inner();
+
+        let serve = async move {
+            let result = conn.serve(self).await;
+            // closing the connection ends the answers
+            drop(conn);
+            result
+        };
+        let write = async {
+            for frame in &frames {
+                // framed as the decoder reads them, uncompressed
+                feed.write_all(&(frame.data.len() as u32).to_le_bytes()).await?;
+                feed.write_all(&frame.data).await?;
+            }
+            feed.shutdown().await
+        };
+        let read = async {
+            let mut packets = Vec::new();
+            while let Some(packet) = answers.next().await {
+                packets.push(packet?);
+            }
+            io::Result::Ok(packets)
+        };
+        // the node stops reading once the connection fails, which the
+        // feed failing on is no news
+        let (result, _, answers) = tokio::join!(serve, write, read);
+        Ok(RecordingReplay {
+            packets: frames.len(),
+            answers: answers?,
+            error: result.err(),
+        })
+    }
+
+    /// Write a consistent snapshot of the node's store to `path`. The node
+    /// keeps serving while the backup is taken.
+    pub fn backup<P: AsRef<Path>>(&self, path: P) -> io::Result<BackupManifest> {
+        self.store.backup(path.as_ref())
+    }
+
+    pub async fn listen(&self) -> io::Result<()> {
+        let port = self.bind_addr.port();
+        if let Some(checks) = &self.self_checks {
+            let report = self.self_check().await;
+            report.log();
+            let passed = report.passed();
+            self.emit(NodeEvent::SelfChecked { report });
+            if !passed && checks.refuse_on_failure {
+                return Err(io::Error::other("Self checks failed, refusing to listen"));
+            }
+        }
+        let listener = self.listener_socket_options.bind(self.bind_addr)?;
+        let websocket = match self.websocket_addr {
+            Some(addr) => Some(self.listener_socket_options.bind(addr)?),
+            None => None,
+        };
+        if let Some(schedule) = self.backup_schedule.clone() {
+            tokio::spawn(schedule.run(self.store.clone()));
+        }
+        tokio::spawn(Self::run_maintenance(self.store.clone(), self.maintenance_interval, self.reporters.clone()));
+        tokio::spawn(self.errors.clone().run());
+        tokio::spawn(delivery::run(self.clone(), self.deliveries_queued.clone()));
+        tokio::spawn(supervisor::run(self.clone()));
+        // counted right away, so the first connections are checked too
+        self.resources.sample();
+        tokio::spawn(resources::run(self.resources.clone()));
+        tokio::spawn(embargo::run(self.clone()));
+        #[cfg(feature = "rss-bridge")]
+        for source in self.rss_sources.iter() {
+            tokio::spawn(feed::run(self.clone(), source.clone()));
+        }
+        #[cfg(feature = "nostr-bridge")]
+        if let Some(bridge) = &self.nostr {
+            for relay in bridge.bridge.relays.iter() {
+                tokio::spawn(nostr::run(self.clone(), bridge.clone(), relay.clone()));
+            }
+        }
+        #[cfg(feature = "matrix-bridge")]
+        if let Some(bridge) = &self.matrix {
+            matrix::spawn(self, bridge);
+        }
+        if let Some(addr) = self.identity_addr {
+            let document = self.identity_document()?;
+            let reporters = self.reporters.clone();
+            tokio::spawn(async move {
+                if let Err(e) = identity::serve(addr, document).await {
+                    error!("Identity endpoint failed: {e}");
+                    reporters.report(Fault::new(FaultKind::Internal, format!("Identity endpoint failed: {e}")));
+                }
+            });
+        }
+        match self.websocket_addr {
+            Some(addr) => info!("Listening started on port {port}, and for WebSockets on port {}, ready to accept connections", addr.port()),
+            None => info!("Listening started on port {port}, ready to accept connections"),
+        }
+        loop {
+            // The second item contains the IP and port of the new connection.
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted.map(|accepted| (accepted, false)),
+                accepted = accept_on(websocket.as_ref()) => accepted.map(|accepted| (accepted, true)),
+            };
+            let ((stream, addr), websocket) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if resources::is_exhausted(&e) => {
+                    warn!("Unable to accept connections: {e}");
+                    if self.resources.may_shed() {
+                        self.shed_idle_connection();
+                    }
+                    // the connection is still waiting to be accepted, and
+                    // would fail again right away
+                    tokio::time::sleep(EXHAUSTED_BACKOFF).await;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let country = self.country(addr.ip());
+            let label = country.as_deref().unwrap_or("unknown");
+            let (pressure, usage) = self.resources.pressure();
+            if pressure == Pressure::Shedding && self.resources.may_shed() {
+                self.shed_idle_connection();
+            }
+            if let (Pressure::Refusing | Pressure::Shedding, Some(usage)) = (pressure, usage) {
+                // telling the guest why would hold on to the descriptor
+                info!("Refusing connection from {addr} [{label}]: {usage}");
+                metrics::connection_refused(country.as_deref());
+                continue;
+            }
+            let allowed = self.live.read().unwrap().access.check_ip(addr.ip());
+            if let Err(reason) = allowed.and_then(|_| self.check_connection(country.as_deref())) {
+                info!("Refusing connection from {addr} [{label}]: {reason}");
+                metrics::connection_refused(country.as_deref());
+                continue;
+            }
+            if let Some((reason, message)) = self.check_limits(addr.ip()) {
+                info!("Refusing connection from {addr} [{label}]: {message}");
+                metrics::connection_refused(country.as_deref());
+                self.refuse_connection(stream, websocket, reason, message);
+                continue;
+            }
+
+            info!("Accepting a new connection from {addr} [{label}]");
+            metrics::connection_accepted(country.as_deref());
+            if let Err(e) = self.listener_socket_options.apply(&stream) {
+                warn!("Unable to set the socket options of the connection from {addr}: {e}");
+            }
+            let guard = self.connections.register(addr, country);
+            self.start_connection(stream, websocket, guard);
+        }
+    }
+
+    /// The country `ip` is located in, if a GeoIP policy is configured and
+    /// knows it.
+    #[cfg(feature = "geoip")]
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        self.geoip.as_ref().and_then(|geoip| geoip.country(ip))
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+
+    /// The key of the RSS item `envelope` carries, if it was pushed by the
+    /// publisher of a bridged feed, see [feed](crate::feed).
+    #[cfg(feature = "rss-bridge")]
+    pub(crate) fn bridged_key(&self, envelope: &Envelope) -> Option<String> {
+        feed::native_key(&self.rss_sources, envelope)
+    }
+
+    #[cfg(not(feature = "rss-bridge"))]
+    pub(crate) fn bridged_key(&self, _envelope: &Envelope) -> Option<String> {
+        None
+    }
+
+    #[cfg(feature = "nostr-bridge")]
+    pub(crate) fn nostr(&self) -> Option<&Nostr> {
+        self.nostr.as_deref()
+    }
+
+    /// Send `envelope` to the Nostr relays of the node's bridge, if it is an
+    /// article the bridge signs for, see [nostr](crate::nostr).
+    #[cfg(feature = "nostr-bridge")]
+    pub(crate) fn send_to_nostr(&self, envelope: &Envelope) {
+        let Some(bridge) = self.nostr() else { return };
+        if let Err(e) = bridge.send(self, envelope) {
+            error!("Unable to send object {} to Nostr relays: {e}", envelope.object_id);
+            self.report_fault(Fault::new(FaultKind::Internal, format!("Unable to send object to Nostr relays: {e}"))
+                .object(envelope.object_id));
+        }
+    }
+
+    #[cfg(not(feature = "nostr-bridge"))]
+    pub(crate) fn send_to_nostr(&self, _envelope: &Envelope) {}
+
+    #[cfg(feature = "matrix-bridge")]
+    pub(crate) fn matrix(&self) -> Option<&Matrix> {
+        self.matrix.as_deref()
+    }
+
+    /// Post `envelope` into the Matrix rooms of the node's bridge taking its
+    /// type, see [matrix](crate::matrix).
+    #[cfg(feature = "matrix-bridge")]
+    pub(crate) fn send_to_matrix(&self, envelope: &Envelope) {
+        if let Some(bridge) = self.matrix() {
+            bridge.send(self, envelope);
+        }
+    }
+
+    #[cfg(not(feature = "matrix-bridge"))]
+    pub(crate) fn send_to_matrix(&self, _envelope: &Envelope) {}
+
+    /// Check an inbound connection from `country` against the GeoIP policy.
+    #[cfg(feature = "geoip")]
+    fn check_connection(&self, country: Option<&str>) -> Result<(), String> {
+        self.geoip.as_ref().map_or(Ok(()), |geoip| geoip.check(country))
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    fn check_connection(&self, _country: Option<&str>) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Check an inbound connection from `ip` against the [ConnectionLimits],
+    /// returning why it is refused if it is over one. The rate limit is
+    /// checked last, so a refused connection doesn't use up a token.
+    fn check_limits(&self, ip: IpAddr) -> Option<(CloseReason, String)> {
+        let live = self.live.read().unwrap();
+        let limits = &live.connection_limits;
+        if limits.max_connections.is_some_and(|max| self.connections.len() >= max) {
+            return Some((CloseReason::TooManyConnections, "Too many open connections".to_string()));
+        }
+        if limits.max_connections_per_ip.is_some_and(|max| self.connections.len_from(ip) >= max) {
+            return Some((CloseReason::TooManyConnections, format!("Too many open connections from {ip}")));
+        }
+        if live.rate_limiter.as_ref().is_some_and(|limiter| !limiter.allow(ip)) {
+            return Some((CloseReason::RateLimited, format!("Too many new connections from {ip}")));
+        }
+        None
+    }
+
+    /// Close the connection that was idle the longest to make room, see
+    /// [resources](crate::connection::resources).
+    fn shed_idle_connection(&self) {
+        match self.connections.shed_idle() {
+            Some(info) => {
+                warn!("Closing connection {} from {} to make room, idle since {}", info.id, info.remote_addr, info.active_at);
+                metrics::connection_shed();
+            }
+            None => warn!("Running out of files, with no idle connection to close"),
+        }
+    }
+
+    /// Tell the guest on `stream` why it is refused, on a task of its own
+    /// that gives up after [REFUSAL_TIMEOUT] so refused peers can't hold on
+    /// to it. While [MAX_REFUSALS] are being told already, as in a flood of
+    /// connections, `stream` is closed right away instead.
+    fn refuse_connection(&self, stream: TcpStream, websocket: bool, reason: CloseReason, message: String) {
+        let Ok(slot) = self.refusals.clone().try_acquire_owned() else {
+            debug!("Closing a refused connection without a reason, {MAX_REFUSALS} refusals are pending");
+            return;
+        };
+        let transport = self.transport.clone();
+        tokio::spawn(async move {
+            let _slot = slot;
+            let refusal = async {
+                let protocol = transport.accept(stream, websocket).await?;
+                InboundConnection::with_protocol(protocol).refuse(reason, message).await
+            };
+            match tokio::time::timeout(REFUSAL_TIMEOUT, refusal).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Unable to tell a refused connection why: {e}"),
+                Err(_) => debug!("Refusing a connection timed out"),
+            }
+        });
+    }
+
+    /// Run [DataStore::maintain] every `every` until the node stops.
+    async fn run_maintenance(store: Arc<dyn DataStore>, every: Duration, reporters: Reporters) {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            let store = store.clone();
+            let message = match tokio::task::spawn_blocking(move || store.maintain()).await {
+                Ok(Err(e)) => format!("Store maintenance failed: {e}"),
+                Err(e) => format!("Store maintenance panicked: {e}"),
+                Ok(Ok(())) => continue,
+            };
+            error!("{message}");
+            reporters.report(Fault::new(FaultKind::Internal, message));
+        }
+    }
+
+    /// Serve `stream`, accepted on the WebSocket endpoint if `websocket`,
+    /// on a task of its own, listed in the registry through `guard` until it
+    /// ends. A second task supervises it, so a panic is logged and counted
+    /// rather than lost.
+    fn start_connection(&self, stream: TcpStream, websocket: bool, guard: ConnectionGuard) {
+        let node = self.clone();
+        let id = guard.id();
+        let remote = stream.peer_addr().ok().map(|addr| addr.ip().to_string());
+        let limits = self.live.read().unwrap().connection_limits;
+        let span = trace::Span::start("osp.connection");
+        span.attribute("osp.connection.id", id);
+        span.attribute("net.peer.ip", remote.as_deref().unwrap_or("unknown"));
+        // logged at the level an operator raises the connection to
+        let log = guard.log();
+        let task = tokio::spawn(log.scope(span.instrument(async move {
+            let connection = node.run_connection(stream, websocket, &guard, limits.handshake_timeout);
+            match limits.max_lifetime {
+                Some(lifetime) => {
+                    if tokio::time::timeout(lifetime, connection).await.is_err() {
+                        info!("Connection {id} reached its maximum lifetime, closing");
+                        metrics::connection_timed_out();
+                    }
+                }
+                None => connection.await,
+            }
+        })));
+        self.connections.register_task(id, task.abort_handle());
+        metrics::connections_open(self.connections.len());
+
+        let connections = self.connections.clone();
+        let reporters = self.reporters.clone();
+        tokio::spawn(async move {
+            match task.await {
+                Err(e) if e.is_panic() => {
+                    let message = reporting::panic_message(&*e.into_panic());
+                    error!("Connection {id} panicked: {message}");
+                    metrics::connection_panicked();
+                    reporters.report(Fault::new(FaultKind::ConnectionPanic, message)
+                        .peer(remote.as_deref())
+                        .connection(id));
+                }
+                Err(_) => info!("Connection {id} was closed by the operator, or to make room"),
+                Ok(()) => debug!("Connection {id} ended"),
+            }
+            metrics::connections_open(connections.len());
+        });
+    }
+
+    async fn run_connection(&self, stream: TcpStream, websocket: bool, guard: &ConnectionGuard, handshake_timeout: Duration) {
+        // until the handshake is done, all there is to tell peers apart by
+        let remote = stream.peer_addr().map_or("unknown".to_string(), |addr| addr.ip().to_string());
+        let features = self.rollouts.decide(&remote);
+        let recorder = self.recordings.recorder(&remote);
+        // the TLS handshake and WebSocket upgrade count towards the
+        // handshake timeout too
+        let handshake = async {
+            let protocol = self.transport.accept(stream, websocket).await?;
+            let mut conn = InboundConnection::with_protocol(protocol)
+                .with_preferences(self.preferences.clone())
+                .with_features(features.clone())
+                .with_node(self.clone());
+            if let Some(recorder) = recorder.clone() {
+                conn = conn.with_recorder(recorder);
+            }
+            conn.begin().await?;
+            io::Result::Ok(conn)
+        };
+        let handshake_span = trace::Span::start("osp.handshake");
+        let connection_handshake = match tokio::time::timeout(handshake_timeout, handshake_span.instrument(handshake)).await {
+            Ok(Ok(conn)) => {
+                metrics::handshake("inbound", Ok(()));
+                self.rollouts.record(&features, true);
+                conn
+            }
+            Ok(Err(e)) => {
+                metrics::handshake("inbound", Err(e.kind()));
+                self.rollouts.record(&features, false);
+                handshake_span.fail(&e);
+                self.errors.report(Level::Error, &remote, format_args!("Handshake with {remote} failed"), &e);
+                return;
+            }
+            Err(_) => {
+                handshake_span.fail("timed out");
+                info!("Handshake of connection {} timed out", guard.id());
+                metrics::handshake("inbound", Err(io::ErrorKind::TimedOut));
+                self.rollouts.record(&features, false);
+                metrics::connection_timed_out();
+                return;
+            }
+        };
+
+        let mut connection_transfer = match connection_handshake.into_transfer(self.data_store()) {
+            Ok(conn) => conn,
+            Err(e) => {
+                self.errors.report(Level::Error, &remote, format_args!("Unable to start transfer with {remote}"), &e);
+                return;
+            }
+        };
+        drop(handshake_span);
+        let peer = connection_transfer.sync().hostname().to_string();
+        guard.transferring(&peer);
+        connection_transfer.track_activity(guard.activity());
+        if recorder.is_none() {
+            if let Some(recorder) = self.recordings.recorder(&peer) {
+                connection_transfer.record(recorder);
+            }
+        }
+
+        let transfer_span = trace::Span::start("osp.transfer");
+        transfer_span.attribute("osp.peer", &peer);
+        if let Err(e) = transfer_span.instrument(connection_transfer.serve(self)).await {
+            transfer_span.fail(&e);
+            self.errors.report(Level::Error, &peer, format_args!("Transfer with {peer} failed"), &e);
+        }
+    }
+
+    pub async fn create_outbound(&self, url: OSPUrl) -> io::Result<OutboundConnection<outbound::TransferState>> {
+        info!("Starting outbound connection to {url}");
+        self.check_federation(&url.domain)?;
+        let peer = url.domain.clone();
+        let invite = self.invites.iter().find(|invite| invite.issuer == peer).cloned();
+        let features = self.rollouts.decide(&peer);
+        let compression = match features.is_enabled(Feature::Compression) {
+            true => self.compression.to_vec(),
+            false => Vec::new(),
+        };
+        let formats = match features.is_enabled(Feature::PayloadFormats) {
+            true => self.payload_formats.to_vec(),
+            false => vec![PayloadFormat::default()],
+        };
+        #[cfg(feature = "dns-auth")]
+        let conn = OutboundConnection::create_with_resolver(url, self.private_key.clone(), self.hostname.clone(), &self.resolver).await?;
+        #[cfg(not(feature = "dns-auth"))]
+        let conn = OutboundConnection::create(url, self.private_key.clone(), self.hostname.clone()).await?;
+        let mut conn = conn
+            .with_transport(self.transport.clone())
+            .with_socket_options(self.outbound_socket_options)
+            .with_compression(compression)
+            .with_formats(formats)
+            .with_software(&self.software);
+        if let Some(recorder) = self.recordings.recorder(&peer) {
+            conn = conn.with_recorder(recorder);
+        }
+        if let Some(invite) = invite {
+            conn = conn.with_invite(invite);
+        }
+        if let Some(key) = self.ed25519_key.clone().filter(|_| features.is_enabled(Feature::Ed25519)) {
+            conn = conn.with_ed25519_key(key);
+        }
+        // the peer starts over if it lost the session, or we did
+        conn = conn.with_resumption_token(SyncSession::load(self.store.clone(), peer.clone())?.resumption_token());
+        let span = trace::Span::start("osp.handshake");
+        span.attribute("osp.peer", &peer);
+        let handshake = async {
+            let mut conn_in_handshake = conn.begin().await?;
+            conn_in_handshake.handshake().await?;
+            io::Result::Ok(conn_in_handshake)
+        };
+        let result = span.instrument(handshake).await;
+        metrics::handshake("outbound", result.as_ref().map(|_| ()).map_err(io::Error::kind));
+        self.rollouts.record(&features, result.is_ok());
+        let conn_in_handshake = result.inspect_err(|e| span.fail(e))?;
+        if let Some(diagnostics) = conn_in_handshake.peer_diagnostics() {
+            self.diagnostics.record(diagnostics.clone());
+        }
+        Ok(conn_in_handshake.into_transfer(self.store.clone())?.with_node(self.clone()))
+    }
+}
+
+/// Accept a connection on `listener`, or never if there is none.
+async fn accept_on(listener: Option<&TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(all(test, feature = "admin-api", feature = "storage-sqlite"))]
+mod tests {
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_protocol::Envelope;
+
+    use crate::OSProtocolNode;
+    use crate::crypto::PrivateKey;
+    use crate::events::NodeEvent;
+    use crate::store::{AuditAction, SqliteStore};
+    use crate::subscription::DeliveryQos;
+
+    #[test]
+    fn test_purge_actor() -> io::Result<()> {
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let mut events = node.events();
+
+        let purged = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![1]).with_actor("alice");
+        let kept = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![2]).with_actor("bob");
+        node.store_object(&purged)?;
+        node.store_object(&kept)?;
+
+        let report = node.purge_actor("alice")?;
+        assert_eq!(report.objects, vec![purged.object_id]);
+        assert_eq!(node.data_store().get_object(purged.object_id)?, None);
+        assert!(node.data_store().get_object(kept.object_id)?.is_some());
+        assert_eq!(events.try_recv().ok(), Some(NodeEvent::ObjectDeleted {
+            object_id: purged.object_id,
+            cause: AuditAction::Purge,
+        }));
+        assert_eq!(node.admin().audit_log(0, 10)?[0].action, AuditAction::Purge);
+        Ok(())
+    }
+
+    #[test]
+    fn test_connection_limits() -> io::Result<()> {
+        use osp_protocol::packet::handshake::CloseReason;
+
+        use crate::connection::registry::{ConnectionLimits, RateLimit};
+
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .connection_limits(ConnectionLimits {
+                max_connections_per_ip: Some(1),
+                rate_limit: Some(RateLimit { burst: 2, per_second: 0.001 }),
+                ..ConnectionLimits::default()
+            })
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+
+        let busy = "127.0.0.1:9000".parse().unwrap();
+        let _guard = node.connections.register(busy, None);
+        let reason = |ip: &str| node.check_limits(ip.parse().unwrap()).map(|(reason, _)| reason);
+        assert_eq!(reason("127.0.0.1"), Some(CloseReason::TooManyConnections));
+        assert_eq!(reason("127.0.0.2"), None);
+        assert_eq!(reason("127.0.0.2"), None);
+        assert_eq!(reason("127.0.0.2"), Some(CloseReason::RateLimited));
+        Ok(())
+    }
+
+    #[test]
+    fn test_trace_propagation() -> io::Result<()> {
+        use crate::policy::TracePropagation;
+
+        let key = PrivateKey::generate(1024)?;
+        let public_key = key.public_key()?;
+        let node = |propagation| OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .federation_peer("Member.test", public_key.clone())
+            .trace_propagation(propagation)
+            .private_key(key.clone())
+            .build();
+
+        assert!(node(TracePropagation::Propagate)?.propagates_traces("outsider.test"));
+        let cluster_only = node(TracePropagation::ClusterOnly)?;
+        assert!(cluster_only.propagates_traces("member.test"));
+        assert!(!cluster_only.propagates_traces("outsider.test"));
+        assert!(!node(TracePropagation::Strip)?.propagates_traces("member.test"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_refused_delivery_is_dropped() -> io::Result<()> {
+        use osp_protocol::{OSPUrl, Scheme};
+
+        use crate::delivery;
+        use crate::policy::access::AccessPolicy;
+
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .access_policy(AccessPolicy::new().deny_host("denied.test"))
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let url = OSPUrl { domain: "denied.test".to_string(), port: 57400, scheme: Scheme::Osp };
+        node.deliver(&url, Envelope::new(Uuid::new_v4(), "node.test".to_string(), vec![1]))?;
+        assert_eq!(node.data_store().delivery_count()?, 1);
+
+        tokio::runtime::Runtime::new()?.block_on(delivery::deliver_due(&node))?;
+        assert_eq!(node.data_store().delivery_count()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_deliveries_honor_qos() -> io::Result<()> {
+        use osp_protocol::{OSPUrl, Scheme};
+
+        use crate::delivery;
+
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let url = OSPUrl { domain: "unreachable.invalid".to_string(), port: 57400, scheme: Scheme::Osp };
+        let first = node.deliver_with(&url, Envelope::new(Uuid::new_v4(), "node.test".to_string(), vec![1]), DeliveryQos::Ordered)?;
+        let second = node.deliver_with(&url, Envelope::new(Uuid::new_v4(), "node.test".to_string(), vec![2]), DeliveryQos::Ordered)?;
+        node.deliver_with(&url, Envelope::new(Uuid::new_v4(), "node.test".to_string(), vec![3]), DeliveryQos::FireAndForget)?;
+        assert_eq!(node.data_store().delivery_count()?, 3);
+
+        // fire-and-forget deliveries aren't retried, ordered ones keep their order
+        tokio::runtime::Runtime::new()?.block_on(delivery::deliver_due(&node))?;
+        let queued = node.data_store().queued_deliveries("unreachable.invalid", 57400)?;
+        assert_eq!(queued.iter().map(|queued| queued.id).collect::<Vec<_>>(), vec![first, second]);
+        assert!(queued[0].next_attempt_at < queued[1].next_attempt_at);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deliveries_pause_when_the_window_is_used_up() -> io::Result<()> {
+        use crate::delivery;
+        use crate::subscription::ReceiveWindow;
+
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let type_id = Uuid::new_v4();
+        node.request_subscription("peer.test", vec![type_id], Vec::new(), DeliveryQos::default(), Some(57400), Vec::new())?;
+        node.subscriptions().advertise_window("peer.test", ReceiveWindow::objects(0))?;
+        node.publish(&Envelope::new(type_id, "node.test".to_string(), vec![1]))?;
+        let queued_at = node.data_store().queued_deliveries("peer.test", 57400)?[0].next_attempt_at;
+
+        // paused without an attempt, and left as it was
+        tokio::runtime::Runtime::new()?.block_on(delivery::deliver_due(&node))?;
+        let queued = node.data_store().queued_deliveries("peer.test", 57400)?;
+        assert_eq!((queued.len(), queued[0].attempts, queued[0].next_attempt_at), (1, 0, queued_at));
+        assert!(node.subscriptions().is_paused("peer.test"));
+
+        node.subscriptions().advertise_window("peer.test", ReceiveWindow::objects(1))?;
+        assert!(!node.subscriptions().is_paused("peer.test"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_processed_deliveries_are_trimmed() -> io::Result<()> {
+        use crate::delivery;
+        use crate::store::PeerSyncState;
+
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let type_id = Uuid::new_v4();
+        node.request_subscription("peer.test", vec![type_id], Vec::new(), DeliveryQos::default(), Some(57400), Vec::new())?;
+        node.publish(&Envelope::new(type_id, "node.test".to_string(), vec![1]))?;
+        node.publish(&Envelope::new(type_id, "node.test".to_string(), vec![2]))?;
+        // both were pushed, but neither ack arrived in time
+        node.data_store().put_peer_state("peer.test", &PeerSyncState { next_sequence: 6, ..PeerSyncState::default() })?;
+        let mut queued = node.data_store().queued_deliveries("peer.test", 57400)?;
+        for (delivery, sequence) in queued.iter_mut().zip([2, 5]) {
+            delivery.pushed_sequence = Some(sequence);
+            node.data_store().put_delivery(delivery)?;
+        }
+
+        assert!(node.subscriptions().report_processed("peer.test", 3)?);
+        assert_eq!(delivery::trim(&node, "peer.test", 3)?, 1);
+        assert_eq!(node.subscriptions().lag("peer.test")?, Some(2));
+        let queued = node.data_store().queued_deliveries("peer.test", 57400)?;
+        assert_eq!((queued.len(), queued[0].pushed_sequence), (1, Some(5)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_deliveries_lost_in_flight() -> io::Result<()> {
+        use osp_protocol::{OSPUrl, ProtocolError, Scheme};
+
+        use crate::delivery;
+
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let url = OSPUrl { domain: "peer.test".to_string(), port: 57400, scheme: Scheme::Osp };
+        node.deliver_with(&url, Envelope::new(Uuid::new_v4(), "node.test".to_string(), vec![1]), DeliveryQos::AtLeastOnce)?;
+        node.deliver_with(&url, Envelope::new(Uuid::new_v4(), "node.test".to_string(), vec![2]), DeliveryQos::FireAndForget)?;
+        let queued = node.data_store().queued_deliveries("peer.test", 57400)?;
+        let err = io::Error::from(ProtocolError::Reset);
+        assert!(delivery::is_lost(&err));
+
+        // pushed once, so dropped
+        assert!(delivery::lost_in_flight(&node, queued[1].clone(), 4, &err)?.is_none());
+        // kept for a retry, unless the peer reports it processed the push
+        let kept = delivery::lost_in_flight(&node, queued[0].clone(), 3, &err)?.unwrap();
+        assert_eq!((kept.pushed_sequence, kept.attempts), (Some(3), 0));
+        assert_eq!(node.data_store().queued_deliveries("peer.test", 57400)?, [kept]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fanout_delegation() -> io::Result<()> {
+        use osp_protocol::{DelegatedPeer, OSPUrl, Scheme};
+
+        use crate::fanout::{self, FanoutTree};
+
+        let origin_key = PrivateKey::generate(1024)?;
+        let relay = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("relay.test".to_string())
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .federation_peer("origin.test", origin_key.public_key()?)
+            .build()?;
+        let type_id = Uuid::new_v4();
+        let url = |domain: &str| OSPUrl { domain: domain.to_string(), port: 57400, scheme: Scheme::Osp };
+        let subscribers = [(url("a.test"), DeliveryQos::default()), (url("b.test"), DeliveryQos::Ordered)];
+        let tree = FanoutTree::build("origin.test", type_id, &subscribers, &[url("relay.test")], 2, None)?;
+        let mut delegation = tree.delegations[0].clone();
+
+        // unsigned, or sent by another peer
+        assert_eq!(relay.accept_delegation("origin.test", delegation.clone()).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        fanout::sign(&mut delegation, &origin_key)?;
+        assert!(relay.accept_delegation("other.test", delegation.clone()).is_err());
+        let mut looping = delegation.clone();
+        looping.children.push(DelegatedPeer { url: url("origin.test"), qos: DeliveryQos::default() });
+        fanout::sign(&mut looping, &origin_key)?;
+        assert_eq!(relay.accept_delegation("origin.test", looping).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        relay.accept_delegation("origin.test", delegation.clone())?;
+
+        // only objects of the origin received from the parent are relayed
+        let envelope = Envelope::new(type_id, "origin.test".to_string(), vec![1]);
+        assert_eq!(relay.syndicate(&envelope, Some("a.test"))?, 0);
+        assert_eq!(relay.syndicate(&Envelope::new(type_id, "other.test".to_string(), vec![2]), Some("origin.test"))?, 0);
+        assert_eq!(relay.syndicate(&envelope, Some("origin.test"))?, 2);
+        assert_eq!(relay.syndicate(&envelope, Some("Origin.Test"))?, 2);
+        let deliveries = relay.data_store().due_deliveries(i64::MAX as u64, 10, &[])?;
+        assert!(deliveries.iter().all(|delivery| delivery.envelope.ttl == envelope.ttl));
+        // kept across restarts
+        assert_eq!(relay.data_store().delegations()?.len(), 1);
+
+        assert!(!relay.revoke_delegation("other.test", delegation.delegation_id)?);
+        assert!(relay.revoke_delegation("ORIGIN.test", delegation.delegation_id)?);
+        assert_eq!(relay.syndicate(&envelope, Some("origin.test"))?, 0);
+        assert!(relay.data_store().delegations()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_published_objects_are_queued_for_subscribers() -> io::Result<()> {
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let type_id = Uuid::new_v4();
+        node.request_subscription("peer.test", vec![type_id], Vec::new(), DeliveryQos::default(), Some(57400), Vec::new())?;
+        node.request_subscription("client.test", vec![type_id], Vec::new(), DeliveryQos::default(), None, Vec::new())?;
+
+        node.publish(&Envelope::new(type_id, "node.test".to_string(), vec![1]))?;
+        node.publish(&Envelope::new(Uuid::new_v4(), "node.test".to_string(), vec![2]))?;
+        let deliveries = node.data_store().due_deliveries(i64::MAX as u64, 10, &[])?;
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!((deliveries[0].peer.as_str(), deliveries[0].port), ("peer.test", 57400));
+
+        // objects aren't sent back to where they came from
+        let envelope = Envelope::new(type_id, "peer.test".to_string(), vec![3]);
+        node.store_object(&envelope)?;
+        assert_eq!(node.syndicate(&envelope, None)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_relay() -> io::Result<()> {
+        let builder = || OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("relay.test".to_string())
+            .data_store(SqliteStore::open_in_memory().unwrap())
+            .private_key(PrivateKey::generate(1024).unwrap());
+        let type_id = Uuid::new_v4();
+        let envelope = Envelope::new(type_id, "origin.test".to_string(), vec![1]).with_ttl(2);
+        let subscribe = |node: &OSProtocolNode| {
+            for peer in ["a.test", "b.test"] {
+                node.request_subscription(peer, vec![type_id], Vec::new(), DeliveryQos::default(), Some(57400), Vec::new())?;
+            }
+            io::Result::Ok(())
+        };
+
+        // every node passes received objects on, but only relays drop
+        // copies coming back
+        let node = builder().build()?;
+        subscribe(&node)?;
+        assert_eq!(node.syndicate(&envelope, Some("a.test"))?, 1);
+        node.store_object(&envelope)?;
+        assert!(!node.relayed_already(&envelope)?);
+
+        let relay = builder().relay(true).build()?;
+        subscribe(&relay)?;
+        assert_eq!(relay.syndicate(&envelope, Some("a.test"))?, 1);
+        let deliveries = relay.data_store().due_deliveries(i64::MAX as u64, 10, &[])?;
+        assert_eq!((deliveries[0].peer.as_str(), deliveries[0].envelope.ttl), ("b.test", 1));
+        assert_eq!(relay.syndicate(&envelope.clone().with_ttl(0), Some("a.test"))?, 0);
+
+        // copies coming back along another path are dropped, new objects
+        // under the same id aren't
+        relay.store_object(&envelope)?;
+        assert!(relay.relayed_already(&envelope.clone().with_ttl(1))?);
+        assert!(!relay.relayed_already(&Envelope { origin: "other.test".to_string(), ..envelope.clone() })?);
+        assert!(!relay.relayed_already(&Envelope { created_at: envelope.created_at + 1, ..envelope.clone() })?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_audience_limits_syndication() -> io::Result<()> {
+        use osp_protocol::Audience;
+
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .community("friends", &["Other.test"])
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let type_id = Uuid::new_v4();
+        node.request_subscription("peer.test", vec![type_id], Vec::new(), DeliveryQos::default(), Some(57400), Vec::new())?;
+        node.request_subscription("other.test", vec![type_id], Vec::new(), DeliveryQos::default(), Some(57400), Vec::new())?;
+
+        let envelope = |audience| Envelope::new(type_id, "node.test".to_string(), vec![1]).with_audience(audience);
+        assert_eq!(node.syndicate(&envelope(Audience::Public), None)?, 2);
+        assert_eq!(node.syndicate(&envelope(Audience::Hosts(vec!["PEER.test".to_string()])), None)?, 1);
+        assert_eq!(node.syndicate(&envelope(Audience::Community("friends".to_string())), None)?, 1);
+        // no one is a member of a community the node doesn't know
+        assert_eq!(node.syndicate(&envelope(Audience::Community("strangers".to_string())), None)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scheduled_publishing() -> io::Result<()> {
+        use crate::embargo;
+
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let mut events = node.events();
+        let type_id = Uuid::new_v4();
+        node.request_subscription("peer.test", vec![type_id], Vec::new(), DeliveryQos::default(), Some(57400), Vec::new())?;
+        let draft = Envelope::new(type_id, "node.test".to_string(), vec![1]);
+        let cancelled = Envelope::new(type_id, "node.test".to_string(), vec![2]);
+        node.schedule_publish(draft.clone(), 1000)?;
+        node.schedule_publish(cancelled.clone(), 1000)?;
+        assert_eq!(node.schedule_publish(draft.clone(), 1000).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        // held back until it is due
+        assert!(node.reschedule_publish(draft.object_id, 2000)?);
+        assert!(node.cancel_publish(cancelled.object_id)?);
+        assert_eq!(embargo::release_due(&node, 1500)?, 0);
+        assert_eq!(node.data_store().get_object(draft.object_id)?, None);
+        assert_eq!(embargo::release_due(&node, 2000)?, 1);
+        assert!(node.data_store().get_object(draft.object_id)?.is_some());
+        assert_eq!(node.data_store().delivery_count()?, 1);
+        assert!(!node.cancel_publish(draft.object_id)?);
+        // released objects aren't scheduled again
+        assert!(!node.reschedule_publish(draft.object_id, 3000)?);
+        assert!(!node.reschedule_publish(cancelled.object_id, 3000)?);
+        assert!(node.data_store().scheduled_objects()?.is_empty());
+
+        let received: Vec<_> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert_eq!(received, [
+            NodeEvent::PublishScheduled { object_id: draft.object_id, publish_at: 1000 },
+            NodeEvent::PublishScheduled { object_id: cancelled.object_id, publish_at: 1000 },
+            NodeEvent::PublishRescheduled { object_id: draft.object_id, publish_at: 2000 },
+            NodeEvent::PublishCancelled { object_id: cancelled.object_id },
+            NodeEvent::ObjectReleased { object_id: draft.object_id },
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preview_broadcast() -> io::Result<()> {
+        use osp_protocol::{Audience, OSPUrl, Scheme};
+
+        use crate::policy::access::AccessPolicy;
+        use crate::preview::{Refusal, Route};
+
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .access_policy(AccessPolicy::new().deny_host("denied.test"))
+            .push_target(OSPUrl { domain: "mirror.test".to_string(), port: 57400, scheme: Scheme::Osp })
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let type_id = Uuid::new_v4();
+        for peer in ["peer.test", "denied.test", "other.test"] {
+            node.request_subscription(peer, vec![type_id], Vec::new(), DeliveryQos::default(), Some(57400), Vec::new())?;
+        }
+        let audience = Audience::Hosts(vec!["peer.test".to_string(), "denied.test".to_string(), "mirror.test".to_string()]);
+        let envelope = Envelope::new(type_id, "node.test".to_string(), vec![1]).with_audience(audience);
+
+        let preview = node.preview_broadcast(&envelope)?;
+        let outcome = |domain: &str| preview.recipients.iter()
+            .find(|recipient| recipient.url.domain == domain)
+            .map(|recipient| (recipient.route.clone(), recipient.refusal.clone()));
+        assert_eq!(outcome("peer.test"), Some((Route::Subscription, None)));
+        assert!(matches!(outcome("denied.test"), Some((Route::Subscription, Some(Refusal::Denied(_))))));
+        assert_eq!(outcome("other.test"), Some((Route::Subscription, Some(Refusal::OutsideAudience))));
+        assert_eq!(outcome("mirror.test"), Some((Route::PushTarget, None)));
+        assert_eq!(preview.receivers().count(), 2);
+
+        // nothing was stored or queued
+        assert_eq!(node.data_store().get_object(envelope.object_id)?, None);
+        assert_eq!(node.data_store().delivery_count()?, 0);
+        Ok(())
+    }
+
+    /// Pushes are answered with receipts telling how the host took them in.
+    #[cfg(feature = "dns-auth")]
+    #[test]
+    fn test_delivery_receipts() -> io::Result<()> {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use osp_data::{Data, HandlerError};
+        use osp_data::standard::Article;
+        use osp_protocol::packet::transfer::{AckStatus, RejectCode};
+
+        use crate::connection::challenge::ChallengeRecord;
+        use crate::connection::dns::ChallengeResolver;
+        use crate::connection::error::TransferError;
+        use crate::connection::outbound::OutboundConnection;
+        use crate::crypto::Ed25519Key;
+        use crate::store::MemoryStore;
+
+        let guest_key = Ed25519Key::generate()?;
+        let addr = "127.0.0.1:57403".parse().unwrap();
+        let host = OSProtocolNode::builder()
+            .bind_to(addr)
+            .hostname("host.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .challenge_resolver(ChallengeResolver::default()
+                .override_record("guest.test", &ChallengeRecord::ed25519(&guest_key.public_key()?, None)?))
+            .validator("not_empty", Uuid::nil(), |envelope: &Envelope| match envelope.payload.is_empty() {
+                true => Err(io::Error::new(io::ErrorKind::InvalidData, "Empty payload")),
+                false => Ok(()),
+            })
+            .handler("untitled", |article: Article, _: Envelope| async move {
+                match article.title.is_empty() {
+                    true => Err(HandlerError::rejected("Untitled article")),
+                    false => Ok(()),
+                }
+            })
+            .build()?;
+
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let listening = host.clone();
+            tokio::spawn(async move { listening.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let mut conn = OutboundConnection::create_with_socket_addr(addr, PrivateKey::generate(1024)?, "guest.test".to_string())?
+                .with_ed25519_key(guest_key)
+                .begin()
+                .await?;
+            conn.handshake().await?;
+            let mut conn = conn.into_transfer(Arc::new(MemoryStore::new()))?;
+
+            let envelope = Envelope::new(Uuid::nil(), "guest.test".to_string(), vec![1]);
+            let receipts = [
+                conn.push_with_receipt(envelope.clone()).await?,
+                conn.push_with_receipt(envelope.clone()).await?,
+            ];
+            let refused = conn.push_with_receipt(Envelope::new(Uuid::nil(), "guest.test".to_string(), vec![])).await?;
+            conn.settle_receipts().await?;
+            let [first, again] = receipts;
+            assert_eq!(first.await?.status, Some(AckStatus::Processed));
+            let again = again.await?;
+            assert_eq!((again.object_id, again.sequence, again.status), (envelope.object_id, 2, Some(AckStatus::Duplicate)));
+            let refused = refused.await.unwrap_err();
+            assert!(matches!(refused.get_ref().and_then(|inner| inner.downcast_ref()), Some(TransferError::Rejected { code: RejectCode::Invalid, .. })));
+            assert_eq!(conn.push_acked(Envelope::new(Uuid::nil(), "guest.test".to_string(), vec![2])).await?, 4);
+
+            // objects a handler rejects aren't kept
+            let untitled = Article::default().to_envelope("guest.test".to_string())?;
+            assert!(conn.push_acked(untitled.clone()).await.is_err());
+            assert_eq!(host.data_store().get_object(untitled.object_id)?, None);
+            Ok(())
+        })
+    }
+
+    /// A read-only node takes in the objects of the types it subscribed to,
+    /// but doesn't pass them on to its own subscribers.
+    #[test]
+    fn test_read_only_doesnt_relay() -> io::Result<()> {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use osp_protocol::packet::transfer::RejectCode;
+
+        use crate::connection::challenge::ChallengeRecord;
+        use crate::connection::dns::ChallengeResolver;
+        use crate::connection::error::TransferError;
+        use crate::connection::outbound::OutboundConnection;
+        use crate::crypto::Ed25519Key;
+        use crate::store::MemoryStore;
+
+        let guest_key = Ed25519Key::generate()?;
+        let addr = "127.0.0.1:57419".parse().unwrap();
+        let mirror = OSProtocolNode::builder()
+            .bind_to(addr)
+            .hostname("mirror.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .challenge_resolver(ChallengeResolver::default()
+                .override_record("guest.test", &ChallengeRecord::ed25519(&guest_key.public_key()?, None)?))
+            .read_only(true)
+            .build()?;
+        let (solicited, other) = (Uuid::new_v4(), Uuid::new_v4());
+        mirror.subscriptions().subscribed_upstream("guest.test", &[solicited]);
+        mirror.request_subscription("sub.test", vec![solicited, other], Vec::new(), DeliveryQos::default(), Some(57400), Vec::new())?;
+
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let listening = mirror.clone();
+            tokio::spawn(async move { listening.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let mut conn = OutboundConnection::create_with_socket_addr(addr, PrivateKey::generate(1024)?, "guest.test".to_string())?
+                .with_ed25519_key(guest_key)
+                .begin()
+                .await?;
+            conn.handshake().await?;
+            let mut conn = conn.into_transfer(Arc::new(MemoryStore::new()))?;
+
+            let envelope = Envelope::new(solicited, "guest.test".to_string(), vec![1]);
+            conn.push_acked(envelope.clone()).await?;
+            assert!(mirror.data_store().get_object(envelope.object_id)?.is_some());
+            assert!(mirror.data_store().due_deliveries(i64::MAX as u64, 10, &[])?.is_empty());
+
+            let refused = conn.push_acked(Envelope::new(other, "guest.test".to_string(), vec![1])).await.unwrap_err();
+            assert!(matches!(refused.get_ref().and_then(|inner| inner.downcast_ref()), Some(TransferError::Rejected { code: RejectCode::ReadOnly, .. })));
+            Ok(())
+        })
+    }
+
+    /// Guests are sent the notices published on the host, once they connect
+    /// and as soon as they are published after.
+    #[cfg(feature = "dns-auth")]
+    #[test]
+    fn test_notices() -> io::Result<()> {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use osp_protocol::packet::transfer::TransferPacketHostToGuest;
+
+        use crate::connection::challenge::ChallengeRecord;
+        use crate::connection::dns::ChallengeResolver;
+        use crate::connection::outbound::OutboundConnection;
+        use crate::crypto::Ed25519Key;
+        use crate::notice::{Notice, NoticeLevel};
+        use crate::store::MemoryStore;
+
+        let guest_key = Ed25519Key::generate()?;
+        let addr = "127.0.0.1:57404".parse().unwrap();
+        let host = OSProtocolNode::builder()
+            .bind_to(addr)
+            .hostname("host.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .challenge_resolver(ChallengeResolver::default()
+                .override_record("guest.test", &ChallengeRecord::ed25519(&guest_key.public_key()?, None)?))
+            .build()?;
+        let shutdown = host.publish_notice(Notice::new(NoticeLevel::Critical, "Shutting down").sunset_at(crate::embargo::now() + 3600));
+
+        let listening = host.clone();
+        tokio::runtime::Runtime::new()?.block_on(async {
+            tokio::spawn(async move { listening.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let mut conn = OutboundConnection::create_with_socket_addr(addr, PrivateKey::generate(1024)?, "guest.test".to_string())?
+                .with_ed25519_key(guest_key)
+                .begin()
+                .await?;
+            conn.handshake().await?;
+            let mut conn = conn.into_transfer(Arc::new(MemoryStore::new()))?;
+            // the push window is sent first
+            conn.ready().await?;
+            let TransferPacketHostToGuest::Notice { level, message, sunset_at } = conn.read_packet().await? else {
+                panic!("Expected the shutdown notice");
+            };
+            assert_eq!((level, message.as_str()), (NoticeLevel::Critical, "Shutting down"));
+            assert!(sunset_at.is_some());
+
+            // withdrawn notices aren't sent again, those published since are,
+            // without waiting for the guest to send anything
+            assert!(host.withdraw_notice(shutdown));
+            host.publish_notice(Notice::new(NoticeLevel::Warning, "Notes are deprecated"));
+            let TransferPacketHostToGuest::Notice { message, sunset_at: None, .. } = conn.read_packet().await? else {
+                panic!("Expected the deprecation notice");
+            };
+            assert_eq!(message, "Notes are deprecated");
+            assert_eq!(host.published_notices().len(), 1);
+            Ok(())
+        })
+    }
+
+    /// Guests keep at most as many pushes waiting for an ack as the host's
+    /// push window allows.
+    #[cfg(feature = "dns-auth")]
+    #[test]
+    fn test_push_window() -> io::Result<()> {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use futures_util::SinkExt;
+
+        use crate::connection::challenge::ChallengeRecord;
+        use crate::connection::dns::ChallengeResolver;
+        use crate::connection::outbound::OutboundConnection;
+        use crate::crypto::Ed25519Key;
+        use crate::store::MemoryStore;
+
+        let guest_key = Ed25519Key::generate()?;
+        let addr = "127.0.0.1:57405".parse().unwrap();
+        let host = OSProtocolNode::builder()
+            .bind_to(addr)
+            .hostname("host.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .challenge_resolver(ChallengeResolver::default()
+                .override_record("guest.test", &ChallengeRecord::ed25519(&guest_key.public_key()?, None)?))
+            .push_window(Some(2))
+            .build()?;
+
+        let listening = host.clone();
+        tokio::runtime::Runtime::new()?.block_on(async {
+            tokio::spawn(async move { listening.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let mut conn = OutboundConnection::create_with_socket_addr(addr, PrivateKey::generate(1024)?, "guest.test".to_string())?
+                .with_ed25519_key(guest_key)
+                .begin()
+                .await?;
+            conn.handshake().await?;
+            let mut conn = conn.into_transfer(Arc::new(MemoryStore::new()))?;
+            for i in 0..5 {
+                conn.push(Envelope::new(Uuid::new_v4(), "guest.test".to_string(), vec![i])).await?;
+                assert!(conn.pushes_in_flight() <= 2);
+            }
+            assert_eq!(conn.push_window(), Some(2));
+
+            let mut sink = conn.into_sink();
+            for i in 0..5 {
+                sink.send(Envelope::new(Uuid::new_v4(), "guest.test".to_string(), vec![i])).await?;
+            }
+            let mut conn = sink.into_inner().unwrap();
+            assert!(conn.pushes_in_flight() <= 2);
+            conn.push_acked(Envelope::new(Uuid::new_v4(), "guest.test".to_string(), vec![5])).await?;
+            assert_eq!(host.data_store().list_objects(0, 100)?.len(), 11);
+            Ok(())
+        })
+    }
+
+    /// Guests that stop answering pings are disconnected, those reading
+    /// their packets are kept.
+    #[cfg(feature = "dns-auth")]
+    #[test]
+    fn test_keepalive() -> io::Result<()> {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use osp_protocol::Keepalive;
+
+        use crate::connection::challenge::ChallengeRecord;
+        use crate::connection::dns::ChallengeResolver;
+        use crate::connection::outbound::OutboundConnection;
+        use crate::crypto::Ed25519Key;
+        use crate::store::MemoryStore;
+
+        let guest_key = Ed25519Key::generate()?;
+        let addr = "127.0.0.1:57406".parse().unwrap();
+        let host = OSProtocolNode::builder()
+            .bind_to(addr)
+            .hostname("host.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .challenge_resolver(ChallengeResolver::default()
+                .override_record("guest.test", &ChallengeRecord::ed25519(&guest_key.public_key()?, None)?))
+            .keepalive(Keepalive { interval: Some(Duration::from_millis(50)), idle_timeout: Some(Duration::from_millis(200)) })
+            .build()?;
+
+        let listening = host.clone();
+        tokio::runtime::Runtime::new()?.block_on(async {
+            tokio::spawn(async move { listening.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let connect = || async {
+                let mut conn = OutboundConnection::create_with_socket_addr(addr, PrivateKey::generate(1024)?, "guest.test".to_string())?
+                    .with_ed25519_key(guest_key.clone())
+                    .begin()
+                    .await?;
+                conn.handshake().await?;
+                let mut conn = conn.into_transfer(Arc::new(MemoryStore::new()))?.with_keepalive(Keepalive::DISABLED);
+                conn.ready().await?;
+                io::Result::Ok(conn)
+            };
+
+            let mut silent = connect().await?;
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            let closed = async {
+                loop {
+                    silent.read_packet().await?;
+                }
+            };
+            assert!(tokio::time::timeout(Duration::from_secs(1), closed).await.is_ok_and(|closed: io::Result<()>| closed.is_err()));
+
+            let mut reading = connect().await?;
+            assert!(reading.ping().await? < Duration::from_millis(200));
+            // pings are answered while waiting for packets that don't come
+            assert!(tokio::time::timeout(Duration::from_millis(400), reading.read_packet()).await.is_err());
+            reading.ping().await?;
+            Ok(())
+        })
+    }
+
+    /// A recorded connection replays to the same answers on another node.
+    #[cfg(feature = "dns-auth")]
+    #[test]
+    fn test_recording() -> io::Result<()> {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use osp_protocol::Envelope;
+        use osp_protocol::packet::transfer::TransferPacketHostToGuest;
+        use osp_protocol::recording::Recording;
+
+        use crate::connection::challenge::ChallengeRecord;
+        use crate::connection::dns::ChallengeResolver;
+        use crate::connection::outbound::OutboundConnection;
+        use crate::crypto::Ed25519Key;
+        use crate::store::MemoryStore;
+
+        let directory = std::env::temp_dir().join(format!("osp-recordings-{}", Uuid::new_v4()));
+        std::fs::create_dir(&directory)?;
+        let guest_key = Ed25519Key::generate()?;
+        let addr = "127.0.0.1:57407".parse().unwrap();
+        let host = OSProtocolNode::builder()
+            .bind_to(addr)
+            .hostname("host.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .challenge_resolver(ChallengeResolver::default()
+                .override_record("guest.test", &ChallengeRecord::ed25519(&guest_key.public_key()?, None)?))
+            .recordings(&directory)
+            .record_peer("guest.test")
+            .build()?;
+        assert_eq!(host.recorded_peers(), ["guest.test"]);
+
+        let listening = host.clone();
+        let object_id = tokio::runtime::Runtime::new()?.block_on(async {
+            tokio::spawn(async move { listening.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let mut conn = OutboundConnection::create_with_socket_addr(addr, PrivateKey::generate(1024)?, "guest.test".to_string())?
+                .with_ed25519_key(guest_key.clone())
+                .begin()
+                .await?;
+            conn.handshake().await?;
+            let mut conn = conn.into_transfer(Arc::new(MemoryStore::new()))?;
+            conn.ready().await?;
+            let envelope = Envelope::new(Uuid::new_v4(), "guest.test".to_string(), vec![1]);
+            conn.push_acked(envelope.clone()).await?;
+            conn.ping().await?;
+            io::Result::Ok(envelope.object_id)
+        })?;
+
+        let recordings = std::fs::read_dir(&directory)?.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(recordings.len(), 1);
+        let recording = Recording::open(recordings[0].path())?;
+        std::fs::remove_dir_all(&directory)?;
+        assert!(recording.frames.iter().all(|frame| frame.describe().is_ok()));
+
+        let replaying = OSProtocolNode::builder()
+            .bind_to(addr)
+            .hostname("host.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let replay = tokio::runtime::Runtime::new()?.block_on(replaying.replay_recording(&recording, "guest.test"))?;
+        assert!(replay.error.is_none());
+        assert_eq!(replay.packets, 2);
+        assert!(replay.answers.iter().any(|answer| matches!(answer, TransferPacketHostToGuest::Ack { object_id: Some(acked), .. } if *acked == object_id)));
+        assert!(replay.answers.iter().any(|answer| matches!(answer, TransferPacketHostToGuest::Pong { .. })));
+        assert!(replaying.data_store().get_object(object_id)?.is_some());
+        Ok(())
+    }
+
+    /// The connection to a push target is reconnected once it is lost, and
+    /// pushes resume over the new one.
+    #[cfg(feature = "dns-auth")]
+    #[test]
+    fn test_push_target_reconnects() -> io::Result<()> {
+        use std::time::Duration;
+
+        use osp_protocol::{OSPUrl, Scheme};
+
+        use crate::connection::challenge::ChallengeRecord;
+        use crate::connection::dns::ChallengeResolver;
+        use crate::crypto::Ed25519Key;
+        use crate::delivery::RetryPolicy;
+        use crate::events::NodeEvent;
+        use crate::supervisor::TargetState;
+
+        let source_key = Ed25519Key::generate()?;
+        let target = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57408".parse().unwrap())
+            .hostname("target.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .challenge_resolver(ChallengeResolver::default()
+                .override_record("source.test", &ChallengeRecord::ed25519(&source_key.public_key()?, None)?))
+            .build()?;
+        let url = OSPUrl { domain: "localhost".to_string(), port: 57408, scheme: Scheme::Osp };
+        let source = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57409".parse().unwrap())
+            .hostname("source.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .ed25519_key(source_key)
+            .push_target(url.clone())
+            .retry_policy(RetryPolicy { initial_backoff: Duration::from_millis(50), max_backoff: Duration::from_millis(200), ..RetryPolicy::default() })
+            .build()?;
+
+        let (listening_target, listening_source) = (target.clone(), source.clone());
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let mut events = source.events();
+            let connected = async {
+                while let Ok(event) = events.recv().await {
+                    if let NodeEvent::PushTargetChanged { state: TargetState::Connected, .. } = event {
+                        return;
+                    }
+                }
+            };
+            let received = |object_id: Uuid| {
+                let store = target.data_store();
+                async move {
+                    while store.get_object(object_id)?.is_none() {
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                    }
+                    io::Result::Ok(())
+                }
+            };
+            tokio::spawn(async move { listening_target.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            tokio::spawn(async move { listening_source.listen().await });
+            tokio::time::timeout(Duration::from_secs(5), connected).await?;
+            assert_eq!(source.push_target_states(), [(url.clone(), TargetState::Connected)]);
+            let first = Envelope::new(Uuid::new_v4(), "source.test".to_string(), vec![1]);
+            source.publish(&first)?;
+            tokio::time::timeout(Duration::from_secs(5), received(first.object_id)).await??;
+
+            // the target drops the connection, as if it restarted
+            for connection in target.connections().connections() {
+                target.connections().disconnect(connection.id);
+            }
+            let mut states = Vec::new();
+            let reconnected = async {
+                while let Ok(NodeEvent::PushTargetChanged { state, .. }) = events.recv().await {
+                    states.push(std::mem::discriminant(&state));
+                    if state == TargetState::Connected {
+                        return;
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), reconnected).await?;
+            assert_eq!(states, [
+                std::mem::discriminant(&TargetState::Disconnected { reason: String::new(), retry_in: Duration::ZERO }),
+                std::mem::discriminant(&TargetState::Connecting { attempt: 1 }),
+                std::mem::discriminant(&TargetState::Connected),
+            ]);
+            let second = Envelope::new(Uuid::new_v4(), "source.test".to_string(), vec![2]);
+            source.publish(&second)?;
+            tokio::time::timeout(Duration::from_secs(5), received(second.object_id)).await??;
+            io::Result::Ok(())
+        })
+    }
+
+    /// A guest publishing only an Ed25519 key is challenged to sign with it.
+    #[cfg(feature = "dns-auth")]
+    #[test]
+    fn test_ed25519_handshake() -> io::Result<()> {
+        use std::time::Duration;
+
+        use crate::connection::challenge::ChallengeRecord;
+        use crate::connection::dns::ChallengeResolver;
+        use crate::connection::outbound::OutboundConnection;
+        use crate::crypto::Ed25519Key;
+
+        let guest_key = Ed25519Key::generate()?;
+        let addr = "127.0.0.1:57402".parse().unwrap();
+        let host = OSProtocolNode::builder()
+            .bind_to(addr)
+            .hostname("host.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .challenge_resolver(ChallengeResolver::default()
+                .override_record("guest.test", &ChallengeRecord::ed25519(&guest_key.public_key()?, None)?))
+            .build()?;
+
+        let listening = host.clone();
+        tokio::runtime::Runtime::new()?.block_on(async {
+            tokio::spawn(async move { listening.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let guest = || OutboundConnection::create_with_socket_addr(addr, PrivateKey::generate(1024)?, "guest.test".to_string());
+            // without the key, the guest can't answer
+            assert!(guest()?.begin().await?.handshake().await.is_err());
+            let mut conn = guest()?.with_ed25519_key(guest_key.clone()).with_software("guest/1.0").begin().await?;
+            conn.handshake().await?;
+            assert_eq!(conn.peer_diagnostics().and_then(|diagnostics| diagnostics.software.as_deref()), Some(crate::connection::diagnostics::SOFTWARE));
+
+            // the host records them once it sent its verdict
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let diagnostics = host.peer_diagnostics();
+            assert_eq!((diagnostics[0].peer.as_str(), diagnostics[0].software.as_deref()), ("guest.test", Some("guest/1.0")));
+            assert_eq!(diagnostics[0].version, osp_protocol::PROTOCOL_VERSION);
+            assert!(!diagnostics[0].is_skewed() && !diagnostics[0].is_deprecated());
+            Ok(())
+        })
+    }
+
+    /// Peers resume the sync session whose resumption token they both
+    /// kept, and start over otherwise.
+    #[cfg(feature = "dns-auth")]
+    #[test]
+    fn test_resumption() -> io::Result<()> {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use crate::connection::challenge::ChallengeRecord;
+        use crate::connection::dns::ChallengeResolver;
+        use crate::connection::outbound::OutboundConnection;
+        use crate::crypto::Ed25519Key;
+        use crate::store::{DataStore, MemoryStore, PeerSyncState};
+
+        let guest_key = Ed25519Key::generate()?;
+        let addr = "127.0.0.1:57418".parse().unwrap();
+        let host = OSProtocolNode::builder()
+            .bind_to(addr)
+            .hostname("host.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .challenge_resolver(ChallengeResolver::default()
+                .override_record("guest.test", &ChallengeRecord::ed25519(&guest_key.public_key()?, None)?))
+            .build()?;
+        let token = Uuid::new_v4();
+        let kept = PeerSyncState { cursor: 4, next_sequence: 6, resumption_token: token };
+        host.data_store().put_peer_state("guest.test", &kept)?;
+        let guest_store: Arc<dyn DataStore> = Arc::new(MemoryStore::new());
+        guest_store.put_peer_state("127.0.0.1", &PeerSyncState { cursor: 5, next_sequence: 5, ..kept.clone() })?;
+
+        let listening = host.clone();
+        tokio::runtime::Runtime::new()?.block_on(async {
+            tokio::spawn(async move { listening.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let guest = |token| -> io::Result<OutboundConnection<_>> {
+                Ok(OutboundConnection::create_with_socket_addr(addr, PrivateKey::generate(1024)?, "guest.test".to_string())?
+                    .with_ed25519_key(guest_key.clone())
+                    .with_resumption_token(token))
+            };
+            let mut conn = guest(token)?.begin().await?;
+            conn.handshake().await?;
+            let mut conn = conn.into_transfer(guest_store.clone())?;
+            assert_eq!((conn.sync().cursor(), conn.sync().last_sent()), (5, 4));
+            assert_eq!(host.data_store().peer_state("guest.test")?, Some(kept.clone()));
+
+            // a guest that lost its session makes the host start over too
+            let mut conn = guest(Uuid::new_v4())?.begin().await?;
+            conn.handshake().await?;
+            let mut conn = conn.into_transfer(guest_store.clone())?;
+            let state = host.data_store().peer_state("guest.test")?.unwrap();
+            assert_ne!(state.resumption_token, token);
+            assert_eq!((state.cursor, state.next_sequence), (0, 1));
+            assert_eq!((conn.sync().cursor(), conn.sync().resumption_token()), (0, state.resumption_token));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_self_checks() -> io::Result<()> {
+        use crate::selfcheck::{CheckStatus, SelfChecks};
+
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57411".parse().unwrap())
+            .hostname("localhost".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .self_checks(SelfChecks { directories: vec![".".into()], min_free_space: u64::MAX, ..SelfChecks::default() })
+            .build()?;
+        let mut events = node.events();
+        tokio::runtime::Runtime::new()?.block_on(async {
+            assert!(node.listen().await.is_err());
+            let NodeEvent::SelfChecked { report } = events.recv().await.unwrap() else {
+                panic!("Expected the self check report");
+            };
+            let disk = report.checks.iter().find(|check| check.check == "disk").unwrap();
+            assert_eq!(disk.status, CheckStatus::Failed);
+            Ok(())
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_refuse_near_file_limit() -> io::Result<()> {
+        use std::time::Duration;
+
+        use tokio::io::AsyncReadExt;
+
+        use crate::connection::resources::ResourceLimits;
+
+        // any open file is too many
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57412".parse().unwrap())
+            .hostname("host.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .resource_limits(ResourceLimits { refuse_at: f64::MIN_POSITIVE, shed_at: 1.0 })
+            .build()?;
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let listening = node.clone();
+            tokio::spawn(async move { listening.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let mut stream = tokio::net::TcpStream::connect("127.0.0.1:57412").await?;
+            assert_eq!(stream.read(&mut [0; 16]).await?, 0);
+            assert!(node.connections().is_empty());
+            Ok(())
+        })
+    }
+
+    /// Peers are connected to at the endpoint of their SRV record, rather
+    /// than the port of their URL.
+    #[cfg(feature = "dns-auth")]
+    #[test]
+    fn test_endpoint_discovery() -> io::Result<()> {
+        use std::time::Duration;
+
+        use osp_protocol::{OSPUrl, Scheme};
+
+        use crate::connection::challenge::ChallengeRecord;
+        use crate::connection::dns::ChallengeResolver;
+        use crate::crypto::Ed25519Key;
+
+        let source_key = Ed25519Key::generate()?;
+        let target = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57413".parse().unwrap())
+            .hostname("target.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .challenge_resolver(ChallengeResolver::default()
+                .override_record("source.test", &ChallengeRecord::ed25519(&source_key.public_key()?, None)?))
+            .build()?;
+        let source = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57414".parse().unwrap())
+            .hostname("source.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .ed25519_key(source_key)
+            .challenge_resolver(ChallengeResolver::default().override_endpoint("target.test", "localhost", 57413))
+            .build()?;
+        tokio::runtime::Runtime::new()?.block_on(async {
+            tokio::spawn(async move { target.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            // nothing listens at the port of the URL
+            let conn = source.create_outbound(OSPUrl { domain: "target.test".to_string(), port: 9, scheme: Scheme::Osp }).await?;
+            assert_eq!(conn.protocol_version(), osp_protocol::PROTOCOL_VERSION);
+            Ok(())
+        })
+    }
+
+    /// Nodes listen for WebSocket upgrades next to raw TCP, and are connected
+    /// to over a WebSocket by their ws:// URL.
+    #[cfg(all(feature = "dns-auth", feature = "websocket"))]
+    #[test]
+    fn test_websocket_endpoint() -> io::Result<()> {
+        use std::time::Duration;
+
+        use osp_protocol::{OSPUrl, Scheme};
+
+        use crate::connection::challenge::ChallengeRecord;
+        use crate::connection::dns::ChallengeResolver;
+        use crate::crypto::Ed25519Key;
+
+        let source_key = Ed25519Key::generate()?;
+        let target = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57415".parse().unwrap())
+            .hostname("target.test".to_string())
+            .websocket_endpoint("127.0.0.1:57416".parse().unwrap())
+            .private_key(PrivateKey::generate(1024)?)
+            .challenge_resolver(ChallengeResolver::default()
+                .override_record("source.test", &ChallengeRecord::ed25519(&source_key.public_key()?, None)?))
+            .build()?;
+        assert_eq!(target.identity_document()?.document.urls, ["osp://target.test:57415", "ws://target.test:57416"]);
+        let source = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57417".parse().unwrap())
+            .hostname("source.test".to_string())
+            .private_key(PrivateKey::generate(1024)?)
+            .ed25519_key(source_key)
+            .build()?;
+        tokio::runtime::Runtime::new()?.block_on(async {
+            tokio::spawn(async move { target.listen().await });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let url = OSPUrl { domain: "localhost".to_string(), port: 57416, scheme: Scheme::Ws };
+            let mut conn = source.create_outbound(url.clone()).await?;
+            assert_eq!(conn.protocol_version(), osp_protocol::PROTOCOL_VERSION);
+            conn.ping().await?;
+            // the WebSocket endpoint doesn't speak raw TCP
+            assert!(source.create_outbound(OSPUrl { scheme: Scheme::Osp, ..url }).await.is_err());
+            Ok(())
+        })
     }
 }
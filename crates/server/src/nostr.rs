@@ -0,0 +1,517 @@
+//! # Nostr Bridge
+//!
+//! With the `nostr-bridge` feature, a node can carry [Article]s into the
+//! Nostr ecosystem and back with a [NostrBridge]. Articles are long-form
+//! content events ([KIND_LONG_FORM], NIP-23) on the Nostr side: title,
+//! summary, url, publication date and tags map to the tags of the event,
+//! and the HTML body to its content.
+//!
+//! Nostr events are signed by their authors, so the bridge is told which
+//! key signs for which OSP origin. Articles the node publishes, or receives
+//! from its peers, are sent to the configured relays if their origin has a
+//! [key](NostrBridge::sign_as). Events of the Nostr
+//! [authors](NostrBridge::author) mapped to an origin are taken in from the
+//! relays as objects of that origin, once their signature is verified, and
+//! syndicated to the node's subscribers like an object pushed to it.
+//! Each event is bridged once, whichever way it went, so an event echoed back
+//! by a relay is not taken in again. After reconnecting, the bridge asks a
+//! relay for the events since the latest one it took in from it, so events
+//! posted while it was away aren't missed.
+//!
+//! Relays are public, so only articles meant for everyone are sent to them,
+//! and an article's [Sensitivity] travels as a NIP-36 content warning.
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+
+use k256::schnorr::{Signature, SigningKey, VerifyingKey};
+
+use log::{debug, error, info, warn};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use tokio::io;
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::Message;
+
+use osp_data::Data;
+use osp_data::standard::Article;
+use osp_protocol::{Envelope, Sensitivity};
+
+use crate::OSProtocolNode;
+use crate::crypto::{self, hex};
+use crate::embargo;
+use crate::reporting::{Fault, FaultKind};
+
+/// Kind of the long-form content events articles are bridged as.
+pub const KIND_LONG_FORM: u32 = 30023;
+
+/// Name of the NIP-36 tag flagging sensitive content.
+const CONTENT_WARNING: &str = "content-warning";
+
+/// Id of the subscription the bridge opens on each relay.
+const SUBSCRIPTION_ID: &str = "osp-bridge";
+/// Number of events buffered for each relay. Relays that fall further
+/// behind miss the oldest events.
+const OUTBOX_CAPACITY: usize = 256;
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn unhex(text: &str) -> io::Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(invalid(format!("Invalid hex {text:?}")));
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| invalid(format!("Invalid hex {text:?}"))))
+        .collect()
+}
+
+/// A Nostr secret key, signing the events of an origin.
+#[derive(Clone)]
+pub struct NostrKey {
+    key: SigningKey,
+}
+
+impl NostrKey {
+    /// Read a secret key from its hex encoding.
+    pub fn from_hex(secret_key: &str) -> io::Result<Self> {
+        let key = SigningKey::from_bytes(&unhex(secret_key)?)
+            .map_err(|_| invalid("Invalid Nostr secret key".to_string()))?;
+        Ok(NostrKey { key })
+    }
+
+    /// The hex encoded public key events signed with this key carry.
+    pub fn public_key(&self) -> String {
+        hex(&self.key.verifying_key().to_bytes())
+    }
+}
+
+impl Debug for NostrKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NostrKey({})", self.public_key())
+    }
+}
+
+/// The relays to bridge with, and the keys mapping OSP origins to Nostr
+/// authors, see the [module](self) docs.
+#[derive(Clone, Debug, Default)]
+pub struct NostrBridge {
+    /// `wss://` or `ws://` urls of the relays.
+    pub relays: Vec<String>,
+    /// By origin
+    keys: HashMap<String, NostrKey>,
+    /// Origins by hex encoded Nostr public key
+    authors: HashMap<String, String>,
+}
+
+impl NostrBridge {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publish to and read from the relay at `url`. Can be called several
+    /// times.
+    pub fn relay(mut self, url: impl Into<String>) -> Self {
+        self.relays.push(url.into());
+        self
+    }
+
+    /// Send the articles originating from `origin` to the relays, signed
+    /// with `key`.
+    pub fn sign_as(mut self, origin: &str, key: NostrKey) -> Self {
+        self.keys.insert(origin.to_ascii_lowercase(), key);
+        self
+    }
+
+    /// Take in the articles the Nostr author with the hex encoded
+    /// `public_key` posts to the relays, as objects originating from
+    /// `origin`.
+    pub fn author(mut self, public_key: &str, origin: &str) -> Self {
+        self.authors.insert(public_key.to_ascii_lowercase(), origin.to_ascii_lowercase());
+        self
+    }
+}
+
+/// A Nostr event, as defined by NIP-01.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    /// Hex encoded SHA-256 of the serialized event.
+    pub id: String,
+    /// Hex encoded public key of the author.
+    pub pubkey: String,
+    /// Unix timestamp (seconds).
+    pub created_at: u64,
+    pub kind: u32,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    /// Hex encoded Schnorr signature of the id.
+    pub sig: String,
+}
+
+impl Event {
+    /// Create an event signed with `key`.
+    pub fn sign(key: &NostrKey, created_at: u64, kind: u32, tags: Vec<Vec<String>>, content: String) -> io::Result<Event> {
+        let mut event = Event {
+            id: String::new(),
+            pubkey: key.public_key(),
+            created_at,
+            kind,
+            tags,
+            content,
+            sig: String::new(),
+        };
+        let id = event.hash();
+        let mut aux_rand = [0; 32];
+        crypto::random_bytes(&mut aux_rand)?;
+        let signature = key.key.sign_prehash_with_aux_rand(&id, &aux_rand)
+            .map_err(|e| io::Error::other(format!("Unable to sign Nostr event: {e}")))?;
+        event.id = hex(&id);
+        event.sig = hex(&signature.to_bytes());
+        Ok(event)
+    }
+
+    fn hash(&self) -> [u8; 32] {
+        let serialized = json!([0, self.pubkey, self.created_at, self.kind, self.tags, self.content]);
+        crypto::sha256(serialized.to_string().as_bytes())
+    }
+
+    /// Whether the id matches the content of the event and is signed by its
+    /// author.
+    pub fn verify(&self) -> bool {
+        let id = self.hash();
+        if hex(&id) != self.id {
+            return false;
+        }
+        let Ok(author) = unhex(&self.pubkey).and_then(|key| VerifyingKey::from_bytes(&key).map_err(|_| invalid(String::new()))) else {
+            return false;
+        };
+        let Ok(signature) = unhex(&self.sig).and_then(|sig| Signature::try_from(&sig[..]).map_err(|_| invalid(String::new()))) else {
+            return false;
+        };
+        author.verify_raw(&id, &signature).is_ok()
+    }
+
+    /// The value of the first tag named `name`.
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter()
+            .find(|tag| tag.first().is_some_and(|first| first == name))
+            .and_then(|tag| tag.get(1))
+            .map(String::as_str)
+    }
+}
+
+fn tag(name: &str, value: impl Into<String>) -> Vec<String> {
+    vec![name.to_string(), value.into()]
+}
+
+/// The long-form content event for `article`, flagged as `sensitivity`,
+/// signed with `key`. `identifier` tells the article apart from the others
+/// of its author, and events with the same identifier replace each other on
+/// the relays. Authors are not carried over, as the event has a single
+/// author.
+pub fn article_event(article: &Article, identifier: &str, sensitivity: &Sensitivity, key: &NostrKey) -> io::Result<Event> {
+    let mut tags = vec![
+        tag("d", identifier),
+        tag("title", &article.title),
+        tag("published_at", article.published_at.to_string()),
+    ];
+    tags.extend(article.summary.iter().map(|summary| tag("summary", summary)));
+    tags.extend(article.url.iter().map(|url| tag("r", url)));
+    tags.extend(article.tags.iter().map(|t| tag("t", t)));
+    if sensitivity.is_flagged() {
+        let reasons: Vec<_> = [(sensitivity.nsfw, "nsfw"), (sensitivity.spoiler, "spoiler")].into_iter()
+            .filter_map(|(flagged, reason)| flagged.then_some(reason))
+            .chain(sensitivity.warnings.iter().map(String::as_str))
+            .collect();
+        tags.push(tag(CONTENT_WARNING, reasons.join(", ")));
+    }
+    Event::sign(key, article.published_at, KIND_LONG_FORM, tags, article.content.clone())
+}
+
+/// The article a long-form content `event` carries, if it is one.
+pub fn event_article(event: &Event) -> Option<Article> {
+    if event.kind != KIND_LONG_FORM {
+        return None;
+    }
+    Some(Article {
+        title: event.tag("title").unwrap_or_default().to_string(),
+        summary: event.tag("summary").map(str::to_string),
+        content: event.content.clone(),
+        url: event.tag("r").map(str::to_string),
+        authors: Vec::new(),
+        published_at: event.tag("published_at").and_then(|at| at.parse().ok()).unwrap_or(event.created_at),
+        tags: event.tags.iter()
+            .filter(|tag| tag.len() > 1 && tag[0] == "t")
+            .map(|tag| tag[1].clone())
+            .collect(),
+    })
+}
+
+/// How `event` is flagged, going by its NIP-36 content warning.
+pub fn event_sensitivity(event: &Event) -> Sensitivity {
+    let Some(warning) = event.tags.iter().find(|tag| tag.first().is_some_and(|name| name == CONTENT_WARNING)) else {
+        return Sensitivity::default();
+    };
+    let mut sensitivity = Sensitivity::default();
+    // a warning without a reason is a warning still
+    let reasons = warning.get(1).map(String::as_str).filter(|reasons| !reasons.trim().is_empty()).unwrap_or(CONTENT_WARNING);
+    for reason in reasons.split(',').map(str::trim).filter(|reason| !reason.is_empty()) {
+        match reason {
+            "nsfw" => sensitivity.nsfw = true,
+            "spoiler" => sensitivity.spoiler = true,
+            _ => sensitivity.warnings.push(reason.to_string()),
+        }
+    }
+    sensitivity
+}
+
+/// The key an event is recorded under in the bridged items of its origin.
+fn bridged_key(event_id: &str) -> String {
+    format!("nostr:{event_id}")
+}
+
+/// A node's bridge, and the events waiting to be sent to its relays.
+pub(crate) struct Nostr {
+    pub(crate) bridge: NostrBridge,
+    outbox: broadcast::Sender<Event>,
+}
+
+impl Nostr {
+    pub(crate) fn new(bridge: NostrBridge) -> Self {
+        Nostr {
+            bridge,
+            outbox: broadcast::channel(OUTBOX_CAPACITY).0,
+        }
+    }
+
+    /// Send `envelope` to the relays if it is an article for everyone of an
+    /// origin the bridge has a key for. Returns whether it was sent.
+    pub(crate) fn send(&self, node: &OSProtocolNode, envelope: &Envelope) -> io::Result<bool> {
+        // anyone can read what the relays carry
+        if envelope.type_id != Article::TYPE_ID || !envelope.audience.is_public() {
+            return Ok(false);
+        }
+        let Some(key) = self.bridge.keys.get(&envelope.origin.to_ascii_lowercase()) else {
+            return Ok(false);
+        };
+        let article = Article::from_envelope(envelope)?;
+        let event = article_event(&article, &envelope.object_id.to_string(), &envelope.sensitivity, key)?;
+        node.data_store().put_bridged_item(&envelope.origin, &bridged_key(&event.id), envelope.object_id)?;
+        debug!("Sending object {} to Nostr relays as event {}", envelope.object_id, event.id);
+        // no relay may be connected
+        let _ = self.outbox.send(event);
+        Ok(true)
+    }
+
+    /// Take in `event` if it is an article of a mapped author that wasn't
+    /// bridged yet. Returns whether it was taken in.
+    pub(crate) fn receive(&self, node: &OSProtocolNode, event: &Event) -> io::Result<bool> {
+        let Some(origin) = self.bridge.authors.get(&event.pubkey) else {
+            return Ok(false);
+        };
+        let Some(article) = event_article(event) else {
+            return Ok(false);
+        };
+        if !event.verify() {
+            return Err(invalid(format!("Nostr event {} has an invalid id or signature", event.id)));
+        }
+        let key = bridged_key(&event.id);
+        let store = node.data_store();
+        if store.bridged_item(origin, &key)?.is_some() {
+            return Ok(false);
+        }
+        let mut envelope = article.to_envelope(origin.clone())?;
+        envelope.sensitivity = event_sensitivity(event);
+        node.store_object(&envelope)?;
+        store.put_bridged_item(origin, &key, envelope.object_id)?;
+        node.syndicate(&envelope, None)?;
+        Ok(true)
+    }
+
+    /// The subscription request for the articles of the mapped authors
+    /// `relay` got since the latest one taken in from it, if any.
+    fn request(&self, node: &OSProtocolNode, relay: &str) -> io::Result<Option<Message>> {
+        if self.bridge.authors.is_empty() {
+            return Ok(None);
+        }
+        let authors: Vec<_> = self.bridge.authors.keys().collect();
+        let since = node.data_store().bridge_cursor(&cursor_source(relay))?.unwrap_or_else(embargo::now);
+        let filter = json!({ "kinds": [KIND_LONG_FORM], "authors": authors, "since": since });
+        Ok(Some(Message::Text(json!(["REQ", SUBSCRIPTION_ID, filter]).to_string())))
+    }
+
+    /// Remember that `event` was taken in from `relay`, so it is asked for
+    /// the events after it once the bridge reconnects.
+    fn taken_in(&self, node: &OSProtocolNode, relay: &str, event: &Event) -> io::Result<()> {
+        let store = node.data_store();
+        let source = cursor_source(relay);
+        // authors may date their events ahead
+        let created_at = event.created_at.min(embargo::now());
+        if store.bridge_cursor(&source)?.is_none_or(|cursor| cursor < created_at) {
+            store.put_bridge_cursor(&source, created_at)?;
+        }
+        Ok(())
+    }
+}
+
+/// The source the cursor of `relay` is stored under.
+fn cursor_source(relay: &str) -> String {
+    format!("nostr:{relay}")
+}
+
+/// Exchange events with `relay` until the node stops, reconnecting when the
+/// connection is lost.
+pub(crate) async fn run(node: OSProtocolNode, nostr: Arc<Nostr>, relay: String) {
+    loop {
+        if let Err(e) = exchange(&node, &nostr, &relay).await {
+            let message = format!("Connection to Nostr relay {relay} failed: {e}");
+            error!("{message}");
+            node.report_fault(Fault::new(FaultKind::Internal, message));
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn exchange(node: &OSProtocolNode, nostr: &Nostr, relay: &str) -> io::Result<()> {
+    let (mut socket, _) = tokio_tungstenite::connect_async(relay).await.map_err(io::Error::other)?;
+    info!("Connected to Nostr relay {relay}");
+    let mut outbox = nostr.outbox.subscribe();
+    if let Some(request) = nostr.request(node, relay)? {
+        socket.send(request).await.map_err(io::Error::other)?;
+    }
+    loop {
+        tokio::select! {
+            event = outbox.recv() => match event {
+                Ok(event) => socket.send(Message::Text(json!(["EVENT", event]).to_string())).await.map_err(io::Error::other)?,
+                Err(broadcast::error::RecvError::Lagged(missed)) => warn!("Nostr relay {relay} missed {missed} event(s)"),
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(text))) => handle(node, nostr, relay, &text),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(io::Error::other(e)),
+                None => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "closed by the relay")),
+            },
+        }
+    }
+}
+
+/// Handle a message `relay` sent.
+fn handle(node: &OSProtocolNode, nostr: &Nostr, relay: &str, text: &str) {
+    let Ok(Value::Array(message)) = serde_json::from_str::<Value>(text) else {
+        warn!("Ignoring an invalid message from Nostr relay {relay}");
+        return;
+    };
+    let Some((kind, rest)) = message.split_first() else {
+        warn!("Ignoring an empty message from Nostr relay {relay}");
+        return;
+    };
+    match (kind.as_str(), rest) {
+        (Some("EVENT"), [_, event]) => {
+            let Ok(event) = serde_json::from_value::<Event>(event.clone()) else {
+                warn!("Ignoring an invalid event from Nostr relay {relay}");
+                return;
+            };
+            match nostr.receive(node, &event).and_then(|received| {
+                if received {
+                    nostr.taken_in(node, relay, &event)?;
+                }
+                Ok(received)
+            }) {
+                Ok(true) => info!("Bridged Nostr event {} from {relay}", event.id),
+                Ok(false) => {}
+                Err(e) => warn!("Unable to bridge Nostr event {} from {relay}: {e}", event.id),
+            }
+        }
+        (Some("OK"), [id, accepted, reason, ..]) if accepted.as_bool() == Some(false) => {
+            warn!("Nostr relay {relay} refused event {id}: {reason}");
+        }
+        (Some("NOTICE"), [notice, ..]) => info!("Nostr relay {relay}: {notice}"),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use serde_json::json;
+
+    use osp_data::Data;
+    use osp_data::standard::Article;
+    use osp_protocol::{Audience, Envelope, Sensitivity};
+
+    use crate::OSProtocolNode;
+    use crate::crypto::PrivateKey;
+    use crate::nostr::{self, Event, NostrBridge, NostrKey, KIND_LONG_FORM};
+    use crate::store::SqliteStore;
+
+    const SECRET_KEY: &str = "0000000000000000000000000000000000000000000000000000000000000003";
+
+    #[test]
+    fn test_bridge() -> io::Result<()> {
+        let key = NostrKey::from_hex(SECRET_KEY)?;
+        // the BIP-340 test vector for the secret key 3
+        assert_eq!(key.public_key(), "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9");
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .nostr_bridge(NostrBridge::new()
+                .relay("wss://relay.test")
+                .sign_as("node.test", key.clone())
+                .author(&key.public_key(), "node.test"))
+            .data_store(SqliteStore::open_in_memory()?)
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+        let bridge = node.nostr().unwrap();
+
+        let article = Article {
+            title: "Hello".to_string(),
+            summary: Some("A greeting".to_string()),
+            content: "<p>Hello, Nostr</p>".to_string(),
+            published_at: 1_700_000_000,
+            tags: vec!["greeting".to_string()],
+            ..Article::default()
+        };
+        let event = nostr::article_event(&article, "hello", &Sensitivity::default(), &key)?;
+        assert_eq!((event.kind, event.tag("d")), (KIND_LONG_FORM, Some("hello")));
+        assert!(event.verify());
+        assert!(!Event { content: "Tampered".to_string(), ..event.clone() }.verify());
+        assert_eq!(nostr::event_article(&event).as_ref(), Some(&article));
+
+        // a relay echoing an article the node sent doesn't bring it back
+        let envelope = article.to_envelope("node.test".to_string())?;
+        let mut outbox = bridge.outbox.subscribe();
+        assert!(bridge.send(&node, &envelope)?);
+        let sent = outbox.try_recv().unwrap();
+        assert!(!bridge.receive(&node, &sent)?);
+
+        // articles not meant for everyone stay off the relays
+        let restricted = Envelope { audience: Audience::Hosts(vec!["peer.test".to_string()]), ..envelope.clone() };
+        assert!(!bridge.send(&node, &restricted)?);
+
+        assert!(bridge.receive(&node, &event)?);
+        assert!(!bridge.receive(&node, &event)?);
+
+        let sensitivity = Sensitivity { spoiler: true, warnings: vec!["violence".to_string()], ..Sensitivity::default() };
+        let flagged = nostr::article_event(&article, "flagged", &sensitivity, &key)?;
+        assert_eq!(flagged.tag("content-warning"), Some("spoiler, violence"));
+        assert_eq!(nostr::event_sensitivity(&flagged), sensitivity);
+
+        // relays sending nothing, or with a cursor to pick up from
+        nostr::handle(&node, bridge, "wss://relay.test", "[]");
+        nostr::handle(&node, bridge, "wss://relay.test", &json!(["EVENT", "osp-bridge", flagged]).to_string());
+        assert_eq!(node.data_store().bridge_cursor("nostr:wss://relay.test")?, Some(flagged.created_at));
+        let stranger = Event::sign(&NostrKey::from_hex(&SECRET_KEY.replace('3', "4"))?, 0, KIND_LONG_FORM, Vec::new(), String::new())?;
+        assert!(!bridge.receive(&node, &stranger)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! # Notices
+//!
+//! Operators tell the operators of the nodes connecting to theirs about
+//! changes to the network with [Notice]s, such as an upcoming shutdown or a
+//! deprecated data type. A node sends the notices
+//! [published](crate::OSProtocolNode::publish_notice) on it to its guests
+//! once they connect, and those published later right away, until their
+//! sunset passed and they are dropped, see
+//! [Notice](osp_protocol::packet::transfer::TransferPacketHostToGuest::Notice).
+//!
+//! The notices a node's connections receive are logged, kept, and emitted
+//! as [NodeEvent::NoticeReceived](crate::events::NodeEvent::NoticeReceived),
+//! so operators learn about them, see
+//! [OSProtocolNode::received_notices](crate::OSProtocolNode::received_notices).
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::{log, Level};
+
+use tokio::sync::watch;
+
+use osp_protocol::packet::transfer::TransferPacketHostToGuest;
+
+pub use osp_protocol::packet::transfer::NoticeLevel;
+
+use crate::time::rfc3339;
+
+/// Received notices kept at most, the oldest are dropped beyond.
+const MAX_RECEIVED: usize = 256;
+
+/// A notice for the operators of other nodes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notice {
+    pub level: NoticeLevel,
+    pub message: String,
+    /// Unix timestamp (seconds) what the notice announces takes effect at,
+    /// e.g. when the node shuts down.
+    pub sunset_at: Option<u64>,
+}
+
+impl Notice {
+    pub fn new(level: NoticeLevel, message: impl Into<String>) -> Self {
+        Notice {
+            level,
+            message: message.into(),
+            sunset_at: None,
+        }
+    }
+
+    pub fn sunset_at(mut self, sunset_at: u64) -> Self {
+        self.sunset_at = Some(sunset_at);
+        self
+    }
+
+    /// Whether what the notice announces took effect before the unix
+    /// timestamp `now`.
+    pub fn is_past_sunset(&self, now: u64) -> bool {
+        self.sunset_at.is_some_and(|sunset_at| sunset_at < now)
+    }
+
+    pub(crate) fn to_packet(&self) -> TransferPacketHostToGuest {
+        TransferPacketHostToGuest::Notice {
+            level: self.level,
+            message: self.message.clone(),
+            sunset_at: self.sunset_at,
+        }
+    }
+}
+
+/// A notice published on this node, to be sent to its guests.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublishedNotice {
+    /// Identifies the notice to [withdraw](crate::OSProtocolNode::withdraw_notice) it.
+    pub id: u64,
+    pub notice: Notice,
+}
+
+/// A notice a peer sent this node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReceivedNotice {
+    /// Hostname of the peer.
+    pub peer: String,
+    pub notice: Notice,
+    /// Unix timestamp (seconds) the notice was last received at.
+    pub received_at: u64,
+}
+
+/// Log `notice` from `peer` at its level.
+pub(crate) fn log_notice(peer: &str, notice: &Notice) {
+    let level = match notice.level {
+        NoticeLevel::Info => Level::Info,
+        NoticeLevel::Warning => Level::Warn,
+        NoticeLevel::Critical => Level::Error,
+    };
+    match notice.sunset_at {
+        Some(sunset_at) => log!(level, "Notice from {peer}, taking effect at {}: {}", rfc3339(sunset_at), notice.message),
+        None => log!(level, "Notice from {peer}: {}", notice.message),
+    }
+}
+
+/// The notices published on a node and those it received.
+pub(crate) struct Notices {
+    published: Mutex<Vec<PublishedNotice>>,
+    /// Ids are never reused, so guests are sent notices published after
+    /// one was withdrawn
+    last_id: AtomicU64,
+    /// The id of the latest notice published, for connections to send it
+    /// without waiting for their guest
+    latest: watch::Sender<u64>,
+    received: Mutex<VecDeque<ReceivedNotice>>,
+}
+
+impl Default for Notices {
+    fn default() -> Self {
+        Notices {
+            published: Mutex::default(),
+            last_id: AtomicU64::default(),
+            latest: watch::channel(0).0,
+            received: Mutex::default(),
+        }
+    }
+}
+
+impl Notices {
+    /// Publish `notice`, returning its id. Ids start at 1.
+    pub(crate) fn publish(&self, notice: Notice) -> u64 {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.published.lock().unwrap().push(PublishedNotice { id, notice });
+        self.latest.send_replace(id);
+        id
+    }
+
+    /// Watch for notices being published.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<u64> {
+        self.latest.subscribe()
+    }
+
+    /// Stop sending the notice with `id`, returning whether it was published.
+    pub(crate) fn withdraw(&self, id: u64) -> bool {
+        let mut published = self.published.lock().unwrap();
+        let before = published.len();
+        published.retain(|published| published.id != id);
+        published.len() < before
+    }
+
+    /// The notices published, dropping those whose sunset passed before
+    /// `now`.
+    pub(crate) fn published(&self, now: u64) -> Vec<PublishedNotice> {
+        let mut published = self.published.lock().unwrap();
+        published.retain(|published| !published.notice.is_past_sunset(now));
+        published.clone()
+    }
+
+    /// The notices published after the one with id `after`, dropping those
+    /// whose sunset passed before `now`.
+    pub(crate) fn since(&self, after: u64, now: u64) -> Vec<PublishedNotice> {
+        self.published(now).into_iter().filter(|published| published.id > after).collect()
+    }
+
+    /// Keep `notice` received from `peer` at `now`, returning whether it is
+    /// new. Guests are sent a host's notices every time they connect, so
+    /// notices received again only have their time updated.
+    pub(crate) fn receive(&self, peer: &str, notice: Notice, now: u64) -> bool {
+        let mut received = self.received.lock().unwrap();
+        if let Some(known) = received.iter_mut().find(|known| known.peer == peer && known.notice == notice) {
+            known.received_at = now;
+            return false;
+        }
+        if received.len() >= MAX_RECEIVED {
+            received.pop_front();
+        }
+        received.push_back(ReceivedNotice { peer: peer.to_string(), notice, received_at: now });
+        true
+    }
+
+    /// The notices received, oldest first.
+    pub(crate) fn received(&self) -> Vec<ReceivedNotice> {
+        self.received.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::notice::{Notice, NoticeLevel, Notices};
+
+    #[test]
+    fn test_notices() {
+        let notices = Notices::default();
+        let shutdown = notices.publish(Notice::new(NoticeLevel::Critical, "Shutting down").sunset_at(2_000));
+        let deprecated = notices.publish(Notice::new(NoticeLevel::Warning, "Notes are deprecated"));
+        assert_eq!((shutdown, deprecated), (1, 2));
+        assert_eq!(notices.since(0, 1_000).len(), 2);
+        assert_eq!(notices.since(shutdown, 1_000).iter().map(|published| published.id).collect::<Vec<_>>(), [deprecated]);
+        // past its sunset, it isn't sent or kept anymore
+        assert_eq!(notices.since(0, 3_000).len(), 1);
+        assert_eq!(notices.published(1_000).len(), 1);
+        assert!(notices.withdraw(deprecated) && !notices.withdraw(deprecated));
+        assert_eq!(notices.publish(Notice::new(NoticeLevel::Info, "Serving likes")), 3);
+
+        let notice = Notice::new(NoticeLevel::Info, "Hello");
+        assert!(notices.receive("a.test", notice.clone(), 100));
+        assert!(!notices.receive("a.test", notice.clone(), 200));
+        assert!(notices.receive("b.test", notice, 200));
+        assert_eq!(notices.received().iter().map(|received| (received.peer.as_str(), received.received_at)).collect::<Vec<_>>(),
+            [("a.test", 200), ("b.test", 200)]);
+    }
+}
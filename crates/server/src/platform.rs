@@ -0,0 +1,395 @@
+//! # Platform Support
+//!
+//! Paths and local IPC differ between operating systems. This module hides
+//! the differences so a node behaves the same on Linux, macOS and Windows:
+//!
+//! - [config_dir] is where a node keeps its configuration and keys:
+//!   `$XDG_CONFIG_HOME/osp` (or `~/.config/osp`) on Linux,
+//!   `~/Library/Application Support/osp` on macOS and `%APPDATA%\osp` on
+//!   Windows.
+//! - [expand_path] resolves a leading `~` to the home directory, so paths
+//!   from configuration files and the command line work everywhere.
+//! - [LocalListener] and [LocalStream] talk to processes on the same machine
+//!   over a Unix domain socket, or a named pipe on Windows. Other platforms
+//!   go without them.
+//! - [Hangups] receives `SIGHUP`, which never arrives on Windows.
+//! - [open_files] and [open_files_limit] tell how many files the process
+//!   has open and may open, on Unix.
+
+use std::env;
+#[cfg(any(unix, windows))]
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+#[cfg(any(unix, windows))]
+use std::pin::Pin;
+#[cfg(any(unix, windows))]
+use std::task::{Context, Poll};
+
+use tokio::io;
+#[cfg(any(unix, windows))]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions};
+
+/// Name of the directory the SDK keeps its files in.
+const APP_DIR: &str = "osp";
+
+/// The value of the environment variable `name` as an absolute path, if it
+/// is set to one. Relative values are ignored, as the XDG spec requires.
+fn env_path(name: &str) -> Option<PathBuf> {
+    env::var_os(name).map(PathBuf::from).filter(|path| path.is_absolute())
+}
+
+/// The home directory of the current user.
+pub fn home_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        env_path("USERPROFILE")
+    } else {
+        env_path("HOME")
+    }
+}
+
+/// The directory to keep a node's configuration and keys in, if the
+/// environment tells where it is.
+#[cfg(windows)]
+pub fn config_dir() -> Option<PathBuf> {
+    env_path("APPDATA").map(|dir| dir.join(APP_DIR))
+}
+
+/// The directory to keep a node's configuration and keys in, if the
+/// environment tells where it is.
+#[cfg(target_os = "macos")]
+pub fn config_dir() -> Option<PathBuf> {
+    home_dir().map(|home| home.join("Library").join("Application Support").join(APP_DIR))
+}
+
+/// The directory to keep a node's configuration and keys in, if the
+/// environment tells where it is.
+#[cfg(not(any(windows, target_os = "macos")))]
+pub fn config_dir() -> Option<PathBuf> {
+    env_path("XDG_CONFIG_HOME")
+        .or_else(|| home_dir().map(|home| home.join(".config")))
+        .map(|dir| dir.join(APP_DIR))
+}
+
+/// Resolve a leading `~` in `path` to the home directory. Other paths, and
+/// `~` without a known home directory, are returned unchanged.
+pub fn expand_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    match (path.strip_prefix("~"), home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// The `SIGHUP` signals the process receives, asking it to reload its
+/// configuration.
+pub struct Hangups {
+    #[cfg(unix)]
+    signal: Signal,
+}
+
+impl Hangups {
+    pub fn new() -> io::Result<Self> {
+        Ok(Hangups {
+            #[cfg(unix)]
+            signal: signal(SignalKind::hangup())?,
+        })
+    }
+
+    /// Wait for the next `SIGHUP`.
+    #[cfg(unix)]
+    pub async fn recv(&mut self) {
+        self.signal.recv().await;
+    }
+
+    /// Wait for the next `SIGHUP`, which is never.
+    #[cfg(not(unix))]
+    pub async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+/// Where a [LocalListener] listens.
+#[cfg(any(unix, windows))]
+#[derive(Clone, Debug, PartialEq)]
+pub enum LocalEndpoint {
+    /// A Unix domain socket at the path.
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// A named pipe, e.g. `\\.\pipe\osp-admin`.
+    #[cfg(windows)]
+    NamedPipe(String),
+}
+
+#[cfg(any(unix, windows))]
+impl LocalEndpoint {
+    /// The endpoint a service called `name` listens on unless configured
+    /// otherwise: `$XDG_RUNTIME_DIR/osp/<name>.sock`, falling back to the
+    /// temporary directory without a runtime directory.
+    #[cfg(unix)]
+    pub fn default_for(name: &str) -> Self {
+        let dir = env_path("XDG_RUNTIME_DIR").unwrap_or_else(env::temp_dir);
+        Self::Unix(dir.join(APP_DIR).join(format!("{name}.sock")))
+    }
+
+    /// The endpoint a service called `name` listens on unless configured
+    /// otherwise: the named pipe `\\.\pipe\osp-<name>`.
+    #[cfg(windows)]
+    pub fn default_for(name: &str) -> Self {
+        Self::NamedPipe(format!(r"\\.\pipe\{APP_DIR}-{name}"))
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl Display for LocalEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(unix)]
+            LocalEndpoint::Unix(path) => write!(f, "{}", path.display()),
+            #[cfg(windows)]
+            LocalEndpoint::NamedPipe(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Accepts [LocalStream]s on a [LocalEndpoint].
+#[cfg(any(unix, windows))]
+pub struct LocalListener {
+    #[cfg(unix)]
+    listener: UnixListener,
+    #[cfg(unix)]
+    path: PathBuf,
+    #[cfg(windows)]
+    name: String,
+    /// The pipe instance waiting for the next client.
+    #[cfg(windows)]
+    server: NamedPipeServer,
+}
+
+#[cfg(any(unix, windows))]
+impl LocalListener {
+    /// Listen on `endpoint`. A socket file left behind by a process that
+    /// exited is replaced, but one that is still being listened on fails
+    /// with [AddrInUse](io::ErrorKind::AddrInUse).
+    #[cfg(unix)]
+    pub fn bind(endpoint: &LocalEndpoint) -> io::Result<Self> {
+        let LocalEndpoint::Unix(path) = endpoint;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if path.exists() {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is already being listened on", path.display())));
+            }
+            std::fs::remove_file(path)?;
+        }
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+            path: path.clone(),
+        })
+    }
+
+    /// Listen on `endpoint`. Fails if another process already owns the
+    /// pipe.
+    #[cfg(windows)]
+    pub fn bind(endpoint: &LocalEndpoint) -> io::Result<Self> {
+        let LocalEndpoint::NamedPipe(name) = endpoint;
+        Ok(Self {
+            name: name.clone(),
+            server: ServerOptions::new().first_pipe_instance(true).create(name)?,
+        })
+    }
+
+    /// Wait for the next client to connect.
+    #[cfg(unix)]
+    pub async fn accept(&mut self) -> io::Result<LocalStream> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(LocalStream::Unix(stream))
+    }
+
+    /// Wait for the next client to connect.
+    #[cfg(windows)]
+    pub async fn accept(&mut self) -> io::Result<LocalStream> {
+        self.server.connect().await?;
+        // a pipe instance serves a single client, so open the next one
+        // before handing this one out
+        let next = ServerOptions::new().create(&self.name)?;
+        Ok(LocalStream::PipeServer(std::mem::replace(&mut self.server, next)))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for LocalListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A connection between two processes on the same machine.
+#[cfg(any(unix, windows))]
+pub enum LocalStream {
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(windows)]
+    PipeServer(NamedPipeServer),
+    #[cfg(windows)]
+    PipeClient(NamedPipeClient),
+}
+
+#[cfg(any(unix, windows))]
+impl LocalStream {
+    /// Connect to a [LocalListener] on `endpoint`.
+    pub async fn connect(endpoint: &LocalEndpoint) -> io::Result<Self> {
+        match endpoint {
+            #[cfg(unix)]
+            LocalEndpoint::Unix(path) => Ok(Self::Unix(UnixStream::connect(path).await?)),
+            #[cfg(windows)]
+            LocalEndpoint::NamedPipe(name) => Ok(Self::PipeClient(ClientOptions::new().open(name)?)),
+        }
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl AsyncRead for LocalStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            #[cfg(unix)]
+            LocalStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(windows)]
+            LocalStream::PipeServer(pipe) => Pin::new(pipe).poll_read(cx, buf),
+            #[cfg(windows)]
+            LocalStream::PipeClient(pipe) => Pin::new(pipe).poll_read(cx, buf),
+        }
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl AsyncWrite for LocalStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            #[cfg(unix)]
+            LocalStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(windows)]
+            LocalStream::PipeServer(pipe) => Pin::new(pipe).poll_write(cx, buf),
+            #[cfg(windows)]
+            LocalStream::PipeClient(pipe) => Pin::new(pipe).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            #[cfg(unix)]
+            LocalStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(windows)]
+            LocalStream::PipeServer(pipe) => Pin::new(pipe).poll_flush(cx),
+            #[cfg(windows)]
+            LocalStream::PipeClient(pipe) => Pin::new(pipe).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            #[cfg(unix)]
+            LocalStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(windows)]
+            LocalStream::PipeServer(pipe) => Pin::new(pipe).poll_shutdown(cx),
+            #[cfg(windows)]
+            LocalStream::PipeClient(pipe) => Pin::new(pipe).poll_shutdown(cx),
+        }
+    }
+}
+
+/// How many files, sockets included, the process has open, if the platform
+/// tells.
+#[cfg(unix)]
+pub fn open_files() -> Option<u64> {
+    // Linux lists them in /proc, macOS and the BSDs in /dev/fd
+    let entries = std::fs::read_dir("/proc/self/fd").or_else(|_| std::fs::read_dir("/dev/fd")).ok()?;
+    // the directory being read is open too
+    Some(entries.count().saturating_sub(1) as u64)
+}
+
+/// How many files, sockets included, the process has open, if the platform
+/// tells.
+#[cfg(not(unix))]
+pub fn open_files() -> Option<u64> {
+    None
+}
+
+/// How many files, sockets included, the process may open, if the platform
+/// tells.
+#[cfg(unix)]
+// the width of rlim_t differs between platforms
+#[allow(clippy::unnecessary_cast)]
+pub fn open_files_limit() -> io::Result<Option<u64>> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: getrlimit only writes to the limit it is given
+    match unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } {
+        0 => Ok(Some(limit.rlim_cur as u64)),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// How many files, sockets included, the process may open, if the platform
+/// tells.
+#[cfg(not(unix))]
+pub fn open_files_limit() -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    #[cfg(any(unix, windows))]
+    use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+    use crate::platform::{expand_path, home_dir};
+    #[cfg(any(unix, windows))]
+    use crate::platform::{LocalEndpoint, LocalListener, LocalStream};
+
+    #[test]
+    fn test_expand_path() {
+        let absolute = if cfg!(windows) { r"C:\keys\node.pem" } else { "/keys/node.pem" };
+        assert_eq!(expand_path(absolute), PathBuf::from(absolute));
+        assert_eq!(expand_path("keys/node.pem"), PathBuf::from("keys/node.pem"));
+        if let Some(home) = home_dir() {
+            assert_eq!(expand_path("~/keys/node.pem"), home.join("keys").join("node.pem"));
+        }
+    }
+
+    #[cfg(any(unix, windows))]
+    #[test]
+    fn test_local_stream() -> io::Result<()> {
+        let endpoint = LocalEndpoint::default_for(&format!("test-{}", std::process::id()));
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let mut listener = LocalListener::bind(&endpoint)?;
+
+            let client = tokio::spawn({
+                let endpoint = endpoint.clone();
+                async move {
+                    let mut stream = LocalStream::connect(&endpoint).await?;
+                    stream.write_all(b"ping").await?;
+                    let mut reply = [0; 4];
+                    stream.read_exact(&mut reply).await?;
+                    io::Result::Ok(reply)
+                }
+            });
+            let mut stream = listener.accept().await?;
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).await?;
+            assert_eq!(&request, b"ping");
+            stream.write_all(b"pong").await?;
+            assert_eq!(&client.await??, b"pong");
+
+            // the endpoint is taken while the listener is alive
+            assert!(LocalListener::bind(&endpoint).is_err());
+            Ok(())
+        })
+    }
+}
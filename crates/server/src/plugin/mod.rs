@@ -0,0 +1,104 @@
+//! # Plugins
+//!
+//! Handlers and validators loaded at runtime rather than compiled into the
+//! node binary. Plugins work on encoded envelopes, so they can process data
+//! types the node itself knows nothing about. A plugin is attached to the data
+//! types it should run for when building the node.
+//!
+//! Native plugins are shared objects exposing a small C ABI, see [native].
+//! They require the `plugins` feature. WASM plugins run sandboxed with fuel
+//! and memory limits, which makes them suitable for processing untrusted
+//! content, see [wasm]. They require the `wasm-plugins` feature.
+
+use tokio::io;
+
+use osp_protocol::Envelope;
+
+#[cfg(feature = "plugins")]
+pub mod native;
+#[cfg(feature = "wasm-plugins")]
+pub mod wasm;
+
+/// A handler and/or validator provided at runtime.
+pub trait Plugin: Send + Sync + 'static {
+    /// Name of the plugin, used as its handler and validator id.
+    fn name(&self) -> &str;
+
+    /// Whether the plugin provides [Plugin::handle].
+    fn handles(&self) -> bool;
+
+    /// Whether the plugin provides [Plugin::validate].
+    fn validates(&self) -> bool;
+
+    /// Process a received object, after it has been stored.
+    fn handle(&self, envelope: &Envelope) -> io::Result<()>;
+
+    /// Accept or refuse a received object before it is stored.
+    fn validate(&self, envelope: &Envelope) -> io::Result<()>;
+
+    /// Whether the plugin provides [Plugin::transform].
+    fn transforms(&self) -> bool {
+        false
+    }
+
+    /// Rewrite a received object before it is stored.
+    fn transform(&self, envelope: Envelope) -> io::Result<Envelope> {
+        Ok(envelope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_protocol::Envelope;
+
+    use crate::OSProtocolNode;
+    use crate::crypto::PrivateKey;
+    use crate::handler::ReplayFilter;
+    use crate::plugin::Plugin;
+
+    struct CountingPlugin(Arc<AtomicUsize>);
+
+    impl Plugin for CountingPlugin {
+        fn name(&self) -> &str { "counting" }
+        fn handles(&self) -> bool { true }
+        fn validates(&self) -> bool { true }
+
+        fn handle(&self, _: &Envelope) -> io::Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn validate(&self, envelope: &Envelope) -> io::Result<()> {
+            match envelope.payload.is_empty() {
+                true => Err(io::Error::new(io::ErrorKind::InvalidData, "Empty payload")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_plugin_registration() -> io::Result<()> {
+        let (count, type_id) = (Arc::new(AtomicUsize::new(0)), Uuid::new_v4());
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .plugin(CountingPlugin(count.clone()), &[type_id])
+            .private_key(PrivateKey::generate(1024)?)
+            .build()?;
+
+        assert!(node.validate(&Envelope::new(type_id, "origin.test".to_string(), vec![])).is_err());
+        // other types are not affected by the plugin
+        assert!(node.validate(&Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![])).is_ok());
+
+        node.data_store().put_object(&Envelope::new(type_id, "origin.test".to_string(), vec![1]))?;
+        tokio::runtime::Runtime::new()?.block_on(node.replay(ReplayFilter::default()))?;
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+//! # Native Plugins
+//!
+//! Plugins loaded from shared objects (`.so`, `.dylib` or `.dll`). A plugin
+//! exports the following C functions, of which only the first two are
+//! required:
+//!
+//! ```c
+//! // Must return 1, the ABI version described here.
+//! uint32_t osp_plugin_abi_version(void);
+//! // Name of the plugin, a static NUL terminated string.
+//! const char *osp_plugin_name(void);
+//! // Called once after loading with the plugin's configuration string.
+//! int32_t osp_plugin_init(const uint8_t *config, size_t config_len);
+//! // Process an object. Returns 0 on success.
+//! int32_t osp_plugin_handle(const uint8_t *envelope, size_t envelope_len);
+//! // Returns 0 to accept the object, anything else to refuse it.
+//! int32_t osp_plugin_validate(const uint8_t *envelope, size_t envelope_len);
+//! ```
+//!
+//! Envelopes are passed in their wire encoding and are only valid for the
+//! duration of the call. Functions may be called from several threads at
+//! once.
+
+use std::ffi::{CStr, OsStr};
+use std::os::raw::c_char;
+
+use libloading::{Library, Symbol};
+
+use log::info;
+
+use tokio::io;
+
+use osp_protocol::Envelope;
+
+use crate::plugin::Plugin;
+
+/// The plugin ABI version this node implements.
+pub const ABI_VERSION: u32 = 1;
+
+type EnvelopeFn = unsafe extern "C" fn(*const u8, usize) -> i32;
+
+fn load_err(err: libloading::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+pub struct NativePlugin {
+    name: String,
+    handle: Option<EnvelopeFn>,
+    validate: Option<EnvelopeFn>,
+    // keeps the functions above loaded, must be dropped last
+    _library: Library,
+}
+
+impl NativePlugin {
+    /// Load the plugin at `path` and initialise it with `config`.
+    ///
+    /// # Safety
+    ///
+    /// Loading a library runs its initialisers, and the plugin is trusted to
+    /// implement the ABI described in the [module documentation](self)
+    /// correctly. Only load plugins you trust as much as the node itself.
+    pub unsafe fn load<P: AsRef<OsStr>>(path: P, config: &str) -> io::Result<Self> {
+        let library = Library::new(path.as_ref()).map_err(load_err)?;
+
+        let abi_version: Symbol<unsafe extern "C" fn() -> u32> = library.get(b"osp_plugin_abi_version\0").map_err(load_err)?;
+        let version = abi_version();
+        if version != ABI_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Plugin implements ABI version {version}, expected {ABI_VERSION}")
+            ));
+        }
+
+        let name: Symbol<unsafe extern "C" fn() -> *const c_char> = library.get(b"osp_plugin_name\0").map_err(load_err)?;
+        let name = CStr::from_ptr(name()).to_string_lossy().into_owned();
+
+        if let Ok(init) = library.get::<EnvelopeFn>(b"osp_plugin_init\0") {
+            let status = init(config.as_ptr(), config.len());
+            if status != 0 {
+                return Err(io::Error::other(
+                    format!("Plugin {name} failed to initialise with status {status}")
+                ));
+            }
+        }
+
+        let handle = library.get::<EnvelopeFn>(b"osp_plugin_handle\0").ok().map(|f| *f);
+        let validate = library.get::<EnvelopeFn>(b"osp_plugin_validate\0").ok().map(|f| *f);
+        info!("Loaded plugin {name} from {}", path.as_ref().to_string_lossy());
+
+        Ok(NativePlugin {
+            name,
+            handle,
+            validate,
+            _library: library,
+        })
+    }
+
+    fn call(&self, f: EnvelopeFn, envelope: &Envelope) -> io::Result<i32> {
+        let bytes = envelope.to_bytes()?;
+        // SAFETY: the library is kept loaded by self, and the plugin was
+        // trusted to follow the ABI when it was loaded
+        Ok(unsafe { f(bytes.as_ptr(), bytes.len()) })
+    }
+}
+
+impl Plugin for NativePlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn handles(&self) -> bool {
+        self.handle.is_some()
+    }
+
+    fn validates(&self) -> bool {
+        self.validate.is_some()
+    }
+
+    fn handle(&self, envelope: &Envelope) -> io::Result<()> {
+        let Some(handle) = self.handle else { return Ok(()) };
+        match self.call(handle, envelope)? {
+            0 => Ok(()),
+            status => Err(io::Error::other(format!("Plugin {} failed with status {status}", self.name))),
+        }
+    }
+
+    fn validate(&self, envelope: &Envelope) -> io::Result<()> {
+        let Some(validate) = self.validate else { return Ok(()) };
+        match self.call(validate, envelope)? {
+            0 => Ok(()),
+            status => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Refused by plugin {} with status {status}", self.name))),
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! # WASM Plugins
+//!
+//! Plugins compiled to WebAssembly and run in a wasmtime sandbox. A module
+//! gets no imports at all, so it can only compute on the bytes it is given,
+//! and every call runs in a fresh instance limited by [WasmLimits]. This makes
+//! WASM plugins the right choice for third-party processing of untrusted
+//! syndicated content, such as sanitization, translation or scoring.
+//!
+//! A module exports its linear memory as `memory` and the following
+//! functions, of which only `osp_alloc` is required:
+//!
+//! ```wat
+//! ;; Reserve `len` bytes for the input and return their address.
+//! (func (export "osp_alloc") (param $len i32) (result i32))
+//! ;; Process an object. Returns 0 on success.
+//! (func (export "osp_handle") (param $ptr i32) (param $len i32) (result i32))
+//! ;; Returns 0 to accept the object, anything else to refuse it.
+//! (func (export "osp_validate") (param $ptr i32) (param $len i32) (result i32))
+//! ;; Rewrite the payload. Returns the address of the new payload in the
+//! ;; upper 32 bits and its length in the lower 32, or a negative value on
+//! ;; failure.
+//! (func (export "osp_transform") (param $ptr i32) (param $len i32) (result i64))
+//! ```
+//!
+//! Every function is passed the object's payload. The envelope metadata is
+//! never exposed to or changed by the module.
+
+use std::path::Path;
+
+use log::info;
+
+use tokio::io;
+
+use wasmtime::{Config, Engine, Instance, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use osp_protocol::Envelope;
+
+use crate::plugin::Plugin;
+
+/// Resources a single call into a WASM plugin may use.
+#[derive(Clone, Debug)]
+pub struct WasmLimits {
+    /// Fuel available to each call, roughly the number of instructions it
+    /// may execute.
+    pub fuel: u64,
+    /// Maximum size of the module's linear memory, in bytes.
+    pub max_memory: usize,
+}
+
+impl Default for WasmLimits {
+    fn default() -> Self {
+        WasmLimits {
+            fuel: 10_000_000,
+            max_memory: 16 * 1024 * 1024,
+        }
+    }
+}
+
+fn wasm_err(err: wasmtime::Error) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+pub struct WasmPlugin {
+    name: String,
+    engine: Engine,
+    instance_pre: InstancePre<StoreLimits>,
+    limits: WasmLimits,
+    handles: bool,
+    validates: bool,
+    transforms: bool,
+}
+
+impl WasmPlugin {
+    /// Load the module at `path`, in binary or text format.
+    pub fn load<P: AsRef<Path>>(name: &str, path: P, limits: WasmLimits) -> io::Result<Self> {
+        let plugin = Self::from_bytes(name, &std::fs::read(path.as_ref())?, limits)?;
+        info!("Loaded WASM plugin {name} from {}", path.as_ref().display());
+        Ok(plugin)
+    }
+
+    /// Compile a module from its binary or text format.
+    pub fn from_bytes(name: &str, bytes: &[u8], limits: WasmLimits) -> io::Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(wasm_err)?;
+
+        let module = Module::new(&engine, bytes).map_err(wasm_err)?;
+        let exports = |name: &str| module.exports().any(|e| e.name() == name);
+        let (handles, validates, transforms) = (exports("osp_handle"), exports("osp_validate"), exports("osp_transform"));
+        if !exports("memory") || !exports("osp_alloc") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "WASM plugin must export memory and osp_alloc"));
+        }
+
+        // no host functions are linked, so a module with imports fails here
+        let instance_pre = Linker::new(&engine).instantiate_pre(&module).map_err(wasm_err)?;
+
+        Ok(WasmPlugin {
+            name: name.to_string(),
+            engine,
+            instance_pre,
+            limits,
+            handles,
+            validates,
+            transforms,
+        })
+    }
+
+    /// Instantiate the module in a fresh, limited store and copy `input` into
+    /// its memory, returning the instance and the input's address.
+    fn prepare(&self, input: &[u8]) -> io::Result<(Store<StoreLimits>, Instance, i32)> {
+        let limits = StoreLimitsBuilder::new().memory_size(self.limits.max_memory).build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.limits.fuel).map_err(wasm_err)?;
+
+        let instance = self.instance_pre.instantiate(&mut store).map_err(wasm_err)?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "osp_alloc").map_err(wasm_err)?;
+        let ptr = alloc.call(&mut store, input.len() as i32).map_err(wasm_err)?;
+
+        let memory = instance.get_memory(&mut store, "memory")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "WASM plugin does not export its memory"))?;
+        memory.write(&mut store, ptr as u32 as usize, input).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok((store, instance, ptr))
+    }
+
+    fn call_status(&self, export: &str, envelope: &Envelope) -> io::Result<i32> {
+        let (mut store, instance, ptr) = self.prepare(&envelope.payload)?;
+        let f = instance.get_typed_func::<(i32, i32), i32>(&mut store, export).map_err(wasm_err)?;
+        f.call(&mut store, (ptr, envelope.payload.len() as i32)).map_err(wasm_err)
+    }
+}
+
+impl Plugin for WasmPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn handles(&self) -> bool {
+        self.handles
+    }
+
+    fn validates(&self) -> bool {
+        self.validates
+    }
+
+    fn handle(&self, envelope: &Envelope) -> io::Result<()> {
+        match self.call_status("osp_handle", envelope)? {
+            0 => Ok(()),
+            status => Err(io::Error::other(format!("Plugin {} failed with status {status}", self.name))),
+        }
+    }
+
+    fn validate(&self, envelope: &Envelope) -> io::Result<()> {
+        match self.call_status("osp_validate", envelope)? {
+            0 => Ok(()),
+            status => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Refused by plugin {} with status {status}", self.name))),
+        }
+    }
+
+    fn transforms(&self) -> bool {
+        self.transforms
+    }
+
+    fn transform(&self, envelope: Envelope) -> io::Result<Envelope> {
+        let (mut store, instance, ptr) = self.prepare(&envelope.payload)?;
+        let f = instance.get_typed_func::<(i32, i32), i64>(&mut store, "osp_transform").map_err(wasm_err)?;
+        let result = f.call(&mut store, (ptr, envelope.payload.len() as i32)).map_err(wasm_err)?;
+        if result < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Plugin {} failed to transform the object", self.name)));
+        }
+
+        let (out_ptr, out_len) = ((result >> 32) as u32 as usize, result as u32 as usize);
+        let memory = instance.get_memory(&mut store, "memory")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "WASM plugin does not export its memory"))?;
+        let payload = memory.data(&store)
+            .get(out_ptr..out_ptr + out_len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "WASM plugin returned a payload outside its memory"))?
+            .to_vec();
+        Ok(Envelope { payload, ..envelope })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_protocol::Envelope;
+
+    use crate::plugin::Plugin;
+    use crate::plugin::wasm::{WasmLimits, WasmPlugin};
+
+    const MODULE: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "osp_alloc") (param i32) (result i32) i32.const 1024)
+            ;; replace the first byte with a '*'
+            (func (export "osp_transform") (param $ptr i32) (param $len i32) (result i64)
+                (i32.store8 (local.get $ptr) (i32.const 42))
+                (i64.or
+                    (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                    (i64.extend_i32_u (local.get $len))))
+            ;; never returns
+            (func (export "osp_validate") (param i32 i32) (result i32)
+                (loop $spin (br $spin))
+                i32.const 0))
+    "#;
+
+    #[test]
+    fn test_wasm_plugin() -> io::Result<()> {
+        let plugin = WasmPlugin::from_bytes("test", MODULE.as_bytes(), WasmLimits::default())?;
+        let envelope = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), b"hello".to_vec());
+
+        assert!(plugin.transforms() && plugin.validates() && !plugin.handles());
+        assert_eq!(plugin.transform(envelope.clone())?.payload, b"*ello");
+        // runs out of fuel instead of hanging the node
+        assert!(plugin.validate(&envelope).is_err());
+        Ok(())
+    }
+}
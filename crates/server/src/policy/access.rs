@@ -0,0 +1,223 @@
+//! # Access Policy
+//!
+//! Static allow and deny lists for the peers of a node, configured with the
+//! node instead of stored like [federation rules](crate::federation):
+//!
+//! - Hostnames, exactly (`peer.example`) or by suffix (`*.example`, which
+//!   matches every subdomain of `example` but not `example` itself). They
+//!   are checked in both directions, before the federation rules and before
+//!   a guest's challenge record is looked up.
+//! - Networks in CIDR notation (`10.0.0.0/8`, `2001:db8::/32`), which the
+//!   address of an inbound connection is checked against as soon as it is
+//!   accepted.
+//!
+//! Denying wins over allowing. Once any hostname (or network) is allowed,
+//! every other one is refused.
+
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use tokio::io;
+
+/// A range of IP addresses, parsed from CIDR notation such as `10.0.0.0/8`.
+/// A plain address is a network of that single address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn new(addr: IpAddr, prefix: u8) -> io::Result<Self> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > max {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Prefix /{prefix} is longer than {max} bits")));
+        }
+        Ok(IpNetwork { addr, prefix })
+    }
+
+    /// Whether `ip` is in the network. IPv4 addresses mapped into IPv6 are
+    /// compared as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid network {s:?}"));
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix = prefix.unwrap_or(if addr.is_ipv4() { 32 } else { 128 });
+        IpNetwork::new(addr, prefix)
+    }
+}
+
+impl Display for IpNetwork {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum HostPattern {
+    Exact(String),
+    /// Matches hostnames ending in `.{suffix}`.
+    Suffix(String),
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Self {
+        let pattern = normalize(pattern);
+        match pattern.strip_prefix("*.") {
+            Some(suffix) => HostPattern::Suffix(suffix.to_string()),
+            None => HostPattern::Exact(pattern),
+        }
+    }
+
+    fn matches(&self, hostname: &str) -> bool {
+        match self {
+            HostPattern::Exact(exact) => hostname == exact,
+            HostPattern::Suffix(suffix) => hostname.strip_suffix(suffix.as_str()).is_some_and(|rest| rest.ends_with('.')),
+        }
+    }
+}
+
+fn normalize(hostname: &str) -> String {
+    hostname.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Which peers a node accepts, see the [module](self) docs. The default
+/// policy accepts every peer.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccessPolicy {
+    allowed_hosts: Vec<HostPattern>,
+    denied_hosts: Vec<HostPattern>,
+    allowed_networks: Vec<IpNetwork>,
+    denied_networks: Vec<IpNetwork>,
+}
+
+impl AccessPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only accept hosts matching `pattern`, and any others allowed. A
+    /// pattern starting with `*.` matches every subdomain.
+    pub fn allow_host(mut self, pattern: &str) -> Self {
+        self.allowed_hosts.push(HostPattern::parse(pattern));
+        self
+    }
+
+    /// Refuse hosts matching `pattern`.
+    pub fn deny_host(mut self, pattern: &str) -> Self {
+        self.denied_hosts.push(HostPattern::parse(pattern));
+        self
+    }
+
+    /// Only accept connections from `network`, and any others allowed.
+    pub fn allow_network(mut self, network: IpNetwork) -> Self {
+        self.allowed_networks.push(network);
+        self
+    }
+
+    /// Refuse connections from `network`.
+    pub fn deny_network(mut self, network: IpNetwork) -> Self {
+        self.denied_networks.push(network);
+        self
+    }
+
+    /// Check `hostname` against the policy, returning why it is refused if
+    /// it isn't accepted.
+    pub fn check_host(&self, hostname: &str) -> Result<(), String> {
+        let normalized = normalize(hostname);
+        let matches = |patterns: &[HostPattern]| patterns.iter().any(|pattern| pattern.matches(&normalized));
+        if matches(&self.denied_hosts) {
+            return Err(format!("{hostname} is denied access"));
+        }
+        if !self.allowed_hosts.is_empty() && !matches(&self.allowed_hosts) {
+            return Err(format!("{hostname} is not allowed access"));
+        }
+        Ok(())
+    }
+
+    /// Check a connection from `ip` against the policy, returning why it is
+    /// refused if it isn't accepted.
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), String> {
+        if let Some(network) = self.denied_networks.iter().find(|network| network.contains(ip)) {
+            return Err(format!("Connections from {network} are denied"));
+        }
+        if !self.allowed_networks.is_empty() && !self.allowed_networks.iter().any(|network| network.contains(ip)) {
+            return Err(format!("Connections from {ip} are not allowed"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use tokio::io;
+
+    use crate::policy::access::{AccessPolicy, IpNetwork};
+
+    #[test]
+    fn test_host_patterns() {
+        let policy = AccessPolicy::new()
+            .allow_host("*.example")
+            .allow_host("Peer.Test")
+            .deny_host("bad.example");
+        assert!(policy.check_host("a.example").is_ok());
+        assert!(policy.check_host("deep.sub.example.").is_ok());
+        assert!(policy.check_host("peer.test").is_ok());
+        assert!(policy.check_host("example").is_err());
+        assert!(policy.check_host("notexample").is_err());
+        assert!(policy.check_host("BAD.example").is_err());
+        assert!(policy.check_host("other.test").is_err());
+        assert!(AccessPolicy::new().check_host("any.test").is_ok());
+    }
+
+    #[test]
+    fn test_networks() -> io::Result<()> {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let policy = AccessPolicy::new()
+            .allow_network("10.0.0.0/8".parse()?)
+            .allow_network("2001:db8::/32".parse()?)
+            .deny_network("10.1.2.3".parse()?);
+        assert!(policy.check_ip(ip("10.200.0.1")).is_ok());
+        assert!(policy.check_ip(ip("::ffff:10.0.0.1")).is_ok());
+        assert!(policy.check_ip(ip("2001:db8:1::1")).is_ok());
+        assert!(policy.check_ip(ip("10.1.2.3")).is_err());
+        assert!(policy.check_ip(ip("192.168.0.1")).is_err());
+        assert!(policy.check_ip(ip("2001:db9::1")).is_err());
+
+        assert!("0.0.0.0/0".parse::<IpNetwork>()?.contains(ip("203.0.113.9")));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("10.0.0/8".parse::<IpNetwork>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! # GeoIP Connection Policy
+//!
+//! Looks up the country inbound connections come from in a MaxMind GeoIP2 or
+//! GeoLite2 country (or city) database, and allows or denies them by country.
+//! Countries are ISO 3166-1 alpha-2 codes such as `DE` or `US`, compared
+//! case-insensitively.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::Path;
+
+use log::info;
+
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+
+use tokio::io;
+
+pub struct GeoIpPolicy {
+    reader: Reader<Vec<u8>>,
+    countries: Countries,
+}
+
+/// The countries connections are allowed or denied from, uppercase.
+#[derive(Default)]
+struct Countries {
+    allowed: HashSet<String>,
+    denied: HashSet<String>,
+}
+
+impl Countries {
+    fn check(&self, country: Option<&str>) -> Result<(), String> {
+        let Some(country) = country else {
+            return match self.allowed.is_empty() {
+                true => Ok(()),
+                false => Err("Country is unknown".to_string()),
+            };
+        };
+
+        let normalized = country.to_ascii_uppercase();
+        if self.denied.contains(&normalized) || (!self.allowed.is_empty() && !self.allowed.contains(&normalized)) {
+            return Err(format!("Connections from {country} are not accepted"));
+        }
+        Ok(())
+    }
+}
+
+fn to_io_error(err: MaxMindDBError) -> io::Error {
+    match err {
+        MaxMindDBError::IoError(msg) => io::Error::other(msg),
+        err => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+    }
+}
+
+impl GeoIpPolicy {
+    /// Load the database at `path`. Without any countries allowed or denied,
+    /// the policy only labels connections with their country.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let reader = Reader::open_readfile(path.as_ref()).map_err(to_io_error)?;
+        info!("Loaded GeoIP database {} ({})", path.as_ref().display(), reader.metadata.database_type);
+        Ok(Self {
+            reader,
+            countries: Countries::default(),
+        })
+    }
+
+    /// Only accept connections from `country`. Once any country is allowed,
+    /// connections from other countries, or whose country is unknown, are
+    /// refused.
+    pub fn allow(mut self, country: &str) -> Self {
+        self.countries.allowed.insert(country.to_ascii_uppercase());
+        self
+    }
+
+    /// Refuse connections from `country`.
+    pub fn deny(mut self, country: &str) -> Self {
+        self.countries.denied.insert(country.to_ascii_uppercase());
+        self
+    }
+
+    /// The country `ip` is located in, if the database knows it.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        record.country
+            .or(record.registered_country)
+            .and_then(|country| country.iso_code)
+            .map(str::to_string)
+    }
+
+    /// Check a connection from `country` against the policy, returning why
+    /// it is refused if it isn't allowed.
+    pub fn check(&self, country: Option<&str>) -> Result<(), String> {
+        self.countries.check(country)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::policy::geoip::Countries;
+
+    #[test]
+    fn test_countries() {
+        let denied = Countries { allowed: HashSet::new(), denied: HashSet::from(["RU".to_string()]) };
+        assert!(denied.check(Some("de")).is_ok() && denied.check(None).is_ok());
+        assert!(denied.check(Some("ru")).is_err());
+
+        // once a country is allowed, every other one is refused
+        let allowed = Countries { allowed: HashSet::from(["DE".to_string()]), denied: HashSet::new() };
+        assert!(allowed.check(Some("De")).is_ok());
+        assert!(allowed.check(Some("US")).is_err() && allowed.check(None).is_err());
+    }
+}
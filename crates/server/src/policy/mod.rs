@@ -0,0 +1,125 @@
+//! # Policies
+//!
+//! Content policies are rules every object must satisfy for the node to
+//! accept it from a peer or to push it on to one. Unlike
+//! [middleware](crate::middleware), content policies are checked in both
+//! directions, so a node never relays content it would refuse to receive.
+//!
+//! Peers can be restricted by hostname and by the network they connect
+//! from, see [access]. With the `geoip` feature, inbound connections can
+//! also be restricted by the country they come from, see [geoip].
+//!
+//! Objects carry the trace they were pushed in to the peers they are pushed
+//! to, which reveals to them how they travelled. [TracePropagation] limits
+//! which peers that is shared with.
+
+use std::collections::HashSet;
+
+use osp_protocol::Envelope;
+
+pub mod access;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+
+/// Which peers trace contexts are exchanged with, both the one a push is
+/// sent in and the `trace_parent` of its envelope.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TracePropagation {
+    /// Exchange trace contexts with every peer.
+    #[default]
+    Propagate,
+    /// Only exchange trace contexts with members of the node's cluster,
+    /// stripping them from objects pushed to or received from other peers.
+    ClusterOnly,
+    /// Never send trace contexts and ignore those received.
+    Strip,
+}
+
+pub trait ContentPolicy: Send + Sync {
+    /// Check `envelope` against the policy, returning why it is refused if it
+    /// doesn't satisfy it.
+    fn check(&self, envelope: &Envelope) -> Result<(), String>;
+}
+
+/// Requirements on the license and attribution metadata of objects.
+/// Licenses are compared as case-insensitive SPDX identifiers.
+#[derive(Clone, Debug, Default)]
+pub struct LicensePolicy {
+    require_license: bool,
+    require_attribution: bool,
+    allowed: HashSet<String>,
+    denied: HashSet<String>,
+}
+
+impl LicensePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuse objects that don't declare a license.
+    pub fn require_license(mut self) -> Self {
+        self.require_license = true;
+        self
+    }
+
+    /// Refuse objects without an attribution.
+    pub fn require_attribution(mut self) -> Self {
+        self.require_attribution = true;
+        self
+    }
+
+    /// Only accept objects under `license`. Once any license is allowed,
+    /// objects under other licenses are refused.
+    pub fn allow(mut self, license: &str) -> Self {
+        self.allowed.insert(license.to_ascii_lowercase());
+        self
+    }
+
+    /// Refuse objects under `license`, e.g. one incompatible with how the
+    /// node republishes content.
+    pub fn deny(mut self, license: &str) -> Self {
+        self.denied.insert(license.to_ascii_lowercase());
+        self
+    }
+}
+
+impl ContentPolicy for LicensePolicy {
+    fn check(&self, envelope: &Envelope) -> Result<(), String> {
+        if self.require_attribution && envelope.attribution.as_deref().is_none_or(str::is_empty) {
+            return Err("Object has no attribution".to_string());
+        }
+
+        let Some(license) = &envelope.license else {
+            return match self.require_license || !self.allowed.is_empty() {
+                true => Err("Object does not declare a license".to_string()),
+                false => Ok(()),
+            };
+        };
+
+        let normalized = license.to_ascii_lowercase();
+        if self.denied.contains(&normalized) || (!self.allowed.is_empty() && !self.allowed.contains(&normalized)) {
+            return Err(format!("License {license} is not accepted"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use osp_protocol::Envelope;
+
+    use crate::policy::{ContentPolicy, LicensePolicy};
+
+    #[test]
+    fn test_license_policy() {
+        let policy = LicensePolicy::new().require_attribution().allow("CC-BY-4.0").allow("CC0-1.0");
+        let envelope = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![]);
+
+        assert!(policy.check(&envelope.clone().with_attribution("Author").with_license("cc-by-4.0")).is_ok());
+        assert!(policy.check(&envelope.clone().with_license("CC-BY-4.0")).is_err());
+        assert!(policy.check(&envelope.clone().with_attribution("Author").with_license("CC-BY-NC-4.0")).is_err());
+        assert!(policy.check(&envelope.with_attribution("Author")).is_err());
+    }
+}
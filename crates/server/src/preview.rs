@@ -0,0 +1,84 @@
+//! # Broadcast Preview
+//!
+//! [preview_broadcast](crate::OSProtocolNode::preview_broadcast) works out
+//! who an object would be syndicated to if it were published, without
+//! storing or sending anything: the subscribers of its type and topic, the
+//! relays of a [fan-out](crate::fanout) tree and the subscribers they
+//! deliver to, and the push targets. For each it tells how the peer would
+//! get the object, or why it wouldn't.
+//!
+//! The preview covers what the node decides on its own. The sensitivity
+//! preferences of a peer are only learned when connecting to it, and the
+//! peer may still refuse the object once it arrives.
+
+use uuid::Uuid;
+
+use osp_protocol::OSPUrl;
+
+use crate::subscription::DeliveryQos;
+
+/// Who an object would be syndicated to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BroadcastPreview {
+    pub object_id: Uuid,
+    pub recipients: Vec<Recipient>,
+}
+
+impl BroadcastPreview {
+    /// The peers that would receive the object.
+    pub fn receivers(&self) -> impl Iterator<Item = &Recipient> {
+        self.recipients.iter().filter(|recipient| recipient.refusal.is_none())
+    }
+
+    /// The peers the object would be held back from.
+    pub fn refused(&self) -> impl Iterator<Item = &Recipient> {
+        self.recipients.iter().filter(|recipient| recipient.refusal.is_some())
+    }
+}
+
+/// A peer an object is syndicated to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recipient {
+    pub url: OSPUrl,
+    pub route: Route,
+    /// How the object would be delivered.
+    pub qos: DeliveryQos,
+    /// Why the peer wouldn't receive the object, if it wouldn't.
+    pub refusal: Option<Refusal>,
+}
+
+/// Why a peer is a recipient.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Route {
+    /// It subscribed to the type of the object.
+    Subscription,
+    /// It is a relay at the top of the fan-out tree of the type.
+    Relay,
+    /// It subscribed to the type, and the relay with hostname `relay`
+    /// delivers to it.
+    Delegated { relay: String },
+    /// It is a [push target](crate::builder::OSProtocolNodeBuilder::push_target).
+    PushTarget,
+    /// It is a child of a delegation this node relays along.
+    Child,
+}
+
+/// Why a peer wouldn't receive an object.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Refusal {
+    /// The object originates from the peer.
+    Origin,
+    /// The peer is outside the [audience](osp_protocol::Audience) of the
+    /// object.
+    OutsideAudience,
+    /// The access policy or the federation rules don't let the node
+    /// federate with the peer.
+    Denied(String),
+    /// The object was taken down.
+    TakenDown,
+    /// A content policy refuses to send the object.
+    Policy(String),
+    /// Converting the object for sending, or translating it to a version
+    /// of its type the peer understands, failed.
+    Conversion(String),
+}
@@ -0,0 +1,118 @@
+//! # Prometheus Export
+//!
+//! With the `prometheus` feature, [Prometheus::install] serves the metrics
+//! a node reports through the [metrics](::metrics) facade to Prometheus, so
+//! a node can be scraped without running a collector next to it:
+//!
+//! ```ignore
+//! let prometheus = Prometheus::install("0.0.0.0:9464".parse()?)?;
+//! node.listen().await?;
+//! ```
+//!
+//! The listener answers scrapes on `/metrics`, and `/health` with `OK`.
+//! Embedders already running an HTTP server can serve
+//! [render](Prometheus::render) from it instead, with a recorder installed
+//! by [install_recorder](Prometheus::install_recorder).
+//!
+//! Besides those of the connections, deliveries, handlers and storage of the
+//! node, the metrics include
+//!
+//! - `osp_handshakes_total`, by `direction` and `outcome`, the kind of error
+//!   failed handshakes failed with
+//! - `osp_challenge_lookup_duration_seconds`, how long looking up the
+//!   challenge records of guests took
+//! - `osp_transfer_bytes_total`, the bytes read (`in`) and written (`out`)
+//!   on connections, TLS records included
+
+use std::net::SocketAddr;
+
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle, PrometheusRecorder};
+
+use tokio::io;
+
+/// Buckets of the `_seconds` histograms, from DNS lookups answered from a
+/// cache to handlers running for a minute.
+const SECONDS_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Prometheus export of the metrics of the nodes in this process, see the
+/// [module](self) docs.
+#[derive(Clone)]
+pub struct Prometheus {
+    handle: PrometheusHandle,
+}
+
+impl Prometheus {
+    /// Start serving scrapes on `addr`. Installs the global metrics
+    /// recorder, so it fails with
+    /// [AlreadyExists](io::ErrorKind::AlreadyExists) if another recorder is
+    /// installed already. Must be called from within a Tokio runtime.
+    pub fn install(addr: SocketAddr) -> io::Result<Prometheus> {
+        let (recorder, exporter) = builder()?.with_http_listener(addr).build().map_err(build_err)?;
+        let prometheus = Prometheus { handle: recorder.handle() };
+        ::metrics::set_global_recorder(recorder)
+            .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "A metrics recorder is already installed"))?;
+        tokio::spawn(exporter);
+        Ok(prometheus)
+    }
+
+    /// Install the global metrics recorder without a listener, for metrics
+    /// [rendered](Self::render) by the embedder.
+    pub fn install_recorder() -> io::Result<Prometheus> {
+        let recorder = recorder()?;
+        let prometheus = Prometheus { handle: recorder.handle() };
+        ::metrics::set_global_recorder(recorder)
+            .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "A metrics recorder is already installed"))?;
+        Ok(prometheus)
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        self.handle.render()
+    }
+}
+
+fn builder() -> io::Result<PrometheusBuilder> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_string()), SECONDS_BUCKETS)
+        .map_err(build_err)
+}
+
+fn recorder() -> io::Result<PrometheusRecorder> {
+    Ok(builder()?.build_recorder())
+}
+
+fn build_err(e: BuildError) -> io::Error {
+    match e {
+        BuildError::FailedToSetGlobalRecorder(_) => io::Error::new(io::ErrorKind::AlreadyExists, e.to_string()),
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io;
+
+    use crate::metrics;
+    use crate::prometheus::recorder;
+
+    #[test]
+    fn test_render() -> io::Result<()> {
+        let recorder = recorder()?;
+        ::metrics::with_local_recorder(&recorder, || {
+            metrics::handshake("inbound", Ok(()));
+            metrics::handshake("inbound", Err(io::ErrorKind::PermissionDenied));
+            metrics::challenge_lookup(Duration::from_millis(20), true);
+            metrics::transfer_bytes("in", 512);
+        });
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"osp_handshakes_total{direction="inbound",outcome="ok"} 1"#), "{rendered}");
+        assert!(rendered.contains(r#"osp_handshakes_total{direction="inbound",outcome="PermissionDenied"} 1"#));
+        assert!(rendered.contains(r#"osp_transfer_bytes_total{direction="in"} 512"#));
+        // a histogram with buckets rather than a summary
+        assert!(rendered.contains(r#"osp_challenge_lookup_duration_seconds_bucket{outcome="found",le="0.025"} 1"#));
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+//! # Provenance
+//!
+//! Nodes sign the objects they publish as [SignedData], with the key they
+//! publish in their `_osp` DNS record, see
+//! [sign](crate::OSProtocolNode::sign). A node built with
+//! [signed_data_type](crate::OSProtocolNodeBuilder::signed_data_type) checks
+//! every signed object of that type pushed to it against the records of its
+//! origin, and refuses those that don't verify: an object signed by another
+//! host than the one it originates from, signed in the future, or whose
+//! signature matches none of the origin's unexpired keys. Handlers can check
+//! signed objects themselves with
+//! [verify_signed](crate::OSProtocolNode::verify_signed).
+
+use tokio::io;
+
+use osp_data::{Data, Error};
+use osp_protocol::Envelope;
+
+pub use osp_data::signed::{Attestation, SignatureAlgorithm, SignedData};
+
+use crate::connection::challenge::ChallengeKey;
+use crate::crypto::{Ed25519Key, PrivateKey};
+
+/// How far in the future objects may be signed, for the clocks of nodes
+/// that don't quite agree.
+pub const MAX_CLOCK_SKEW: u64 = 5 * 60;
+
+/// The key to sign objects with.
+#[derive(Clone, Copy)]
+pub enum Signer<'a> {
+    Rsa(&'a PrivateKey),
+    Ed25519(&'a Ed25519Key),
+}
+
+impl Signer<'_> {
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        match self {
+            Signer::Rsa(_) => SignatureAlgorithm::Rsa,
+            Signer::Ed25519(_) => SignatureAlgorithm::Ed25519,
+        }
+    }
+}
+
+/// Sign `data` as published by `origin` at the unix timestamp `signed_at`
+/// (seconds).
+pub fn sign<T: Data>(data: T, origin: &str, signed_at: u64, signer: Signer) -> io::Result<SignedData<T>> {
+    let mut signed = SignedData::unsigned(data, origin.to_ascii_lowercase(), signed_at, signer.algorithm());
+    let bytes = signed.signed_bytes()?;
+    signed.signature = match signer {
+        Signer::Rsa(key) => key.sign(&bytes)?,
+        Signer::Ed25519(key) => key.sign(&bytes)?,
+    };
+    Ok(signed)
+}
+
+/// Whether `attestation` carries a valid signature made with the private
+/// half of `key`. Keys of another algorithm than the signature never match.
+pub fn verify(attestation: &Attestation, key: &ChallengeKey) -> io::Result<bool> {
+    match (attestation.algorithm, key) {
+        (SignatureAlgorithm::Rsa, ChallengeKey::Rsa(key)) => key.verify(&attestation.signed_bytes, &attestation.signature),
+        (SignatureAlgorithm::Ed25519, ChallengeKey::Ed25519(key)) => key.verify(&attestation.signed_bytes, &attestation.signature),
+        _ => Ok(false),
+    }
+}
+
+/// Reads the attestation of a signed object without knowing its type.
+pub(crate) type Attester = fn(&Envelope) -> Result<Attestation, Error>;
+
+pub(crate) fn attester<T: Data>() -> Attester {
+    |envelope| SignedData::<T>::from_envelope(envelope)?.attestation()
+}
+
+/// Check that `attestation` is made by `origin`, and not in the future as of
+/// `now`, before its signature is checked.
+pub(crate) fn check_attribution(attestation: &Attestation, origin: &str, now: u64) -> io::Result<()> {
+    if !attestation.origin.eq_ignore_ascii_case(origin) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Object is signed by {} but originates from {origin}", attestation.origin),
+        ));
+    }
+    if attestation.signed_at > now + MAX_CLOCK_SKEW {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Object is signed in the future, at {}", attestation.signed_at)));
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "dns-auth"))]
+mod tests {
+    use tokio::io;
+
+    use osp_data::Data;
+    use osp_data::standard::Article;
+
+    use crate::OSProtocolNode;
+    use crate::connection::challenge::{ChallengeKey, ChallengeRecord};
+    use crate::connection::dns::ChallengeResolver;
+    use crate::crypto::{Ed25519Key, PrivateKey};
+    use crate::embargo;
+    use crate::provenance::{self, SignedData, Signer};
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_provenance() -> io::Result<()> {
+        let (key, ed25519) = (PrivateKey::generate(1024)?, Ed25519Key::generate()?);
+        let resolver = ChallengeResolver::default()
+            .override_record("blog.test", &ChallengeRecord::ed25519(&ed25519.public_key()?, None)?)
+            .override_record("node.test", &ChallengeRecord::rsa(&key.public_key()?, None)?);
+        let node = OSProtocolNode::builder()
+            .bind_to("127.0.0.1:57401".parse().unwrap())
+            .hostname("node.test".to_string())
+            .challenge_resolver(resolver)
+            .signed_data_type::<Article>()
+            .data_store(MemoryStore::new())
+            .private_key(key.clone())
+            .build()?;
+        let article = Article { title: "Hello".to_string(), ..Article::default() };
+        let runtime = tokio::runtime::Runtime::new()?;
+
+        let signed = node.sign(article.clone())?;
+        assert_eq!(signed.origin, "node.test");
+        assert!(provenance::verify(&signed.attestation()?, &ChallengeKey::Rsa(key.public_key()?))?);
+        assert!(!provenance::verify(&signed.attestation()?, &ChallengeKey::Ed25519(ed25519.public_key()?))?);
+        runtime.block_on(node.check_signature(&signed.to_envelope("node.test".to_string())?))?;
+        // relaying it doesn't change where it comes from
+        assert!(runtime.block_on(node.check_signature(&signed.to_envelope("relay.test".to_string())?)).is_err());
+
+        let blog = provenance::sign(article.clone(), "blog.test", embargo::now(), Signer::Ed25519(&ed25519))?;
+        runtime.block_on(node.verify_signed(&blog))?;
+        let altered = SignedData { data: Article { title: "Altered".to_string(), ..article.clone() }, ..blog.clone() };
+        assert!(runtime.block_on(node.verify_signed(&altered)).is_err());
+        let future = provenance::sign(article.clone(), "blog.test", embargo::now() + 3600, Signer::Ed25519(&ed25519))?;
+        assert!(runtime.block_on(node.check_signature(&future.to_envelope("blog.test".to_string())?)).is_err());
+        // signed with a key blog.test doesn't publish
+        let forged = provenance::sign(article.clone(), "blog.test", embargo::now(), Signer::Rsa(&key))?;
+        assert!(runtime.block_on(node.verify_signed(&forged)).is_err());
+
+        // objects of types the node doesn't check signatures of pass
+        runtime.block_on(node.check_signature(&article.to_envelope("blog.test".to_string())?))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+//! # Recording Connections
+//!
+//! A node can record the packets of its connections with selected peers to
+//! files, with the secrets they hold blanked out, for going over an interop
+//! incident after the fact, see [osp_protocol::recording]. Recordings are
+//! written to the directory set with
+//! [recordings](crate::OSProtocolNodeBuilder::recordings), one file
+//! `<peer>-<unix millis>.jsonl` per connection, for the peers set with
+//! [record_peer](crate::OSProtocolNodeBuilder::record_peer) or
+//! [OSProtocolNode::record_peer](crate::OSProtocolNode::record_peer) while
+//! the node runs.
+//!
+//! Connections the node opens are recorded from the start if the peer's
+//! hostname is recorded. Guests haven't identified yet when their
+//! connection is accepted, so connections from them are recorded from the
+//! start if their address is recorded, and from the transfer phase if their
+//! hostname is.
+//!
+//! A recording can be fed back through the decoding and dispatch of a node,
+//! see [OSProtocolNode::replay_recording](crate::OSProtocolNode::replay_recording).
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use tokio::io;
+
+use osp_protocol::packet::transfer::TransferPacketHostToGuest;
+use osp_protocol::recording::Recorder;
+
+/// What came of [replaying](crate::OSProtocolNode::replay_recording) a
+/// recording.
+pub struct RecordingReplay {
+    /// How many packets the guest sent were fed to the node.
+    pub packets: usize,
+    /// The packets the node answered with, in order.
+    pub answers: Vec<TransferPacketHostToGuest>,
+    /// What the connection failed with, if it did.
+    pub error: Option<io::Error>,
+}
+
+/// The directory a node records to and the peers it records.
+#[derive(Default)]
+pub(crate) struct Recordings {
+    directory: Option<PathBuf>,
+    peers: RwLock<HashSet<String>>,
+}
+
+impl Recordings {
+    pub(crate) fn new(directory: Option<PathBuf>, peers: HashSet<String>) -> Self {
+        Recordings { directory, peers: RwLock::new(peers) }
+    }
+
+    pub(crate) fn directory(&self) -> Option<&Path> {
+        self.directory.as_deref()
+    }
+
+    pub(crate) fn peers(&self) -> Vec<String> {
+        let mut peers = self.peers.read().unwrap().iter().cloned().collect::<Vec<_>>();
+        peers.sort();
+        peers
+    }
+
+    pub(crate) fn add(&self, peer: &str) {
+        self.peers.write().unwrap().insert(peer.to_ascii_lowercase());
+    }
+
+    pub(crate) fn remove(&self, peer: &str) -> bool {
+        self.peers.write().unwrap().remove(&peer.to_ascii_lowercase())
+    }
+
+    /// A recorder for a new connection with `peer`, a hostname or an
+    /// address, if it is recorded. A recording that can't be created is
+    /// logged, the connection goes ahead unrecorded.
+    pub(crate) fn recorder(&self, peer: &str) -> Option<Recorder> {
+        let directory = self.directory.as_ref()?;
+        let peer = peer.to_ascii_lowercase();
+        if !self.peers.read().unwrap().contains(&peer) {
+            return None;
+        }
+        // colons of IPv6 addresses aren't allowed in every file system
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let path = directory.join(format!("{}-{millis}.jsonl", peer.replace(':', "_")));
+        Recorder::create(&path)
+            .inspect_err(|e| warn!("Unable to record connection with {peer} to {}: {e}", path.display()))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::recording::Recordings;
+
+    #[test]
+    fn test_recordings() {
+        let directory = std::env::temp_dir().join(format!("osp-recordings-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&directory).unwrap();
+        let recordings = Recordings::new(Some(directory.clone()), HashSet::from(["peer.test".to_string()]));
+        assert!(recordings.recorder("Peer.test").is_some());
+        assert!(recordings.recorder("other.test").is_none());
+        recordings.add("::1");
+        assert!(recordings.recorder("::1").is_some());
+        assert!(recordings.remove("PEER.test"));
+        assert_eq!(recordings.peers(), ["::1"]);
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 2);
+        assert!(Recordings::new(None, HashSet::from(["peer.test".to_string()])).recorder("peer.test").is_none());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
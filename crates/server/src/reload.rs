@@ -0,0 +1,104 @@
+//! # Reloading
+//!
+//! A few settings of a node can be changed while it runs, without dropping
+//! the connections it has open: which peers it accepts, whether it only
+//! federates with hosts allowed by a [federation rule](crate::federation),
+//! the targets it pushes its objects to and the limits on inbound
+//! connections. Pass the
+//! new [LiveSettings] to [reload](crate::OSProtocolNode::reload), and the
+//! node applies them to the connections it accepts and the objects it
+//! publishes from then on. Observers of the node's
+//! [events](crate::OSProtocolNode::events) are told with
+//! [NodeEvent::ConfigReloaded](crate::events::NodeEvent::ConfigReloaded).
+//!
+//! With the `config` feature, a node set up from a config file can
+//! [watch](crate::OSProtocolNode::watch_config_file) it and reload whenever
+//! it changes or the process receives `SIGHUP`.
+
+use std::sync::Arc;
+
+use osp_protocol::OSPUrl;
+
+use crate::builder::ConfigProblem;
+use crate::connection::registry::{ConnectionLimits, RateLimit, RateLimiter};
+use crate::policy::access::AccessPolicy;
+
+/// The settings of a node that can be [reloaded](self).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LiveSettings {
+    pub access: AccessPolicy,
+    /// See [allowlist_only](crate::builder::OSProtocolNodeBuilder::allowlist_only).
+    pub allowlist_only: bool,
+    /// See [push_target](crate::builder::OSProtocolNodeBuilder::push_target).
+    pub push_targets: Vec<OSPUrl>,
+    /// See [ConnectionLimits].
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl LiveSettings {
+    /// Why the settings can't be applied, if they can't.
+    pub(crate) fn problems(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        if self.max_connections == Some(0) {
+            problems.push(ConfigProblem::ZeroMaxConnections);
+        }
+        if self.max_connections_per_ip == Some(0) {
+            problems.push(ConfigProblem::ZeroMaxConnectionsPerIp);
+        }
+        if self.rate_limit.is_some_and(|limit| !limit.is_valid()) {
+            problems.push(ConfigProblem::InvalidRateLimit);
+        }
+        problems
+    }
+}
+
+/// The settings a node currently runs with.
+pub(crate) struct Live {
+    pub(crate) access: AccessPolicy,
+    pub(crate) allowlist_only: bool,
+    pub(crate) push_targets: Vec<OSPUrl>,
+    pub(crate) connection_limits: ConnectionLimits,
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl Live {
+    pub(crate) fn new(access: AccessPolicy, allowlist_only: bool, push_targets: Vec<OSPUrl>, connection_limits: ConnectionLimits) -> Self {
+        Live {
+            access,
+            allowlist_only,
+            push_targets,
+            connection_limits,
+            rate_limiter: connection_limits.rate_limit.map(|limit| Arc::new(RateLimiter::new(limit))),
+        }
+    }
+
+    pub(crate) fn settings(&self) -> LiveSettings {
+        LiveSettings {
+            access: self.access.clone(),
+            allowlist_only: self.allowlist_only,
+            push_targets: self.push_targets.clone(),
+            max_connections: self.connection_limits.max_connections,
+            max_connections_per_ip: self.connection_limits.max_connections_per_ip,
+            rate_limit: self.connection_limits.rate_limit,
+        }
+    }
+
+    /// Switch to `settings`. The addresses the rate limiter tracks are
+    /// kept if the rate limit stays the same.
+    pub(crate) fn apply(&mut self, settings: LiveSettings) {
+        if settings.rate_limit != self.connection_limits.rate_limit {
+            self.rate_limiter = settings.rate_limit.map(|limit| Arc::new(RateLimiter::new(limit)));
+        }
+        self.access = settings.access;
+        self.allowlist_only = settings.allowlist_only;
+        self.push_targets = settings.push_targets;
+        self.connection_limits = ConnectionLimits {
+            max_connections: settings.max_connections,
+            max_connections_per_ip: settings.max_connections_per_ip,
+            rate_limit: settings.rate_limit,
+            ..self.connection_limits
+        };
+    }
+}
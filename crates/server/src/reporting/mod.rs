@@ -0,0 +1,116 @@
+//! # Error Reporting
+//!
+//! Faults of a node that point at a bug rather than a misbehaving peer, such
+//! as a panicking handler or connection task or a failing store, are handed
+//! to the [ErrorReporter]s registered on the node, so operators get alerted
+//! instead of having to spot them in the logs. They are logged either way.
+//!
+//! With the `sentry` feature, [SentryReporter](sentry::SentryReporter)
+//! reports them to Sentry.
+
+use std::any::Any;
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+#[cfg(feature = "sentry")]
+pub mod sentry;
+
+/// What kind of fault a [Fault] is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultKind {
+    /// A handler panicked while handling an object.
+    HandlerPanic,
+    /// The task serving a connection panicked.
+    ConnectionPanic,
+    /// An operation of the node failed where it shouldn't, e.g. the store.
+    Internal,
+}
+
+/// An unexpected fault of a node, with what is known about where it
+/// happened.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    pub message: String,
+    /// Hostname of the peer involved, or its address before the handshake.
+    pub peer: Option<String>,
+    /// Id of the inbound connection it happened on, see
+    /// [ConnectionInfo](crate::connection::registry::ConnectionInfo).
+    pub connection: Option<u64>,
+    /// Id of the handler that panicked.
+    pub handler: Option<String>,
+    /// The object that was being processed.
+    pub object_id: Option<Uuid>,
+}
+
+impl Fault {
+    pub(crate) fn new(kind: FaultKind, message: impl Into<String>) -> Self {
+        Fault {
+            kind,
+            message: message.into(),
+            peer: None,
+            connection: None,
+            handler: None,
+            object_id: None,
+        }
+    }
+
+    pub(crate) fn peer(mut self, peer: Option<&str>) -> Self {
+        self.peer = peer.map(str::to_string);
+        self
+    }
+
+    pub(crate) fn connection(mut self, id: u64) -> Self {
+        self.connection = Some(id);
+        self
+    }
+
+    pub(crate) fn handler(mut self, id: &str) -> Self {
+        self.handler = Some(id.to_string());
+        self
+    }
+
+    pub(crate) fn object(mut self, object_id: Uuid) -> Self {
+        self.object_id = Some(object_id);
+        self
+    }
+}
+
+/// Receives the faults of a node, see the [module](self) docs. Reporting
+/// runs on the node's tasks, so implementations should hand slow work off.
+pub trait ErrorReporter: Send + Sync {
+    fn report(&self, fault: &Fault);
+}
+
+impl<F> ErrorReporter for F
+where
+    F: Fn(&Fault) + Send + Sync,
+{
+    fn report(&self, fault: &Fault) {
+        self(fault)
+    }
+}
+
+/// The error reporters registered on a node.
+#[derive(Clone, Default)]
+pub(crate) struct Reporters(Vec<Arc<dyn ErrorReporter>>);
+
+impl Reporters {
+    pub(crate) fn register(&mut self, reporter: Arc<dyn ErrorReporter>) {
+        self.0.push(reporter);
+    }
+
+    pub(crate) fn report(&self, fault: Fault) {
+        for reporter in &self.0 {
+            reporter.report(&fault);
+        }
+    }
+}
+
+/// The message a task panicked with, if it was a string.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload.downcast_ref::<&str>().map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "panicked".to_string())
+}
@@ -0,0 +1,69 @@
+//! Fault reporting to Sentry.
+
+use std::sync::Arc;
+
+use sentry::{Hub, Level};
+
+use crate::reporting::{ErrorReporter, Fault, FaultKind};
+
+/// Reports faults as Sentry events, tagged with what is known about where
+/// they happened. The application sets up Sentry itself with `sentry::init`
+/// and keeps its guard alive for as long as the node runs.
+///
+/// ```ignore
+/// let _guard = sentry::init("https://key@sentry.example.com/1");
+/// let node = OSProtocolNode::builder()
+///     .error_reporter(SentryReporter::new())
+///     // ...
+///     .build()?;
+/// ```
+#[derive(Clone)]
+pub struct SentryReporter {
+    hub: Arc<Hub>,
+}
+
+impl SentryReporter {
+    /// Report through the main hub, the one `sentry::init` binds the client
+    /// to.
+    pub fn new() -> Self {
+        SentryReporter { hub: Hub::main() }
+    }
+
+    /// Report through `hub` instead.
+    pub fn with_hub(hub: Arc<Hub>) -> Self {
+        SentryReporter { hub }
+    }
+}
+
+impl Default for SentryReporter {
+    fn default() -> Self {
+        SentryReporter::new()
+    }
+}
+
+impl ErrorReporter for SentryReporter {
+    fn report(&self, fault: &Fault) {
+        self.hub.with_scope(
+            |scope| {
+                scope.set_tag("osp.fault", match fault.kind {
+                    FaultKind::HandlerPanic => "handler_panic",
+                    FaultKind::ConnectionPanic => "connection_panic",
+                    FaultKind::Internal => "internal",
+                });
+                if let Some(peer) = &fault.peer {
+                    scope.set_tag("osp.peer", peer);
+                }
+                if let Some(connection) = fault.connection {
+                    scope.set_tag("osp.connection", connection);
+                }
+                if let Some(handler) = &fault.handler {
+                    scope.set_tag("osp.handler", handler);
+                }
+                if let Some(object_id) = fault.object_id {
+                    scope.set_tag("osp.object_id", object_id);
+                }
+            },
+            || self.hub.capture_message(&fault.message, Level::Error),
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! # Feature Rollouts
+//!
+//! Turning a new protocol feature on for a whole network at once breaks
+//! every connection with peers that mishandle it. A node can instead roll a
+//! [Feature] out to a share of its peers, or to named canary peers first,
+//! see [rollout](crate::OSProtocolNodeBuilder::rollout), and compare how
+//! handshakes fare with and without it before going further, see
+//! [OSProtocolNode::rollout_stats](crate::OSProtocolNode::rollout_stats).
+//!
+//! Peers are put in or out of a rollout by a hash of their name, so a peer
+//! gets the same features on every connection. Connections the node opens
+//! are decided by the peer's hostname. Guests haven't identified yet when
+//! features are negotiated, so connections from them are decided by their
+//! address. Features without a rollout are enabled for every connection.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+use osp_data::ContentHash;
+
+use crate::metrics;
+
+/// A protocol feature negotiated in handshakes, which can be rolled out.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// Compressing frames, see
+    /// [compression](crate::OSProtocolNodeBuilder::compression).
+    /// Connections without it aren't compressed.
+    Compression,
+    /// Payload formats other than the default, see
+    /// [payload_formats](crate::OSProtocolNodeBuilder::payload_formats).
+    /// Connections without it carry objects in the default format.
+    PayloadFormats,
+    /// Answering challenges with the node's
+    /// [Ed25519 key](crate::OSProtocolNodeBuilder::ed25519_key). Connections
+    /// without it answer with the RSA key. Only applies to connections the
+    /// node opens, as guests pick the key they answer with.
+    Ed25519,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 3] = [Feature::Compression, Feature::PayloadFormats, Feature::Ed25519];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Compression => "compression",
+            Feature::PayloadFormats => "payload_formats",
+            Feature::Ed25519 => "ed25519",
+        }
+    }
+}
+
+impl Display for Feature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Who a feature is rolled out to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rollout {
+    /// Share of peers the feature is enabled for, from 0 to 100.
+    pub percent: u8,
+    /// Hostnames of peers, or addresses of guests, the feature is enabled
+    /// for whatever the share.
+    pub peers: HashSet<String>,
+}
+
+impl Rollout {
+    pub fn percent(percent: u8) -> Self {
+        Rollout { percent, peers: HashSet::new() }
+    }
+
+    pub fn peer(mut self, peer: &str) -> Self {
+        self.peers.insert(peer.to_ascii_lowercase());
+        self
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.percent <= 100
+    }
+
+    /// Whether `feature` is enabled for `peer`, a hostname or an address.
+    pub fn includes(&self, feature: Feature, peer: &str) -> bool {
+        let peer = peer.to_ascii_lowercase();
+        if self.peers.contains(&peer) {
+            return true;
+        }
+        // hashed with the feature, so a peer in one rollout isn't in every
+        // other one too
+        let hash = ContentHash::of(format!("{feature}/{peer}").as_bytes());
+        let bucket = u16::from_be_bytes([hash.as_bytes()[0], hash.as_bytes()[1]]) % 100;
+        bucket < self.percent as u16
+    }
+}
+
+/// The features enabled for a connection.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Features {
+    /// Whether each feature with a rollout is enabled
+    rolled_out: HashMap<Feature, bool>,
+}
+
+impl Features {
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.rolled_out.get(&feature).copied().unwrap_or(true)
+    }
+}
+
+/// How many handshakes succeeded and failed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HandshakeOutcomes {
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+impl HandshakeOutcomes {
+    /// The share of handshakes that succeeded, `None` if there were none.
+    pub fn success_rate(&self) -> Option<f64> {
+        let total = self.succeeded + self.failed;
+        (total > 0).then(|| self.succeeded as f64 / total as f64)
+    }
+}
+
+/// How handshakes fared with and without a feature being rolled out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RolloutStats {
+    pub feature: Feature,
+    pub rollout: Rollout,
+    /// Handshakes of connections the feature was enabled for.
+    pub enabled: HandshakeOutcomes,
+    /// Handshakes of connections it was not.
+    pub disabled: HandshakeOutcomes,
+}
+
+/// The rollouts of a node and how handshakes fared.
+#[derive(Default)]
+pub(crate) struct Rollouts {
+    rollouts: HashMap<Feature, Rollout>,
+    outcomes: Mutex<HashMap<(Feature, bool), HandshakeOutcomes>>,
+}
+
+impl Rollouts {
+    pub(crate) fn new(rollouts: HashMap<Feature, Rollout>) -> Self {
+        Rollouts { rollouts, outcomes: Mutex::default() }
+    }
+
+    /// The features enabled for connections with `peer`.
+    pub(crate) fn decide(&self, peer: &str) -> Features {
+        Features {
+            rolled_out: self.rollouts.iter()
+                .map(|(feature, rollout)| (*feature, rollout.includes(*feature, peer)))
+                .collect(),
+        }
+    }
+
+    /// Count the handshake of a connection with `features`.
+    pub(crate) fn record(&self, features: &Features, succeeded: bool) {
+        let mut outcomes = self.outcomes.lock().unwrap();
+        for (feature, enabled) in &features.rolled_out {
+            let counts = outcomes.entry((*feature, *enabled)).or_default();
+            match succeeded {
+                true => counts.succeeded += 1,
+                false => counts.failed += 1,
+            }
+            metrics::rollout_handshake(*feature, *enabled, succeeded);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> Vec<RolloutStats> {
+        let outcomes = self.outcomes.lock().unwrap();
+        Feature::ALL.iter()
+            .filter_map(|feature| Some(RolloutStats {
+                feature: *feature,
+                rollout: self.rollouts.get(feature)?.clone(),
+                enabled: outcomes.get(&(*feature, true)).copied().unwrap_or_default(),
+                disabled: outcomes.get(&(*feature, false)).copied().unwrap_or_default(),
+            }))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::rollout::{Feature, HandshakeOutcomes, Rollout, Rollouts};
+
+    #[test]
+    fn test_rollouts() {
+        let peers = (0..1000).map(|i| format!("peer{i}.test")).collect::<Vec<_>>();
+        let rollout = Rollout::percent(10).peer("Canary.test");
+        let included = peers.iter().filter(|peer| rollout.includes(Feature::Compression, peer)).count();
+        assert!((50..150).contains(&included), "{included} of 1000 peers included");
+        assert!(rollout.includes(Feature::Compression, "canary.test"));
+        assert!(!Rollout::percent(0).includes(Feature::Compression, "peer1.test"));
+        assert!(Rollout::percent(100).includes(Feature::Compression, "peer1.test"));
+        assert!(!Rollout::percent(101).is_valid());
+
+        let rollouts = Rollouts::new(HashMap::from([(Feature::Compression, rollout)]));
+        let canary = rollouts.decide("canary.test");
+        assert!(canary.is_enabled(Feature::Compression) && canary.is_enabled(Feature::Ed25519));
+        rollouts.record(&canary, true);
+        let excluded = peers.iter().map(|peer| rollouts.decide(peer)).find(|features| !features.is_enabled(Feature::Compression)).unwrap();
+        rollouts.record(&excluded, false);
+        let stats = rollouts.stats();
+        assert_eq!(stats.iter().map(|stats| (stats.feature, stats.enabled, stats.disabled)).collect::<Vec<_>>(), [(
+            Feature::Compression,
+            HandshakeOutcomes { succeeded: 1, failed: 0 },
+            HandshakeOutcomes { succeeded: 0, failed: 1 },
+        )]);
+        assert_eq!(stats[0].enabled.success_rate(), Some(1.0));
+    }
+}
@@ -0,0 +1,308 @@
+//! # Topic Routing
+//!
+//! Objects may be published under a hierarchical topic, such as
+//! `blog/rust/async`, whose levels are separated by `/`. Peers subscribe to
+//! topics with [TopicFilter]s, where a `*` level matches any one level and a
+//! `#` as the last level matches any number of levels, including none:
+//! `blog/rust/*` matches `blog/rust/async` but not `blog/rust`, while
+//! `blog/#` matches `blog`, `blog/rust` and `blog/rust/async`.
+//!
+//! A [RoutingTable] holds the routes of many filters in a trie, so finding
+//! the routes for a topic only walks the levels of the topic instead of
+//! every filter. Routes are returned the most specific first: levels are
+//! compared from the left, and an exact level takes precedence over `*`,
+//! which takes precedence over `#`. For `blog/rust/async` that is
+//!
+//! 1. `blog/rust/async`
+//! 2. `blog/rust/*`
+//! 3. `blog/rust/#`
+//! 4. `blog/*/async`
+//! 5. `blog/#`
+//! 6. `#`
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use tokio::io;
+
+/// One level of a [TopicFilter].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Level {
+    Exact(String),
+    /// `*`, any one level.
+    Single,
+    /// `#`, any number of levels.
+    Multi,
+}
+
+/// A pattern of topics, see the [module](self) docs.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct TopicFilter {
+    levels: Vec<Level>,
+}
+
+impl TopicFilter {
+    /// Whether the filter matches `topic`.
+    pub fn matches(&self, topic: &str) -> bool {
+        let mut topic = topic.split('/');
+        for level in &self.levels {
+            match (level, topic.next()) {
+                (Level::Multi, _) => return true,
+                (Level::Single, Some(_)) => {}
+                (Level::Exact(exact), Some(level)) if exact == level => {}
+                _ => return false,
+            }
+        }
+        topic.next().is_none()
+    }
+
+    /// Whether the filter matches every topic `other` matches.
+    pub fn covers(&self, other: &TopicFilter) -> bool {
+        for (i, level) in self.levels.iter().enumerate() {
+            match (level, other.levels.get(i)) {
+                (Level::Multi, _) => return true,
+                (Level::Single, Some(Level::Exact(_) | Level::Single)) => {}
+                (Level::Exact(exact), Some(Level::Exact(other))) if exact == other => {}
+                _ => return false,
+            }
+        }
+        self.levels.len() == other.levels.len()
+    }
+}
+
+impl FromStr for TopicFilter {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid topic filter {s:?}: {reason}"));
+        let mut levels = Vec::new();
+        for level in s.split('/') {
+            if levels.last() == Some(&Level::Multi) {
+                return Err(invalid("# must be the last level"));
+            }
+            levels.push(match level {
+                "*" => Level::Single,
+                "#" => Level::Multi,
+                "" => return Err(invalid("empty level")),
+                level if level.contains(['*', '#']) => return Err(invalid("wildcards must be a whole level")),
+                level if level.contains(char::is_control) => return Err(invalid("control character")),
+                level => Level::Exact(level.to_string()),
+            });
+        }
+        Ok(TopicFilter { levels })
+    }
+}
+
+impl Display for TopicFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, level) in self.levels.iter().enumerate() {
+            if i > 0 {
+                f.write_str("/")?;
+            }
+            match level {
+                Level::Exact(level) => f.write_str(level)?,
+                Level::Single => f.write_str("*")?,
+                Level::Multi => f.write_str("#")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Node<T> {
+    exact: HashMap<String, Node<T>>,
+    single: Option<Box<Node<T>>>,
+    /// Routes of filters that end in this node
+    routes: Vec<T>,
+    /// Routes of filters that end in a `#` below this node
+    multi: Vec<T>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Node {
+            exact: HashMap::new(),
+            single: None,
+            routes: Vec::new(),
+            multi: Vec::new(),
+        }
+    }
+}
+
+impl<T> Node<T> {
+    fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.single.is_none() && self.routes.is_empty() && self.multi.is_empty()
+    }
+
+    /// Add the routes matching `topic` to `found`, the most specific first.
+    fn collect<'a>(&'a self, topic: &[&str], found: &mut Vec<&'a T>) {
+        match topic.split_first() {
+            None => found.extend(&self.routes),
+            Some((level, rest)) => {
+                if let Some(child) = self.exact.get(*level) {
+                    child.collect(rest, found);
+                }
+                if let Some(child) = &self.single {
+                    child.collect(rest, found);
+                }
+            }
+        }
+        found.extend(&self.multi);
+    }
+
+    /// Remove the routes of the filter with `levels` that `remove` returns
+    /// true for, pruning nodes left empty. Returns how many were removed.
+    fn remove(&mut self, levels: &[Level], remove: &mut impl FnMut(&T) -> bool) -> usize {
+        let routes = match levels.split_first() {
+            None => &mut self.routes,
+            Some((Level::Multi, _)) => &mut self.multi,
+            Some((Level::Single, rest)) => {
+                let Some(child) = self.single.as_mut() else {
+                    return 0;
+                };
+                let removed = child.remove(rest, remove);
+                if child.is_empty() {
+                    self.single = None;
+                }
+                return removed;
+            }
+            Some((Level::Exact(level), rest)) => {
+                let Some(child) = self.exact.get_mut(level) else {
+                    return 0;
+                };
+                let removed = child.remove(rest, remove);
+                if child.is_empty() {
+                    self.exact.remove(level);
+                }
+                return removed;
+            }
+        };
+        let before = routes.len();
+        routes.retain(|route| !remove(route));
+        before - routes.len()
+    }
+}
+
+/// Routes of topic filters, looked up by topic. See the [module](self) docs
+/// for the order routes are found in.
+pub struct RoutingTable<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+impl<T> Default for RoutingTable<T> {
+    fn default() -> Self {
+        RoutingTable {
+            root: Node::default(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> RoutingTable<T> {
+    pub fn new() -> Self {
+        RoutingTable::default()
+    }
+
+    /// The number of routes in the table.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add a route for the topics matching `filter`.
+    pub fn insert(&mut self, filter: &TopicFilter, route: T) {
+        let mut node = &mut self.root;
+        for level in &filter.levels {
+            node = match level {
+                Level::Exact(level) => node.exact.entry(level.clone()).or_default(),
+                Level::Single => node.single.get_or_insert_with(Default::default),
+                Level::Multi => {
+                    node.multi.push(route);
+                    self.len += 1;
+                    return;
+                }
+            };
+        }
+        node.routes.push(route);
+        self.len += 1;
+    }
+
+    /// Remove the routes of `filter` that `remove` returns true for,
+    /// returning how many were removed.
+    pub fn remove(&mut self, filter: &TopicFilter, mut remove: impl FnMut(&T) -> bool) -> usize {
+        let removed = self.root.remove(&filter.levels, &mut remove);
+        self.len -= removed;
+        removed
+    }
+
+    /// The routes of the filters matching `topic`, the most specific first.
+    pub fn matches(&self, topic: &str) -> Vec<&T> {
+        let topic = topic.split('/').collect::<Vec<_>>();
+        let mut found = Vec::new();
+        self.root.collect(&topic, &mut found);
+        found
+    }
+
+    /// The route of the most specific filter matching `topic`, if any.
+    pub fn best(&self, topic: &str) -> Option<&T> {
+        self.matches(topic).into_iter().next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use crate::routing::{RoutingTable, TopicFilter};
+
+    #[test]
+    fn test_filters() -> io::Result<()> {
+        let filter = "blog/rust/*".parse::<TopicFilter>()?;
+        assert!(filter.matches("blog/rust/async"));
+        assert!(!filter.matches("blog/rust"));
+        assert!(!filter.matches("blog/rust/async/tokio"));
+        let filter = "blog/#".parse::<TopicFilter>()?;
+        assert!(filter.matches("blog") && filter.matches("blog/rust/async"));
+        assert!(!filter.matches("news/rust"));
+        assert_eq!(filter.to_string(), "blog/#");
+
+        assert!(filter.covers(&"blog/*/async".parse()?));
+        assert!(!"blog/*".parse::<TopicFilter>()?.covers(&filter));
+        for invalid in ["", "blog//rust", "blog/#/rust", "blog/ru*"] {
+            assert!(invalid.parse::<TopicFilter>().is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_precedence() -> io::Result<()> {
+        let mut table = RoutingTable::new();
+        let filters = ["#", "blog/#", "blog/*/async", "blog/rust/#", "blog/rust/*", "blog/rust/async", "news/#", "blog/*"];
+        for filter in filters {
+            table.insert(&filter.parse()?, filter);
+        }
+        assert_eq!(table.len(), filters.len());
+
+        assert_eq!(
+            table.matches("blog/rust/async").into_iter().copied().collect::<Vec<_>>(),
+            vec!["blog/rust/async", "blog/rust/*", "blog/rust/#", "blog/*/async", "blog/#", "#"],
+        );
+        // `#` also matches the level it is below
+        assert_eq!(table.matches("blog/rust").into_iter().copied().collect::<Vec<_>>(), vec!["blog/rust/#", "blog/*", "blog/#", "#"]);
+        assert_eq!(table.best("blog/go/async"), Some(&"blog/*/async"));
+        assert_eq!(table.best("photos"), Some(&"#"));
+
+        assert_eq!(table.remove(&"blog/rust/async".parse()?, |_| true), 1);
+        assert_eq!(table.remove(&"#".parse()?, |_| true), 1);
+        assert_eq!(table.remove(&"blog/go/async".parse()?, |_| true), 0);
+        assert_eq!(table.best("blog/rust/async"), Some(&"blog/rust/*"));
+        assert_eq!(table.best("photos"), None);
+        assert_eq!(table.len(), filters.len() - 2);
+        Ok(())
+    }
+}
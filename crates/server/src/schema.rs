@@ -0,0 +1,64 @@
+//! # Schema Registry
+//!
+//! Descriptors for the data types a node serves, which peers can request
+//! with a `DescribeType` packet, and a cache of the descriptors the node has
+//! fetched from its peers.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use uuid::Uuid;
+
+use osp_protocol::TypeDescriptor;
+
+/// A descriptor fetched from a peer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerTypeDescriptor {
+    /// Hostname of the peer that described the type.
+    pub peer: String,
+    pub descriptor: TypeDescriptor,
+    /// Unix timestamp the descriptor was fetched at.
+    pub fetched_at: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct SchemaRegistry {
+    local: HashMap<Uuid, TypeDescriptor>,
+    remote: RwLock<HashMap<Uuid, PeerTypeDescriptor>>,
+}
+
+impl SchemaRegistry {
+    pub(crate) fn register(&mut self, descriptor: TypeDescriptor) {
+        self.local.insert(descriptor.type_id, descriptor);
+    }
+
+    /// The descriptor of a type this node serves.
+    pub(crate) fn local(&self, type_id: Uuid) -> Option<TypeDescriptor> {
+        self.local.get(&type_id).cloned()
+    }
+
+    pub(crate) fn all_local(&self) -> Vec<TypeDescriptor> {
+        self.local.values().cloned().collect()
+    }
+
+    /// Remember a descriptor fetched from `peer`, replacing any earlier one
+    /// for the same type.
+    pub(crate) fn cache(&self, peer: &str, descriptor: TypeDescriptor) {
+        self.remote.write().unwrap().insert(descriptor.type_id, PeerTypeDescriptor {
+            peer: peer.to_string(),
+            descriptor,
+            fetched_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        });
+    }
+
+    #[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+    pub(crate) fn remote(&self, type_id: Uuid) -> Option<PeerTypeDescriptor> {
+        self.remote.read().unwrap().get(&type_id).cloned()
+    }
+
+    #[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+    pub(crate) fn all_remote(&self) -> Vec<PeerTypeDescriptor> {
+        self.remote.read().unwrap().values().cloned().collect()
+    }
+}
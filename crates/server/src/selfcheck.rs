@@ -0,0 +1,288 @@
+//! # Startup Self Checks
+//!
+//! A node that starts on a host it can't run well on usually fails much
+//! later, and far from the cause: signatures are rejected because the clock
+//! is years off, connections are refused once the process runs out of file
+//! descriptors, a backup fails when the disk is full. Built with
+//! [self_checks](crate::OSProtocolNodeBuilder::self_checks), a node checks
+//! its environment before it starts listening:
+//!
+//! - `clock`: the system clock is not before [CLOCK_FLOOR]
+//! - `open_files`: the process may open at least
+//!   [min_open_files](SelfChecks::min_open_files) files, and more than the
+//!   node's connection limit
+//! - `dns`: the node's hostname, or [dns_probe](SelfChecks::dns_probe),
+//!   resolves
+//! - `disk`: every directory the node writes to has
+//!   [min_free_space](SelfChecks::min_free_space) left
+//! - `port`: the addresses the node listens on are free
+//!
+//! The outcome is logged as a JSON [SelfCheckReport] and emitted as a
+//! [NodeEvent::SelfChecked](crate::events::NodeEvent::SelfChecked). Unless
+//! [refuse_on_failure](SelfChecks::refuse_on_failure) is turned off, a node
+//! with a check that [failed](CheckStatus::Failed) refuses to listen.
+//! Checks run at any time with
+//! [OSProtocolNode::self_check](crate::OSProtocolNode::self_check).
+//!
+//! File descriptor limits and free space are only checked on Unix.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+
+use serde::Serialize;
+
+use tokio::io;
+use tokio::net::TcpListener;
+
+use crate::platform::open_files_limit;
+
+/// Unix timestamp (milliseconds) of the start of 2024. A clock before it
+/// was never set, as happens on boards without a real-time clock.
+pub const CLOCK_FLOOR: u64 = 1_704_067_200_000;
+
+/// How long the DNS check waits for the hostname to resolve.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// File descriptors kept free for stores, logs and outbound connections
+/// on top of the connection limit.
+const SPARE_FILES: u64 = 64;
+
+/// What the checks before a node starts listening look for, see the
+/// [module](self) docs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfChecks {
+    /// Refuse to start listening when a check failed. Defaults to `true`.
+    pub refuse_on_failure: bool,
+    /// Directories the data store keeps its data in, such as the one of a
+    /// SQLite database, checked for free space along with the directories
+    /// of backups and recordings.
+    pub directories: Vec<PathBuf>,
+    /// Bytes a directory needs free. Defaults to 100 MiB.
+    pub min_free_space: u64,
+    /// Files the process needs to be allowed to open. Defaults to 1024.
+    pub min_open_files: u64,
+    /// A hostname to resolve instead of the node's own, e.g. for nodes
+    /// whose hostname only resolves outside their network.
+    pub dns_probe: Option<String>,
+}
+
+impl Default for SelfChecks {
+    fn default() -> Self {
+        SelfChecks {
+            refuse_on_failure: true,
+            directories: Vec::new(),
+            min_free_space: 100 * 1024 * 1024,
+            min_open_files: 1024,
+            dns_probe: None,
+        }
+    }
+}
+
+/// How a check came out.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Passed,
+    /// The node can run, but may run into trouble.
+    Warning,
+    /// The node won't run properly.
+    Failed,
+    /// The check can't be done on this platform.
+    Skipped,
+}
+
+/// The outcome of one check.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CheckResult {
+    /// The check, e.g. `disk`, see the [module](self) docs.
+    pub check: &'static str,
+    pub status: CheckStatus,
+    /// What was found, e.g. the free space of the directory checked.
+    pub detail: String,
+}
+
+/// The outcome of every check, in the order they ran.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SelfCheckReport {
+    pub checks: Vec<CheckResult>,
+}
+
+impl SelfCheckReport {
+    /// Whether no check failed. Warnings don't count.
+    pub fn passed(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+        self.checks.iter().filter(|check| check.status == CheckStatus::Failed)
+    }
+
+    /// Log the report as JSON, and every check that didn't pass on its own.
+    pub(crate) fn log(&self) {
+        info!("Self checks: {}", serde_json::to_string(self).unwrap_or_default());
+        for check in &self.checks {
+            match check.status {
+                CheckStatus::Failed => error!("Self check {} failed: {}", check.check, check.detail),
+                CheckStatus::Warning => warn!("Self check {} warns: {}", check.check, check.detail),
+                CheckStatus::Passed | CheckStatus::Skipped => {}
+            }
+        }
+    }
+
+    fn push(&mut self, check: &'static str, status: CheckStatus, detail: String) {
+        self.checks.push(CheckResult { check, status, detail });
+    }
+}
+
+/// What the checks of a node look at.
+pub(crate) struct Environment<'a> {
+    pub(crate) hostname: &'a str,
+    /// The addresses the node listens on
+    pub(crate) addrs: Vec<SocketAddr>,
+    /// The directories the node writes to, besides those of the checks
+    pub(crate) directories: Vec<PathBuf>,
+    pub(crate) max_connections: Option<usize>,
+}
+
+impl SelfChecks {
+    /// Check the environment a node runs in.
+    pub(crate) async fn run(&self, environment: Environment<'_>) -> SelfCheckReport {
+        let mut report = SelfCheckReport::default();
+        check_clock(&mut report);
+        self.check_open_files(&mut report, environment.max_connections);
+        self.check_dns(&mut report, environment.hostname).await;
+        for directory in self.directories.iter().chain(&environment.directories) {
+            self.check_disk(&mut report, directory);
+        }
+        for addr in environment.addrs {
+            check_port(&mut report, addr).await;
+        }
+        report
+    }
+
+    fn check_open_files(&self, report: &mut SelfCheckReport, max_connections: Option<usize>) {
+        let limit = match open_files_limit() {
+            Ok(Some(limit)) => limit,
+            Ok(None) => return report.push("open_files", CheckStatus::Skipped, "Not checked on this platform".to_string()),
+            Err(e) => return report.push("open_files", CheckStatus::Warning, format!("Unable to read the limit: {e}")),
+        };
+        let connections = max_connections.map_or(0, |max| max as u64);
+        let needed = max_connections.map_or(0, |_| connections.saturating_add(SPARE_FILES));
+        let (status, detail) = if limit < self.min_open_files {
+            (CheckStatus::Warning, format!("{limit} files may be open, less than {}", self.min_open_files))
+        } else if limit < needed {
+            (CheckStatus::Warning, format!("{limit} files may be open, too few for {connections} connections"))
+        } else {
+            (CheckStatus::Passed, format!("{limit} files may be open"))
+        };
+        report.push("open_files", status, detail);
+    }
+
+    async fn check_dns(&self, report: &mut SelfCheckReport, hostname: &str) {
+        let host = self.dns_probe.as_deref().unwrap_or(hostname);
+        let (status, detail) = match tokio::time::timeout(DNS_TIMEOUT, tokio::net::lookup_host((host, 0))).await {
+            Ok(Ok(addrs)) => (CheckStatus::Passed, format!("{host} resolves to {} addresses", addrs.count())),
+            Ok(Err(e)) => (CheckStatus::Warning, format!("{host} doesn't resolve: {e}")),
+            Err(_) => (CheckStatus::Warning, format!("{host} didn't resolve in {DNS_TIMEOUT:?}")),
+        };
+        report.push("dns", status, detail);
+    }
+
+    fn check_disk(&self, report: &mut SelfCheckReport, directory: &Path) {
+        // directories that don't exist yet are created on the file system
+        // of the closest one that does
+        let Some(existing) = directory.ancestors().find(|path| path.exists()) else {
+            return report.push("disk", CheckStatus::Failed, format!("{} is on no file system", directory.display()));
+        };
+        let (status, detail) = match free_space(existing) {
+            Ok(Some(free)) if free < self.min_free_space => {
+                (CheckStatus::Failed, format!("{} has {} MiB free, less than {} MiB", directory.display(), free >> 20, self.min_free_space >> 20))
+            }
+            Ok(Some(free)) => (CheckStatus::Passed, format!("{} has {} MiB free", directory.display(), free >> 20)),
+            Ok(None) => (CheckStatus::Skipped, format!("{} is not checked on this platform", directory.display())),
+            Err(e) => (CheckStatus::Warning, format!("Unable to read the free space of {}: {e}", directory.display())),
+        };
+        report.push("disk", status, detail);
+    }
+}
+
+fn check_clock(report: &mut SelfCheckReport) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64);
+    match now < CLOCK_FLOOR {
+        true => report.push("clock", CheckStatus::Failed, format!("The clock is at {now}, before {CLOCK_FLOOR}")),
+        false => report.push("clock", CheckStatus::Passed, format!("The clock is at {now}")),
+    }
+}
+
+async fn check_port(report: &mut SelfCheckReport, addr: SocketAddr) {
+    match TcpListener::bind(addr).await {
+        Ok(_) => report.push("port", CheckStatus::Passed, format!("{addr} is free")),
+        Err(e) => report.push("port", CheckStatus::Failed, format!("Unable to listen on {addr}: {e}")),
+    }
+}
+
+/// Bytes free on the file system of `path` for unprivileged processes, if
+/// the platform tells.
+#[cfg(unix)]
+// the widths of fsblkcnt_t and c_ulong differ between platforms
+#[allow(clippy::unnecessary_cast)]
+fn free_space(path: &Path) -> io::Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::zeroed();
+    // SAFETY: the path is nul terminated, and statvfs only writes to the
+    // stat it is given
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: statvfs succeeded, so it filled in the stat
+    let stat = unsafe { stat.assume_init() };
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+
+    use crate::selfcheck::{CheckStatus, Environment, SelfChecks};
+
+    #[tokio::test]
+    async fn test_self_checks() {
+        let addr: SocketAddr = "127.0.0.1:57410".parse().unwrap();
+        let checks = SelfChecks { directories: vec![std::env::temp_dir().join("osp-missing/store")], ..SelfChecks::default() };
+        let environment = || Environment {
+            hostname: "localhost",
+            addrs: vec![addr],
+            directories: Vec::new(),
+            max_connections: Some(usize::MAX - 64),
+        };
+        let report = checks.run(environment()).await;
+        let statuses = report.checks.iter().map(|check| (check.check, check.status)).collect::<Vec<_>>();
+        assert_eq!(statuses, [
+            ("clock", CheckStatus::Passed),
+            ("open_files", CheckStatus::Warning),
+            ("dns", CheckStatus::Passed),
+            ("disk", CheckStatus::Passed),
+            ("port", CheckStatus::Passed),
+        ]);
+        assert!(report.passed());
+
+        let _taken = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let checks = SelfChecks { directories: vec![PathBuf::from(".")], min_free_space: u64::MAX, ..SelfChecks::default() };
+        let report = checks.run(environment()).await;
+        assert_eq!(report.failures().map(|check| check.check).collect::<Vec<_>>(), ["disk", "port"]);
+        assert!(serde_json::to_string(&report).unwrap().contains(r#""status":"failed""#));
+    }
+}
@@ -0,0 +1,331 @@
+//! # Archives
+//!
+//! Export stored objects as append-only archives for long-term preservation,
+//! and import them again, e.g. to seed a fresh node.
+//!
+//! Two formats are supported:
+//!
+//! - [ArchiveFormat::Jsonl]: one JSON object per line, with the object's
+//!   metadata in plain text and the complete encoded envelope in base64.
+//! - [ArchiveFormat::Warc]: a WARC file with a `warcinfo` record followed by
+//!   one `resource` record per object, holding the encoded envelope.
+//!
+//! Both formats keep the envelope exactly as it was received, so nothing the
+//! envelope carries is lost on the way through an archive.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use log::info;
+
+use serde::{Deserialize, Serialize};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_protocol::Envelope;
+
+use crate::store::DataStore;
+use crate::time;
+
+/// Content type of the WARC records holding envelopes.
+const ENVELOPE_CONTENT_TYPE: &str = "application/vnd.osp.envelope";
+
+/// Number of objects loaded from the store at a time while exporting.
+const EXPORT_BATCH_SIZE: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArchiveFormat {
+    Jsonl,
+    Warc,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "jsonl" => Ok(ArchiveFormat::Jsonl),
+            "warc" => Ok(ArchiveFormat::Warc),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown archive format {s}"))),
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveFormat::Jsonl => write!(f, "jsonl"),
+            ArchiveFormat::Warc => write!(f, "warc"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonlRecord {
+    object_id: String,
+    type_id: String,
+    origin: String,
+    created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    license: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attribution: Option<String>,
+    /// The encoded envelope, base64.
+    envelope: String,
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Writes envelopes to an archive.
+pub struct ArchiveWriter<W: Write> {
+    inner: W,
+    format: ArchiveFormat,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut inner: W, format: ArchiveFormat) -> io::Result<Self> {
+        if format == ArchiveFormat::Warc {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let info = format!(
+                "software: osp_server_sdk/{}\r\nformat: Open Syndication Protocol envelope archive\r\n",
+                env!("CARGO_PKG_VERSION")
+            );
+            Self::write_warc_record(&mut inner, &[
+                ("WARC-Type", "warcinfo".to_string()),
+                ("WARC-Record-ID", format!("<urn:uuid:{}>", Uuid::new_v4())),
+                ("WARC-Date", time::rfc3339(now)),
+                ("Content-Type", "application/warc-fields".to_string()),
+            ], info.as_bytes())?;
+        }
+        Ok(Self { inner, format })
+    }
+
+    fn write_warc_record(inner: &mut W, headers: &[(&str, String)], block: &[u8]) -> io::Result<()> {
+        write!(inner, "WARC/1.1\r\n")?;
+        for (name, value) in headers {
+            write!(inner, "{name}: {value}\r\n")?;
+        }
+        write!(inner, "Content-Length: {}\r\n\r\n", block.len())?;
+        inner.write_all(block)?;
+        write!(inner, "\r\n\r\n")
+    }
+
+    /// Append `envelope` to the archive.
+    pub fn write(&mut self, envelope: &Envelope) -> io::Result<()> {
+        let bytes = envelope.to_bytes()?;
+        match self.format {
+            ArchiveFormat::Jsonl => {
+                let record = JsonlRecord {
+                    object_id: envelope.object_id.to_string(),
+                    type_id: envelope.type_id.to_string(),
+                    origin: envelope.origin.clone(),
+                    created_at: envelope.created_at,
+                    license: envelope.license.clone(),
+                    attribution: envelope.attribution.clone(),
+                    envelope: BASE64.encode(bytes),
+                };
+                serde_json::to_writer(&mut self.inner, &record)?;
+                self.inner.write_all(b"\n")
+            }
+            ArchiveFormat::Warc => {
+                let mut headers = vec![
+                    ("WARC-Type", "resource".to_string()),
+                    ("WARC-Record-ID", format!("<urn:uuid:{}>", envelope.object_id)),
+                    ("WARC-Date", time::rfc3339(envelope.created_at)),
+                    ("WARC-Target-URI", format!("osp://{}/objects/{}", envelope.origin, envelope.object_id)),
+                    ("OSP-Type-Id", envelope.type_id.to_string()),
+                    ("Content-Type", ENVELOPE_CONTENT_TYPE.to_string()),
+                ];
+                if let Some(license) = &envelope.license {
+                    headers.push(("OSP-License", license.clone()));
+                }
+                if let Some(attribution) = &envelope.attribution {
+                    headers.push(("OSP-Attribution", attribution.clone()));
+                }
+                Self::write_warc_record(&mut self.inner, &headers, &bytes)
+            }
+        }
+    }
+
+    /// Flush the archive and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads envelopes back out of an archive written by [ArchiveWriter].
+pub struct ArchiveReader<R: BufRead> {
+    inner: R,
+    format: ArchiveFormat,
+}
+
+impl<R: BufRead> ArchiveReader<R> {
+    pub fn new(inner: R, format: ArchiveFormat) -> Self {
+        Self { inner, format }
+    }
+
+    /// The next non-empty line, without its line ending.
+    fn next_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.inner.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let trimmed = line.trim_end_matches(['\r', '\n']);
+            if !trimmed.is_empty() {
+                return Ok(Some(trimmed.to_string()));
+            }
+        }
+    }
+
+    fn read_jsonl(&mut self) -> io::Result<Option<Envelope>> {
+        let Some(line) = self.next_line()? else { return Ok(None) };
+        let record: JsonlRecord = serde_json::from_str(&line).map_err(invalid_data)?;
+        let envelope = Envelope::from_bytes(&BASE64.decode(record.envelope).map_err(invalid_data)?)?;
+        if envelope.object_id.to_string() != record.object_id {
+            return Err(invalid_data(format!("Archived envelope does not match object id {}", record.object_id)));
+        }
+        Ok(Some(envelope))
+    }
+
+    fn read_warc(&mut self) -> io::Result<Option<Envelope>> {
+        loop {
+            let Some(version) = self.next_line()? else { return Ok(None) };
+            if !version.starts_with("WARC/") {
+                return Err(invalid_data(format!("Expected a WARC record, found {version:?}")));
+            }
+
+            let mut headers = HashMap::new();
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if self.inner.read_line(&mut line)? == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated WARC record"));
+                }
+                let header = line.trim_end_matches(['\r', '\n']);
+                if header.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+                }
+            }
+
+            let length: u64 = headers.get("content-length")
+                .ok_or_else(|| invalid_data("WARC record is missing its Content-Length"))?
+                .parse()
+                .map_err(invalid_data)?;
+            let mut block = Vec::new();
+            (&mut self.inner).take(length).read_to_end(&mut block)?;
+            if (block.len() as u64) < length {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated WARC record"));
+            }
+
+            // skip records that don't hold envelopes, such as the warcinfo
+            let is_envelope = headers.get("warc-type").is_some_and(|t| t == "resource")
+                && headers.get("content-type").is_some_and(|t| t == ENVELOPE_CONTENT_TYPE);
+            if is_envelope {
+                return Envelope::from_bytes(&block).map(Some);
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for ArchiveReader<R> {
+    type Item = io::Result<Envelope>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.format {
+            ArchiveFormat::Jsonl => self.read_jsonl(),
+            ArchiveFormat::Warc => self.read_warc(),
+        }.transpose()
+    }
+}
+
+/// Write every object in `store` to `writer`, in the order they were stored,
+/// returning how many were written.
+pub fn export<W: Write>(store: &dyn DataStore, writer: W, format: ArchiveFormat) -> io::Result<usize> {
+    let mut archive = ArchiveWriter::new(writer, format)?;
+    let (mut offset, mut exported) = (0, 0);
+    loop {
+        let batch = store.list_objects(offset, EXPORT_BATCH_SIZE)?;
+        // objects deleted since they were listed are skipped
+        for object_id in &batch {
+            if let Some(envelope) = store.get_object(*object_id)? {
+                archive.write(&envelope)?;
+                exported += 1;
+            }
+        }
+        offset += batch.len();
+        if batch.len() < EXPORT_BATCH_SIZE {
+            break;
+        }
+    }
+    archive.finish()?;
+    info!("Exported {exported} objects as {format}");
+    Ok(exported)
+}
+
+/// Store every object in the archive read from `reader` with `put`,
+/// returning how many were imported.
+pub fn import<R, F>(reader: R, format: ArchiveFormat, mut put: F) -> io::Result<usize>
+where
+    R: BufRead,
+    F: FnMut(&Envelope) -> io::Result<()>,
+{
+    let mut imported = 0;
+    for envelope in ArchiveReader::new(reader, format) {
+        put(&envelope?)?;
+        imported += 1;
+    }
+    info!("Imported {imported} objects from {format} archive");
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_protocol::Envelope;
+
+    use crate::store::{DataStore, MemoryStore};
+    use crate::store::archive::{export, import, ArchiveFormat};
+
+    #[test]
+    fn test_archive_roundtrip() -> io::Result<()> {
+        let source = MemoryStore::new();
+        let objects = (0..3u8)
+            .map(|i| Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![i; 64]))
+            .collect::<Vec<_>>();
+        for envelope in &objects {
+            source.put_object(envelope)?;
+        }
+
+        for format in [ArchiveFormat::Jsonl, ArchiveFormat::Warc] {
+            let mut archive = Vec::new();
+            assert_eq!(export(&source, &mut archive, format)?, 3);
+
+            let target = MemoryStore::new();
+            assert_eq!(import(Cursor::new(archive), format, |e| target.put_object(e))?, 3);
+            for envelope in &objects {
+                assert_eq!(target.get_object(envelope.object_id)?.as_ref(), Some(envelope));
+            }
+        }
+        Ok(())
+    }
+}
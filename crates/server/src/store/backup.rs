@@ -0,0 +1,154 @@
+//! # Backups
+//!
+//! A backup is a snapshot of a store written next to a small manifest holding
+//! the snapshot's checksum, so a restore can prove the snapshot is intact
+//! before it replaces anything.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+
+use tokio::io;
+
+use crate::crypto::{self, Sha256};
+use crate::store::DataStore;
+
+/// Metadata recorded alongside a snapshot.
+#[derive(Debug, PartialEq)]
+pub struct BackupManifest {
+    /// Unix timestamp (seconds) the snapshot was taken at.
+    pub created_at: u64,
+    /// Schema version of the store the snapshot was taken from.
+    pub schema_version: u32,
+    /// Hex encoded SHA-256 of the snapshot file.
+    pub checksum: String,
+}
+
+impl BackupManifest {
+    /// The manifest path belonging to the snapshot at `snapshot`.
+    pub fn path_for(snapshot: &Path) -> PathBuf {
+        let mut name = snapshot.as_os_str().to_owned();
+        name.push(".manifest");
+        PathBuf::from(name)
+    }
+
+    /// Build the manifest for a snapshot that was just written.
+    pub fn create(snapshot: &Path, schema_version: u32) -> io::Result<Self> {
+        Ok(BackupManifest {
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            schema_version,
+            checksum: file_checksum(snapshot)?,
+        })
+    }
+
+    pub fn write(&self, snapshot: &Path) -> io::Result<()> {
+        fs::write(
+            Self::path_for(snapshot),
+            format!("created_at={}\nschema_version={}\nchecksum={}\n", self.created_at, self.schema_version, self.checksum)
+        )
+    }
+
+    pub fn read(snapshot: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(Self::path_for(snapshot))?;
+        let invalid = |field: &str| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Backup manifest is missing or has an invalid {field}")
+        );
+
+        let field = |name: &str| contents.lines()
+            .find_map(|line| line.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')))
+            .map(str::to_string);
+
+        Ok(BackupManifest {
+            created_at: field("created_at").and_then(|v| v.parse().ok()).ok_or_else(|| invalid("created_at"))?,
+            schema_version: field("schema_version").and_then(|v| v.parse().ok()).ok_or_else(|| invalid("schema_version"))?,
+            checksum: field("checksum").ok_or_else(|| invalid("checksum"))?,
+        })
+    }
+
+    /// Check that the snapshot at `snapshot` still matches this manifest.
+    pub fn verify(&self, snapshot: &Path) -> io::Result<()> {
+        let actual = file_checksum(snapshot)?;
+        if actual != self.checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Backup {} is corrupt: checksum {} does not match manifest {}", snapshot.display(), actual, self.checksum)
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Hex encoded SHA-256 of the file at `path`.
+pub fn file_checksum(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(crypto::hex(&hasher.finish()))
+}
+
+/// Periodically back up a node's store into a directory.
+#[derive(Clone, Debug)]
+pub struct BackupSchedule {
+    /// Directory the snapshots are written to.
+    pub directory: PathBuf,
+    /// Time between snapshots.
+    pub interval: Duration,
+    /// How many snapshots to keep. Older ones are deleted after each backup.
+    pub keep: usize,
+}
+
+impl BackupSchedule {
+    /// Take a backup every `interval`, forever.
+    pub(crate) async fn run(self, store: Arc<dyn DataStore>) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.tick().await; // the first tick completes immediately
+        loop {
+            interval.tick().await;
+            // backups read the whole store and write it out, off the runtime
+            let (schedule, store) = (self.clone(), store.clone());
+            match tokio::task::spawn_blocking(move || schedule.backup_once(store.as_ref())).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Scheduled backup failed: {e}"),
+                Err(e) => error!("Scheduled backup panicked: {e}"),
+            }
+        }
+    }
+
+    fn backup_once(&self, store: &dyn DataStore) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let path = self.directory.join(format!("osp-backup-{now}.db"));
+
+        let manifest = store.backup(&path)?;
+        info!("Backed up store to {} ({})", path.display(), manifest.checksum);
+
+        let mut snapshots = fs::read_dir(&self.directory)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| {
+                let name = p.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+                name.starts_with("osp-backup-") && name.ends_with(".db")
+            })
+            .collect::<Vec<_>>();
+        snapshots.sort();
+
+        while snapshots.len() > self.keep {
+            let old = snapshots.remove(0);
+            info!("Removing old backup {}", old.display());
+            fs::remove_file(&old)?;
+            let _ = fs::remove_file(BackupManifest::path_for(&old));
+        }
+        Ok(())
+    }
+}
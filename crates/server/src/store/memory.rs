@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_protocol::{Envelope, Tombstone};
+
+use crate::delivery::PendingDelivery;
+use crate::embargo::ScheduledObject;
+use crate::fanout::DelegationRecord;
+use crate::federation::FederationRule;
+use crate::invite::InviteRecord;
+use crate::store::{no_peer_state, AuditEntry, DataStore, OriginUsage, PeerSyncState, TypeUsage};
+use crate::subscription::Subscription;
+
+struct StoredObject {
+    /// Insertion counter, used to find the oldest objects of a type.
+    stored: u64,
+    received_at: u64,
+    size: u64,
+    envelope: Envelope,
+    cold_key: Option<String>,
+}
+
+/// A [DataStore] that keeps everything in memory. Nothing survives a restart,
+/// which makes it useful for tests and throwaway nodes only.
+#[derive(Default)]
+pub struct MemoryStore {
+    peers: Mutex<HashMap<String, PeerSyncState>>,
+    objects: Mutex<HashMap<Uuid, StoredObject>>,
+    counter: AtomicU64,
+    tombstones: Mutex<HashMap<Uuid, Tombstone>>,
+    audit_log: Mutex<Vec<AuditEntry>>,
+    subscriptions: Mutex<HashMap<String, Subscription>>,
+    invites: Mutex<HashMap<Uuid, InviteRecord>>,
+    federation_rules: Mutex<HashMap<String, FederationRule>>,
+    deliveries: Mutex<HashMap<Uuid, PendingDelivery>>,
+    scheduled: Mutex<HashMap<Uuid, ScheduledObject>>,
+    /// By origin and key
+    bridged_items: Mutex<HashMap<(String, String), Uuid>>,
+    bridge_cursors: Mutex<HashMap<String, u64>>,
+    /// In the order they were first saved
+    delegations: Mutex<Vec<DelegationRecord>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl DataStore for MemoryStore {
+    fn peer_state(&self, hostname: &str) -> io::Result<Option<PeerSyncState>> {
+        Ok(self.peers.lock().unwrap().get(hostname).cloned())
+    }
+
+    fn put_peer_state(&self, hostname: &str, state: &PeerSyncState) -> io::Result<()> {
+        self.peers.lock().unwrap().insert(hostname.to_string(), state.clone());
+        Ok(())
+    }
+
+    fn remove_peer_state(&self, hostname: &str) -> io::Result<()> {
+        self.peers.lock().unwrap().remove(hostname);
+        Ok(())
+    }
+
+    fn advance_peer_cursor(&self, hostname: &str, sequence: u64) -> io::Result<u64> {
+        let mut peers = self.peers.lock().unwrap();
+        let state = peers.get_mut(hostname).ok_or_else(|| no_peer_state(hostname))?;
+        let cursor = state.cursor;
+        if cursor + 1 == sequence {
+            state.cursor = sequence;
+        }
+        Ok(cursor)
+    }
+
+    fn take_peer_sequence(&self, hostname: &str) -> io::Result<u64> {
+        let mut peers = self.peers.lock().unwrap();
+        let state = peers.get_mut(hostname).ok_or_else(|| no_peer_state(hostname))?;
+        state.next_sequence += 1;
+        Ok(state.next_sequence - 1)
+    }
+
+    fn put_object(&self, envelope: &Envelope) -> io::Result<()> {
+        let size = envelope.to_bytes()?.len() as u64;
+        self.objects.lock().unwrap().insert(envelope.object_id, StoredObject {
+            stored: self.counter.fetch_add(1, Ordering::Relaxed),
+            received_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            size,
+            envelope: envelope.clone(),
+            cold_key: None,
+        });
+        Ok(())
+    }
+
+    fn get_object(&self, object_id: Uuid) -> io::Result<Option<Envelope>> {
+        Ok(self.objects.lock().unwrap().get(&object_id).map(|o| o.envelope.clone()))
+    }
+
+    fn remove_object(&self, object_id: Uuid) -> io::Result<bool> {
+        Ok(self.objects.lock().unwrap().remove(&object_id).is_some())
+    }
+
+    fn list_objects(&self, offset: usize, limit: usize) -> io::Result<Vec<Uuid>> {
+        let objects = self.objects.lock().unwrap();
+        let mut all = objects.values().collect::<Vec<_>>();
+        all.sort_by_key(|o| o.stored);
+        Ok(all.into_iter().skip(offset).take(limit).map(|o| o.envelope.object_id).collect())
+    }
+
+    fn objects_by_actor(&self, actor: &str, limit: usize) -> io::Result<Vec<Uuid>> {
+        let objects = self.objects.lock().unwrap();
+        Ok(objects.values()
+            .filter(|o| o.envelope.actor.as_deref() == Some(actor))
+            .take(limit)
+            .map(|o| o.envelope.object_id)
+            .collect())
+    }
+
+    fn put_tombstone(&self, tombstone: &Tombstone) -> io::Result<()> {
+        self.tombstones.lock().unwrap().insert(tombstone.object_id, tombstone.clone());
+        Ok(())
+    }
+
+    fn tombstone(&self, object_id: Uuid) -> io::Result<Option<Tombstone>> {
+        Ok(self.tombstones.lock().unwrap().get(&object_id).cloned())
+    }
+
+    fn append_audit(&self, entry: &AuditEntry) -> io::Result<()> {
+        self.audit_log.lock().unwrap().push(entry.clone());
+        Ok(())
+    }
+
+    fn audit_log(&self, offset: usize, limit: usize) -> io::Result<Vec<AuditEntry>> {
+        Ok(self.audit_log.lock().unwrap().iter().skip(offset).take(limit).cloned().collect())
+    }
+
+    fn audit_log_between(&self, from: u64, to: u64, offset: usize, limit: usize) -> io::Result<Vec<AuditEntry>> {
+        Ok(self.audit_log.lock().unwrap().iter()
+            .filter(|entry| (from..to).contains(&entry.recorded_at))
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    fn put_subscription(&self, subscription: &Subscription) -> io::Result<()> {
+        self.subscriptions.lock().unwrap().insert(subscription.peer.clone(), subscription.clone());
+        Ok(())
+    }
+
+    fn subscription(&self, peer: &str) -> io::Result<Option<Subscription>> {
+        Ok(self.subscriptions.lock().unwrap().get(peer).cloned())
+    }
+
+    fn subscriptions(&self) -> io::Result<Vec<Subscription>> {
+        Ok(self.subscriptions.lock().unwrap().values().cloned().collect())
+    }
+
+    fn remove_subscription(&self, peer: &str) -> io::Result<bool> {
+        Ok(self.subscriptions.lock().unwrap().remove(peer).is_some())
+    }
+
+    fn put_invite(&self, record: &InviteRecord) -> io::Result<()> {
+        self.invites.lock().unwrap().insert(record.invite.token_id, record.clone());
+        Ok(())
+    }
+
+    fn invite(&self, token_id: Uuid) -> io::Result<Option<InviteRecord>> {
+        Ok(self.invites.lock().unwrap().get(&token_id).cloned())
+    }
+
+    fn invites(&self) -> io::Result<Vec<InviteRecord>> {
+        Ok(self.invites.lock().unwrap().values().cloned().collect())
+    }
+
+    fn put_federation_rule(&self, rule: &FederationRule) -> io::Result<()> {
+        self.federation_rules.lock().unwrap().insert(rule.host.clone(), rule.clone());
+        Ok(())
+    }
+
+    fn federation_rule(&self, host: &str) -> io::Result<Option<FederationRule>> {
+        Ok(self.federation_rules.lock().unwrap().get(host).cloned())
+    }
+
+    fn federation_rules(&self) -> io::Result<Vec<FederationRule>> {
+        Ok(self.federation_rules.lock().unwrap().values().cloned().collect())
+    }
+
+    fn oldest_objects(&self, type_id: Uuid, limit: usize) -> io::Result<Vec<Uuid>> {
+        let objects = self.objects.lock().unwrap();
+        let mut matching = objects.values()
+            .filter(|o| o.envelope.type_id == type_id)
+            .collect::<Vec<_>>();
+        matching.sort_by_key(|o| o.stored);
+        Ok(matching.into_iter().take(limit).map(|o| o.envelope.object_id).collect())
+    }
+
+    fn type_usage(&self, type_id: Uuid) -> io::Result<TypeUsage> {
+        let objects = self.objects.lock().unwrap();
+        Ok(objects.values()
+            .filter(|o| o.envelope.type_id == type_id)
+            .fold(TypeUsage { type_id, ..Default::default() }, |mut usage, o| {
+                usage.objects += 1;
+                usage.bytes += o.size;
+                usage
+            }))
+    }
+
+    fn usage(&self) -> io::Result<Vec<TypeUsage>> {
+        let mut usage: HashMap<Uuid, TypeUsage> = HashMap::new();
+        for o in self.objects.lock().unwrap().values() {
+            let entry = usage.entry(o.envelope.type_id).or_insert(TypeUsage {
+                type_id: o.envelope.type_id,
+                ..Default::default()
+            });
+            entry.objects += 1;
+            entry.bytes += o.size;
+        }
+        Ok(usage.into_values().collect())
+    }
+
+    fn origin_usage(&self, from: u64, to: u64) -> io::Result<Vec<OriginUsage>> {
+        let mut usage: HashMap<(Uuid, &str), OriginUsage> = HashMap::new();
+        let objects = self.objects.lock().unwrap();
+        for o in objects.values().filter(|o| (from..to).contains(&o.received_at)) {
+            let entry = usage.entry((o.envelope.type_id, &o.envelope.origin)).or_insert_with(|| OriginUsage {
+                type_id: o.envelope.type_id,
+                origin: o.envelope.origin.clone(),
+                ..Default::default()
+            });
+            entry.objects += 1;
+            entry.bytes += o.size;
+        }
+        Ok(usage.into_values().collect())
+    }
+
+    fn objects_received_before(&self, cutoff: u64, limit: usize) -> io::Result<Vec<Uuid>> {
+        let objects = self.objects.lock().unwrap();
+        let mut matching = objects.values()
+            .filter(|o| o.received_at < cutoff && o.cold_key.is_none())
+            .collect::<Vec<_>>();
+        matching.sort_by_key(|o| o.stored);
+        Ok(matching.into_iter().take(limit).map(|o| o.envelope.object_id).collect())
+    }
+
+    fn tier_object(&self, stub: &Envelope, cold_key: &str) -> io::Result<()> {
+        let size = stub.to_bytes()?.len() as u64;
+        if let Some(object) = self.objects.lock().unwrap().get_mut(&stub.object_id) {
+            object.envelope = stub.clone();
+            object.size = size;
+            object.cold_key = Some(cold_key.to_string());
+        }
+        Ok(())
+    }
+
+    fn cold_key(&self, object_id: Uuid) -> io::Result<Option<String>> {
+        Ok(self.objects.lock().unwrap().get(&object_id).and_then(|o| o.cold_key.clone()))
+    }
+
+    fn put_delivery(&self, delivery: &PendingDelivery) -> io::Result<()> {
+        self.deliveries.lock().unwrap().insert(delivery.id, delivery.clone());
+        Ok(())
+    }
+
+    fn due_deliveries(&self, now: u64, limit: usize, paused: &[String]) -> io::Result<Vec<PendingDelivery>> {
+        let mut due = self.deliveries.lock().unwrap().values()
+            .filter(|delivery| delivery.next_attempt_at <= now && !paused.contains(&delivery.peer))
+            .cloned()
+            .collect::<Vec<_>>();
+        due.sort_by_key(|delivery| delivery.next_attempt_at);
+        due.truncate(limit);
+        Ok(due)
+    }
+
+    fn queued_deliveries(&self, peer: &str, port: u16) -> io::Result<Vec<PendingDelivery>> {
+        let mut queued = self.deliveries.lock().unwrap().values()
+            .filter(|delivery| delivery.peer == peer && delivery.port == port)
+            .cloned()
+            .collect::<Vec<_>>();
+        queued.sort_by_key(|delivery| delivery.next_attempt_at);
+        Ok(queued)
+    }
+
+    fn remove_delivery(&self, id: Uuid) -> io::Result<bool> {
+        Ok(self.deliveries.lock().unwrap().remove(&id).is_some())
+    }
+
+    fn delivery_count(&self) -> io::Result<u64> {
+        Ok(self.deliveries.lock().unwrap().len() as u64)
+    }
+
+    fn put_scheduled(&self, scheduled: &ScheduledObject) -> io::Result<()> {
+        self.scheduled.lock().unwrap().insert(scheduled.envelope.object_id, scheduled.clone());
+        Ok(())
+    }
+
+    fn scheduled(&self, object_id: Uuid) -> io::Result<Option<ScheduledObject>> {
+        Ok(self.scheduled.lock().unwrap().get(&object_id).cloned())
+    }
+
+    fn scheduled_objects(&self) -> io::Result<Vec<ScheduledObject>> {
+        self.due_scheduled(u64::MAX, usize::MAX)
+    }
+
+    fn due_scheduled(&self, now: u64, limit: usize) -> io::Result<Vec<ScheduledObject>> {
+        let mut due = self.scheduled.lock().unwrap().values()
+            .filter(|scheduled| scheduled.publish_at <= now)
+            .cloned()
+            .collect::<Vec<_>>();
+        due.sort_by_key(|scheduled| scheduled.publish_at);
+        due.truncate(limit);
+        Ok(due)
+    }
+
+    fn remove_scheduled(&self, object_id: Uuid) -> io::Result<bool> {
+        Ok(self.scheduled.lock().unwrap().remove(&object_id).is_some())
+    }
+
+    fn reschedule(&self, object_id: Uuid, publish_at: u64) -> io::Result<bool> {
+        Ok(self.scheduled.lock().unwrap().get_mut(&object_id)
+            .map(|scheduled| scheduled.publish_at = publish_at)
+            .is_some())
+    }
+
+    fn bridged_item(&self, origin: &str, key: &str) -> io::Result<Option<Uuid>> {
+        Ok(self.bridged_items.lock().unwrap().get(&(origin.to_string(), key.to_string())).copied())
+    }
+
+    fn put_bridged_item(&self, origin: &str, key: &str, object_id: Uuid) -> io::Result<()> {
+        self.bridged_items.lock().unwrap().insert((origin.to_string(), key.to_string()), object_id);
+        Ok(())
+    }
+
+    fn bridge_cursor(&self, source: &str) -> io::Result<Option<u64>> {
+        Ok(self.bridge_cursors.lock().unwrap().get(source).copied())
+    }
+
+    fn put_bridge_cursor(&self, source: &str, cursor: u64) -> io::Result<()> {
+        self.bridge_cursors.lock().unwrap().insert(source.to_string(), cursor);
+        Ok(())
+    }
+
+    fn put_delegation(&self, record: &DelegationRecord) -> io::Result<()> {
+        let mut delegations = self.delegations.lock().unwrap();
+        let delegation_id = record.delegation.delegation_id;
+        match delegations.iter_mut().find(|saved| saved.delegation.delegation_id == delegation_id) {
+            Some(saved) => *saved = record.clone(),
+            None => delegations.push(record.clone()),
+        }
+        Ok(())
+    }
+
+    fn delegations(&self) -> io::Result<Vec<DelegationRecord>> {
+        Ok(self.delegations.lock().unwrap().clone())
+    }
+
+    fn remove_delegation(&self, delegation_id: Uuid) -> io::Result<bool> {
+        let mut delegations = self.delegations.lock().unwrap();
+        let before = delegations.len();
+        delegations.retain(|saved| saved.delegation.delegation_id != delegation_id);
+        Ok(delegations.len() < before)
+    }
+}
@@ -0,0 +1,296 @@
+//! # Schema Migrations
+//!
+//! Every schema change to a database-backed store is shipped as an embedded,
+//! numbered migration. Migrations are forward-only: once applied they are
+//! recorded with a checksum, and a store whose recorded history doesn't match
+//! the migrations compiled into this build is refused rather than modified.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::info;
+
+use rusqlite::{Connection, params};
+
+use tokio::io;
+
+use crate::crypto;
+use crate::store::sqlite::sql_err;
+
+/// A single schema change.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+impl Migration {
+    /// Hex encoded SHA-256 of the migration's SQL.
+    pub fn checksum(&self) -> String {
+        crypto::hex(&crypto::sha256(self.sql.as_bytes()))
+    }
+}
+
+/// A migration that has been recorded as applied in a store.
+#[derive(Debug, PartialEq)]
+pub struct AppliedMigration {
+    pub version: u32,
+    pub checksum: String,
+}
+
+/// Migrations for [SqliteStore](crate::store::SqliteStore), in order.
+pub const SQLITE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "peer_state",
+        sql: include_str!("sqlite/0001_peer_state.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "objects",
+        sql: include_str!("sqlite/0002_objects.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "cold_storage",
+        sql: include_str!("sqlite/0003_cold_storage.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "takedowns",
+        sql: include_str!("sqlite/0004_takedowns.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "object_actor",
+        sql: include_str!("sqlite/0005_object_actor.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "subscriptions",
+        sql: include_str!("sqlite/0006_subscriptions.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "invites",
+        sql: include_str!("sqlite/0007_invites.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "federation_rules",
+        sql: include_str!("sqlite/0008_federation_rules.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "deliveries",
+        sql: include_str!("sqlite/0009_deliveries.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "subscription_port",
+        sql: include_str!("sqlite/0010_subscription_port.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "subscription_lease",
+        sql: include_str!("sqlite/0011_subscription_lease.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "subscription_topics",
+        sql: include_str!("sqlite/0012_subscription_topics.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "delivery_qos",
+        sql: include_str!("sqlite/0013_delivery_qos.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "delivery_pushed_sequence",
+        sql: include_str!("sqlite/0014_delivery_pushed_sequence.sql"),
+    },
+    Migration {
+        version: 15,
+        name: "scheduled_objects",
+        sql: include_str!("sqlite/0015_scheduled_objects.sql"),
+    },
+    Migration {
+        version: 16,
+        name: "bridged_items",
+        sql: include_str!("sqlite/0016_bridged_items.sql"),
+    },
+    Migration {
+        version: 17,
+        name: "subscription_type_versions",
+        sql: include_str!("sqlite/0017_subscription_type_versions.sql"),
+    },
+    Migration {
+        version: 18,
+        name: "standard_views",
+        sql: include_str!("sqlite/0018_standard_views.sql"),
+    },
+    Migration {
+        version: 19,
+        name: "dedup_keys",
+        sql: include_str!("sqlite/0019_dedup_keys.sql"),
+    },
+    Migration {
+        version: 20,
+        name: "tombstone_signature",
+        sql: include_str!("sqlite/0020_tombstone_signature.sql"),
+    },
+    Migration {
+        version: 21,
+        name: "subscription_peer_case",
+        sql: include_str!("sqlite/0021_subscription_peer_case.sql"),
+    },
+    Migration {
+        version: 22,
+        name: "delegations",
+        sql: include_str!("sqlite/0022_delegations.sql"),
+    },
+    Migration {
+        version: 23,
+        name: "bridge_cursors",
+        sql: include_str!("sqlite/0023_bridge_cursors.sql"),
+    },
+    Migration {
+        version: 24,
+        name: "undecodable_articles",
+        sql: include_str!("sqlite/0024_undecodable_articles.sql"),
+    },
+    Migration {
+        version: 25,
+        name: "audit_type",
+        sql: include_str!("sqlite/0025_audit_type.sql"),
+    },
+    Migration {
+        version: 26,
+        name: "delivery_scheme",
+        sql: include_str!("sqlite/0026_delivery_scheme.sql"),
+    },
+];
+
+/// Check the migrations recorded in a store against the `known` migrations
+/// and return the ones that still need to be applied, which includes any
+/// known migration missing from the store, not only those newer than the
+/// last one applied.
+///
+/// This fails if the store has migrations this build doesn't know about
+/// (it was written by a newer version) or if a recorded checksum doesn't match
+/// (the migration was edited after it shipped).
+pub fn pending<'a>(applied: &[AppliedMigration], known: &'a [Migration]) -> io::Result<Vec<&'a Migration>> {
+    for record in applied {
+        let Some(migration) = known.iter().find(|m| m.version == record.version) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Store has schema version {} which is newer than this build supports", record.version)
+            ));
+        };
+
+        if migration.checksum() != record.checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Checksum mismatch for migration {} ({})", migration.version, migration.name)
+            ));
+        }
+    }
+
+    Ok(known.iter().filter(|m| !applied.iter().any(|record| record.version == m.version)).collect())
+}
+
+/// Bring a SQLite database up to date, returning the resulting schema
+/// version.
+pub fn migrate_sqlite(conn: &mut Connection, known: &[Migration]) -> io::Result<u32> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS osp_schema_migrations (
+            version INTEGER PRIMARY KEY NOT NULL,
+            name TEXT NOT NULL,
+            checksum TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        );"
+    ).map_err(sql_err)?;
+
+    let applied = {
+        let mut stmt = conn.prepare("SELECT version, checksum FROM osp_schema_migrations ORDER BY version")
+            .map_err(sql_err)?;
+        let rows = stmt.query_map([], |row| Ok(AppliedMigration {
+            version: row.get(0)?,
+            checksum: row.get(1)?,
+        })).map_err(sql_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sql_err)?
+    };
+
+    let mut version = applied.iter().map(|m| m.version).max().unwrap_or(0);
+    for migration in pending(&applied, known)? {
+        info!("Applying migration {} ({})", migration.version, migration.name);
+        let applied_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+
+        let tx = conn.transaction().map_err(sql_err)?;
+        tx.execute_batch(migration.sql).map_err(sql_err)?;
+        tx.execute(
+            "INSERT INTO osp_schema_migrations (version, name, checksum, applied_at) VALUES (?1, ?2, ?3, ?4)",
+            params![migration.version, migration.name, migration.checksum(), applied_at],
+        ).map_err(sql_err)?;
+        tx.commit().map_err(sql_err)?;
+
+        version = version.max(migration.version);
+    }
+
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use tokio::io;
+
+    use super::{migrate_sqlite, Migration};
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration { version: 1, name: "first", sql: "CREATE TABLE first (id INTEGER);" },
+        Migration { version: 2, name: "second", sql: "CREATE TABLE second (id INTEGER);" },
+    ];
+
+    #[test]
+    fn test_migrations_apply_once() -> io::Result<()> {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        assert_eq!(migrate_sqlite(&mut conn, &TEST_MIGRATIONS[..1])?, 1);
+        assert_eq!(migrate_sqlite(&mut conn, TEST_MIGRATIONS)?, 2);
+        // running again is a no-op
+        assert_eq!(migrate_sqlite(&mut conn, TEST_MIGRATIONS)?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_skipped_migrations_are_applied() -> io::Result<()> {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        assert_eq!(migrate_sqlite(&mut conn, &TEST_MIGRATIONS[1..])?, 2);
+        assert_eq!(migrate_sqlite(&mut conn, TEST_MIGRATIONS)?, 2);
+        conn.execute("INSERT INTO first (id) VALUES (1)", []).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_newer_store_is_refused() -> io::Result<()> {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_sqlite(&mut conn, TEST_MIGRATIONS)?;
+
+        assert!(migrate_sqlite(&mut conn, &TEST_MIGRATIONS[..1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_mismatch_is_refused() -> io::Result<()> {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_sqlite(&mut conn, TEST_MIGRATIONS)?;
+
+        let edited = &[
+            Migration { version: 1, name: "first", sql: "CREATE TABLE first (id TEXT);" },
+        ];
+        assert!(migrate_sqlite(&mut conn, edited).is_err());
+        Ok(())
+    }
+}
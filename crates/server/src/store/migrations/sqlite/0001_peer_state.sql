@@ -0,0 +1,7 @@
+-- Stores created before migrations were introduced already have this table.
+CREATE TABLE IF NOT EXISTS peer_state (
+    hostname TEXT PRIMARY KEY NOT NULL,
+    cursor INTEGER NOT NULL,
+    next_sequence INTEGER NOT NULL,
+    resumption_token BLOB NOT NULL
+);
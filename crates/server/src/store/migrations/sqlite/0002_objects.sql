@@ -0,0 +1,10 @@
+CREATE TABLE objects (
+    object_id BLOB PRIMARY KEY NOT NULL,
+    type_id BLOB NOT NULL,
+    origin TEXT NOT NULL,
+    received_at INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    envelope BLOB NOT NULL
+);
+
+CREATE INDEX objects_type_received ON objects (type_id, received_at);
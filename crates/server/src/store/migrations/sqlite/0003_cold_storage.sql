@@ -0,0 +1,3 @@
+-- Key of the full envelope in cold storage. Objects with a cold key only keep
+-- their metadata locally.
+ALTER TABLE objects ADD COLUMN cold_key TEXT;
@@ -0,0 +1,16 @@
+CREATE TABLE tombstones (
+    object_id BLOB PRIMARY KEY NOT NULL,
+    reason TEXT NOT NULL,
+    authority TEXT NOT NULL,
+    taken_down_at INTEGER NOT NULL
+);
+
+-- Append-only record of actions taken on the node's content.
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    recorded_at INTEGER NOT NULL,
+    action TEXT NOT NULL,
+    object_id BLOB,
+    actor TEXT NOT NULL,
+    detail TEXT NOT NULL
+);
@@ -0,0 +1,5 @@
+-- Account that created the object, so an actor's objects can be found
+-- without decoding every envelope.
+ALTER TABLE objects ADD COLUMN actor TEXT;
+
+CREATE INDEX objects_actor ON objects (actor);
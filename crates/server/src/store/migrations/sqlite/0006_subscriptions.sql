@@ -0,0 +1,7 @@
+CREATE TABLE subscriptions (
+    peer TEXT PRIMARY KEY NOT NULL,
+    -- Concatenated 16 byte ids of the subscribed data types.
+    data_types BLOB NOT NULL,
+    state INTEGER NOT NULL,
+    requested_at INTEGER NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE invites (
+    token_id BLOB PRIMARY KEY NOT NULL,
+    -- The signed invite, as a token.
+    invite TEXT NOT NULL,
+    issued_at INTEGER NOT NULL,
+    last_used_at INTEGER,
+    revoked INTEGER NOT NULL DEFAULT 0
+);
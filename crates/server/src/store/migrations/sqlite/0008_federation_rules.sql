@@ -0,0 +1,6 @@
+CREATE TABLE federation_rules (
+    host TEXT PRIMARY KEY NOT NULL,
+    -- 1 for an allowed host, 0 for a denied one.
+    allow INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
@@ -0,0 +1,12 @@
+CREATE TABLE deliveries (
+    id BLOB PRIMARY KEY NOT NULL,
+    peer TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    envelope BLOB NOT NULL,
+    attempts INTEGER NOT NULL,
+    -- Unix timestamp in milliseconds.
+    next_attempt_at INTEGER NOT NULL,
+    last_error TEXT
+);
+
+CREATE INDEX deliveries_next_attempt ON deliveries (next_attempt_at);
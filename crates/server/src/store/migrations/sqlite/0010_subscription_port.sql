@@ -0,0 +1,2 @@
+-- Port the subscribed peer takes deliveries on, NULL if it doesn't listen.
+ALTER TABLE subscriptions ADD COLUMN port INTEGER;
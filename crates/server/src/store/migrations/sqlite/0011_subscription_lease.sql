@@ -0,0 +1,3 @@
+-- Unix timestamp (seconds) the subscription's lease runs out at, NULL if it
+-- doesn't expire.
+ALTER TABLE subscriptions ADD COLUMN expires_at INTEGER;
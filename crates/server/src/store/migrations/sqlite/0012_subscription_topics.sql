@@ -0,0 +1,3 @@
+-- Topic filters the subscription is limited to, one per line, empty if it
+-- takes every object of its data types.
+ALTER TABLE subscriptions ADD COLUMN topics TEXT NOT NULL DEFAULT '';
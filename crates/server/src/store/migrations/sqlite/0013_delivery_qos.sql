@@ -0,0 +1,6 @@
+-- How objects are delivered to the subscriber, and how a queued delivery
+-- is pushed: 0 fire and forget, 1 at least once, 2 ordered.
+ALTER TABLE subscriptions ADD COLUMN qos INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE deliveries ADD COLUMN qos INTEGER NOT NULL DEFAULT 1;
+
+CREATE INDEX deliveries_peer ON deliveries (peer, port);
@@ -0,0 +1,3 @@
+-- Sequence a queued delivery was first pushed with, when the push was not
+-- acknowledged in time. NULL if it was never pushed that way.
+ALTER TABLE deliveries ADD COLUMN pushed_sequence INTEGER;
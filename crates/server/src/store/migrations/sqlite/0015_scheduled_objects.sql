@@ -0,0 +1,9 @@
+CREATE TABLE scheduled_objects (
+    object_id BLOB PRIMARY KEY NOT NULL,
+    envelope BLOB NOT NULL,
+    -- Unix timestamps in seconds.
+    publish_at INTEGER NOT NULL,
+    scheduled_at INTEGER NOT NULL
+);
+
+CREATE INDEX scheduled_objects_publish_at ON scheduled_objects (publish_at);
@@ -0,0 +1,8 @@
+-- Objects carrying the items of RSS feeds, whether bridged or pushed by the
+-- feed's publisher, by the publisher's hostname and the item's guid or link.
+CREATE TABLE bridged_items (
+    origin TEXT NOT NULL,
+    item_key TEXT NOT NULL,
+    object_id BLOB NOT NULL,
+    PRIMARY KEY (origin, item_key)
+);
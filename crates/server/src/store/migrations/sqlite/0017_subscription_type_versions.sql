@@ -0,0 +1,3 @@
+-- The latest version of data types the subscriber understands, each as its
+-- 16 byte type id followed by the version as a big-endian u16.
+ALTER TABLE subscriptions ADD COLUMN type_versions BLOB NOT NULL DEFAULT x'';
@@ -0,0 +1,50 @@
+-- Fields of stored articles, decoded when they are stored so they can be
+-- queried with plain SQL. Authors and tags are JSON arrays.
+CREATE TABLE article_fields (
+    object_id BLOB PRIMARY KEY NOT NULL,
+    title TEXT NOT NULL,
+    summary TEXT,
+    content TEXT NOT NULL,
+    url TEXT,
+    authors TEXT NOT NULL,
+    published_at INTEGER NOT NULL,
+    tags TEXT NOT NULL
+);
+
+CREATE INDEX article_fields_published ON article_fields (published_at);
+
+CREATE TRIGGER article_fields_removed AFTER DELETE ON objects
+BEGIN
+    DELETE FROM article_fields WHERE object_id = OLD.object_id;
+END;
+
+-- Ids as they are written elsewhere, e.g. 0b6f1a9e-4c8d-4e2a-9f3b-7d5c2e1a8b40
+CREATE VIEW osp_objects AS
+SELECT
+    lower(substr(hex(object_id), 1, 8) || '-' || substr(hex(object_id), 9, 4) || '-' || substr(hex(object_id), 13, 4) || '-'
+        || substr(hex(object_id), 17, 4) || '-' || substr(hex(object_id), 21)) AS object_id,
+    lower(substr(hex(type_id), 1, 8) || '-' || substr(hex(type_id), 9, 4) || '-' || substr(hex(type_id), 13, 4) || '-'
+        || substr(hex(type_id), 17, 4) || '-' || substr(hex(type_id), 21)) AS type_id,
+    origin,
+    actor,
+    received_at,
+    size,
+    cold_key IS NOT NULL AS tiered
+FROM objects;
+
+CREATE VIEW osp_articles AS
+SELECT
+    lower(substr(hex(o.object_id), 1, 8) || '-' || substr(hex(o.object_id), 9, 4) || '-' || substr(hex(o.object_id), 13, 4) || '-'
+        || substr(hex(o.object_id), 17, 4) || '-' || substr(hex(o.object_id), 21)) AS object_id,
+    o.origin,
+    o.actor,
+    o.received_at,
+    a.title,
+    a.summary,
+    a.content,
+    a.url,
+    a.authors,
+    a.published_at,
+    a.tags
+FROM article_fields a
+JOIN objects o ON o.object_id = a.object_id;
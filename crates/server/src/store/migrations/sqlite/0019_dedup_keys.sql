@@ -0,0 +1,7 @@
+CREATE TABLE dedup_keys (
+    key BLOB PRIMARY KEY NOT NULL,
+    -- Unix timestamp in seconds.
+    recorded_at INTEGER NOT NULL
+);
+
+CREATE INDEX dedup_keys_recorded_at ON dedup_keys (recorded_at);
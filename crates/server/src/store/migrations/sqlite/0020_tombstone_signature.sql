@@ -0,0 +1,3 @@
+-- Ed25519 signature of the tombstone by the node that issued the takedown,
+-- NULL if it wasn't signed.
+ALTER TABLE tombstones ADD COLUMN signature BLOB;
@@ -0,0 +1,3 @@
+-- Subscriptions are kept under the lowercase hostname of the peer. Of
+-- subscriptions differing only in case, one is kept.
+UPDATE OR REPLACE subscriptions SET peer = lower(peer);
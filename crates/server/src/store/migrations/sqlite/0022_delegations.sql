@@ -0,0 +1,9 @@
+-- Fan-out delegations this node issued or relays along. Those it issued
+-- have the port and scheme the relay listens with, to revoke them.
+CREATE TABLE delegations (
+    delegation_id BLOB PRIMARY KEY NOT NULL,
+    -- The signed delegation, encoded as sent.
+    delegation BLOB NOT NULL,
+    relay_port INTEGER,
+    relay_scheme TEXT
+);
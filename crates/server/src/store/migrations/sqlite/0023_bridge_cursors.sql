@@ -0,0 +1,7 @@
+-- How far a bridge read the source it takes objects in from, e.g. the
+-- creation time of the latest event taken in from a Nostr relay, so it
+-- picks up from there after a restart.
+CREATE TABLE bridge_cursors (
+    source TEXT PRIMARY KEY NOT NULL,
+    cursor INTEGER NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Articles whose fields couldn't be decoded, so they aren't decoded again
+-- every time the store is opened.
+CREATE TABLE undecodable_articles (
+    object_id BLOB PRIMARY KEY NOT NULL
+);
+
+CREATE TRIGGER undecodable_articles_removed AFTER DELETE ON objects
+BEGIN
+    DELETE FROM undecodable_articles WHERE object_id = OLD.object_id;
+END;
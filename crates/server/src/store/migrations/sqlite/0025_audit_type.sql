@@ -0,0 +1,9 @@
+-- The type of the object an action was taken on, so evictions can be
+-- counted per type without parsing their detail, and an index to read the
+-- entries of a period without scanning the whole log.
+ALTER TABLE audit_log ADD COLUMN type_id BLOB;
+
+UPDATE audit_log SET type_id = unhex(replace(substr(detail, -36), '-', ''))
+WHERE action = 'evict' AND detail LIKE 'Over the storage quota of type %';
+
+CREATE INDEX audit_log_recorded ON audit_log (recorded_at);
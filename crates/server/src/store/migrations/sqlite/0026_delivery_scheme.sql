@@ -0,0 +1,4 @@
+-- How peers are connected to for deliveries and subscriptions, so that
+-- those reached over WebSockets aren't dialed over raw TCP.
+ALTER TABLE deliveries ADD COLUMN scheme TEXT NOT NULL DEFAULT 'osp';
+ALTER TABLE subscriptions ADD COLUMN scheme TEXT NOT NULL DEFAULT 'osp';
@@ -0,0 +1,372 @@
+//! # Data Store
+//!
+//! Persistent node state. Everything a node needs to remember across restarts
+//! goes through a [DataStore], so that embedders can pick the backend that
+//! suits their deployment.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_protocol::{Envelope, Tombstone};
+
+mod memory;
+#[cfg(feature = "storage-sqlite")]
+mod sqlite;
+pub mod archive;
+pub mod backup;
+#[cfg(feature = "storage-sqlite")]
+pub mod migrations;
+pub mod quota;
+pub mod tiering;
+#[cfg(feature = "tiering-s3")]
+pub mod s3;
+
+pub use memory::MemoryStore;
+#[cfg(feature = "storage-sqlite")]
+pub use sqlite::SqliteStore;
+
+use crate::delivery::PendingDelivery;
+use crate::embargo::ScheduledObject;
+use crate::fanout::DelegationRecord;
+use crate::federation::FederationRule;
+use crate::invite::InviteRecord;
+use crate::store::backup::BackupManifest;
+use crate::subscription::Subscription;
+
+/// Synchronization state kept for each peer, keyed by the peer's hostname.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerSyncState {
+    /// The highest sequence number received from the peer and processed.
+    pub cursor: u64,
+    /// The next sequence number to assign to an object sent to the peer.
+    pub next_sequence: u64,
+    /// Token identifying the sync session, presented to resume it after a
+    /// reconnect instead of starting a full re-sync.
+    pub resumption_token: Uuid,
+}
+
+impl Default for PeerSyncState {
+    fn default() -> Self {
+        PeerSyncState {
+            cursor: 0,
+            next_sequence: 1,
+            resumption_token: Uuid::new_v4(),
+        }
+    }
+}
+
+/// The error for updating the sync state of a peer that has none saved.
+pub(crate) fn no_peer_state(hostname: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no sync state saved for {hostname}"))
+}
+
+/// How much of the store a single data type takes up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TypeUsage {
+    pub type_id: Uuid,
+    /// Number of stored objects of the type.
+    pub objects: u64,
+    /// Total size of the stored objects' encoded envelopes.
+    pub bytes: u64,
+}
+
+/// How much of the store the objects of a data type from a single origin
+/// take up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OriginUsage {
+    pub type_id: Uuid,
+    pub origin: String,
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+/// An action recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditAction {
+    /// An object was taken down and replaced with a tombstone.
+    Takedown,
+    /// Every object of an actor was deleted.
+    Purge,
+    /// An object was deleted at the request of its origin.
+    Delete,
+    /// The operator approved a peer's subscription.
+    ApproveSubscription,
+    /// The operator denied a peer's subscription.
+    DenySubscription,
+    /// The operator issued an invite.
+    IssueInvite,
+    /// The operator revoked an invite.
+    RevokeInvite,
+    /// A federation rule was set by the operator, or merged from a cluster
+    /// member.
+    UpdateFederation,
+    /// An object was deleted to keep its type within its storage quota.
+    Evict,
+}
+
+impl FromStr for AuditAction {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "takedown" => Ok(AuditAction::Takedown),
+            "purge" => Ok(AuditAction::Purge),
+            "delete" => Ok(AuditAction::Delete),
+            "approve_subscription" => Ok(AuditAction::ApproveSubscription),
+            "deny_subscription" => Ok(AuditAction::DenySubscription),
+            "issue_invite" => Ok(AuditAction::IssueInvite),
+            "revoke_invite" => Ok(AuditAction::RevokeInvite),
+            "update_federation" => Ok(AuditAction::UpdateFederation),
+            "evict" => Ok(AuditAction::Evict),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown audit action {s}"))),
+        }
+    }
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditAction::Takedown => write!(f, "takedown"),
+            AuditAction::Purge => write!(f, "purge"),
+            AuditAction::Delete => write!(f, "delete"),
+            AuditAction::ApproveSubscription => write!(f, "approve_subscription"),
+            AuditAction::DenySubscription => write!(f, "deny_subscription"),
+            AuditAction::IssueInvite => write!(f, "issue_invite"),
+            AuditAction::RevokeInvite => write!(f, "revoke_invite"),
+            AuditAction::UpdateFederation => write!(f, "update_federation"),
+            AuditAction::Evict => write!(f, "evict"),
+        }
+    }
+}
+
+/// An entry in the audit log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    /// Unix timestamp (seconds) the action was taken at.
+    pub recorded_at: u64,
+    pub action: AuditAction,
+    /// The object the action was taken on, if any.
+    pub object_id: Option<Uuid>,
+    /// The type of that object, if known.
+    pub type_id: Option<Uuid>,
+    /// Who took the action: `operator` for the node's own operator and the
+    /// policies it configured, or the hostname of the peer that requested
+    /// it.
+    pub actor: String,
+    pub detail: String,
+}
+
+/// What [OSProtocolNode::purge_actor](crate::OSProtocolNode::purge_actor)
+/// deleted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PurgeReport {
+    pub actor: String,
+    /// Ids of the deleted objects.
+    pub objects: Vec<Uuid>,
+    /// How many of the deleted objects had their payload in cold storage.
+    pub cold_objects: usize,
+    /// Unix timestamp (seconds) the purge completed at.
+    pub purged_at: u64,
+}
+
+/// Storage backend for a node.
+pub trait DataStore: Send + Sync {
+    /// Load the sync state for `hostname`, if any has been saved.
+    fn peer_state(&self, hostname: &str) -> io::Result<Option<PeerSyncState>>;
+
+    /// Save the sync state for `hostname`, replacing any previous state.
+    fn put_peer_state(&self, hostname: &str, state: &PeerSyncState) -> io::Result<()>;
+
+    /// Forget the sync state for `hostname`. The next session with the peer
+    /// will start from scratch.
+    fn remove_peer_state(&self, hostname: &str) -> io::Result<()>;
+
+    /// Move the cursor for `hostname` on to `sequence` if it is at the
+    /// sequence before, leaving the rest of the state alone. Returns the
+    /// cursor as it was, and a `NotFound` error if no state is saved.
+    fn advance_peer_cursor(&self, hostname: &str, sequence: u64) -> io::Result<u64>;
+
+    /// Allocate the next sequence number to send `hostname`, leaving the rest
+    /// of the state alone. Returns a `NotFound` error if no state is saved.
+    fn take_peer_sequence(&self, hostname: &str) -> io::Result<u64>;
+
+    /// Store an object. Storing an object that is already present replaces
+    /// it.
+    fn put_object(&self, envelope: &Envelope) -> io::Result<()>;
+
+    /// Load the object with id `object_id`.
+    fn get_object(&self, object_id: Uuid) -> io::Result<Option<Envelope>>;
+
+    /// Delete the object with id `object_id`, returning whether it existed.
+    fn remove_object(&self, object_id: Uuid) -> io::Result<bool>;
+
+    /// The ids of up to `limit` stored objects of any type, in the order they
+    /// were stored, skipping the first `offset`.
+    fn list_objects(&self, offset: usize, limit: usize) -> io::Result<Vec<Uuid>>;
+
+    /// The ids of up to `limit` objects created by `actor`.
+    fn objects_by_actor(&self, actor: &str, limit: usize) -> io::Result<Vec<Uuid>>;
+
+    /// Record that an object was taken down. Storing a tombstone that is
+    /// already present replaces it.
+    fn put_tombstone(&self, tombstone: &Tombstone) -> io::Result<()>;
+
+    /// The tombstone left for `object_id`, if it was taken down.
+    fn tombstone(&self, object_id: Uuid) -> io::Result<Option<Tombstone>>;
+
+    /// Append `entry` to the audit log.
+    fn append_audit(&self, entry: &AuditEntry) -> io::Result<()>;
+
+    /// Up to `limit` audit log entries, oldest first, skipping the first
+    /// `offset`.
+    fn audit_log(&self, offset: usize, limit: usize) -> io::Result<Vec<AuditEntry>>;
+
+    /// Up to `limit` audit log entries recorded between the unix timestamps
+    /// `from` and `to` (seconds, `to` excluded), oldest first, skipping the
+    /// first `offset` of them.
+    fn audit_log_between(&self, from: u64, to: u64, offset: usize, limit: usize) -> io::Result<Vec<AuditEntry>>;
+
+    /// Save a peer's subscription, replacing any previous one of the same
+    /// peer.
+    fn put_subscription(&self, subscription: &Subscription) -> io::Result<()>;
+
+    /// The subscription of `peer`, if it has requested one.
+    fn subscription(&self, peer: &str) -> io::Result<Option<Subscription>>;
+
+    /// Every subscription, in any state.
+    fn subscriptions(&self) -> io::Result<Vec<Subscription>>;
+
+    /// Remove the subscription of `peer`, returning whether it had one.
+    fn remove_subscription(&self, peer: &str) -> io::Result<bool>;
+
+    /// Save an invite issued by this node, replacing any previous record of
+    /// the same invite.
+    fn put_invite(&self, record: &InviteRecord) -> io::Result<()>;
+
+    /// The record of the invite with id `token_id`, if this node issued it.
+    fn invite(&self, token_id: Uuid) -> io::Result<Option<InviteRecord>>;
+
+    /// Every invite this node issued.
+    fn invites(&self) -> io::Result<Vec<InviteRecord>>;
+
+    /// Save a federation rule, replacing any previous rule for the same host.
+    fn put_federation_rule(&self, rule: &FederationRule) -> io::Result<()>;
+
+    /// The federation rule for `host`, if there is one.
+    fn federation_rule(&self, host: &str) -> io::Result<Option<FederationRule>>;
+
+    /// Every federation rule.
+    fn federation_rules(&self) -> io::Result<Vec<FederationRule>>;
+
+    /// The ids of the `limit` objects of `type_id` that were stored first.
+    fn oldest_objects(&self, type_id: Uuid, limit: usize) -> io::Result<Vec<Uuid>>;
+
+    /// Storage used by `type_id`.
+    fn type_usage(&self, type_id: Uuid) -> io::Result<TypeUsage>;
+
+    /// Storage used by every data type with at least one stored object.
+    fn usage(&self) -> io::Result<Vec<TypeUsage>>;
+
+    /// Storage used by the objects of each type and origin stored between
+    /// the unix timestamps `from` and `to` (seconds, `to` excluded).
+    fn origin_usage(&self, from: u64, to: u64) -> io::Result<Vec<OriginUsage>>;
+
+    /// The ids of up to `limit` objects stored before the unix timestamp
+    /// `cutoff` that still have their payload stored locally.
+    fn objects_received_before(&self, cutoff: u64, limit: usize) -> io::Result<Vec<Uuid>>;
+
+    /// Replace a stored object with `stub`, a copy without its payload, and
+    /// remember that the full envelope lives in cold storage under `cold_key`.
+    fn tier_object(&self, stub: &Envelope, cold_key: &str) -> io::Result<()>;
+
+    /// Where the full envelope of a tiered object lives in cold storage, or
+    /// `None` if the object is stored locally.
+    fn cold_key(&self, object_id: Uuid) -> io::Result<Option<String>>;
+
+    /// Queue a delivery, replacing any previous one with the same id.
+    fn put_delivery(&self, delivery: &PendingDelivery) -> io::Result<()>;
+
+    /// Up to `limit` deliveries due at or before the unix timestamp `now`
+    /// (milliseconds), the longest overdue first, leaving out those to the
+    /// peers in `paused`.
+    fn due_deliveries(&self, now: u64, limit: usize, paused: &[String]) -> io::Result<Vec<PendingDelivery>>;
+
+    /// The deliveries queued for the peer `peer` listening on `port`, due or
+    /// not, the soonest due first.
+    fn queued_deliveries(&self, peer: &str, port: u16) -> io::Result<Vec<PendingDelivery>>;
+
+    /// Remove the delivery with id `id` from the queue, returning whether
+    /// it was queued.
+    fn remove_delivery(&self, id: Uuid) -> io::Result<bool>;
+
+    /// Number of queued deliveries.
+    fn delivery_count(&self) -> io::Result<u64>;
+
+    /// Hold back an object until its release, replacing any scheduled object
+    /// with the same id.
+    fn put_scheduled(&self, scheduled: &ScheduledObject) -> io::Result<()>;
+
+    /// The scheduled object with id `object_id`, if there is one.
+    fn scheduled(&self, object_id: Uuid) -> io::Result<Option<ScheduledObject>>;
+
+    /// Every scheduled object, the soonest released first.
+    fn scheduled_objects(&self) -> io::Result<Vec<ScheduledObject>>;
+
+    /// Up to `limit` scheduled objects due at or before the unix timestamp
+    /// `now` (seconds), the longest overdue first.
+    fn due_scheduled(&self, now: u64, limit: usize) -> io::Result<Vec<ScheduledObject>>;
+
+    /// Remove the scheduled object with id `object_id`, returning whether
+    /// there was one.
+    fn remove_scheduled(&self, object_id: Uuid) -> io::Result<bool>;
+
+    /// Move the release of the scheduled object `object_id` to `publish_at`,
+    /// returning whether it was still scheduled. Objects released or
+    /// cancelled meanwhile stay that way.
+    fn reschedule(&self, object_id: Uuid, publish_at: u64) -> io::Result<bool>;
+
+    /// The object carrying the item with key `key` of the feed of `origin`,
+    /// if it was [bridged](crate::feed) or pushed by the origin.
+    fn bridged_item(&self, origin: &str, key: &str) -> io::Result<Option<Uuid>>;
+
+    /// Remember that the object `object_id` carries the item with key `key`
+    /// of the feed of `origin`.
+    fn put_bridged_item(&self, origin: &str, key: &str, object_id: Uuid) -> io::Result<()>;
+
+    /// How far a bridge read `source`, if it did, e.g. the creation time
+    /// of the latest event it took in from a [Nostr](crate::nostr) relay.
+    fn bridge_cursor(&self, source: &str) -> io::Result<Option<u64>>;
+
+    /// Remember that a bridge read `source` as far as `cursor`.
+    fn put_bridge_cursor(&self, source: &str, cursor: u64) -> io::Result<()>;
+
+    /// Save a [fan-out](crate::fanout) delegation this node issued or
+    /// relays along, replacing any previous record of the same delegation.
+    fn put_delegation(&self, record: &DelegationRecord) -> io::Result<()>;
+
+    /// Every delegation this node issued or relays along, in the order they
+    /// were first saved.
+    fn delegations(&self) -> io::Result<Vec<DelegationRecord>>;
+
+    /// Remove the delegation with id `delegation_id`, returning whether
+    /// there was one.
+    fn remove_delegation(&self, delegation_id: Uuid) -> io::Result<bool>;
+
+    /// Periodic housekeeping, run in the background while the node listens.
+    fn maintain(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Write a consistent snapshot of the store to `path`, along with a
+    /// manifest for verifying it. Stores that can't be snapshotted return
+    /// [io::ErrorKind::Unsupported].
+    fn backup(&self, path: &Path) -> io::Result<BackupManifest> {
+        let _ = path;
+        Err(io::Error::new(io::ErrorKind::Unsupported, "This store does not support backups"))
+    }
+}
@@ -0,0 +1,202 @@
+//! # Storage Quotas
+//!
+//! Optional per data type limits on how much of the store a type may use.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_protocol::Envelope;
+
+use crate::embargo;
+use crate::metrics;
+use crate::store::{AuditAction, AuditEntry, DataStore, TypeUsage};
+
+/// What to do when storing an object would push a type over its quota.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+    /// Delete the oldest objects of the type until the new one fits.
+    OldestFirst,
+    /// Refuse to store the new object.
+    RejectNew,
+}
+
+/// Limits for a single data type. A limit of `None` is unbounded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StorageQuota {
+    pub max_objects: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub eviction: EvictionPolicy,
+}
+
+impl StorageQuota {
+    fn exceeded_by(&self, objects: u64, bytes: u64) -> bool {
+        self.max_objects.is_some_and(|max| objects > max) || self.max_bytes.is_some_and(|max| bytes > max)
+    }
+
+    /// Whether storing an object of `size` bytes takes the type over this
+    /// quota, given the size of the stored object it replaces, if any.
+    fn exceeded_after(&self, usage: &TypeUsage, size: u64, replaced: Option<u64>) -> bool {
+        match replaced {
+            Some(old) => self.exceeded_by(usage.objects, usage.bytes.saturating_sub(old) + size),
+            None => self.exceeded_by(usage.objects + 1, usage.bytes + size),
+        }
+    }
+}
+
+/// The quotas configured on a node, keyed by data type id.
+#[derive(Clone, Debug, Default)]
+pub struct StorageQuotas {
+    quotas: HashMap<Uuid, StorageQuota>,
+    /// Held from checking the usage of a type to storing the object, so
+    /// objects stored at once can't take the same room
+    storing: Arc<Mutex<()>>,
+}
+
+impl StorageQuotas {
+    pub fn set(&mut self, type_id: Uuid, quota: StorageQuota) {
+        self.quotas.insert(type_id, quota);
+    }
+
+    pub fn get(&self, type_id: Uuid) -> Option<&StorageQuota> {
+        self.quotas.get(&type_id)
+    }
+
+    /// Every quota, with the type it is for.
+    pub fn iter(&self) -> impl Iterator<Item = (Uuid, &StorageQuota)> {
+        self.quotas.iter().map(|(type_id, quota)| (*type_id, quota))
+    }
+
+    /// Store `envelope`, first making room for it or refusing it according to
+    /// the quota for its type. Storing an object again only counts the
+    /// difference in size against the quota.
+    pub fn store(&self, store: &dyn DataStore, envelope: &Envelope) -> io::Result<()> {
+        let _storing = self.quotas.contains_key(&envelope.type_id)
+            .then(|| self.storing.lock().unwrap());
+        if let Some(quota) = self.quotas.get(&envelope.type_id) {
+            let size = envelope.to_bytes()?.len() as u64;
+            let mut usage = store.type_usage(envelope.type_id)?;
+            let mut replaced = match store.get_object(envelope.object_id)? {
+                Some(existing) if existing.type_id == envelope.type_id => Some(existing.to_bytes()?.len() as u64),
+                _ => None,
+            };
+
+            if quota.exceeded_after(&usage, size, replaced) {
+                if quota.eviction == EvictionPolicy::RejectNew
+                    || quota.max_objects == Some(0)
+                    || quota.max_bytes.is_some_and(|max| size > max)
+                {
+                    warn!("Rejecting object {} as type {} is over its storage quota", envelope.object_id, envelope.type_id);
+                    metrics::storage_rejected(envelope.type_id);
+                    return Err(io::Error::new(
+                        io::ErrorKind::QuotaExceeded,
+                        format!("Storage quota exceeded for type {}", envelope.type_id)
+                    ));
+                }
+
+                while quota.exceeded_after(&usage, size, replaced) {
+                    let oldest = store.oldest_objects(envelope.type_id, 1)?;
+                    let Some(object_id) = oldest.first() else { break };
+                    info!("Evicting object {object_id} to stay within the quota for type {}", envelope.type_id);
+                    store.remove_object(*object_id)?;
+                    store.append_audit(&AuditEntry {
+                        recorded_at: embargo::now(),
+                        action: AuditAction::Evict,
+                        object_id: Some(*object_id),
+                        actor: "operator".to_string(),
+                        detail: format!("Over the storage quota of type {}", envelope.type_id),
+                        type_id: Some(envelope.type_id),
+                    })?;
+                    metrics::storage_evicted(envelope.type_id);
+                    if *object_id == envelope.object_id {
+                        replaced = None;
+                    }
+                    usage = store.type_usage(envelope.type_id)?;
+                }
+            }
+        }
+
+        store.put_object(envelope)?;
+        metrics::storage_usage(&store.type_usage(envelope.type_id)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_protocol::Envelope;
+
+    use crate::store::{DataStore, MemoryStore};
+    use crate::store::quota::{EvictionPolicy, StorageQuota, StorageQuotas};
+
+    fn quotas(type_id: Uuid, eviction: EvictionPolicy) -> StorageQuotas {
+        let mut quotas = StorageQuotas::default();
+        quotas.set(type_id, StorageQuota { max_objects: Some(2), max_bytes: None, eviction });
+        quotas
+    }
+
+    #[test]
+    fn test_oldest_first_eviction() -> io::Result<()> {
+        let (store, type_id) = (MemoryStore::new(), Uuid::new_v4());
+        let quotas = quotas(type_id, EvictionPolicy::OldestFirst);
+
+        let objects = (0..3).map(|_| Envelope::new(type_id, "origin.test".to_string(), vec![0])).collect::<Vec<_>>();
+        for envelope in &objects {
+            quotas.store(&store, envelope)?;
+        }
+
+        assert_eq!(store.type_usage(type_id)?.objects, 2);
+        assert_eq!(store.get_object(objects[0].object_id)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_new() -> io::Result<()> {
+        let (store, type_id) = (MemoryStore::new(), Uuid::new_v4());
+        let quotas = quotas(type_id, EvictionPolicy::RejectNew);
+
+        quotas.store(&store, &Envelope::new(type_id, "origin.test".to_string(), vec![0]))?;
+        quotas.store(&store, &Envelope::new(type_id, "origin.test".to_string(), vec![0]))?;
+        assert!(quotas.store(&store, &Envelope::new(type_id, "origin.test".to_string(), vec![0])).is_err());
+        assert_eq!(store.type_usage(type_id)?.objects, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_storing_again_counts_the_difference() -> io::Result<()> {
+        let (store, type_id) = (MemoryStore::new(), Uuid::new_v4());
+        let mut quotas = StorageQuotas::default();
+        let envelope = Envelope::new(type_id, "origin.test".to_string(), vec![0; 16]);
+        let size = envelope.to_bytes()?.len() as u64;
+        quotas.set(type_id, StorageQuota { max_objects: Some(1), max_bytes: Some(size + 8), eviction: EvictionPolicy::RejectNew });
+
+        quotas.store(&store, &envelope)?;
+        quotas.store(&store, &envelope)?;
+        quotas.store(&store, &Envelope { payload: vec![0; 24], ..envelope.clone() })?;
+        assert!(quotas.store(&store, &Envelope { payload: vec![0; 32], ..envelope.clone() }).is_err());
+        assert_eq!(store.type_usage(type_id)?.objects, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_stores_stay_within_quota() -> io::Result<()> {
+        let (store, type_id) = (MemoryStore::new(), Uuid::new_v4());
+        let quotas = quotas(type_id, EvictionPolicy::RejectNew);
+
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| quotas.store(&store, &Envelope::new(type_id, "origin.test".to_string(), vec![0])));
+            }
+        });
+        assert_eq!(store.type_usage(type_id)?.objects, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! # S3 Cold Storage
+//!
+//! [ColdStorage] on any S3-compatible object store, signed with AWS Signature
+//! Version 4.
+
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io;
+
+use url::Url;
+
+use crate::crypto::{hex, hmac_sha256, sha256};
+use crate::store::tiering::ColdStorage;
+use crate::time;
+
+/// Connection settings for an S3-compatible bucket.
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    /// Endpoint of the service, e.g. `https://s3.eu-west-1.amazonaws.com`.
+    pub endpoint: Url,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Prefix prepended to every object key.
+    pub prefix: String,
+}
+
+pub struct S3ColdStorage {
+    config: S3Config,
+    agent: ureq::Agent,
+}
+
+/// Format a unix timestamp as the `(YYYYMMDD, YYYYMMDD'T'HHMMSS'Z')` pair
+/// used by SigV4.
+fn amz_dates(timestamp: u64) -> (String, String) {
+    let (year, month, day, hour, minute, second) = time::civil(timestamp);
+    let date = format!("{year:04}{month:02}{day:02}");
+    let time = format!("{date}T{hour:02}{minute:02}{second:02}Z");
+    (date, time)
+}
+
+fn to_io(err: ureq::Error) -> io::Error {
+    match err {
+        ureq::Error::Status(404, _) => io::Error::new(io::ErrorKind::NotFound, "Object not found in cold storage"),
+        ureq::Error::Status(code, resp) => io::Error::other(
+            format!("S3 request failed with status {code}: {}", resp.status_text())
+        ),
+        ureq::Error::Transport(t) => io::Error::other(t.to_string()),
+    }
+}
+
+impl S3ColdStorage {
+    pub fn new(config: S3Config) -> Self {
+        Self {
+            config,
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    fn request(&self, method: &str, key: &str, body: &[u8]) -> io::Result<ureq::Response> {
+        let path = format!("/{}/{}{}", self.config.bucket, self.config.prefix, key);
+        let url = self.config.endpoint.join(&path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let (date, amz_date) = amz_dates(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+        let payload_hash = hex(&sha256(body));
+        let scope = format!("{date}/{}/s3/aws4_request", self.config.region);
+
+        let canonical_request = format!(
+            "{method}\n{}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\nhost;x-amz-content-sha256;x-amz-date\n{payload_hash}",
+            url.path()
+        );
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}", hex(&sha256(canonical_request.as_bytes())));
+
+        let mut key_bytes = hmac_sha256(format!("AWS4{}", self.config.secret_key).as_bytes(), date.as_bytes())?;
+        for part in [self.config.region.as_str(), "s3", "aws4_request"] {
+            key_bytes = hmac_sha256(&key_bytes, part.as_bytes())?;
+        }
+        let signature = hex(&hmac_sha256(&key_bytes, string_to_sign.as_bytes())?);
+
+        self.agent.request_url(method, &url)
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("x-amz-date", &amz_date)
+            .set("authorization", &format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}",
+                self.config.access_key
+            ))
+            .send_bytes(body)
+            .map_err(to_io)
+    }
+}
+
+impl ColdStorage for S3ColdStorage {
+    fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+        self.request("PUT", key, bytes).map(|_| ())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.request("GET", key, &[])?.into_reader().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match self.request("DELETE", key, &[]) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::amz_dates;
+
+    #[test]
+    fn test_amz_dates() {
+        assert_eq!(amz_dates(1718454896), ("20240615".to_string(), "20240615T123456Z".to_string()));
+    }
+}
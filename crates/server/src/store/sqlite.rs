@@ -0,0 +1,1100 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info};
+
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Params, params};
+use rusqlite::backup::Backup;
+use rusqlite::types::Value;
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_data::{ContentHash, Data};
+use osp_data::standard::Article;
+use osp_protocol::{Envelope, OSPUrl, Scheme, Tombstone};
+
+use crate::dedup::DedupBackend;
+use crate::delivery::PendingDelivery;
+use crate::embargo::ScheduledObject;
+use crate::fanout::{self, DelegationRecord};
+use crate::federation::{FederationAction, FederationRule};
+use crate::invite::{self, InviteRecord};
+use crate::store::{no_peer_state, AuditEntry, DataStore, OriginUsage, PeerSyncState, TypeUsage};
+use crate::store::backup::BackupManifest;
+use crate::store::migrations::{migrate_sqlite, SQLITE_MIGRATIONS};
+use crate::subscription::{DeliveryQos, Subscription, SubscriptionState};
+
+/// A [DataStore] backed by a SQLite database file. The schema is migrated to
+/// the latest version when the store is opened.
+///
+/// Stored objects can be queried with plain SQL, through [query](Self::query)
+/// or any SQLite client opening the file, with the views
+///
+/// - `osp_objects`: `object_id`, `type_id`, `origin`, `actor`,
+///   `received_at`, `size` and whether the object is `tiered` to cold
+///   storage, for every object
+/// - `osp_articles`: `object_id`, `origin`, `actor`, `received_at` and the
+///   fields of every [Article], with its `authors` and `tags` as JSON arrays
+///
+/// Ids are written as hyphenated lowercase UUIDs. Fields are decoded when an
+/// object is stored, so they stay queryable once it is tiered.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    /// Read-only connection to the same file [query](Self::query) runs on,
+    /// so queries don't hold up the store. Stores only in memory can't be
+    /// opened twice and query on `conn`.
+    reader: Option<Mutex<Connection>>,
+    schema_version: u32,
+}
+
+/// Pages a [backup](DataStore::backup) copies at once, and how long it
+/// pauses between them, so writers get to commit while it runs.
+const BACKUP_STEP_PAGES: std::os::raw::c_int = 256;
+const BACKUP_STEP_PAUSE: Duration = Duration::from_millis(10);
+
+pub(crate) fn sql_err(err: rusqlite::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+impl SqliteStore {
+    /// Open the database at `path`, creating it if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut store = Self::with_connection(Connection::open(path).map_err(sql_err)?)?;
+        let reader = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(sql_err)?;
+        store.reader = Some(Mutex::new(reader));
+        Ok(store)
+    }
+
+    /// Open a database that only lives in memory.
+    pub fn open_in_memory() -> io::Result<Self> {
+        Self::with_connection(Connection::open_in_memory().map_err(sql_err)?)
+    }
+
+    fn with_connection(mut conn: Connection) -> io::Result<Self> {
+        let schema_version = migrate_sqlite(&mut conn, SQLITE_MIGRATIONS)?;
+        let tx = conn.transaction().map_err(sql_err)?;
+        let indexed = index_articles(&tx)?;
+        tx.commit().map_err(sql_err)?;
+        if indexed > 0 {
+            info!("Decoded the fields of {indexed} stored article(s)");
+        }
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            reader: None,
+            schema_version,
+        })
+    }
+
+    /// The version of the last migration applied to the database.
+    pub fn schema_version(&self) -> u32 {
+        self.schema_version
+    }
+
+    /// Run the read-only statement `sql`, e.g. over the views of stored
+    /// objects, returning the values of each row. Statements that would
+    /// change the database are refused.
+    pub fn query<P: Params>(&self, sql: &str, params: P) -> io::Result<Vec<Vec<Value>>> {
+        let conn = self.reader.as_ref().unwrap_or(&self.conn).lock().unwrap();
+        let mut stmt = conn.prepare(sql).map_err(sql_err)?;
+        if !stmt.readonly() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Only statements reading the store can be run"));
+        }
+        let columns = stmt.column_count();
+        let rows = stmt.query_map(params, |row| (0..columns).map(|i| row.get(i)).collect())
+            .map_err(sql_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sql_err)
+    }
+
+    /// Replace the database at `target` with the backup at `backup`.
+    ///
+    /// The backup is checked against its manifest and SQLite's own integrity
+    /// check before anything is touched, and swapped in with a rename so a
+    /// failed restore leaves `target` as it was. The store at `target` must
+    /// not be open while restoring.
+    pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(backup: P, target: Q) -> io::Result<BackupManifest> {
+        let (backup, target) = (backup.as_ref(), target.as_ref());
+        let manifest = BackupManifest::read(backup)?;
+        manifest.verify(backup)?;
+
+        let conn = Connection::open_with_flags(backup, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(sql_err)?;
+        let integrity: String = conn.query_row("PRAGMA integrity_check", [], |row| row.get(0)).map_err(sql_err)?;
+        if integrity != "ok" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Backup {} failed the integrity check: {integrity}", backup.display())
+            ));
+        }
+        drop(conn);
+
+        let mut staging = target.as_os_str().to_owned();
+        staging.push(".restore");
+        fs::copy(backup, &staging)?;
+        fs::rename(&staging, target)?;
+
+        info!("Restored {} from backup taken at {}", target.display(), manifest.created_at);
+        Ok(manifest)
+    }
+}
+
+/// Decode the fields of `envelope` into the table backing its view, if it is
+/// of a standard type. Objects that can't be decoded are left out, and
+/// recorded so they aren't decoded again. Returns whether it was indexed.
+fn index_fields(conn: &Connection, envelope: &Envelope) -> io::Result<bool> {
+    if envelope.type_id != Article::TYPE_ID {
+        return Ok(false);
+    }
+    let object_id = envelope.object_id.as_bytes();
+    let article = match Article::from_envelope(envelope) {
+        Ok(article) => article,
+        Err(e) => {
+            debug!("Not indexing article {}: {e}", envelope.object_id);
+            conn.execute("DELETE FROM article_fields WHERE object_id = ?1", params![object_id]).map_err(sql_err)?;
+            conn.execute("INSERT OR IGNORE INTO undecodable_articles (object_id) VALUES (?1)", params![object_id])
+                .map_err(sql_err)?;
+            return Ok(false);
+        }
+    };
+    conn.execute("DELETE FROM undecodable_articles WHERE object_id = ?1", params![object_id]).map_err(sql_err)?;
+    let json = |values: &[String]| serde_json::to_string(values).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    conn.execute(
+        "INSERT OR REPLACE INTO article_fields (object_id, title, summary, content, url, authors, published_at, tags)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            object_id,
+            article.title,
+            article.summary,
+            article.content,
+            article.url,
+            json(&article.authors)?,
+            article.published_at as i64,
+            json(&article.tags)?,
+        ],
+    ).map_err(sql_err)?;
+    Ok(true)
+}
+
+/// Decode the fields of the articles stored before they were indexed,
+/// leaving out those that couldn't be decoded before. Returns how many were.
+fn index_articles(conn: &Connection) -> io::Result<usize> {
+    let mut stmt = conn.prepare(
+        "SELECT envelope FROM objects
+         WHERE type_id = ?1 AND cold_key IS NULL
+           AND object_id NOT IN (SELECT object_id FROM article_fields)
+           AND object_id NOT IN (SELECT object_id FROM undecodable_articles)"
+    ).map_err(sql_err)?;
+    let rows = stmt.query_map(params![Article::TYPE_ID.as_bytes()], |row| row.get::<_, Vec<u8>>(0))
+        .map_err(sql_err)?;
+    let mut indexed = 0;
+    for bytes in rows {
+        if index_fields(conn, &Envelope::from_bytes(&bytes.map_err(sql_err)?)?)? {
+            indexed += 1;
+        }
+    }
+    Ok(indexed)
+}
+
+/// An audit log entry, unless its action is unknown.
+fn audit_from_row(row: &rusqlite::Row) -> rusqlite::Result<io::Result<AuditEntry>> {
+    let action = match row.get::<_, String>(1)?.parse() {
+        Ok(action) => action,
+        Err(e) => return Ok(Err(e)),
+    };
+    Ok(Ok(AuditEntry {
+        recorded_at: row.get::<_, i64>(0)? as u64,
+        action,
+        object_id: row.get::<_, Option<[u8; 16]>>(2)?.map(Uuid::from_bytes),
+        actor: row.get(3)?,
+        detail: row.get(4)?,
+        type_id: row.get::<_, Option<[u8; 16]>>(5)?.map(Uuid::from_bytes),
+    }))
+}
+
+fn subscription_from_row(row: &rusqlite::Row) -> rusqlite::Result<Subscription> {
+    let data_types: Vec<u8> = row.get(1)?;
+    let topics: String = row.get(6)?;
+    let qos: u8 = row.get(7)?;
+    let type_versions: Vec<u8> = row.get(8)?;
+    let scheme: String = row.get(9)?;
+    Ok(Subscription {
+        peer: row.get(0)?,
+        data_types: data_types.chunks_exact(16)
+            .map(|id| Uuid::from_slice(id).unwrap())
+            .collect(),
+        state: SubscriptionState::from_u8(row.get(2)?),
+        requested_at: row.get::<_, i64>(3)? as u64,
+        port: row.get::<_, Option<i64>>(4)?.map(|port| port as u16),
+        scheme: Scheme::parse(&scheme).unwrap_or_default(),
+        expires_at: row.get::<_, Option<i64>>(5)?.map(|expires_at| expires_at as u64),
+        // filters were checked before they were stored
+        topics: topics.lines().filter_map(|filter| filter.parse().ok()).collect(),
+        qos: DeliveryQos::from_u8(qos).unwrap_or_default(),
+        type_versions: type_versions.chunks_exact(18)
+            .map(|entry| (Uuid::from_slice(&entry[..16]).unwrap(), u16::from_be_bytes([entry[16], entry[17]])))
+            .collect(),
+    })
+}
+
+fn invite_from_row(row: &rusqlite::Row) -> rusqlite::Result<(String, i64, Option<i64>, bool)> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+}
+
+fn invite_record((token, issued_at, last_used_at, revoked): (String, i64, Option<i64>, bool)) -> io::Result<InviteRecord> {
+    Ok(InviteRecord {
+        invite: invite::from_token(&token)?,
+        issued_at: issued_at as u64,
+        last_used_at: last_used_at.map(|at| at as u64),
+        revoked,
+    })
+}
+
+type DeliveryRow = (Vec<u8>, String, u16, Vec<u8>, u32, i64, Option<String>, u8, Option<i64>, String);
+
+fn delivery_from_row(row: &rusqlite::Row) -> rusqlite::Result<DeliveryRow> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?, row.get(8)?, row.get(9)?))
+}
+
+fn pending_delivery((id, peer, port, envelope, attempts, next_attempt_at, last_error, qos, pushed_sequence, scheme): DeliveryRow) -> io::Result<PendingDelivery> {
+    let id = Uuid::from_slice(&id).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(PendingDelivery {
+        id,
+        peer,
+        port,
+        scheme: Scheme::parse(&scheme).unwrap_or_default(),
+        envelope: Envelope::from_bytes(&envelope)?,
+        attempts,
+        next_attempt_at: next_attempt_at as u64,
+        last_error,
+        qos: DeliveryQos::from_u8(qos).unwrap_or_default(),
+        pushed_sequence: pushed_sequence.map(|sequence| sequence as u64),
+    })
+}
+
+fn scheduled_from_row(row: &rusqlite::Row) -> rusqlite::Result<(Vec<u8>, i64, i64)> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+}
+
+fn scheduled_object((envelope, publish_at, scheduled_at): (Vec<u8>, i64, i64)) -> io::Result<ScheduledObject> {
+    Ok(ScheduledObject {
+        envelope: Envelope::from_bytes(&envelope)?,
+        publish_at: publish_at as u64,
+        scheduled_at: scheduled_at as u64,
+    })
+}
+
+fn delegation_from_row(row: &rusqlite::Row) -> rusqlite::Result<(Vec<u8>, Option<u16>, Option<String>)> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+}
+
+fn delegation_record((delegation, port, scheme): (Vec<u8>, Option<u16>, Option<String>)) -> io::Result<DelegationRecord> {
+    let delegation = fanout::decode(&delegation)?;
+    let relay_url = port.map(|port| OSPUrl {
+        domain: delegation.relay.clone(),
+        port,
+        scheme: scheme.as_deref().and_then(Scheme::parse).unwrap_or_default(),
+    });
+    Ok(DelegationRecord { delegation, relay_url })
+}
+
+fn federation_rule_from_row(row: &rusqlite::Row) -> rusqlite::Result<FederationRule> {
+    Ok(FederationRule {
+        host: row.get(0)?,
+        action: match row.get::<_, bool>(1)? {
+            true => FederationAction::Allow,
+            false => FederationAction::Deny,
+        },
+        updated_at: row.get::<_, i64>(2)? as u64,
+    })
+}
+
+impl DataStore for SqliteStore {
+    fn peer_state(&self, hostname: &str) -> io::Result<Option<PeerSyncState>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT cursor, next_sequence, resumption_token FROM peer_state WHERE hostname = ?1",
+            params![hostname],
+            |row| {
+                let token: [u8; 16] = row.get(2)?;
+                Ok(PeerSyncState {
+                    cursor: row.get::<_, i64>(0)? as u64,
+                    next_sequence: row.get::<_, i64>(1)? as u64,
+                    resumption_token: Uuid::from_bytes(token),
+                })
+            },
+        ).optional().map_err(sql_err)
+    }
+
+    fn put_peer_state(&self, hostname: &str, state: &PeerSyncState) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO peer_state (hostname, cursor, next_sequence, resumption_token)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (hostname) DO UPDATE SET
+                cursor = excluded.cursor,
+                next_sequence = excluded.next_sequence,
+                resumption_token = excluded.resumption_token",
+            params![
+                hostname,
+                state.cursor as i64,
+                state.next_sequence as i64,
+                state.resumption_token.as_bytes(),
+            ],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn remove_peer_state(&self, hostname: &str) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM peer_state WHERE hostname = ?1", params![hostname]).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn advance_peer_cursor(&self, hostname: &str, sequence: u64) -> io::Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        let cursor = conn.query_row(
+            "SELECT cursor FROM peer_state WHERE hostname = ?1",
+            params![hostname],
+            |row| row.get::<_, i64>(0),
+        ).optional().map_err(sql_err)?.ok_or_else(|| no_peer_state(hostname))? as u64;
+        if cursor + 1 == sequence {
+            conn.execute(
+                "UPDATE peer_state SET cursor = ?2 WHERE hostname = ?1",
+                params![hostname, sequence as i64],
+            ).map_err(sql_err)?;
+        }
+        Ok(cursor)
+    }
+
+    fn take_peer_sequence(&self, hostname: &str) -> io::Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        let sequence = conn.query_row(
+            "UPDATE peer_state SET next_sequence = next_sequence + 1 WHERE hostname = ?1
+             RETURNING next_sequence - 1",
+            params![hostname],
+            |row| row.get::<_, i64>(0),
+        ).optional().map_err(sql_err)?.ok_or_else(|| no_peer_state(hostname))?;
+        Ok(sequence as u64)
+    }
+
+    fn put_object(&self, envelope: &Envelope) -> io::Result<()> {
+        let bytes = envelope.to_bytes()?;
+        let received_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sql_err)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO objects (object_id, type_id, origin, received_at, size, envelope, actor)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                envelope.object_id.as_bytes(),
+                envelope.type_id.as_bytes(),
+                envelope.origin,
+                received_at,
+                bytes.len() as i64,
+                bytes,
+                envelope.actor,
+            ],
+        ).map_err(sql_err)?;
+        index_fields(&tx, envelope)?;
+        tx.commit().map_err(sql_err)
+    }
+
+    fn get_object(&self, object_id: Uuid) -> io::Result<Option<Envelope>> {
+        let conn = self.conn.lock().unwrap();
+        let bytes: Option<Vec<u8>> = conn.query_row(
+            "SELECT envelope FROM objects WHERE object_id = ?1",
+            params![object_id.as_bytes()],
+            |row| row.get(0),
+        ).optional().map_err(sql_err)?;
+        bytes.map(|b| Envelope::from_bytes(&b)).transpose()
+    }
+
+    fn remove_object(&self, object_id: Uuid) -> io::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM objects WHERE object_id = ?1", params![object_id.as_bytes()])
+            .map_err(sql_err)?;
+        Ok(removed > 0)
+    }
+
+    fn list_objects(&self, offset: usize, limit: usize) -> io::Result<Vec<Uuid>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT object_id FROM objects ORDER BY rowid LIMIT ?1 OFFSET ?2"
+        ).map_err(sql_err)?;
+        let rows = stmt.query_map(params![limit as i64, offset as i64], |row| {
+            Ok(Uuid::from_bytes(row.get(0)?))
+        }).map_err(sql_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sql_err)
+    }
+
+    fn objects_by_actor(&self, actor: &str, limit: usize) -> io::Result<Vec<Uuid>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT object_id FROM objects WHERE actor = ?1 LIMIT ?2").map_err(sql_err)?;
+        let rows = stmt.query_map(params![actor, limit as i64], |row| {
+            Ok(Uuid::from_bytes(row.get(0)?))
+        }).map_err(sql_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sql_err)
+    }
+
+    fn put_tombstone(&self, tombstone: &Tombstone) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO tombstones (object_id, reason, authority, taken_down_at, signature)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                tombstone.object_id.as_bytes(),
+                tombstone.reason,
+                tombstone.authority,
+                tombstone.taken_down_at as i64,
+                tombstone.signature,
+            ],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn tombstone(&self, object_id: Uuid) -> io::Result<Option<Tombstone>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT reason, authority, taken_down_at, signature FROM tombstones WHERE object_id = ?1",
+            params![object_id.as_bytes()],
+            |row| Ok(Tombstone {
+                object_id,
+                reason: row.get(0)?,
+                authority: row.get(1)?,
+                taken_down_at: row.get::<_, i64>(2)? as u64,
+                signature: row.get(3)?,
+            }),
+        ).optional().map_err(sql_err)
+    }
+
+    fn append_audit(&self, entry: &AuditEntry) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO audit_log (recorded_at, action, object_id, actor, detail, type_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                entry.recorded_at as i64,
+                entry.action.to_string(),
+                entry.object_id.as_ref().map(Uuid::as_bytes),
+                entry.actor,
+                entry.detail,
+                entry.type_id.as_ref().map(Uuid::as_bytes),
+            ],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn audit_log(&self, offset: usize, limit: usize) -> io::Result<Vec<AuditEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT recorded_at, action, object_id, actor, detail, type_id FROM audit_log ORDER BY id LIMIT ?1 OFFSET ?2"
+        ).map_err(sql_err)?;
+        let rows = stmt.query_map(params![limit as i64, offset as i64], audit_from_row).map_err(sql_err)?;
+        rows.map(|row| row.map_err(sql_err)?).collect()
+    }
+
+    fn audit_log_between(&self, from: u64, to: u64, offset: usize, limit: usize) -> io::Result<Vec<AuditEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT recorded_at, action, object_id, actor, detail, type_id FROM audit_log
+             WHERE recorded_at >= ?1 AND recorded_at < ?2 ORDER BY id LIMIT ?3 OFFSET ?4"
+        ).map_err(sql_err)?;
+        let rows = stmt.query_map(params![from as i64, to as i64, limit as i64, offset as i64], audit_from_row)
+            .map_err(sql_err)?;
+        rows.map(|row| row.map_err(sql_err)?).collect()
+    }
+
+    fn put_subscription(&self, subscription: &Subscription) -> io::Result<()> {
+        let data_types = subscription.data_types.iter().flat_map(|id| *id.as_bytes()).collect::<Vec<_>>();
+        let topics = subscription.topics.iter().map(|filter| filter.to_string()).collect::<Vec<_>>().join("\n");
+        let type_versions = subscription.type_versions.iter()
+            .flat_map(|(id, version)| id.as_bytes().iter().copied().chain(version.to_be_bytes()))
+            .collect::<Vec<_>>();
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO subscriptions (peer, data_types, state, requested_at, port, expires_at, topics, qos, type_versions, scheme) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                subscription.peer,
+                data_types,
+                subscription.state as u8,
+                subscription.requested_at as i64,
+                subscription.port.map(|port| port as i64),
+                subscription.expires_at.map(|expires_at| expires_at as i64),
+                topics,
+                subscription.qos as u8,
+                type_versions,
+                subscription.scheme.as_str(),
+            ],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn subscription(&self, peer: &str) -> io::Result<Option<Subscription>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT peer, data_types, state, requested_at, port, expires_at, topics, qos, type_versions, scheme FROM subscriptions WHERE peer = ?1",
+            params![peer],
+            subscription_from_row,
+        ).optional().map_err(sql_err)
+    }
+
+    fn subscriptions(&self) -> io::Result<Vec<Subscription>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT peer, data_types, state, requested_at, port, expires_at, topics, qos, type_versions, scheme FROM subscriptions ORDER BY peer")
+            .map_err(sql_err)?;
+        let rows = stmt.query_map([], subscription_from_row).map_err(sql_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sql_err)
+    }
+
+    fn remove_subscription(&self, peer: &str) -> io::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM subscriptions WHERE peer = ?1", params![peer]).map_err(sql_err)?;
+        Ok(removed > 0)
+    }
+
+    fn put_invite(&self, record: &InviteRecord) -> io::Result<()> {
+        let token = invite::to_token(&record.invite)?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO invites (token_id, invite, issued_at, last_used_at, revoked) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                record.invite.token_id.as_bytes(),
+                token,
+                record.issued_at as i64,
+                record.last_used_at.map(|at| at as i64),
+                record.revoked,
+            ],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn invite(&self, token_id: Uuid) -> io::Result<Option<InviteRecord>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT invite, issued_at, last_used_at, revoked FROM invites WHERE token_id = ?1",
+            params![token_id.as_bytes()],
+            invite_from_row,
+        ).optional().map_err(sql_err)?.map(invite_record).transpose()
+    }
+
+    fn invites(&self) -> io::Result<Vec<InviteRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT invite, issued_at, last_used_at, revoked FROM invites ORDER BY issued_at")
+            .map_err(sql_err)?;
+        let rows = stmt.query_map([], invite_from_row).map_err(sql_err)?;
+        rows.map(|row| invite_record(row.map_err(sql_err)?)).collect()
+    }
+
+    fn put_federation_rule(&self, rule: &FederationRule) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO federation_rules (host, allow, updated_at) VALUES (?1, ?2, ?3)",
+            params![rule.host, rule.action == FederationAction::Allow, rule.updated_at as i64],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn federation_rule(&self, host: &str) -> io::Result<Option<FederationRule>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT host, allow, updated_at FROM federation_rules WHERE host = ?1",
+            params![host],
+            federation_rule_from_row,
+        ).optional().map_err(sql_err)
+    }
+
+    fn federation_rules(&self) -> io::Result<Vec<FederationRule>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT host, allow, updated_at FROM federation_rules ORDER BY host")
+            .map_err(sql_err)?;
+        let rows = stmt.query_map([], federation_rule_from_row).map_err(sql_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sql_err)
+    }
+
+    fn oldest_objects(&self, type_id: Uuid, limit: usize) -> io::Result<Vec<Uuid>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT object_id FROM objects WHERE type_id = ?1 ORDER BY received_at, rowid LIMIT ?2"
+        ).map_err(sql_err)?;
+        let rows = stmt.query_map(params![type_id.as_bytes(), limit as i64], |row| {
+            Ok(Uuid::from_bytes(row.get(0)?))
+        }).map_err(sql_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sql_err)
+    }
+
+    fn type_usage(&self, type_id: Uuid) -> io::Result<TypeUsage> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM objects WHERE type_id = ?1",
+            params![type_id.as_bytes()],
+            |row| Ok(TypeUsage {
+                type_id,
+                objects: row.get::<_, i64>(0)? as u64,
+                bytes: row.get::<_, i64>(1)? as u64,
+            }),
+        ).map_err(sql_err)
+    }
+
+    fn usage(&self) -> io::Result<Vec<TypeUsage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT type_id, COUNT(*), SUM(size) FROM objects GROUP BY type_id")
+            .map_err(sql_err)?;
+        let rows = stmt.query_map([], |row| Ok(TypeUsage {
+            type_id: Uuid::from_bytes(row.get(0)?),
+            objects: row.get::<_, i64>(1)? as u64,
+            bytes: row.get::<_, i64>(2)? as u64,
+        })).map_err(sql_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sql_err)
+    }
+
+    fn origin_usage(&self, from: u64, to: u64) -> io::Result<Vec<OriginUsage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT type_id, origin, COUNT(*), SUM(size) FROM objects
+             WHERE received_at >= ?1 AND received_at < ?2 GROUP BY type_id, origin"
+        ).map_err(sql_err)?;
+        let rows = stmt.query_map(params![from as i64, to.min(i64::MAX as u64) as i64], |row| Ok(OriginUsage {
+            type_id: Uuid::from_bytes(row.get(0)?),
+            origin: row.get(1)?,
+            objects: row.get::<_, i64>(2)? as u64,
+            bytes: row.get::<_, i64>(3)? as u64,
+        })).map_err(sql_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sql_err)
+    }
+
+    fn objects_received_before(&self, cutoff: u64, limit: usize) -> io::Result<Vec<Uuid>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT object_id FROM objects WHERE received_at < ?1 AND cold_key IS NULL ORDER BY received_at, rowid LIMIT ?2"
+        ).map_err(sql_err)?;
+        let rows = stmt.query_map(params![cutoff as i64, limit as i64], |row| {
+            Ok(Uuid::from_bytes(row.get(0)?))
+        }).map_err(sql_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sql_err)
+    }
+
+    fn tier_object(&self, stub: &Envelope, cold_key: &str) -> io::Result<()> {
+        let bytes = stub.to_bytes()?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE objects SET envelope = ?2, size = ?3, cold_key = ?4 WHERE object_id = ?1",
+            params![stub.object_id.as_bytes(), bytes, bytes.len() as i64, cold_key],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn cold_key(&self, object_id: Uuid) -> io::Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let key: Option<Option<String>> = conn.query_row(
+            "SELECT cold_key FROM objects WHERE object_id = ?1",
+            params![object_id.as_bytes()],
+            |row| row.get(0),
+        ).optional().map_err(sql_err)?;
+        Ok(key.flatten())
+    }
+
+    fn put_delivery(&self, delivery: &PendingDelivery) -> io::Result<()> {
+        let envelope = delivery.envelope.to_bytes()?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO deliveries (id, peer, port, envelope, attempts, next_attempt_at, last_error, qos, pushed_sequence, scheme)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                delivery.id.as_bytes(),
+                delivery.peer,
+                delivery.port,
+                envelope,
+                delivery.attempts,
+                delivery.next_attempt_at as i64,
+                delivery.last_error,
+                delivery.qos as u8,
+                delivery.pushed_sequence.map(|sequence| sequence as i64),
+                delivery.scheme.as_str(),
+            ],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn due_deliveries(&self, now: u64, limit: usize, paused: &[String]) -> io::Result<Vec<PendingDelivery>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, peer, port, envelope, attempts, next_attempt_at, last_error, qos, pushed_sequence, scheme FROM deliveries
+             WHERE next_attempt_at <= ?1 ORDER BY next_attempt_at"
+        ).map_err(sql_err)?;
+        // rows are read as they are needed, so the query stops at the limit
+        let rows = stmt.query_map(params![now as i64], delivery_from_row).map_err(sql_err)?;
+        rows.filter(|row| !row.as_ref().is_ok_and(|row| paused.contains(&row.1)))
+            .take(limit)
+            .map(|row| pending_delivery(row.map_err(sql_err)?))
+            .collect()
+    }
+
+    fn queued_deliveries(&self, peer: &str, port: u16) -> io::Result<Vec<PendingDelivery>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, peer, port, envelope, attempts, next_attempt_at, last_error, qos, pushed_sequence, scheme FROM deliveries
+             WHERE peer = ?1 AND port = ?2 ORDER BY next_attempt_at"
+        ).map_err(sql_err)?;
+        let rows = stmt.query_map(params![peer, port], delivery_from_row).map_err(sql_err)?;
+        rows.map(|row| pending_delivery(row.map_err(sql_err)?)).collect()
+    }
+
+    fn remove_delivery(&self, id: Uuid) -> io::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM deliveries WHERE id = ?1", params![id.as_bytes()])
+            .map_err(sql_err)?;
+        Ok(removed > 0)
+    }
+
+    fn delivery_count(&self) -> io::Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM deliveries", [], |row| row.get(0)).map_err(sql_err)?;
+        Ok(count as u64)
+    }
+
+    fn put_scheduled(&self, scheduled: &ScheduledObject) -> io::Result<()> {
+        let envelope = scheduled.envelope.to_bytes()?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO scheduled_objects (object_id, envelope, publish_at, scheduled_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                scheduled.envelope.object_id.as_bytes(),
+                envelope,
+                scheduled.publish_at as i64,
+                scheduled.scheduled_at as i64,
+            ],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn scheduled(&self, object_id: Uuid) -> io::Result<Option<ScheduledObject>> {
+        let conn = self.conn.lock().unwrap();
+        let row = conn.query_row(
+            "SELECT envelope, publish_at, scheduled_at FROM scheduled_objects WHERE object_id = ?1",
+            params![object_id.as_bytes()],
+            scheduled_from_row,
+        ).optional().map_err(sql_err)?;
+        row.map(scheduled_object).transpose()
+    }
+
+    fn scheduled_objects(&self) -> io::Result<Vec<ScheduledObject>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT envelope, publish_at, scheduled_at FROM scheduled_objects ORDER BY publish_at")
+            .map_err(sql_err)?;
+        let rows = stmt.query_map([], scheduled_from_row).map_err(sql_err)?;
+        rows.map(|row| scheduled_object(row.map_err(sql_err)?)).collect()
+    }
+
+    fn due_scheduled(&self, now: u64, limit: usize) -> io::Result<Vec<ScheduledObject>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT envelope, publish_at, scheduled_at FROM scheduled_objects
+             WHERE publish_at <= ?1 ORDER BY publish_at LIMIT ?2"
+        ).map_err(sql_err)?;
+        let rows = stmt.query_map(params![now as i64, limit as i64], scheduled_from_row).map_err(sql_err)?;
+        rows.map(|row| scheduled_object(row.map_err(sql_err)?)).collect()
+    }
+
+    fn remove_scheduled(&self, object_id: Uuid) -> io::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM scheduled_objects WHERE object_id = ?1", params![object_id.as_bytes()])
+            .map_err(sql_err)?;
+        Ok(removed > 0)
+    }
+
+    fn reschedule(&self, object_id: Uuid, publish_at: u64) -> io::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE scheduled_objects SET publish_at = ?2 WHERE object_id = ?1",
+            params![object_id.as_bytes(), publish_at as i64],
+        ).map_err(sql_err)?;
+        Ok(updated > 0)
+    }
+
+    fn bridged_item(&self, origin: &str, key: &str) -> io::Result<Option<Uuid>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT object_id FROM bridged_items WHERE origin = ?1 AND item_key = ?2",
+            params![origin, key],
+            |row| Ok(Uuid::from_bytes(row.get(0)?)),
+        ).optional().map_err(sql_err)
+    }
+
+    fn put_bridged_item(&self, origin: &str, key: &str, object_id: Uuid) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO bridged_items (origin, item_key, object_id) VALUES (?1, ?2, ?3)",
+            params![origin, key, object_id.as_bytes()],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn bridge_cursor(&self, source: &str) -> io::Result<Option<u64>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT cursor FROM bridge_cursors WHERE source = ?1",
+            params![source],
+            |row| Ok(row.get::<_, i64>(0)? as u64),
+        ).optional().map_err(sql_err)
+    }
+
+    fn put_bridge_cursor(&self, source: &str, cursor: u64) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO bridge_cursors (source, cursor) VALUES (?1, ?2)",
+            params![source, cursor as i64],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn put_delegation(&self, record: &DelegationRecord) -> io::Result<()> {
+        let delegation = fanout::encode(&record.delegation)?;
+        let conn = self.conn.lock().unwrap();
+        // updated in place, so the delegations keep the order they were saved in
+        conn.execute(
+            "INSERT INTO delegations (delegation_id, delegation, relay_port, relay_scheme) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (delegation_id) DO UPDATE SET delegation = ?2, relay_port = ?3, relay_scheme = ?4",
+            params![
+                record.delegation.delegation_id.as_bytes(),
+                delegation,
+                record.relay_url.as_ref().map(|url| url.port),
+                record.relay_url.as_ref().map(|url| url.scheme.as_str()),
+            ],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn delegations(&self) -> io::Result<Vec<DelegationRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT delegation, relay_port, relay_scheme FROM delegations ORDER BY rowid")
+            .map_err(sql_err)?;
+        let rows = stmt.query_map([], delegation_from_row).map_err(sql_err)?;
+        rows.map(|row| delegation_record(row.map_err(sql_err)?)).collect()
+    }
+
+    fn remove_delegation(&self, delegation_id: Uuid) -> io::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM delegations WHERE delegation_id = ?1", params![delegation_id.as_bytes()])
+            .map_err(sql_err)?;
+        Ok(removed > 0)
+    }
+
+    /// Uses SQLite's online backup API, so the node can keep running while
+    /// the snapshot is taken. The pages are copied a few at a time from the
+    /// read-only connection, so the store isn't locked while they are, only
+    /// [query](Self::query) is. Stores only in memory have no second
+    /// connection and are locked for the whole copy.
+    fn backup(&self, path: &Path) -> io::Result<BackupManifest> {
+        if path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Refusing to overwrite {}", path.display())
+            ));
+        }
+
+        {
+            let source = self.reader.as_ref().unwrap_or(&self.conn).lock().unwrap();
+            let mut target = Connection::open(path).map_err(sql_err)?;
+            Backup::new(&source, &mut target).map_err(sql_err)?
+                .run_to_completion(BACKUP_STEP_PAGES, BACKUP_STEP_PAUSE, None)
+                .map_err(sql_err)?;
+        }
+
+        let manifest = BackupManifest::create(path, self.schema_version)?;
+        manifest.write(path)?;
+        Ok(manifest)
+    }
+}
+
+impl DedupBackend for SqliteStore {
+    fn contains(&self, key: &ContentHash, since: u64) -> io::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT 1 FROM dedup_keys WHERE key = ?1 AND recorded_at >= ?2",
+            params![key.as_bytes(), since as i64],
+            |_| Ok(()),
+        ).optional().map(|found| found.is_some()).map_err(sql_err)
+    }
+
+    fn insert(&self, key: &ContentHash, at: u64) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO dedup_keys (key, recorded_at) VALUES (?1, ?2)",
+            params![key.as_bytes(), at as i64],
+        ).map_err(sql_err)?;
+        Ok(())
+    }
+
+    fn prune(&self, before: u64, capacity: usize) -> io::Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let expired = conn.execute("DELETE FROM dedup_keys WHERE recorded_at < ?1", params![before as i64]).map_err(sql_err)?;
+        let excess = conn.execute(
+            "DELETE FROM dedup_keys WHERE key IN (SELECT key FROM dedup_keys ORDER BY recorded_at DESC LIMIT -1 OFFSET ?1)",
+            params![capacity as i64],
+        ).map_err(sql_err)?;
+        Ok(expired + excess)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use tokio::io;
+
+    use uuid::Uuid;
+
+    use rusqlite::types::Value;
+
+    use osp_data::Data;
+    use osp_data::standard::Article;
+    use osp_protocol::{Envelope, OSPUrl, Scheme};
+
+    use crate::delivery::PendingDelivery;
+    use crate::embargo::ScheduledObject;
+    use crate::store::{AuditAction, AuditEntry, DataStore, PeerSyncState, SqliteStore};
+    use super::{index_articles, sql_err};
+    use crate::subscription::DeliveryQos;
+
+    #[test]
+    fn test_backup_restore() -> io::Result<()> {
+        let dir = env::temp_dir().join(format!("osp-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir)?;
+        let (db, snapshot) = (dir.join("node.db"), dir.join("snapshot.db"));
+
+        let state = PeerSyncState { cursor: 42, ..Default::default() };
+        let store = SqliteStore::open(&db)?;
+        store.put_peer_state("peer.test", &state)?;
+        store.backup(&snapshot)?;
+
+        store.put_peer_state("peer.test", &PeerSyncState::default())?;
+        drop(store);
+
+        SqliteStore::restore(&snapshot, &db)?;
+        assert_eq!(SqliteStore::open(&db)?.peer_state("peer.test")?, Some(state));
+
+        // a tampered snapshot must not be restored
+        fs::write(&snapshot, b"garbage")?;
+        assert!(SqliteStore::restore(&snapshot, &db).is_err());
+
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn test_deliveries() -> io::Result<()> {
+        let store = SqliteStore::open_in_memory()?;
+        let url = OSPUrl { domain: "peer.test".to_string(), port: 57400, scheme: Scheme::Osp };
+        let envelope = Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![1, 2, 3]);
+        // deliveries keep how the peer is connected to
+        let later = PendingDelivery { next_attempt_at: 2000, scheme: Scheme::Wss, ..PendingDelivery::new(&url, envelope.clone(), DeliveryQos::Ordered) };
+        let sooner = PendingDelivery {
+            next_attempt_at: 1000,
+            attempts: 2,
+            last_error: Some("peer down".to_string()),
+            ..PendingDelivery::new(&url, envelope, DeliveryQos::FireAndForget)
+        };
+        store.put_delivery(&later)?;
+        store.put_delivery(&sooner)?;
+
+        assert_eq!(store.due_deliveries(500, 10, &[])?, vec![]);
+        assert_eq!(store.due_deliveries(1500, 10, &[])?, vec![sooner.clone()]);
+        assert_eq!(store.due_deliveries(2000, 10, &[])?, vec![sooner.clone(), later.clone()]);
+        assert_eq!(store.due_deliveries(2000, 1, &[])?, vec![sooner.clone()]);
+        assert_eq!(store.due_deliveries(2000, 10, &["peer.test".to_string()])?, vec![]);
+        assert_eq!(store.delivery_count()?, 2);
+        assert_eq!(store.queued_deliveries("peer.test", 57400)?, vec![sooner.clone(), later.clone()]);
+        assert_eq!(store.queued_deliveries("peer.test", 57401)?, vec![]);
+
+        assert!(store.remove_delivery(sooner.id)?);
+        assert!(!store.remove_delivery(sooner.id)?);
+        assert_eq!(store.delivery_count()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scheduled_objects() -> io::Result<()> {
+        let store = SqliteStore::open_in_memory()?;
+        let envelope = || Envelope::new(Uuid::new_v4(), "origin.test".to_string(), vec![1, 2, 3]);
+        let later = ScheduledObject { envelope: envelope(), publish_at: 2000, scheduled_at: 100 };
+        let sooner = ScheduledObject { envelope: envelope(), publish_at: 1000, scheduled_at: 200 };
+        store.put_scheduled(&later)?;
+        store.put_scheduled(&sooner)?;
+
+        assert_eq!(store.due_scheduled(500, 10)?, vec![]);
+        assert_eq!(store.due_scheduled(2000, 10)?, vec![sooner.clone(), later.clone()]);
+        assert_eq!(store.due_scheduled(2000, 1)?, vec![sooner.clone()]);
+        assert_eq!(store.scheduled(later.envelope.object_id)?, Some(later.clone()));
+
+        assert!(store.remove_scheduled(sooner.envelope.object_id)?);
+        assert!(!store.remove_scheduled(sooner.envelope.object_id)?);
+        assert_eq!(store.scheduled_objects()?, vec![later]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_views() -> io::Result<()> {
+        let store = SqliteStore::open_in_memory()?;
+        let article = Article {
+            title: "Hello".to_string(),
+            authors: vec!["Ada".to_string()],
+            published_at: 1_700_000_000,
+            tags: vec!["greeting".to_string(), "news".to_string()],
+            ..Article::default()
+        };
+        let envelope = article.to_envelope("blog.test".to_string())?;
+        store.put_object(&envelope)?;
+        store.put_object(&Envelope::new(Uuid::new_v4(), "blog.test".to_string(), Vec::new()))?;
+
+        let text = |value: &Value| match value {
+            Value::Text(text) => text.clone(),
+            value => panic!("{value:?} isn't text"),
+        };
+        let rows = store.query("SELECT object_id, title FROM osp_articles WHERE origin = ?1", ["blog.test"])?;
+        assert_eq!(rows.iter().map(|row| (text(&row[0]), text(&row[1]))).collect::<Vec<_>>(), [(envelope.object_id.to_string(), "Hello".to_string())]);
+        let tags = store.query("SELECT t.value FROM osp_articles, json_each(osp_articles.tags) t ORDER BY t.value", [])?;
+        assert_eq!(tags, [[Value::Text("greeting".to_string())], [Value::Text("news".to_string())]]);
+        assert_eq!(store.query("SELECT count(*) FROM osp_objects WHERE NOT tiered", [])?, [[Value::Integer(2)]]);
+
+        assert_eq!(store.query("DELETE FROM objects", []).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        store.remove_object(envelope.object_id)?;
+        assert!(store.query("SELECT * FROM osp_articles", [])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_log_between() -> io::Result<()> {
+        let (store, type_id) = (SqliteStore::open_in_memory()?, Uuid::new_v4());
+        for recorded_at in [100, 200] {
+            store.append_audit(&AuditEntry {
+                recorded_at,
+                action: AuditAction::Evict,
+                object_id: Some(Uuid::new_v4()),
+                actor: "operator".to_string(),
+                detail: String::new(),
+                type_id: Some(type_id),
+            })?;
+        }
+
+        let entries = store.audit_log_between(150, 250, 0, 10)?;
+        assert_eq!(entries.iter().map(|entry| (entry.recorded_at, entry.type_id)).collect::<Vec<_>>(), [(200, Some(type_id))]);
+        assert!(store.audit_log_between(150, 250, 1, 10)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_undecodable_article_is_recorded() -> io::Result<()> {
+        let store = SqliteStore::open_in_memory()?;
+        let envelope = Envelope::new(Article::TYPE_ID, "blog.test".to_string(), vec![0xff]);
+        store.put_object(&envelope)?;
+
+        let conn = store.conn.lock().unwrap();
+        assert_eq!(index_articles(&conn)?, 0);
+        let undecodable: i64 = conn.query_row("SELECT count(*) FROM undecodable_articles", [], |row| row.get(0)).map_err(sql_err)?;
+        assert_eq!(undecodable, 1);
+        Ok(())
+    }
+}
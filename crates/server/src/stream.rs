@@ -0,0 +1,209 @@
+//! # Streams
+//!
+//! Attachments of objects, such as images or audio enclosures, can be too
+//! large to hold in memory, let alone to encode into a payload. Peers
+//! stream them instead:
+//! [send_stream](crate::connection::outbound::OutboundConnection::send_stream)
+//! sends the bytes of any [AsyncRead] in chunks, and the node receiving them
+//! hands them to its [StreamHandler] as an [IncomingStream] while they
+//! arrive, which is an [AsyncRead] itself. Chunks are only read from the
+//! connection as fast as the handler reads them, so a slow handler slows
+//! the peer down instead of piling the stream up in memory. The connection
+//! serves nothing else while it waits for the handler, so handlers that
+//! stall for longer than [STALL_TIMEOUT] have the stream refused, as do
+//! streams longer than [MAX_STREAM_LENGTH].
+//!
+//! A node has one stream handler at most, set with
+//! [stream_handler](crate::builder::OSProtocolNodeBuilder::stream_handler).
+//! Nodes without one, and read-only nodes, refuse streams. Like a
+//! [DataHandler](osp_data::DataHandler), the handler refuses the stream by
+//! failing, and the peer is told why.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use log::error;
+
+use tokio::io::{self, AsyncRead, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use uuid::Uuid;
+
+use osp_data::HandlerError;
+
+use crate::reporting;
+
+/// Chunks waiting for the handler before the connection waits for it.
+const BUFFERED_CHUNKS: usize = 4;
+
+/// Longest stream a node takes in, in bytes.
+pub const MAX_STREAM_LENGTH: u64 = 4 * 1024 * 1024 * 1024;
+
+/// How long the connection waits for a handler to take in a chunk, or to
+/// finish with a stream that ended.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+type StreamFuture = Pin<Box<dyn Future<Output = Result<(), HandlerError>> + Send>>;
+pub(crate) type ErasedStreamHandler = dyn Fn(IncomingStream) -> StreamFuture + Send + Sync;
+
+/// Application code taking in the streams peers send, see the
+/// [module](self) docs.
+pub trait StreamHandler: Send + Sync {
+    /// Handle `stream`, reading it to the end. Failing refuses the stream,
+    /// see [HandlerError].
+    fn handle(&self, stream: IncomingStream) -> impl Future<Output = Result<(), HandlerError>> + Send;
+}
+
+impl<F, Fut> StreamHandler for F
+where
+    F: Fn(IncomingStream) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<(), HandlerError>> + Send,
+{
+    fn handle(&self, stream: IncomingStream) -> impl Future<Output = Result<(), HandlerError>> + Send {
+        self(stream)
+    }
+}
+
+/// Erase the type of `handler`, so a node can hold it.
+pub(crate) fn erase<H: StreamHandler + 'static>(handler: H) -> Arc<ErasedStreamHandler> {
+    let handler = Arc::new(handler);
+    Arc::new(move |stream| {
+        let handler = handler.clone();
+        Box::pin(async move { handler.handle(stream).await })
+    })
+}
+
+enum Event {
+    Data(Vec<u8>),
+    End,
+    Abort,
+}
+
+/// The bytes of a stream a peer sends, as they arrive. Reading fails with
+/// [ConnectionAborted](io::ErrorKind::ConnectionAborted) if the peer
+/// aborted the stream, or [UnexpectedEof](io::ErrorKind::UnexpectedEof) if
+/// the connection closed before it ended.
+pub struct IncomingStream {
+    pub stream_id: Uuid,
+    /// Hostname of the peer sending the stream.
+    pub peer: String,
+    events: mpsc::Receiver<Event>,
+    chunk: Vec<u8>,
+    /// How much of `chunk` was read
+    read: usize,
+    ended: bool,
+}
+
+impl AsyncRead for IncomingStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read < this.chunk.len() {
+                let len = buf.remaining().min(this.chunk.len() - this.read);
+                buf.put_slice(&this.chunk[this.read..this.read + len]);
+                this.read += len;
+                return Poll::Ready(Ok(()));
+            }
+            if this.ended {
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.events.poll_recv(cx)) {
+                Some(Event::Data(data)) => {
+                    this.chunk = data;
+                    this.read = 0;
+                }
+                Some(Event::End) => this.ended = true,
+                Some(Event::Abort) => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionAborted, "The peer aborted the stream")));
+                }
+                None => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The connection closed before the stream ended")));
+                }
+            }
+        }
+    }
+}
+
+/// The end of a stream fed by the connection receiving it, with the task of
+/// the handler reading the other end.
+pub(crate) struct StreamSender {
+    stream_id: Uuid,
+    events: mpsc::Sender<Event>,
+    handler: JoinHandle<Result<(), HandlerError>>,
+    /// Bytes handed to the handler so far
+    sent: u64,
+}
+
+impl StreamSender {
+    /// Start running `handler` on the stream with id `stream_id` from
+    /// `peer`. A panicking handler must not take the connection down with
+    /// it, so it runs as a task of its own.
+    pub(crate) fn open(handler: &ErasedStreamHandler, stream_id: Uuid, peer: &str) -> Self {
+        let (events, receiver) = mpsc::channel(BUFFERED_CHUNKS);
+        let stream = IncomingStream {
+            stream_id,
+            peer: peer.to_string(),
+            events: receiver,
+            chunk: Vec::new(),
+            read: 0,
+            ended: false,
+        };
+        StreamSender {
+            stream_id,
+            events,
+            handler: tokio::spawn(handler(stream)),
+            sent: 0,
+        }
+    }
+
+    pub(crate) fn stream_id(&self) -> Uuid {
+        self.stream_id
+    }
+
+    /// Hand `data` to the handler, waiting while it is behind. Data for a
+    /// handler that stopped reading is dropped. Fails if the stream grows
+    /// longer than [MAX_STREAM_LENGTH], or the handler doesn't take `data`
+    /// in within [STALL_TIMEOUT], after which the stream should be
+    /// [cancelled](Self::cancel).
+    pub(crate) async fn send(&mut self, data: Vec<u8>) -> Result<(), HandlerError> {
+        self.sent += data.len() as u64;
+        if self.sent > MAX_STREAM_LENGTH {
+            return Err(HandlerError::rejected(format!("The stream is longer than the {MAX_STREAM_LENGTH} bytes allowed")));
+        }
+        match tokio::time::timeout(STALL_TIMEOUT, self.events.send(Event::Data(data))).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "The stream handler stalled").into()),
+        }
+    }
+
+    /// Stop the handler without waiting for it, returning the id of the
+    /// stream.
+    pub(crate) fn cancel(self) -> Uuid {
+        self.handler.abort();
+        self.stream_id
+    }
+
+    /// End the stream, or abort it if `aborted`, and wait for the handler
+    /// to finish with it, for up to [STALL_TIMEOUT].
+    pub(crate) async fn finish(mut self, aborted: bool) -> Result<(), HandlerError> {
+        let event = if aborted { Event::Abort } else { Event::End };
+        let _ = tokio::time::timeout(STALL_TIMEOUT, self.events.send(event)).await;
+        drop(self.events);
+        let Ok(finished) = tokio::time::timeout(STALL_TIMEOUT, &mut self.handler).await else {
+            self.handler.abort();
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "The stream handler did not finish in time").into());
+        };
+        match finished {
+            Ok(result) => result,
+            Err(e) => {
+                let message = e.try_into_panic().map_or_else(|e| e.to_string(), |payload| reporting::panic_message(&*payload));
+                error!("Stream handler panicked on stream {}: {message}", self.stream_id);
+                Err(io::Error::other("Stream handler panicked").into())
+            }
+        }
+    }
+}
@@ -0,0 +1,672 @@
+//! # Subscriptions
+//!
+//! Peers subscribe to the data types they want a node to send them, and
+//! may limit their subscription to objects published under some topics,
+//! with the [TopicFilter]s described in [routing](crate::routing). By
+//! default every subscription is approved as soon as it is requested. Nodes
+//! that curate who receives their content can instead hold subscriptions for
+//! their operator to approve or deny, by building the node with
+//! [SubscriptionApproval::Manual].
+//!
+//! Peers that subscribed with the port they listen on are sent new objects
+//! of their data types as they come in from other peers, with one less
+//! [ttl](osp_protocol::Envelope::ttl), or are
+//! [published](crate::OSProtocolNode::publish) by the node itself. The
+//! [SubscriptionManager] keeps track of who is subscribed to what, and each
+//! object is queued for its subscribers as a
+//! [delivery](crate::delivery), so it still reaches subscribers that are
+//! down for a while.
+//!
+//! Subscribers choose how objects are delivered to them, with a
+//! [DeliveryQos]: pushed once and forgotten, retried until the subscriber
+//! acknowledges them, or that and in the order they were queued in. The
+//! host reports the level it delivers with when it answers the
+//! subscription.
+//!
+//! Subscribers that can't keep up advertise a [ReceiveWindow] of how many
+//! more objects, or bytes of payload, they can take in. Every object
+//! delivered to a subscriber takes from its window, and once it is used up
+//! delivering to the subscriber pauses, leaving its objects queued, until
+//! it advertises its window again. Windows, and which subscribers are
+//! paused, are kept in memory only, so a restarted node delivers without a
+//! window until it is advertised again.
+//!
+//! Subscribers may also report the highest sequence of the objects
+//! delivered to them that they durably processed, see
+//! [report_processed](crate::connection::outbound::OutboundConnection::report_processed).
+//! The node tells from it how far behind each subscriber is, its
+//! [lag](SubscriptionManager::lag), reported as the `osp_peer_lag` gauge,
+//! and stops retrying deliveries the subscriber processed although their
+//! ack didn't arrive in time. Like windows, reports are kept in memory only.
+//!
+//! Subscriptions may be leased: peers learn how long in the handshake and
+//! renew their subscription before it runs out, see
+//! [subscription_lease](crate::builder::OSProtocolNodeBuilder::subscription_lease).
+//! Peers that vanished without unsubscribing stop being sent objects once
+//! their lease has run out. Their subscription is kept, so it is approved
+//! again right away if they come back and renew it.
+//!
+//! Hostnames are compared case-insensitively, subscriptions are kept under
+//! the lowercase hostname of the peer.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_protocol::{OSPUrl, Scheme};
+
+use crate::routing::{RoutingTable, TopicFilter};
+use crate::store::DataStore;
+
+pub use osp_protocol::packet::transfer::{DeliveryQos, ReceiveWindow, SubscriptionState};
+
+/// A lease of a day, for nodes that lease subscriptions, see
+/// [subscription_lease](crate::builder::OSProtocolNodeBuilder::subscription_lease).
+pub const DEFAULT_SUBSCRIPTION_LEASE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How subscription requests are decided.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SubscriptionApproval {
+    /// Approve every request.
+    #[default]
+    Automatic,
+    /// Queue requests until the operator approves or denies them through the
+    /// [AdminApi](crate::admin::AdminApi).
+    Manual,
+}
+
+/// A peer's subscription to this node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subscription {
+    /// Hostname of the subscribed peer.
+    pub peer: String,
+    pub data_types: Vec<Uuid>,
+    pub state: SubscriptionState,
+    /// Unix timestamp (seconds) the subscription was last requested at.
+    pub requested_at: u64,
+    /// Port the peer takes deliveries on, `None` if it doesn't listen and
+    /// only recorded its interest.
+    pub port: Option<u16>,
+    /// Whether deliveries are pushed to the port over raw TCP or a
+    /// WebSocket.
+    pub scheme: Scheme,
+    /// Unix timestamp (seconds) the lease of the subscription runs out at,
+    /// `None` if it doesn't.
+    pub expires_at: Option<u64>,
+    /// Topics the subscription is limited to, empty if it takes every
+    /// object of its data types.
+    pub topics: Vec<TopicFilter>,
+    /// How objects are delivered to the peer.
+    pub qos: DeliveryQos,
+    /// The latest version of data types the peer understands, see
+    /// [translate_for](osp_data::DataTypeRegistry::translate_for). Types
+    /// left out are delivered in whichever version they are.
+    pub type_versions: Vec<(Uuid, u16)>,
+}
+
+impl Subscription {
+    /// Whether the lease of the subscription has run out by `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Where and how to deliver objects, with when the lease of the subscriber
+/// runs out.
+type Subscriber = (OSPUrl, DeliveryQos, Option<u64>);
+
+/// The subscribers of a data type.
+#[derive(Default)]
+struct TypeSubscribers {
+    /// Subscribers taking every object
+    all: Vec<Subscriber>,
+    /// Subscribers limited to topics
+    topics: RoutingTable<Subscriber>,
+}
+
+type Subscribers = HashMap<Uuid, TypeSubscribers>;
+
+/// The receive window a subscriber advertised, and what is left of it.
+#[derive(Clone, Copy)]
+struct Window {
+    advertised: ReceiveWindow,
+    left: ReceiveWindow,
+}
+
+/// Keeps track of the subscriptions to a node, stored in its
+/// [DataStore].
+pub struct SubscriptionManager {
+    store: Arc<dyn DataStore>,
+    approval: SubscriptionApproval,
+    lease: Option<Duration>,
+    /// Loaded from the store when first needed and dropped whenever a
+    /// subscription changes. Loaded while the lock is held for writing, so a
+    /// change made meanwhile drops what was loaded once it is in place
+    subscribers: RwLock<Option<Subscribers>>,
+    /// By lowercase hostname of the subscriber, which have none if they
+    /// aren't here
+    windows: Mutex<HashMap<String, Window>>,
+    /// By lowercase hostname of the subscriber, the highest sequence it
+    /// reported it processed
+    processed: Mutex<HashMap<String, u64>>,
+    /// Lowercase hostnames of the subscribers deliveries are paused for, until
+    /// they advertise their window again
+    paused: Mutex<HashSet<String>>,
+    /// By lowercase hostname, the types this node subscribed to at peers,
+    /// `None` for every type
+    upstream: Mutex<HashMap<String, Option<HashSet<Uuid>>>>,
+}
+
+impl SubscriptionManager {
+    pub(crate) fn new(store: Arc<dyn DataStore>, approval: SubscriptionApproval, lease: Option<Duration>) -> Self {
+        SubscriptionManager {
+            store,
+            approval,
+            lease,
+            subscribers: RwLock::new(None),
+            windows: Mutex::new(HashMap::new()),
+            processed: Mutex::new(HashMap::new()),
+            paused: Mutex::new(HashSet::new()),
+            upstream: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How subscription requests are decided.
+    pub fn approval(&self) -> SubscriptionApproval {
+        self.approval
+    }
+
+    /// How long subscriptions last unless they are renewed, `None` if they
+    /// don't expire.
+    pub fn lease(&self) -> Option<Duration> {
+        self.lease
+    }
+
+    /// Where to deliver new objects of `type_id` published under `topic`:
+    /// the peers with an approved subscription to it that take deliveries,
+    /// and whose lease hasn't run out. Subscriptions limited to topics only
+    /// get objects published under a topic they match.
+    pub fn subscribers(&self, type_id: Uuid, topic: Option<&str>) -> io::Result<Vec<OSPUrl>> {
+        Ok(self.deliveries(type_id, topic)?.into_iter().map(|(url, _)| url).collect())
+    }
+
+    /// The [subscribers](Self::subscribers) of `type_id` under `topic`, with
+    /// how objects are delivered to each.
+    pub fn deliveries(&self, type_id: Uuid, topic: Option<&str>) -> io::Result<Vec<(OSPUrl, DeliveryQos)>> {
+        let now = now();
+        let current = |subscribers: &Subscribers| {
+            let Some(subscribers) = subscribers.get(&type_id) else {
+                return Vec::new();
+            };
+            let matching = topic.map(|topic| subscribers.topics.matches(topic)).unwrap_or_default();
+            let mut found = Vec::<(OSPUrl, DeliveryQos)>::new();
+            for (url, qos, expires_at) in subscribers.all.iter().chain(matching) {
+                // a peer may match with several filters
+                if expires_at.is_none_or(|expires_at| expires_at > now) && !found.iter().any(|(found, _)| found == url) {
+                    found.push((url.clone(), *qos));
+                }
+            }
+            found
+        };
+        if let Some(subscribers) = self.subscribers.read().unwrap().as_ref() {
+            return Ok(current(subscribers));
+        }
+
+        let mut cached = self.subscribers.write().unwrap();
+        if let Some(subscribers) = cached.as_ref() {
+            return Ok(current(subscribers));
+        }
+        let mut subscribers = Subscribers::new();
+        for subscription in self.store.subscriptions()? {
+            let Some(port) = subscription.port else {
+                continue;
+            };
+            if subscription.state != SubscriptionState::Approved {
+                continue;
+            }
+            for type_id in subscription.data_types {
+                let url = OSPUrl { domain: subscription.peer.clone(), port, scheme: subscription.scheme };
+                let subscribers = subscribers.entry(type_id).or_default();
+                for filter in &subscription.topics {
+                    subscribers.topics.insert(filter, (url.clone(), subscription.qos, subscription.expires_at));
+                }
+                if subscription.topics.is_empty() {
+                    subscribers.all.push((url, subscription.qos, subscription.expires_at));
+                }
+            }
+        }
+        Ok(current(cached.insert(subscribers)))
+    }
+
+    /// Handle a subscription request from `peer` for `data_types`, limited
+    /// to `topics` if there are any and delivered with `qos` in the
+    /// `type_versions` the peer understands, returning the
+    /// state of the subscription and its state before, if `peer` had one.
+    /// Denied peers stay denied.
+    /// Otherwise the request is approved or, with
+    /// [SubscriptionApproval::Manual], held for the operator unless it only
+    /// narrows an approved subscription.
+    pub(crate) fn request(
+        &self,
+        peer: &str,
+        data_types: Vec<Uuid>,
+        topics: Vec<TopicFilter>,
+        qos: DeliveryQos,
+        port: Option<u16>,
+        type_versions: Vec<(Uuid, u16)>,
+    ) -> io::Result<(SubscriptionState, Option<SubscriptionState>)> {
+        let peer = &peer.to_ascii_lowercase();
+        let existing = self.store.subscription(peer)?;
+        let narrows = |existing: &Subscription| {
+            data_types.iter().all(|type_id| existing.data_types.contains(type_id))
+                && (existing.topics.is_empty()
+                    || !topics.is_empty() && topics.iter().all(|filter| existing.topics.iter().any(|existing| existing.covers(filter))))
+        };
+        let state = match (&existing, self.approval) {
+            (Some(existing), _) if existing.state == SubscriptionState::Denied => SubscriptionState::Denied,
+            (_, SubscriptionApproval::Automatic) => SubscriptionState::Approved,
+            (Some(existing), SubscriptionApproval::Manual)
+                if existing.state == SubscriptionState::Approved && narrows(existing) => SubscriptionState::Approved,
+            (_, SubscriptionApproval::Manual) => SubscriptionState::Pending,
+        };
+
+        let now = now();
+        self.put(&Subscription {
+            peer: peer.clone(),
+            data_types,
+            state,
+            requested_at: now,
+            port,
+            // subscribing peers name the port they take raw TCP on
+            scheme: Scheme::Osp,
+            expires_at: self.expiry(now),
+            topics,
+            qos,
+            type_versions,
+        })?;
+        Ok((state, existing.map(|subscription| subscription.state)))
+    }
+
+    /// Approve or deny the subscription of `peer`. Approving starts its
+    /// lease over, as the peer may have waited for longer than it lasts.
+    pub(crate) fn decide(&self, peer: &str, approve: bool) -> io::Result<SubscriptionState> {
+        let peer = &peer.to_ascii_lowercase();
+        let Some(mut subscription) = self.store.subscription(peer)? else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{peer} has not requested a subscription")));
+        };
+        subscription.state = match approve {
+            true => SubscriptionState::Approved,
+            false => SubscriptionState::Denied,
+        };
+        if approve {
+            subscription.expires_at = self.expiry(now());
+        }
+        self.put(&subscription)?;
+        Ok(subscription.state)
+    }
+
+    /// Extend the lease of the subscription of `peer`, even if it already
+    /// ran out, returning the state of the subscription and how it is
+    /// delivered to. [Unsubscribed](SubscriptionState::Unsubscribed) if
+    /// `peer` has none.
+    pub(crate) fn renew(&self, peer: &str) -> io::Result<(SubscriptionState, DeliveryQos)> {
+        let peer = &peer.to_ascii_lowercase();
+        let Some(mut subscription) = self.store.subscription(peer)? else {
+            return Ok((SubscriptionState::Unsubscribed, DeliveryQos::default()));
+        };
+        if subscription.state != SubscriptionState::Denied {
+            subscription.expires_at = self.expiry(now());
+            self.put(&subscription)?;
+        }
+        Ok((subscription.state, subscription.qos))
+    }
+
+    /// Stop delivering objects of `data_types` to `peer`, or end its
+    /// subscription if `data_types` is empty or it would be left without
+    /// any. Denied subscriptions are kept, so the peer stays denied. Returns
+    /// whether `peer` was subscribed.
+    pub fn unsubscribe(&self, peer: &str, data_types: &[Uuid]) -> io::Result<bool> {
+        let peer = &peer.to_ascii_lowercase();
+        let Some(mut subscription) = self.store.subscription(peer)? else {
+            return Ok(false);
+        };
+        if subscription.state == SubscriptionState::Denied {
+            return Ok(true);
+        }
+        subscription.data_types.retain(|type_id| !data_types.is_empty() && !data_types.contains(type_id));
+        match subscription.data_types.is_empty() {
+            true => {
+                self.store.remove_subscription(peer)?;
+                *self.subscribers.write().unwrap() = None;
+                self.windows.lock().unwrap().remove(peer);
+                self.processed.lock().unwrap().remove(peer);
+                self.paused.lock().unwrap().remove(peer);
+            }
+            false => self.put(&subscription)?,
+        }
+        Ok(true)
+    }
+
+    /// Record that this node subscribed to objects of `data_types` at
+    /// `peer`, or of every type if `data_types` is empty.
+    pub(crate) fn subscribed_upstream(&self, peer: &str, data_types: &[Uuid]) {
+        let mut upstream = self.upstream.lock().unwrap();
+        let types = upstream.entry(peer.to_ascii_lowercase()).or_insert_with(|| Some(HashSet::new()));
+        match (types, data_types.is_empty()) {
+            (types, true) => *types = None,
+            (Some(types), false) => types.extend(data_types),
+            (None, false) => {}
+        }
+    }
+
+    /// Record that this node unsubscribed from objects of `data_types` at
+    /// `peer`, or from every type if `data_types` is empty.
+    pub(crate) fn unsubscribed_upstream(&self, peer: &str, data_types: &[Uuid]) {
+        let mut upstream = self.upstream.lock().unwrap();
+        let peer = peer.to_ascii_lowercase();
+        if let Some(Some(types)) = upstream.get_mut(&peer).filter(|_| !data_types.is_empty()) {
+            types.retain(|type_id| !data_types.contains(type_id));
+            if !types.is_empty() {
+                return;
+            }
+        }
+        upstream.remove(&peer);
+    }
+
+    /// Whether this node subscribed to objects of `type_id` at `peer` since
+    /// it started, so it asked for them to be pushed. The subscriptions made
+    /// before a restart aren't known, as they are only kept in memory.
+    pub fn solicited(&self, peer: &str, type_id: Uuid) -> bool {
+        self.upstream.lock().unwrap().get(&peer.to_ascii_lowercase())
+            .is_some_and(|types| types.as_ref().is_none_or(|types| types.contains(&type_id)))
+    }
+
+    /// Advertise the receive window of the subscription of `peer`, replacing
+    /// what was left of the one before, or lifting it if `window` is
+    /// [unbounded](ReceiveWindow::is_unbounded), and resume deliveries to it
+    /// if they were [paused](Self::pause). Returns `false`, ignoring the
+    /// window, if `peer` has no subscription.
+    pub(crate) fn advertise_window(&self, peer: &str, window: ReceiveWindow) -> io::Result<bool> {
+        let peer = &peer.to_ascii_lowercase();
+        if self.store.subscription(peer)?.is_none() {
+            return Ok(false);
+        }
+        let mut windows = self.windows.lock().unwrap();
+        match window.is_unbounded() {
+            true => windows.remove(peer),
+            false => windows.insert(peer.to_string(), Window { advertised: window, left: window }),
+        };
+        self.paused.lock().unwrap().remove(peer);
+        Ok(true)
+    }
+
+    /// Pause deliveries to `peer`, as its receive window is used up, until
+    /// it advertises its window again.
+    pub(crate) fn pause(&self, peer: &str) {
+        self.paused.lock().unwrap().insert(peer.to_ascii_lowercase());
+    }
+
+    /// Whether deliveries to `peer` are [paused](Self::pause).
+    pub(crate) fn is_paused(&self, peer: &str) -> bool {
+        self.paused.lock().unwrap().contains(&peer.to_ascii_lowercase())
+    }
+
+    /// The subscribers deliveries are [paused](Self::pause) for.
+    pub(crate) fn paused(&self) -> Vec<String> {
+        self.paused.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// The latest version of the data type `type_id` `peer` understands,
+    /// `None` if it didn't tell or has no subscription.
+    pub fn type_version(&self, peer: &str, type_id: Uuid) -> io::Result<Option<u16>> {
+        let peer = &peer.to_ascii_lowercase();
+        Ok(self.store.subscription(peer)?.and_then(|subscription| {
+            subscription.type_versions.iter().find(|(id, _)| *id == type_id).map(|(_, version)| *version)
+        }))
+    }
+
+    /// What is left of the receive window of `peer`, `None` if it has none.
+    pub fn window(&self, peer: &str) -> Option<ReceiveWindow> {
+        let peer = &peer.to_ascii_lowercase();
+        self.windows.lock().unwrap().get(peer).map(|window| window.left)
+    }
+
+    /// Whether an object with `bytes` of payload fits in what is left of the
+    /// receive window of `peer`. Objects larger than the whole window fit
+    /// while nothing was taken from it, so they aren't held back forever.
+    pub(crate) fn fits_window(&self, peer: &str, bytes: u64) -> bool {
+        let peer = &peer.to_ascii_lowercase();
+        let windows = self.windows.lock().unwrap();
+        let Some(window) = windows.get(peer) else {
+            return true;
+        };
+        window.left.objects != Some(0)
+            && (window.left.bytes.is_none_or(|left| bytes <= left) || window.left == window.advertised)
+    }
+
+    /// Take an object with `bytes` of payload delivered to `peer` from its
+    /// receive window.
+    pub(crate) fn take_window(&self, peer: &str, bytes: u64) {
+        let peer = &peer.to_ascii_lowercase();
+        if let Some(window) = self.windows.lock().unwrap().get_mut(peer) {
+            window.left.objects = window.left.objects.map(|left| left.saturating_sub(1));
+            window.left.bytes = window.left.bytes.map(|left| left.saturating_sub(bytes));
+        }
+    }
+
+    /// Record that `peer` durably processed the objects delivered to it up
+    /// to the one pushed with `sequence`. Returns `false`, ignoring the
+    /// report, if `peer` has no subscription or claims to have processed
+    /// objects it was never sent.
+    pub(crate) fn report_processed(&self, peer: &str, sequence: u64) -> io::Result<bool> {
+        let peer = &peer.to_ascii_lowercase();
+        if self.store.subscription(peer)?.is_none() || sequence > self.last_sent(peer)? {
+            return Ok(false);
+        }
+        let mut processed = self.processed.lock().unwrap();
+        let processed = processed.entry(peer.to_string()).or_default();
+        *processed = sequence.max(*processed);
+        Ok(true)
+    }
+
+    /// The highest sequence `peer` reported it processed, `None` if it
+    /// didn't report any.
+    pub fn processed(&self, peer: &str) -> Option<u64> {
+        let peer = &peer.to_ascii_lowercase();
+        self.processed.lock().unwrap().get(peer).copied()
+    }
+
+    /// How many of the objects pushed to `peer` it didn't report it
+    /// processed yet, `None` if it didn't report any.
+    pub fn lag(&self, peer: &str) -> io::Result<Option<u64>> {
+        let peer = &peer.to_ascii_lowercase();
+        let Some(processed) = self.processed(peer) else {
+            return Ok(None);
+        };
+        Ok(Some(self.last_sent(peer)?.saturating_sub(processed)))
+    }
+
+    /// The sequence of the last object pushed to `peer`.
+    fn last_sent(&self, peer: &str) -> io::Result<u64> {
+        Ok(self.store.peer_state(peer)?.map_or(0, |state| state.next_sequence - 1))
+    }
+
+    /// When a lease starting `now` runs out.
+    fn expiry(&self, now: u64) -> Option<u64> {
+        self.lease.map(|lease| now + lease.as_secs())
+    }
+
+    fn put(&self, subscription: &Subscription) -> io::Result<()> {
+        self.store.put_subscription(subscription)?;
+        *self.subscribers.write().unwrap() = None;
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io;
+
+    use uuid::Uuid;
+
+    use osp_protocol::{OSPUrl, Scheme};
+
+    use crate::store::{DataStore, MemoryStore, PeerSyncState};
+    use crate::subscription::{DeliveryQos, ReceiveWindow, SubscriptionApproval, SubscriptionManager, SubscriptionState};
+
+    #[test]
+    fn test_subscribers() -> io::Result<()> {
+        let manager = SubscriptionManager::new(Arc::new(MemoryStore::new()), SubscriptionApproval::Manual, None);
+        let (notes, posts) = (Uuid::new_v4(), Uuid::new_v4());
+        manager.request("peer.test", vec![notes, posts], Vec::new(), DeliveryQos::default(), Some(4270), Vec::new())?;
+        manager.request("client.test", vec![notes], Vec::new(), DeliveryQos::default(), None, Vec::new())?;
+        // pending subscriptions aren't delivered to
+        assert!(manager.subscribers(notes, None)?.is_empty());
+
+        // hostnames are compared case-insensitively
+        manager.decide("Peer.Test", true)?;
+        manager.decide("client.test", true)?;
+        let peer = OSPUrl { domain: "peer.test".to_string(), port: 4270, scheme: Scheme::Osp };
+        assert_eq!(manager.subscribers(notes, None)?, vec![peer.clone()]);
+        assert_eq!(manager.subscribers(posts, None)?, vec![peer]);
+
+        assert!(manager.unsubscribe("peer.test", &[notes])?);
+        assert!(manager.subscribers(notes, None)?.is_empty());
+        assert_eq!(manager.subscribers(posts, None)?.len(), 1);
+        assert!(manager.unsubscribe("peer.test", &[])?);
+        assert!(manager.subscribers(posts, None)?.is_empty());
+        assert!(!manager.unsubscribe("peer.test", &[])?);
+
+        // unsubscribing doesn't lift a denial
+        manager.decide("client.test", false)?;
+        manager.unsubscribe("client.test", &[])?;
+        assert_eq!(manager.request("client.test", vec![notes], Vec::new(), DeliveryQos::default(), None, Vec::new())?.0, SubscriptionState::Denied);
+        Ok(())
+    }
+
+    #[test]
+    fn test_topics() -> io::Result<()> {
+        let manager = SubscriptionManager::new(Arc::new(MemoryStore::new()), SubscriptionApproval::Manual, None);
+        let notes = Uuid::new_v4();
+        let url = |peer: &str| OSPUrl { domain: peer.to_string(), port: 4270, scheme: Scheme::Osp };
+        manager.request("all.test", vec![notes], Vec::new(), DeliveryQos::default(), Some(4270), Vec::new())?;
+        manager.request("blog.test", vec![notes], vec!["blog/#".parse()?], DeliveryQos::default(), Some(4270), Vec::new())?;
+        manager.request("rust.test", vec![notes], vec!["blog/rust/*".parse()?, "blog/*/async".parse()?], DeliveryQos::Ordered, Some(4270), Vec::new())?;
+        for peer in ["all.test", "blog.test", "rust.test"] {
+            manager.decide(peer, true)?;
+        }
+
+        assert_eq!(manager.subscribers(notes, Some("blog/rust/async"))?, vec![url("all.test"), url("rust.test"), url("blog.test")]);
+        assert_eq!(manager.subscribers(notes, Some("blog"))?, vec![url("all.test"), url("blog.test")]);
+        assert_eq!(manager.subscribers(notes, Some("news/rust"))?, vec![url("all.test")]);
+        // objects without a topic only go to subscriptions without topics
+        assert_eq!(manager.subscribers(notes, None)?, vec![url("all.test")]);
+        assert_eq!(manager.deliveries(notes, Some("blog/rust/async"))?[1], (url("rust.test"), DeliveryQos::Ordered));
+
+        // narrowing the topics needs no approval, widening them does
+        let request = |filter: &str| manager.request("blog.test", vec![notes], vec![filter.parse()?], DeliveryQos::default(), Some(4270), Vec::new()).map(|(state, _)| state);
+        assert_eq!(request("blog/rust/#")?, SubscriptionState::Approved);
+        assert_eq!(request("blog/#")?, SubscriptionState::Pending);
+        Ok(())
+    }
+
+    #[test]
+    fn test_window() -> io::Result<()> {
+        let manager = SubscriptionManager::new(Arc::new(MemoryStore::new()), SubscriptionApproval::Automatic, None);
+        assert!(!manager.advertise_window("peer.test", ReceiveWindow::objects(1))?);
+        manager.request("peer.test", vec![Uuid::new_v4()], Vec::new(), DeliveryQos::default(), Some(4270), Vec::new())?;
+        assert!(manager.fits_window("peer.test", u64::MAX));
+
+        assert!(manager.advertise_window("peer.test", ReceiveWindow { objects: Some(2), bytes: Some(100) })?);
+        // too large for the window, but it is all there is
+        assert!(manager.fits_window("peer.test", 150));
+        manager.take_window("peer.test", 60);
+        assert!(!manager.fits_window("peer.test", 50));
+        assert!(manager.fits_window("peer.test", 40));
+        manager.take_window("peer.test", 40);
+        assert_eq!(manager.window("peer.test"), Some(ReceiveWindow { objects: Some(0), bytes: Some(0) }));
+        assert!(!manager.fits_window("peer.test", 0));
+
+        // advertising the window again resumes deliveries
+        manager.advertise_window("peer.test", ReceiveWindow::objects(1))?;
+        assert!(manager.fits_window("peer.test", 1 << 20));
+        manager.advertise_window("peer.test", ReceiveWindow::default())?;
+        assert_eq!(manager.window("peer.test"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_processed() -> io::Result<()> {
+        let store = Arc::new(MemoryStore::new());
+        let manager = SubscriptionManager::new(store.clone(), SubscriptionApproval::Automatic, None);
+        store.put_peer_state("peer.test", &PeerSyncState { next_sequence: 6, ..PeerSyncState::default() })?;
+        assert!(!manager.report_processed("peer.test", 3)?);
+        manager.request("peer.test", vec![Uuid::new_v4()], Vec::new(), DeliveryQos::default(), Some(4270), Vec::new())?;
+        assert_eq!(manager.lag("peer.test")?, None);
+
+        assert!(manager.report_processed("peer.test", 3)?);
+        assert_eq!(manager.lag("peer.test")?, Some(2));
+        // reports that arrive late don't move it back
+        assert!(manager.report_processed("peer.test", 1)?);
+        assert_eq!(manager.processed("peer.test"), Some(3));
+        // only five were sent
+        assert!(!manager.report_processed("peer.test", 6)?);
+
+        manager.unsubscribe("peer.test", &[])?;
+        assert_eq!(manager.processed("peer.test"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lease() -> io::Result<()> {
+        let store = Arc::new(MemoryStore::new());
+        let manager = SubscriptionManager::new(store.clone(), SubscriptionApproval::Automatic, Some(Duration::from_secs(60)));
+        let notes = Uuid::new_v4();
+        manager.request("peer.test", vec![notes], Vec::new(), DeliveryQos::default(), Some(4270), Vec::new())?;
+        assert_eq!(manager.subscribers(notes, None)?.len(), 1);
+
+        // the peer vanished and its lease ran out
+        let mut subscription = store.subscription("peer.test")?.unwrap();
+        subscription.expires_at = Some(subscription.requested_at - 1);
+        manager.put(&subscription)?;
+        assert!(manager.subscribers(notes, None)?.is_empty());
+
+        // renewing brings it back, without asking for approval again
+        assert_eq!(manager.renew("peer.test")?.0, SubscriptionState::Approved);
+        assert_eq!(manager.subscribers(notes, None)?.len(), 1);
+        assert!(store.subscription("peer.test")?.unwrap().expires_at > Some(subscription.requested_at));
+
+        manager.unsubscribe("peer.test", &[])?;
+        assert_eq!(manager.renew("peer.test")?.0, SubscriptionState::Unsubscribed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_solicited() {
+        let manager = SubscriptionManager::new(Arc::new(MemoryStore::new()), SubscriptionApproval::Automatic, None);
+        let (notes, posts) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(!manager.solicited("peer.test", notes));
+
+        manager.subscribed_upstream("Peer.Test", &[notes, posts]);
+        assert!(manager.solicited("peer.test", notes) && !manager.solicited("other.test", notes));
+        manager.unsubscribed_upstream("peer.test", &[notes]);
+        assert!(!manager.solicited("peer.test", notes) && manager.solicited("peer.test", posts));
+
+        // subscribing to every type takes objects of any
+        manager.subscribed_upstream("peer.test", &[]);
+        assert!(manager.solicited("peer.test", notes));
+        manager.unsubscribed_upstream("peer.test", &[]);
+        assert!(!manager.solicited("peer.test", posts));
+    }
+}
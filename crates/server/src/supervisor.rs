@@ -0,0 +1,225 @@
+//! # Supervised Push Targets
+//!
+//! While a node listens, it keeps a connection open to each of its
+//! [push targets](crate::OSProtocolNodeBuilder::push_target), and pushes
+//! the deliveries queued for the target over it as they come due, see
+//! [delivery](crate::delivery). When the connection is lost, e.g. because
+//! the target restarted, or can't be made, the target's hostname is
+//! resolved again and the node reconnects after a backoff. The backoff
+//! grows with each failed attempt like that of failed deliveries, see
+//! [RetryPolicy](crate::delivery::RetryPolicy), but reconnecting never
+//! gives up. A connection lost within [STABLE_AFTER] of being made counts
+//! as a failed attempt too, so a target dropping connections right after
+//! taking them isn't redialed without backing off. Deliveries queued for a
+//! target wait while it is disconnected, without using up their attempts,
+//! and are pushed once it is back.
+//!
+//! Every change of a target's [TargetState] is emitted as a
+//! [NodeEvent::PushTargetChanged], and the state of each target is listed
+//! by [OSProtocolNode::push_target_states](crate::OSProtocolNode::push_target_states).
+//! Whether a target is connected is reported as the
+//! `osp_push_target_connected` gauge, and every reconnect attempt is counted
+//! in `osp_push_target_reconnects_total`, both labeled with the `peer` and
+//! `port` of the target.
+//!
+//! Push targets [reloaded](crate::reload) into a running node are
+//! supervised from the next check of the settings on, and those no longer
+//! among them are disconnected.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use tokio::io;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use osp_protocol::{Closure, OSPUrl};
+
+use crate::OSProtocolNode;
+use crate::connection::outbound::{OutboundConnection, TransferState};
+use crate::delivery::{self, PendingDelivery, Pushed, BATCH_SIZE, POLL_INTERVAL};
+use crate::events::NodeEvent;
+use crate::metrics;
+
+/// How long a connection to a push target has to last for the target to
+/// count as back, resetting the backoff of reconnecting to it.
+pub const STABLE_AFTER: Duration = Duration::from_secs(30);
+
+/// The state of the connection with a push target.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TargetState {
+    /// Resolving the target and handshaking with it, for the `attempt`th
+    /// time since it was last connected for [STABLE_AFTER].
+    Connecting { attempt: u32 },
+    /// Connected, pushing deliveries as they come due.
+    Connected,
+    /// The connection was lost or couldn't be made because of `reason`, and
+    /// is tried again in `retry_in`.
+    Disconnected { reason: String, retry_in: Duration },
+}
+
+/// A push target of the node.
+struct Target {
+    url: OSPUrl,
+    state: TargetState,
+    /// Notified when a delivery is queued for the target
+    queued: Arc<Notify>,
+}
+
+/// The push targets a node keeps connections to, by hostname and port.
+#[derive(Default)]
+pub(crate) struct Supervisor {
+    targets: Mutex<HashMap<(String, u16), Target>>,
+}
+
+impl Supervisor {
+    /// Whether deliveries to `peer` at `port` are pushed by a supervised
+    /// connection.
+    pub(crate) fn supervises(&self, peer: &str, port: u16) -> bool {
+        self.targets.lock().unwrap().contains_key(&(peer.to_string(), port))
+    }
+
+    /// Wake up the connection with `url`, if it is supervised, as a
+    /// delivery was queued for it.
+    pub(crate) fn queued(&self, url: &OSPUrl) {
+        if let Some(target) = self.targets.lock().unwrap().get(&(url.domain.clone(), url.port)) {
+            target.queued.notify_one();
+        }
+    }
+
+    pub(crate) fn states(&self) -> Vec<(OSPUrl, TargetState)> {
+        let mut states = self.targets.lock().unwrap().values()
+            .map(|target| (target.url.clone(), target.state.clone()))
+            .collect::<Vec<_>>();
+        states.sort_by(|(a, _), (b, _)| (&a.domain, a.port).cmp(&(&b.domain, b.port)));
+        states
+    }
+
+    fn register(&self, url: &OSPUrl) -> Arc<Notify> {
+        let queued = Arc::new(Notify::new());
+        self.targets.lock().unwrap().insert((url.domain.clone(), url.port), Target {
+            url: url.clone(),
+            state: TargetState::Connecting { attempt: 1 },
+            queued: queued.clone(),
+        });
+        queued
+    }
+
+    fn forget(&self, key: &(String, u16)) {
+        self.targets.lock().unwrap().remove(key);
+    }
+}
+
+/// Keep connections to the node's push targets until it stops, checking
+/// for targets that were added or removed every [POLL_INTERVAL].
+pub(crate) async fn run(node: OSProtocolNode) {
+    let mut running = HashMap::<(String, u16), JoinHandle<()>>::new();
+    loop {
+        let targets = node.live_settings().push_targets;
+        running.retain(|key, task| {
+            let kept = targets.iter().any(|url| (&url.domain, url.port) == (&key.0, key.1));
+            if !kept {
+                info!("No longer supervising push target {}:{}", key.0, key.1);
+                task.abort();
+                node.supervisor().forget(key);
+                metrics::push_target_connected(&key.0, key.1, false);
+            }
+            kept
+        });
+        for url in targets {
+            running.entry((url.domain.clone(), url.port)).or_insert_with(|| {
+                let queued = node.supervisor().register(&url);
+                tokio::spawn(supervise(node.clone(), url, queued))
+            });
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Connect to the push target at `url`, push its deliveries and reconnect
+/// whenever the connection is lost, forever.
+async fn supervise(node: OSProtocolNode, url: OSPUrl, queued: Arc<Notify>) {
+    let mut failures = 0;
+    loop {
+        transition(&node, &url, TargetState::Connecting { attempt: failures + 1 });
+        let connect = tokio::time::timeout(node.handshake_timeout(), node.create_outbound(url.clone())).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Connecting timed out")));
+        let err = match connect {
+            Ok(conn) => {
+                transition(&node, &url, TargetState::Connected);
+                let connected_at = Instant::now();
+                let err = serve(&node, &url, conn, &queued).await;
+                if connected_at.elapsed() >= STABLE_AFTER {
+                    failures = 0;
+                }
+                err
+            }
+            Err(e) => e,
+        };
+        if let Some(closure) = Closure::of(&err) {
+            metrics::connection_closed(closure);
+        }
+        failures += 1;
+        let retry_in = node.retry_policy().backoff(failures, delivery::random_unit().unwrap_or_default());
+        warn!("Lost the connection to push target {url}, reconnecting in {retry_in:?}: {err}");
+        transition(&node, &url, TargetState::Disconnected { reason: err.to_string(), retry_in });
+        tokio::time::sleep(retry_in).await;
+        metrics::push_target_reconnect(&url.domain, url.port);
+    }
+}
+
+/// Push the deliveries for the target at `url` over `conn` as they come
+/// due, until the connection is lost. Returns what it was lost with.
+async fn serve(node: &OSProtocolNode, url: &OSPUrl, mut conn: OutboundConnection<TransferState>, queued: &Notify) -> io::Error {
+    loop {
+        let due = match due_deliveries(node, url) {
+            Ok(due) => due,
+            Err(e) => return e,
+        };
+        match delivery::push(node, &mut conn, due).await {
+            Ok(Pushed::All) => {}
+            Ok(Pushed::Lost { err, unpushed }) => {
+                debug!("{} deliveries to {url} wait for the connection to be back", unpushed.len());
+                return err;
+            }
+            Err(e) => return e,
+        }
+        // reading notices the peer going away while there is nothing to
+        // push, and pings it while it is silent
+        tokio::select! {
+            _ = queued.notified() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            packet = conn.read_packet() => {
+                if let Err(e) = packet {
+                    return e;
+                }
+            }
+        }
+    }
+}
+
+/// The deliveries queued for the target at `url` that are due, the
+/// soonest due first. None are while deliveries to it are paused.
+fn due_deliveries(node: &OSProtocolNode, url: &OSPUrl) -> io::Result<Vec<PendingDelivery>> {
+    if node.subscriptions().is_paused(&url.domain) {
+        return Ok(Vec::new());
+    }
+    let now = delivery::now_millis();
+    Ok(node.data_store().queued_deliveries(&url.domain, url.port)?.into_iter()
+        .take_while(|queued| queued.next_attempt_at <= now)
+        .take(BATCH_SIZE)
+        .collect())
+}
+
+/// Move the target at `url` to `state`, emitting the change.
+fn transition(node: &OSProtocolNode, url: &OSPUrl, state: TargetState) {
+    let key = (url.domain.clone(), url.port);
+    if let Some(target) = node.supervisor().targets.lock().unwrap().get_mut(&key) {
+        target.state = state.clone();
+    }
+    metrics::push_target_connected(&url.domain, url.port, state == TargetState::Connected);
+    node.emit(NodeEvent::PushTargetChanged { url: url.clone(), state });
+}
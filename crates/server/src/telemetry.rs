@@ -0,0 +1,292 @@
+//! # OpenTelemetry Export
+//!
+//! With the `otel` feature, [Telemetry::install] exports the traces and
+//! metrics of a node over OTLP, so it shows up next to the rest of an
+//! existing observability stack:
+//!
+//! - Traces cover every inbound connection, from the handshake through the
+//!   transfer to the handlers each received object is dispatched to, and
+//!   every object the node pushes. Pushed objects carry the id of the trace
+//!   they were pushed in, so a peer exporting to the same backend continues
+//!   it and the work on both nodes can be followed as one trace.
+//! - Metrics are the ones the node reports through the
+//!   [metrics](::metrics) facade, forwarded to OpenTelemetry instruments by
+//!   [OtelRecorder].
+//!
+//! ```ignore
+//! let telemetry = Telemetry::install(OtlpConfig {
+//!     endpoint: "http://collector:4317".to_string(),
+//!     ..OtlpConfig::default()
+//! })?;
+//! node.listen().await?;
+//! telemetry.shutdown()?;
+//! ```
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use ::metrics::{Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+
+use opentelemetry::{global, KeyValue};
+use opentelemetry::metrics::{Meter, MeterProvider};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{runtime, Resource};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::TracerProvider;
+
+use tokio::io;
+
+/// Where and how [Telemetry] exports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OtlpConfig {
+    /// gRPC endpoint of the OTLP collector.
+    pub endpoint: String,
+    /// The `service.name` traces and metrics are reported under.
+    pub service_name: String,
+    /// How often metrics are exported.
+    pub metrics_interval: Duration,
+    /// How long a single export may take.
+    pub timeout: Duration,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        OtlpConfig {
+            endpoint: "http://localhost:4317".to_string(),
+            service_name: "osp-node".to_string(),
+            metrics_interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// OTLP export of the traces and metrics of the nodes in this process, see
+/// the [module](self) docs.
+pub struct Telemetry {
+    tracer_provider: TracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Telemetry {
+    /// Start exporting according to `config`. Installs the global tracer
+    /// provider and metrics recorder, so it fails with
+    /// [AlreadyExists](io::ErrorKind::AlreadyExists) if another recorder
+    /// is installed already. Must be called from within a Tokio runtime.
+    pub fn install(config: OtlpConfig) -> io::Result<Telemetry> {
+        let resource = Resource::new([KeyValue::new("service.name", config.service_name)]);
+
+        let span_exporter = SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(&config.endpoint)
+            .with_timeout(config.timeout)
+            .build()
+            .map_err(otel_err)?;
+        let tracer_provider = TracerProvider::builder()
+            .with_batch_exporter(span_exporter, runtime::Tokio)
+            .with_resource(resource.clone())
+            .build();
+
+        let metric_exporter = MetricExporter::builder()
+            .with_tonic()
+            .with_endpoint(&config.endpoint)
+            .with_timeout(config.timeout)
+            .build()
+            .map_err(otel_err)?;
+        let reader = PeriodicReader::builder(metric_exporter, runtime::Tokio)
+            .with_interval(config.metrics_interval)
+            .with_timeout(config.timeout)
+            .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource)
+            .build();
+
+        ::metrics::set_global_recorder(OtelRecorder::new(meter_provider.meter("osp_server_sdk")))
+            .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "A metrics recorder is already installed"))?;
+        global::set_tracer_provider(tracer_provider.clone());
+        Ok(Telemetry { tracer_provider, meter_provider })
+    }
+
+    /// Export what is still buffered and stop exporting.
+    pub fn shutdown(self) -> io::Result<()> {
+        let traces = self.tracer_provider.shutdown().map_err(otel_err);
+        let metrics = self.meter_provider.shutdown().map_err(otel_err);
+        traces.and(metrics)
+    }
+}
+
+fn otel_err(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::other(e)
+}
+
+/// A [Recorder] reporting the metrics recorded through the
+/// [metrics](::metrics) facade as OpenTelemetry instruments of `meter`.
+/// Labels become attributes. [Telemetry::install] installs one, use it
+/// directly to combine it with other recorders.
+pub struct OtelRecorder {
+    meter: Meter,
+    descriptions: Mutex<HashMap<String, SharedString>>,
+    // handles are cached, so absolute counters and relative gauge updates
+    // see the previous value of their series
+    counters: Mutex<HashMap<Key, Arc<OtelCounter>>>,
+    gauges: Mutex<HashMap<Key, Arc<OtelGauge>>>,
+    histograms: Mutex<HashMap<Key, Arc<OtelHistogram>>>,
+}
+
+impl OtelRecorder {
+    pub fn new(meter: Meter) -> Self {
+        OtelRecorder {
+            meter,
+            descriptions: Mutex::new(HashMap::new()),
+            counters: Mutex::new(HashMap::new()),
+            gauges: Mutex::new(HashMap::new()),
+            histograms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn describe(&self, key: KeyName, description: SharedString) {
+        self.descriptions.lock().unwrap().insert(key.as_str().to_string(), description);
+    }
+
+    fn description(&self, key: &Key) -> String {
+        self.descriptions.lock().unwrap().get(key.name()).map_or_else(String::new, |description| description.to_string())
+    }
+}
+
+fn attributes(key: &Key) -> Vec<KeyValue> {
+    key.labels().map(|label| KeyValue::new(label.key().to_string(), label.value().to_string())).collect()
+}
+
+impl Recorder for OtelRecorder {
+    fn describe_counter(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description);
+    }
+
+    fn describe_gauge(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description);
+    }
+
+    fn describe_histogram(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description);
+    }
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let counter = self.counters.lock().unwrap().entry(key.clone()).or_insert_with(|| Arc::new(OtelCounter {
+            counter: self.meter.u64_counter(key.name().to_string()).with_description(self.description(key)).build(),
+            attributes: attributes(key),
+            total: AtomicU64::new(0),
+        })).clone();
+        Counter::from_arc(counter)
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let gauge = self.gauges.lock().unwrap().entry(key.clone()).or_insert_with(|| Arc::new(OtelGauge {
+            gauge: self.meter.f64_gauge(key.name().to_string()).with_description(self.description(key)).build(),
+            attributes: attributes(key),
+            value: Mutex::new(0.0),
+        })).clone();
+        Gauge::from_arc(gauge)
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let histogram = self.histograms.lock().unwrap().entry(key.clone()).or_insert_with(|| Arc::new(OtelHistogram {
+            histogram: self.meter.f64_histogram(key.name().to_string()).with_description(self.description(key)).build(),
+            attributes: attributes(key),
+        })).clone();
+        Histogram::from_arc(histogram)
+    }
+}
+
+struct OtelCounter {
+    counter: opentelemetry::metrics::Counter<u64>,
+    attributes: Vec<KeyValue>,
+    total: AtomicU64,
+}
+
+impl CounterFn for OtelCounter {
+    fn increment(&self, value: u64) {
+        self.total.fetch_add(value, Ordering::Relaxed);
+        self.counter.add(value, &self.attributes);
+    }
+
+    fn absolute(&self, value: u64) {
+        // OpenTelemetry counters only go up, by the difference
+        let previous = self.total.fetch_max(value, Ordering::Relaxed);
+        if value > previous {
+            self.counter.add(value - previous, &self.attributes);
+        }
+    }
+}
+
+struct OtelGauge {
+    gauge: opentelemetry::metrics::Gauge<f64>,
+    attributes: Vec<KeyValue>,
+    value: Mutex<f64>,
+}
+
+impl OtelGauge {
+    fn update(&self, update: impl FnOnce(f64) -> f64) {
+        let mut value = self.value.lock().unwrap();
+        *value = update(*value);
+        self.gauge.record(*value, &self.attributes);
+    }
+}
+
+impl GaugeFn for OtelGauge {
+    fn increment(&self, value: f64) {
+        self.update(|current| current + value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|current| current - value);
+    }
+
+    fn set(&self, value: f64) {
+        self.update(|_| value);
+    }
+}
+
+struct OtelHistogram {
+    histogram: opentelemetry::metrics::Histogram<f64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl HistogramFn for OtelHistogram {
+    fn record(&self, value: f64) {
+        self.histogram.record(value, &self.attributes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use ::metrics::{Key, Label, Level, Metadata, Recorder};
+
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+
+    use crate::telemetry::OtelRecorder;
+
+    #[test]
+    fn test_recorder_keeps_series() {
+        const METADATA: Metadata<'static> = Metadata::new("osp_server_sdk", Level::INFO, None);
+        let recorder = OtelRecorder::new(SdkMeterProvider::default().meter("test"));
+        let key = Key::from_parts("osp_connections_total", vec![Label::new("country", "DE")]);
+
+        recorder.register_counter(&key, &METADATA).increment(2);
+        recorder.register_counter(&key, &METADATA).absolute(5);
+        recorder.register_counter(&key, &METADATA).absolute(3);
+        recorder.register_counter(&Key::from_name("osp_connections_refused_total"), &METADATA).increment(1);
+        let counters = recorder.counters.lock().unwrap();
+        assert_eq!(counters.len(), 2);
+        assert_eq!(counters[&key].total.load(Ordering::Relaxed), 5);
+
+        let gauge = Key::from_name("osp_connections_open");
+        recorder.register_gauge(&gauge, &METADATA).set(4.0);
+        recorder.register_gauge(&gauge, &METADATA).decrement(1.5);
+        assert_eq!(*recorder.gauges.lock().unwrap()[&gauge].value.lock().unwrap(), 2.5);
+    }
+}
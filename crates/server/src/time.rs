@@ -0,0 +1,30 @@
+//! # Time Formatting
+//!
+//! Calendar conversions for the few places that need to print timestamps,
+//! without pulling in a date library.
+
+/// Split a unix timestamp into `(year, month, day, hour, minute, second)`
+/// in UTC.
+pub(crate) fn civil(timestamp: u64) -> (i64, u32, u32, u32, u32, u32) {
+    let (days, secs) = ((timestamp / 86400) as i64, timestamp % 86400);
+
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    (year, month as u32, day as u32, (secs / 3600) as u32, (secs % 3600 / 60) as u32, (secs % 60) as u32)
+}
+
+/// Format a unix timestamp as an RFC 3339 UTC date, e.g.
+/// `2024-06-15T12:34:56Z`.
+pub(crate) fn rfc3339(timestamp: u64) -> String {
+    let (year, month, day, hour, minute, second) = civil(timestamp);
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
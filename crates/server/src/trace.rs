@@ -0,0 +1,130 @@
+//! # Traces
+//!
+//! Nodes trace the inbound connections they serve, from the handshake
+//! through the transfer to the handlers each object is dispatched to, and
+//! the objects they push. Spans are reported through the global
+//! [OpenTelemetry](::opentelemetry) tracer, see
+//! [telemetry](crate::telemetry), and are compiled out without the `otel`
+//! feature.
+//!
+//! A pushed object carries the [TraceContext] of the span it was pushed in,
+//! so the host continues the guest's trace and the work on both nodes can be
+//! correlated. Objects also keep the context of the trace they were first
+//! pushed in as the `trace_parent` of their envelope, so pushes relaying
+//! them on continue that trace even though they run outside of it. Which
+//! peers see either is up to the node's [TracePropagation](crate::policy::TracePropagation).
+#![cfg_attr(not(feature = "otel"), allow(unused_variables))]
+
+use std::fmt::Display;
+use std::future::Future;
+
+#[cfg(feature = "otel")]
+use opentelemetry::{global, Context, KeyValue};
+#[cfg(feature = "otel")]
+use opentelemetry::trace::{FutureExt, SpanContext, SpanId, Status, TraceContextExt, TraceFlags, TraceId, TraceState, Tracer};
+
+use osp_protocol::packet::transfer::TraceContext;
+
+/// Name of the tracer node spans are reported under.
+#[cfg(feature = "otel")]
+const TRACER: &str = "osp_server_sdk";
+
+/// A span of work done by the node. It ends when it and every future
+/// [instrumented](Span::instrument) with it are dropped.
+#[derive(Clone, Default)]
+pub(crate) struct Span {
+    #[cfg(feature = "otel")]
+    cx: Context,
+}
+
+impl Span {
+    /// Start a span named `name` as a child of the current one, if any.
+    pub(crate) fn start(name: &'static str) -> Span {
+        #[cfg(feature = "otel")]
+        {
+            Self::start_with_parent(name, Context::current())
+        }
+        #[cfg(not(feature = "otel"))]
+        Span::default()
+    }
+
+    /// Start a span named `name` continuing the trace `remote` came from, or
+    /// as a child of the current one if the peer sent none.
+    pub(crate) fn continue_remote(name: &'static str, remote: Option<TraceContext>) -> Span {
+        #[cfg(feature = "otel")]
+        {
+            let parent = match remote {
+                Some(remote) => {
+                    let flags = if remote.sampled { TraceFlags::SAMPLED } else { TraceFlags::default() };
+                    let span_context = SpanContext::new(
+                        TraceId::from_bytes(remote.trace_id),
+                        SpanId::from_bytes(remote.span_id),
+                        flags,
+                        true,
+                        TraceState::default(),
+                    );
+                    Context::current().with_remote_span_context(span_context)
+                }
+                None => Context::current(),
+            };
+            Self::start_with_parent(name, parent)
+        }
+        #[cfg(not(feature = "otel"))]
+        Span::default()
+    }
+
+    /// Start a span named `name` as a child of the current one, or
+    /// continuing the trace `remote` came from if there is no current one.
+    pub(crate) fn start_or_continue(name: &'static str, remote: Option<TraceContext>) -> Span {
+        #[cfg(feature = "otel")]
+        if Context::current().has_active_span() {
+            return Self::start(name);
+        }
+        Self::continue_remote(name, remote)
+    }
+
+    #[cfg(feature = "otel")]
+    fn start_with_parent(name: &'static str, parent: Context) -> Span {
+        let span = global::tracer(TRACER).start_with_context(name, &parent);
+        Span { cx: parent.with_span(span) }
+    }
+
+    pub(crate) fn attribute(&self, key: &'static str, value: impl Display) {
+        #[cfg(feature = "otel")]
+        self.cx.span().set_attribute(KeyValue::new(key, value.to_string()));
+    }
+
+    /// Mark the span as failed with `err`.
+    pub(crate) fn fail(&self, err: impl Display) {
+        #[cfg(feature = "otel")]
+        self.cx.span().set_status(Status::error(err.to_string()));
+    }
+
+    /// Run `future` inside the span, so spans started by it are its
+    /// children.
+    pub(crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        #[cfg(feature = "otel")]
+        {
+            future.with_context(self.cx.clone())
+        }
+        #[cfg(not(feature = "otel"))]
+        future
+    }
+
+    /// The context to send along with an object pushed in this span, if it
+    /// is recorded at all.
+    pub(crate) fn trace_context(&self) -> Option<TraceContext> {
+        #[cfg(feature = "otel")]
+        {
+            let span = self.cx.span();
+            let span_context = span.span_context();
+            span_context.is_valid().then(|| TraceContext {
+                trace_id: span_context.trace_id().to_bytes(),
+                span_id: span_context.span_id().to_bytes(),
+                sampled: span_context.is_sampled(),
+            })
+        }
+        #[cfg(not(feature = "otel"))]
+        None
+    }
+}
@@ -0,0 +1,56 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use log::info;
+use osp_server_sdk::store::{DataStore, SqliteStore};
+use osp_server_sdk::store::archive::{self, ArchiveFormat};
+
+/// Export a node's stored objects to an archive, or import an archive into a node's store
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// SQLite database the node keeps its state in
+    #[arg(long, default_value = "osp_node.db")]
+    store: String,
+
+    /// Archive format, jsonl or warc
+    #[arg(long, default_value = "jsonl")]
+    format: ArchiveFormat,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write every stored object to an archive
+    Export {
+        output: PathBuf,
+    },
+    /// Store every object in an archive
+    Import {
+        input: PathBuf,
+    },
+}
+
+fn main() -> io::Result<()> {
+    let mut clog = colog::default_builder();
+    clog.filter(None, log::LevelFilter::Info);
+    clog.init();
+
+    let args = Args::parse();
+    let store = SqliteStore::open(args.store)?;
+
+    match args.command {
+        Command::Export { output } => {
+            info!("Exporting to {}", output.display());
+            archive::export(&store, BufWriter::new(File::create_new(output)?), args.format)?;
+        }
+        Command::Import { input } => {
+            info!("Importing from {}", input.display());
+            archive::import(BufReader::new(File::open(input)?), args.format, |e| store.put_object(e))?;
+        }
+    }
+    Ok(())
+}
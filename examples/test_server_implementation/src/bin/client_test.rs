@@ -1,12 +1,10 @@
-use std::{fs};
-
 use clap::{Parser};
 use log::{info};
-use openssl::rsa::Rsa;
 use tokio::io;
 use url::Url;
+use osp_client_sdk::OSProtocolClient;
+use osp_data::OspData;
 use osp_protocol::OSPUrl;
-use osp_server_sdk::connection::outbound::OutboundConnection;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -22,6 +20,17 @@ struct Args {
     /// Used to identify myself during the handshake
     #[arg(long)]
     hostname: String,
+
+    /// Text of a note to send once connected
+    #[arg(long)]
+    note: Option<String>,
+}
+
+/// A plain text note, as handled by the test server
+#[derive(OspData)]
+#[osp(id = "5e0cbd52-2d38-4a3e-9bb0-7a4d1b1f3c11")]
+struct Note {
+    text: String,
 }
 
 #[tokio::main]
@@ -32,14 +41,19 @@ async fn main() -> io::Result<()> {
 
     let args = Args::parse();
 
-    let key_contents = fs::read_to_string(args.private_key.clone()).expect(format!("Unable to open private key file {}", args.private_key).as_str());
-    let key = Rsa::private_key_from_pem(key_contents.as_bytes()).unwrap();
-
     let reg_url = Url::parse(args.url.as_str()).unwrap();
-    let url = OSPUrl::from(reg_url);
-
-    info!("Starting outbound thread");
-    let mut conn = OutboundConnection::create(url, key, args.hostname).await?;
-    let mut conn_in_handshake = conn.begin().await?;
-    conn_in_handshake.handshake().await
-}
\ No newline at end of file
+    let url = OSPUrl::try_from(reg_url)?;
+
+    info!("Connecting to {url}");
+    let mut client = OSProtocolClient::builder()
+        .hostname(args.hostname)
+        .private_key_file(&args.private_key)?
+        .connect_url(url)
+        .await?;
+
+    if let Some(text) = args.note {
+        let sequence = client.send_data(&Note { text }).await?;
+        info!("Sent note with sequence {sequence}");
+    }
+    Ok(())
+}
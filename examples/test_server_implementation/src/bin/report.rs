@@ -0,0 +1,57 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::Parser;
+use log::info;
+use osp_server_sdk::compliance::{self, ReportFormat};
+use osp_server_sdk::store::SqliteStore;
+use osp_server_sdk::store::quota::StorageQuotas;
+
+/// Report what a node's store held and removed over a period
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// SQLite database the node keeps its state in
+    #[arg(long, default_value = "osp_node.db")]
+    store: String,
+
+    /// Report format, json or csv
+    #[arg(long, default_value = "json")]
+    format: ReportFormat,
+
+    /// Start of the period, a unix timestamp (default: 30 days ago)
+    #[arg(long)]
+    from: Option<u64>,
+
+    /// End of the period, a unix timestamp (default: now)
+    #[arg(long)]
+    to: Option<u64>,
+
+    /// File to write the report to, instead of stdout
+    output: Option<PathBuf>,
+}
+
+fn main() -> io::Result<()> {
+    let mut clog = colog::default_builder();
+    clog.filter(None, log::LevelFilter::Info);
+    clog.init();
+
+    let args = Args::parse();
+    let store = SqliteStore::open(args.store)?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let to = args.to.unwrap_or(now);
+    let from = args.from.unwrap_or(to.saturating_sub(30 * 24 * 60 * 60));
+    // quotas aren't kept in the store, evictions are reported without them
+    let report = compliance::generate(&store, &StorageQuotas::default(), from, to)?;
+
+    match args.output {
+        Some(output) => {
+            info!("Writing the report to {}", output.display());
+            report.write(BufWriter::new(File::create_new(output)?), args.format)?;
+        }
+        None => report.write(io::stdout().lock(), args.format)?,
+    }
+    Ok(())
+}
@@ -1,32 +1,94 @@
 use std::net::{SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::Duration;
 use std::{io};
 use clap::Parser;
+use log::info;
+use osp_data::OspData;
+use osp_protocol::Envelope;
 use osp_server_sdk::OSProtocolNode;
+use osp_server_sdk::builder::OSProtocolNodeBuilder;
+use osp_server_sdk::connection::dns::ChallengeResolver;
+use osp_server_sdk::handler::ReplayFilter;
+use osp_server_sdk::platform;
+use osp_server_sdk::middleware::script::ScriptMiddleware;
+use osp_server_sdk::store::SqliteStore;
+use osp_server_sdk::store::backup::BackupSchedule;
 
 /// Test implementation of an Open Syndication Protocol server node
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// TOML file to read the bind address, hostname, keys, push targets,
+    /// access policy, connection limits and resolver settings from, instead
+    /// of the flags. `OSP_` environment variables override its settings.
+    /// Push targets, access policy and limits are reloaded when the file
+    /// changes or on SIGHUP
+    #[arg(long, conflicts_with_all = ["bind", "private_key", "hostname", "nameserver"])]
+    config: Option<PathBuf>,
+
     /// IPv4 address to bind to
-    #[arg(short, long)]
-    bind: String,
+    #[arg(short, long, required_unless_present = "config")]
+    bind: Option<String>,
 
     /// TCP port to bind to
     #[arg(short, long, default_value_t = 42069)]
     port: u16,
 
-    /// RSA Private Key for decrypting DNS challenges
+    /// RSA Private Key for decrypting DNS challenges, `key.pem` in the
+    /// platform config directory by default
     #[arg(long)]
-    private_key: String,
+    private_key: Option<PathBuf>,
 
     /// Used to identify myself during the handshake
+    #[arg(long, required_unless_present = "config")]
+    hostname: Option<String>,
+
+    /// SQLite database to keep node state in
+    #[arg(long, default_value = "osp_node.db")]
+    store: String,
+
+    /// Directory to write hourly backups of the store to
+    #[arg(long)]
+    backup_dir: Option<PathBuf>,
+
+    /// Refuse all content pushed by peers, e.g. for a public mirror
+    #[arg(long)]
+    read_only: bool,
+
+    /// Relay objects across a mesh, dropping copies that come back
+    #[arg(long)]
+    relay: bool,
+
+    /// Run every stored object through the handlers again before listening
+    #[arg(long)]
+    replay: bool,
+
+    /// Only replay through the handler with this id
+    #[arg(long, requires = "replay")]
+    replay_handler: Option<String>,
+
+    /// Rhai script deciding which pushed objects to accept, reloaded when it changes
+    #[arg(long)]
+    routing_script: Option<PathBuf>,
+
+    /// Nameserver to look up challenge records with, instead of public
+    /// resolvers. Can be given several times
     #[arg(long)]
-    hostname: String,
+    nameserver: Vec<SocketAddr>,
     //
     // /// Servers to open outbound connections to
     // #[arg(long)]
     // push_to: Vec<String>
 }
+
+/// A plain text note, used to demonstrate handlers
+#[derive(OspData)]
+#[osp(id = "5e0cbd52-2d38-4a3e-9bb0-7a4d1b1f3c11")]
+struct Note {
+    text: String,
+}
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     let mut clog = colog::default_builder();
@@ -34,17 +96,63 @@ async fn main() -> io::Result<()> {
     clog.init();
 
     let args = Args::parse();
-    let addr = SocketAddrV4::new(args.bind.parse().expect("Invalid bind address"), args.port);
-    let node = OSProtocolNode::builder()
-        .bind_to(SocketAddr::from(addr))
-        .private_key_file(args.private_key)
-        .hostname(args.hostname)
-        .build();
+    let builder = match &args.config {
+        Some(path) => OSProtocolNodeBuilder::from_config_file(path)?,
+        None => {
+            let bind = args.bind.expect("No --bind given");
+            let addr = SocketAddrV4::new(bind.parse().expect("Invalid bind address"), args.port);
+            let private_key = args.private_key
+                .or_else(|| platform::config_dir().map(|dir| dir.join("key.pem")))
+                .expect("No --private-key given and no config directory to look for one in");
+            let mut builder = OSProtocolNode::builder()
+                .bind_to(SocketAddr::from(addr))
+                .private_key_file(private_key)
+                .hostname(args.hostname.expect("No --hostname given"));
+            if !args.nameserver.is_empty() {
+                builder = builder.challenge_resolver(ChallengeResolver::nameservers(&args.nameserver));
+            }
+            builder
+        }
+    };
+    let mut builder = builder
+        .data_store(SqliteStore::open(args.store)?)
+        .read_only(args.read_only)
+        .relay(args.relay)
+        .handler("log", |note: Note, envelope: Envelope| async move {
+            info!("Note from {}: {}", envelope.origin, note.text);
+            Ok(())
+        });
+
+    if let Some(directory) = args.backup_dir {
+        builder = builder.backup_schedule(BackupSchedule {
+            directory,
+            interval: Duration::from_secs(60 * 60),
+            keep: 24,
+        });
+    }
+
+    if let Some(path) = args.routing_script {
+        builder = builder.middleware(ScriptMiddleware::from_file(path)?);
+    }
+
+    let node = builder.build()?;
+
+    if let Some(path) = args.config {
+        let node = node.clone();
+        tokio::spawn(async move { node.watch_config_file(path).await });
+    }
+
+    if args.replay {
+        node.replay(ReplayFilter {
+            handler: args.replay_handler,
+            ..Default::default()
+        }).await?;
+    }
 
     node.listen().await
 
     // for uri in args.push_to {
-    //     let osp_url = OSPUrl::from(Url::parse(uri.as_str()).unwrap());
+    //     let osp_url = OSPUrl::try_from(Url::parse(uri.as_str()).unwrap()).unwrap();
     //     info!("url: {osp_url}");
     //     let n = Arc::clone(&node);
     //     GLOBAL_THREAD_COUNT.fetch_add(1, Ordering::SeqCst);